@@ -48,3 +48,53 @@ impl From<[u8; 7]> for CAddr {
 impl From<[u8; 8]> for CAddr {
     fn from(v: [u8; 8]) -> CAddr { CAddr([v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7]], 8) }
 }
+
+impl CAddr {
+    /// Address of an empty path, as the guard of a capability pool
+    /// that has none.
+    pub fn empty() -> CAddr {
+        CAddr([0; 8], 0)
+    }
+
+    /// Address of the entry `count` entries after this one, in the
+    /// same capability pool. `None` if there is no such entry.
+    pub fn offset(&self, count: u8) -> Option<CAddr> {
+        if self.1 == 0 {
+            return None;
+        }
+
+        let mut addr = *self;
+        addr.0[self.1 - 1] = match self.0[self.1 - 1].checked_add(count) {
+            Some(index) => index,
+            None => return None,
+        };
+        Some(addr)
+    }
+
+    /// The path `rest` appended to this one, or `None` if it would be
+    /// longer than 8 bytes.
+    pub fn join(&self, rest: CAddr) -> Option<CAddr> {
+        if self.1 + rest.1 > 8 {
+            return None;
+        }
+
+        let mut addr = *self;
+        addr.0[self.1..(self.1 + rest.1)].copy_from_slice(&rest.0[..rest.1]);
+        addr.1 = self.1 + rest.1;
+        Some(addr)
+    }
+
+    /// The rest of the path after `prefix`, or `None` if the path does
+    /// not start with it.
+    pub fn strip_prefix(&self, prefix: CAddr) -> Option<CAddr> {
+        if prefix.1 > self.1 || self.0[..prefix.1] != prefix.0[..prefix.1] {
+            return None;
+        }
+
+        let mut rest = *self;
+        for _ in 0..prefix.1 {
+            rest = rest << 1;
+        }
+        Some(rest)
+    }
+}
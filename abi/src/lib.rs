@@ -6,12 +6,569 @@ mod caddr;
 
 pub use caddr::CAddr;
 
+use core::ops::BitOr;
+
 /// A trait that allows setting a struct back to its default value.
 pub trait SetDefault {
     /// Set this struct back to its default value.
     fn set_default(&mut self);
 }
 
+/// Version of the system call ABI. Bumped on every incompatible
+/// change to `SystemCall` or its payloads.
+pub const ABI_VERSION: u32 = 26;
+
+/// Kernel feature: more than one CPU is online.
+pub const FEATURE_SMP: u64 = 1 << 0;
+/// Kernel feature: kernel page-table isolation is enabled.
+pub const FEATURE_KPTI: u64 = 1 << 1;
+/// Kernel feature: kernel debug tracing is compiled in.
+pub const FEATURE_TRACING: u64 = 1 << 2;
+/// Kernel feature: the processor supports hardware virtualization
+/// (VT-x).
+pub const FEATURE_VMX: u64 = 1 << 3;
+/// Kernel feature: address spaces are tagged with PCIDs.
+pub const FEATURE_PCID: u64 = 1 << 4;
+
+/// Value of RAX on `syscall` selecting a fastpath endpoint call.
+/// The call is also in the task buffer, as an `EndpointCall` of a
+/// `ChannelMessage::Raw` word, which the kernel takes instead when
+/// the fastpath does not apply. RDI holds the bytes of the endpoint
+/// address, lowest first, RSI its depth, and RDX the word. Any other
+/// value of RAX selects the call in the task buffer alone.
+pub const FASTPATH_CALL: u64 = 1;
+
+/// Priority of newly created tasks. Higher priorities are more
+/// urgent, and the default is in the middle of the range so that
+/// tasks can be set both above and below it.
+pub const PRIORITY_DEFAULT: u8 = 128;
+
+/// Highest priority. The initial task may set priorities up to it.
+pub const PRIORITY_MAX: u8 = 255;
+
+/// Configuration of the running kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelInfo {
+    /// Kernel version as major, minor and patch.
+    pub version: (u16, u16, u16),
+    /// ABI version the kernel implements. See `ABI_VERSION`.
+    pub abi_version: u32,
+    /// Bitmap of enabled `FEATURE_*` flags.
+    pub features: u64,
+    /// Supported page sizes. Bit `n` is set if pages of `1 << n`
+    /// bytes can be mapped.
+    pub page_sizes: u64,
+}
+
+/// Physical memory usage, in bytes. `free` covers both untyped
+/// memory and the kernel's frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    pub total: usize,
+    pub page_tables: usize,
+    pub kernel_objects: usize,
+    pub kernel_stacks: usize,
+    pub user_frames: usize,
+    pub free: usize,
+}
+
+/// Endpoint calls taken by the kernel, as `FASTPATH_CALL`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpcStats {
+    /// Calls switched directly to a waiting receiver.
+    pub fastpath_hits: u64,
+    /// Calls that fell back to the general path.
+    pub fastpath_misses: u64,
+}
+
+/// Distribution of the TSC cycles a kernel path took, over its last
+/// samples on one CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CycleStats {
+    /// Samples ever recorded, of which only the last ones are kept.
+    pub samples: u64,
+    pub median: u64,
+    /// 99th percentile.
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// Context switch and IPC cycle counts of one CPU, reported by
+/// `DebugSwitchStats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwitchStats {
+    /// From a task entering the kernel to the kernel switching to the
+    /// next task.
+    pub switch: CycleStats,
+    /// From a task calling an endpoint to it resuming with the reply.
+    pub ipc_round_trip: CycleStats,
+}
+
+/// Number of vectors an `IrqStats` snapshot covers.
+pub const IRQ_STATS_VECTORS: usize = 16;
+
+/// Interrupt statistics of one CPU, for the `IRQ_STATS_VECTORS`
+/// vectors from `first_vector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    pub first_vector: u8,
+    /// Interrupts taken on each vector.
+    pub counts: [u64; IRQ_STATS_VECTORS],
+    /// Longest time the kernel spent on an interrupt of each vector
+    /// before returning to a task, in nanoseconds.
+    pub max_latency_ns: [u64; IRQ_STATS_VECTORS],
+    /// Interrupts found to be spurious, on any vector.
+    pub spurious: u64,
+    /// Non-maskable interrupts taken.
+    pub nmis: u64,
+}
+
+/// Clock read by `ClockGetTime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// Time since boot, which never goes backwards.
+    Monotonic,
+    /// Wall-clock time since the Unix epoch, from the RTC at boot.
+    Realtime,
+}
+
+/// Point in time, in seconds and nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSpec {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+/// Number of addresses a `SoftDirtyRing` holds.
+pub const SOFT_DIRTY_RING_ENTRIES: usize = 509;
+
+/// Ring buffer, filling one page, that a VSpace tracking writes logs
+/// the written pages into. `head` and `tail` count entries from the
+/// start; the kernel writes at `head`, and user-space consumes up to
+/// it from `tail`. Writes that find the ring full are only counted in
+/// `dropped`.
+#[repr(C)]
+pub struct SoftDirtyRing {
+    pub head: u64,
+    pub tail: u64,
+    pub dropped: u64,
+    pub entries: [u64; SOFT_DIRTY_RING_ENTRIES],
+}
+
+/// Number of slots in an `IpcRingHeader`. A power of two, so that
+/// slot indices stay right when the counters wrap.
+pub const IPC_RING_SLOTS: usize = 128;
+
+/// Buffer in the data pages of an IPC ring.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpcRingSlot {
+    /// Offset of the buffer from the start of the data pages.
+    pub offset: u32,
+    pub length: u32,
+}
+
+/// Header filling the first page of an IPC ring. `produced` and
+/// `consumed` count slots from the start; the producer fills slots at
+/// `produced` and then advances it, and the consumer takes them up to
+/// it from `consumed`. Both run without the kernel. A consumer about
+/// to wait sets `consumer_waiting`, and checks the ring once more; a
+/// producer that finds it set after advancing `produced` calls
+/// `IpcRingNotify`, which clears it and wakes the consumer.
+#[repr(C)]
+pub struct IpcRingHeader {
+    pub produced: u64,
+    pub consumed: u64,
+    pub consumer_waiting: u64,
+    pub slots: [IpcRingSlot; IPC_RING_SLOTS],
+}
+
+/// Map flag: map the page read-only and copy it to a fresh frame on
+/// the first write.
+pub const MAP_COW: u64 = 1 << 0;
+
+/// First entry of the initial task's capability pool holding device
+/// untyped capabilities, one for each device memory region found at
+/// boot, in the order the boot loader reported them.
+pub const DEVICE_UNTYPED_FIRST: u8 = 224;
+/// Largest number of device untyped capabilities given to the initial
+/// task. Entries past the last region may hold other capabilities, for
+/// which `DeviceUntypedInfo` fails.
+pub const DEVICE_UNTYPED_COUNT: usize = 16;
+
+/// Entry of the initial task's capability pool holding an I/O port
+/// capability for all ports.
+pub const IO_PORT_ALL: u8 = 240;
+
+/// Entry of the initial task's capability pool holding the kernel log
+/// capability.
+pub const KERNEL_LOG: u8 = 241;
+
+/// Entry of the initial task's capability pool holding the IRQ control
+/// capability.
+pub const IRQ_CONTROL: u8 = 242;
+
+/// Entry of the initial task's capability pool holding the ASID
+/// control capability.
+pub const ASID_CONTROL: u8 = 243;
+
+/// Entry of the initial task's capability pool holding the CPU
+/// control capability.
+pub const CPU_CONTROL: u8 = 244;
+
+/// Entry of the initial task's capability pool holding the boot image
+/// capability.
+pub const BOOT_IMAGE: u8 = 245;
+
+/// Largest number of bytes `KernelLogWrite` logs at once.
+pub const KERNEL_LOG_WRITE_LENGTH: usize = 32;
+
+/// Version of the initial capability layout and of `BootInfo`.
+/// Bumped on every incompatible change to either.
+pub const BOOT_INFO_VERSION: u32 = 3;
+
+/// Entry of the initial task's capability pool holding the pool
+/// itself.
+pub const BOOT_CPOOL: u8 = 0;
+/// Entry of the initial task's capability pool holding the initial
+/// task.
+pub const BOOT_TASK: u8 = 1;
+/// Entry of the initial task's capability pool holding its top page
+/// table.
+pub const BOOT_TOP_PAGE_TABLE: u8 = 2;
+/// Entry of the initial task's capability pool holding the raw page
+/// with the `BootInfo`, mapped at `BOOT_INFO_VADDR`.
+pub const BOOT_INFO_FRAME: u8 = 3;
+/// Entry of the initial task's capability pool holding its scheduling
+/// context, bound to it.
+pub const BOOT_SCHED_CONTEXT: u8 = 4;
+
+/// Virtual address of the `BootInfo` in the initial task.
+pub const BOOT_INFO_VADDR: usize = 0x90000000;
+
+/// First entry of the initial task's capability pool holding untyped
+/// capabilities, one for each free memory region found at boot.
+pub const UNTYPED_FIRST: u8 = 192;
+/// Largest number of untyped capabilities given to the initial task.
+/// Regions past the last are left unused.
+pub const UNTYPED_COUNT: usize = 32;
+
+/// Range of entries of the initial task's capability pool, from
+/// `start` up to but not including `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotRegion {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Physical memory region of an untyped capability given to the
+/// initial task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionInfo {
+    pub start_paddr: u64,
+    pub length: usize,
+}
+
+/// Description of the initial task's capabilities, written by the
+/// kernel into the page at `BOOT_INFO_VADDR`. Entries of `untyped`
+/// and `device_untyped` describe the capabilities of the slot regions
+/// with the same name, in order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    /// Layout version. See `BOOT_INFO_VERSION`.
+    pub version: u32,
+    pub untyped_slots: SlotRegion,
+    pub untyped: [RegionInfo; UNTYPED_COUNT],
+    pub device_untyped_slots: SlotRegion,
+    pub device_untyped: [RegionInfo; DEVICE_UNTYPED_COUNT],
+    /// Entries left empty for the initial task to use.
+    pub empty_slots: SlotRegion,
+}
+
+impl BootInfo {
+    /// Boot information with no capabilities.
+    pub fn empty() -> BootInfo {
+        const NO_REGION: RegionInfo = RegionInfo { start_paddr: 0, length: 0 };
+        const NO_SLOTS: SlotRegion = SlotRegion { start: 0, end: 0 };
+
+        BootInfo {
+            version: BOOT_INFO_VERSION,
+            untyped_slots: NO_SLOTS,
+            untyped: [NO_REGION; UNTYPED_COUNT],
+            device_untyped_slots: NO_SLOTS,
+            device_untyped: [NO_REGION; DEVICE_UNTYPED_COUNT],
+            empty_slots: NO_SLOTS,
+        }
+    }
+
+    /// Entry of the largest untyped capability, if there is any.
+    pub fn largest_untyped(&self) -> Option<CAddr> {
+        let count = self.untyped_slots.end - self.untyped_slots.start;
+        (0..count).max_by_key(|i| self.untyped[*i].length)
+            .map(|i| CAddr::from((self.untyped_slots.start + i) as u8))
+    }
+}
+
+/// Cache maintenance operation on a virtual range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOperation {
+    /// Write dirty lines back to memory.
+    Clean,
+    /// Discard lines, so that the next access reads memory.
+    Invalidate,
+    /// Write dirty lines back and discard them.
+    CleanInvalidate,
+}
+
+/// Memory type of the mappings of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Cached, with writes held in the cache until written back.
+    WriteBack,
+    /// Cached for reads, with writes going straight to memory.
+    WriteThrough,
+    /// Not cached.
+    Uncached,
+}
+
+/// Kernel object type created by `UntypedRetype`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    /// Capability pool of `1 << size_bits` entries.
+    CPool,
+    /// Task, stopped until its registers are set.
+    Task,
+    /// Channel.
+    Channel,
+    /// 4 KiB page.
+    RawPage,
+    /// 2 MiB page.
+    LargePage,
+    /// Top-level page table.
+    PML4,
+    /// Page directory pointer table.
+    PDPT,
+    /// Page directory.
+    PD,
+    /// Page table.
+    PT,
+    /// Address space with its own top-level page table. Only created
+    /// by `RetypeVSpace`, which assigns it an ASID; `UntypedRetype`
+    /// fails for it.
+    VSpace,
+    /// One-shot or periodic timer.
+    Timer,
+    /// Local descriptor table.
+    Ldt,
+    /// Scheduling context, unbound.
+    SchedContext,
+    /// Endpoint, with no blocked senders.
+    Endpoint,
+    /// Reply object, bound to no caller.
+    Reply,
+    /// Notification, with a clear word.
+    Notification,
+    /// Notification group, with no notification in it.
+    NotificationGroup,
+}
+
+/// Why a capability address could not be used by a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapError {
+    /// The address names no entry: it does not match the guard of a
+    /// capability pool on the way, or indexes past its end.
+    InvalidSlot,
+    /// The entry holds a capability of another type than the call
+    /// needs, or one that is not a capability pool on the way.
+    TypeMismatch,
+    /// The capability lacks a right the call needs.
+    InsufficientRights,
+    /// The entry is empty, or its object was deleted or revoked.
+    DeletedObject,
+    /// The address ends before naming an entry.
+    Truncated,
+}
+
+/// Rights of a capability, checked on every use of it. A copy of a
+/// capability can only keep or remove rights of the original. A
+/// capability reached through nested pools only has the rights that
+/// every pool capability on the path also has, and entries of a pool
+/// can only be filled, moved or deleted through a path with
+/// `RIGHT_WRITE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapRights(pub u8);
+
+/// Capability right: read the object, or map memory readable.
+pub const RIGHT_READ: CapRights = CapRights(1 << 0);
+/// Capability right: change the object, or map memory writable.
+pub const RIGHT_WRITE: CapRights = CapRights(1 << 1);
+/// Capability right: send capabilities through a channel, or copy
+/// this capability.
+pub const RIGHT_GRANT: CapRights = CapRights(1 << 2);
+/// Capability right: map memory into an address space.
+pub const RIGHT_MAP: CapRights = CapRights(1 << 3);
+/// Capability attribute: the endpoint capability is deleted once a
+/// send through it succeeds. Unlike rights, it cannot be removed, and
+/// a capability with it can be moved but not copied.
+pub const RIGHT_SEND_ONCE: CapRights = CapRights(1 << 4);
+/// No capability rights.
+pub const RIGHTS_NONE: CapRights = CapRights(0);
+/// All capability rights, held by newly created capabilities.
+pub const RIGHTS_ALL: CapRights = CapRights(0b1111);
+
+impl CapRights {
+    /// Whether all rights of `other` are also in these rights.
+    pub fn contains(self, other: CapRights) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Rights both in these rights and in `other`.
+    pub fn intersect(self, other: CapRights) -> CapRights {
+        CapRights(self.0 & other.0)
+    }
+}
+
+impl BitOr for CapRights {
+    type Output = CapRights;
+
+    fn bitor(self, other: CapRights) -> CapRights {
+        CapRights(self.0 | other.0)
+    }
+}
+
+/// Type of a capability, as listed by `CapDump` and counted by
+/// `ObjectCounts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapType {
+    CPool,
+    Untyped,
+    Task,
+    RawPage,
+    TaskBufferPage,
+    Channel,
+    Pmem,
+    DeviceUntyped,
+    Dma,
+    SharedFrameSet,
+    Msi,
+    IrqHandler,
+    Timer,
+    PML4,
+    PDPT,
+    PD,
+    PT,
+    LargePage,
+    VSpace,
+    Ldt,
+    IoPort,
+    Quota,
+    KernelLog,
+    IrqControl,
+    AsidControl,
+    AsidPool,
+    SchedContext,
+    Endpoint,
+    Reply,
+    Notification,
+    NotificationGroup,
+    IpcRing,
+    CpuControl,
+    BootImage,
+}
+
+/// Number of `CapType` variants.
+pub const CAP_TYPE_COUNT: usize = 34;
+
+/// A capability found by `CapDump`.
+#[derive(Debug, Clone, Copy)]
+pub struct CapInfo {
+    /// Address of the capability, from the task's root pool.
+    pub caddr: CAddr,
+    pub cap_type: CapType,
+    /// Identity of the kernel object. Capabilities to the same object
+    /// have the same identity.
+    pub object: u64,
+    pub rights: CapRights,
+    /// Badge of the capability, 0 if it has none.
+    pub badge: u64,
+}
+
+/// Number of capabilities a `CapDump` holds.
+pub const CAP_DUMP_ENTRIES: usize = 16;
+
+/// Part of the listing of the capabilities reachable from a task's
+/// pools, depth first. Entries past the last capability are `None`.
+#[derive(Debug, Clone, Copy)]
+pub struct CapDump {
+    pub entries: [Option<CapInfo>; CAP_DUMP_ENTRIES],
+    /// Position to continue the listing from, or `None` if it is
+    /// complete.
+    pub next: Option<usize>,
+}
+
+/// How a traced message went from one task to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcKind {
+    /// Sent to an endpoint.
+    Send,
+    /// Called an endpoint.
+    Call,
+    /// Replied to a caller.
+    Reply,
+    /// Forwarded a call, whose caller the receiver replies to.
+    Forward,
+    /// Notification word taken by a task receiving from an endpoint.
+    Notification,
+    /// Value put in a channel, by a put or a donation.
+    ChannelPut,
+    /// Value taken from a channel, by a take, a poll or a donation.
+    ChannelTake,
+}
+
+/// One message recorded by IPC tracing. Tasks and endpoints are
+/// identified as the `object` of a `CapInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpcTraceEntry {
+    /// Monotonic time the message was received, in nanoseconds.
+    pub timestamp_ns: u64,
+    pub kind: IpcKind,
+    /// Task the message came from, 0 for a notification or a value
+    /// taken from a channel.
+    pub sender: u64,
+    /// Task the message went to, 0 for a value put in a channel.
+    pub receiver: u64,
+    /// Endpoint or channel the message went through, 0 for a reply.
+    pub endpoint: u64,
+    /// Notification word, 0 for other messages.
+    pub badge: u64,
+    /// Payload bytes copied, 0 for messages without one.
+    pub length: usize,
+    /// Whether the call took the fastpath.
+    pub fastpath: bool,
+}
+
+/// Number of entries an `IpcTrace` holds.
+pub const IPC_TRACE_ENTRIES: usize = 16;
+
+/// Part of the IPC trace, oldest first. Entries past the last message
+/// recorded are `None`.
+#[derive(Debug, Clone, Copy)]
+pub struct IpcTrace {
+    /// Position of the first entry, later than the one asked for if
+    /// older entries were overwritten.
+    pub start: u64,
+    pub entries: [Option<IpcTraceEntry>; IPC_TRACE_ENTRIES],
+}
+
+/// Kernel objects currently alive, indexed by `CapType`. Objects that
+/// no capability refers to anymore but the kernel still holds are
+/// counted until they are destroyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectCounts {
+    pub live: [usize; CAP_TYPE_COUNT],
+}
+
 #[derive(Debug)]
 pub struct CapSystemCall<'a> {
     pub target: &'a [u8],
@@ -31,6 +588,11 @@ pub enum SystemCall {
     DebugTestSucceed,
     #[cfg(feature="kernel_debug")]
     DebugTestFail,
+    #[cfg(feature="kernel_debug")]
+    DebugSwitchStats {
+        request: usize,
+        response: Option<SwitchStats>,
+    },
     Print {
         request: ([u8; 32], usize)
     },
@@ -42,10 +604,291 @@ pub enum SystemCall {
         untyped: CAddr,
         toplevel_table: CAddr,
         request: (usize, CAddr),
+        flags: u64,
+    },
+    RetypeLargePage {
+        request: (CAddr, CAddr),
+    },
+    MapLargePage {
+        untyped: CAddr,
+        toplevel_table: CAddr,
+        request: (usize, CAddr),
+    },
+    LargePageSplit {
+        request: (CAddr, CAddr, CAddr, CAddr),
+        response: Option<bool>,
+    },
+    LargePageMerge {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    MapDemandZero {
+        untyped: CAddr,
+        toplevel_table: CAddr,
+        request: (usize, usize),
+    },
+    PmemPage {
+        request: (CAddr, usize, CAddr),
+        response: Option<CAddr>,
+    },
+    DeviceUntypedRetype {
+        request: (CAddr, usize, CAddr),
+        response: Option<CAddr>,
+    },
+    DeviceUntypedInfo {
+        request: CAddr,
+        response: Option<(u64, usize)>,
+    },
+    PmemFlush {
+        request: (CAddr, usize, usize),
+        response: Option<bool>,
+    },
+    PmemFence {
+        request: CAddr,
+    },
+    RetypeDma {
+        request: (CAddr, usize, u64),
+        response: Option<(CAddr, u64)>,
+    },
+    DmaPage {
+        request: (CAddr, usize, CAddr),
+        response: Option<CAddr>,
+    },
+    CacheMaintenance {
+        request: (CAddr, usize, usize, CacheOperation),
+        response: Option<bool>,
+    },
+    PageCacheMaintenance {
+        request: (CAddr, usize, usize, CacheOperation),
+        response: Option<bool>,
+    },
+    PageSetCacheMode {
+        request: (CAddr, CacheMode),
+        response: Option<bool>,
+    },
+    AsidControlMakePool {
+        request: (CAddr, CAddr),
+        response: Option<CAddr>,
+    },
+    RetypeVSpace {
+        request: (CAddr, CAddr),
+        response: Option<CAddr>,
+    },
+    VSpaceMap {
+        request: (CAddr, usize, CAddr),
+        response: Option<bool>,
+    },
+    VSpaceUnmap {
+        request: (CAddr, usize),
+        response: Option<bool>,
+    },
+    VSpaceDestroy {
+        request: CAddr,
+        response: Option<usize>,
+    },
+    BootImageLoad {
+        request: (CAddr, CAddr),
+        response: Option<u64>,
+    },
+    VSpaceRemap {
+        request: (CAddr, usize, usize, usize, usize),
+        response: Option<bool>,
+    },
+    VSpaceHarvest {
+        request: (CAddr, usize, usize, bool),
+        response: Option<(u64, u64)>,
+    },
+    VSpaceTrackWrites {
+        request: (CAddr, CAddr),
+        response: Option<usize>,
+    },
+    RetypeSharedFrameSet {
+        request: (CAddr, usize),
+        response: Option<CAddr>,
+    },
+    SharedFrameSetMap {
+        request: (CAddr, CAddr, usize, bool),
+        response: Option<bool>,
+    },
+    SharedFrameSetUnmap {
+        request: (CAddr, CAddr, usize),
+        response: Option<bool>,
+    },
+    SharedFrameSetRelease {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    RetypeIpcRing {
+        request: (CAddr, CAddr),
+        response: Option<CAddr>,
+    },
+    IpcRingBind {
+        request: (CAddr, Option<CAddr>, u64),
+        response: Option<bool>,
+    },
+    IpcRingNotify {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    IrqControlGetMsi {
+        request: (CAddr, CAddr, usize),
+        response: Option<(CAddr, u64, u32)>,
+    },
+    MsiMessage {
+        request: CAddr,
+        response: Option<(u64, u32)>,
+    },
+    MsiSetAffinity {
+        request: (CAddr, usize),
+        response: Option<(u64, u32)>,
+    },
+    IrqControlGetHandler {
+        request: (CAddr, CAddr, u32, usize, CAddr),
+        response: Option<bool>,
+    },
+    CpuControlOffline {
+        request: (CAddr, usize),
+        response: Option<bool>,
+    },
+    CpuControlOnline {
+        request: (CAddr, usize),
+        response: Option<bool>,
+    },
+    IrqHandlerBind {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    IrqHandlerBindNotification {
+        request: (CAddr, CAddr, u64),
+        response: Option<bool>,
+    },
+    IrqHandlerAck {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    IrqHandlerSetAffinity {
+        request: (CAddr, usize),
+        response: Option<bool>,
+    },
+    RetypeTimer {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    TimerBind {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    TimerBindNotification {
+        request: (CAddr, CAddr, u64),
+        response: Option<bool>,
+    },
+    TimerArm {
+        request: (CAddr, u64, u64),
+        response: Option<bool>,
+    },
+    TimerCancel {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    RetypeSchedContext {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    SchedContextConfigure {
+        request: (CAddr, u64, u64),
+        response: Option<bool>,
+    },
+    SchedContextBind {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    SchedContextUnbind {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    RetypeLdt {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    LdtSetEntry {
+        request: (CAddr, usize, u64),
+        response: Option<bool>,
+    },
+    IoPortIssue {
+        request: (CAddr, CAddr, u16, u16),
+        response: Option<CAddr>,
+    },
+    IoPortIn {
+        request: (CAddr, u16, u8),
+        response: Option<u32>,
+    },
+    IoPortOut {
+        request: (CAddr, u16, u8, u32),
+        response: Option<bool>,
+    },
+    KernelLogRead {
+        request: (CAddr, CAddr, u64),
+        response: Option<(u64, usize)>,
+    },
+    KernelLogWrite {
+        request: (CAddr, [u8; KERNEL_LOG_WRITE_LENGTH], usize),
+        response: Option<bool>,
+    },
+    IpcTraceSet {
+        request: (CAddr, bool),
+        response: Option<bool>,
+    },
+    IpcTraceRead {
+        request: (CAddr, u64),
+        response: Option<IpcTrace>,
     },
     RetypeCPool {
         request: (CAddr, CAddr),
     },
+    CPoolMint {
+        request: (CAddr, CAddr, usize, CAddr),
+        response: Option<bool>,
+    },
+    CPoolCopyWithRights {
+        request: (CAddr, CAddr, CapRights),
+        response: Option<bool>,
+    },
+    CPoolMove {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    CPoolSwap {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    CPoolDelete {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    CPoolRevoke {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    UntypedRetype {
+        request: (CAddr, ObjectType, usize, CAddr, usize, usize),
+        response: Option<usize>,
+    },
+    UntypedRevoke {
+        request: CAddr,
+        response: Option<bool>,
+    },
+    RetypeQuota {
+        request: (CAddr, Option<CAddr>, usize),
+        response: Option<CAddr>,
+    },
+    UntypedSetQuota {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    QuotaInfo {
+        request: CAddr,
+        response: Option<(usize, usize)>,
+    },
     ChannelTake {
         request: CAddr,
         response: Option<ChannelMessage>,
@@ -53,6 +896,123 @@ pub enum SystemCall {
     ChannelPut {
         request: (CAddr, ChannelMessage),
     },
+    ChannelDonate {
+        request: (CAddr, ChannelMessage, CAddr),
+        response: Option<ChannelMessage>,
+    },
+    ChannelPoll {
+        request: CAddr,
+        response: Option<ChannelMessage>,
+    },
+    RetypeChannel {
+        request: (CAddr, CAddr),
+    },
+    RetypeEndpoint {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    EndpointSetInheritance {
+        request: (CAddr, bool),
+    },
+    EndpointMintSendOnce {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    RetypeReply {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    EndpointSend {
+        request: (CAddr, ChannelMessage),
+    },
+    EndpointCall {
+        request: (CAddr, ChannelMessage),
+        response: Option<ChannelMessage>,
+    },
+    EndpointReceive {
+        request: (CAddr, Option<CAddr>),
+        response: Option<ChannelMessage>,
+    },
+    EndpointReply {
+        request: (CAddr, ChannelMessage),
+        response: Option<bool>,
+    },
+    EndpointForward {
+        request: (CAddr, CAddr, ChannelMessage),
+        response: Option<bool>,
+    },
+    ReplySetLendWindow {
+        request: (CAddr, Option<(CAddr, u64, usize)>),
+        response: Option<bool>,
+    },
+    RetypeNotification {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    NotificationSignal {
+        request: (CAddr, u64),
+    },
+    NotificationWait {
+        request: CAddr,
+        response: Option<u64>,
+    },
+    NotificationWaitUntil {
+        request: (CAddr, u64),
+        response: Option<u64>,
+    },
+    RetypeNotificationGroup {
+        request: CAddr,
+        response: Option<CAddr>,
+    },
+    NotificationGroupAdd {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    NotificationGroupRemove {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
+    NotificationGroupSignal {
+        request: (CAddr, u64),
+    },
+    TaskBindNotification {
+        request: (CAddr, Option<CAddr>),
+        response: Option<bool>,
+    },
+    TimerTicks {
+        response: Option<u64>,
+    },
+    ClockGetTime {
+        request: Clock,
+        response: Option<TimeSpec>,
+    },
+    KernelInfo {
+        response: Option<KernelInfo>,
+    },
+    MemInfo {
+        request: CAddr,
+        response: Option<MemInfo>,
+    },
+    IpcStats {
+        request: CAddr,
+        response: Option<IpcStats>,
+    },
+    IrqStats {
+        request: (CAddr, usize, u8),
+        response: Option<IrqStats>,
+    },
+    CapDump {
+        request: (CAddr, CAddr, usize),
+        response: Option<CapDump>,
+    },
+    ObjectCounts {
+        request: CAddr,
+        response: Option<ObjectCounts>,
+    },
+    VirtToPhys {
+        request: (CAddr, usize),
+        response: Option<(usize, MapAttributes)>,
+    },
     RetypeTask {
         request: (CAddr, CAddr),
     },
@@ -62,39 +1022,285 @@ pub enum SystemCall {
     TaskSetStackPointer {
         request: (CAddr, u64),
     },
+    TaskSetRegisters {
+        request: (CAddr, TaskRegisters),
+    },
+    TaskGetRegisters {
+        request: CAddr,
+        response: Option<TaskRegisters>,
+    },
+    TaskSetFsBase {
+        request: (CAddr, u64),
+        response: Option<bool>,
+    },
+    TaskSetLdt {
+        request: (CAddr, CAddr),
+        response: Option<bool>,
+    },
     TaskSetCPool {
         request: (CAddr, CAddr),
     },
     TaskSetTopPageTable {
         request: (CAddr, CAddr),
     },
+    TaskSetVSpace {
+        request: (CAddr, CAddr),
+    },
     TaskSetBuffer {
         request: (CAddr, CAddr),
     },
+    TaskSetFaultHandler {
+        request: (CAddr, CAddr),
+    },
+    TaskSetDebugger {
+        request: (CAddr, CAddr),
+    },
+    TaskSetReceiveWindow {
+        request: (CAddr, Option<CAddr>),
+    },
+    TaskSetPriority {
+        request: (CAddr, u8),
+        response: Option<bool>,
+    },
+    TaskSetMaxPriority {
+        request: (CAddr, u8),
+        response: Option<bool>,
+    },
+    TaskYield,
+    SleepUntil {
+        request: u64,
+        response: Option<bool>,
+    },
+    SleepFor {
+        request: u64,
+        response: Option<bool>,
+    },
+    TaskSetAffinity {
+        request: (CAddr, u64),
+        response: Option<bool>,
+    },
+    TaskSetBreakpoint {
+        request: (CAddr, u8, u64, BreakpointKind, u8),
+        response: Option<bool>,
+    },
+    TaskClearBreakpoint {
+        request: (CAddr, u8),
+        response: Option<bool>,
+    },
     TaskSetActive {
         request: CAddr
     },
     TaskSetInactive {
         request: CAddr
     },
+    TaskExit {
+        request: u64,
+    },
+    TaskBindExitNotification {
+        request: (CAddr, Option<CAddr>, u64),
+        response: Option<bool>,
+    },
+    TaskExitStatus {
+        request: CAddr,
+        response: Option<u64>,
+    },
+    TaskSuspend {
+        request: CAddr,
+    },
+    TaskResume {
+        request: CAddr,
+    },
 }
 
+/// Longest payload a message can carry, in bytes.
+pub const MESSAGE_LENGTH_MAX: usize = 1024;
+
 /// Represents a task buffer used for system calls.
+///
+/// The buffer is a frame set for each task, which also holds the
+/// payload of a `ChannelMessage::Payload`: the kernel copies its
+/// first `payload_length` bytes into the buffer of the receiver, and
+/// fails the message if the length is above `MESSAGE_LENGTH_MAX`.
 pub struct TaskBuffer {
     pub call: Option<SystemCall>,
+    /// First capability error of the last system call, if any.
+    pub cap_error: Option<CapError>,
     pub payload_length: usize,
-    pub payload_data: [u8; 1024],
+    pub payload_data: [u8; MESSAGE_LENGTH_MAX],
 }
 
 impl SetDefault for TaskBuffer {
     fn set_default(&mut self) {
         self.call = None;
+        self.cap_error = None;
     }
 }
 
+/// Largest number of capabilities a message can carry.
+pub const MESSAGE_CAPS: usize = 4;
+
+/// Capabilities carried by a `ChannelMessage::Grant`.
+#[derive(Debug, Clone, Copy)]
+pub struct CapTransfer {
+    /// On send, the sender's capabilities to transfer. On receive, the
+    /// receive window entries they were placed in, or `None` for those
+    /// that did not fit.
+    pub caps: [Option<CAddr>; MESSAGE_CAPS],
+    /// Whether the sender keeps its capabilities. Otherwise they are
+    /// moved out of its capability pools.
+    pub copy: bool,
+}
+
+/// Frames lent along a `ChannelMessage::Lend`, mapped into the
+/// receiver instead of copied, until it replies.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLoan {
+    /// On send, the sender's shared frame set to lend. `None` on
+    /// receive.
+    pub frames: Option<CAddr>,
+    /// Whether the receiver may write to the frames.
+    pub writable: bool,
+    /// On receive, the address the frames are mapped at in the lend
+    /// window of the reply object, or `None` if they could not be.
+    pub address: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub enum ChannelMessage {
     Raw(u64),
     Cap(Option<CAddr>),
+    Grant(u64, CapTransfer),
+    /// A value with frames lent for the duration of a call.
+    Lend(u64, FrameLoan),
     Payload,
+    PageFault(PageFaultInfo),
+    Fault(FaultInfo),
+    Debug(DebugEvent),
+    /// Word of the notification bound to a task receiving from an
+    /// endpoint, signalled before any sender came.
+    Notification(u64),
+}
+
+/// User-mode register state of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TaskRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+/// CPU fault forwarded to a fault handler, other than a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Divide error (#DE).
+    DivideError,
+    /// Invalid opcode (#UD).
+    InvalidOpcode,
+    /// General protection (#GP).
+    GeneralProtection,
+    /// Alignment check (#AC).
+    AlignmentCheck,
+}
+
+/// A CPU fault, sent by the kernel to the fault handler channel of
+/// the faulting task. The task is stopped until the handler sets it
+/// active again, after emulating the instruction or changing its
+/// registers as needed.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultInfo {
+    pub kind: FaultKind,
+    /// Error code pushed by the CPU, or zero if there is none.
+    pub error_code: u64,
+    /// Registers at the fault. `rip` is the faulting instruction.
+    pub registers: TaskRegisters,
+}
+
+/// Access that triggers a hardware breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Writing the data at the address.
+    Write,
+    /// Reading or writing the data at the address.
+    ReadWrite,
+}
+
+/// Cause of a debug event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEventKind {
+    /// `int3` breakpoint instruction (#BP).
+    Breakpoint,
+    /// Hardware breakpoint of the given slot (#DB).
+    HardwareBreakpoint(u8),
+    /// Single step, with the trap flag set (#DB).
+    SingleStep,
+}
+
+/// A debug event, sent by the kernel to the debugger channel of the
+/// task. The task is stopped until the debugger sets it active again.
+#[derive(Debug, Clone, Copy)]
+pub struct DebugEvent {
+    pub kind: DebugEventKind,
+    /// Debug status (DR6) of #DB, or zero for #BP.
+    pub status: u64,
+    /// Registers at the event. `rip` is the instruction after `int3`,
+    /// a trapping data breakpoint or a step, and the breakpoint
+    /// instruction otherwise.
+    pub registers: TaskRegisters,
+}
+
+/// Attributes of a virtual memory mapping, as found by the kernel's
+/// page table walker. Permissions are the effective ones, combined
+/// over all paging levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapAttributes {
+    /// Length of the page containing the address.
+    pub page_length: usize,
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+    pub global: bool,
+    pub write_through: bool,
+    pub cache_disabled: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+/// A decoded page fault, sent by the kernel to the fault handler
+/// channel of the faulting task. The task is stopped until the
+/// handler sets it active again.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultInfo {
+    /// Linear address that caused the fault.
+    pub address: u64,
+    /// Instruction pointer of the faulting instruction.
+    pub instruction_pointer: u64,
+    /// The page was present, and the fault is a protection violation.
+    pub present: bool,
+    /// The access was a write.
+    pub write: bool,
+    /// The access came from user mode.
+    pub user: bool,
+    /// A reserved bit was set in a paging structure entry.
+    pub reserved: bool,
+    /// The access was an instruction fetch.
+    pub instruction_fetch: bool,
+    /// The fault hit the guard page below a stack.
+    pub stack_overflow: bool,
 }
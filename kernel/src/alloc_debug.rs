@@ -0,0 +1,77 @@
+use core::{ptr, slice};
+use common::*;
+use util::Mutex;
+use arch;
+
+/// Byte that freed frames and heap objects are filled with.
+pub const POISON: u8 = 0x6b;
+
+/// Number of return addresses a backtrace is identified by.
+const BACKTRACE_DEPTH: usize = 8;
+
+/// Number of distinct backtraces remembered for `log_backtrace`.
+const BACKTRACE_SLOTS: usize = 128;
+
+/// Backtraces seen so far, by ID. Once all slots are taken, IDs are
+/// still computed, but new backtraces are not remembered.
+struct Backtraces {
+    slots: [(u32, [usize; BACKTRACE_DEPTH]); BACKTRACE_SLOTS],
+    count: usize,
+}
+
+static BACKTRACES: Mutex<Backtraces> = Mutex::new(Backtraces {
+    slots: [(0, [0; BACKTRACE_DEPTH]); BACKTRACE_SLOTS],
+    count: 0,
+});
+
+/// Identify the current call stack by a hash of its return
+/// addresses, and remember it. IDs fit in 31 bits.
+pub fn backtrace_id() -> u32 {
+    let mut frames = [0; BACKTRACE_DEPTH];
+    let count = arch::backtrace(&mut frames);
+
+    // FNV-1a over the return addresses.
+    let mut hash: u32 = 0x811c9dc5;
+    for frame in frames[..count].iter() {
+        for i in 0..8 {
+            hash ^= (frame >> (i * 8)) as u8 as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    }
+    let id = hash & 0x7fff_ffff;
+
+    let mut backtraces = BACKTRACES.lock();
+    let count = backtraces.count;
+    if count < BACKTRACE_SLOTS && !backtraces.slots[..count].iter().any(|slot| slot.0 == id) {
+        backtraces.slots[count] = (id, frames);
+        backtraces.count += 1;
+    }
+
+    id
+}
+
+/// Log the return addresses of the backtrace with the given ID.
+pub fn log_backtrace(id: u32) {
+    let backtraces = BACKTRACES.lock();
+    match backtraces.slots[..backtraces.count].iter().find(|slot| slot.0 == id) {
+        Some(&(_, ref frames)) => {
+            log!("Backtrace {:08x}:", id);
+            for frame in frames.iter().take_while(|&&frame| frame != 0) {
+                log!("  0x{:x}", frame);
+            }
+        },
+        None => log!("Backtrace {:08x} was not recorded.", id),
+    }
+}
+
+/// Fill `length` bytes at `vaddr` with `POISON`.
+pub unsafe fn poison(vaddr: VAddr, length: usize) {
+    ptr::write_bytes(vaddr.into(): usize as *mut u8, POISON, length);
+}
+
+/// Offset of the first byte of the `length` bytes at `vaddr` that is
+/// not `POISON`, if any.
+pub unsafe fn find_unpoisoned(vaddr: VAddr, length: usize) -> Option<usize> {
+    slice::from_raw_parts(vaddr.into(): usize as *const u8, length)
+        .iter().position(|&byte| byte != POISON)
+}
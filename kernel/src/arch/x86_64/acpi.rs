@@ -0,0 +1,151 @@
+use core::{ptr, slice};
+use core::mem::size_of;
+use common::*;
+use arch::paging::MemoryObject;
+
+/// Signature of the root system description pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Physical address of the BIOS data area word holding the segment
+/// of the extended BIOS data area.
+const EBDA_SEGMENT_PADDR: usize = 0x40E;
+/// Length of the start of the extended BIOS data area searched for
+/// the RSDP.
+const EBDA_SEARCH_LENGTH: usize = 1024;
+/// BIOS read-only memory area searched for the RSDP.
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+/// Length of the ACPI 1.0 part of the RSDP, covered by its checksum.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Root system description pointer.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The fields below are only valid for revision 2 and later.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all system description tables.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic address structure, describing a register block.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct GenericAddress {
+    /// 0 for system memory, 1 for system I/O.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// Read a `T` at `paddr`.
+///
+/// # Safety
+///
+/// `paddr` must hold firmware tables, not memory used by anything
+/// else.
+pub unsafe fn read<T: Copy>(paddr: PAddr) -> T {
+    let object = MemoryObject::<u8>::slice(paddr, size_of::<T>());
+    ptr::read_unaligned(object.as_ptr() as *const T)
+}
+
+/// Whether the bytes at `paddr` sum to zero, as ACPI checksums
+/// require.
+unsafe fn checksum(paddr: PAddr, length: usize) -> bool {
+    let object = MemoryObject::<u8>::slice(paddr, length);
+    slice::from_raw_parts(object.as_ptr(), length).iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Search the RSDP in `length` bytes at `start`, on 16-byte
+/// boundaries.
+unsafe fn search_rsdp(start: usize, length: usize) -> Option<PAddr> {
+    let found = {
+        let object = MemoryObject::<u8>::slice(PAddr::from(start), length);
+        let area = slice::from_raw_parts(object.as_ptr(), length);
+        (0..(length / 16)).map(|i| i * 16)
+            .find(|&offset| &area[offset..(offset + RSDP_SIGNATURE.len())] == RSDP_SIGNATURE)
+    };
+
+    match found {
+        Some(offset) if checksum(PAddr::from(start + offset), RSDP_V1_LENGTH) =>
+            Some(PAddr::from(start + offset)),
+        _ => None,
+    }
+}
+
+/// Physical address of the RSDP, from the extended BIOS data area or
+/// the BIOS read-only memory area.
+fn rsdp() -> Option<PAddr> {
+    unsafe {
+        let ebda = (read::<u16>(PAddr::from(EBDA_SEGMENT_PADDR)) as usize) << 4;
+        if ebda != 0 {
+            if let Some(rsdp) = search_rsdp(ebda, EBDA_SEARCH_LENGTH) {
+                return Some(rsdp);
+            }
+        }
+        search_rsdp(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)
+    }
+}
+
+/// Find the system description table with `signature`, through the
+/// XSDT if there is one, or the RSDT otherwise. Returns the physical
+/// address of its header. Tables with a bad checksum are skipped.
+pub fn find_table(signature: &[u8; 4]) -> Option<PAddr> {
+    let rsdp_paddr = match rsdp() {
+        Some(rsdp) => rsdp,
+        None => return None,
+    };
+
+    unsafe {
+        let rsdp = read::<Rsdp>(rsdp_paddr);
+        let (root, entry_length) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            (PAddr::from(rsdp.xsdt_address), size_of::<u64>())
+        } else {
+            (PAddr::from(rsdp.rsdt_address as u64), size_of::<u32>())
+        };
+
+        let root_header = read::<SdtHeader>(root);
+        let entries = (root_header.length as usize - size_of::<SdtHeader>()) / entry_length;
+        for i in 0..entries {
+            let entry = root + size_of::<SdtHeader>() + i * entry_length;
+            let table = if entry_length == size_of::<u64>() {
+                PAddr::from(read::<u64>(entry))
+            } else {
+                PAddr::from(read::<u32>(entry) as u64)
+            };
+
+            let header = read::<SdtHeader>(table);
+            if &header.signature == signature && checksum(table, header.length as usize) {
+                return Some(table);
+            }
+        }
+    }
+
+    None
+}
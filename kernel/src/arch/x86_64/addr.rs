@@ -1,5 +1,5 @@
 use core::fmt;
-use core::ops::{Add, AddAssign};
+use core::ops::{Add, AddAssign, Sub};
 
 macro_rules! addr_common {
     ( $t:ty, $e:expr ) => {
@@ -11,6 +11,14 @@ macro_rules! addr_common {
             }
         }
 
+        impl Sub<usize> for $t {
+            type Output = Self;
+
+            fn sub(self, _rhs: usize) -> Self {
+                Self::from(self.into(): usize - _rhs)
+            }
+        }
+
         impl AddAssign<usize> for $t {
             fn add_assign(&mut self, _rhs: usize) {
                 self.0 = self.0 + (_rhs as u64);
@@ -0,0 +1,26 @@
+use arch::{kernel_start_vaddr, kernel_end_vaddr};
+
+/// Walk the frame pointer chain of the current kernel stack, and
+/// write the return addresses found into `frames`, innermost first.
+/// Returns the number of frames written. The walk stops at the first
+/// frame pointer outside of the kernel image, where the kernel stack
+/// lives.
+#[inline(never)]
+pub fn backtrace(frames: &mut [usize]) -> usize {
+    let start = kernel_start_vaddr().into(): usize;
+    let end = kernel_end_vaddr().into(): usize;
+
+    let mut rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) ::: "intel"); }
+
+    let mut count = 0;
+    while count < frames.len() && rbp % 8 == 0 && rbp >= start && rbp + 16 <= end {
+        let frame = rbp as *const usize;
+        unsafe {
+            frames[count] = *frame.offset(1);
+            rbp = *frame;
+        }
+        count += 1;
+    }
+    count
+}
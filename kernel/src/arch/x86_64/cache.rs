@@ -0,0 +1,77 @@
+use arch::cpu::{self, CPUID_07_EBX_CLFLUSHOPT, CPUID_07_EBX_CLWB};
+use arch::paging::CACHE_LINE_LENGTH;
+
+/// Ranges of at least this many bytes are flushed with `wbinvd`
+/// instead of line by line.
+pub const WBINVD_THRESHOLD: usize = 1024 * 1024;
+
+/// Cache line flush instruction to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineFlush {
+    Clwb,
+    Clflushopt,
+    Clflush,
+}
+
+fn line_flush(clean_only: bool) -> LineFlush {
+    let (_, ebx, _, _) = cpu::cpuid(0x7, 0);
+
+    if clean_only && ebx & CPUID_07_EBX_CLWB != 0 {
+        LineFlush::Clwb
+    } else if ebx & CPUID_07_EBX_CLFLUSHOPT != 0 {
+        LineFlush::Clflushopt
+    } else {
+        LineFlush::Clflush
+    }
+}
+
+unsafe fn flush_lines(start: usize, length: usize, flush: LineFlush) {
+    let mut line = start - start % CACHE_LINE_LENGTH;
+
+    while line < start + length {
+        match flush {
+            LineFlush::Clwb => asm!("clwb ($0)" :: "r" (line) : "memory" : "volatile"),
+            LineFlush::Clflushopt => asm!("clflushopt ($0)" :: "r" (line) : "memory" : "volatile"),
+            LineFlush::Clflush => asm!("clflush ($0)" :: "r" (line) : "memory" : "volatile"),
+        }
+        line += CACHE_LINE_LENGTH;
+    }
+
+    asm!("mfence" ::: "memory" : "volatile");
+}
+
+/// Write back dirty cache lines in the given virtual range. The lines
+/// may stay in the cache.
+///
+/// # Safety
+///
+/// The range must be mapped.
+pub unsafe fn clean_range(start: usize, length: usize) {
+    flush_lines(start, length, line_flush(true));
+}
+
+/// Write back and invalidate cache lines in the given virtual
+/// range. x86 has no user-safe invalidate-only operation, so this is
+/// also used for plain invalidation.
+///
+/// # Safety
+///
+/// The range must be mapped.
+pub unsafe fn clean_invalidate_range(start: usize, length: usize) {
+    flush_lines(start, length, line_flush(false));
+}
+
+/// Write back and invalidate all caches of the current CPU.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn wbinvd() {
+    asm!("wbinvd" ::: "memory" : "volatile");
+}
+
+/// Order all previous cache line flushes and stores before any later
+/// store.
+pub fn store_fence() {
+    unsafe { asm!("sfence" ::: "memory" : "volatile"); }
+}
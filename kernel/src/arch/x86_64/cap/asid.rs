@@ -0,0 +1,148 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool256Arc};
+use arch::paging::{self, Asid};
+use cap::{UntypedDescriptor, Derived};
+use super::{VSpaceCap, VSpaceDescriptor};
+
+/// ASID control descriptor.
+#[derive(Debug)]
+pub struct AsidControlDescriptor {
+    next: Option<ManagedArcAny>,
+}
+
+/// ASID control capability. Reference-counted smart pointer to ASID
+/// control descriptor.
+///
+/// There is only one, given to the initial task. The address space
+/// identifiers VSpaces are tagged with are only handed out through
+/// it, as ASID pools, so the kernel never runs out of them on behalf
+/// of a task.
+pub type AsidControlCap = ManagedArc<RwLock<AsidControlDescriptor>>;
+
+/// ASID pool descriptor.
+#[derive(Debug)]
+pub struct AsidPoolDescriptor {
+    /// Index of the pool, until it is revoked.
+    pool: Option<usize>,
+    /// VSpaces assigned an ASID, at the index of the ASID in the pool.
+    vspace_weak_pool: ManagedWeakPool256Arc,
+    next: Option<ManagedArcAny>,
+}
+
+/// ASID pool capability. Reference-counted smart pointer to ASID pool
+/// descriptor.
+///
+/// Assigns each VSpace created through it one of `ASID_POOL_SIZE`
+/// ASIDs. An ASID is free again once its VSpace is revoked.
+pub type AsidPoolCap = ManagedArc<RwLock<AsidPoolDescriptor>>;
+
+impl AsidControlCap {
+    /// Create the ASID control capability.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot.
+    pub unsafe fn bootstrap(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(AsidControlDescriptor {
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl AsidControlDescriptor {
+    /// Create an ASID pool from `untyped`. Returns `None` if all ASID
+    /// pools are handed out.
+    pub fn make_pool(&self, untyped: &mut UntypedDescriptor) -> Option<AsidPoolCap> {
+        let pool = match paging::allocate_pool() {
+            Some(pool) => pool,
+            None => return None,
+        };
+        let mut arc: Option<AsidPoolCap> = None;
+
+        let vspace_weak_pool = unsafe { ManagedWeakPool256Arc::create(
+            untyped.allocate(ManagedWeakPool256Arc::inner_length(),
+                             ManagedWeakPool256Arc::inner_alignment())) };
+
+        unsafe {
+            untyped.derive(AsidPoolCap::inner_length(), AsidPoolCap::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    AsidPoolCap::new(paddr, RwLock::new(AsidPoolDescriptor {
+                        pool: Some(pool),
+                        vspace_weak_pool: vspace_weak_pool,
+                        next: next_child,
+                    }))
+                );
+
+                arc.clone().unwrap().into()
+            });
+        }
+
+        arc
+    }
+}
+
+impl AsidPoolDescriptor {
+    /// Whether a VSpace can still be assigned an ASID from this pool.
+    pub fn has_free(&self) -> bool {
+        self.pool.is_some() &&
+            (0..paging::ASID_POOL_SIZE).any(|index| !self.vspace_weak_pool.read().is_occupied(index))
+    }
+
+    /// Assign `vspace` a free ASID of this pool, which its address
+    /// space is tagged with from now on. Returns `false` if there is
+    /// none.
+    pub fn assign(&self, vspace: &VSpaceCap) -> bool {
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => return false,
+        };
+
+        match self.vspace_weak_pool.read().downgrade_free(vspace) {
+            Some(index) => {
+                vspace.read().pml4().write().set_pinned_asid(Some(Asid::pinned(pool, index)));
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Derived for AsidControlDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
+
+impl Derived for AsidPoolDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    /// VSpaces still assigned an ASID fall back to ASIDs the kernel
+    /// recycles, and the pool is handed out again.
+    fn revoke(arc: &AsidPoolCap) {
+        let mut desc = arc.write();
+        for index in 0..paging::ASID_POOL_SIZE {
+            let vspace: Option<ManagedArc<RwLock<VSpaceDescriptor>>> =
+                desc.vspace_weak_pool.read().upgrade(index);
+            if let Some(vspace) = vspace {
+                vspace.read().pml4().write().set_pinned_asid(None);
+            }
+        }
+        desc.vspace_weak_pool.read().clear();
+
+        if let Some(pool) = desc.pool.take() {
+            paging::free_pool(pool);
+        }
+    }
+}
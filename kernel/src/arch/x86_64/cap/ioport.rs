@@ -0,0 +1,117 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use arch::{self, inportb, outportb, inportw, outportw, inportl, outportl};
+use cap::{UntypedDescriptor, Derived};
+
+/// I/O port descriptor.
+#[derive(Debug)]
+pub struct IoPortDescriptor {
+    first: u16,
+    last: u16,
+    next: Option<ManagedArcAny>,
+}
+
+/// I/O port capability. Reference-counted smart pointer to I/O port
+/// descriptor.
+///
+/// Holders can read and write the ports from `first` to `last`, through
+/// the kernel. Ports the kernel drives itself are never accessible.
+pub type IoPortCap = ManagedArc<RwLock<IoPortDescriptor>>;
+
+impl IoPortCap {
+    /// Create an I/O port capability for the ports from `first` to
+    /// `last`.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot, or for a range within the range of
+    /// an existing I/O port capability.
+    pub unsafe fn bootstrap(first: u16, last: u16, untyped: &mut UntypedDescriptor) -> Self {
+        assert!(first <= last);
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(IoPortDescriptor {
+                    first: first,
+                    last: last,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl IoPortDescriptor {
+    /// First port of the range.
+    pub fn first(&self) -> u16 {
+        self.first
+    }
+
+    /// Last port of the range.
+    pub fn last(&self) -> u16 {
+        self.last
+    }
+
+    /// Create an I/O port capability for the ports from `first` to
+    /// `last`, which must be within this range. Returns `None`
+    /// otherwise.
+    pub fn issue(&self, first: u16, last: u16, untyped: &mut UntypedDescriptor) -> Option<IoPortCap> {
+        if first > last || first < self.first || last > self.last {
+            return None;
+        }
+
+        Some(unsafe { IoPortCap::bootstrap(first, last, untyped) })
+    }
+
+    /// Whether an access of `width` bytes at `port` is allowed.
+    fn allows(&self, port: u16, width: u8) -> bool {
+        let valid_width = width == 1 || width == 2 || width == 4;
+        valid_width && port >= self.first &&
+            port as u32 + width as u32 - 1 <= self.last as u32 &&
+            !arch::is_kernel_port(port, width as u16)
+    }
+
+    /// Read `width` bytes, 1, 2 or 4, from `port`. Returns `None` if
+    /// the access is not allowed.
+    pub fn read(&self, port: u16, width: u8) -> Option<u32> {
+        if !self.allows(port, width) {
+            return None;
+        }
+
+        Some(unsafe {
+            match width {
+                1 => inportb(port) as u32,
+                2 => inportw(port) as u32,
+                _ => inportl(port),
+            }
+        })
+    }
+
+    /// Write the low `width` bytes, 1, 2 or 4, of `value` to
+    /// `port`. Returns `false` if the access is not allowed.
+    pub fn write(&self, port: u16, width: u8, value: u32) -> bool {
+        if !self.allows(port, width) {
+            return false;
+        }
+
+        unsafe {
+            match width {
+                1 => outportb(port, value as u8),
+                2 => outportw(port, value as u16),
+                _ => outportl(port, value),
+            }
+        }
+        true
+    }
+}
+
+impl Derived for IoPortDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
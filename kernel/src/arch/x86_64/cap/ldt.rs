@@ -0,0 +1,64 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use arch::segmentation::{Ldt, SegmentDescriptor};
+use cap::{UntypedDescriptor, Derived};
+
+/// LDT descriptor.
+#[derive(Debug)]
+pub struct LdtDescriptor {
+    ldt: Ldt,
+    next: Option<ManagedArcAny>,
+}
+
+/// LDT capability. Reference-counted smart pointer to LDT descriptor.
+///
+/// Tasks the LDT is installed in can load its entries with selectors
+/// that have `TI_LDT` set, to run code needing segments of its own.
+pub type LdtCap = ManagedArc<RwLock<LdtDescriptor>>;
+
+impl LdtCap {
+    /// Create an LDT capability from an untyped capability. All its
+    /// entries are null.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(LdtDescriptor {
+                    ldt: Ldt::empty(),
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+}
+
+impl LdtDescriptor {
+    /// Set entry `index` to the raw segment descriptor `descriptor`, or
+    /// clear it if zero. Returns `false` if the entry does not exist or
+    /// the descriptor is refused, as `Ldt::set`. Tasks running on other
+    /// CPUs see the change at their next switch.
+    pub fn set_entry(&mut self, index: usize, descriptor: u64) -> bool {
+        self.ldt.set(index, SegmentDescriptor::from_raw(descriptor))
+    }
+
+    /// Load the LDT on the current CPU.
+    pub fn load(&self) {
+        unsafe { ::arch::init::load_ldt(Some(&self.ldt)) }
+    }
+}
+
+impl Derived for LdtDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(_arc: &LdtCap) {
+        // The LDT may still be loaded from the last task switch.
+        unsafe { ::arch::load_ldt(None) }
+    }
+}
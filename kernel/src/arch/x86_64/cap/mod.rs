@@ -8,6 +8,18 @@ macro_rules! doto_arch_any {
             $f ($any.into(): ::arch::cap::PDCap, $($param),*)
         } else if $any.is::<::arch::cap::PTCap>() {
             $f ($any.into(): ::arch::cap::PTCap, $($param),*)
+        } else if $any.is::<::arch::cap::LargePageCap>() {
+            $f ($any.into(): ::arch::cap::LargePageCap, $($param),*)
+        } else if $any.is::<::arch::cap::VSpaceCap>() {
+            $f ($any.into(): ::arch::cap::VSpaceCap, $($param),*)
+        } else if $any.is::<::arch::cap::LdtCap>() {
+            $f ($any.into(): ::arch::cap::LdtCap, $($param),*)
+        } else if $any.is::<::arch::cap::IoPortCap>() {
+            $f ($any.into(): ::arch::cap::IoPortCap, $($param),*)
+        } else if $any.is::<::arch::cap::AsidControlCap>() {
+            $f ($any.into(): ::arch::cap::AsidControlCap, $($param),*)
+        } else if $any.is::<::arch::cap::AsidPoolCap>() {
+            $f ($any.into(): ::arch::cap::AsidPoolCap, $($param),*)
         } else {
             panic!();
         }
@@ -16,20 +28,36 @@ macro_rules! doto_arch_any {
 
 /// Paging-related arch-specific capabilities.
 mod paging;
+/// Local descriptor table capability.
+mod ldt;
+/// I/O port range capability.
+mod ioport;
+/// Address space identifier capabilities.
+mod asid;
 
 pub use self::paging::{PML4Descriptor, PML4Cap,
                        PDPTDescriptor, PDPTCap,
                        PDDescriptor, PDCap,
                        PTDescriptor, PTCap,
                        PageDescriptor, PageCap,
-                       PAGE_LENGTH};
+                       LargePageDescriptor, LargePageCap,
+                       VSpaceDescriptor, VSpaceCap,
+                       PageFaultResult,
+                       PAGE_LENGTH, LARGE_PAGE_SPLIT_COUNT, unmap_region};
+pub use self::ldt::{LdtDescriptor, LdtCap};
+pub use self::ioport::{IoPortDescriptor, IoPortCap};
+pub use self::asid::{AsidControlDescriptor, AsidControlCap, AsidPoolDescriptor, AsidPoolCap};
 
 /// The top-level page table capability. In `x86_64`, this is PML4.
 pub type TopPageTableCap = PML4Cap;
 
 use common::*;
 use core::any::{TypeId};
+use core::ops::DerefMut;
 use util::managed_arc::{ManagedArc, ManagedArcAny};
+use arch::paging::LARGE_PAGE_LENGTH;
+use cap::UntypedCap;
+use abi::{ObjectType, CapType};
 
 /// Create a managed Arc (capability) from an address of an
 /// architecture-specific kernel object. The `type_id` should be a
@@ -50,11 +78,80 @@ pub unsafe fn upgrade_arch_any(ptr: PAddr, type_id: TypeId) -> Option<ManagedArc
         Some({ ManagedArc::from_ptr(ptr): PDCap }.into())
     } else if type_id == TypeId::of::<PTCap>() {
         Some({ ManagedArc::from_ptr(ptr): PTCap }.into())
+    } else if type_id == TypeId::of::<LargePageCap>() {
+        Some({ ManagedArc::from_ptr(ptr): LargePageCap }.into())
+    } else if type_id == TypeId::of::<VSpaceCap>() {
+        Some({ ManagedArc::from_ptr(ptr): VSpaceCap }.into())
+    } else if type_id == TypeId::of::<LdtCap>() {
+        Some({ ManagedArc::from_ptr(ptr): LdtCap }.into())
+    } else if type_id == TypeId::of::<IoPortCap>() {
+        Some({ ManagedArc::from_ptr(ptr): IoPortCap }.into())
+    } else if type_id == TypeId::of::<AsidControlCap>() {
+        Some({ ManagedArc::from_ptr(ptr): AsidControlCap }.into())
+    } else if type_id == TypeId::of::<AsidPoolCap>() {
+        Some({ ManagedArc::from_ptr(ptr): AsidPoolCap }.into())
     } else {
         None
     }
 }
 
+/// Type of the architecture-specific capability with the given
+/// `type_id`. This function is used by `kernel::cap::cap_type`. If the
+/// `type_id` is not recognized, `None` is returned.
+pub fn arch_cap_type(type_id: TypeId) -> Option<CapType> {
+    if type_id == TypeId::of::<PML4Cap>() {
+        Some(CapType::PML4)
+    } else if type_id == TypeId::of::<PDPTCap>() {
+        Some(CapType::PDPT)
+    } else if type_id == TypeId::of::<PDCap>() {
+        Some(CapType::PD)
+    } else if type_id == TypeId::of::<PTCap>() {
+        Some(CapType::PT)
+    } else if type_id == TypeId::of::<LargePageCap>() {
+        Some(CapType::LargePage)
+    } else if type_id == TypeId::of::<VSpaceCap>() {
+        Some(CapType::VSpace)
+    } else if type_id == TypeId::of::<LdtCap>() {
+        Some(CapType::Ldt)
+    } else if type_id == TypeId::of::<IoPortCap>() {
+        Some(CapType::IoPort)
+    } else if type_id == TypeId::of::<AsidControlCap>() {
+        Some(CapType::AsidControl)
+    } else if type_id == TypeId::of::<AsidPoolCap>() {
+        Some(CapType::AsidPool)
+    } else {
+        None
+    }
+}
+
+/// Create an architecture-specific object of type `object` from an
+/// untyped capability. This function is used by
+/// `kernel::cap::retype_any`. `size_bits` must be 0, or the natural
+/// size of the object. Returns `None` if the size or the type is
+/// invalid.
+pub fn retype_arch_any(untyped: &UntypedCap, object: ObjectType, size_bits: usize) -> Option<ManagedArcAny> {
+    let natural_bits = match object {
+        ObjectType::LargePage => LARGE_PAGE_LENGTH.trailing_zeros() as usize,
+        ObjectType::PML4 | ObjectType::PDPT | ObjectType::PD | ObjectType::PT =>
+            PAGE_LENGTH.trailing_zeros() as usize,
+        _ => 0,
+    };
+    if size_bits != 0 && size_bits != natural_bits {
+        return None;
+    }
+
+    let mut untyped = untyped.write();
+    match object {
+        ObjectType::LargePage => Some(LargePageCap::retype_from(untyped.deref_mut()).into()),
+        ObjectType::PML4 => Some(PML4Cap::retype_from(untyped.deref_mut()).into()),
+        ObjectType::PDPT => Some(PDPTCap::retype_from(untyped.deref_mut()).into()),
+        ObjectType::PD => Some(PDCap::retype_from(untyped.deref_mut()).into()),
+        ObjectType::PT => Some(PTCap::retype_from(untyped.deref_mut()).into()),
+        ObjectType::Ldt => Some(LdtCap::retype_from(untyped.deref_mut()).into()),
+        _ => None,
+    }
+}
+
 /// Drop an architecture-specific `any` capability. `ManagedArcAny` is
 /// not itself droppable. It must be converted to its real type before
 /// dropping. This function is used by `kernel::cap::drop_any`.
@@ -67,6 +164,18 @@ pub fn drop_any(any: ManagedArcAny) {
         any.into(): PDCap;
     } else if any.is::<PTCap>() {
         any.into(): PTCap;
+    } else if any.is::<LargePageCap>() {
+        any.into(): LargePageCap;
+    } else if any.is::<VSpaceCap>() {
+        any.into(): VSpaceCap;
+    } else if any.is::<LdtCap>() {
+        any.into(): LdtCap;
+    } else if any.is::<IoPortCap>() {
+        any.into(): IoPortCap;
+    } else if any.is::<AsidControlCap>() {
+        any.into(): AsidControlCap;
+    } else if any.is::<AsidPoolCap>() {
+        any.into(): AsidPoolCap;
     } else {
         panic!();
     }
@@ -0,0 +1,119 @@
+use common::*;
+use arch::cache;
+use util::MemoryObject;
+use core::any::Any;
+use core::cmp;
+use abi::CacheOperation;
+use super::{PML4Descriptor, PageDescriptor, LargePageDescriptor, PAGE_LENGTH};
+use cap::{RawPage, SetDefault};
+
+/// Run a cache maintenance operation on `length` bytes at `offset` in
+/// the frame at `paddr`, within one page.
+///
+/// # Safety
+///
+/// The range must lie within the page.
+unsafe fn maintain_page(paddr: PAddr, offset: usize, length: usize, operation: CacheOperation) {
+    // Cache lines are physically tagged, so flushing through the
+    // kernel's own mapping of the frame reaches every other mapping's
+    // data.
+    let object = MemoryObject::<RawPage>::new(paddr);
+    let start = object.as_ptr() as usize + offset;
+
+    match operation {
+        CacheOperation::Clean =>
+            cache::clean_range(start, length),
+        CacheOperation::Invalidate | CacheOperation::CleanInvalidate =>
+            cache::clean_invalidate_range(start, length),
+    }
+}
+
+/// Run a cache maintenance operation on `length` bytes at `offset` in
+/// the physically contiguous frames of `frames_length` bytes at
+/// `paddr`. Returns `false` if the range does not fit.
+fn maintain_frames(paddr: PAddr, frames_length: usize, offset: usize, length: usize,
+                   operation: CacheOperation) -> bool {
+    let end = match offset.checked_add(length) {
+        Some(end) if end <= frames_length => end,
+        _ => return false,
+    };
+
+    if length >= cache::WBINVD_THRESHOLD {
+        unsafe { cache::wbinvd(); }
+        return true;
+    }
+
+    let mut page = offset - offset % PAGE_LENGTH;
+    while page < end {
+        let range_start = cmp::max(page, offset);
+        let range_end = cmp::min(page + PAGE_LENGTH, end);
+        unsafe {
+            maintain_page(paddr + page, range_start - page, range_end - range_start, operation);
+        }
+        page += PAGE_LENGTH;
+    }
+
+    true
+}
+
+impl PML4Descriptor {
+    /// Run a cache maintenance operation on a virtual range of this
+    /// address space. Every page in the range must be mapped and
+    /// accessible from user mode, otherwise nothing is done and
+    /// `false` is returned.
+    pub fn cache_maintenance(&self, vaddr: VAddr, length: usize, operation: CacheOperation) -> bool {
+        let start = vaddr.into(): usize;
+        let end = match start.checked_add(length) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        let first_page = start - start % PAGE_LENGTH;
+        let mut page = first_page;
+        while page < end {
+            match self.lookup(VAddr::from(page)) {
+                Some((_, ref attributes)) if attributes.user => (),
+                _ => return false,
+            }
+            page += PAGE_LENGTH;
+        }
+
+        if length >= cache::WBINVD_THRESHOLD {
+            unsafe { cache::wbinvd(); }
+            return true;
+        }
+
+        let mut page = first_page;
+        while page < end {
+            let range_start = cmp::max(page, start);
+            let range_end = cmp::min(page + PAGE_LENGTH, end);
+            let (paddr, _) = self.lookup(VAddr::from(page)).unwrap();
+
+            unsafe {
+                maintain_page(paddr, range_start - page, range_end - range_start, operation);
+            }
+
+            page += PAGE_LENGTH;
+        }
+
+        true
+    }
+}
+
+impl<T: SetDefault + Any> PageDescriptor<T> {
+    /// Run a cache maintenance operation on `length` bytes at
+    /// `offset` in the page, wherever it is mapped. Returns `false`
+    /// if the range is not within the page.
+    pub fn cache_maintenance(&self, offset: usize, length: usize, operation: CacheOperation) -> bool {
+        maintain_frames(self.start_paddr, PAGE_LENGTH, offset, length, operation)
+    }
+}
+
+impl LargePageDescriptor {
+    /// Run a cache maintenance operation on `length` bytes at
+    /// `offset` in the large page, wherever it is mapped. Returns
+    /// `false` if the range is not within the large page.
+    pub fn cache_maintenance(&self, offset: usize, length: usize, operation: CacheOperation) -> bool {
+        maintain_frames(self.start_paddr, self.length(), offset, length, operation)
+    }
+}
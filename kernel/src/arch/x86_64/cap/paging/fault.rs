@@ -0,0 +1,296 @@
+use common::*;
+use arch::paging::{PDPT, PD, PT, PDEntry, PTEntry, PT_P, PT_RW, PT_TRACKED,
+                   pml4_index, pdpt_index, pd_index, pt_index, flush, flush_range_all_cpus};
+use arch::interrupt::PageFaultError;
+use util::{MemoryObject};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{self, Ordering};
+use abi::{SoftDirtyRing, SOFT_DIRTY_RING_ENTRIES};
+use super::{PML4Descriptor, PML4Cap, PTCap, PAGE_LENGTH};
+use cap::{self, UntypedCap, CPoolCap, CPoolDescriptor, RawPage, RawPageCap};
+
+/// Outcome of a page fault in a user address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultResult {
+    /// The fault was resolved, and the faulting instruction can be
+    /// restarted.
+    Resolved,
+    /// The fault hit the guard page below a stack.
+    StackOverflow,
+    /// The fault cannot be resolved by the kernel.
+    Unhandled,
+}
+
+impl From<bool> for PageFaultResult {
+    fn from(resolved: bool) -> PageFaultResult {
+        if resolved { PageFaultResult::Resolved } else { PageFaultResult::Unhandled }
+    }
+}
+
+impl PML4Cap {
+    /// Try to resolve a page fault at `vaddr` in this address space.
+    pub fn handle_page_fault(&self, vaddr: VAddr, error: PageFaultError) -> PageFaultResult {
+        if !error.is_user() || error.is_reserved() {
+            return PageFaultResult::Unhandled;
+        }
+
+        if error.is_present() && error.is_write() {
+            if self.resolve_soft_dirty(vaddr) {
+                return PageFaultResult::Resolved;
+            }
+
+            return self.resolve_cow(vaddr).into();
+        }
+
+        if !error.is_present() {
+            if self.read().pt_entry(vaddr).map(|entry| entry.is_guard()).unwrap_or(false) {
+                return PageFaultResult::StackOverflow;
+            }
+
+            return self.resolve_demand_zero(vaddr).into();
+        }
+
+        PageFaultResult::Unhandled
+    }
+
+    /// Fill a reserved demand-zero entry with a zeroed frame from the
+    /// fault pool.
+    fn resolve_demand_zero(&self, vaddr: VAddr) -> bool {
+        let (untyped, cpool) = match self.read().upgrade_fault_pool() {
+            Some(pool) => pool,
+            None => return false,
+        };
+
+        let mut pt_cap = match self.read().lookup_pt(vaddr, cpool.read().deref()) {
+            Some(pt_cap) => pt_cap,
+            None => return false,
+        };
+
+        let index = pt_index(vaddr);
+        let entry = { pt_cap.read().read()[index] };
+        if entry.is_present() {
+            // Already filled, possibly from another CPU.
+            return true;
+        }
+
+        if !entry.is_demand_zero() {
+            return false;
+        }
+
+        if !untyped.read().can_retype(PAGE_LENGTH) {
+            return false;
+        }
+
+        // Raw pages are zeroed on retype.
+        let page = RawPageCap::retype_from(untyped.write().deref_mut());
+        cpool.read().downgrade_free(&page);
+        pt_cap.map_page(index, &page);
+
+        true
+    }
+
+    /// Give a page write-protected for soft-dirty tracking its write
+    /// access back, and log it as written. Returns `false` if the page
+    /// is not tracked, which includes it having been resolved from
+    /// another CPU already.
+    fn resolve_soft_dirty(&self, vaddr: VAddr) -> bool {
+        let desc = self.read();
+        let pd_entry = match desc.pd_entry(vaddr) {
+            Some(pd_entry) => pd_entry,
+            None => return false,
+        };
+
+        let tracked = (PT_P | PT_TRACKED).bits() as usize;
+        let resolved = unsafe {
+            let mut pt = MemoryObject::<PT>::new(pd_entry.get_address());
+            let atomic = pt.as_mut()[pt_index(vaddr)].as_atomic();
+            // Swap both bits at once, so that only one CPU logs the
+            // page, and no CPU sees it neither tracked nor writable.
+            loop {
+                let old = atomic.load(Ordering::SeqCst);
+                if old & tracked != tracked {
+                    break false;
+                }
+                let new = (old & !(PT_TRACKED.bits() as usize)) | PT_RW.bits() as usize;
+                if atomic.compare_and_swap(old, new, Ordering::SeqCst) == old {
+                    break true;
+                }
+            }
+        };
+        if !resolved {
+            return false;
+        }
+
+        let page_vaddr = VAddr::from(vaddr.into(): usize & !(PAGE_LENGTH - 1));
+        desc.log_soft_dirty(page_vaddr);
+        // Other CPUs only cache the page read-only, and fault in.
+        unsafe { flush(page_vaddr); }
+
+        true
+    }
+
+    /// Copy a copy-on-write page to a fresh frame from the fault
+    /// pool, and map the copy writable. Every address space sharing
+    /// the page makes its own copy on its first write, and the
+    /// original frame is left to its capability.
+    fn resolve_cow(&self, vaddr: VAddr) -> bool {
+        let (untyped, cpool) = match self.read().upgrade_fault_pool() {
+            Some(pool) => pool,
+            None => return false,
+        };
+
+        let mut pt_cap = match self.read().lookup_pt(vaddr, cpool.read().deref()) {
+            Some(pt_cap) => pt_cap,
+            None => return false,
+        };
+
+        let index = pt_index(vaddr);
+        let page_vaddr = VAddr::from(vaddr.into(): usize & !(PAGE_LENGTH - 1));
+        let entry = { pt_cap.read().read()[index] };
+        if !entry.is_present() {
+            return false;
+        }
+
+        if entry.is_writeable() {
+            // Already resolved, possibly from another CPU. Only the
+            // stale TLB entry is left.
+            unsafe { flush(page_vaddr); }
+            return true;
+        }
+
+        if !entry.is_cow() || !untyped.read().can_retype(PAGE_LENGTH) {
+            return false;
+        }
+
+        let page = RawPageCap::retype_from(untyped.write().deref_mut());
+        {
+            let source = unsafe { MemoryObject::<RawPage>::new(entry.get_address()) };
+            let mut page_desc = page.write();
+            let mut target = page_desc.write();
+            target.0.copy_from_slice(unsafe { &source.as_ref().0 });
+        }
+        cpool.read().downgrade_free(&page);
+
+        pt_cap.remap_page(index, &page);
+        unsafe { flush_range_all_cpus(page_vaddr, PAGE_LENGTH); }
+
+        true
+    }
+}
+
+impl PML4Descriptor {
+    /// Set the untyped capability that frames are allocated from when
+    /// resolving page faults, and the capability pool that receives
+    /// their capabilities. Replaces those set by an earlier mapping.
+    pub fn downgrade_fault_pool(&self, untyped: &UntypedCap, cpool: &CPoolCap) {
+        let pool = self.fault_weak_pool.read();
+        pool.remove(0);
+        pool.remove(1);
+        pool.downgrade_at(untyped, 0);
+        pool.downgrade_at(cpool, 1);
+    }
+
+    /// Set the page, holding a `SoftDirtyRing`, that pages written
+    /// while tracked are logged into.
+    pub fn set_soft_dirty_ring(&self, ring: PAddr) {
+        *self.soft_dirty_ring.lock() = Some(ring);
+    }
+
+    /// Log the page at `vaddr` as written, or count it as dropped if
+    /// the ring is full.
+    fn log_soft_dirty(&self, vaddr: VAddr) {
+        let ring_paddr = self.soft_dirty_ring.lock();
+        let ring_paddr = match *ring_paddr {
+            Some(ring_paddr) => ring_paddr,
+            None => return,
+        };
+
+        unsafe {
+            let mut ring = MemoryObject::<SoftDirtyRing>::new(ring_paddr);
+            let ring = ring.as_mut();
+            // User-space changes `tail` concurrently, and may set it
+            // to anything; a bogus one only makes the ring look full.
+            let head = ptr::read_volatile(&ring.head);
+            let tail = ptr::read_volatile(&ring.tail);
+            if head.wrapping_sub(tail) < SOFT_DIRTY_RING_ENTRIES as u64 {
+                let index = (head % SOFT_DIRTY_RING_ENTRIES as u64) as usize;
+                ptr::write_volatile(&mut ring.entries[index], vaddr.into(): usize as u64);
+                atomic::fence(Ordering::Release);
+                ptr::write_volatile(&mut ring.head, head.wrapping_add(1));
+            } else {
+                let dropped = ptr::read_volatile(&ring.dropped);
+                ptr::write_volatile(&mut ring.dropped, dropped.wrapping_add(1));
+            }
+        }
+    }
+
+    /// Read the fault pool of this address space.
+    pub fn upgrade_fault_pool(&self) -> Option<(UntypedCap, CPoolCap)> {
+        let untyped: Option<UntypedCap> = self.fault_weak_pool.read().upgrade(0);
+        let cpool: Option<CPoolCap> = self.fault_weak_pool.read().upgrade(1);
+
+        match (untyped, cpool) {
+            (Some(untyped), Some(cpool)) => Some((untyped, cpool)),
+            _ => None,
+        }
+    }
+
+    /// Find the page table capability covering `vaddr` in
+    /// `cpool`. Returns `None` if no page table is mapped there.
+    fn lookup_pt(&self, vaddr: VAddr, cpool: &CPoolDescriptor) -> Option<PTCap> {
+        let pd_entry = match self.pd_entry(vaddr) {
+            Some(pd_entry) => pd_entry,
+            None => return None,
+        };
+
+        for i in 0..cpool.size() {
+            if let Some(any) = cpool.upgrade_any(i) {
+                if any.is::<PTCap>() {
+                    let pt_cap: PTCap = any.into();
+                    if pt_cap.read().start_paddr() == pd_entry.get_address() {
+                        return Some(pt_cap);
+                    }
+                } else {
+                    cap::drop_any(any);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Read the page table entry covering `vaddr`, present or
+    /// not. Returns `None` if no page table is mapped there.
+    fn pt_entry(&self, vaddr: VAddr) -> Option<PTEntry> {
+        self.pd_entry(vaddr).map(|pd_entry| unsafe {
+            MemoryObject::<PT>::new(pd_entry.get_address()).as_ref()[pt_index(vaddr)]
+        })
+    }
+
+    /// Read the page directory entry referring to the page table
+    /// covering `vaddr`. Returns `None` if there is none, or if
+    /// `vaddr` is in a large page.
+    fn pd_entry(&self, vaddr: VAddr) -> Option<PDEntry> {
+        let pml4_entry = { self.read()[pml4_index(vaddr)] };
+        if !pml4_entry.is_present() {
+            return None;
+        }
+
+        let pdpt_entry = unsafe {
+            MemoryObject::<PDPT>::new(pml4_entry.get_address()).as_ref()[pdpt_index(vaddr)]
+        };
+        if !pdpt_entry.is_present() {
+            return None;
+        }
+
+        let pd_entry = unsafe {
+            MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref()[pd_index(vaddr)]
+        };
+        if !pd_entry.is_present() || pd_entry.is_page() {
+            return None;
+        }
+
+        Some(pd_entry)
+    }
+}
@@ -0,0 +1,212 @@
+use common::*;
+use arch::paging::{BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH};
+use util::{RwLock, align_up};
+use util::managed_arc::{ManagedWeakPool1Arc, ManagedArcAny};
+use core::any::TypeId;
+use core::marker::{PhantomData};
+use super::{LargePageDescriptor, LargePageCap, PageDescriptor, PDCap, flush_user_all};
+use cap::{self, UntypedDescriptor, UntypedCap, RawPageCap, Derived};
+use meminfo::MemoryCategory;
+use abi::CacheMode;
+
+/// Number of base pages in a large page.
+pub const LARGE_PAGE_SPLIT_COUNT: usize = LARGE_PAGE_LENGTH / BASE_PAGE_LENGTH;
+
+impl LargePageCap {
+    /// Create a large page capability from an untyped capability. The
+    /// backing memory is aligned to the large page length.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let start_paddr = unsafe { untyped.allocate_as(LARGE_PAGE_LENGTH, LARGE_PAGE_LENGTH,
+                                                           MemoryCategory::UserFrame) };
+
+        let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        let untyped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe {
+            untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    Self::new(paddr, RwLock::new(LargePageDescriptor {
+                        mapped_weak_pool: mapped_weak_pool,
+                        start_paddr: start_paddr,
+                        first_child: None,
+                        children_paddr: PAddr::from(0: usize),
+                        untyped_weak_pool: untyped_weak_pool,
+                        next: next_child,
+                    }))
+                );
+
+                arc.clone().unwrap().into()
+            });
+        }
+
+        arc.unwrap()
+    }
+
+    /// Memory `split` takes from an untyped region, in whole pages
+    /// so that `merge` can give it back.
+    pub fn split_length() -> usize {
+        let length = LARGE_PAGE_SPLIT_COUNT * (RawPageCap::inner_length() + RawPageCap::inner_alignment() +
+                                               ManagedWeakPool1Arc::inner_length() +
+                                               ManagedWeakPool1Arc::inner_alignment());
+        (length + BASE_PAGE_LENGTH - 1) / BASE_PAGE_LENGTH * BASE_PAGE_LENGTH
+    }
+
+    /// Split the large page into `LARGE_PAGE_SPLIT_COUNT` raw page
+    /// capabilities covering the same memory. The children are
+    /// recorded in the large page's derivation list, and passed in
+    /// order to `f`. Memory for the child descriptors is taken from
+    /// `untyped`, and given back by `merge`. Returns `false` if the
+    /// large page is mapped or already split.
+    pub fn split<F: FnMut(usize, &RawPageCap)>(&self, untyped: &UntypedCap, mut f: F) -> bool {
+        let mut desc = self.write();
+        if desc.is_mapped() || desc.is_split() {
+            return false;
+        }
+
+        let children_paddr = unsafe {
+            untyped.write().allocate_as(Self::split_length(), BASE_PAGE_LENGTH,
+                                        MemoryCategory::KernelObject)
+        };
+        desc.children_paddr = children_paddr;
+        {
+            let pool = desc.untyped_weak_pool.read();
+            pool.remove(0);
+            pool.downgrade_at(untyped, 0);
+        }
+
+        // Build the list backwards so that `first_child` is the page
+        // at the lowest address.
+        let mut next_paddr = children_paddr;
+        for i in (0..LARGE_PAGE_SPLIT_COUNT).rev() {
+            let page_paddr = desc.start_paddr + i * BASE_PAGE_LENGTH;
+
+            let page = unsafe {
+                let pool_paddr = align_up(next_paddr, ManagedWeakPool1Arc::inner_alignment());
+                let paddr = align_up(pool_paddr + ManagedWeakPool1Arc::inner_length(),
+                                     RawPageCap::inner_alignment());
+                next_paddr = paddr + RawPageCap::inner_length();
+                let mapped_weak_pool = ManagedWeakPool1Arc::create(pool_paddr);
+
+                RawPageCap::new(paddr, RwLock::new(PageDescriptor {
+                    mapped_weak_pool: mapped_weak_pool,
+                    start_paddr: page_paddr,
+                    uncached: false,
+                    cache_mode: CacheMode::WriteBack,
+                    next: desc.first_child.take(),
+                    _marker: PhantomData,
+                }))
+            };
+
+            cap::object_created(TypeId::of::<RawPageCap>());
+            f(i, &page);
+            desc.first_child = Some(page.into());
+        }
+
+        true
+    }
+
+    /// Merge a split large page back, and give the memory of the
+    /// child descriptors back to the untyped capability `split` took
+    /// it from. This only succeeds when all children have been
+    /// deleted, i.e. no capability pool refers to them and nothing
+    /// else holds them.
+    pub fn merge(&self) -> bool {
+        let mut desc = self.write();
+        if !desc.is_split() || !desc.children_deleted() {
+            return false;
+        }
+
+        let mut next = desc.first_child.take();
+        while let Some(any) = next {
+            let page: RawPageCap = any.into();
+            next = page.write().next.take();
+            cap::object_destroyed(TypeId::of::<RawPageCap>());
+        }
+
+        let untyped: Option<UntypedCap> = desc.untyped_weak_pool.read().upgrade(0);
+        if let Some(untyped) = untyped {
+            let mut untyped_desc = untyped.write();
+            for i in 0..(Self::split_length() / BASE_PAGE_LENGTH) {
+                unsafe {
+                    untyped_desc.free_page(desc.children_paddr + i * BASE_PAGE_LENGTH,
+                                           MemoryCategory::KernelObject);
+                }
+            }
+        }
+        desc.untyped_weak_pool.read().remove(0);
+
+        true
+    }
+}
+
+impl LargePageDescriptor {
+    pub fn start_paddr(&self) -> PAddr {
+        self.start_paddr
+    }
+
+    pub fn length(&self) -> usize {
+        LARGE_PAGE_LENGTH
+    }
+
+    /// Whether the large page is mapped in a page directory.
+    pub fn is_mapped(&self) -> bool {
+        self.mapped_weak_pool.read().is_occupied(0)
+    }
+
+    /// Whether the large page has been split into base pages.
+    pub fn is_split(&self) -> bool {
+        self.first_child.is_some()
+    }
+
+    /// Check that no child page is referenced from outside the
+    /// derivation list.
+    fn children_deleted(&self) -> bool {
+        let mut current: Option<RawPageCap> =
+            self.first_child.as_ref().map(|any| any.clone_typed());
+
+        while let Some(page) = current {
+            // One reference is held by the derivation list, the other
+            // by `current`.
+            if page.lead_count() > 2 || page.is_weakly_referenced() {
+                return false;
+            }
+
+            current = page.read().next.as_ref().map(|any| any.clone_typed());
+        }
+
+        true
+    }
+}
+
+impl Derived for LargePageDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &LargePageCap) {
+        {
+            let desc = arc.read();
+            let parent: Option<PDCap> = desc.mapped_weak_pool.read().upgrade(0);
+            if let Some(parent) = parent {
+                parent.write().unmap_paddr(desc.start_paddr);
+                unsafe { flush_user_all(); }
+            }
+            desc.mapped_weak_pool.read().clear();
+        }
+
+        // Split pages cover the same memory, so they go with it.
+        let first_child = arc.write().first_child.take();
+        let kept = cap::revoke_list(first_child);
+        arc.write().first_child = kept;
+    }
+
+    fn is_destroyable(&self) -> bool {
+        self.first_child.is_none()
+    }
+}
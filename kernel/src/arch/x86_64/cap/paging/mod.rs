@@ -1,25 +1,55 @@
 mod page;
+mod large;
 mod pml4;
+mod fault;
+mod cache;
+mod vspace;
+
+pub use self::large::LARGE_PAGE_SPLIT_COUNT;
+pub use self::fault::PageFaultResult;
+pub use self::pml4::{unmap_region, is_region_mapped};
 
 use common::*;
-use arch::paging::{BASE_PAGE_LENGTH,
-                   PT, PTEntry, PT_P, PT_RW, PT_US,
-                   PD, PDEntry, PD_P, PD_RW, PD_US,
-                   PDPT, PDPTEntry, PDPT_P, PDPT_RW, PDPT_US};
-use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
-use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use arch::USER_END;
+use arch::paging::{BASE_PAGE_LENGTH, Asid,
+                   PT, PTEntry, PT_P, PT_RW, PT_US, PT_PWT, PT_PCD, PT_COW, PT_ZERO, PT_GUARD,
+                   PD, PDEntry, PD_P, PD_RW, PD_US, PD_PS,
+                   PDPT, PDPTEntry, PDPT_P, PDPT_RW, PDPT_US, flush_range_all_cpus};
+use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock, Mutex};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc, ManagedWeakPool3Arc};
 use core::marker::{PhantomData};
 use core::any::{Any};
-use cap::{UntypedDescriptor, SetDefault};
+use abi::CacheMode;
+use cap::{UntypedDescriptor, SetDefault, Derived};
+use meminfo::MemoryCategory;
 
 /// Page length used in current kernel. This is `BASE_PAGE_LENGTH` in x86_64.
 pub const PAGE_LENGTH: usize = BASE_PAGE_LENGTH;
 
+/// Page table entry flags for the caching of a page mapping.
+fn cache_flags(mode: CacheMode) -> PTEntry {
+    match mode {
+        CacheMode::WriteBack => PTEntry::empty(),
+        CacheMode::WriteThrough => PT_PWT,
+        CacheMode::Uncached => PT_PWT | PT_PCD,
+    }
+}
+
 /// PML4 page table descriptor.
 pub struct PML4Descriptor {
     start_paddr: PAddr,
-    #[allow(dead_code)]
+    /// Isolated page table used while user code runs, with only the
+    /// trampoline mapped in the kernel half.
+    #[cfg(feature="kpti")]
+    user_paddr: PAddr,
+    asid: Option<Asid>,
+    /// Untyped and capability pool used to resolve page faults.
+    fault_weak_pool: ManagedWeakPool3Arc,
+    /// Page holding the `SoftDirtyRing` that tracked writes are
+    /// logged into.
+    soft_dirty_ring: Mutex<Option<PAddr>>,
     next: Option<ManagedArcAny>,
+    next_pml4: Option<PML4Cap>,
 }
 
 /// PML4 page table capability.
@@ -30,7 +60,6 @@ pub type PML4Cap = ManagedArc<RwLock<PML4Descriptor>>;
 pub struct PDPTDescriptor {
     mapped_weak_pool: ManagedWeakPool1Arc,
     start_paddr: PAddr,
-    #[allow(dead_code)]
     next: Option<ManagedArcAny>,
 }
 
@@ -42,7 +71,6 @@ pub type PDPTCap = ManagedArc<RwLock<PDPTDescriptor>>;
 pub struct PDDescriptor {
     mapped_weak_pool: ManagedWeakPool1Arc,
     start_paddr: PAddr,
-    #[allow(dead_code)]
     next: Option<ManagedArcAny>,
 }
 
@@ -54,7 +82,6 @@ pub type PDCap = ManagedArc<RwLock<PDDescriptor>>;
 pub struct PTDescriptor {
     mapped_weak_pool: ManagedWeakPool1Arc,
     start_paddr: PAddr,
-    #[allow(dead_code)]
     next: Option<ManagedArcAny>,
 }
 
@@ -65,7 +92,10 @@ pub type PTCap = ManagedArc<RwLock<PTDescriptor>>;
 pub struct PageDescriptor<T: SetDefault + Any> {
     mapped_weak_pool: ManagedWeakPool1Arc,
     start_paddr: PAddr,
-    #[allow(dead_code)]
+    /// Whether the page is device memory, always mapped uncached.
+    uncached: bool,
+    /// Memory type of the page's mappings.
+    cache_mode: CacheMode,
     next: Option<ManagedArcAny>,
     _marker: PhantomData<T>
 }
@@ -73,13 +103,47 @@ pub struct PageDescriptor<T: SetDefault + Any> {
 /// Page capability.
 pub type PageCap<T> = ManagedArc<RwLock<PageDescriptor<T>>>;
 
+/// Large (2 MiB) page descriptor. A large page can either be mapped
+/// as a whole, or be split into 512 base pages, which are then
+/// tracked as its children.
+pub struct LargePageDescriptor {
+    mapped_weak_pool: ManagedWeakPool1Arc,
+    start_paddr: PAddr,
+    first_child: Option<ManagedArcAny>,
+    /// Pages holding the descriptors of the split pages.
+    children_paddr: PAddr,
+    /// Untyped capability `children_paddr` is allocated from.
+    untyped_weak_pool: ManagedWeakPool1Arc,
+    next: Option<ManagedArcAny>,
+}
+
+/// Large page capability.
+pub type LargePageCap = ManagedArc<RwLock<LargePageDescriptor>>;
+
+/// Address space descriptor. Unlike a bare PML4, whose lower paging
+/// structures are capabilities of their own, a VSpace owns every
+/// paging structure below its PML4, and frees them all on `destroy`.
+pub struct VSpaceDescriptor {
+    pml4: PML4Cap,
+    /// Untyped capability the paging structures are allocated from.
+    untyped_weak_pool: ManagedWeakPool1Arc,
+    /// Number of paging structures below the PML4.
+    table_count: usize,
+    next: Option<ManagedArcAny>,
+}
+
+/// Address space capability.
+pub type VSpaceCap = ManagedArc<RwLock<VSpaceDescriptor>>;
+
 macro_rules! paging_cap {
     ( $cap:ty, $desc:tt, $paging:ty, $entry:tt, $map_fn:ident, $sub_cap:ty, $access:expr ) => (
         impl $cap {
             pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
                 let mut arc: Option<Self> = None;
 
-                let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
+                let start_paddr = unsafe {
+                    untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, MemoryCategory::PageTable)
+                };
 
                 let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
                     untyped.allocate(ManagedWeakPool1Arc::inner_length(),
@@ -139,18 +203,72 @@ macro_rules! paging_cap {
             fn write(&mut self) -> UniqueWriteGuard<$paging> {
                 unsafe { UniqueWriteGuard::new(self.page_object()) }
             }
+
+            /// Clear the entries pointing to `paddr`. The caller is
+            /// responsible for flushing the TLB.
+            fn unmap_paddr(&mut self, paddr: PAddr) {
+                for item in self.write().iter_mut() {
+                    if item.is_present() && item.get_address() == paddr {
+                        *item = $entry::empty();
+                    }
+                }
+            }
         }
     )
 }
 
+/// Implement `Derived` for a paging structure or page that records the
+/// table it is mapped in, of type `$parent`. Revoking it unmaps it
+/// from there.
+macro_rules! mapped_derived {
+    ( $desc:ty, $parent:ty ) => (
+        impl Derived for $desc {
+            fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+                &mut self.next
+            }
+
+            fn revoke(arc: &ManagedArc<RwLock<Self>>) {
+                let desc = arc.read();
+                let parent: Option<$parent> = desc.mapped_weak_pool.read().upgrade(0);
+                if let Some(parent) = parent {
+                    parent.write().unmap_paddr(desc.start_paddr);
+                    unsafe { flush_user_all(); }
+                }
+                desc.mapped_weak_pool.read().clear();
+            }
+        }
+    )
+}
+
+/// Invalidate the user translations of every address space on all
+/// CPUs.
+unsafe fn flush_user_all() {
+    flush_range_all_cpus(VAddr::from(0: usize), USER_END);
+}
+
 paging_cap!(PDPTCap, PDPTDescriptor, PDPT, PDPTEntry, map_pd, PDCap, PDPT_P | PDPT_RW | PDPT_US);
 paging_cap!(PDCap, PDDescriptor, PD, PDEntry, map_pt, PTCap, PD_P | PD_RW | PD_US);
 
+impl PDCap {
+    pub fn map_large_page(&mut self, index: usize, sub: &LargePageCap) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        let sub_desc = sub.read();
+        assert!(!current[index].is_present());
+        assert!(!sub_desc.is_split());
+
+        sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        current[index] = PDEntry::new(sub_desc.start_paddr(), PD_P | PD_RW | PD_US | PD_PS);
+    }
+}
+
 impl PTCap {
     pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
         let mut arc: Option<Self> = None;
 
-        let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
+        let start_paddr = unsafe {
+            untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, MemoryCategory::PageTable)
+        };
 
         let mapped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
             untyped.allocate(ManagedWeakPool1Arc::inner_length(),
@@ -186,7 +304,53 @@ impl PTCap {
         assert!(!current[index].is_present());
 
         sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
-        current[index] = PTEntry::new(sub_desc.start_paddr(), PT_P | PT_RW | PT_US);
+        current[index] = PTEntry::new(sub_desc.start_paddr(),
+                                      PT_P | PT_RW | PT_US | cache_flags(sub_desc.cache_mode()));
+    }
+
+    /// Map a page read-only and copy-on-write. The page is copied to
+    /// a fresh frame on the first write through this mapping.
+    pub fn map_page_cow<T: SetDefault + Any>(&mut self, index: usize, sub: &PageCap<T>) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        let sub_desc = sub.read();
+        assert!(!current[index].is_present());
+
+        sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        current[index] = PTEntry::new(sub_desc.start_paddr(),
+                                      PT_P | PT_US | PT_COW | cache_flags(sub_desc.cache_mode()));
+    }
+
+    /// Reserve an entry to be filled with a zeroed page on first
+    /// touch.
+    pub fn reserve_page(&mut self, index: usize) {
+        self.mark_unmapped(index, PT_ZERO);
+    }
+
+    /// Mark an entry as a guard page below a stack.
+    pub fn guard_page(&mut self, index: usize) {
+        self.mark_unmapped(index, PT_GUARD);
+    }
+
+    fn mark_unmapped(&mut self, index: usize, marker: PTEntry) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        assert!(!current[index].is_present());
+
+        current[index] = PTEntry::new(PAddr::from(0: usize), marker);
+    }
+
+    /// Replace an existing mapping with a writable mapping of
+    /// `sub`. The caller is responsible for flushing the TLB.
+    fn remap_page<T: SetDefault + Any>(&mut self, index: usize, sub: &PageCap<T>) {
+        let mut current_desc = self.write();
+        let mut current = current_desc.write();
+        let sub_desc = sub.read();
+        assert!(current[index].is_present());
+
+        sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
+        current[index] = PTEntry::new(sub_desc.start_paddr(),
+                                      PT_P | PT_RW | PT_US | cache_flags(sub_desc.cache_mode()));
     }
 }
 
@@ -210,4 +374,18 @@ impl PTDescriptor {
     fn write(&mut self) -> UniqueWriteGuard<PT> {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
+
+    /// Clear the entries mapping `paddr`. The caller is responsible
+    /// for flushing the TLB.
+    fn unmap_paddr(&mut self, paddr: PAddr) {
+        for item in self.write().iter_mut() {
+            if item.is_present() && item.get_address() == paddr {
+                *item = PTEntry::empty();
+            }
+        }
+    }
 }
+
+mapped_derived!(PDPTDescriptor, PML4Cap);
+mapped_derived!(PDDescriptor, PDPTCap);
+mapped_derived!(PTDescriptor, PDCap);
@@ -1,19 +1,44 @@
 use common::*;
 use arch::paging::{BASE_PAGE_LENGTH};
 use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
-use util::managed_arc::{ManagedWeakPool1Arc};
+use util::managed_arc::{ManagedWeakPool1Arc, ManagedArcAny};
 use core::marker::{PhantomData};
 use core::any::{Any};
 use core::mem;
-use super::{PageDescriptor, PageCap, PAGE_LENGTH};
-use cap::{UntypedDescriptor, SetDefault};
+use super::{PageDescriptor, PageCap, PTCap, PAGE_LENGTH, flush_user_all, is_region_mapped};
+use cap::{UntypedDescriptor, SetDefault, Derived};
+use meminfo::MemoryCategory;
+use abi::{CacheMode, CacheOperation};
 
 impl<T: SetDefault + Any> PageCap<T> {
     pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
-        unsafe { Self::bootstrap(untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH), untyped) }
+        unsafe {
+            let start_paddr = untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH,
+                                                  MemoryCategory::UserFrame);
+            Self::bootstrap(start_paddr, untyped)
+        }
     }
 
     pub unsafe fn bootstrap(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Self {
+        let arc = Self::bootstrap_device(start_paddr, untyped);
+        arc.write().write().set_default();
+        arc
+    }
+
+    /// Create a page capability for `start_paddr` without clearing
+    /// its contents. Used for memory whose contents must be kept,
+    /// like device or persistent memory.
+    pub unsafe fn bootstrap_device(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Self {
+        Self::bootstrap_with(start_paddr, false, untyped)
+    }
+
+    /// Like `bootstrap_device`, but the page is always mapped
+    /// uncached. Used for memory-mapped device registers.
+    pub unsafe fn bootstrap_uncached(start_paddr: PAddr, untyped: &mut UntypedDescriptor) -> Self {
+        Self::bootstrap_with(start_paddr, true, untyped)
+    }
+
+    unsafe fn bootstrap_with(start_paddr: PAddr, uncached: bool, untyped: &mut UntypedDescriptor) -> Self {
         assert!(mem::size_of::<T>() <= PAGE_LENGTH);
 
         let mut arc: Option<Self> = None;
@@ -23,15 +48,15 @@ impl<T: SetDefault + Any> PageCap<T> {
                              ManagedWeakPool1Arc::inner_alignment()));
 
         untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
-            let mut desc = PageDescriptor::<T> {
+            let desc = PageDescriptor::<T> {
                 mapped_weak_pool: mapped_weak_pool,
                 start_paddr: start_paddr,
+                uncached: uncached,
+                cache_mode: if uncached { CacheMode::Uncached } else { CacheMode::WriteBack },
                 next: next_child,
                 _marker: PhantomData
             };
 
-            desc.write().set_default();
-
             arc = Some(
                 Self::new(paddr, RwLock::new(desc))
             );
@@ -45,6 +70,31 @@ impl<T: SetDefault + Any> PageCap<T> {
     pub const fn length() -> usize {
         BASE_PAGE_LENGTH
     }
+
+    /// Change the memory type later mappings of the page get to
+    /// `mode`. The page's cache lines are written back and discarded,
+    /// so that none are left from the old type. Returns `false` while
+    /// the page is mapped anywhere, as other CPUs may still hold the
+    /// old type in their TLBs and caches; unmap it first. Device pages
+    /// stay uncached: returns `false` for any other mode.
+    pub fn set_cache_mode(&self, mode: CacheMode) -> bool {
+        let mut desc = self.write();
+        if desc.uncached && mode != CacheMode::Uncached {
+            return false;
+        }
+        if desc.cache_mode == mode {
+            return true;
+        }
+        // Mappings made through page tables are tracked, others, like
+        // those of VSpaces, are found by walking every address space.
+        if desc.mapped_weak_pool.read().is_occupied(0) ||
+            is_region_mapped(desc.start_paddr, desc.start_paddr + PAGE_LENGTH) {
+            return false;
+        }
+
+        desc.cache_mode = mode;
+        desc.cache_maintenance(0, PAGE_LENGTH, CacheOperation::CleanInvalidate)
+    }
 }
 
 impl<T: SetDefault + Any> PageDescriptor<T> {
@@ -56,6 +106,16 @@ impl<T: SetDefault + Any> PageDescriptor<T> {
         BASE_PAGE_LENGTH
     }
 
+    /// Whether the page is device memory, always mapped uncached.
+    pub fn is_uncached(&self) -> bool {
+        self.uncached
+    }
+
+    /// Memory type of the page's mappings.
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
     fn page_object(&self) -> MemoryObject<T> {
         unsafe { MemoryObject::new(self.start_paddr) }
     }
@@ -68,3 +128,19 @@ impl<T: SetDefault + Any> PageDescriptor<T> {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
 }
+
+impl<T: SetDefault + Any> Derived for PageDescriptor<T> {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &PageCap<T>) {
+        let desc = arc.read();
+        let parent: Option<PTCap> = desc.mapped_weak_pool.read().upgrade(0);
+        if let Some(parent) = parent {
+            parent.write().unmap_paddr(desc.start_paddr);
+            unsafe { flush_user_all(); }
+        }
+        desc.mapped_weak_pool.read().clear();
+    }
+}
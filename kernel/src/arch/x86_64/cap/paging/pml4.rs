@@ -1,17 +1,32 @@
 use common::*;
 use arch::{KERNEL_BASE};
 use arch::init::{KERNEL_PDPT};
-use arch::paging::{BASE_PAGE_LENGTH, PML4, PML4Entry, pml4_index};
-use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock};
-use super::{PML4Descriptor, PML4Cap, PDPTCap, PDCap, PTCap, PageCap};
-use cap::{self, UntypedDescriptor, CPoolDescriptor, SetDefault};
+use arch::paging::{self, Asid, BASE_PAGE_LENGTH, PML4, PML4Entry, PDPT, PDPTEntry, PD, PDEntry, PT, PTEntry,
+                   pml4_index};
+use util::{MemoryObject, UniqueReadGuard, UniqueWriteGuard, RwLock, Mutex};
+use util::managed_arc::{ManagedWeakPool3Arc, ManagedArcAny};
+use super::{PML4Descriptor, PML4Cap, PDPTCap, PDCap, PTCap, PageCap, LargePageCap, flush_user_all};
+use cap::{self, UntypedDescriptor, CPoolDescriptor, SetDefault, Derived};
 use core::any::Any;
+use core::iter::Iterator;
+use abi::MapAttributes;
+use meminfo::MemoryCategory;
 
 impl PML4Cap {
     pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
         let mut arc: Option<Self> = None;
 
-        let start_paddr = unsafe { untyped.allocate(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH) };
+        let start_paddr = unsafe {
+            untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, MemoryCategory::PageTable)
+        };
+        #[cfg(feature="kpti")]
+        let user_paddr = unsafe {
+            untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, MemoryCategory::PageTable)
+        };
+
+        let fault_weak_pool = unsafe { ManagedWeakPool3Arc::create(
+            untyped.allocate(ManagedWeakPool3Arc::inner_length(),
+                             ManagedWeakPool3Arc::inner_alignment())) };
 
         unsafe {
             use arch::paging::{PML4_P, PML4_RW};
@@ -19,7 +34,13 @@ impl PML4Cap {
             untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
                 let mut desc = PML4Descriptor {
                     start_paddr: start_paddr,
+                    #[cfg(feature="kpti")]
+                    user_paddr: user_paddr,
+                    asid: None,
+                    fault_weak_pool: fault_weak_pool,
+                    soft_dirty_ring: Mutex::new(None),
                     next: next_child,
+                    next_pml4: None,
                 };
 
                 for item in desc.write().iter_mut() {
@@ -29,6 +50,16 @@ impl PML4Cap {
                 desc.write()[pml4_index(VAddr::from(KERNEL_BASE))] =
                     PML4Entry::new(KERNEL_PDPT.paddr(), PML4_P | PML4_RW);
 
+                #[cfg(feature="kpti")]
+                {
+                    let mut user = desc.user_page_object();
+                    for item in user.as_mut().iter_mut() {
+                        *item = PML4Entry::empty();
+                    }
+                    user.as_mut()[pml4_index(VAddr::from(KERNEL_BASE))] =
+                        ::arch::kpti::trampoline_entry();
+                }
+
                 arc = Some(
                     Self::new(paddr, RwLock::new(desc))
                 );
@@ -37,6 +68,7 @@ impl PML4Cap {
             });
         }
 
+        register_pml4(arc.clone().unwrap());
         arc.unwrap()
     }
 
@@ -44,20 +76,20 @@ impl PML4Cap {
         use arch::paging::{pml4_index, PML4_P, PML4_RW, PML4_US};
 
         let mut current_desc = self.write();
-        let mut current = current_desc.write();
         let sub_desc = sub.read();
         assert!(!(pml4_index(VAddr::from(KERNEL_BASE)) == index));
-        assert!(!current[index].is_present());
+        assert!(!{ current_desc.read()[index] }.is_present());
 
         sub_desc.mapped_weak_pool.read().downgrade_at(self, 0);
-        current[index] = PML4Entry::new(sub_desc.start_paddr(), PML4_P | PML4_RW | PML4_US);
+        current_desc.set_user_entry(index, PML4Entry::new(sub_desc.start_paddr(),
+                                                          PML4_P | PML4_RW | PML4_US));
     }
 
-    pub fn map<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>,
-                                    untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
-        use arch::paging::{pml4_index, pdpt_index, pd_index, pt_index};
-
-        log!("PML4 mapping: 0x{:x}", vaddr);
+    /// Walk down to the page directory covering `vaddr`, creating
+    /// missing PDPT and PD tables from `untyped`.
+    fn walk_pd(&mut self, vaddr: VAddr,
+               untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) -> PDCap {
+        use arch::paging::{pml4_index, pdpt_index};
 
         let mut pdpt_cap: PDPTCap = {
             let index = pml4_index(vaddr);
@@ -87,7 +119,7 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
-        let mut pd_cap: PDCap = {
+        let pd_cap: PDCap = {
             let index = pdpt_index(vaddr);
 
             if !{ pdpt_cap.read().read()[index] }.is_present() {
@@ -115,7 +147,18 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
-        let mut pt_cap: PTCap = {
+        pd_cap
+    }
+
+    /// Walk down to the page table covering `vaddr`, creating
+    /// missing tables from `untyped`.
+    fn walk_pt(&mut self, vaddr: VAddr,
+               untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) -> PTCap {
+        use arch::paging::{pd_index};
+
+        let mut pd_cap = self.walk_pd(vaddr, untyped, cpool);
+
+        let pt_cap: PTCap = {
             let index = pd_index(vaddr);
 
             if !{ pd_cap.read().read()[index] }.is_present() {
@@ -123,6 +166,7 @@ impl PML4Cap {
                 pd_cap.map_pt(index, &pt_cap);
                 cpool.downgrade_free(&pt_cap);
             }
+            assert!(!{ pd_cap.read().read()[index] }.is_page());
 
             let position = (0..cpool.size()).position(|i| {
                 let any = cpool.upgrade_any(i);
@@ -143,8 +187,64 @@ impl PML4Cap {
             cpool.upgrade(position)
         }.unwrap();
 
+        pt_cap
+    }
+
+    pub fn map<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>,
+                                    untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        log!("PML4 mapping: 0x{:x}", vaddr);
+
+        let mut pt_cap = self.walk_pt(vaddr, untyped, cpool);
         pt_cap.map_page(pt_index(vaddr), page);
     }
+
+    /// Reserve `vaddr` with no backing frame. A zeroed frame from the
+    /// fault pool of this address space is mapped on first touch.
+    pub fn reserve_zero(&mut self, vaddr: VAddr,
+                        untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        let mut pt_cap = self.walk_pt(vaddr, untyped, cpool);
+        pt_cap.reserve_page(pt_index(vaddr));
+    }
+
+    /// Leave `vaddr` unmapped as the guard page below a stack. Faults
+    /// on it are reported as stack overflows.
+    pub fn guard(&mut self, vaddr: VAddr,
+                 untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        let mut pt_cap = self.walk_pt(vaddr, untyped, cpool);
+        pt_cap.guard_page(pt_index(vaddr));
+    }
+
+    /// Map a page copy-on-write at `vaddr`. Write faults on the
+    /// mapping are resolved using the fault pool of this address
+    /// space.
+    pub fn map_cow<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>,
+                                        untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
+        use arch::paging::{pt_index};
+
+        log!("PML4 copy-on-write mapping: 0x{:x}", vaddr);
+
+        let mut pt_cap = self.walk_pt(vaddr, untyped, cpool);
+        pt_cap.map_page_cow(pt_index(vaddr), page);
+    }
+
+    /// Map a large page at `vaddr`, which must be aligned to the
+    /// large page length.
+    pub fn map_large(&mut self, vaddr: VAddr, page: &LargePageCap,
+                     untyped: &mut UntypedDescriptor, cpool: &mut CPoolDescriptor) {
+        use arch::paging::{pd_index, LARGE_PAGE_LENGTH};
+
+        log!("PML4 large mapping: 0x{:x}", vaddr);
+        assert!(vaddr.into(): usize % LARGE_PAGE_LENGTH == 0);
+
+        let mut pd_cap = self.walk_pd(vaddr, untyped, cpool);
+        pd_cap.map_large_page(pd_index(vaddr), page);
+    }
 }
 
 impl PML4Descriptor {
@@ -156,10 +256,31 @@ impl PML4Descriptor {
         BASE_PAGE_LENGTH
     }
 
+    /// Translate `vaddr` to the physical address it maps to, and the
+    /// attributes of the mapping. Returns `None` if nothing is mapped
+    /// at `vaddr`.
+    pub fn lookup(&self, vaddr: VAddr) -> Option<(PAddr, MapAttributes)> {
+        use arch::paging;
+
+        unsafe { paging::lookup_in(self.start_paddr, vaddr) }
+    }
+
     fn page_object(&self) -> MemoryObject<PML4> {
         unsafe { MemoryObject::new(self.start_paddr) }
     }
 
+    /// Set entry `index` in the user half. The isolated page table
+    /// shares the user half, so with KPTI it is set there as well.
+    pub fn set_user_entry(&mut self, index: usize, entry: PML4Entry) {
+        assert!(index != pml4_index(VAddr::from(KERNEL_BASE)));
+
+        { self.write()[index] = entry; }
+        #[cfg(feature="kpti")]
+        unsafe {
+            self.user_page_object().as_mut()[index] = entry;
+        }
+    }
+
     pub fn read(&self) -> UniqueReadGuard<PML4> {
         unsafe { UniqueReadGuard::new(self.page_object()) }
     }
@@ -168,9 +289,277 @@ impl PML4Descriptor {
         unsafe { UniqueWriteGuard::new(self.page_object()) }
     }
 
+    /// Switch to this address space. With PCIDs, the address space
+    /// keeps its ASID across switches, so its TLB entries survive.
+    #[cfg(not(feature="kpti"))]
     pub fn switch_to(&mut self) {
         use arch::paging;
 
-        unsafe { paging::switch_to(self.start_paddr); }
+        self.asid = unsafe { paging::switch_to_asid(self.start_paddr, self.asid) };
+    }
+
+    /// Switch to this address space. The kernel keeps running on the
+    /// full page table, and the isolated one is loaded on return to
+    /// user mode.
+    #[cfg(feature="kpti")]
+    pub fn switch_to(&mut self) {
+        use arch::{paging, kpti};
+
+        unsafe {
+            paging::switch_to(self.start_paddr);
+            kpti::set_top_page_tables(self.start_paddr, self.user_paddr);
+        }
+    }
+
+    #[cfg(feature="kpti")]
+    unsafe fn user_page_object(&self) -> MemoryObject<PML4> {
+        MemoryObject::new(self.user_paddr)
+    }
+
+    /// Drop the ASID of this address space, so that its TLB entries
+    /// tagged with the old PCID are never used again. Must be called
+    /// after removing or downgrading a mapping of an address space
+    /// that is not the current one.
+    pub fn invalidate_asid(&mut self) {
+        match self.asid {
+            Some(asid) if asid.is_pinned() => asid.invalidate(),
+            _ => self.asid = None,
+        }
+    }
+
+    /// Tag this address space with `asid`, assigned from an ASID
+    /// pool, or with ASIDs the kernel recycles if `None`.
+    pub fn set_pinned_asid(&mut self, asid: Option<Asid>) {
+        if let Some(asid) = self.asid {
+            if asid.is_pinned() {
+                asid.invalidate();
+            }
+        }
+        self.asid = asid;
+    }
+
+    /// Clear the user entries pointing to `paddr`. The caller is
+    /// responsible for flushing the TLB.
+    pub fn unmap_paddr(&mut self, paddr: PAddr) {
+        let kernel_index = pml4_index(VAddr::from(KERNEL_BASE));
+        for index in 0..512 {
+            let entry = { self.read()[index] };
+            if index != kernel_index && entry.is_present() && entry.get_address() == paddr {
+                self.set_user_entry(index, PML4Entry::empty());
+            }
+        }
+    }
+}
+
+impl Derived for PML4Descriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &PML4Cap) {
+        {
+            let mut desc = arc.write();
+            unsafe { paging::switch_away_from(desc.start_paddr); }
+            desc.invalidate_asid();
+            desc.fault_weak_pool.read().clear();
+            *desc.soft_dirty_ring.lock() = None;
+        }
+        unregister_pml4(arc);
+    }
+}
+
+/// The first PML4 created.
+static FIRST_PML4: Mutex<Option<PML4Cap>> = Mutex::new(None);
+
+/// Register a new PML4. Using `FIRST_PML4` static, this forms a
+/// linked-list of all address spaces.
+fn register_pml4(cap: PML4Cap) {
+    let mut first_pml4 = FIRST_PML4.lock();
+    if first_pml4.is_none() {
+        *first_pml4 = Some(cap);
+    } else {
+        let mut first = first_pml4.as_mut().unwrap().write();
+        let mut second = cap.write();
+        let third_pml4 = first.next_pml4.take();
+
+        second.next_pml4 = third_pml4;
+        first.next_pml4 = Some(cap.clone());
+    }
+}
+
+/// Remove a PML4 from the list of all address spaces, if it is there.
+fn unregister_pml4(cap: &PML4Cap) {
+    let mut first_pml4 = FIRST_PML4.lock();
+    if first_pml4.as_ref().map(|first| first.ptr_eq(cap)).unwrap_or(false) {
+        *first_pml4 = cap.write().next_pml4.take();
+        return;
+    }
+
+    let mut current = first_pml4.clone();
+    while let Some(pml4) = current {
+        let mut desc = pml4.write();
+        if desc.next_pml4.as_ref().map(|next| next.ptr_eq(cap)).unwrap_or(false) {
+            desc.next_pml4 = cap.write().next_pml4.take();
+            return;
+        }
+        current = desc.next_pml4.clone();
+    }
+}
+
+/// A PML4 iterator.
+struct PML4Iterator {
+    next: Option<PML4Cap>,
+}
+
+impl Iterator for PML4Iterator {
+    type Item = PML4Cap;
+
+    fn next(&mut self) -> Option<PML4Cap> {
+        if let Some(current) = self.next.clone() {
+            {
+                let current_pml4 = current.read();
+                self.next = current_pml4.next_pml4.clone();
+            }
+            return Some(current);
+        } else {
+            None
+        }
+    }
+}
+
+/// Return a PML4 iterator using `FIRST_PML4`.
+fn pml4_iter() -> PML4Iterator {
+    PML4Iterator {
+        next: FIRST_PML4.lock().clone(),
+    }
+}
+
+/// Remove, from every address space, the user mappings of frames and
+/// paging structures in `start..end`, so that the memory can be
+/// reused. This also catches mappings that no capability tracks, like
+/// those of VSpaces.
+pub fn unmap_region(start: PAddr, end: PAddr) {
+    let in_region = |paddr: PAddr| paddr >= start && paddr < end;
+    let kernel_index = pml4_index(VAddr::from(KERNEL_BASE));
+    let mut unmapped = false;
+
+    for pml4 in pml4_iter() {
+        let mut desc = pml4.write();
+        {
+            let mut ring = desc.soft_dirty_ring.lock();
+            if ring.as_ref().map(|paddr| in_region(*paddr)).unwrap_or(false) {
+                *ring = None;
+            }
+        }
+
+        let mut changed = false;
+        for index in 0..512 {
+            let entry = { desc.read()[index] };
+            if index == kernel_index || !entry.is_present() {
+                continue;
+            }
+
+            if in_region(entry.get_address()) {
+                desc.set_user_entry(index, PML4Entry::empty());
+                changed = true;
+            } else {
+                changed |= unsafe { unmap_pdpt(entry.get_address(), &in_region) };
+            }
+        }
+
+        if changed {
+            desc.invalidate_asid();
+            unmapped = true;
+        }
+    }
+
+    if unmapped {
+        unsafe { flush_user_all(); }
+    }
+}
+
+/// Whether any address space maps a frame or paging structure in
+/// `start..end` in its user half, tracked by a capability or not.
+pub fn is_region_mapped(start: PAddr, end: PAddr) -> bool {
+    let in_region = |paddr: PAddr| paddr >= start && paddr < end;
+    let kernel_index = pml4_index(VAddr::from(KERNEL_BASE));
+
+    pml4_iter().any(|pml4| {
+        let desc = pml4.read();
+        (0..512).any(|index| {
+            let entry = { desc.read()[index] };
+            index != kernel_index && entry.is_present() &&
+                (in_region(entry.get_address()) || unsafe { pdpt_maps(entry.get_address(), &in_region) })
+        })
+    })
+}
+
+/// Whether any entry below a PDPT points into a region.
+unsafe fn pdpt_maps<F: Fn(PAddr) -> bool>(paddr: PAddr, in_region: &F) -> bool {
+    let pdpt = MemoryObject::<PDPT>::new(paddr);
+    pdpt.as_ref().iter().any(|pdpt_entry| {
+        if !pdpt_entry.is_present() {
+            return false;
+        }
+        if in_region(pdpt_entry.get_address()) {
+            return true;
+        }
+
+        let pd = MemoryObject::<PD>::new(pdpt_entry.get_address());
+        pd.as_ref().iter().any(|pd_entry| {
+            if !pd_entry.is_present() {
+                return false;
+            }
+            if in_region(pd_entry.get_address()) {
+                return true;
+            }
+            if pd_entry.is_page() {
+                return false;
+            }
+
+            let pt = MemoryObject::<PT>::new(pd_entry.get_address());
+            pt.as_ref().iter().any(|pt_entry| pt_entry.is_present() && in_region(pt_entry.get_address()))
+        })
+    })
+}
+
+/// Clear the entries below a PDPT that point into a region. Returns
+/// whether any entry was cleared.
+unsafe fn unmap_pdpt<F: Fn(PAddr) -> bool>(paddr: PAddr, in_region: &F) -> bool {
+    let mut changed = false;
+    let mut pdpt = MemoryObject::<PDPT>::new(paddr);
+    for pdpt_entry in pdpt.as_mut().iter_mut() {
+        if !pdpt_entry.is_present() {
+            continue;
+        }
+        if in_region(pdpt_entry.get_address()) {
+            *pdpt_entry = PDPTEntry::empty();
+            changed = true;
+            continue;
+        }
+
+        let mut pd = MemoryObject::<PD>::new(pdpt_entry.get_address());
+        for pd_entry in pd.as_mut().iter_mut() {
+            if !pd_entry.is_present() {
+                continue;
+            }
+            if in_region(pd_entry.get_address()) {
+                *pd_entry = PDEntry::empty();
+                changed = true;
+                continue;
+            }
+            if pd_entry.is_page() {
+                continue;
+            }
+
+            let mut pt = MemoryObject::<PT>::new(pd_entry.get_address());
+            for pt_entry in pt.as_mut().iter_mut() {
+                if pt_entry.is_present() && in_region(pt_entry.get_address()) {
+                    *pt_entry = PTEntry::empty();
+                    changed = true;
+                }
+            }
+        }
     }
+    changed
 }
@@ -0,0 +1,621 @@
+use common::*;
+use arch::{KERNEL_BASE, USER_END};
+use arch::paging::{BASE_PAGE_LENGTH, PDPT, PD, PT, PML4Entry, PDPTEntry, PDEntry, PTEntry,
+                   PML4_P, PML4_RW, PML4_US, PDPT_P, PDPT_RW, PDPT_US, PD_P, PD_RW, PD_US,
+                   PT_P, PT_RW, PT_US, PT_A, PT_D, PT_OWNED, PT_TRACKED, PT_SHARED, pml4_index, pdpt_index, pd_index, pt_index,
+                   flush_range_all_cpus};
+use util::{MemoryObject, RwLock};
+use util::managed_arc::{ManagedWeakPool1Arc, ManagedArcAny};
+use core::{cmp, ptr};
+use core::sync::atomic::Ordering;
+use core::ops::DerefMut;
+use core::any::Any;
+use cap::{UntypedCap, UntypedDescriptor, RawPageCap, SetDefault, Derived};
+use meminfo::MemoryCategory;
+use super::{VSpaceDescriptor, VSpaceCap, PML4Cap, PageCap, cache_flags};
+
+/// Largest number of pages `VSpaceDescriptor::harvest` reports on at
+/// once, one bit each.
+pub const HARVEST_MAX_PAGES: usize = 64;
+
+/// Number of owned frames `VSpaceDescriptor::remap` drops between TLB
+/// flushes when shrinking.
+const REMAP_BATCH: usize = 64;
+
+impl VSpaceCap {
+    /// Create an empty address space from an untyped capability. Its
+    /// lower paging structures are allocated from, and returned to,
+    /// the same untyped capability.
+    pub fn retype_from(untyped: &UntypedCap) -> Self {
+        let mut arc: Option<Self> = None;
+        let mut untyped_desc = untyped.write();
+
+        let pml4 = PML4Cap::retype_from(untyped_desc.deref_mut());
+
+        let untyped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped_desc.allocate(ManagedWeakPool1Arc::inner_length(),
+                                  ManagedWeakPool1Arc::inner_alignment())) };
+        untyped_weak_pool.read().downgrade_at(untyped, 0);
+
+        unsafe {
+            untyped_desc.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+                arc = Some(
+                    Self::new(paddr, RwLock::new(VSpaceDescriptor {
+                        pml4: pml4,
+                        untyped_weak_pool: untyped_weak_pool,
+                        table_count: 0,
+                        next: next_child,
+                    }))
+                );
+
+                arc.clone().unwrap().into()
+            });
+        }
+
+        arc.unwrap()
+    }
+}
+
+/// Largest number of paging structures that mapping `count`
+/// contiguous pages may have to create: page tables, page
+/// directories and PDPTs, each possibly straddling one more boundary.
+fn tables_needed(count: usize) -> usize {
+    (count / 512 + 2) + (count / (512 * 512) + 2) + (count / (512 * 512 * 512) + 2)
+}
+
+/// Allocate an empty paging structure from `untyped`, which zeroes
+/// it.
+unsafe fn allocate_table(untyped: &mut UntypedDescriptor) -> PAddr {
+    untyped.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, MemoryCategory::PageTable)
+}
+
+impl VSpaceDescriptor {
+    /// The top-level page table of this address space, for tasks to
+    /// run in.
+    pub fn pml4(&self) -> PML4Cap {
+        self.pml4.clone()
+    }
+
+    fn upgrade_untyped(&self) -> Option<UntypedCap> {
+        self.untyped_weak_pool.read().upgrade(0)
+    }
+
+    /// Whether `count` pages from `vaddr` are page-aligned and in the
+    /// user half.
+    fn is_user_range(vaddr: VAddr, count: usize) -> bool {
+        let address = vaddr.into(): usize;
+        address % BASE_PAGE_LENGTH == 0 &&
+            count <= (USER_END - cmp::min(address, USER_END)) / BASE_PAGE_LENGTH
+    }
+
+    /// Page table covering `vaddr`, if there is one.
+    unsafe fn find_pt(&self, vaddr: VAddr) -> Option<PAddr> {
+        let pml4_entry = { self.pml4.read().read()[pml4_index(vaddr)] };
+        if !pml4_entry.is_present() {
+            return None;
+        }
+        let pdpt_entry =
+            MemoryObject::<PDPT>::new(pml4_entry.get_address()).as_ref()[pdpt_index(vaddr)];
+        if !pdpt_entry.is_present() {
+            return None;
+        }
+        let pd_entry =
+            MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref()[pd_index(vaddr)];
+        if !pd_entry.is_present() || pd_entry.is_page() {
+            return None;
+        }
+        Some(pd_entry.get_address())
+    }
+
+    /// Page table covering `vaddr`, creating missing paging
+    /// structures from `untyped`.
+    unsafe fn walk_pt(&mut self, vaddr: VAddr, untyped: &mut UntypedDescriptor) -> PAddr {
+        let pml4_entry = { self.pml4.read().read()[pml4_index(vaddr)] };
+        let pdpt_paddr = if pml4_entry.is_present() {
+            pml4_entry.get_address()
+        } else {
+            let paddr = allocate_table(untyped);
+            self.table_count += 1;
+            self.pml4.write().set_user_entry(pml4_index(vaddr),
+                                             PML4Entry::new(paddr, PML4_P | PML4_RW | PML4_US));
+            paddr
+        };
+
+        let mut pdpt = MemoryObject::<PDPT>::new(pdpt_paddr);
+        let pdpt_entry = pdpt.as_ref()[pdpt_index(vaddr)];
+        let pd_paddr = if pdpt_entry.is_present() {
+            pdpt_entry.get_address()
+        } else {
+            let paddr = allocate_table(untyped);
+            self.table_count += 1;
+            pdpt.as_mut()[pdpt_index(vaddr)] = PDPTEntry::new(paddr, PDPT_P | PDPT_RW | PDPT_US);
+            paddr
+        };
+
+        let mut pd = MemoryObject::<PD>::new(pd_paddr);
+        let pd_entry = pd.as_ref()[pd_index(vaddr)];
+        if pd_entry.is_present() {
+            pd_entry.get_address()
+        } else {
+            let paddr = allocate_table(untyped);
+            self.table_count += 1;
+            pd.as_mut()[pd_index(vaddr)] = PDEntry::new(paddr, PD_P | PD_RW | PD_US);
+            paddr
+        }
+    }
+
+    /// Whether `vaddr` lies in a large page, so that no page table
+    /// covers it.
+    unsafe fn in_large_page(&self, vaddr: VAddr) -> bool {
+        let pml4_entry = { self.pml4.read().read()[pml4_index(vaddr)] };
+        if !pml4_entry.is_present() {
+            return false;
+        }
+        let pdpt_entry =
+            MemoryObject::<PDPT>::new(pml4_entry.get_address()).as_ref()[pdpt_index(vaddr)];
+        if !pdpt_entry.is_present() {
+            return false;
+        }
+        let pd_entry =
+            MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref()[pd_index(vaddr)];
+        pd_entry.is_present() && pd_entry.is_page()
+    }
+
+    /// Page table entry mapping `vaddr`, if it is present.
+    unsafe fn entry(&self, vaddr: VAddr) -> Option<PTEntry> {
+        self.find_pt(vaddr).and_then(|pt| {
+            let entry = MemoryObject::<PT>::new(pt).as_ref()[pt_index(vaddr)];
+            if entry.is_present() { Some(entry) } else { None }
+        })
+    }
+
+    /// Map `page` writable at `vaddr`, creating missing paging
+    /// structures. Returns `false` if `vaddr` is not a page-aligned
+    /// user address, or is already mapped.
+    pub fn map<T: SetDefault + Any>(&mut self, vaddr: VAddr, page: &PageCap<T>) -> bool {
+        let (paddr, cache_mode) = {
+            let page = page.read();
+            (page.start_paddr(), page.cache_mode())
+        };
+        self.map_frames_with(vaddr, paddr, 1, PT_P | PT_RW | PT_US | cache_flags(cache_mode))
+    }
+
+    /// Map `count` physically contiguous frames of a shared frame set
+    /// from `paddr` at `vaddr`, read-only unless `writable`. Either
+    /// all pages are mapped, or, if any of them is already mapped or
+    /// out of the user half, none. The pages can then only be
+    /// unmapped with `unmap_frames`, or by tearing the address space
+    /// down, so the set always knows where it is mapped.
+    pub fn map_frames(&mut self, vaddr: VAddr, paddr: PAddr, count: usize, writable: bool) -> bool {
+        let flags = if writable { PT_P | PT_RW | PT_US | PT_SHARED } else { PT_P | PT_US | PT_SHARED };
+        self.map_frames_with(vaddr, paddr, count, flags)
+    }
+
+    fn map_frames_with(&mut self, vaddr: VAddr, paddr: PAddr, count: usize, flags: PTEntry) -> bool {
+        if !Self::is_user_range(vaddr, count) {
+            return false;
+        }
+        if (0..count).any(|i| unsafe {
+            let page_vaddr = vaddr + i * BASE_PAGE_LENGTH;
+            self.entry(page_vaddr).is_some() || self.in_large_page(page_vaddr)
+        }) {
+            return false;
+        }
+
+        let untyped = match self.upgrade_untyped() {
+            Some(untyped) => untyped,
+            None => return false,
+        };
+        let mut untyped_desc = untyped.write();
+        if !untyped_desc.can_retype(tables_needed(count) * BASE_PAGE_LENGTH) {
+            return false;
+        }
+
+        for i in 0..count {
+            let page_vaddr = vaddr + i * BASE_PAGE_LENGTH;
+            unsafe {
+                let pt = self.walk_pt(page_vaddr, untyped_desc.deref_mut());
+                MemoryObject::<PT>::new(pt).as_mut()[pt_index(page_vaddr)] =
+                    PTEntry::new(paddr + i * BASE_PAGE_LENGTH, flags);
+            }
+        }
+
+        true
+    }
+
+    /// Remove the mapping at `vaddr`, freeing the frame if this
+    /// address space owns it. Paging structures are kept until
+    /// `destroy`. Returns `false` if nothing is mapped there, or the
+    /// page belongs to a shared frame set.
+    pub fn unmap(&mut self, vaddr: VAddr) -> bool {
+        let entry = match unsafe { self.entry(vaddr) } {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if entry.is_shared() {
+            return false;
+        }
+        if !self.unmap_frames(vaddr, entry.get_address(), 1) {
+            return false;
+        }
+
+        if entry.is_owned() {
+            if let Some(untyped) = self.upgrade_untyped() {
+                unsafe { untyped.write().free_page(entry.get_address(), MemoryCategory::UserFrame); }
+            }
+        }
+        true
+    }
+
+    /// Whether the `count` contiguous frames from `paddr` are mapped
+    /// at `vaddr`.
+    pub fn maps_frames(&self, vaddr: VAddr, paddr: PAddr, count: usize) -> bool {
+        Self::is_user_range(vaddr, count) && (0..count).all(|i| unsafe {
+            self.entry(vaddr + i * BASE_PAGE_LENGTH).map(|entry| entry.get_address()) ==
+                Some(paddr + i * BASE_PAGE_LENGTH)
+        })
+    }
+
+    /// Remove the mappings of `count` contiguous frames from `paddr`
+    /// at `vaddr`. Returns `false`, and leaves everything mapped, if
+    /// any of the pages does not map the expected frame.
+    pub fn unmap_frames(&mut self, vaddr: VAddr, paddr: PAddr, count: usize) -> bool {
+        if !self.maps_frames(vaddr, paddr, count) {
+            return false;
+        }
+
+        for i in 0..count {
+            let page_vaddr = vaddr + i * BASE_PAGE_LENGTH;
+            unsafe {
+                let pt = self.find_pt(page_vaddr).unwrap();
+                MemoryObject::<PT>::new(pt).as_mut()[pt_index(page_vaddr)] = PTEntry::empty();
+            }
+        }
+
+        unsafe { self.flush(vaddr, count); }
+
+        true
+    }
+
+    /// Clear the entry mapping `vaddr`, which must be present, and
+    /// return it. The caller is responsible for flushing the TLB.
+    unsafe fn take_entry(&mut self, vaddr: VAddr) -> PTEntry {
+        let mut pt = MemoryObject::<PT>::new(self.find_pt(vaddr).unwrap());
+        let entry = pt.as_ref()[pt_index(vaddr)];
+        pt.as_mut()[pt_index(vaddr)] = PTEntry::empty();
+        entry
+    }
+
+    /// Set the entry mapping `vaddr`, creating missing paging
+    /// structures from `untyped`.
+    unsafe fn set_entry(&mut self, vaddr: VAddr, entry: PTEntry, untyped: &mut UntypedDescriptor) {
+        let pt = self.walk_pt(vaddr, untyped);
+        MemoryObject::<PT>::new(pt).as_mut()[pt_index(vaddr)] = entry;
+    }
+
+    /// Move the `old_count` pages mapped from `old_vaddr` to
+    /// `new_vaddr`, resizing the range to `new_count` pages. Frames
+    /// are moved, never copied, and keep their permissions. Growing
+    /// maps zeroed writable frames owned by this address space, and
+    /// shrinking unmaps the tail. `new_vaddr` may be `old_vaddr` to
+    /// resize in place, and the ranges may overlap. Returns `false`,
+    /// and changes nothing, if a page of the old range is unmapped or
+    /// belongs to a shared frame set, a page of the new range outside
+    /// the old one is mapped or lies in a large page, or the untyped
+    /// capability cannot provide the frames and paging structures
+    /// needed.
+    pub fn remap(&mut self, old_vaddr: VAddr, old_count: usize,
+                 new_vaddr: VAddr, new_count: usize) -> bool {
+        if !Self::is_user_range(old_vaddr, old_count) || !Self::is_user_range(new_vaddr, new_count) {
+            return false;
+        }
+
+        let old_end = old_vaddr + old_count * BASE_PAGE_LENGTH;
+        let in_old = |vaddr: VAddr| vaddr >= old_vaddr && vaddr < old_end;
+        if (0..old_count).any(|i| unsafe {
+            self.entry(old_vaddr + i * BASE_PAGE_LENGTH).map(|entry| entry.is_shared()).unwrap_or(true)
+        }) {
+            return false;
+        }
+        if (0..new_count).any(|i| {
+            let vaddr = new_vaddr + i * BASE_PAGE_LENGTH;
+            !in_old(vaddr) && unsafe { self.entry(vaddr).is_some() || self.in_large_page(vaddr) }
+        }) {
+            return false;
+        }
+
+        let untyped = match self.upgrade_untyped() {
+            Some(untyped) => untyped,
+            None => return false,
+        };
+        let mut untyped_desc = untyped.write();
+        let moved = cmp::min(old_count, new_count);
+        let grown = new_count - moved;
+        if !untyped_desc.can_retype((grown + tables_needed(new_count)) * BASE_PAGE_LENGTH) {
+            return false;
+        }
+
+        // Owned frames of the dropped tail. They are freed only once
+        // no CPU can write them through a stale translation.
+        let mut dropped = [PAddr::from(0: usize); REMAP_BATCH];
+        let mut dropped_count = 0;
+
+        unsafe {
+            // Take the tail first, as it may lie in the new range.
+            for i in moved..old_count {
+                let entry = self.take_entry(old_vaddr + i * BASE_PAGE_LENGTH);
+                if entry.is_owned() {
+                    if dropped_count == REMAP_BATCH {
+                        self.flush(old_vaddr, old_count);
+                        for &paddr in dropped.iter() {
+                            untyped_desc.free_page(paddr, MemoryCategory::UserFrame);
+                        }
+                        dropped_count = 0;
+                    }
+                    dropped[dropped_count] = entry.get_address();
+                    dropped_count += 1;
+                }
+            }
+
+            // Move in the direction that never overwrites a page not
+            // moved yet.
+            for j in 0..moved {
+                let i = if new_vaddr > old_vaddr { moved - 1 - j } else { j };
+                let entry = self.take_entry(old_vaddr + i * BASE_PAGE_LENGTH);
+                self.set_entry(new_vaddr + i * BASE_PAGE_LENGTH, entry, untyped_desc.deref_mut());
+            }
+
+            self.flush(old_vaddr, old_count);
+            for &paddr in dropped[..dropped_count].iter() {
+                untyped_desc.free_page(paddr, MemoryCategory::UserFrame);
+            }
+
+            // The new pages were unmapped, so nothing caches them.
+            for i in moved..new_count {
+                let paddr = untyped_desc.allocate_as(BASE_PAGE_LENGTH, BASE_PAGE_LENGTH,
+                                                     MemoryCategory::UserFrame);
+                self.set_entry(new_vaddr + i * BASE_PAGE_LENGTH,
+                               PTEntry::new(paddr, PT_P | PT_RW | PT_US | PT_OWNED),
+                               untyped_desc.deref_mut());
+            }
+        }
+
+        true
+    }
+
+    /// Map zeroed writable frames owned by this address space over
+    /// the `length` bytes from `vaddr`, keeping the owned frames
+    /// already mapped there, and copy `data` to the start of the
+    /// range. Returns `false` if `data` is longer than the range, a
+    /// page of the range maps a frame this address space does not
+    /// own, or the untyped capability cannot provide the frames and
+    /// paging structures needed. Pages mapped before a failure stay
+    /// mapped.
+    pub fn fill(&mut self, vaddr: VAddr, length: usize, data: &[u8]) -> bool {
+        let start = vaddr.into(): usize;
+        let end = match start.checked_add(length) {
+            Some(end) if data.len() <= length => end,
+            _ => return false,
+        };
+        let first = start - start % BASE_PAGE_LENGTH;
+        let count = (end - first + BASE_PAGE_LENGTH - 1) / BASE_PAGE_LENGTH;
+        if !Self::is_user_range(VAddr::from(first), count) {
+            return false;
+        }
+
+        for i in 0..count {
+            let page_vaddr = VAddr::from(first) + i * BASE_PAGE_LENGTH;
+            let owned = match unsafe { self.entry(page_vaddr) } {
+                Some(entry) => entry.is_owned(),
+                None => self.remap(page_vaddr, 0, page_vaddr, 1),
+            };
+            if !owned {
+                return false;
+            }
+        }
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let address = start + offset;
+            let page_offset = address % BASE_PAGE_LENGTH;
+            let chunk = cmp::min(data.len() - offset, BASE_PAGE_LENGTH - page_offset);
+            unsafe {
+                let paddr = self.entry(VAddr::from(address - page_offset)).unwrap().get_address();
+                let frame = MemoryObject::<u8>::slice(paddr + page_offset, chunk);
+                ptr::copy_nonoverlapping(data[offset..].as_ptr(), frame.as_ptr(), chunk);
+            }
+            offset += chunk;
+        }
+
+        true
+    }
+
+    /// Invalidate `count` pages from `vaddr` on all CPUs.
+    unsafe fn flush(&mut self, vaddr: VAddr, count: usize) {
+        self.pml4.write().invalidate_asid();
+        flush_range_all_cpus(vaddr, count * BASE_PAGE_LENGTH);
+    }
+
+    /// Read the accessed and dirty bits of the `count` pages from
+    /// `vaddr`, as two bitmaps with bit `i` for page `i`. Unmapped
+    /// pages read as neither. With `clear`, the bits are cleared in
+    /// the same atomic operation as they are read, so no access in
+    /// between is lost. Returns `None` if the range is not in the
+    /// user half, or longer than `HARVEST_MAX_PAGES`.
+    pub fn harvest(&mut self, vaddr: VAddr, count: usize, clear: bool) -> Option<(u64, u64)> {
+        if count > HARVEST_MAX_PAGES || !Self::is_user_range(vaddr, count) {
+            return None;
+        }
+
+        let mut accessed = 0;
+        let mut dirty = 0;
+        for i in 0..count {
+            let page_vaddr = vaddr + i * BASE_PAGE_LENGTH;
+            let pt = match unsafe { self.find_pt(page_vaddr) } {
+                Some(pt) => pt,
+                None => continue,
+            };
+
+            let entry = unsafe {
+                let mut pt = MemoryObject::<PT>::new(pt);
+                let slot = &mut pt.as_mut()[pt_index(page_vaddr)];
+                if clear && slot.is_present() {
+                    // The processor sets the bits with locked
+                    // operations, so clear them with one as well.
+                    PTEntry::from_bits_truncate(slot.as_atomic().fetch_and(
+                        !(PT_A | PT_D).bits() as usize, Ordering::SeqCst) as u64)
+                } else {
+                    *slot
+                }
+            };
+
+            if entry.is_present() {
+                if entry.is_accessed() {
+                    accessed |= 1 << i;
+                }
+                if entry.is_dirty() {
+                    dirty |= 1 << i;
+                }
+            }
+        }
+
+        if clear {
+            // Cached translations would otherwise keep the bits from
+            // being set again.
+            unsafe { self.flush(vaddr, count); }
+        }
+
+        Some((accessed, dirty))
+    }
+
+    /// Write-protect every writable mapping, and log the pages written
+    /// from then on into `ring`, which must hold a `SoftDirtyRing`.
+    /// The first write to each page gives its write access back and
+    /// logs it, instead of faulting the task. The ring page itself is
+    /// never protected. Calling this again starts a new round, with
+    /// the ring kept as it is. Returns the number of pages protected.
+    pub fn track_writes(&mut self, ring: &RawPageCap) -> usize {
+        let ring_paddr = ring.read().start_paddr();
+        self.pml4.read().set_soft_dirty_ring(ring_paddr);
+
+        let kernel_index = pml4_index(VAddr::from(KERNEL_BASE));
+        let mut count = 0;
+        for index in 0..512 {
+            let pml4_entry = { self.pml4.read().read()[index] };
+            if index != kernel_index && pml4_entry.is_present() {
+                count += unsafe { protect_pdpt(pml4_entry.get_address(), ring_paddr) };
+            }
+        }
+
+        if count > 0 {
+            unsafe { self.flush(VAddr::from(0: usize), USER_END / BASE_PAGE_LENGTH); }
+        }
+        count
+    }
+
+    /// Tear down the whole user half of this address space, returning
+    /// every paging structure below the PML4 to the untyped capability
+    /// it came from, along with the frames it owns. Other mapped
+    /// frames are left to their own capabilities. The PML4 itself is kept, so the address space
+    /// stays usable, and empty. Returns the number of structures
+    /// freed.
+    pub fn destroy(&mut self) -> usize {
+        let untyped = match self.upgrade_untyped() {
+            Some(untyped) => untyped,
+            None => return 0,
+        };
+        let mut untyped_desc = untyped.write();
+        let kernel_index = pml4_index(VAddr::from(KERNEL_BASE));
+        let mut freed = 0;
+
+        for index in 0..512 {
+            let pml4_entry = { self.pml4.read().read()[index] };
+            if index == kernel_index || !pml4_entry.is_present() {
+                continue;
+            }
+
+            // Detach the subtree and make sure no CPU caches a
+            // translation through it before its frames are reused.
+            self.pml4.write().set_user_entry(index, PML4Entry::empty());
+            self.pml4.write().invalidate_asid();
+            unsafe {
+                flush_range_all_cpus(VAddr::from(0: usize), USER_END);
+                freed += free_pdpt(untyped_desc.deref_mut(), pml4_entry.get_address());
+            }
+        }
+
+        self.table_count -= freed;
+        freed
+    }
+}
+
+impl Derived for VSpaceDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &VSpaceCap) {
+        let mut desc = arc.write();
+        desc.destroy();
+        desc.untyped_weak_pool.read().clear();
+    }
+}
+
+/// Write-protect the writable pages below a PDPT, other than the one
+/// at `ring`, marking them tracked. Returns the number of pages
+/// protected.
+unsafe fn protect_pdpt(paddr: PAddr, ring: PAddr) -> usize {
+    let mut count = 0;
+    for pdpt_entry in MemoryObject::<PDPT>::new(paddr).as_ref().iter() {
+        if !pdpt_entry.is_present() {
+            continue;
+        }
+
+        for pd_entry in MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref().iter() {
+            if !pd_entry.is_present() || pd_entry.is_page() {
+                continue;
+            }
+
+            let mut pt = MemoryObject::<PT>::new(pd_entry.get_address());
+            for slot in pt.as_mut().iter_mut() {
+                if slot.is_present() && slot.is_writeable() && slot.get_address() != ring {
+                    // Mark first, so that a write seeing the entry
+                    // read-only always finds it tracked.
+                    slot.as_atomic().fetch_or(PT_TRACKED.bits() as usize, Ordering::SeqCst);
+                    slot.as_atomic().fetch_and(!(PT_RW.bits() as usize), Ordering::SeqCst);
+                    count += 1;
+                }
+            }
+        }
+    }
+    count
+}
+
+/// Free a detached PDPT, every structure below it, and the frames
+/// owned through it. Returns the number of structures freed.
+unsafe fn free_pdpt(untyped: &mut UntypedDescriptor, paddr: PAddr) -> usize {
+    let mut freed = 1;
+    for pdpt_entry in MemoryObject::<PDPT>::new(paddr).as_ref().iter() {
+        if !pdpt_entry.is_present() {
+            continue;
+        }
+
+        for pd_entry in MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref().iter() {
+            if pd_entry.is_present() && !pd_entry.is_page() {
+                for pt_entry in MemoryObject::<PT>::new(pd_entry.get_address()).as_ref().iter() {
+                    if pt_entry.is_present() && pt_entry.is_owned() {
+                        untyped.free_page(pt_entry.get_address(), MemoryCategory::UserFrame);
+                    }
+                }
+                untyped.free_page(pd_entry.get_address(), MemoryCategory::PageTable);
+                freed += 1;
+            }
+        }
+
+        untyped.free_page(pdpt_entry.get_address(), MemoryCategory::PageTable);
+        freed += 1;
+    }
+
+    untyped.free_page(paddr, MemoryCategory::PageTable);
+    freed
+}
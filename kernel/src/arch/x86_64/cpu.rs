@@ -0,0 +1,436 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use arch::interrupt::LOCAL_APIC;
+
+/// Maximum number of CPUs supported by the kernel.
+pub const MAX_CPUS: usize = 16;
+
+/// Bitmap of CPUs that are online, indexed by local APIC id.
+static ONLINE_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Bitmap of CPUs that may still run tasks, indexed by local APIC id.
+/// A CPU taken offline keeps running them until it notices, so it
+/// leaves this bitmap itself with `stop_scheduling`, once it handed
+/// its tasks over.
+static SCHEDULING_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Bitmap of CPUs that were taken offline by `park`, and can be
+/// brought back online, indexed by local APIC id.
+static PARKED_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Bitmap of parked CPUs asked to come back online, which they do
+/// themselves with `finish_unpark`, indexed by local APIC id.
+static UNPARKING_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Local APIC id of the bootstrap CPU.
+static BOOTSTRAP_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Local APIC id of the current CPU. This is used as the CPU index
+/// for all per-CPU data.
+pub fn current_id() -> usize {
+    let id = (LOCAL_APIC.lock().id() >> 24) as usize;
+    assert!(id < MAX_CPUS);
+    id
+}
+
+/// Local APIC id of the current CPU, read from CPUID rather than the
+/// local APIC, so that it can be used where `LOCAL_APIC` may already
+/// be locked, as in the NMI handler.
+pub fn current_id_lockless() -> usize {
+    let (_, ebx, _, _) = cpuid(0x1, 0);
+    let id = (ebx >> 24) as usize;
+    assert!(id < MAX_CPUS);
+    id
+}
+
+/// Mark the CPU with the given id as online.
+pub fn set_online(id: usize) {
+    assert!(id < MAX_CPUS);
+    SCHEDULING_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+    ONLINE_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+}
+
+/// Mark the CPU with the given id as offline.
+#[allow(dead_code)]
+pub fn set_offline(id: usize) {
+    assert!(id < MAX_CPUS);
+    ONLINE_CPUS.fetch_and(!(1 << id), Ordering::SeqCst);
+}
+
+/// Take the CPU with the given id offline, and keep it parked until
+/// `unpark`. It no longer accepts tasks, but runs those it has until
+/// it calls `stop_scheduling`. Returns `false` if it was not online.
+pub fn park(id: usize) -> bool {
+    assert!(id < MAX_CPUS);
+    if ONLINE_CPUS.fetch_and(!(1 << id), Ordering::SeqCst) & (1 << id) == 0 {
+        return false;
+    }
+    PARKED_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+    true
+}
+
+/// Record that the CPU with the given id, taken offline, handed its
+/// tasks over and runs none anymore. Called by that CPU.
+pub fn stop_scheduling(id: usize) {
+    assert!(id < MAX_CPUS);
+    if !is_online(id) {
+        SCHEDULING_CPUS.fetch_and(!(1 << id), Ordering::SeqCst);
+    }
+}
+
+/// Whether the CPU with the given id may be running tasks, which is
+/// the case of online CPUs and of CPUs taken offline that did not
+/// call `stop_scheduling` yet.
+pub fn is_scheduling(id: usize) -> bool {
+    id < MAX_CPUS && SCHEDULING_CPUS.load(Ordering::SeqCst) & (1 << id) != 0
+}
+
+/// Number of CPUs that may be running tasks.
+pub fn scheduling_count() -> usize {
+    SCHEDULING_CPUS.load(Ordering::SeqCst).count_ones() as usize
+}
+
+/// Ask the CPU with the given id to come back online. It is online
+/// once it calls `finish_unpark`. Returns `false` if it was not
+/// parked.
+pub fn unpark(id: usize) -> bool {
+    assert!(id < MAX_CPUS);
+    if PARKED_CPUS.fetch_and(!(1 << id), Ordering::SeqCst) & (1 << id) == 0 {
+        return false;
+    }
+    UNPARKING_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+    true
+}
+
+/// Whether the CPU with the given id was asked to come back online,
+/// and has yet to.
+pub fn is_unparking(id: usize) -> bool {
+    assert!(id < MAX_CPUS);
+    UNPARKING_CPUS.load(Ordering::SeqCst) & (1 << id) != 0
+}
+
+/// Bring the CPU with the given id, asked to by `unpark`, back online.
+/// Called by that CPU.
+pub fn finish_unpark(id: usize) {
+    assert!(id < MAX_CPUS);
+    if UNPARKING_CPUS.fetch_and(!(1 << id), Ordering::SeqCst) & (1 << id) != 0 {
+        set_online(id);
+    }
+}
+
+/// Record the CPU with the given id as the bootstrap CPU.
+pub fn set_bootstrap(id: usize) {
+    assert!(id < MAX_CPUS);
+    BOOTSTRAP_ID.store(id, Ordering::SeqCst);
+}
+
+/// Whether the CPU with the given id is the bootstrap CPU. It takes
+/// the legacy interrupts, and is never parked.
+pub fn is_bootstrap(id: usize) -> bool {
+    BOOTSTRAP_ID.load(Ordering::SeqCst) == id
+}
+
+/// Whether the CPU with the given id is online.
+pub fn is_online(id: usize) -> bool {
+    id < MAX_CPUS && ONLINE_CPUS.load(Ordering::SeqCst) & (1 << id) != 0
+}
+
+/// Number of CPUs currently online.
+pub fn online_count() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst).count_ones() as usize
+}
+
+/// CR0 monitor coprocessor bit: `wait` honors `CR0_TS`.
+pub const CR0_MP: u64 = 1 << 1;
+/// CR0 emulation bit: x87 instructions raise #NM.
+pub const CR0_EM: u64 = 1 << 2;
+/// CR0 task switched bit: x87, SSE and AVX instructions raise #NM.
+pub const CR0_TS: u64 = 1 << 3;
+/// CR0 numeric error bit: x87 errors raise #MF.
+pub const CR0_NE: u64 = 1 << 5;
+
+/// CR4 machine check enable bit.
+pub const CR4_MCE: u64 = 1 << 6;
+/// CR4 page global enable bit.
+pub const CR4_PGE: u64 = 1 << 7;
+/// CR4 bit enabling `fxsave`, `fxrstor` and SSE instructions.
+pub const CR4_OSFXSR: u64 = 1 << 9;
+/// CR4 bit reporting unmasked SIMD floating-point errors with #XM.
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+/// CR4 bit enabling `xsave`, `xrstor` and `xsetbv`.
+pub const CR4_OSXSAVE: u64 = 1 << 18;
+/// CR4 bit enabling `rdfsbase`, `wrfsbase`, `rdgsbase` and `wrgsbase`.
+pub const CR4_FSGSBASE: u64 = 1 << 16;
+/// CR4 process-context identifier enable bit.
+pub const CR4_PCIDE: u64 = 1 << 17;
+/// CR4 supervisor-mode execution prevention bit.
+pub const CR4_SMEP: u64 = 1 << 20;
+/// CR4 supervisor-mode access prevention bit.
+pub const CR4_SMAP: u64 = 1 << 21;
+
+/// Extended feature enable register.
+pub const IA32_EFER: u32 = 0xC0000080;
+/// EFER `syscall`/`sysret` enable bit.
+pub const EFER_SCE: u64 = 1 << 0;
+/// Base of the FS segment.
+pub const IA32_FS_BASE: u32 = 0xC0000100;
+
+/// CPUID.01H:EDX bit reporting the machine check exception.
+pub const CPUID_01_EDX_MCE: u32 = 1 << 7;
+/// CPUID.01H:EDX bit reporting the machine check architecture.
+pub const CPUID_01_EDX_MCA: u32 = 1 << 14;
+/// CPUID.01H:EDX bit reporting `fxsave` and `fxrstor`.
+pub const CPUID_01_EDX_FXSR: u32 = 1 << 24;
+/// CPUID.01H:ECX bit reporting `xsave` and XCR0.
+pub const CPUID_01_ECX_XSAVE: u32 = 1 << 26;
+/// CPUID.01H:ECX bit reporting PCID support.
+pub const CPUID_01_ECX_PCID: u32 = 1 << 17;
+/// CPUID.01H:ECX bit reporting VMX (VT-x) support.
+pub const CPUID_01_ECX_VMX: u32 = 1 << 5;
+/// CPUID.01H:ECX bit reporting TSC-deadline mode of the APIC timer.
+pub const CPUID_01_ECX_TSC_DEADLINE: u32 = 1 << 24;
+/// CPUID.01H:ECX bit reporting `monitor` and `mwait`.
+pub const CPUID_01_ECX_MONITOR: u32 = 1 << 3;
+/// CPUID.05H:ECX bit reporting that leaf 05H enumerates the C-states
+/// of `mwait`.
+pub const CPUID_05_ECX_EMX: u32 = 1 << 0;
+/// CPUID.06H:EAX bit reporting a local APIC timer that keeps running
+/// in deep C-states.
+pub const CPUID_06_EAX_ARAT: u32 = 1 << 2;
+/// CPUID.80000007H:EDX bit reporting a TSC running at a constant rate
+/// in all power states.
+pub const CPUID_80000007_EDX_INVARIANT_TSC: u32 = 1 << 8;
+/// CPUID.80000001H:EDX bit reporting 1 GiB page support.
+pub const CPUID_80000001_EDX_PAGE1GB: u32 = 1 << 26;
+/// CPUID.(EAX=07H,ECX=0):EBX bit reporting `rdfsbase` and its kin.
+pub const CPUID_07_EBX_FSGSBASE: u32 = 1 << 0;
+/// CPUID.(EAX=07H,ECX=0):EBX bit reporting SMEP support.
+pub const CPUID_07_EBX_SMEP: u32 = 1 << 7;
+/// CPUID.(EAX=07H,ECX=0):EBX bit reporting SMAP support.
+pub const CPUID_07_EBX_SMAP: u32 = 1 << 20;
+/// CPUID.(EAX=07H,ECX=0):EBX bit reporting `clflushopt` support.
+pub const CPUID_07_EBX_CLFLUSHOPT: u32 = 1 << 23;
+/// CPUID.(EAX=07H,ECX=0):EBX bit reporting `clwb` support.
+pub const CPUID_07_EBX_CLWB: u32 = 1 << 24;
+
+/// Execute `cpuid` with the given leaf and subleaf. Returns `(eax,
+/// ebx, ecx, edx)`.
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}" (eax), "={ebx}" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+             : "{eax}" (leaf), "{ecx}" (subleaf)
+             :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// Read a model-specific register.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Write a model-specific register.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (value as u32), "{edx}" ((value >> 32) as u32)
+         : "memory" : "volatile");
+}
+
+/// Read the time-stamp counter.
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}" (low), "={edx}" (high) ::: "volatile"); }
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Read the CR0 register.
+pub unsafe fn cr0() -> u64 {
+    let ret: u64;
+    asm!("mov %cr0, $0" : "=r" (ret));
+    ret
+}
+
+/// Write the CR0 register.
+pub unsafe fn cr0_write(val: u64) {
+    asm!("mov $0, %cr0" :: "r" (val) : "memory");
+}
+
+/// Read the CR4 register.
+pub unsafe fn cr4() -> u64 {
+    let ret: u64;
+    asm!("mov %cr4, $0" : "=r" (ret));
+    ret
+}
+
+/// Write the CR4 register.
+pub unsafe fn cr4_write(val: u64) {
+    asm!("mov $0, %cr4" :: "r" (val) : "memory");
+}
+
+/// Whether CR4.FSGSBASE is set, and the FS and GS bases can be
+/// accessed without MSRs.
+static FSGSBASE_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Enable `rdfsbase`, `wrfsbase`, `rdgsbase` and `wrgsbase` if the
+/// processor supports them. User mode can then change its FS and GS
+/// bases itself, so they are saved on each exception.
+pub fn init_fsgsbase() {
+    let (_, ebx, _, _) = cpuid(0x7, 0);
+    if ebx & CPUID_07_EBX_FSGSBASE != 0 {
+        unsafe { cr4_write(cr4() | CR4_FSGSBASE); }
+        FSGSBASE_ENABLED.store(true, Ordering::SeqCst);
+        log!("FSGSBASE enabled.");
+    }
+}
+
+/// Whether `init_fsgsbase` enabled the FS and GS base instructions.
+pub fn has_fsgsbase() -> bool {
+    FSGSBASE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Read the FS base.
+pub unsafe fn fs_base() -> u64 {
+    if has_fsgsbase() {
+        let base: u64;
+        asm!("rdfsbase $0" : "=r" (base) ::: "volatile");
+        base
+    } else {
+        rdmsr(IA32_FS_BASE)
+    }
+}
+
+/// Write the FS base.
+pub unsafe fn set_fs_base(base: u64) {
+    if has_fsgsbase() {
+        asm!("wrfsbase $0" :: "r" (base) : "memory" : "volatile");
+    } else {
+        wrmsr(IA32_FS_BASE, base);
+    }
+}
+
+/// Read the GS base of user mode. In the kernel, it is the one `swapgs`
+/// brings back, kept in `IA32_KERNEL_GS_BASE`.
+pub unsafe fn user_gs_base() -> u64 {
+    if has_fsgsbase() {
+        let base: u64;
+        // Interrupts are disabled in the kernel, and NMIs check the GS
+        // base themselves.
+        asm!("swapgs
+              rdgsbase $0
+              swapgs" : "=r" (base) ::: "volatile");
+        base
+    } else {
+        rdmsr(::arch::percpu::IA32_KERNEL_GS_BASE)
+    }
+}
+
+/// Write the GS base of user mode.
+pub unsafe fn set_user_gs_base(base: u64) {
+    if has_fsgsbase() {
+        asm!("swapgs
+              wrgsbase $0
+              swapgs" :: "r" (base) : "memory" : "volatile");
+    } else {
+        wrmsr(::arch::percpu::IA32_KERNEL_GS_BASE, base);
+    }
+}
+
+/// Whether the local APIC timer supports TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    let (_, _, ecx, _) = cpuid(0x1, 0);
+    ecx & CPUID_01_ECX_TSC_DEADLINE != 0
+}
+
+/// Number of `mwait` sub-states of C0 to C7, four bits each, or
+/// `None` if the processor lacks `monitor` and `mwait`, or does not
+/// enumerate their C-states.
+pub fn mwait_substates() -> Option<u32> {
+    let (max, _, _, _) = cpuid(0x0, 0);
+    let (_, _, ecx, _) = cpuid(0x1, 0);
+    if max < 0x5 || ecx & CPUID_01_ECX_MONITOR == 0 {
+        return None;
+    }
+
+    let (_, _, ecx, edx) = cpuid(0x5, 0);
+    if ecx & CPUID_05_ECX_EMX == 0 {
+        return None;
+    }
+    Some(edx)
+}
+
+/// Whether the local APIC timer keeps running in deep C-states.
+pub fn has_always_running_apic_timer() -> bool {
+    let (max, _, _, _) = cpuid(0x0, 0);
+    if max < 0x6 {
+        return false;
+    }
+
+    let (eax, _, _, _) = cpuid(0x6, 0);
+    eax & CPUID_06_EAX_ARAT != 0
+}
+
+/// Whether the processor supports VMX (VT-x).
+pub fn has_vmx() -> bool {
+    let (_, _, ecx, _) = cpuid(0x1, 0);
+    ecx & CPUID_01_ECX_VMX != 0
+}
+
+/// Whether the TSC runs at a constant rate in all power states.
+pub fn has_invariant_tsc() -> bool {
+    let (max_extended, _, _, _) = cpuid(0x80000000, 0);
+    if max_extended < 0x80000007 {
+        return false;
+    }
+
+    let (_, _, _, edx) = cpuid(0x80000007, 0);
+    edx & CPUID_80000007_EDX_INVARIANT_TSC != 0
+}
+
+/// TSC frequency in hertz, from CPUID leaf 0x15, or `None` if the
+/// processor does not enumerate it.
+pub fn tsc_frequency() -> Option<u64> {
+    let (max, _, _, _) = cpuid(0x0, 0);
+    if max < 0x15 {
+        return None;
+    }
+
+    let (denominator, numerator, crystal_hz, _) = cpuid(0x15, 0);
+    if denominator == 0 || numerator == 0 {
+        return None;
+    }
+
+    let crystal_hz = if crystal_hz != 0 {
+        crystal_hz as u64
+    } else if max >= 0x16 {
+        // Derive the crystal frequency from the base frequency, in MHz.
+        let (base_mhz, _, _, _) = cpuid(0x16, 0);
+        if base_mhz == 0 {
+            return None;
+        }
+        base_mhz as u64 * 1_000_000 * denominator as u64 / numerator as u64
+    } else {
+        return None;
+    };
+
+    Some(crystal_hz * numerator as u64 / denominator as u64)
+}
+
+/// Page sizes supported by the processor, as a bitmap where bit `n`
+/// stands for pages of `1 << n` bytes.
+pub fn page_sizes() -> u64 {
+    use arch::paging::{BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH, HUGE_PAGE_LENGTH};
+
+    let mut sizes = (BASE_PAGE_LENGTH | LARGE_PAGE_LENGTH) as u64;
+
+    let (max_extended, _, _, _) = cpuid(0x80000000, 0);
+    if max_extended >= 0x80000001 {
+        let (_, _, _, edx) = cpuid(0x80000001, 0);
+        if edx & CPUID_80000001_EDX_PAGE1GB != 0 {
+            sizes |= HUGE_PAGE_LENGTH as u64;
+        }
+    }
+
+    sizes
+}
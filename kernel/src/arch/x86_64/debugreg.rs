@@ -0,0 +1,218 @@
+use core::fmt;
+use util::Mutex;
+use abi::BreakpointKind;
+use arch::USER_END;
+
+/// Number of hardware breakpoints.
+pub const BREAKPOINT_COUNT: usize = 4;
+
+/// DR6 bits of the breakpoints whose condition was met.
+const DR6_BREAKPOINTS: u64 = 0xf;
+/// DR6: a debug register access was attempted while DR7.GD was set.
+const DR6_BD: u64 = 1 << 13;
+/// DR6: single step, with RFLAGS.TF set.
+const DR6_BS: u64 = 1 << 14;
+/// DR6: task switch to a task with the debug trap flag set.
+const DR6_BT: u64 = 1 << 15;
+/// DR6 value with no debug condition reported.
+const DR6_INIT: u64 = 0xFFFF_0FF0;
+
+/// RFLAGS resume flag, suppressing instruction breakpoints for one
+/// instruction.
+pub const RFLAGS_RF: u64 = 1 << 16;
+
+/// Debug conditions reported by DR6 in a debug exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugStatus(u64);
+
+impl DebugStatus {
+    /// Raw DR6 value, with reserved bits cleared.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Bitmap of the breakpoints whose condition was met. Bits may be
+    /// set for disabled breakpoints too.
+    pub fn breakpoints(&self) -> u8 {
+        (self.0 & DR6_BREAKPOINTS) as u8
+    }
+
+    /// The exception is a single step.
+    pub fn is_single_step(&self) -> bool {
+        self.0 & DR6_BS != 0
+    }
+}
+
+impl fmt::Display for DebugStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DR6 0x{:x}{}{}{}{}", self.0,
+               if self.breakpoints() != 0 { ", breakpoint hit" } else { "" },
+               if self.is_single_step() { ", single step" } else { "" },
+               if self.0 & DR6_BT != 0 { ", task switch" } else { "" },
+               if self.0 & DR6_BD != 0 { ", debug register access" } else { "" })
+    }
+}
+
+/// Read DR6 and reset it, as the CPU never clears it. Called once per
+/// debug exception.
+pub fn take_status() -> DebugStatus {
+    let dr6: u64;
+    unsafe {
+        asm!("mov %dr6, $0" : "=r" (dr6));
+        asm!("mov $0, %dr6" :: "r" (DR6_INIT));
+    }
+    DebugStatus(dr6 & !DR6_INIT)
+}
+
+/// Addresses and DR7 controls of the hardware breakpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebugRegisters {
+    address: [u64; BREAKPOINT_COUNT],
+    control: u64,
+}
+
+impl DebugRegisters {
+    /// Set breakpoint `slot` on `length` bytes at `address`. Returns
+    /// `false` if the slot does not exist, or if `length` is not 1, 2,
+    /// 4 or 8, is not 1 for an execute breakpoint, or does not align
+    /// `address`.
+    pub fn set(&mut self, slot: usize, address: u64, kind: BreakpointKind, length: u8) -> bool {
+        self.set_in(slot, address, kind, length, 0, u64::max_value())
+    }
+
+    /// Set breakpoint `slot` as `set`, for a task. Returns `false` too
+    /// if the breakpoint is not in user space.
+    pub fn set_user(&mut self, slot: usize, address: u64, kind: BreakpointKind, length: u8) -> bool {
+        self.set_in(slot, address, kind, length, 0, USER_END as u64)
+    }
+
+    /// Set breakpoint `slot` if it lies within `start..end`.
+    fn set_in(&mut self, slot: usize, address: u64, kind: BreakpointKind, length: u8,
+              start: u64, end: u64) -> bool {
+        let length_bits = match length {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => return false,
+        };
+        let kind_bits = match kind {
+            BreakpointKind::Execute if length == 1 => 0b00,
+            BreakpointKind::Execute => return false,
+            BreakpointKind::Write => 0b01,
+            BreakpointKind::ReadWrite => 0b11,
+        };
+        if slot >= BREAKPOINT_COUNT || address % length as u64 != 0 ||
+            address < start || address >= end || end - address < length as u64
+        {
+            return false;
+        }
+
+        self.clear(slot);
+        self.address[slot] = address;
+        self.control |= (1 << (slot * 2)) |
+            ((kind_bits | (length_bits << 2)) << (16 + slot * 4));
+        true
+    }
+
+    /// Disable breakpoint `slot`. Returns `false` if it was not set.
+    pub fn clear(&mut self, slot: usize) -> bool {
+        if !self.is_set(slot) {
+            return false;
+        }
+        self.address[slot] = 0;
+        self.control &= !((0b11 << (slot * 2)) | (0b1111 << (16 + slot * 4)));
+        true
+    }
+
+    /// Whether breakpoint `slot` is set.
+    pub fn is_set(&self, slot: usize) -> bool {
+        slot < BREAKPOINT_COUNT && self.control & (0b11 << (slot * 2)) != 0
+    }
+
+    /// Whether breakpoint `slot` is set on instruction execution.
+    fn is_execute(&self, slot: usize) -> bool {
+        self.is_set(slot) && (self.control >> (16 + slot * 4)) & 0b11 == 0
+    }
+
+    /// Whether `status` reports an instruction breakpoint of these
+    /// registers. Such a breakpoint is a fault, and returning to the
+    /// instruction needs `RFLAGS_RF` not to hit it again.
+    pub fn hit_execute(&self, status: DebugStatus) -> bool {
+        (0..BREAKPOINT_COUNT).any(|slot| status.breakpoints() & (1 << slot) != 0 && self.is_execute(slot))
+    }
+
+    /// Bitmap of the breakpoints set.
+    fn set_slots(&self) -> u8 {
+        (0..BREAKPOINT_COUNT).filter(|slot| self.is_set(*slot)).fold(0, |slots, slot| slots | (1 << slot))
+    }
+}
+
+/// Breakpoints set on kernel code and data, by the in-kernel
+/// debugger. They take precedence over task breakpoints in the same
+/// slot.
+static KERNEL_BREAKPOINTS: Mutex<DebugRegisters> = Mutex::new(DebugRegisters {
+    address: [0; BREAKPOINT_COUNT],
+    control: 0,
+});
+
+/// Read DR7.
+fn dr7() -> u64 {
+    let dr7: u64;
+    unsafe { asm!("mov %dr7, $0" : "=r" (dr7)); }
+    dr7
+}
+
+/// Load the breakpoints of the task about to run, together with the
+/// kernel ones, on the current CPU.
+pub fn load(task: &DebugRegisters) {
+    let mut registers = *KERNEL_BREAKPOINTS.lock();
+    for slot in 0..BREAKPOINT_COUNT {
+        if !registers.is_set(slot) && task.is_set(slot) {
+            registers.address[slot] = task.address[slot];
+            registers.control |= task.control &
+                ((0b11 << (slot * 2)) | (0b1111 << (16 + slot * 4)));
+        }
+    }
+
+    // Most tasks use no breakpoint, so avoid writing DR7 again.
+    if registers.control == 0 && dr7() & 0xff == 0 {
+        return;
+    }
+    unsafe {
+        // Disable all breakpoints while their addresses change.
+        asm!("mov $0, %dr7" :: "r" (0u64));
+        asm!("mov $0, %dr0" :: "r" (registers.address[0]));
+        asm!("mov $0, %dr1" :: "r" (registers.address[1]));
+        asm!("mov $0, %dr2" :: "r" (registers.address[2]));
+        asm!("mov $0, %dr3" :: "r" (registers.address[3]));
+        asm!("mov $0, %dr7" :: "r" (registers.control));
+    }
+}
+
+/// Set kernel breakpoint `slot`, as `DebugRegisters::set`. It is
+/// loaded on each CPU at its next task switch.
+#[allow(dead_code)]
+pub fn set_kernel_breakpoint(slot: usize, address: u64, kind: BreakpointKind, length: u8) -> bool {
+    KERNEL_BREAKPOINTS.lock().set(slot, address, kind, length)
+}
+
+/// Clear kernel breakpoint `slot`. Returns `false` if it was not set.
+#[allow(dead_code)]
+pub fn clear_kernel_breakpoint(slot: usize) -> bool {
+    KERNEL_BREAKPOINTS.lock().clear(slot)
+}
+
+/// Bitmap of the breakpoints of `status` set by the in-kernel
+/// debugger, and whether one of them is an instruction breakpoint.
+/// Others are task breakpoints, which kernel accesses to user memory
+/// can hit too.
+pub fn kernel_hits(status: DebugStatus) -> (u8, bool) {
+    match KERNEL_BREAKPOINTS.try_lock() {
+        Some(breakpoints) =>
+            (status.breakpoints() & breakpoints.set_slots(), breakpoints.hit_execute(status)),
+        // Resuming with RF set is harmless if the breakpoint was not
+        // an instruction one.
+        None => (status.breakpoints(), status.breakpoints() != 0),
+    }
+}
@@ -0,0 +1,201 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use common::PAddr;
+use arch::{cpu, command_line_option};
+use arch::paging::MemoryObject;
+use arch::cpu::{CR0_MP, CR0_EM, CR0_TS, CR0_NE, CR4_OSFXSR, CR4_OSXMMEXCPT, CR4_OSXSAVE,
+                CPUID_01_EDX_FXSR, CPUID_01_ECX_XSAVE};
+
+/// XCR0 bits of the x87 and SSE state, always enabled with XSAVE.
+const XCR0_X87_SSE: u64 = 0b11;
+/// XCR0 bit of the upper halves of the AVX registers.
+const XCR0_AVX: u64 = 1 << 2;
+/// XCR0 bits of the AVX-512 state, enabled all together or not at all.
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// Length of the `fxsave` area.
+const FXSAVE_LENGTH: usize = 512;
+/// Alignment `xsave` requires, and that also suits `fxsave`.
+pub const FPU_STATE_ALIGNMENT: usize = 64;
+
+/// Offset of the x87 control word in the legacy area.
+const FCW_OFFSET: usize = 0;
+/// Offset of MXCSR in the legacy area.
+const MXCSR_OFFSET: usize = 24;
+/// x87 control word after `fninit`: all exceptions masked.
+const FCW_DEFAULT: u16 = 0x037F;
+/// MXCSR after reset: all exceptions masked.
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// Whether `xsave` is used, instead of `fxsave`.
+static USE_XSAVE: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether the state is only restored on the first #NM of the task
+/// after each switch, instead of on every switch.
+static LAZY: AtomicBool = ATOMIC_BOOL_INIT;
+/// State components enabled in XCR0.
+static XCR0: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Length of the save area of each task.
+static STATE_LENGTH: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Enable the x87, SSE and AVX state for user mode, with `xsave` when
+/// the processor has it, and pick the policy given by the `fpu=`
+/// command line option, `eager` by default. The kernel itself never
+/// touches the state. Must be called on each CPU, on the bootstrap one
+/// before any task is created.
+pub fn init() {
+    let (_, _, ecx, edx) = cpu::cpuid(0x1, 0);
+    assert!(edx & CPUID_01_EDX_FXSR != 0, "fxsave is not supported");
+
+    unsafe {
+        cpu::cr0_write((cpu::cr0() & !CR0_EM) | CR0_MP | CR0_NE);
+        cpu::cr4_write(cpu::cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+    }
+
+    if ecx & CPUID_01_ECX_XSAVE != 0 {
+        let (low, _, _, high) = cpu::cpuid(0xD, 0);
+        let supported = (low as u64) | ((high as u64) << 32);
+        let mut xcr0 = XCR0_X87_SSE | (supported & XCR0_AVX);
+        if supported & XCR0_AVX512 == XCR0_AVX512 {
+            xcr0 |= XCR0_AVX512;
+        }
+
+        unsafe {
+            cpu::cr4_write(cpu::cr4() | CR4_OSXSAVE);
+            xsetbv(xcr0);
+        }
+        // EBX is the length for the components enabled in XCR0.
+        let (_, length, _, _) = cpu::cpuid(0xD, 0);
+        XCR0.store(xcr0 as usize, Ordering::SeqCst);
+        STATE_LENGTH.store(length as usize, Ordering::SeqCst);
+        USE_XSAVE.store(true, Ordering::SeqCst);
+        log!("XSAVE enabled, XCR0 0x{:x}, {} bytes of state.", xcr0, length);
+    } else {
+        STATE_LENGTH.store(FXSAVE_LENGTH, Ordering::SeqCst);
+        log!("FXSAVE enabled, {} bytes of state.", FXSAVE_LENGTH);
+    }
+
+    match command_line_option("fpu") {
+        None | Some("eager") => (),
+        Some("lazy") => LAZY.store(true, Ordering::SeqCst),
+        Some(other) => log!("Unknown fpu={}, using eager switching.", other),
+    }
+}
+
+/// Length of the save area of a task.
+pub fn state_length() -> usize {
+    let length = STATE_LENGTH.load(Ordering::Relaxed);
+    assert!(length != 0, "FPU is not initialized");
+    length
+}
+
+/// Write XCR0.
+unsafe fn xsetbv(value: u64) {
+    asm!("xsetbv" :: "{ecx}" (0), "{eax}" (value as u32), "{edx}" ((value >> 32) as u32)
+         :: "volatile");
+}
+
+/// Clear `CR0_TS`, if set.
+unsafe fn clts() {
+    if cpu::cr0() & CR0_TS != 0 {
+        asm!("clts" :::: "volatile");
+    }
+}
+
+/// Set `CR0_TS`, if clear.
+unsafe fn stts() {
+    let cr0 = cpu::cr0();
+    if cr0 & CR0_TS == 0 {
+        cpu::cr0_write(cr0 | CR0_TS);
+    }
+}
+
+/// Extended state of a task: x87, SSE, and the AVX components enabled
+/// in XCR0. Its save area is allocated from untyped memory.
+#[derive(Debug)]
+pub struct FpuState {
+    paddr: PAddr,
+    /// Whether the registers hold the state, and the area is stale.
+    loaded: bool,
+}
+
+impl FpuState {
+    /// State saved at `paddr`, with the x87 and SSE exceptions masked
+    /// and every register cleared.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` must point to `state_length()` zeroed bytes, aligned to
+    /// `FPU_STATE_ALIGNMENT`, and owned by the state.
+    pub unsafe fn new(paddr: PAddr) -> FpuState {
+        // With XSAVE, the zeroed header puts all components in their
+        // initial state, but MXCSR is always loaded from the legacy
+        // area.
+        let object = MemoryObject::<u8>::slice(paddr, FXSAVE_LENGTH);
+        let area = object.as_ptr();
+        *(area.offset(FCW_OFFSET as isize) as *mut u16) = FCW_DEFAULT;
+        *(area.offset(MXCSR_OFFSET as isize) as *mut u32) = MXCSR_DEFAULT;
+
+        FpuState {
+            paddr: paddr,
+            loaded: false,
+        }
+    }
+
+    /// Prepare the registers for the task about to run. With the eager
+    /// policy, its state is restored. With the lazy one, the first
+    /// instruction using it raises #NM, and `handle_unavailable`
+    /// restores it.
+    pub unsafe fn enter(&mut self) {
+        if self.loaded {
+            clts();
+        } else if LAZY.load(Ordering::Relaxed) {
+            stts();
+        } else {
+            clts();
+            self.restore();
+        }
+    }
+
+    /// Restore the state on the #NM raised by the task. Returns `false`
+    /// if the state was already loaded, which makes the #NM a fault of
+    /// the task.
+    pub unsafe fn handle_unavailable(&mut self) -> bool {
+        if self.loaded {
+            return false;
+        }
+        clts();
+        self.restore();
+        true
+    }
+
+    /// Save the state of the task that just entered the kernel, if it
+    /// is loaded. The task may run on another CPU next, so the
+    /// registers cannot be trusted to keep it.
+    pub unsafe fn exit(&mut self) {
+        if !self.loaded {
+            return;
+        }
+
+        let object = MemoryObject::<u8>::slice(self.paddr, state_length());
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            let xcr0 = XCR0.load(Ordering::Relaxed) as u64;
+            asm!("xsave64 ($0)" :: "r" (object.as_ptr()), "{eax}" (xcr0 as u32), "{edx}" ((xcr0 >> 32) as u32)
+                 : "memory" : "volatile");
+        } else {
+            asm!("fxsave64 ($0)" :: "r" (object.as_ptr()) : "memory" : "volatile");
+        }
+        self.loaded = false;
+    }
+
+    /// Load the registers from the save area.
+    unsafe fn restore(&mut self) {
+        let object = MemoryObject::<u8>::slice(self.paddr, state_length());
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            let xcr0 = XCR0.load(Ordering::Relaxed) as u64;
+            asm!("xrstor64 ($0)" :: "r" (object.as_ptr()), "{eax}" (xcr0 as u32), "{edx}" ((xcr0 >> 32) as u32)
+                 : "memory" : "volatile");
+        } else {
+            asm!("fxrstor64 ($0)" :: "r" (object.as_ptr()) : "memory" : "volatile");
+        }
+        self.loaded = true;
+    }
+}
@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use super::cpu::{self, MAX_CPUS};
+use super::interrupt::{TaskRuntime, Exception, timer};
+use super::addr::VAddr;
+use super::paging;
+
+/// Time to the next deadline below which the idle loop stays in C1,
+/// as waking up from deeper states takes too long to be worth it.
+const DEEP_IDLE_NS: u64 = 1_000_000;
+
+/// Whether `monitor` and `mwait` are used, rather than `hlt`.
+static MWAIT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// MWAIT hint of the deepest C-state the processor enumerates, used
+/// when the next deadline is far enough.
+static DEEP_HINT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Words the idle loop of each CPU monitors. Nothing needs to write
+/// them: interrupts, reschedule IPIs among them, end the wait all the
+/// same.
+static WAKE_WORDS: [AtomicUsize; MAX_CPUS] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Pick how the idle loop waits. C-states deeper than C1 are only
+/// entered if the local APIC timer keeps running in them, or the
+/// next deadline would be missed.
+pub fn init() {
+    let hints = match cpu::mwait_substates() {
+        Some(hints) => hints,
+        None => {
+            log!("Idle: hlt");
+            return;
+        },
+    };
+    MWAIT.store(true, Ordering::SeqCst);
+
+    // Sub-state counts of C0 to C7, four bits each. Hint `n << 4`
+    // enters C(n + 1).
+    let deepest = if cpu::has_always_running_apic_timer() {
+        (1..8).rev().find(|&state| (hints >> (state * 4)) & 0xf != 0).unwrap_or(1)
+    } else {
+        1
+    };
+    DEEP_HINT.store((deepest - 1) << 4, Ordering::SeqCst);
+    log!("Idle: mwait, deepest C{}", deepest);
+}
+
+#[naked]
+unsafe fn hlt_loop() -> ! {
+    asm!("1: hlt
+          jmp 1b" :::: "volatile");
+    ::core::intrinsics::unreachable();
+}
+
+/// Wait with `mwait`, address to monitor in `r9` and hint in `rbx`.
+/// The wait also ends on a write to the monitored line, so it is
+/// entered again.
+#[naked]
+unsafe fn mwait_loop() -> ! {
+    asm!("1: mov %r9, %rax
+          xor %ecx, %ecx
+          xor %edx, %edx
+          monitor
+          mov %rbx, %rax
+          mwait
+          jmp 1b" :::: "volatile");
+    ::core::intrinsics::unreachable();
+}
+
+/// Switch to an idle task that runs in kernel-mode, waiting with
+/// interrupts enabled. This is used when no other task is runnable.
+/// Like normal context switching, this returns only when exceptions
+/// (interrupts) happen. `sleep_ns` is the time to the next timer
+/// deadline, or `None` if no timer is pending: the further away it
+/// is, the deeper the C-state entered.
+pub fn idle(sleep_ns: Option<u64>) -> Exception {
+    let mut task_runtime = TaskRuntime::default();
+
+    if MWAIT.load(Ordering::Relaxed) {
+        let deep = sleep_ns.map(|sleep_ns| sleep_ns >= DEEP_IDLE_NS).unwrap_or(true);
+        let hint = if deep { DEEP_HINT.load(Ordering::Relaxed) } else { 0 };
+        let word = &WAKE_WORDS[cpu::current_id()] as *const AtomicUsize as u64;
+        let mut registers = task_runtime.registers();
+        registers.rbx = hint as u64;
+        registers.r9 = word;
+        task_runtime.set_registers(&registers);
+        task_runtime.set_instruction_pointer(VAddr::from(mwait_loop as *const () as u64));
+    } else {
+        task_runtime.set_instruction_pointer(VAddr::from(hlt_loop as *const () as u64));
+    }
+
+    unsafe {
+        task_runtime.switch_to(false, None)
+    }
+}
+
+/// Take the interrupt waiting for the current CPU, and return it. The
+/// kernel runs with interrupts disabled, so handlers that run long call
+/// this once `interrupt_pending` says one is waiting; otherwise it
+/// waits for the next one to come.
+pub fn interrupt_window() -> Exception {
+    idle(Some(0))
+}
+
+/// Wait on the current CPU, parked, with its local timer stopped and
+/// in the deepest C-state, until an interrupt comes. The reschedule
+/// IPI that brings it back online is one. Shootdowns skip parked
+/// CPUs, so the TLB is flushed before returning.
+pub fn park() -> Exception {
+    timer::stop_local();
+    let exception = idle(None);
+    unsafe { paging::flush_all(); }
+    exception
+}
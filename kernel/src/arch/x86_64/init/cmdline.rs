@@ -0,0 +1,43 @@
+use core::str;
+
+/// Longest kernel command line kept. Longer ones are truncated.
+const COMMAND_LINE_LENGTH: usize = 256;
+
+/// Copy of the kernel command line, as the multiboot one is only
+/// mapped before paging is initialized. Written once, at boot.
+static mut COMMAND_LINE: [u8; COMMAND_LINE_LENGTH] = [0; COMMAND_LINE_LENGTH];
+static mut COMMAND_LINE_SIZE: usize = 0;
+
+/// Keep a copy of `command_line`.
+///
+/// # Safety
+///
+/// Must be called only once, on the bootstrap CPU, before anything
+/// reads the command line.
+pub unsafe fn save(command_line: &str) {
+    // Truncate at a character boundary, so the copy stays valid UTF-8.
+    let mut length = ::core::cmp::min(command_line.len(), COMMAND_LINE_LENGTH);
+    while !command_line.is_char_boundary(length) {
+        length -= 1;
+    }
+    COMMAND_LINE[..length].copy_from_slice(&command_line.as_bytes()[..length]);
+    COMMAND_LINE_SIZE = length;
+}
+
+/// The kernel command line.
+pub fn command_line() -> &'static str {
+    unsafe { str::from_utf8_unchecked(&COMMAND_LINE[..COMMAND_LINE_SIZE]) }
+}
+
+/// Value of the `key=value` option of the kernel command line, or an
+/// empty string for a bare `key`. Returns `None` if the option is not
+/// given.
+pub fn option(key: &str) -> Option<&'static str> {
+    for word in command_line().split_whitespace() {
+        let mut parts = word.splitn(2, '=');
+        if parts.next() == Some(key) {
+            return Some(parts.next().unwrap_or(""));
+        }
+    }
+    None
+}
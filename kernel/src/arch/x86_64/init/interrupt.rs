@@ -1,17 +1,28 @@
 use arch::interrupt::{self, IDT, IO_APIC, LOCAL_APIC, disable_pic};
 
-/// Initialize interrupt. Disable PIC and then initialize APIC
+/// Initialize interrupt. Remap and mask the legacy PICs, and then initialize APIC
 /// together with keyboard interrupt on I/O APIC.
 pub fn init() {
     unsafe { disable_pic() };
     IDT.load();
+    interrupt::init_vectors();
+    interrupt::init_apic();
 
     {
         let mut local_apic = LOCAL_APIC.lock();
         let mut io_apic = IO_APIC.lock();
         let local_apic_id = local_apic.id() as u8;
-        io_apic.set_irq(0x1, local_apic_id, interrupt::KEYBOARD_INTERRUPT_CODE);
+        io_apic.set_isa_irq(0x1, local_apic_id, interrupt::KEYBOARD_INTERRUPT_CODE);
 
         local_apic.set_siv(0x1FF);
     }
 }
+
+/// Initialize interrupt on a secondary CPU, after `init` ran on the
+/// bootstrap one. The IDT, the vectors and the I/O APIC are shared, so
+/// only the local APIC and its timer are left to set up.
+pub fn init_secondary() {
+    IDT.load();
+    LOCAL_APIC.lock().set_siv(0x1FF);
+    interrupt::timer::init_secondary();
+}
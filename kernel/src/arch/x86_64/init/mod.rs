@@ -11,18 +11,33 @@ mod interrupt;
 /// Segmentation initialization code.
 mod segmentation;
 
+/// Kernel command line options.
+mod cmdline;
+
+/// Secondary CPU startup.
+mod smp;
+
 pub use self::paging::{KERNEL_PML4, KERNEL_PDPT, KERNEL_PD,
-                       OBJECT_POOL_PT, OBJECT_POOL_START_VADDR,
-                       LOCAL_APIC_PAGE_VADDR, IO_APIC_PAGE_VADDR};
-pub use self::segmentation::set_kernel_stack;
+                       OBJECT_POOL_PT, OBJECT_POOL_START_VADDR, KERNEL_PD_VADDR,
+                       OBJECT_POOL_EXTENSION_START_VADDR, OBJECT_POOL_MAX_EXTENSIONS,
+                       MMIO_PT, MMIO_WINDOW_START_VADDR, MMIO_WINDOW_SIZE,
+                       KERNEL_STACK_AREA_START_VADDR, KERNEL_STACK_AREA_MAX_PTS,
+                       FRAME_WINDOW_LENGTH,
+                       kernel_stack_guard_page_vaddr, map_frame_window};
+pub use self::segmentation::{set_kernel_stack, set_interrupt_stack, KERNEL_STACK_INDEX, load_ldt,
+                              gdt_region, tss_region, ldt_region};
+pub use self::cmdline::{command_line, option as command_line_option};
+pub use self::smp::start_secondary_cpus;
 
-use ::kmain;
+use ::{kmain, kmain_secondary};
 use super::{kernel_end_paddr, kernel_start_paddr, kernel_start_vaddr};
 
 use core::mem;
 use core::slice::{self, Iter};
 
 use common::{PAddr, MemoryRegion};
+use util::{align_up, align_down};
+use arch::paging::BASE_PAGE_LENGTH;
 
 extern {
     /// Multiboot signature exposed by linker.
@@ -60,12 +75,18 @@ impl<'a> Iterator for FreeRegionsIterator<'a> {
 }
 
 /// Initialization information to be passed to `kmain`. It contains
-/// free regions and rinit and kernel memory region information. At
-/// most 16 free regions are supported.
+/// free regions, persistent memory regions, device memory regions, and
+/// rinit and kernel memory region information. At most 16 free
+/// regions, 4 persistent memory regions and 16 device memory regions
+/// are supported.
 #[derive(Debug)]
 pub struct InitInfo {
     free_regions_size: usize,
     free_regions: [Option<MemoryRegion>; 16],
+    pmem_regions_size: usize,
+    pmem_regions: [Option<MemoryRegion>; 4],
+    device_regions_size: usize,
+    device_regions: [Option<MemoryRegion>; 16],
     rinit_region: MemoryRegion,
     kernel_region: MemoryRegion,
 }
@@ -77,6 +98,20 @@ impl InitInfo {
         FreeRegionsIterator(self.free_regions.iter())
     }
 
+    /// Return a `FreeRegionsIterator` that allows iterating over all
+    /// persistent memory regions. Those are never part of the free
+    /// regions.
+    pub fn pmem_regions(&self) -> FreeRegionsIterator {
+        FreeRegionsIterator(self.pmem_regions.iter())
+    }
+
+    /// Return a `FreeRegionsIterator` that allows iterating over all
+    /// device memory regions, in the order the boot loader reported
+    /// them. Those are never part of the free regions.
+    pub fn device_regions(&self) -> FreeRegionsIterator {
+        FreeRegionsIterator(self.device_regions.iter())
+    }
+
     /// The kernel memory region.
     pub fn kernel_region(&self) -> MemoryRegion {
         self.kernel_region
@@ -91,6 +126,10 @@ impl InitInfo {
     pub fn new(kernel_region: MemoryRegion, rinit_region: MemoryRegion) -> InitInfo {
         InitInfo { free_regions_size: 0,
                    free_regions: [None; 16],
+                   pmem_regions_size: 0,
+                   pmem_regions: [None; 4],
+                   device_regions_size: 0,
+                   device_regions: [None; 16],
                    kernel_region: kernel_region,
                    rinit_region: rinit_region }
     }
@@ -100,6 +139,28 @@ impl InitInfo {
         self.free_regions[self.free_regions_size] = Some(region);
         self.free_regions_size += 1;
     }
+
+    /// Append a new persistent memory region to the `InitInfo`.
+    pub fn push_pmem_region(&mut self, region: MemoryRegion) {
+        if self.pmem_regions_size == self.pmem_regions.len() {
+            log!("too many persistent memory regions, ignoring {:?}", region);
+            return;
+        }
+
+        self.pmem_regions[self.pmem_regions_size] = Some(region);
+        self.pmem_regions_size += 1;
+    }
+
+    /// Append a new device memory region to the `InitInfo`.
+    pub fn push_device_region(&mut self, region: MemoryRegion) {
+        if self.device_regions_size == self.device_regions.len() {
+            log!("too many device memory regions, ignoring {:?}", region);
+            return;
+        }
+
+        self.device_regions[self.device_regions_size] = Some(region);
+        self.device_regions_size += 1;
+    }
 }
 
 /// Read the multiboot structure. Construct an `InitInfo` with all
@@ -114,6 +175,11 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
         })
     }.unwrap();
 
+    if let Some(command_line) = bootinfo.command_line() {
+        log!("command line: {}", command_line);
+        unsafe { cmdline::save(command_line); }
+    }
+
     let rinit_module = bootinfo.modules().unwrap().next().unwrap();
     log!("rinit module: {:?}", rinit_module);
     
@@ -128,12 +194,33 @@ fn bootstrap_archinfo() -> (InitInfo, MemoryRegion) {
     
     for area in bootinfo.memory_regions().unwrap() {
         use self::multiboot::{MemoryType};
-        
+
+        if area.memory_type() == MemoryType::Persistent {
+            // Persistent memory is never handed to the volatile frame
+            // allocator.
+            archinfo.push_pmem_region(MemoryRegion::new(area.base_address(),
+                                                        area.length() as usize));
+            continue;
+        }
+
+        if area.memory_type() == MemoryType::Reserved {
+            // Reserved regions hold device memory. Only whole pages
+            // inside them can be handed out.
+            let start = align_up(area.base_address(), BASE_PAGE_LENGTH);
+            let end = align_down(area.base_address() + area.length() as usize, BASE_PAGE_LENGTH);
+            if end > start {
+                archinfo.push_device_region(MemoryRegion::new(start, end.into(): usize -
+                                                              start.into(): usize));
+            }
+            continue;
+        }
+
         if !(area.memory_type() == MemoryType::RAM) {
             continue;
         }
 
         let mut cur_region = MemoryRegion::new(area.base_address(), area.length() as usize);
+        smp::reserve_boot_memory(&mut cur_region);
 
         if cur_region.skip_up(&archinfo.kernel_region()) {
             assert!(cur_region.skip_up(&archinfo.rinit_region()));
@@ -160,8 +247,21 @@ pub fn kinit() {
     log!("alloc_region: {:?}", alloc_region);
 
     paging::init(&mut alloc_region);
-    segmentation::init();
+    segmentation::init(segmentation::initial_stack());
     interrupt::init();
+    ::arch::interrupt::init_nmi();
+    ::arch::interrupt::init_mce();
+    ::arch::interrupt::init_kdebug();
+    ::arch::interrupt::init_syscall();
+    if ::arch::command_line_option("dump_descriptors").is_some() {
+        ::arch::segmentation::log_loaded_gdt();
+        ::arch::interrupt::log_loaded_idt();
+    }
+    ::arch::user::init();
+    ::arch::cpu::init_fsgsbase();
+    ::arch::fpu::init();
+    #[cfg(feature="kpti")]
+    ::arch::kpti::init(&mut alloc_region);
 
     archinfo.push_free_region(alloc_region);
 
@@ -174,5 +274,30 @@ pub fn kinit() {
         log!("I/O APIC version: 0x{:x}", io_apic.version());
     }
 
+    ::arch::cpu::set_bootstrap(::arch::cpu::current_id());
+    ::arch::cpu::set_online(::arch::cpu::current_id());
+
     kmain(archinfo);
 }
+
+/// Secondary CPU entrypoint, called from `ap_start64_high` on the stack
+/// `start_secondary_cpus` gave the CPU. It sets up the state of its
+/// own the bootstrap CPU set up in `kinit`, comes online, and jumps to
+/// `kmain_secondary`.
+#[no_mangle]
+pub extern "C" fn kinit_secondary() -> ! {
+    unsafe { ::arch::paging::init_pcid(); }
+    segmentation::init(smp::boot_stack());
+    interrupt::init_secondary();
+    ::arch::interrupt::init_nmi();
+    ::arch::interrupt::init_mce();
+    ::arch::interrupt::init_kdebug();
+    ::arch::interrupt::init_syscall();
+    ::arch::user::init();
+    ::arch::cpu::init_fsgsbase();
+    ::arch::fpu::init();
+
+    smp::finish_start();
+
+    kmain_secondary()
+}
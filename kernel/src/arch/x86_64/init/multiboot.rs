@@ -314,7 +314,9 @@ impl BootDevice {
 #[derive(Debug, PartialEq, Eq)]
 pub enum MemoryType {
     RAM = 1,
-    Unusable = 2,
+    Reserved = 2,
+    Unusable = 5,
+    Persistent = 7,
 }
 
 /// Multiboot format of the MMAP buffer.
@@ -344,6 +346,9 @@ impl MemoryEntry {
     pub fn memory_type(&self) -> MemoryType {
         match self.mtype {
             1 => MemoryType::RAM,
+            2 => MemoryType::Reserved,
+            // 12 is the legacy pre-ACPI 6.0 persistent memory type.
+            7 | 12 => MemoryType::Persistent,
             _ => MemoryType::Unusable
         }
     }
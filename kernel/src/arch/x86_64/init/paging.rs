@@ -4,7 +4,7 @@ use arch::{kernel_start_paddr, kernel_start_vaddr,
            kernel_end_paddr};
 use arch::paging::{PTEntry, PML4, PDPT, PD, PT,
                    pml4_index, pdpt_index, pd_index, pt_index,
-                   BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH};
+                   BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH, HUGE_PAGE_LENGTH};
 use arch::{KERNEL_BASE};
 use common::{PAddr, VAddr, MemoryRegion};
 use util::{block_count, align_up, ExternReadonlyObject, ExternMutex};
@@ -28,25 +28,47 @@ const INITIAL_ALLOC_PDPT_OFFSET: usize = 0x1000;
 const INITIAL_ALLOC_PD_OFFSET: usize = 0x2000;
 /// Offset for the new Object Pool PT virtual address.
 const INITIAL_ALLOC_OBJECT_POOL_PT_OFFSET: usize = 0x3000;
+/// Offset for the new MMIO window PT virtual address.
+const INITIAL_ALLOC_MMIO_PT_OFFSET: usize = 0x4000;
 /// Offset for the new kernel PT virtual address.
 #[allow(dead_code)]
-const INITIAL_ALLOC_KERNEL_PT_START_OFFSET: usize = 0x4000;
+const INITIAL_ALLOC_KERNEL_PT_START_OFFSET: usize = 0x5000;
 
 // Below should be used AFTER switching to new page table structure.
 /// Object Pool virtual address after switching to new page table.
 pub const OBJECT_POOL_START_VADDR: VAddr = VAddr::new(KERNEL_BASE +
                                                       0xe00000);
 /// Object Pool size, excluding the recursive Object Pool virtual
-/// address, local APIC page address, and I/O APIC page address.
+/// address, MMIO window PT address, and kernel PD address.
 pub const OBJECT_POOL_SIZE: usize = 509;
 /// Object Pool PT virtual address after switching to new page table.
 pub const OBJECT_POOL_PT_VADDR: VAddr = VAddr::new(KERNEL_BASE +
                                                    0xfff000);
-/// Local APIC page virtual address after switching to new page table.
-pub const LOCAL_APIC_PAGE_VADDR: VAddr = VAddr::new(KERNEL_BASE +
-                                                    0xffe000);
-/// I/O APIC page virtual address after switching to new page table.
-pub const IO_APIC_PAGE_VADDR: VAddr = VAddr::new(KERNEL_BASE + 0xffd000);
+/// MMIO window PT virtual address after switching to new page table.
+pub const MMIO_PT_VADDR: VAddr = VAddr::new(KERNEL_BASE + 0xffe000);
+/// Kernel PD virtual address after switching to new page table.
+pub const KERNEL_PD_VADDR: VAddr = VAddr::new(KERNEL_BASE + 0xffd000);
+/// Start of the window device memory is mapped in.
+pub const MMIO_WINDOW_START_VADDR: VAddr = VAddr::new(KERNEL_BASE + 0x2000000);
+/// Number of pages in the MMIO window.
+pub const MMIO_WINDOW_SIZE: usize = 512;
+/// Start of the Object Pool extension PTs, which are chained on
+/// demand once the Object Pool PT is full. Each one maps 2 MiB.
+pub const OBJECT_POOL_EXTENSION_START_VADDR: VAddr = VAddr::new(KERNEL_BASE +
+                                                                0x1000000);
+/// Maximum number of Object Pool extension PTs.
+pub const OBJECT_POOL_MAX_EXTENSIONS: usize = 8;
+/// Start of the area kernel stacks are mapped in, below guard pages.
+/// Its PTs are allocated on demand, each mapping 2 MiB.
+pub const KERNEL_STACK_AREA_START_VADDR: VAddr = VAddr::new(KERNEL_BASE + 0x3000000);
+/// Maximum number of kernel stack area PTs.
+pub const KERNEL_STACK_AREA_MAX_PTS: usize = 8;
+/// Start of the window mapping the frame allocator's memory, in the
+/// kernel PDPT entry right below the kernel image.
+pub const FRAME_WINDOW_START_VADDR: VAddr = VAddr::new(KERNEL_BASE -
+                                                       HUGE_PAGE_LENGTH as u64);
+/// Length of the frame window.
+pub const FRAME_WINDOW_LENGTH: usize = HUGE_PAGE_LENGTH;
 
 /// Initial PD. Invalid after switching to the new page table.
 static INITIAL_PD: ExternMutex<PD> =
@@ -55,6 +77,9 @@ static INITIAL_PD: ExternMutex<PD> =
 /// Object Pool PT struct.
 pub static OBJECT_POOL_PT: ExternMutex<[PTEntry; OBJECT_POOL_SIZE]> =
     unsafe { ExternMutex::new(None) };
+/// MMIO window PT struct.
+pub static MMIO_PT: ExternMutex<[PTEntry; MMIO_WINDOW_SIZE]> =
+    unsafe { ExternMutex::new(None) };
 /// Kernel PML4 struct.
 pub static KERNEL_PML4: ExternReadonlyObject<PML4> =
     unsafe { ExternReadonlyObject::new() };
@@ -66,7 +91,7 @@ pub static KERNEL_PD: ExternReadonlyObject<PD> =
     unsafe { ExternReadonlyObject::new() };
 
 /// Guard page virtual address after switching to the new page table.
-fn kernel_stack_guard_page_vaddr() -> VAddr {
+pub fn kernel_stack_guard_page_vaddr() -> VAddr {
     unsafe { VAddr::from((&kernel_stack_guard_page as *const _) as u64) }
 }
 
@@ -147,9 +172,9 @@ fn alloc_kernel_pd(region: &mut MemoryRegion, pdpt: &mut PDPT, alloc_base: PAddr
 }
 
 /// Allocate the object pool PT. It also maps a reverse ObjectPool PT
-/// access point, and APIC pages (local and I/O).
+/// access point, the MMIO window PT, and the kernel PD.
 fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) -> Unique<PT> {
-    use arch::paging::{PTEntry, PDEntry, PD_P, PD_RW, PT_P, PT_RW, PT_PWT, PT_PCD};
+    use arch::paging::{PTEntry, PDEntry, PD_P, PD_RW, PT_P, PT_RW};
     
     let paddr = alloc_base + INITIAL_ALLOC_OBJECT_POOL_PT_OFFSET;
     let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_OBJECT_POOL_PT_OFFSET;
@@ -169,34 +194,17 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
         }
 
         {
-            /// Write 64 bits to msr register.
-            #[allow(dead_code)]
-            unsafe fn wrmsr(msr: u32, value: u64) {
-                let low = value as u32;
-                let high = (value >> 32) as u32;
-                asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high) : "memory" : "volatile" );
-            }
-
-            /// Read 64 bits msr register.
-            #[allow(unused_mut)]
-            unsafe fn rdmsr(msr: u32) -> u64 {
-                let (high, low): (u32, u32);
-                asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
-                ((high as u64) << 32) | (low as u64)
-            }
-
-            let apic_msr = unsafe { rdmsr(0x1B) };
-            assert!(apic_msr & (1<<11) == (1<<11));
-            let apic_base = PAddr::from((apic_msr >> 12) * 0x1000);
-            // Mapping APIC Page
-            let apic_pt_index = pt_index(LOCAL_APIC_PAGE_VADDR);
-            pt[apic_pt_index] = PTEntry::new(apic_base, PT_P | PT_RW | PT_PWT | PT_PCD);
+            // Mapping MMIO window PT, so that devices can be mapped
+            // in later.
+            let mmio_pt_index = pt_index(MMIO_PT_VADDR);
+            pt[mmio_pt_index] = PTEntry::new(alloc_base + INITIAL_ALLOC_MMIO_PT_OFFSET, PT_P | PT_RW);
         }
 
         {
-            let io_apic_base = PAddr::from(0xfec00000: u64);
-            let io_apic_pt_index = pt_index(IO_APIC_PAGE_VADDR);
-            pt[io_apic_pt_index] = PTEntry::new(io_apic_base, PT_P | PT_RW | PT_PWT | PT_PCD);
+            // Mapping kernel PD, so that Object Pool extensions can
+            // be chained in later.
+            let kernel_pd_pt_index = pt_index(KERNEL_PD_VADDR);
+            pt[kernel_pd_pt_index] = PTEntry::new(alloc_base + INITIAL_ALLOC_PD_OFFSET, PT_P | PT_RW);
         }
     }
 
@@ -207,6 +215,29 @@ fn alloc_object_pool_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAdd
     pt_unique
 }
 
+/// Allocate the empty MMIO window PT.
+fn alloc_mmio_pt(region: &mut MemoryRegion, pd: &mut PD, alloc_base: PAddr) -> Unique<PT> {
+    use arch::paging::{PTEntry, PDEntry, PD_P, PD_RW};
+
+    let paddr = alloc_base + INITIAL_ALLOC_MMIO_PT_OFFSET;
+    let vaddr = INITIAL_ALLOC_START_VADDR + INITIAL_ALLOC_MMIO_PT_OFFSET;
+
+    log!("mmio_pt, paddr: 0x{:x}, vaddr: 0x{:x}", paddr, vaddr);
+
+    let mut pt_unique = unsafe { Unique::new_unchecked(vaddr.into(): usize as *mut PT) };
+
+    {
+        let pt = unsafe { pt_unique.as_mut() };
+        *pt = [PTEntry::empty(); 512];
+    }
+
+    region.move_up(paddr + BASE_PAGE_LENGTH);
+
+    pd[pd_index(MMIO_WINDOW_START_VADDR)] = PDEntry::new(paddr, PD_P | PD_RW);
+
+    pt_unique
+}
+
 /// Allocate one kernel page using `offset_size`.
 fn alloc_kernel_page(pt: &mut PT, offset_size: usize) {
     use arch::paging::{PT_P, PT_RW};
@@ -280,13 +311,53 @@ fn map_alloc_region(alloc_region: &mut MemoryRegion) -> PAddr {
     map_alloc_start_paddr
 }
 
+/// Map `regions` one after the other from `FRAME_WINDOW_START_VADDR`
+/// with large pages, using the free page `pd` as the page directory,
+/// and return the start of the window. The mapping is
+/// in the kernel PDPT, so it is visible in every address space.
+///
+/// # Safety
+///
+/// Must be called only once, after switching to the new page table.
+/// Each region must be aligned to `LARGE_PAGE_LENGTH`, and all of them
+/// no longer than `FRAME_WINDOW_LENGTH` together.
+pub unsafe fn map_frame_window(regions: &[MemoryRegion], pd: PAddr) -> VAddr {
+    use arch::paging::{PDEntry, PDPTEntry, PD_P, PD_RW, PD_PS, PDPT_P, PDPT_RW, MemoryObject};
+
+    assert!(regions.iter().map(|region| region.length()).sum::<usize>() <= FRAME_WINDOW_LENGTH);
+
+    {
+        let mut pd_object = MemoryObject::<PD>::new(pd);
+        let pd_table = pd_object.as_mut();
+        *pd_table = [PDEntry::empty(); 512];
+
+        let mut index = 0;
+        for region in regions {
+            assert!(region.start_paddr().into(): usize % LARGE_PAGE_LENGTH == 0);
+            assert!(region.length() % LARGE_PAGE_LENGTH == 0);
+
+            for i in 0..(region.length() / LARGE_PAGE_LENGTH) {
+                pd_table[index] = PDEntry::new(region.start_paddr() + i * LARGE_PAGE_LENGTH,
+                                               PD_P | PD_RW | PD_PS);
+                index += 1;
+            }
+        }
+    }
+
+    MemoryObject::<PDPT>::new(KERNEL_PDPT.paddr()).as_mut()[pdpt_index(FRAME_WINDOW_START_VADDR)] =
+        PDPTEntry::new(pd, PDPT_P | PDPT_RW);
+
+    FRAME_WINDOW_START_VADDR
+}
+
 /// Main function to initialize paging.
 pub fn init(mut alloc_region: &mut MemoryRegion) {
-    use arch::paging::{switch_to};
+    use arch::paging::{switch_to, init_pcid};
     
     let kernel_page_size = block_count(kernel_end_paddr().into(): usize -
                                        kernel_start_paddr().into(): usize, BASE_PAGE_LENGTH);
     let alloc_size = 3 /* PML4, PDPT, and PD, and object pool PT */ +
+        1 /* MMIO window PT */ +
         block_count(kernel_page_size, 512) /* Kernel page mapping PT */ ;
     
     assert!(alloc_size <= 512);
@@ -310,6 +381,9 @@ pub fn init(mut alloc_region: &mut MemoryRegion) {
     let _ = alloc_object_pool_pt(&mut alloc_region,
                                  unsafe { pd_unique.as_mut() },
                                  alloc_base_paddr);
+    let _ = alloc_mmio_pt(&mut alloc_region,
+                          unsafe { pd_unique.as_mut() },
+                          alloc_base_paddr);
 
     alloc_kernel_pts(&mut alloc_region, unsafe { pd_unique.as_mut() }, alloc_base_paddr);
     
@@ -317,7 +391,10 @@ pub fn init(mut alloc_region: &mut MemoryRegion) {
         INITIAL_PD.unbootstrap();
     }
     unsafe { switch_to(KERNEL_PML4.paddr()); }
+    unsafe { init_pcid(); }
     unsafe {
         OBJECT_POOL_PT.bootstrap(OBJECT_POOL_PT_VADDR.into(): usize as *mut _);
+        MMIO_PT.bootstrap(MMIO_PT_VADDR.into(): usize as *mut _);
+        KERNEL_PD.bootstrap(KERNEL_PD_VADDR.into(): usize as *const _, KERNEL_PD.paddr());
     }
 }
@@ -1,44 +1,124 @@
-use arch::segmentation::{SegmentDescriptor, SegmentSelector, TaskStateSegment};
+use arch::segmentation::{self, Gdt, Ldt, SegmentSelector, TaskStateSegment, IST_LENGTH, LDT_SELECTOR,
+                         kernel_gdt};
+use arch::interrupt::dtables::lldt;
+use arch::cpu::{self, MAX_CPUS};
+use arch::percpu;
+use common::VAddr;
 use core::mem::size_of;
 
 extern {
-    /// GDT memory address exposed by linker.
-    static mut GDT: [SegmentDescriptor; 9];
     /// Initial stack address exposed by linker.
     static init_stack: u64;
 }
 
-/// Task State Segment static.
-static mut TSS: TaskStateSegment = TaskStateSegment::empty();
+/// GDT of each CPU, indexed by local APIC id.
+static mut GDTS: [Gdt; MAX_CPUS] = [Gdt::empty(); MAX_CPUS];
+
+/// Task State Segment of each CPU, indexed by local APIC id.
+static mut TSSS: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::empty(); MAX_CPUS];
+
+/// LDT of each CPU, indexed by local APIC id. Tasks with an LDT have it
+/// copied here when they are switched to, as their own is not mapped at
+/// a fixed address.
+static mut LDTS: [Ldt; MAX_CPUS] = [Ldt::empty(); MAX_CPUS];
+
+/// Whether the LDT of each CPU is loaded, indexed by local APIC id.
+static mut LDT_LOADED: [bool; MAX_CPUS] = [false; MAX_CPUS];
 
 /// Load the task state register.
 pub unsafe fn load_tr(sel: SegmentSelector) {
     asm!("ltr $0" :: "r" (sel.bits()));
 }
 
+/// TSS of the current CPU, found from its per-CPU data.
+unsafe fn current_tss() -> &'static mut TaskStateSegment {
+    &mut *(percpu::current().tss() as *mut TaskStateSegment)
+}
+
+/// Interrupt stack table entry of the kernel stack, which interrupt
+/// gates without a dedicated stack use.
+pub const KERNEL_STACK_INDEX: u16 = 1;
+
 /// Set the current kernel stack. Essential for context switching.
 pub unsafe fn set_kernel_stack(addr: u64) {
-    TSS.sp0 = addr;
-    TSS.ist1 = addr;
+    let tss = current_tss();
+    tss.set_rsp(0, addr);
+    tss.set_ist(KERNEL_STACK_INDEX as usize, addr);
+}
+
+/// Set the stack of interrupt stack table entry `index` of the current
+/// CPU to `stack_top`. Entries other than `KERNEL_STACK_INDEX` hold the
+/// dedicated stacks of exceptions that can hit the kernel.
+pub unsafe fn set_interrupt_stack(index: u16, stack_top: u64) {
+    assert!(index != KERNEL_STACK_INDEX && index as usize <= IST_LENGTH);
+    current_tss().set_ist(index as usize, stack_top);
+}
+
+/// Load `ldt` on the current CPU, or unload the LDT if `None`. Called
+/// on each switch to a task.
+pub unsafe fn load_ldt(ldt: Option<&Ldt>) {
+    let cpu = percpu::current().id();
+    match ldt {
+        Some(ldt) => {
+            LDTS[cpu] = *ldt;
+            if !LDT_LOADED[cpu] {
+                lldt(LDT_SELECTOR);
+                LDT_LOADED[cpu] = true;
+            }
+        },
+        None if LDT_LOADED[cpu] => {
+            lldt(SegmentSelector::from_raw(0));
+            LDT_LOADED[cpu] = false;
+        },
+        None => (),
+    }
 }
 
-/// Main function to initialize interrupt.
-pub fn init() {
+/// Virtual address and length of the GDTs of all CPUs.
+#[allow(dead_code)]
+pub fn gdt_region() -> (VAddr, usize) {
+    unsafe { (VAddr::from(&GDTS as *const _ as u64), size_of::<[Gdt; MAX_CPUS]>()) }
+}
+
+/// Virtual address and length of the TSSs of all CPUs.
+#[allow(dead_code)]
+pub fn tss_region() -> (VAddr, usize) {
+    unsafe { (VAddr::from(&TSSS as *const _ as u64), size_of::<[TaskStateSegment; MAX_CPUS]>()) }
+}
+
+/// Virtual address and length of the LDTs of all CPUs.
+#[allow(dead_code)]
+pub fn ldt_region() -> (VAddr, usize) {
+    unsafe { (VAddr::from(&LDTS as *const _ as u64), size_of::<[Ldt; MAX_CPUS]>()) }
+}
+
+/// Top of the initial stack, which only the bootstrap processor runs
+/// on.
+pub fn initial_stack() -> u64 {
+    unsafe { &init_stack as *const _ as u64 }
+}
+
+/// Build the GDT and TSS of the current CPU and switch to them from
+/// the boot GDT, with `kernel_stack` as the kernel stack until the
+/// first switch to a task. Must be called on each CPU, on the stack
+/// it started on.
+pub fn init(kernel_stack: u64) {
+    let cpu = cpu::current_id_lockless();
     unsafe {
-        use arch::segmentation::{DESC_P, DESC_DPL3,
-                                 TYPE_SYS_TSS_AVAILABLE};
-        let kernel_stack = &init_stack as *const _ as u64;
-        let tss_vaddr = &TSS as *const _ as u64;
+        GDTS[cpu] = kernel_gdt(&TSSS[cpu], &LDTS[cpu]);
+        GDTS[cpu].load();
 
-        set_kernel_stack(kernel_stack);
-        GDT[7] = SegmentDescriptor::new((tss_vaddr & 0xFFFFFFFF) as u32,
-                                        size_of::<TaskStateSegment>() as u32);
-        GDT[7].insert(DESC_P | TYPE_SYS_TSS_AVAILABLE | DESC_DPL3);
-        GDT[8] = SegmentDescriptor::from_raw(tss_vaddr >> 32);
-
-        log!("kernel_stack = 0x{:x}", kernel_stack);
-        // asm!("ltr ax" :: "{rax}"(&GDT[7] as *const _ as usize)
-        //      : "rax" : "intel", "volatile");
+        // The selectors are those of the boot GDT, but the segment
+        // registers cache the descriptors they were loaded from.
+        segmentation::load_cs(SegmentSelector::new(1));
+        segmentation::load_ss(SegmentSelector::new(2));
+        segmentation::load_ds(SegmentSelector::new(2));
+        segmentation::load_es(SegmentSelector::new(2));
         load_tr(SegmentSelector::new(7));
+        percpu::init(&TSSS[cpu] as *const _ as u64);
+
+        set_kernel_stack(kernel_stack);
+        log!("CPU {}: GDT at 0x{:x}, kernel_stack = 0x{:x}",
+             cpu, &GDTS[cpu] as *const _ as u64, kernel_stack);
     }
 }
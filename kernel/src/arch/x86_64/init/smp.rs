@@ -0,0 +1,198 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use arch::paging::{PML4, PDPT, PD, PML4Entry, PDPTEntry, PDEntry, MemoryObject,
+                   PML4_P, PML4_RW, PDPT_P, PDPT_RW, PD_P, PD_RW, PD_PS,
+                   pml4_index, BASE_PAGE_LENGTH};
+use arch::interrupt::{LOCAL_APIC, IpiMode, local_apic_ids, tsc_sync_source, tsc_sync_target};
+use arch::interrupt::timer::now_ns;
+use arch::cpu::{self, MAX_CPUS};
+use arch::KERNEL_BASE;
+use common::{PAddr, VAddr, MemoryRegion};
+use meminfo::MemoryCategory;
+use frame;
+use super::{KERNEL_PML4, KERNEL_PDPT};
+
+extern {
+    /// Start of the secondary CPU startup code exposed by linker.
+    static ap_boot_start: u8;
+    /// End of the secondary CPU startup code exposed by linker.
+    static ap_boot_end: u8;
+}
+
+/// Physical address the startup code is copied to, below 1MB, where
+/// secondary CPUs start in real mode. Its page number is the vector of
+/// the startup IPI.
+const AP_BOOT_PADDR: usize = 0x8000;
+/// Physical addresses of the page tables secondary CPUs enter long mode
+/// on, right after the startup code.
+const AP_BOOT_PML4_PADDR: usize = 0x9000;
+const AP_BOOT_PDPT_PADDR: usize = 0xA000;
+const AP_BOOT_PD_PADDR: usize = 0xB000;
+/// Length of the memory the startup code and its page tables take.
+const AP_BOOT_LENGTH: usize = 0x4000;
+
+/// Block order of the stack of each secondary CPU, sixteen pages.
+const STACK_ORDER: usize = 4;
+
+/// Time to wait after the INIT IPI, in nanoseconds.
+const INIT_DELAY_NS: u64 = 10_000_000;
+/// Time to wait for a secondary CPU to start after each startup IPI.
+const STARTUP_DELAY_NS: u64 = 200_000;
+/// Time to wait for a secondary CPU to start after the last startup
+/// IPI, before giving up on it.
+const START_TIMEOUT_NS: u64 = 100_000_000;
+
+/// Page table the startup code switches to once in the kernel, read by
+/// `ap_start64_high`.
+#[no_mangle]
+pub static mut AP_BOOT_CR3: u64 = 0;
+/// Top of the stack of the secondary CPU being started, read by
+/// `ap_start64_high`.
+#[no_mangle]
+pub static mut AP_BOOT_STACK: u64 = 0;
+
+/// Whether the memory of the startup code was kept out of the free
+/// regions.
+static BOOT_MEMORY_RESERVED: AtomicBool = ATOMIC_BOOL_INIT;
+/// Set by the secondary CPU being started once it runs on its stack.
+static STARTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Keep the memory of the startup code out of the RAM area `area`,
+/// moving `area` past it, if `area` holds it. Secondary CPUs are only
+/// started if one area did.
+pub fn reserve_boot_memory(area: &mut MemoryRegion) {
+    let boot = MemoryRegion::new(PAddr::from(AP_BOOT_PADDR), AP_BOOT_LENGTH);
+    if area.start_paddr() <= boot.start_paddr() && area.end_paddr() > boot.end_paddr() {
+        area.skip_up(&boot);
+        BOOT_MEMORY_RESERVED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Copy the startup code to `AP_BOOT_PADDR`, build its page tables,
+/// which identity map the first 2MB and share the kernel half of the
+/// kernel PML4, and record the kernel PML4 for `ap_start64_high`.
+unsafe fn install_boot_code() {
+    let start = &ap_boot_start as *const u8;
+    let length = &ap_boot_end as *const u8 as usize - start as usize;
+    assert!(length <= BASE_PAGE_LENGTH);
+    let code = MemoryObject::<u8>::slice(PAddr::from(AP_BOOT_PADDR), length);
+    ptr::copy_nonoverlapping(start, code.as_ptr(), length);
+
+    {
+        let mut pd = MemoryObject::<PD>::new(PAddr::from(AP_BOOT_PD_PADDR));
+        let pd = pd.as_mut();
+        *pd = [PDEntry::empty(); 512];
+        pd[0] = PDEntry::new(PAddr::from(0: usize), PD_P | PD_RW | PD_PS);
+    }
+    {
+        let mut pdpt = MemoryObject::<PDPT>::new(PAddr::from(AP_BOOT_PDPT_PADDR));
+        let pdpt = pdpt.as_mut();
+        *pdpt = [PDPTEntry::empty(); 512];
+        pdpt[0] = PDPTEntry::new(PAddr::from(AP_BOOT_PD_PADDR), PDPT_P | PDPT_RW);
+    }
+    {
+        let mut pml4 = MemoryObject::<PML4>::new(PAddr::from(AP_BOOT_PML4_PADDR));
+        let pml4 = pml4.as_mut();
+        *pml4 = [PML4Entry::empty(); 512];
+        pml4[0] = PML4Entry::new(PAddr::from(AP_BOOT_PDPT_PADDR), PML4_P | PML4_RW);
+        pml4[pml4_index(VAddr::from(KERNEL_BASE))] = PML4Entry::new(KERNEL_PDPT.paddr(), PML4_P | PML4_RW);
+    }
+
+    AP_BOOT_CR3 = KERNEL_PML4.paddr().into(): u64;
+}
+
+/// Wait for the secondary CPU being started to set `STARTED`, for at
+/// most `timeout_ns`.
+fn wait_started(timeout_ns: u64) -> bool {
+    let deadline = now_ns() + timeout_ns;
+    while now_ns() < deadline {
+        if STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+    }
+    STARTED.load(Ordering::SeqCst)
+}
+
+/// Start the CPU with local APIC id `id` with the INIT, startup,
+/// startup IPI sequence. Returns whether it started.
+fn start(id: usize) -> bool {
+    let vector = (AP_BOOT_PADDR / BASE_PAGE_LENGTH) as u8;
+
+    LOCAL_APIC.lock().send_ipi(id as u32, IpiMode::Init);
+    let deadline = now_ns() + INIT_DELAY_NS;
+    while now_ns() < deadline { }
+
+    for _ in 0..2 {
+        LOCAL_APIC.lock().send_ipi(id as u32, IpiMode::Startup(vector));
+        if wait_started(STARTUP_DELAY_NS) {
+            return true;
+        }
+    }
+    wait_started(START_TIMEOUT_NS)
+}
+
+/// Start the secondary CPUs the MADT lists, one at a time, each on a
+/// stack of its own, and wait for each to check its TSC and come
+/// online. A CPU that does not start in time is reset and left out.
+/// Must be called once, on the bootstrap CPU, once the frame allocator
+/// and the timer are set up.
+///
+/// With KPTI, all CPUs would share the entry stack, so secondary CPUs
+/// are left out.
+pub fn start_secondary_cpus() {
+    if cfg!(feature="kpti") {
+        log!("KPTI enabled, secondary CPUs not started.");
+        return;
+    }
+    if !BOOT_MEMORY_RESERVED.load(Ordering::SeqCst) {
+        log!("No memory for the startup code, secondary CPUs not started.");
+        return;
+    }
+
+    let current = cpu::current_id();
+    let ids = local_apic_ids() & !(1 << current);
+    if ids == 0 {
+        return;
+    }
+    unsafe { install_boot_code(); }
+
+    for id in (0..MAX_CPUS).filter(|&id| ids & (1 << id) != 0) {
+        let stack = match frame::allocate(STACK_ORDER, MemoryCategory::KernelStack) {
+            Some(stack) => stack,
+            None => {
+                log!("No memory for the stack of CPU {}.", id);
+                return;
+            },
+        };
+        unsafe {
+            AP_BOOT_STACK = (frame::to_vaddr(stack) + (BASE_PAGE_LENGTH << STACK_ORDER)).into(): u64;
+        }
+        STARTED.store(false, Ordering::SeqCst);
+
+        if !start(id) {
+            // Reset it, so that it does not start later on the stack
+            // of the next one.
+            LOCAL_APIC.lock().send_ipi(id as u32, IpiMode::Init);
+            unsafe { frame::free(stack, STACK_ORDER, MemoryCategory::KernelStack); }
+            log!("CPU {} did not start.", id);
+            continue;
+        }
+
+        tsc_sync_source(id);
+        while !cpu::is_online(id) { }
+        log!("CPU {} online.", id);
+    }
+}
+
+/// Called by a secondary CPU once set up, on the stack it was started
+/// on: let the bootstrap CPU check its TSC, and go online.
+pub fn finish_start() {
+    STARTED.store(true, Ordering::SeqCst);
+    tsc_sync_target();
+    cpu::set_online(cpu::current_id());
+}
+
+/// Top of the stack the current secondary CPU was started on.
+pub fn boot_stack() -> u64 {
+    unsafe { AP_BOOT_STACK }
+}
@@ -1,30 +1,188 @@
 use common::*;
-use arch::init::{LOCAL_APIC_PAGE_VADDR, IO_APIC_PAGE_VADDR};
 use util::{Mutex};
+use arch::cpu;
+use arch::paging::{map_device, VolatileMmio, BASE_PAGE_LENGTH};
 use super::{InterruptVector};
+use super::madt;
+
+/// Model-specific register holding the local APIC base.
+const IA32_APIC_BASE: u32 = 0x1B;
+/// Global enable bit of `IA32_APIC_BASE`.
+const IA32_APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Physical address of the I/O APIC, if the MADT does not give one.
+const IO_APIC_BASE: u64 = 0xfec00000;
+
+/// I/O APIC version register.
+const IO_APIC_VERSION: u32 = 0x1;
+/// First I/O APIC redirection table register.
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Redirection entry bit: the input is active low.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+/// Redirection entry bit: the input is level-triggered.
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+/// Redirection entry bit: the input is masked.
+const REDIRECTION_MASKED: u64 = 1 << 16;
+/// Shift of the destination APIC id in a redirection entry.
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+/// Interrupt command register, low half.
+const ICR_LOW: u32 = 0x300;
+/// Interrupt command register, high half, holding the destination.
+const ICR_HIGH: u32 = 0x310;
+/// ICR bit: the previous IPI is still being delivered.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICR bit: assert level, required for all modes but INIT de-assert.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// ICR destination shorthand: all CPUs including the current one.
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+/// ICR destination shorthand: all CPUs except the current one.
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// First interrupt request register, each covering 32 vectors.
+const IRR_BASE: u32 = 0x200;
+/// Number of interrupt request registers.
+const IRR_COUNT: u32 = 8;
+
+/// Local vector table entry of the timer.
+const LVT_TIMER: u32 = 0x320;
+/// Local vector table entry of the performance counters.
+const LVT_PERFMON: u32 = 0x340;
+/// Timer initial count register.
+const TIMER_INITIAL_COUNT: u32 = 0x380;
+/// Timer current count register.
+const TIMER_CURRENT_COUNT: u32 = 0x390;
+/// Timer divide configuration register.
+const TIMER_DIVIDE: u32 = 0x3E0;
+
+/// Delivery mode of an inter-processor interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiMode {
+    /// Deliver the given vector.
+    Fixed(InterruptVector),
+    /// Deliver a non-maskable interrupt.
+    Nmi,
+    /// Reset the target to its wait-for-SIPI state.
+    Init,
+    /// Start a CPU waiting for SIPI at the real-mode page with the
+    /// given number.
+    Startup(u8),
+}
+
+impl IpiMode {
+    /// Vector and delivery mode bits of the ICR.
+    fn bits(&self) -> u32 {
+        match *self {
+            IpiMode::Fixed(vector) => vector as u32 & 0xff,
+            IpiMode::Nmi => 0b100 << 8,
+            IpiMode::Init => 0b101 << 8,
+            IpiMode::Startup(page) => (0b110 << 8) | page as u32,
+        }
+    }
+}
+
+/// How an interrupt input is triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Active level of an interrupt input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// I/O APIC redirection table entry, delivering an input to one CPU
+/// in fixed mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: InterruptVector,
+    /// Local APIC id of the CPU the interrupt is delivered to.
+    pub destination: u8,
+    pub trigger: TriggerMode,
+    pub polarity: Polarity,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    /// Entry for an edge-triggered, active high input, delivered
+    /// unmasked.
+    pub fn new(vector: InterruptVector, destination: u8) -> RedirectionEntry {
+        RedirectionEntry {
+            vector: vector,
+            destination: destination,
+            trigger: TriggerMode::Edge,
+            polarity: Polarity::ActiveHigh,
+            masked: false,
+        }
+    }
+
+    fn bits(&self) -> u64 {
+        let mut bits = (self.vector as u64 & 0xff) |
+            ((self.destination as u64) << REDIRECTION_DESTINATION_SHIFT);
+        if self.polarity == Polarity::ActiveLow {
+            bits |= REDIRECTION_ACTIVE_LOW;
+        }
+        if self.trigger == TriggerMode::Level {
+            bits |= REDIRECTION_LEVEL;
+        }
+        if self.masked {
+            bits |= REDIRECTION_MASKED;
+        }
+        bits
+    }
+
+    fn from_bits(bits: u64) -> RedirectionEntry {
+        RedirectionEntry {
+            vector: bits & 0xff,
+            destination: (bits >> REDIRECTION_DESTINATION_SHIFT) as u8,
+            trigger: if bits & REDIRECTION_LEVEL != 0 { TriggerMode::Level } else { TriggerMode::Edge },
+            polarity: if bits & REDIRECTION_ACTIVE_LOW != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            masked: bits & REDIRECTION_MASKED != 0,
+        }
+    }
+}
 
 /// Local APIC pointer.
 #[derive(Debug)]
 pub struct LocalAPIC {
-    address: VAddr,
+    mmio: VolatileMmio,
 }
 
 /// I/O APIC pointer.
 #[derive(Debug)]
 pub struct IOAPIC {
-    address: VAddr,
+    mmio: VolatileMmio,
 }
 
 /// The local APIC static.
 pub static LOCAL_APIC: Mutex<LocalAPIC> = Mutex::new(LocalAPIC {
-    address: LOCAL_APIC_PAGE_VADDR
+    mmio: VolatileMmio::empty()
 });
 
 /// The I/O APIC static.
 pub static IO_APIC: Mutex<IOAPIC> = Mutex::new(IOAPIC {
-    address: IO_APIC_PAGE_VADDR
+    mmio: VolatileMmio::empty()
 });
 
+/// Map the local and I/O APIC registers, reading the I/O APIC address
+/// and ISA IRQ overrides from the MADT. Must be called after paging
+/// is initialized, and before any other APIC access.
+pub fn init() {
+    let apic_msr = unsafe { cpu::rdmsr(IA32_APIC_BASE) };
+    assert!(apic_msr & IA32_APIC_BASE_ENABLE == IA32_APIC_BASE_ENABLE);
+    let apic_base = PAddr::from((apic_msr >> 12) * 0x1000);
+    let io_apic_base = madt::init().unwrap_or(PAddr::from(IO_APIC_BASE));
+
+    LOCAL_APIC.lock().mmio = unsafe { map_device(apic_base, BASE_PAGE_LENGTH) };
+    let mut io_apic = IO_APIC.lock();
+    io_apic.mmio = unsafe { map_device(io_apic_base, BASE_PAGE_LENGTH) };
+    madt::check_overrides(io_apic.redirection_entry_count() as u32);
+}
+
 #[allow(dead_code)]
 impl LocalAPIC {
     /// Read a value from the local APIC.
@@ -33,8 +191,7 @@ impl LocalAPIC {
     ///
     /// `reg` must be valid.
     unsafe fn read(&self, reg: u32) -> u32 {
-        use core::intrinsics::{volatile_load};
-        volatile_load((self.address.into(): usize + reg as usize) as *const u32)
+        self.mmio.read(reg as usize)
     }
 
     /// Write a value to the local APIC.
@@ -43,8 +200,7 @@ impl LocalAPIC {
     ///
     /// `reg` must be valid.
     unsafe fn write(&mut self, reg: u32, value: u32) {
-        use core::intrinsics::{volatile_store};
-        volatile_store((self.address.into(): usize + reg as usize) as *mut u32, value);
+        self.mmio.write(reg as usize, value);
     }
 
     /// APIC id.
@@ -72,20 +228,72 @@ impl LocalAPIC {
         unsafe { self.write(0xB0, 0) }
     }
 
-    /// Enable timer with a specific value.
-    pub fn enable_timer(&mut self) {
-        unsafe {
-            self.write(0x3E0, 0x3);
-            self.write(0x380, 0x10000);
-            self.write(0x320, (1<<17) | 0x40);
-            log!("timer register is 0b{:b}", self.read(0x320));
-        }
+    /// Set the timer local vector table entry, holding the vector,
+    /// mask bit and timer mode.
+    pub fn set_timer(&mut self, lvt: u32) {
+        unsafe { self.write(LVT_TIMER, lvt) }
+    }
+
+    /// Set the performance counter local vector table entry, holding
+    /// the vector, delivery mode and mask bit.
+    pub fn set_perfmon(&mut self, lvt: u32) {
+        unsafe { self.write(LVT_PERFMON, lvt) }
+    }
+
+    /// Set the timer divide configuration.
+    pub fn set_timer_divide(&mut self, divide: u32) {
+        unsafe { self.write(TIMER_DIVIDE, divide) }
+    }
+
+    /// Set the timer initial count, starting the countdown. Zero
+    /// stops the timer.
+    pub fn set_timer_initial_count(&mut self, count: u32) {
+        unsafe { self.write(TIMER_INITIAL_COUNT, count) }
+    }
+
+    /// Timer current count.
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read(TIMER_CURRENT_COUNT) }
+    }
+
+    /// Whether an interrupt is waiting to be delivered to the CPU.
+    /// Vectors below 32 are reserved, so the first register is
+    /// skipped.
+    pub fn interrupt_pending(&self) -> bool {
+        (1..IRR_COUNT).any(|i| unsafe { self.read(IRR_BASE + i * 0x10) } != 0)
     }
 
     /// Current error status.
     pub fn error_status(&self) -> u32 {
         unsafe { self.read(0x280) }
     }
+
+    /// Write the ICR, sending an IPI, and wait until it is delivered.
+    fn write_icr(&mut self, destination: u32, command: u32) {
+        unsafe {
+            self.write(ICR_HIGH, destination << 24);
+            self.write(ICR_LOW, command | ICR_LEVEL_ASSERT);
+            while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 { }
+        }
+    }
+
+    /// Send an inter-processor interrupt to the CPU with local APIC
+    /// id `target`.
+    pub fn send_ipi(&mut self, target: u32, mode: IpiMode) {
+        self.write_icr(target, mode.bits());
+    }
+
+    /// Send an inter-processor interrupt to all CPUs except the
+    /// current one.
+    pub fn broadcast_ipi(&mut self, mode: IpiMode) {
+        self.write_icr(0, ICR_ALL_EXCLUDING_SELF | mode.bits());
+    }
+
+    /// Send an inter-processor interrupt to all CPUs, including the
+    /// current one.
+    pub fn broadcast_ipi_including_self(&mut self, mode: IpiMode) {
+        self.write_icr(0, ICR_ALL_INCLUDING_SELF | mode.bits());
+    }
 }
 
 #[allow(dead_code)]
@@ -96,9 +304,8 @@ impl IOAPIC {
     ///
     /// `reg` must be valid.
     unsafe fn read(&self, reg: u32) -> u32 {
-        use core::intrinsics::{volatile_load, volatile_store};
-        volatile_store((self.address.into(): usize + 0x0 as usize) as *mut u32, reg);
-        volatile_load((self.address.into(): usize + 0x10 as usize) as *const u32)
+        self.mmio.write(0x0, reg);
+        self.mmio.read(0x10)
     }
 
     /// Write a value to the I/O APIC.
@@ -107,9 +314,8 @@ impl IOAPIC {
     ///
     /// `reg` must be valid.
     unsafe fn write(&mut self, reg: u32, value: u32) {
-        use core::intrinsics::volatile_store;
-        volatile_store((self.address.into(): usize + 0x0 as usize) as *mut u32, reg);
-        volatile_store((self.address.into(): usize + 0x10 as usize) as *mut u32, value);
+        self.mmio.write(0x0, reg);
+        self.mmio.write(0x10, value);
     }
 
     /// I/O APIC id.
@@ -127,24 +333,56 @@ impl IOAPIC {
         unsafe { self.read(0x2) }
     }
 
-    /// Set IRQ to an interrupt vector.
-    pub fn set_irq(&mut self, irq: u8, apic_id: u8, vector: InterruptVector) {
-        let vector = vector as u8;
+    /// Number of redirection entries, that is, of interrupt inputs.
+    pub fn redirection_entry_count(&self) -> u8 {
+        (unsafe { self.read(IO_APIC_VERSION) } >> 16) as u8 + 1
+    }
+
+    /// Redirection entry of input `gsi`.
+    pub fn redirection(&self, gsi: u32) -> RedirectionEntry {
+        assert!(gsi < self.redirection_entry_count() as u32);
+        let index = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        let low = unsafe { self.read(index) } as u64;
+        let high = unsafe { self.read(index + 1) } as u64;
+        RedirectionEntry::from_bits((high << 32) | low)
+    }
+
+    /// Program the redirection entry of input `gsi`. The entry is
+    /// masked while it is being changed.
+    pub fn set_redirection(&mut self, gsi: u32, entry: RedirectionEntry) {
+        assert!(gsi < self.redirection_entry_count() as u32);
+        let index = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        let bits = entry.bits();
+        unsafe {
+            self.write(index, REDIRECTION_MASKED as u32);
+            self.write(index + 1, (bits >> 32) as u32);
+            self.write(index, bits as u32);
+        }
+    }
 
-        let low_index: u32 = 0x10 + (irq as u32) * 2;
-        let high_index: u32 = 0x10 + (irq as u32) * 2 + 1;
+    /// Mask or unmask input `gsi`.
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let mut entry = self.redirection(gsi);
+        entry.masked = masked;
+        self.set_redirection(gsi, entry);
+    }
 
-        let mut high = unsafe { self.read(high_index) };
-        high &= !0xff000000;
-        high |= (apic_id as u32) << 24;
-        unsafe { self.write(high_index, high) };
+    /// Deliver input `gsi`, edge-triggered active high, to `vector`
+    /// on the CPU with local APIC id `apic_id`.
+    pub fn set_irq(&mut self, gsi: u8, apic_id: u8, vector: InterruptVector) {
+        self.set_redirection(gsi as u32, RedirectionEntry::new(vector, apic_id));
+    }
 
-        let mut low = unsafe { self.read(low_index) };
-        low &= !(1<<16);
-        low &= !(1<<11);
-        low &= !0x700;
-        low &= !0xff;
-        low |= vector as u32;
-        unsafe { self.write(low_index, low) };
+    /// Deliver ISA IRQ `irq` to `vector` on the CPU with local APIC id
+    /// `apic_id`, following the MADT interrupt source overrides.
+    pub fn set_isa_irq(&mut self, irq: u8, apic_id: u8, vector: InterruptVector) {
+        let isa_irq = madt::isa_irq(irq);
+        self.set_redirection(isa_irq.gsi, RedirectionEntry {
+            vector: vector,
+            destination: apic_id,
+            trigger: isa_irq.trigger,
+            polarity: isa_irq.polarity,
+            masked: false,
+        });
     }
 }
@@ -0,0 +1,325 @@
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
+use util::Mutex;
+use arch::{cpu, inportb, outportb, command_line_option};
+use super::hpet;
+use super::pit::{PIT_FREQUENCY, PIT_CHANNEL_2, PIT_COMMAND, PIT_GATE};
+
+/// Number of TSC reads each CPU makes in a synchronization check.
+const TSC_SYNC_ITERATIONS: usize = 100_000;
+
+/// Counter read by `now_ns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clocksource {
+    /// The time-stamp counter. Only used if it is invariant, and in
+    /// sync between CPUs.
+    Tsc,
+    /// The HPET main counter.
+    Hpet,
+    /// PIT channel 2, counting down from 65536 and extended in
+    /// software. It must be read at least every 54 ms, which a tick
+    /// kept for it does.
+    Pit,
+}
+
+impl Clocksource {
+    fn index(self) -> usize {
+        match self {
+            Clocksource::Tsc => 0,
+            Clocksource::Hpet => 1,
+            Clocksource::Pit => 2,
+        }
+    }
+
+    fn from_index(index: usize) -> Clocksource {
+        match index {
+            0 => Clocksource::Tsc,
+            1 => Clocksource::Hpet,
+            _ => Clocksource::Pit,
+        }
+    }
+
+    /// Nanoseconds counted by this clocksource, from an origin of its
+    /// own.
+    fn read_ns(self) -> u64 {
+        match self {
+            Clocksource::Tsc => {
+                let per_ms = TSC_TICKS_PER_MS.load(Ordering::Relaxed) as u64;
+                if per_ms == 0 {
+                    return 0;
+                }
+                let elapsed = cpu::rdtsc() - TSC_BASE.load(Ordering::Relaxed) as u64;
+                (elapsed / per_ms) * 1_000_000 + (elapsed % per_ms) * 1_000_000 / per_ms
+            },
+            Clocksource::Hpet => hpet::now_ns().unwrap_or(0),
+            Clocksource::Pit => {
+                let ticks = pit_ticks();
+                (ticks / PIT_FREQUENCY) * 1_000_000_000 +
+                    (ticks % PIT_FREQUENCY) * 1_000_000_000 / PIT_FREQUENCY
+            },
+        }
+    }
+}
+
+/// PIT channel 2 counter, extended to 64 bits.
+struct PitCounter {
+    /// Count read last.
+    last: u16,
+    /// PIT ticks elapsed up to the last read.
+    ticks: u64,
+}
+
+impl PitCounter {
+    fn read(&mut self) -> u64 {
+        let count = unsafe {
+            // Latch the channel 2 count, then read it low byte first.
+            outportb(PIT_COMMAND, 0b10000000);
+            let low = inportb(PIT_CHANNEL_2) as u16;
+            let high = inportb(PIT_CHANNEL_2) as u16;
+            (high << 8) | low
+        };
+
+        // The count goes down, and wraps from 1 to 0 (65536).
+        self.ticks += self.last.wrapping_sub(count) as u64;
+        self.last = count;
+        self.ticks
+    }
+}
+
+static PIT_COUNTER: Mutex<PitCounter> = Mutex::new(PitCounter { last: 0, ticks: 0 });
+/// PIT ticks elapsed up to the last read of `PIT_COUNTER`.
+static PIT_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// PIT ticks elapsed. Never waits: if another read holds the counter,
+/// possibly one the current CPU interrupted, the count that read last
+/// returned is used, which is at most that read behind.
+fn pit_ticks() -> u64 {
+    match PIT_COUNTER.try_lock() {
+        Some(mut counter) => {
+            let ticks = counter.read();
+            PIT_TICKS.store(ticks as usize, Ordering::SeqCst);
+            ticks
+        },
+        None => PIT_TICKS.load(Ordering::SeqCst) as u64,
+    }
+}
+
+/// TSC ticks per millisecond.
+static TSC_TICKS_PER_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// TSC value at `init`, from which the TSC clocksource counts.
+static TSC_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Whether the TSC is invariant, and no synchronization check found
+/// it out of sync.
+static TSC_STABLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Index of the clocksource in use.
+static SOURCE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Wrapping difference between `now_ns` and the clocksource's own
+/// count, so that `now_ns` stays continuous across a switch.
+static OFFSET_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Sequence count of `SOURCE` and `OFFSET_NS`, odd while a switch
+/// updates them.
+static SEQUENCE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Serializes clocksource switches.
+static SWITCH_LOCK: Mutex<()> = Mutex::new(());
+
+/// Last TSC value read in a synchronization check, and the largest
+/// step backwards seen, in ticks.
+static TSC_SYNC: Mutex<(u64, u64)> = Mutex::new((0, 0));
+/// Arrivals at synchronization check rendezvous, four per check.
+static TSC_SYNC_ARRIVED: AtomicUsize = ATOMIC_USIZE_INIT;
+/// One more than the id of the CPU whose synchronization check is
+/// under way, or zero.
+static TSC_SYNC_TARGET: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Serializes synchronization checks.
+static TSC_SYNC_LOCK: Mutex<()> = Mutex::new(());
+
+/// TSC ticks per millisecond, from CPUID if the processor enumerates
+/// the TSC frequency, or `calibrated_per_ms` otherwise.
+fn tsc_ticks_per_ms(calibrated_per_ms: u64) -> u64 {
+    match cpu::tsc_frequency() {
+        Some(hz) => hz / 1000,
+        None => calibrated_per_ms,
+    }
+}
+
+/// Set PIT channel 2 counting down from 65536, and start extending it.
+fn start_pit() {
+    unsafe {
+        // Enable the gate, with the speaker off.
+        let gate = inportb(PIT_GATE);
+        outportb(PIT_GATE, (gate & !0x02) | 0x01);
+
+        // Channel 2, low byte then high byte, rate generator, with a
+        // reload value of 0 standing for 65536.
+        outportb(PIT_COMMAND, 0b10110100);
+        outportb(PIT_CHANNEL_2, 0);
+        outportb(PIT_CHANNEL_2, 0);
+    }
+
+    let mut counter = PIT_COUNTER.lock();
+    counter.read();
+    counter.ticks = 0;
+    PIT_TICKS.store(0, Ordering::SeqCst);
+}
+
+/// Best clocksource other than the TSC.
+fn fallback() -> Clocksource {
+    if hpet::now_ns().is_some() || hpet::init() {
+        Clocksource::Hpet
+    } else {
+        start_pit();
+        Clocksource::Pit
+    }
+}
+
+/// The TSC if it is stable, or the best fallback otherwise.
+fn preferred() -> Clocksource {
+    if TSC_STABLE.load(Ordering::SeqCst) {
+        Clocksource::Tsc
+    } else {
+        fallback()
+    }
+}
+
+/// Pick the clocksource, given by the `clocksource=` command line
+/// option, or the TSC if it is invariant, and the best fallback
+/// otherwise. `calibrated_tsc_per_ms` is the TSC frequency measured
+/// against the PIT. With `use_hpet`, the HPET is used regardless, so
+/// that its deadlines match `now_ns`. Must be called once, on the
+/// bootstrap CPU.
+pub fn init(calibrated_tsc_per_ms: u64, use_hpet: bool) {
+    TSC_TICKS_PER_MS.store(tsc_ticks_per_ms(calibrated_tsc_per_ms) as usize, Ordering::SeqCst);
+    TSC_BASE.store(cpu::rdtsc() as usize, Ordering::SeqCst);
+    TSC_STABLE.store(cpu::has_invariant_tsc(), Ordering::SeqCst);
+
+    let source = if use_hpet {
+        Clocksource::Hpet
+    } else {
+        match command_line_option("clocksource") {
+            None => preferred(),
+            Some("tsc") => Clocksource::Tsc,
+            Some("hpet") => {
+                if hpet::now_ns().is_some() || hpet::init() {
+                    Clocksource::Hpet
+                } else {
+                    log!("No usable HPET clocksource.");
+                    preferred()
+                }
+            },
+            Some("pit") => {
+                start_pit();
+                Clocksource::Pit
+            },
+            Some(other) => {
+                log!("Unknown clocksource {}.", other);
+                preferred()
+            },
+        }
+    };
+    switch_to(source);
+
+    log!("Clocksource: {:?}, TSC: {} ticks/ms, invariant: {}",
+         source, TSC_TICKS_PER_MS.load(Ordering::SeqCst), TSC_STABLE.load(Ordering::SeqCst));
+}
+
+/// Switch to `source`, keeping `now_ns` continuous.
+fn switch_to(source: Clocksource) {
+    let _guard = SWITCH_LOCK.lock();
+    let now = now_ns();
+    let offset = now.wrapping_sub(source.read_ns());
+
+    SEQUENCE.fetch_add(1, Ordering::SeqCst);
+    OFFSET_NS.store(offset as usize, Ordering::SeqCst);
+    SOURCE.store(source.index(), Ordering::SeqCst);
+    SEQUENCE.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Clocksource in use.
+pub fn current() -> Clocksource {
+    Clocksource::from_index(SOURCE.load(Ordering::Relaxed))
+}
+
+/// TSC ticks per millisecond, for TSC-deadline mode.
+pub fn tsc_per_ms() -> u64 {
+    TSC_TICKS_PER_MS.load(Ordering::Relaxed) as u64
+}
+
+/// Nanoseconds counted by the clocksource since `init`.
+pub fn now_ns() -> u64 {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::SeqCst);
+        if sequence & 1 != 0 {
+            continue;
+        }
+
+        let source = Clocksource::from_index(SOURCE.load(Ordering::SeqCst));
+        let offset = OFFSET_NS.load(Ordering::SeqCst) as u64;
+        let now = source.read_ns().wrapping_add(offset);
+        if SEQUENCE.load(Ordering::SeqCst) == sequence {
+            return now;
+        }
+    }
+}
+
+/// Stop trusting the TSC, and switch away from it if it is in use.
+fn mark_tsc_unstable() {
+    TSC_STABLE.store(false, Ordering::SeqCst);
+    if current() == Clocksource::Tsc {
+        let source = fallback();
+        log!("TSC unstable, switching to the {:?} clocksource.", source);
+        switch_to(source);
+    }
+}
+
+/// Wait until `count` arrivals at TSC synchronization rendezvous.
+fn tsc_sync_rendezvous(count: usize) {
+    TSC_SYNC_ARRIVED.fetch_add(1, Ordering::SeqCst);
+    while TSC_SYNC_ARRIVED.load(Ordering::SeqCst) < count { }
+}
+
+/// Read the TSC in turns with the other CPU, recording any value
+/// older than the one the other CPU read before.
+fn tsc_sync_check(first: usize) {
+    tsc_sync_rendezvous(first + 2);
+    for _ in 0..TSC_SYNC_ITERATIONS {
+        let mut sync = TSC_SYNC.lock();
+        let now = cpu::rdtsc();
+        if now < sync.0 && sync.0 - now > sync.1 {
+            sync.1 = sync.0 - now;
+        }
+        sync.0 = now;
+    }
+    tsc_sync_rendezvous(first + 4);
+}
+
+/// Check that the TSC of `cpu`, coming online, is in sync with the
+/// current CPU, waiting for `cpu` to call `tsc_sync_target`. Checks
+/// of several CPUs are made one at a time. Switches away from the TSC
+/// clocksource, and returns `false`, if it is not in sync.
+pub fn tsc_sync_source(cpu: usize) -> bool {
+    let _guard = TSC_SYNC_LOCK.lock();
+    // Each check adds four arrivals, and the other CPU cannot get past
+    // the first rendezvous before this one arrives.
+    let first = TSC_SYNC_ARRIVED.load(Ordering::SeqCst) & !3;
+    *TSC_SYNC.lock() = (0, 0);
+    TSC_SYNC_TARGET.store(cpu + 1, Ordering::SeqCst);
+    tsc_sync_check(first);
+    TSC_SYNC_TARGET.store(0, Ordering::SeqCst);
+
+    let warp = TSC_SYNC.lock().1;
+    if warp != 0 {
+        log!("CPU {}: TSC out of sync by {} ticks.", cpu, warp);
+        mark_tsc_unstable();
+        return false;
+    }
+    true
+}
+
+/// Take part in the TSC synchronization check of the current CPU,
+/// while coming online, waiting for its turn. See `tsc_sync_source`.
+pub fn tsc_sync_target() {
+    let cpu = cpu::current_id();
+    while TSC_SYNC_TARGET.load(Ordering::SeqCst) != cpu + 1 { }
+    let first = TSC_SYNC_ARRIVED.load(Ordering::SeqCst) & !3;
+    tsc_sync_check(first);
+}
@@ -0,0 +1,57 @@
+use arch::cpu;
+use super::{InterruptVector, IO_APIC, RedirectionEntry};
+use super::{madt, vector};
+
+/// Allocate a vector for I/O APIC input `gsi`, and route the input,
+/// masked, to it on the CPU with id `cpu`. Returns `None` if the input
+/// or CPU does not exist, or no vector is free.
+pub fn route_irq(gsi: u32, cpu: usize) -> Option<InterruptVector> {
+    let mut io_apic = IO_APIC.lock();
+    if !cpu::is_online(cpu) || gsi >= io_apic.redirection_entry_count() as u32 {
+        return None;
+    }
+
+    let vector = match vector::allocate(cpu) {
+        Some(vector) => vector,
+        None => return None,
+    };
+    let (trigger, polarity) = madt::gsi_signalling(gsi);
+    io_apic.set_redirection(gsi, RedirectionEntry {
+        vector: vector,
+        destination: cpu as u8,
+        trigger: trigger,
+        polarity: polarity,
+        masked: true,
+    });
+    Some(vector)
+}
+
+/// Mask or unmask I/O APIC input `gsi`.
+pub fn set_irq_masked(gsi: u32, masked: bool) {
+    IO_APIC.lock().set_masked(gsi, masked);
+}
+
+/// Move I/O APIC input `gsi`, routed to `vector` on the CPU with id
+/// `cpu`, to a new vector on the CPU with id `new_cpu`, keeping it
+/// masked or not. Returns the new vector, or `None`, leaving the input
+/// as it is, if `new_cpu` is not online or has no free vector.
+pub fn set_irq_affinity(gsi: u32, cpu: usize, vector: InterruptVector,
+                        new_cpu: usize) -> Option<InterruptVector> {
+    let mut io_apic = IO_APIC.lock();
+    if !cpu::is_online(new_cpu) {
+        return None;
+    }
+
+    let new_vector = match vector::allocate(new_cpu) {
+        Some(vector) => vector,
+        None => return None,
+    };
+    let mut entry = io_apic.redirection(gsi);
+    entry.vector = new_vector;
+    entry.destination = new_cpu as u8;
+    io_apic.set_redirection(gsi, entry);
+
+    // An interrupt already sent to the old vector is taken as spurious.
+    vector::free(cpu, vector);
+    Some(new_vector)
+}
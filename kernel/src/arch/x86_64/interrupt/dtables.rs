@@ -1,3 +1,5 @@
+use arch::segmentation::SegmentSelector;
+
 /// A struct describing a pointer to a descriptor table (GDT / IDT).
 /// This is in a format suitable for giving to 'lgdt' or 'lidt'.
 #[repr(C, packed)]
@@ -9,18 +11,31 @@ pub struct DescriptorTablePointer {
 }
 
 /// Load GDT table.
-#[allow(dead_code)]
 pub unsafe fn lgdt(gdt: &DescriptorTablePointer) {
     asm!("lgdt ($0)" :: "r" (gdt) : "memory");
 }
 
-/// Load LDT table.
-#[allow(dead_code)]
-pub unsafe fn lldt(ldt: &DescriptorTablePointer) {
-    asm!("lldt ($0)" :: "r" (ldt) : "memory");
+/// Read the pointer to the current GDT table.
+pub fn sgdt() -> DescriptorTablePointer {
+    let mut gdt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt ($0)" :: "r" (&mut gdt) : "memory"); }
+    gdt
+}
+
+/// Load LDT table, from the descriptor `selector` picks in the GDT. A
+/// null selector leaves no LDT loaded.
+pub unsafe fn lldt(selector: SegmentSelector) {
+    asm!("lldt $0" :: "r" (selector.bits()) : "memory");
 }
 
 /// Load IDT table.
 pub unsafe fn lidt(idt: &DescriptorTablePointer) {
     asm!("lidt ($0)" :: "r" (idt) : "memory");
 }
+
+/// Read the pointer to the current IDT table.
+pub fn sidt() -> DescriptorTablePointer {
+    let mut idt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sidt ($0)" :: "r" (&mut idt) : "memory"); }
+    idt
+}
@@ -0,0 +1,302 @@
+use core::fmt;
+use common::*;
+use abi::{FaultKind, DebugEventKind};
+use arch::debugreg::{self, DebugStatus};
+use super::switch::{ExceptionInfo, Registers};
+use super::fault::{self, PageFaultError};
+use super::{Exception, InterruptVector, PAGE_FAULT_INTERRUPT_CODE};
+
+/// Number of vectors reserved for CPU exceptions.
+pub const EXCEPTION_VECTOR_COUNT: InterruptVector = 32;
+
+pub const DIVIDE_ERROR_INTERRUPT_CODE: InterruptVector = 0x00;
+pub const DEBUG_INTERRUPT_CODE: InterruptVector = 0x01;
+pub const NMI_INTERRUPT_CODE: InterruptVector = 0x02;
+pub const BREAKPOINT_INTERRUPT_CODE: InterruptVector = 0x03;
+pub const OVERFLOW_INTERRUPT_CODE: InterruptVector = 0x04;
+pub const BOUND_RANGE_INTERRUPT_CODE: InterruptVector = 0x05;
+pub const INVALID_OPCODE_INTERRUPT_CODE: InterruptVector = 0x06;
+pub const DEVICE_NOT_AVAILABLE_INTERRUPT_CODE: InterruptVector = 0x07;
+pub const DOUBLE_FAULT_INTERRUPT_CODE: InterruptVector = 0x08;
+pub const COPROCESSOR_SEGMENT_OVERRUN_INTERRUPT_CODE: InterruptVector = 0x09;
+pub const INVALID_TSS_INTERRUPT_CODE: InterruptVector = 0x0A;
+pub const SEGMENT_NOT_PRESENT_INTERRUPT_CODE: InterruptVector = 0x0B;
+pub const STACK_SEGMENT_FAULT_INTERRUPT_CODE: InterruptVector = 0x0C;
+pub const GENERAL_PROTECTION_INTERRUPT_CODE: InterruptVector = 0x0D;
+pub const X87_FLOATING_POINT_INTERRUPT_CODE: InterruptVector = 0x10;
+pub const ALIGNMENT_CHECK_INTERRUPT_CODE: InterruptVector = 0x11;
+pub const MACHINE_CHECK_INTERRUPT_CODE: InterruptVector = 0x12;
+pub const SIMD_FLOATING_POINT_INTERRUPT_CODE: InterruptVector = 0x13;
+pub const VIRTUALIZATION_INTERRUPT_CODE: InterruptVector = 0x14;
+pub const CONTROL_PROTECTION_INTERRUPT_CODE: InterruptVector = 0x15;
+pub const HYPERVISOR_INJECTION_INTERRUPT_CODE: InterruptVector = 0x1C;
+pub const VMM_COMMUNICATION_INTERRUPT_CODE: InterruptVector = 0x1D;
+pub const SECURITY_INTERRUPT_CODE: InterruptVector = 0x1E;
+
+/// State captured when an exception is raised: the interrupt stack
+/// frame, the error code if the vector pushes one, and all
+/// general-purpose registers.
+#[derive(Debug, Clone)]
+pub struct ExceptionContext {
+    pub vector: InterruptVector,
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+    pub error_code: Option<u64>,
+    pub registers: Registers,
+}
+
+impl ExceptionContext {
+    /// Gather the context of the last exception.
+    pub fn new(info: &ExceptionInfo, registers: &Registers) -> ExceptionContext {
+        ExceptionContext {
+            vector: info.exception_code,
+            instruction_pointer: info.instruction_pointer,
+            code_segment: info.code_segment,
+            cpu_flags: info.cpu_flags,
+            stack_pointer: info.stack_pointer,
+            stack_segment: info.stack_segment,
+            error_code: info.error_code,
+            registers: registers.clone(),
+        }
+    }
+
+    /// The exception was raised in user mode.
+    pub fn is_user(&self) -> bool {
+        self.code_segment & 0x3 == 0x3
+    }
+
+    /// Log the stack frame and the registers.
+    pub fn log(&self) {
+        let registers = &self.registers;
+        log!("rip 0x{:016x} rsp 0x{:016x} rflags 0x{:016x}",
+             self.instruction_pointer, self.stack_pointer, self.cpu_flags);
+        log!("cs  0x{:016x} ss  0x{:016x}", self.code_segment, self.stack_segment);
+        log!("rax 0x{:016x} rbx 0x{:016x} rcx 0x{:016x} rdx 0x{:016x}",
+             registers.rax, registers.rbx, registers.rcx, registers.rdx);
+        log!("rsi 0x{:016x} rdi 0x{:016x} rbp 0x{:016x} r8  0x{:016x}",
+             registers.rsi, registers.rdi, registers.rbp, registers.r8);
+        log!("r9  0x{:016x} r10 0x{:016x} r11 0x{:016x} r12 0x{:016x}",
+             registers.r9, registers.r10, registers.r11, registers.r12);
+        log!("r13 0x{:016x} r14 0x{:016x} r15 0x{:016x}",
+             registers.r13, registers.r14, registers.r15);
+    }
+}
+
+/// Error code pushed by exceptions that may be caused by a segment
+/// selector. Zero if no selector is involved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError(u64);
+
+impl SelectorError {
+    /// The exception happened while delivering an external event.
+    pub fn is_external(&self) -> bool {
+        self.0 & 0x1 != 0
+    }
+
+    /// Descriptor table the selector refers to.
+    pub fn table(&self) -> &'static str {
+        match (self.0 >> 1) & 0x3 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        }
+    }
+
+    /// Index of the descriptor in its table.
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1FFF
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "no selector");
+        }
+        write!(f, "{} index {}{}", self.table(), self.index(),
+               if self.is_external() { ", external" } else { "" })
+    }
+}
+
+/// A CPU exception, decoded from its vector and error code.
+#[derive(Debug, Clone, Copy)]
+pub enum CpuException {
+    DivideError,
+    Debug(DebugStatus),
+    Nmi,
+    Breakpoint,
+    Overflow,
+    BoundRange,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    DoubleFault,
+    CoprocessorSegmentOverrun,
+    InvalidTss(SelectorError),
+    SegmentNotPresent(SelectorError),
+    StackSegmentFault(SelectorError),
+    GeneralProtection(SelectorError),
+    PageFault {
+        /// Linear address that caused the fault, read from CR2.
+        address: VAddr,
+        error: PageFaultError,
+    },
+    X87FloatingPoint,
+    AlignmentCheck,
+    MachineCheck,
+    SimdFloatingPoint,
+    Virtualization,
+    ControlProtection(u64),
+    HypervisorInjection,
+    VmmCommunication(u64),
+    Security(u64),
+    /// A vector reserved by the architecture.
+    Reserved(InterruptVector),
+}
+
+impl CpuException {
+    /// Kind of fault the exception is forwarded to fault handlers as,
+    /// or `None` if it stops the task.
+    pub fn fault_kind(&self) -> Option<FaultKind> {
+        match *self {
+            CpuException::DivideError => Some(FaultKind::DivideError),
+            CpuException::InvalidOpcode => Some(FaultKind::InvalidOpcode),
+            CpuException::GeneralProtection(_) => Some(FaultKind::GeneralProtection),
+            CpuException::AlignmentCheck => Some(FaultKind::AlignmentCheck),
+            _ => None,
+        }
+    }
+
+    /// Debug event the exception reports to a debugger, with the
+    /// debug status, if it is one.
+    pub fn debug_event(&self) -> Option<(DebugEventKind, u64)> {
+        match *self {
+            CpuException::Breakpoint => Some((DebugEventKind::Breakpoint, 0)),
+            CpuException::Debug(status) => {
+                let breakpoints = status.breakpoints();
+                if breakpoints != 0 {
+                    Some((DebugEventKind::HardwareBreakpoint(breakpoints.trailing_zeros() as u8),
+                          status.bits()))
+                } else if status.is_single_step() {
+                    Some((DebugEventKind::SingleStep, status.bits()))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Decode the exception of `context`, which must have an
+    /// exception vector.
+    pub fn decode(context: &ExceptionContext) -> CpuException {
+        let error_code = context.error_code.unwrap_or(0);
+        let selector = SelectorError(error_code);
+
+        match context.vector {
+            DIVIDE_ERROR_INTERRUPT_CODE => CpuException::DivideError,
+            DEBUG_INTERRUPT_CODE => CpuException::Debug(debugreg::take_status()),
+            NMI_INTERRUPT_CODE => CpuException::Nmi,
+            BREAKPOINT_INTERRUPT_CODE => CpuException::Breakpoint,
+            OVERFLOW_INTERRUPT_CODE => CpuException::Overflow,
+            BOUND_RANGE_INTERRUPT_CODE => CpuException::BoundRange,
+            INVALID_OPCODE_INTERRUPT_CODE => CpuException::InvalidOpcode,
+            DEVICE_NOT_AVAILABLE_INTERRUPT_CODE => CpuException::DeviceNotAvailable,
+            DOUBLE_FAULT_INTERRUPT_CODE => CpuException::DoubleFault,
+            COPROCESSOR_SEGMENT_OVERRUN_INTERRUPT_CODE => CpuException::CoprocessorSegmentOverrun,
+            INVALID_TSS_INTERRUPT_CODE => CpuException::InvalidTss(selector),
+            SEGMENT_NOT_PRESENT_INTERRUPT_CODE => CpuException::SegmentNotPresent(selector),
+            STACK_SEGMENT_FAULT_INTERRUPT_CODE => CpuException::StackSegmentFault(selector),
+            GENERAL_PROTECTION_INTERRUPT_CODE => CpuException::GeneralProtection(selector),
+            PAGE_FAULT_INTERRUPT_CODE => CpuException::PageFault {
+                address: unsafe { ::arch::paging::cr2() },
+                error: PageFaultError::new(error_code),
+            },
+            X87_FLOATING_POINT_INTERRUPT_CODE => CpuException::X87FloatingPoint,
+            ALIGNMENT_CHECK_INTERRUPT_CODE => CpuException::AlignmentCheck,
+            MACHINE_CHECK_INTERRUPT_CODE => CpuException::MachineCheck,
+            SIMD_FLOATING_POINT_INTERRUPT_CODE => CpuException::SimdFloatingPoint,
+            VIRTUALIZATION_INTERRUPT_CODE => CpuException::Virtualization,
+            CONTROL_PROTECTION_INTERRUPT_CODE => CpuException::ControlProtection(error_code),
+            HYPERVISOR_INJECTION_INTERRUPT_CODE => CpuException::HypervisorInjection,
+            VMM_COMMUNICATION_INTERRUPT_CODE => CpuException::VmmCommunication(error_code),
+            SECURITY_INTERRUPT_CODE => CpuException::Security(error_code),
+            vector => CpuException::Reserved(vector),
+        }
+    }
+}
+
+impl fmt::Display for CpuException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuException::DivideError => write!(f, "divide error (#DE)"),
+            CpuException::Debug(status) => write!(f, "debug (#DB), {}", status),
+            CpuException::Nmi => write!(f, "non-maskable interrupt"),
+            CpuException::Breakpoint => write!(f, "breakpoint (#BP)"),
+            CpuException::Overflow => write!(f, "overflow (#OF)"),
+            CpuException::BoundRange => write!(f, "bound range exceeded (#BR)"),
+            CpuException::InvalidOpcode => write!(f, "invalid opcode (#UD)"),
+            CpuException::DeviceNotAvailable => write!(f, "device not available (#NM)"),
+            CpuException::DoubleFault => write!(f, "double fault (#DF)"),
+            CpuException::CoprocessorSegmentOverrun => write!(f, "coprocessor segment overrun"),
+            CpuException::InvalidTss(selector) => write!(f, "invalid TSS (#TS), {}", selector),
+            CpuException::SegmentNotPresent(selector) =>
+                write!(f, "segment not present (#NP), {}", selector),
+            CpuException::StackSegmentFault(selector) =>
+                write!(f, "stack-segment fault (#SS), {}", selector),
+            CpuException::GeneralProtection(selector) =>
+                write!(f, "general protection (#GP), {}", selector),
+            CpuException::PageFault { address, error } =>
+                write!(f, "page fault (#PF) at 0x{:x}, {}", address, error),
+            CpuException::X87FloatingPoint => write!(f, "x87 floating-point error (#MF)"),
+            CpuException::AlignmentCheck => write!(f, "alignment check (#AC)"),
+            CpuException::MachineCheck => write!(f, "machine check (#MC)"),
+            CpuException::SimdFloatingPoint => write!(f, "SIMD floating-point error (#XM)"),
+            CpuException::Virtualization => write!(f, "virtualization exception (#VE)"),
+            CpuException::ControlProtection(code) =>
+                write!(f, "control protection (#CP), error 0x{:x}", code),
+            CpuException::HypervisorInjection => write!(f, "hypervisor injection (#HV)"),
+            CpuException::VmmCommunication(code) =>
+                write!(f, "VMM communication (#VC), error 0x{:x}", code),
+            CpuException::Security(code) => write!(f, "security exception (#SX), error 0x{:x}", code),
+            CpuException::Reserved(vector) => write!(f, "reserved exception vector {}", vector),
+        }
+    }
+}
+
+/// Single entry point for all CPU exceptions but NMIs, which have
+/// their own entry in `nmi`. Exceptions raised in kernel mode are
+/// reported with their whole context, and panic. Those raised in user
+/// mode are returned, to be handled for the task.
+pub fn dispatch(context: &ExceptionContext) -> Exception {
+    let exception = CpuException::decode(context);
+
+    match exception {
+        CpuException::PageFault { address, error } => {
+            if !error.is_user() {
+                fault::kernel_page_fault(address, error, context);
+            }
+
+            Exception::PageFault {
+                address: address,
+                instruction_pointer: VAddr::from(context.instruction_pointer),
+                error: error,
+            }
+        },
+        _ if !context.is_user() => kernel_exception(exception, context),
+        _ => Exception::Fault {
+            exception: exception,
+            instruction_pointer: VAddr::from(context.instruction_pointer),
+            error_code: context.error_code.unwrap_or(0),
+        },
+    }
+}
+
+/// Report an exception raised in kernel mode, and panic.
+fn kernel_exception(exception: CpuException, context: &ExceptionContext) -> ! {
+    log!("Kernel exception: {}", exception);
+    context.log();
+    panic!("kernel exception: {}", exception);
+}
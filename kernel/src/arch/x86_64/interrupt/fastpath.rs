@@ -0,0 +1,119 @@
+use super::{RuntimeEntry, SYSTEM_CALL_INTERRUPT_CODE, system_call_return_to_raw};
+use super::switch::{ExceptionStackFrame, CUR_REGISTERS, store_exception_stack};
+
+/// Handler of fastpath calls, given RDI, RSI and RDX of the caller:
+/// the address and depth of the endpoint, and the word sent. Returns
+/// the entry of the task to return to in place of the caller, or
+/// `None` to take the general system call path, with nothing changed.
+pub type FastpathHandler = fn(u64, u64, u64) -> Option<RuntimeEntry>;
+
+static mut HANDLER: Option<FastpathHandler> = None;
+
+/// Set the handler of `syscall` entries with `FASTPATH_CALL` in RAX.
+/// Until then, they take the general path.
+pub fn set_handler(handler: FastpathHandler) {
+    unsafe { HANDLER = Some(handler); }
+}
+
+/// Run the handler for the caller, whose registers are saved, and
+/// whose interrupt stack frame is at `frame`. The caller is saved as
+/// for the general path first, for the handler to take it out of the
+/// CPU with `TaskRuntime::exit`. Returns whether the handler switched
+/// to another task, which is then loaded into `frame`.
+unsafe extern "C" fn dispatch(frame: *mut ExceptionStackFrame) -> u64 {
+    let handler = match HANDLER {
+        Some(handler) => handler,
+        None => return 0,
+    };
+    store_exception_stack(frame, SYSTEM_CALL_INTERRUPT_CODE);
+
+    match handler(CUR_REGISTERS.rdi, CUR_REGISTERS.rsi, CUR_REGISTERS.rdx) {
+        Some(entry) => {
+            entry.resume(&mut *frame);
+            1
+        },
+        None => 0,
+    }
+}
+
+/// Entry of a `syscall` with `FASTPATH_CALL` in RAX, jumped to from
+/// `syscall_entry` with the kernel GS base, and the interrupt stack
+/// frame of the caller on its kernel stack. The registers of the
+/// caller are saved as the general path saves them, and `dispatch`
+/// runs on the rest of the stack. If it switched, the registers of the
+/// receiver are loaded, and `iretq` returns to it directly, without
+/// going back to the scheduler. Otherwise the registers of the caller
+/// are loaded back, and the general path taken as it would have been.
+///
+/// Only reached without KPTI, whose entry stack is too small to run
+/// the handler on.
+#[naked]
+#[inline(never)]
+#[cfg_attr(feature="kpti", allow(dead_code))]
+pub unsafe extern "C" fn fastpath_entry() {
+    clear_entry_flags!();
+
+    asm!("mov [$0], rax
+          mov [$1], rbx
+          mov [$2], rcx
+          mov [$3], rdx
+          mov [$4], rsi
+          mov [$5], rdi
+          mov [$6], r8
+          mov [$7], r9
+          mov [$8], r10
+          mov [$9], r11
+          mov [$10], r12
+          mov [$11], r13
+          mov [$12], r14
+          mov [$13], r15
+          mov [$14], rbp
+
+          mov rdi, rsp
+          sub rsp, 8
+          call $15
+          add rsp, 8
+          test rax, rax
+
+          mov rax, [$0]
+          mov rbx, [$1]
+          mov rcx, [$2]
+          mov rdx, [$3]
+          mov rsi, [$4]
+          mov rdi, [$5]
+          mov r8, [$6]
+          mov r9, [$7]
+          mov r10, [$8]
+          mov r11, [$9]
+          mov r12, [$10]
+          mov r13, [$11]
+          mov r14, [$12]
+          mov r15, [$13]
+          mov rbp, [$14]
+
+          swapgs
+          jz 1f
+          iretq
+          1:
+          jmp $16"
+         ::
+         "i"(&CUR_REGISTERS.rax),
+         "i"(&CUR_REGISTERS.rbx),
+         "i"(&CUR_REGISTERS.rcx),
+         "i"(&CUR_REGISTERS.rdx),
+         "i"(&CUR_REGISTERS.rsi),
+         "i"(&CUR_REGISTERS.rdi),
+         "i"(&CUR_REGISTERS.r8),
+         "i"(&CUR_REGISTERS.r9),
+         "i"(&CUR_REGISTERS.r10),
+         "i"(&CUR_REGISTERS.r11),
+         "i"(&CUR_REGISTERS.r12),
+         "i"(&CUR_REGISTERS.r13),
+         "i"(&CUR_REGISTERS.r14),
+         "i"(&CUR_REGISTERS.r15),
+         "i"(&CUR_REGISTERS.rbp),
+
+         "i"(dispatch as unsafe extern "C" fn(*mut ExceptionStackFrame) -> u64),
+         "i"(system_call_return_to_raw as unsafe extern "C" fn())
+         :: "volatile", "intel");
+}
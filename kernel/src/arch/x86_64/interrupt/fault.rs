@@ -0,0 +1,100 @@
+use core::fmt;
+use common::*;
+use abi::PageFaultInfo;
+use arch::paging::{self, BASE_PAGE_LENGTH};
+use arch::init::kernel_stack_guard_page_vaddr;
+use arch::kstack;
+use super::exception::ExceptionContext;
+
+/// Error code bit: the page was present, and the fault is a
+/// protection violation.
+const PF_PRESENT: u64 = 1 << 0;
+/// Error code bit: the access was a write.
+const PF_WRITE: u64 = 1 << 1;
+/// Error code bit: the access came from user mode.
+const PF_USER: u64 = 1 << 2;
+/// Error code bit: a reserved bit was set in a paging structure
+/// entry.
+const PF_RESERVED: u64 = 1 << 3;
+/// Error code bit: the access was an instruction fetch.
+const PF_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+/// Page fault error code pushed by the processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(u64);
+
+impl PageFaultError {
+    /// Decode a raw page fault error code.
+    pub fn new(code: u64) -> PageFaultError {
+        PageFaultError(code)
+    }
+
+    /// The page was present, and the fault is a protection violation.
+    pub fn is_present(&self) -> bool {
+        self.0 & PF_PRESENT != 0
+    }
+
+    /// The access was a write.
+    pub fn is_write(&self) -> bool {
+        self.0 & PF_WRITE != 0
+    }
+
+    /// The access came from user mode.
+    pub fn is_user(&self) -> bool {
+        self.0 & PF_USER != 0
+    }
+
+    /// A reserved bit was set in a paging structure entry.
+    pub fn is_reserved(&self) -> bool {
+        self.0 & PF_RESERVED != 0
+    }
+
+    /// The access was an instruction fetch.
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.0 & PF_INSTRUCTION_FETCH != 0
+    }
+
+    /// Build the report sent to a user-space fault handler.
+    pub fn info(&self, address: VAddr, instruction_pointer: VAddr,
+                stack_overflow: bool) -> PageFaultInfo {
+        PageFaultInfo {
+            address: address.into(),
+            instruction_pointer: instruction_pointer.into(),
+            present: self.is_present(),
+            write: self.is_write(),
+            user: self.is_user(),
+            reserved: self.is_reserved(),
+            instruction_fetch: self.is_instruction_fetch(),
+            stack_overflow: stack_overflow,
+        }
+    }
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {} from {} mode{} (error 0x{:x})",
+               if self.is_instruction_fetch() { "fetch" }
+               else if self.is_write() { "write" } else { "read" },
+               if self.is_present() { "present page" } else { "non-present page" },
+               if self.is_user() { "user" } else { "supervisor" },
+               if self.is_reserved() { ", reserved bit set" } else { "" },
+               self.0)
+    }
+}
+
+/// Report a page fault raised in kernel mode with the faulting
+/// register state and the page walk of the faulting address, and
+/// panic.
+pub fn kernel_page_fault(address: VAddr, error: PageFaultError,
+                         context: &ExceptionContext) -> ! {
+    log!("Kernel page fault at 0x{:x}: {}", address, error);
+    context.log();
+    unsafe { paging::log_walk(address); }
+
+    let guard = kernel_stack_guard_page_vaddr().into(): usize;
+    let fault = address.into(): usize;
+    if (fault >= guard && fault < guard + BASE_PAGE_LENGTH) || kstack::is_guard_page(address) {
+        panic!("kernel stack overflow at 0x{:x}", address);
+    }
+    panic!("kernel page fault at 0x{:x}, {}", address, error);
+}
@@ -0,0 +1,211 @@
+use util::Mutex;
+use arch::acpi::{self, SdtHeader, GenericAddress};
+use arch::paging::{map_device, VolatileMmio};
+use common::*;
+use super::{LOCAL_APIC, IO_APIC, HPET_INTERRUPT_CODE};
+
+/// Length of the HPET register block.
+const HPET_REGISTERS_LENGTH: usize = 0x400;
+/// Longest counter period allowed by the specification, in
+/// femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+/// Femtoseconds per nanosecond.
+const FS_PER_NS: u64 = 1_000_000;
+
+/// General capabilities and ID register.
+const GENERAL_CAPABILITIES: usize = 0x000;
+/// General configuration register.
+const GENERAL_CONFIGURATION: usize = 0x010;
+/// Main counter value register.
+const MAIN_COUNTER: usize = 0x0F0;
+
+/// General configuration: the main counter runs.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// Timer configuration: raise the interrupt.
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+/// Timer configuration: fire periodically.
+const TIMER_PERIODIC: u64 = 1 << 3;
+/// Timer configuration: the timer supports periodic mode.
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Timer configuration: the next comparator write sets the period
+/// accumulator of a periodic timer.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+/// Timer configuration: shift of the I/O APIC input routing.
+const TIMER_ROUTE_SHIFT: u64 = 9;
+
+/// HPET description table.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+/// Configuration register of comparator `n`.
+fn timer_configuration(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+
+/// Comparator value register of comparator `n`.
+fn timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+/// HPET register block. Comparator 0 is used as the wakeup timer,
+/// routed to the bootstrap CPU.
+#[derive(Debug)]
+struct Hpet {
+    mmio: VolatileMmio,
+    /// Counter period, in femtoseconds.
+    period_fs: u64,
+    /// Configuration of comparator 0, without the mode bits.
+    timer_configuration: u64,
+    /// Whether comparator 0 supports periodic mode.
+    periodic_capable: bool,
+    /// Smallest comparator distance from the counter that reliably
+    /// fires, in counter ticks.
+    minimum_ticks: u64,
+}
+
+static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+
+impl Hpet {
+    unsafe fn read(&self, reg: usize) -> u64 {
+        self.mmio.read(reg)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u64) {
+        self.mmio.write(reg, value)
+    }
+
+    fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) }
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns / self.period_fs) * FS_PER_NS + (ns % self.period_fs) * FS_PER_NS / self.period_fs
+    }
+
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks / FS_PER_NS) * self.period_fs + (ticks % FS_PER_NS) * self.period_fs / FS_PER_NS
+    }
+}
+
+/// Find the HPET in the ACPI tables, map its registers, start its
+/// counter and route comparator 0 to the current CPU. Returns `false`
+/// if there is no usable HPET.
+pub fn init() -> bool {
+    let table_paddr = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => return false,
+    };
+    let table = unsafe { acpi::read::<HpetTable>(table_paddr) };
+    let base_address = table.base_address;
+    if base_address.address_space != 0 {
+        return false;
+    }
+
+    let mmio = unsafe { map_device(PAddr::from(base_address.address), HPET_REGISTERS_LENGTH) };
+    let capabilities: u64 = unsafe { mmio.read(GENERAL_CAPABILITIES) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return false;
+    }
+
+    let mut hpet = Hpet {
+        mmio: mmio,
+        period_fs: period_fs,
+        timer_configuration: 0,
+        periodic_capable: false,
+        minimum_ticks: ::core::cmp::max(table.minimum_tick as u64, 1),
+    };
+
+    unsafe {
+        hpet.write(GENERAL_CONFIGURATION, 0);
+        hpet.write(MAIN_COUNTER, 0);
+
+        // Route comparator 0 to the lowest I/O APIC input it supports.
+        let configuration = hpet.read(timer_configuration(0));
+        let routes = (configuration >> 32) as u32;
+        if routes == 0 {
+            return false;
+        }
+        let irq = routes.trailing_zeros() as u8;
+        let apic_id = LOCAL_APIC.lock().id() as u8;
+        IO_APIC.lock().set_irq(irq, apic_id, HPET_INTERRUPT_CODE);
+
+        hpet.timer_configuration = (irq as u64) << TIMER_ROUTE_SHIFT;
+        hpet.periodic_capable = configuration & TIMER_PERIODIC_CAPABLE != 0;
+        hpet.write(timer_configuration(0), hpet.timer_configuration);
+
+        hpet.write(GENERAL_CONFIGURATION, CONFIGURATION_ENABLE);
+        log!("HPET: period {} fs, comparator 0 on I/O APIC input {}", period_fs, irq);
+    }
+
+    *HPET.lock() = Some(hpet);
+    true
+}
+
+/// Nanoseconds counted by the HPET since `init`, or `None` if there
+/// is no HPET.
+pub fn now_ns() -> Option<u64> {
+    HPET.lock().as_ref().map(|hpet| hpet.ticks_to_ns(hpet.counter()))
+}
+
+/// Fire comparator 0 every `period_ns` nanoseconds. Returns `false` if
+/// there is no HPET, or comparator 0 only supports one-shot mode.
+pub fn set_periodic(period_ns: u64) -> bool {
+    let guard = HPET.lock();
+    let hpet = match guard.as_ref() {
+        Some(hpet) if hpet.periodic_capable => hpet,
+        _ => return false,
+    };
+
+    let ticks = ::core::cmp::max(hpet.ns_to_ticks(period_ns), hpet.minimum_ticks);
+    unsafe {
+        hpet.write(timer_configuration(0), hpet.timer_configuration | TIMER_INTERRUPT_ENABLE |
+                   TIMER_PERIODIC | TIMER_VALUE_SET);
+        hpet.write(timer_comparator(0), hpet.counter() + ticks);
+        hpet.write(timer_comparator(0), ticks);
+    }
+    true
+}
+
+/// Fire comparator 0 once, when `now_ns` reaches `deadline_ns`.
+/// Returns `false` if there is no HPET.
+pub fn set_next_deadline(deadline_ns: u64) -> bool {
+    let guard = HPET.lock();
+    let hpet = match guard.as_ref() {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+
+    unsafe {
+        hpet.write(timer_configuration(0), hpet.timer_configuration | TIMER_INTERRUPT_ENABLE);
+
+        // The comparator only fires on equality, so a deadline the
+        // counter already passed would wait for the counter to wrap.
+        let mut comparator = ::core::cmp::max(hpet.ns_to_ticks(deadline_ns),
+                                              hpet.counter() + hpet.minimum_ticks);
+        hpet.write(timer_comparator(0), comparator);
+        while hpet.counter() >= comparator {
+            comparator = hpet.counter() + hpet.minimum_ticks;
+            hpet.write(timer_comparator(0), comparator);
+        }
+    }
+    true
+}
+
+/// Stop comparator 0.
+#[allow(dead_code)]
+pub fn stop() {
+    if let Some(hpet) = HPET.lock().as_ref() {
+        unsafe { hpet.write(timer_configuration(0), hpet.timer_configuration); }
+    }
+}
@@ -1,4 +1,5 @@
 use arch::segmentation::{self, SegmentSelector};
+use arch::init::KERNEL_STACK_INDEX;
 use super::bit_field::BitField;
 use super::{HandlerFunc, InterruptVector};
 
@@ -34,7 +35,7 @@ impl<'a> EntryOptions<'a> {
         Self::minimal(entry)
             .set_present(true)
             .disable_interrupts(true)
-            .set_stack_index(0x1)
+            .set_stack_index(KERNEL_STACK_INDEX)
     }
 
     /// Set the entry to be present.
@@ -128,3 +129,35 @@ impl Entry {
         entry
     }
 }
+
+impl Entry {
+    /// Address of the handler.
+    fn handler(&self) -> u64 {
+        self.pointer_low as u64 | (self.pointer_middle as u64) << 16 | (self.pointer_high as u64) << 32
+    }
+}
+
+/// Log each present entry of the IDT the current CPU has loaded, read
+/// back with `sidt`.
+pub fn log_loaded_idt() {
+    use super::dtables::sidt;
+    use core::mem::size_of;
+
+    let pointer = sidt();
+    let base = pointer.base;
+    let length = (pointer.limit as usize + 1) / size_of::<Entry>();
+    log!("IDT at 0x{:x}, {} entries", base, length);
+
+    for vector in 0..length {
+        let entry = unsafe { *(base as *const Entry).offset(vector as isize) };
+        let options = entry.options;
+        if !options.get_bit(15) {
+            continue;
+        }
+        let selector = entry.gdt_selector;
+        log!("{:3}: handler 0x{:016x} selector 0x{:x} {} gate DPL {} IST {}",
+             vector, entry.handler(), selector.bits(),
+             if options.get_bit(8) { "trap" } else { "interrupt" },
+             options.get_range(13..15), options.get_range(0..3));
+    }
+}
@@ -0,0 +1,75 @@
+use arch::cpu::{self, MAX_CPUS};
+use arch::init::set_interrupt_stack;
+use arch::debugreg::{self, RFLAGS_RF};
+use super::switch::{ExceptionStackFrame, INTERRUPTED_SAVED_WORDS};
+use super::{debug_return_to_raw, breakpoint_return_to_raw};
+
+/// Interrupt stack table index of the stack debug exceptions are taken
+/// on.
+pub const DEBUG_STACK_INDEX: u16 = 4;
+/// Length of the debug exception stack.
+const DEBUG_STACK_LENGTH: usize = 8192;
+
+/// Stacks debug exceptions are taken on, one per CPU, so that one
+/// raised in the kernel does not clobber the stack it interrupted.
+#[link_section = ".trampoline.data"]
+static mut DEBUG_STACKS: [[u64; DEBUG_STACK_LENGTH / 8]; MAX_CPUS] = [[0; DEBUG_STACK_LENGTH / 8]; MAX_CPUS];
+
+// Debug events of tasks go to the scheduler, to be sent to their
+// debugger. Those raised in the kernel are handled here.
+return_to_interrupted_fn!(debug_entry, kernel_debug_handler, user: debug_return_to_raw);
+return_to_interrupted_fn!(breakpoint_entry, kernel_breakpoint_handler, user: breakpoint_return_to_raw);
+
+/// Log a debug event raised in the kernel, with the interrupted
+/// registers. Logs to the NMI log, as the event may have hit code
+/// holding any lock.
+unsafe fn report(event: &str, saved: *const u64) {
+    let frame = &*(saved.offset(INTERRUPTED_SAVED_WORDS) as *const ExceptionStackFrame);
+    // The general purpose registers are pushed from rax to r15, above
+    // the saved CR3 with KPTI.
+    let register = |index: isize| *saved.offset(INTERRUPTED_SAVED_WORDS - 1 - index);
+    let cpu = cpu::current_id_lockless();
+
+    nmi_log!(cpu, "kernel {}: rip 0x{:016x} rsp 0x{:016x} rflags 0x{:016x}",
+             event, frame.instruction_pointer, frame.stack_pointer, frame.cpu_flags);
+    nmi_log!(cpu, "rax 0x{:016x} rbx 0x{:016x} rcx 0x{:016x} rdx 0x{:016x}",
+             register(0), register(1), register(2), register(3));
+    nmi_log!(cpu, "rbp 0x{:016x} rsi 0x{:016x} rdi 0x{:016x} r8  0x{:016x}",
+             register(4), register(5), register(6), register(7));
+    nmi_log!(cpu, "r9  0x{:016x} r10 0x{:016x} r11 0x{:016x} r12 0x{:016x}",
+             register(8), register(9), register(10), register(11));
+    nmi_log!(cpu, "r13 0x{:016x} r14 0x{:016x} r15 0x{:016x}",
+             register(12), register(13), register(14));
+}
+
+/// Report a debug exception raised in the kernel, and resume.
+unsafe extern "C" fn kernel_debug_handler(saved: *const u64) {
+    let frame = &mut *(saved.offset(INTERRUPTED_SAVED_WORDS) as *mut ExceptionStackFrame);
+    let status = debugreg::take_status();
+    let (kernel_breakpoints, execute) = debugreg::kernel_hits(status);
+
+    if execute {
+        frame.cpu_flags |= RFLAGS_RF;
+    }
+    // Task breakpoints hit by the kernel reading or writing user memory
+    // are of no interest to it.
+    if status.breakpoints() != 0 && kernel_breakpoints == 0 && !status.is_single_step() {
+        return;
+    }
+    report("debug exception", saved);
+    nmi_log!(cpu::current_id_lockless(), "{}, kernel breakpoints hit 0x{:x}", status, kernel_breakpoints);
+}
+
+/// Report an `int3` raised in the kernel, and resume after it.
+unsafe extern "C" fn kernel_breakpoint_handler(saved: *const u64) {
+    report("breakpoint", saved);
+}
+
+/// Set up the debug exception stack of the current CPU. Must be called
+/// on each CPU.
+pub fn init() {
+    unsafe {
+        let stack = &DEBUG_STACKS[cpu::current_id_lockless()];
+        set_interrupt_stack(DEBUG_STACK_INDEX, (stack as *const _ as u64) + DEBUG_STACK_LENGTH as u64);
+    }
+}
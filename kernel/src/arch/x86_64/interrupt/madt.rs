@@ -0,0 +1,231 @@
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use util::Mutex;
+use arch::acpi::{self, SdtHeader};
+use common::*;
+use arch::cpu::MAX_CPUS;
+use super::apic::{Polarity, TriggerMode};
+
+/// MADT entry type of a processor local APIC.
+const ENTRY_LOCAL_APIC: u8 = 0;
+/// MADT entry type of an I/O APIC.
+const ENTRY_IO_APIC: u8 = 1;
+/// MADT entry type of an interrupt source override.
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+
+/// Number of ISA IRQs.
+pub const ISA_IRQ_COUNT: usize = 16;
+
+/// Fixed part of the MADT, after the header.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+/// Header of every MADT entry.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct EntryHeader {
+    entry_type: u8,
+    length: u8,
+}
+
+/// Processor local APIC MADT entry.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct LocalApicEntry {
+    header: EntryHeader,
+    processor_id: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+/// Local APIC entry flags: the processor is usable.
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// I/O APIC MADT entry.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IoApicEntry {
+    header: EntryHeader,
+    id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+/// Interrupt source override MADT entry.
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct InterruptSourceOverrideEntry {
+    header: EntryHeader,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+/// Global system interrupt an ISA IRQ is wired to, and how it is
+/// signalled.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaIrq {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// ISA IRQs, identity-mapped and edge-triggered active high unless
+/// the MADT overrides them.
+static ISA_IRQS: Mutex<[IsaIrq; ISA_IRQ_COUNT]> = Mutex::new([IsaIrq {
+    gsi: 0,
+    polarity: Polarity::ActiveHigh,
+    trigger: TriggerMode::Edge,
+}; ISA_IRQ_COUNT]);
+
+/// Bitmap of the local APIC ids of the usable processors the MADT
+/// lists, up to `MAX_CPUS`.
+static LOCAL_APIC_IDS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Decode the polarity bits of MPS INTI flags, `default` meaning
+/// conforming to the bus.
+fn polarity(flags: u16, default: Polarity) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => default,
+    }
+}
+
+/// Decode the trigger mode bits of MPS INTI flags.
+fn trigger(flags: u16, default: TriggerMode) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => default,
+    }
+}
+
+/// Read the MADT, recording the usable processors and the ISA
+/// interrupt source overrides. Returns the physical address of the first I/O APIC, or `None` if
+/// there is no MADT.
+pub fn init() -> Option<PAddr> {
+    {
+        let mut isa_irqs = ISA_IRQS.lock();
+        for (irq, isa_irq) in isa_irqs.iter_mut().enumerate() {
+            isa_irq.gsi = irq as u32;
+        }
+    }
+
+    let madt = match acpi::find_table(b"APIC") {
+        Some(madt) => madt,
+        None => return None,
+    };
+    let length = unsafe { acpi::read::<SdtHeader>(madt) }.length as usize;
+
+    let mut io_apic = None;
+    let mut offset = size_of::<MadtHeader>();
+    while offset + size_of::<EntryHeader>() <= length {
+        let entry = madt + offset;
+        let header = unsafe { acpi::read::<EntryHeader>(entry) };
+        let entry_length = header.length as usize;
+        if entry_length < size_of::<EntryHeader>() || offset + entry_length > length {
+            log!("MADT entry at offset {} has a bad length {}, ignoring the rest", offset, entry_length);
+            break;
+        }
+
+        let minimum_length = match header.entry_type {
+            ENTRY_LOCAL_APIC => size_of::<LocalApicEntry>(),
+            ENTRY_IO_APIC => size_of::<IoApicEntry>(),
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => size_of::<InterruptSourceOverrideEntry>(),
+            _ => 0,
+        };
+        if entry_length < minimum_length {
+            log!("MADT entry of type {} is too short, ignoring it", header.entry_type);
+            offset += entry_length;
+            continue;
+        }
+
+        match header.entry_type {
+            ENTRY_LOCAL_APIC => {
+                let entry = unsafe { acpi::read::<LocalApicEntry>(entry) };
+                let (id, flags) = (entry.apic_id as usize, entry.flags);
+                if flags & LOCAL_APIC_ENABLED == 0 {
+                    log!("Processor with local APIC id {} is disabled, ignoring it", id);
+                } else if id >= MAX_CPUS {
+                    log!("Processor with local APIC id {} is past the {} supported, ignoring it", id, MAX_CPUS);
+                } else {
+                    LOCAL_APIC_IDS.fetch_or(1 << id, Ordering::SeqCst);
+                }
+            },
+            ENTRY_IO_APIC if io_apic.is_none() => {
+                let entry = unsafe { acpi::read::<IoApicEntry>(entry) };
+                io_apic = Some(PAddr::from(entry.address as u64));
+            },
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                let entry = unsafe { acpi::read::<InterruptSourceOverrideEntry>(entry) };
+                let (source, gsi, flags) = (entry.source as usize, entry.gsi, entry.flags);
+                if entry.bus == 0 && source < ISA_IRQ_COUNT {
+                    log!("ISA IRQ {} overridden to GSI {}, flags 0x{:x}", source, gsi, flags);
+                    ISA_IRQS.lock()[source] = IsaIrq {
+                        gsi: gsi,
+                        polarity: polarity(flags, Polarity::ActiveHigh),
+                        trigger: trigger(flags, TriggerMode::Edge),
+                    };
+                } else {
+                    log!("Ignoring interrupt source override of bus {} IRQ {}", entry.bus, source);
+                }
+            },
+            _ => (),
+        }
+
+        offset += entry_length;
+    }
+
+    io_apic
+}
+
+/// Drop the ISA IRQ overrides to inputs the I/O APIC, with
+/// `input_count` inputs, does not have, keeping those IRQs
+/// identity-mapped.
+pub fn check_overrides(input_count: u32) {
+    for (irq, isa_irq) in ISA_IRQS.lock().iter_mut().enumerate() {
+        if isa_irq.gsi >= input_count {
+            log!("ISA IRQ {} overridden to GSI {}, past the {} I/O APIC inputs, ignoring the override",
+                 irq, isa_irq.gsi, input_count);
+            *isa_irq = IsaIrq {
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            };
+        }
+    }
+}
+
+/// Trigger mode and polarity of I/O APIC input `gsi`. An input an ISA
+/// IRQ is wired to follows that IRQ, and others are assumed to be PCI
+/// interrupts, level-triggered active low.
+pub fn gsi_signalling(gsi: u32) -> (TriggerMode, Polarity) {
+    match ISA_IRQS.lock().iter().find(|isa_irq| isa_irq.gsi == gsi) {
+        Some(isa_irq) => (isa_irq.trigger, isa_irq.polarity),
+        None => (TriggerMode::Level, Polarity::ActiveLow),
+    }
+}
+
+/// Bitmap of the local APIC ids of the usable processors, the
+/// bootstrap one included. Empty without a MADT.
+pub fn local_apic_ids() -> usize {
+    LOCAL_APIC_IDS.load(Ordering::SeqCst)
+}
+
+/// How ISA IRQ `irq` reaches the I/O APIC.
+pub fn isa_irq(irq: u8) -> IsaIrq {
+    assert!((irq as usize) < ISA_IRQ_COUNT);
+    ISA_IRQS.lock()[irq as usize]
+}
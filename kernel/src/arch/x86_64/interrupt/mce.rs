@@ -0,0 +1,277 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
+use preempt;
+use arch::cpu::{self, MAX_CPUS};
+use arch::init::set_interrupt_stack;
+use super::switch::{ExceptionStackFrame, INTERRUPTED_SAVED_WORDS};
+
+/// Interrupt stack table index of the stack machine checks are taken
+/// on.
+pub const MCE_STACK_INDEX: u16 = 3;
+/// Length of the machine check stack.
+const MCE_STACK_LENGTH: usize = 8192;
+
+/// Machine check capabilities: bank count, and what is implemented.
+const IA32_MCG_CAP: u32 = 0x179;
+/// Machine check status of the processor.
+const IA32_MCG_STATUS: u32 = 0x17A;
+/// Global machine check enable, if `MCG_CAP_CTL_P`.
+const IA32_MCG_CTL: u32 = 0x17B;
+/// First bank register. Each bank has four: control, status, address
+/// and miscellaneous information.
+const IA32_MC0_CTL: u32 = 0x400;
+
+/// MCG_CAP: number of banks.
+const MCG_CAP_COUNT: u64 = 0xff;
+/// MCG_CAP: `IA32_MCG_CTL` is present.
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+/// MCG_CAP: software error recovery is supported.
+const MCG_CAP_SER_P: u64 = 1 << 24;
+
+/// MCG_STATUS: execution can restart at the interrupted instruction.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// MCG_STATUS: the interrupted instruction is the one that erred.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+/// MCi_STATUS: the bank holds an error.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+/// MCi_STATUS: an error was lost while the bank was full.
+const MCI_STATUS_OVER: u64 = 1 << 62;
+/// MCi_STATUS: the error was not corrected.
+const MCI_STATUS_UC: u64 = 1 << 61;
+/// MCi_STATUS: reporting the error was enabled.
+const MCI_STATUS_EN: u64 = 1 << 60;
+/// MCi_STATUS: `IA32_MCi_MISC` is valid.
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+/// MCi_STATUS: `IA32_MCi_ADDR` is valid.
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// MCi_STATUS: the processor context is corrupt.
+const MCI_STATUS_PCC: u64 = 1 << 57;
+/// MCi_STATUS: the error was signaled by a machine check, with
+/// software error recovery.
+const MCI_STATUS_S: u64 = 1 << 56;
+/// MCi_STATUS: software must act on the error before going on, with
+/// software error recovery.
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// Stacks machine checks are taken on, one per CPU, as they can hit
+/// the kernel, and are broadcast to all CPUs.
+#[link_section = ".trampoline.data"]
+static mut MCE_STACKS: [[u64; MCE_STACK_LENGTH / 8]; MAX_CPUS] = [[0; MCE_STACK_LENGTH / 8]; MAX_CPUS];
+
+/// Number of error reporting banks, or zero without the machine check
+/// architecture.
+static BANK_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Whether uncorrected errors may be recoverable.
+static SOFTWARE_RECOVERY: AtomicBool = ATOMIC_BOOL_INIT;
+
+fn bank_msr(bank: usize, register: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + register
+}
+
+/// Error held by a bank.
+struct BankError {
+    bank: usize,
+    status: u64,
+    address: Option<u64>,
+    misc: Option<u64>,
+}
+
+impl BankError {
+    /// Read bank `bank`, if it holds an error.
+    unsafe fn read(bank: usize) -> Option<BankError> {
+        let status = cpu::rdmsr(bank_msr(bank, 1));
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+
+        Some(BankError {
+            bank: bank,
+            status: status,
+            address: if status & MCI_STATUS_ADDRV != 0 { Some(cpu::rdmsr(bank_msr(bank, 2))) } else { None },
+            misc: if status & MCI_STATUS_MISCV != 0 { Some(cpu::rdmsr(bank_msr(bank, 3))) } else { None },
+        })
+    }
+
+    /// Make the bank available for the next error.
+    unsafe fn clear(&self) {
+        cpu::wrmsr(bank_msr(self.bank, 1), 0);
+    }
+
+    fn uncorrected(&self) -> bool {
+        self.status & MCI_STATUS_UC != 0
+    }
+
+    /// Whether the kernel cannot go on: the processor context is
+    /// corrupt, or an uncorrected error needs recovery the kernel does
+    /// not do.
+    fn fatal(&self) -> bool {
+        if self.status & MCI_STATUS_PCC != 0 {
+            return true;
+        }
+        if !self.uncorrected() {
+            return false;
+        }
+        !SOFTWARE_RECOVERY.load(Ordering::Relaxed) ||
+            (self.status & MCI_STATUS_S != 0 && self.status & MCI_STATUS_AR != 0)
+    }
+
+    /// Architectural class of the MCA error code, ignoring the
+    /// correction report filtering bit.
+    fn class(&self) -> &'static str {
+        let code = self.status & 0xffff & !(1 << 12);
+        match code {
+            0x0000 => "no error",
+            0x0001 => "unclassified error",
+            0x0002 => "microcode ROM parity error",
+            0x0003 => "external error",
+            0x0004 => "functional redundancy check error",
+            0x0005 => "internal parity error",
+            0x0006 => "SMM handler code access violation",
+            0x0400 => "internal timer error",
+            _ if code & 0xfc00 == 0x0400 => "internal unclassified error",
+            _ if code & 0xfff0 == 0x0010 => "TLB error",
+            _ if code & 0xff80 == 0x0080 => "memory controller error",
+            _ if code & 0xff00 == 0x0100 => "cache hierarchy error",
+            _ if code & 0xf800 == 0x0800 => "bus or interconnect error",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "bank {}: {} {} (MCA code 0x{:04x}, model code 0x{:04x}), status 0x{:016x}",
+               self.bank, if self.uncorrected() { "uncorrected" } else { "corrected" },
+               self.class(), self.status & 0xffff, (self.status >> 16) & 0xffff, self.status));
+        if self.status & MCI_STATUS_PCC != 0 {
+            try!(write!(f, ", processor context corrupt"));
+        }
+        if self.status & MCI_STATUS_OVER != 0 {
+            try!(write!(f, ", errors lost"));
+        }
+        if self.status & MCI_STATUS_EN == 0 {
+            try!(write!(f, ", not signaled"));
+        }
+        if let Some(address) = self.address {
+            try!(write!(f, ", address 0x{:x}", address));
+        }
+        if let Some(misc) = self.misc {
+            try!(write!(f, ", misc 0x{:x}", misc));
+        }
+        Ok(())
+    }
+}
+
+// Like NMIs, machine checks can hit the kernel at any point.
+return_to_interrupted_fn!(mce_entry, mce_handler);
+
+/// Log the errors held by the banks, and panic unless the kernel can
+/// go on. Runs with anything interrupted, so it logs to the NMI log.
+unsafe extern "C" fn mce_handler(saved: *const u64) {
+    let frame = &*(saved.offset(INTERRUPTED_SAVED_WORDS) as *const ExceptionStackFrame);
+    let cpu = cpu::current_id_lockless();
+    preempt::nmi_enter(cpu);
+
+    let mcg_status = cpu::rdmsr(IA32_MCG_STATUS);
+    nmi_log!(cpu, "machine check at rip 0x{:x}{}, cs 0x{:x}, MCG_STATUS 0x{:x}",
+             frame.instruction_pointer,
+             if mcg_status & MCG_STATUS_EIPV != 0 { " (erring)" } else { "" },
+             frame.code_segment, mcg_status);
+
+    // The interrupted code cannot go on if the processor cannot
+    // restart it.
+    let mut fatal = mcg_status & MCG_STATUS_RIPV == 0;
+    for bank in 0..BANK_COUNT.load(Ordering::Relaxed) {
+        if let Some(error) = BankError::read(bank) {
+            nmi_log!(cpu, "{}", error);
+            fatal = fatal || error.fatal();
+        }
+    }
+
+    if fatal {
+        // The banks are left as they are, so that firmware or the next
+        // boot can read them again. The panic prints the log.
+        panic!("unrecoverable machine check on CPU {}", cpu);
+    }
+
+    for bank in 0..BANK_COUNT.load(Ordering::Relaxed) {
+        if let Some(error) = BankError::read(bank) {
+            error.clear();
+        }
+    }
+    // A machine check while MCIP is still set shuts the processor
+    // down.
+    cpu::wrmsr(IA32_MCG_STATUS, 0);
+    preempt::nmi_exit(cpu);
+}
+
+/// Whether software must leave `IA32_MC0_CTL` as firmware set it,
+/// which is the case of Intel P6 family processors before Nehalem.
+fn bank_0_reserved() -> bool {
+    let (_, ebx, ecx, edx) = cpu::cpuid(0x0, 0);
+    let intel = (ebx, edx, ecx) == (0x756e6547, 0x49656e69, 0x6c65746e);
+    let (eax, _, _, _) = cpu::cpuid(0x1, 0);
+    let family = (eax >> 8) & 0xf;
+    let model = ((eax >> 4) & 0xf) | ((eax >> 12) & 0xf0);
+    intel && family == 6 && model < 0x1A
+}
+
+/// Set up the machine check stack, enable reporting on all banks,
+/// logging any error left from before boot, and enable machine
+/// checks. Must be called on each CPU.
+pub fn init() {
+    unsafe {
+        let stack = &MCE_STACKS[cpu::current_id_lockless()];
+        set_interrupt_stack(MCE_STACK_INDEX, (stack as *const _ as u64) + MCE_STACK_LENGTH as u64);
+    }
+
+    let (_, _, _, edx) = cpu::cpuid(0x1, 0);
+    if edx & cpu::CPUID_01_EDX_MCE == 0 {
+        log!("No machine check support.");
+        return;
+    }
+
+    if edx & cpu::CPUID_01_EDX_MCA != 0 {
+        let capabilities = unsafe { cpu::rdmsr(IA32_MCG_CAP) };
+        let count = (capabilities & MCG_CAP_COUNT) as usize;
+        BANK_COUNT.store(count, Ordering::Relaxed);
+        SOFTWARE_RECOVERY.store(capabilities & MCG_CAP_SER_P != 0, Ordering::Relaxed);
+
+        unsafe {
+            if capabilities & MCG_CAP_CTL_P != 0 {
+                cpu::wrmsr(IA32_MCG_CTL, !0);
+            }
+            let skip_bank_0 = bank_0_reserved();
+            for bank in 0..count {
+                if let Some(error) = BankError::read(bank) {
+                    log!("Machine check from before boot, {}", error);
+                    error.clear();
+                }
+                if bank != 0 || !skip_bank_0 {
+                    cpu::wrmsr(bank_msr(bank, 0), !0);
+                }
+            }
+        }
+
+        log!("Machine check architecture: {} banks, software recovery: {}",
+             count, SOFTWARE_RECOVERY.load(Ordering::Relaxed));
+    }
+
+    unsafe { cpu::cr4_write(cpu::cr4() | cpu::CR4_MCE); }
+}
+
+/// Log and clear corrected errors of the current CPU, which do not
+/// raise a machine check.
+pub fn poll() {
+    for bank in 0..BANK_COUNT.load(Ordering::Relaxed) {
+        if let Some(error) = unsafe { BankError::read(bank) } {
+            // Errors a machine check is about to report are left to
+            // its handler.
+            if !error.fatal() {
+                log!("CPU {}: machine check {}", cpu::current_id(), error);
+                unsafe { error.clear(); }
+            }
+        }
+    }
+}
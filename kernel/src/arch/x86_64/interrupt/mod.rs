@@ -3,7 +3,7 @@ mod idt;
 /// Helpers for representing bit fields.
 mod bit_field;
 /// Functions and data-structures to load descriptor tables.
-mod dtables;
+pub mod dtables;
 /// Advanced Programmable Interrupt Controller.
 mod apic;
 /// Programmable Interrupt Controller.
@@ -12,34 +12,202 @@ mod pic;
 /// Context switching related functionality.
 #[macro_use]
 mod switch;
+/// Page fault decoding and reporting.
+mod fault;
+/// CPU exception decoding, reporting and dispatch.
+mod exception;
+/// Local APIC timer driver.
+pub mod timer;
+/// High Precision Event Timer driver.
+mod hpet;
+/// 8253/8254 Programmable Interval Timer driver.
+mod pit;
+/// Counters `timer::now_ns` reads, and TSC synchronization checks.
+mod clocksource;
+/// MADT parsing, for the I/O APIC and ISA IRQ overrides.
+mod madt;
+/// Routing of I/O APIC inputs to device vectors.
+mod device;
+/// Per-CPU interrupt vector allocator, by priority class.
+mod vector;
+/// Messages of message-signaled interrupts.
+mod msi;
+/// NMI handling and the soft lockup watchdog.
+#[macro_use]
+mod nmi;
+/// Machine check handling.
+mod mce;
+/// In-kernel debugger, reporting debug events raised in kernel mode.
+mod kdebug;
+/// Per-CPU interrupt statistics.
+mod stats;
+/// `syscall` instruction entry.
+mod syscall;
+/// Assembly entry of fastpath calls, from the `syscall` entry.
+mod fastpath;
 
 use common::*;
-use self::switch::{last_exception_return_value, switch_to_raw};
+use abi::{TaskRegisters, BreakpointKind};
+use arch::{KernelStack, FpuState, USER_END};
+use arch::cpu;
+use arch::debugreg::{self, DebugRegisters, RFLAGS_RF};
+use self::switch::{last_exception_return_value, switch_to_raw, ExceptionInfo, ExceptionStackFrame};
 
 pub use self::switch::{HandlerFunc, Registers};
-pub use self::apic::{LOCAL_APIC, IO_APIC};
+pub use self::apic::{LOCAL_APIC, IO_APIC, IpiMode, RedirectionEntry, TriggerMode, Polarity,
+                     init as init_apic};
 pub use self::pic::{disable_pic};
+use self::pic::{PIC_SPURIOUS_MASTER_INTERRUPT_CODE, PIC_SPURIOUS_SLAVE_INTERRUPT_CODE};
+pub use self::fault::PageFaultError;
+pub use self::exception::CpuException;
+pub use self::msi::{MsiMessage, allocate_msi, free_msi, move_msi};
+pub use self::device::{route_irq, set_irq_masked, set_irq_affinity};
+use self::vector::{VectorClass, DEVICE_VECTOR_BASE, DEVICE_VECTOR_COUNT};
+pub use self::vector::init as init_vectors;
+pub use self::nmi::init as init_nmi;
+use self::nmi::{NMI_STACK_INDEX, nmi_entry};
+pub use self::mce::{init as init_mce, poll as poll_machine_checks};
+use self::mce::{MCE_STACK_INDEX, mce_entry};
+pub use self::kdebug::init as init_kdebug;
+use self::kdebug::{DEBUG_STACK_INDEX, debug_entry, breakpoint_entry};
+pub use self::stats::irq_stats;
+pub use self::madt::local_apic_ids;
+pub use self::idt::log_loaded_idt;
+pub use self::syscall::init as init_syscall;
+pub use self::fastpath::{set_handler as set_fastpath_handler, FastpathHandler};
+pub use self::clocksource::{Clocksource, current as current_clocksource, tsc_sync_source, tsc_sync_target};
+
+use self::exception::*;
 
 /// Interrupt vector type.
 pub type InterruptVector = u64;
 
-pub const TIMER_INTERRUPT_CODE: InterruptVector = 0x40;
+pub const PAGE_FAULT_INTERRUPT_CODE: InterruptVector = 0x0E;
+pub const TIMER_INTERRUPT_CODE: InterruptVector = 0xE0;
+pub const HPET_INTERRUPT_CODE: InterruptVector = 0xE1;
+pub const PIT_INTERRUPT_CODE: InterruptVector = 0xE2;
 pub const SPURIOUS_INTERRUPT_CODE: InterruptVector = 0xFF;
 pub const KEYBOARD_INTERRUPT_CODE: InterruptVector = 0x21;
 pub const SYSTEM_CALL_INTERRUPT_CODE: InterruptVector = 0x80;
 pub const DEBUG_CALL_INTERRUPT_CODE: InterruptVector = 0x81;
+pub const TLB_SHOOTDOWN_INTERRUPT_CODE: InterruptVector = 0xF0;
+pub const RESCHEDULE_INTERRUPT_CODE: InterruptVector = 0xF1;
 
+return_to_raw_fn!(divide_error_return_to_raw, DIVIDE_ERROR_INTERRUPT_CODE);
+return_to_raw_fn!(debug_return_to_raw, DEBUG_INTERRUPT_CODE);
+return_to_raw_fn!(breakpoint_return_to_raw, BREAKPOINT_INTERRUPT_CODE);
+return_to_raw_fn!(overflow_return_to_raw, OVERFLOW_INTERRUPT_CODE);
+return_to_raw_fn!(bound_range_return_to_raw, BOUND_RANGE_INTERRUPT_CODE);
+return_to_raw_fn!(invalid_opcode_return_to_raw, INVALID_OPCODE_INTERRUPT_CODE);
+return_to_raw_fn!(device_not_available_return_to_raw, DEVICE_NOT_AVAILABLE_INTERRUPT_CODE);
+return_error_to_raw_fn!(double_fault_return_to_raw, DOUBLE_FAULT_INTERRUPT_CODE);
+return_to_raw_fn!(coprocessor_segment_overrun_return_to_raw, COPROCESSOR_SEGMENT_OVERRUN_INTERRUPT_CODE);
+return_error_to_raw_fn!(invalid_tss_return_to_raw, INVALID_TSS_INTERRUPT_CODE);
+return_error_to_raw_fn!(segment_not_present_return_to_raw, SEGMENT_NOT_PRESENT_INTERRUPT_CODE);
+return_error_to_raw_fn!(stack_segment_fault_return_to_raw, STACK_SEGMENT_FAULT_INTERRUPT_CODE);
+return_error_to_raw_fn!(general_protection_return_to_raw, GENERAL_PROTECTION_INTERRUPT_CODE);
+return_error_to_raw_fn!(page_fault_return_to_raw, PAGE_FAULT_INTERRUPT_CODE);
+return_to_raw_fn!(reserved_15_return_to_raw, 0x0F);
+return_to_raw_fn!(x87_floating_point_return_to_raw, X87_FLOATING_POINT_INTERRUPT_CODE);
+return_error_to_raw_fn!(alignment_check_return_to_raw, ALIGNMENT_CHECK_INTERRUPT_CODE);
+return_to_raw_fn!(simd_floating_point_return_to_raw, SIMD_FLOATING_POINT_INTERRUPT_CODE);
+return_to_raw_fn!(virtualization_return_to_raw, VIRTUALIZATION_INTERRUPT_CODE);
+return_error_to_raw_fn!(control_protection_return_to_raw, CONTROL_PROTECTION_INTERRUPT_CODE);
+return_to_raw_fn!(reserved_22_return_to_raw, 0x16);
+return_to_raw_fn!(reserved_23_return_to_raw, 0x17);
+return_to_raw_fn!(reserved_24_return_to_raw, 0x18);
+return_to_raw_fn!(reserved_25_return_to_raw, 0x19);
+return_to_raw_fn!(reserved_26_return_to_raw, 0x1A);
+return_to_raw_fn!(reserved_27_return_to_raw, 0x1B);
+return_to_raw_fn!(hypervisor_injection_return_to_raw, HYPERVISOR_INJECTION_INTERRUPT_CODE);
+return_error_to_raw_fn!(vmm_communication_return_to_raw, VMM_COMMUNICATION_INTERRUPT_CODE);
+return_error_to_raw_fn!(security_return_to_raw, SECURITY_INTERRUPT_CODE);
+return_to_raw_fn!(reserved_31_return_to_raw, 0x1F);
 return_to_raw_fn!(timer_return_to_raw, TIMER_INTERRUPT_CODE);
+return_to_raw_fn!(hpet_return_to_raw, HPET_INTERRUPT_CODE);
+return_to_raw_fn!(pit_return_to_raw, PIT_INTERRUPT_CODE);
 return_to_raw_fn!(spurious_return_to_raw, SPURIOUS_INTERRUPT_CODE);
 return_to_raw_fn!(keyboard_return_to_raw, KEYBOARD_INTERRUPT_CODE);
 return_to_raw_fn!(system_call_return_to_raw, SYSTEM_CALL_INTERRUPT_CODE);
 return_to_raw_fn!(debug_call_return_to_raw, DEBUG_CALL_INTERRUPT_CODE);
+return_to_raw_fn!(tlb_shootdown_return_to_raw, TLB_SHOOTDOWN_INTERRUPT_CODE);
+return_to_raw_fn!(reschedule_return_to_raw, RESCHEDULE_INTERRUPT_CODE);
+return_to_raw_fn!(device_0_return_to_raw, 0x50);
+return_to_raw_fn!(device_1_return_to_raw, 0x51);
+return_to_raw_fn!(device_2_return_to_raw, 0x52);
+return_to_raw_fn!(device_3_return_to_raw, 0x53);
+return_to_raw_fn!(device_4_return_to_raw, 0x54);
+return_to_raw_fn!(device_5_return_to_raw, 0x55);
+return_to_raw_fn!(device_6_return_to_raw, 0x56);
+return_to_raw_fn!(device_7_return_to_raw, 0x57);
+return_to_raw_fn!(device_8_return_to_raw, 0x58);
+return_to_raw_fn!(device_9_return_to_raw, 0x59);
+return_to_raw_fn!(device_10_return_to_raw, 0x5A);
+return_to_raw_fn!(device_11_return_to_raw, 0x5B);
+return_to_raw_fn!(device_12_return_to_raw, 0x5C);
+return_to_raw_fn!(device_13_return_to_raw, 0x5D);
+return_to_raw_fn!(device_14_return_to_raw, 0x5E);
+return_to_raw_fn!(device_15_return_to_raw, 0x5F);
+return_to_raw_fn!(device_16_return_to_raw, 0x60);
+return_to_raw_fn!(device_17_return_to_raw, 0x61);
+return_to_raw_fn!(device_18_return_to_raw, 0x62);
+return_to_raw_fn!(device_19_return_to_raw, 0x63);
+return_to_raw_fn!(device_20_return_to_raw, 0x64);
+return_to_raw_fn!(device_21_return_to_raw, 0x65);
+return_to_raw_fn!(device_22_return_to_raw, 0x66);
+return_to_raw_fn!(device_23_return_to_raw, 0x67);
+return_to_raw_fn!(device_24_return_to_raw, 0x68);
+return_to_raw_fn!(device_25_return_to_raw, 0x69);
+return_to_raw_fn!(device_26_return_to_raw, 0x6A);
+return_to_raw_fn!(device_27_return_to_raw, 0x6B);
+return_to_raw_fn!(device_28_return_to_raw, 0x6C);
+return_to_raw_fn!(device_29_return_to_raw, 0x6D);
+return_to_raw_fn!(device_30_return_to_raw, 0x6E);
+return_to_raw_fn!(device_31_return_to_raw, 0x6F);
+return_to_raw_fn!(pic_spurious_master_return_to_raw, PIC_SPURIOUS_MASTER_INTERRUPT_CODE);
+return_to_raw_fn!(pic_spurious_slave_return_to_raw, PIC_SPURIOUS_SLAVE_INTERRUPT_CODE);
 
 lazy_static! {
     /// The interrupt descriptor table static.
     pub static ref IDT: idt::Idt = {
         let mut idt = idt::Idt::new();
 
+        // All exception vectors, in order. `int3` and `into` are
+        // allowed from user mode.
+        let exceptions: [HandlerFunc; EXCEPTION_VECTOR_COUNT as usize] = [
+            divide_error_return_to_raw, debug_entry, nmi_entry,
+            breakpoint_entry, overflow_return_to_raw, bound_range_return_to_raw,
+            invalid_opcode_return_to_raw, device_not_available_return_to_raw,
+            double_fault_return_to_raw, coprocessor_segment_overrun_return_to_raw,
+            invalid_tss_return_to_raw, segment_not_present_return_to_raw,
+            stack_segment_fault_return_to_raw, general_protection_return_to_raw,
+            page_fault_return_to_raw, reserved_15_return_to_raw,
+            x87_floating_point_return_to_raw, alignment_check_return_to_raw,
+            mce_entry, simd_floating_point_return_to_raw,
+            virtualization_return_to_raw, control_protection_return_to_raw,
+            reserved_22_return_to_raw, reserved_23_return_to_raw, reserved_24_return_to_raw,
+            reserved_25_return_to_raw, reserved_26_return_to_raw, reserved_27_return_to_raw,
+            hypervisor_injection_return_to_raw, vmm_communication_return_to_raw,
+            security_return_to_raw, reserved_31_return_to_raw,
+        ];
+        for (vector, handler) in exceptions.iter().enumerate() {
+            idt.set_handler(vector as InterruptVector, *handler);
+        }
+        // NMIs can hit the kernel, so they get a stack of their own.
+        idt.set_handler(NMI_INTERRUPT_CODE, nmi_entry)
+            .set_stack_index(NMI_STACK_INDEX);
+        // So can machine checks.
+        idt.set_handler(MACHINE_CHECK_INTERRUPT_CODE, mce_entry)
+            .set_stack_index(MCE_STACK_INDEX);
+        // So can debug exceptions, with kernel breakpoints set.
+        idt.set_handler(DEBUG_INTERRUPT_CODE, debug_entry)
+            .set_stack_index(DEBUG_STACK_INDEX);
+        idt.set_handler(BREAKPOINT_INTERRUPT_CODE, breakpoint_entry)
+            .set_privilege_level(0x3)
+            .set_stack_index(DEBUG_STACK_INDEX);
+        idt.set_handler(OVERFLOW_INTERRUPT_CODE, overflow_return_to_raw)
+            .set_privilege_level(0x3);
+
         idt.set_handler(SYSTEM_CALL_INTERRUPT_CODE, system_call_return_to_raw)
             .set_privilege_level(0x3);
         idt.set_handler(DEBUG_CALL_INTERRUPT_CODE, debug_call_return_to_raw)
@@ -50,11 +218,42 @@ lazy_static! {
             .set_privilege_level(0x3);
         idt.set_handler(TIMER_INTERRUPT_CODE, timer_return_to_raw)
             .set_privilege_level(0x3);
+        idt.set_handler(HPET_INTERRUPT_CODE, hpet_return_to_raw);
+        idt.set_handler(PIT_INTERRUPT_CODE, pit_return_to_raw);
+        idt.set_handler(TLB_SHOOTDOWN_INTERRUPT_CODE, tlb_shootdown_return_to_raw);
+        idt.set_handler(RESCHEDULE_INTERRUPT_CODE, reschedule_return_to_raw);
+        let devices: [HandlerFunc; DEVICE_VECTOR_COUNT] = [
+            device_0_return_to_raw, device_1_return_to_raw, device_2_return_to_raw, device_3_return_to_raw,
+            device_4_return_to_raw, device_5_return_to_raw, device_6_return_to_raw, device_7_return_to_raw,
+            device_8_return_to_raw, device_9_return_to_raw, device_10_return_to_raw, device_11_return_to_raw,
+            device_12_return_to_raw, device_13_return_to_raw, device_14_return_to_raw, device_15_return_to_raw,
+            device_16_return_to_raw, device_17_return_to_raw, device_18_return_to_raw, device_19_return_to_raw,
+            device_20_return_to_raw, device_21_return_to_raw, device_22_return_to_raw, device_23_return_to_raw,
+            device_24_return_to_raw, device_25_return_to_raw, device_26_return_to_raw, device_27_return_to_raw,
+            device_28_return_to_raw, device_29_return_to_raw, device_30_return_to_raw, device_31_return_to_raw,
+        ];
+        for (index, handler) in devices.iter().enumerate() {
+            idt.set_handler(DEVICE_VECTOR_BASE + index as InterruptVector, *handler);
+        }
+        idt.set_handler(PIC_SPURIOUS_MASTER_INTERRUPT_CODE, pic_spurious_master_return_to_raw);
+        idt.set_handler(PIC_SPURIOUS_SLAVE_INTERRUPT_CODE, pic_spurious_slave_return_to_raw);
 
         idt
     };
 }
 
+/// Ask the CPU with id `id` to pick a task again, preempting the one
+/// it is running.
+pub fn send_reschedule(id: usize) {
+    LOCAL_APIC.lock().send_ipi(id as u32, IpiMode::Fixed(RESCHEDULE_INTERRUPT_CODE));
+}
+
+/// Virtual address and length of the IDT.
+#[allow(dead_code)]
+pub fn idt_region() -> (VAddr, usize) {
+    (VAddr::from(&*IDT as *const _ as u64), ::core::mem::size_of::<idt::Idt>())
+}
+
 /// Enum that represents exceptions. Abstracted from interrupt
 /// exception codes.
 #[derive(Debug)]
@@ -63,40 +262,110 @@ pub enum Exception {
     DebugCall,
     Keyboard,
     Spurious,
-    Timer
+    Timer,
+    TlbShootdown,
+    /// Another CPU asked this one to pick a task again.
+    Reschedule,
+    /// Device interrupt, from an I/O APIC line or message-signaled,
+    /// on a device vector allocated on the current CPU.
+    Device {
+        vector: InterruptVector,
+    },
+    /// CPU exception raised in user mode, other than a page fault.
+    Fault {
+        exception: CpuException,
+        /// Instruction pointer of the faulting instruction.
+        instruction_pointer: VAddr,
+        /// Error code pushed by the CPU, or zero if there is none.
+        error_code: u64,
+    },
+    PageFault {
+        /// Linear address that caused the fault.
+        address: VAddr,
+        /// Instruction pointer of the faulting instruction.
+        instruction_pointer: VAddr,
+        /// Decoded page fault error code.
+        error: PageFaultError,
+    },
 }
 
 impl Exception {
-    /// Create a new Exception using an exception code and an optional
-    /// error code.
-    fn new(code: u64, _error: Option<u64>) -> Exception {
-        match code {
+    /// Create a new Exception using the information of the last
+    /// exception and the registers at the time it happened. CPU
+    /// exceptions go through `exception::dispatch`, and those raised
+    /// in kernel mode panic there.
+    fn new(info: &ExceptionInfo, registers: &Registers) -> Exception {
+        if info.exception_code < EXCEPTION_VECTOR_COUNT {
+            return exception::dispatch(&ExceptionContext::new(info, registers));
+        }
+
+        match info.exception_code {
             TIMER_INTERRUPT_CODE => Exception::Timer,
+            HPET_INTERRUPT_CODE => Exception::Timer,
+            PIT_INTERRUPT_CODE => Exception::Timer,
             SPURIOUS_INTERRUPT_CODE => Exception::Spurious,
             KEYBOARD_INTERRUPT_CODE => Exception::Keyboard,
             SYSTEM_CALL_INTERRUPT_CODE => Exception::SystemCall,
             DEBUG_CALL_INTERRUPT_CODE => Exception::DebugCall,
+            TLB_SHOOTDOWN_INTERRUPT_CODE => Exception::TlbShootdown,
+            RESCHEDULE_INTERRUPT_CODE => Exception::Reschedule,
+            vector if VectorClass::of(vector) == Some(VectorClass::Device) =>
+                Exception::Device { vector: vector },
+            PIC_SPURIOUS_MASTER_INTERRUPT_CODE | PIC_SPURIOUS_SLAVE_INTERRUPT_CODE => {
+                // The legacy PICs are masked, so only spurious IRQs
+                // should get here.
+                let irq = (info.exception_code - pic::PIC_MASTER_VECTOR_BASE) as u8;
+                if !unsafe { pic::handle_spurious(irq) } {
+                    log!("Unexpected legacy IRQ {}.", irq);
+                }
+                Exception::Spurious
+            },
             _ => panic!(),
         }
     }
 
+    /// Whether the exception is a hardware interrupt, as opposed to a
+    /// system call or CPU exception raised by the task.
+    pub fn is_interrupt(&self) -> bool {
+        match self {
+            &Exception::Keyboard | &Exception::Spurious | &Exception::Timer |
+            &Exception::TlbShootdown | &Exception::Reschedule | &Exception::Device { .. } => true,
+            _ => false,
+        }
+    }
+
     /// Send End of Interrupt signal if appropriate.
     pub unsafe fn send_eoi(&self) {
         match self {
             &Exception::Timer => LOCAL_APIC.lock().eoi(),
             &Exception::Keyboard => LOCAL_APIC.lock().eoi(),
+            &Exception::TlbShootdown => LOCAL_APIC.lock().eoi(),
+            &Exception::Reschedule => LOCAL_APIC.lock().eoi(),
+            &Exception::Device { .. } => LOCAL_APIC.lock().eoi(),
             _ => (),
         }
     }
 }
 
+/// Flags user mode may change: CF, PF, AF, ZF, SF, TF, DF, OF, AC
+/// and ID.
+const USER_CPU_FLAGS: u64 = 0x0024_0DD5;
+
 /// Represents a task runtime. Used by the task capability.
 #[derive(Debug)]
 pub struct TaskRuntime {
     instruction_pointer: u64,
     cpu_flags: u64,
     stack_pointer: u64,
-    registers: Registers
+    registers: Registers,
+    debug_registers: DebugRegisters,
+    /// FS segment base, for thread-local storage.
+    fs_base: u64,
+    /// GS segment base of user mode. Only FSGSBASE lets the task change
+    /// it.
+    gs_base: u64,
+    /// x87, SSE and AVX state. `None` for kernel tasks.
+    fpu: Option<FpuState>,
 }
 
 impl Default for TaskRuntime {
@@ -106,24 +375,129 @@ impl Default for TaskRuntime {
             cpu_flags: 0b11001000000110,
             stack_pointer: 0x0,
             registers: Registers::default(),
+            debug_registers: DebugRegisters::default(),
+            fs_base: 0,
+            gs_base: 0,
+            fpu: None,
         }
     }
 }
 
+/// A task runtime loaded into the CPU by `TaskRuntime::enter`, ready
+/// to run.
+#[derive(Debug)]
+pub struct RuntimeEntry {
+    instruction_pointer: u64,
+    cpu_flags: u64,
+    stack_pointer: u64,
+    code_seg: u64,
+    data_seg: u64,
+    kernel_stack: u64,
+}
+
+impl RuntimeEntry {
+    /// Run the task until an exception happens, which is then saved
+    /// with `TaskRuntime::exit`, before anything else.
+    ///
+    /// # Safety
+    ///
+    /// Must follow `TaskRuntime::enter` of the task on the current
+    /// CPU, and the kernel stack the task enters the kernel on must
+    /// stay until it returned.
+    pub unsafe fn run(&self) {
+        switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags,
+                      self.code_seg, self.data_seg, self.kernel_stack);
+    }
+
+    /// Load the task into the interrupt stack frame `frame`, for the
+    /// fastpath stub to return to it in place of the task the frame is
+    /// of, and have it enter the kernel on its own stack from then on.
+    ///
+    /// # Safety
+    ///
+    /// As for `run`, and the task must have a kernel stack.
+    unsafe fn resume(&self, frame: &mut ExceptionStackFrame) {
+        debug_assert!(self.kernel_stack != 0, "fastpath to a task without kernel stack");
+        frame.instruction_pointer = self.instruction_pointer;
+        frame.code_segment = self.code_seg;
+        frame.cpu_flags = self.cpu_flags;
+        frame.stack_pointer = self.stack_pointer;
+        frame.stack_segment = self.data_seg;
+        ::arch::init::set_kernel_stack(self.kernel_stack);
+    }
+}
+
 impl TaskRuntime {
     /// Switch to a task using the task runtime.
     ///
     /// # Safety
     ///
     /// `TaskRuntime` must have all values valid. `mode_change` must
-    /// be set according to the task capability.
-    pub unsafe fn switch_to(&mut self, mode_change: bool) -> Exception {
+    /// be set according to the task capability. The task enters the
+    /// kernel on `kernel_stack`, or, without one, on the current stack.
+    pub unsafe fn switch_to(&mut self, mode_change: bool, kernel_stack: Option<&KernelStack>) -> Exception {
+        loop {
+            self.enter(mode_change, kernel_stack).run();
+            if let Some(exception) = self.exit() {
+                return exception;
+            }
+        }
+    }
+
+    /// Load the task runtime into the CPU, to be run with the
+    /// returned entry. The task runtime is not used until it is saved
+    /// back with `exit`, so it need not stay borrowed in between.
+    ///
+    /// # Safety
+    ///
+    /// As for `switch_to`.
+    pub unsafe fn enter(&mut self, mode_change: bool, kernel_stack: Option<&KernelStack>) -> RuntimeEntry {
         let code_seg: u64 = if mode_change { 0x28 | 0x3 } else { 0x8 | 0x0 };
         let data_seg: u64 = if mode_change { 0x30 | 0x3 } else { 0x10 | 0x0 };
 
+        #[cfg(feature="kpti")]
+        {
+            if !mode_change {
+                ::arch::kpti::set_kernel_mode();
+            }
+        }
+
+        stats::record_return();
         switch::set_cur_registers(self.registers.clone());
-        switch_to_raw(self.stack_pointer, self.instruction_pointer, self.cpu_flags, code_seg, data_seg);
+        debugreg::load(&self.debug_registers);
+        if let Some(ref mut fpu) = self.fpu {
+            fpu.enter();
+        }
+        cpu::set_fs_base(self.fs_base);
+        if cpu::has_fsgsbase() {
+            cpu::set_user_gs_base(self.gs_base);
+        }
+        RuntimeEntry {
+            instruction_pointer: self.instruction_pointer,
+            cpu_flags: self.cpu_flags,
+            stack_pointer: self.stack_pointer,
+            code_seg: code_seg,
+            data_seg: data_seg,
+            kernel_stack: kernel_stack.map(|stack| stack.top().into(): u64).unwrap_or(0),
+        }
+    }
+
+    /// Save the task runtime back once the entry returned from `enter`
+    /// ran, and return the exception it ran until. Returns `None` if
+    /// the exception was handled already, and the task is to be
+    /// entered and run again.
+    ///
+    /// # Safety
+    ///
+    /// Must directly follow `RuntimeEntry::run` of the task on the
+    /// current CPU.
+    pub unsafe fn exit(&mut self) -> Option<Exception> {
         self.registers = switch::cur_registers();
+        // With FSGSBASE, the task may have changed its bases itself.
+        if cpu::has_fsgsbase() {
+            self.fs_base = cpu::fs_base();
+            self.gs_base = cpu::user_gs_base();
+        }
 
         let exception_info = last_exception_return_value().unwrap();
 
@@ -131,10 +505,36 @@ impl TaskRuntime {
         self.cpu_flags = exception_info.cpu_flags;
         self.stack_pointer = exception_info.stack_pointer;
 
-        let exception = Exception::new(exception_info.exception_code, exception_info.error_code);
+        let exception = Exception::new(&exception_info, &self.registers);
+        // With the lazy policy, the first use of the FPU after a switch
+        // restores its state, and the task goes on.
+        if let Exception::Fault { exception: CpuException::DeviceNotAvailable, .. } = exception {
+            if self.fpu.as_mut().map(|fpu| fpu.handle_unavailable()).unwrap_or(false) {
+                return None;
+            }
+        }
+        if let Some(ref mut fpu) = self.fpu {
+            fpu.exit();
+        }
+        stats::record(exception_info.exception_code, match exception {
+            Exception::Spurious => true,
+            _ => false,
+        });
+        match exception {
+            Exception::TlbShootdown => ::arch::paging::handle_shootdown(),
+            Exception::Timer => nmi::watchdog_tick(),
+            Exception::Fault { exception: CpuException::Debug(status), .. } => {
+                // Resume at the breakpoint instruction without hitting
+                // it again.
+                if self.debug_registers.hit_execute(status) {
+                    self.cpu_flags |= RFLAGS_RF;
+                }
+            },
+            _ => (),
+        }
         exception.send_eoi();
 
-        return exception;
+        Some(exception)
     }
 
     /// Set the instruction pointer of the task runtime.
@@ -146,6 +546,69 @@ impl TaskRuntime {
     pub fn set_stack_pointer(&mut self, stack_pointer: VAddr) {
         self.stack_pointer = stack_pointer.into();
     }
+
+    /// Give the task runtime an x87, SSE and AVX state, which is then
+    /// switched with it.
+    pub fn set_fpu_state(&mut self, fpu: FpuState) {
+        self.fpu = Some(fpu);
+    }
+
+    /// Set the FS segment base of the task runtime. Returns `false` if
+    /// it is not in user space.
+    pub fn set_fs_base(&mut self, fs_base: VAddr) -> bool {
+        let fs_base: u64 = fs_base.into();
+        if fs_base >= USER_END as u64 {
+            return false;
+        }
+        self.fs_base = fs_base;
+        true
+    }
+
+    /// Registers of the task runtime, as saved at the last exception.
+    pub fn registers(&self) -> TaskRegisters {
+        let registers = &self.registers;
+        TaskRegisters {
+            rax: registers.rax, rbx: registers.rbx, rcx: registers.rcx, rdx: registers.rdx,
+            rsi: registers.rsi, rdi: registers.rdi, rbp: registers.rbp,
+            rsp: self.stack_pointer,
+            r8: registers.r8, r9: registers.r9, r10: registers.r10, r11: registers.r11,
+            r12: registers.r12, r13: registers.r13, r14: registers.r14, r15: registers.r15,
+            rip: self.instruction_pointer,
+            rflags: self.cpu_flags,
+        }
+    }
+
+    /// Set the registers of the task runtime. Only the flags user mode
+    /// may change are taken from `registers.rflags`.
+    pub fn set_registers(&mut self, registers: &TaskRegisters) {
+        self.registers = Registers {
+            rax: registers.rax, rbx: registers.rbx, rcx: registers.rcx, rdx: registers.rdx,
+            rsi: registers.rsi, rdi: registers.rdi, rbp: registers.rbp,
+            r8: registers.r8, r9: registers.r9, r10: registers.r10, r11: registers.r11,
+            r12: registers.r12, r13: registers.r13, r14: registers.r14, r15: registers.r15,
+        };
+        self.stack_pointer = registers.rsp;
+        self.instruction_pointer = registers.rip;
+        self.cpu_flags = (self.cpu_flags & !USER_CPU_FLAGS) | (registers.rflags & USER_CPU_FLAGS);
+    }
+
+    /// Set hardware breakpoint `slot` of the task on `length` bytes at
+    /// user address `address`. Returns `false` if the breakpoint is
+    /// invalid.
+    pub fn set_breakpoint(&mut self, slot: usize, address: u64, kind: BreakpointKind, length: u8) -> bool {
+        self.debug_registers.set_user(slot, address, kind, length)
+    }
+
+    /// Clear hardware breakpoint `slot` of the task. Returns `false` if
+    /// it was not set.
+    pub fn clear_breakpoint(&mut self, slot: usize) -> bool {
+        self.debug_registers.clear(slot)
+    }
+}
+
+/// Whether an interrupt is waiting for the current CPU to take it.
+pub fn interrupt_pending() -> bool {
+    LOCAL_APIC.lock().interrupt_pending()
 }
 
 /// Enable interrupt. Not used.
@@ -0,0 +1,56 @@
+use arch::cpu;
+use super::InterruptVector;
+use super::vector;
+
+/// Fixed upper part of the MSI address, targeting the local APICs.
+const MSI_ADDRESS_BASE: u64 = 0xFEE00000;
+/// Shift of the destination local APIC id in the MSI address.
+const MSI_ADDRESS_DESTINATION_SHIFT: u64 = 12;
+
+/// Address and data a device writes to raise a message-signaled
+/// interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// Message delivering `vector` edge-triggered, in fixed mode, to
+    /// the CPU with id `cpu`, in physical destination mode.
+    pub fn new(vector: InterruptVector, cpu: usize) -> MsiMessage {
+        MsiMessage {
+            address: MSI_ADDRESS_BASE | ((cpu as u64) << MSI_ADDRESS_DESTINATION_SHIFT),
+            data: vector as u32 & 0xff,
+        }
+    }
+}
+
+/// Allocate a device interrupt vector for a message-signaled interrupt
+/// delivered to the CPU with id `cpu`. Returns `None` if the CPU is
+/// not online, or all vectors are taken.
+pub fn allocate_msi(cpu: usize) -> Option<(InterruptVector, MsiMessage)> {
+    if !cpu::is_online(cpu) {
+        return None;
+    }
+
+    vector::allocate(cpu).map(|vector| (vector, MsiMessage::new(vector, cpu)))
+}
+
+/// Return a vector from `allocate_msi` for the CPU with id `cpu`.
+pub fn free_msi(cpu: usize, vector: InterruptVector) {
+    vector::free(cpu, vector);
+}
+
+/// Replace `vector` from `allocate_msi` for the CPU with id `cpu` by a
+/// vector delivered to the CPU with id `new_cpu`. Returns `None`,
+/// keeping the old vector, if `new_cpu` is not online or all its
+/// vectors are taken.
+pub fn move_msi(cpu: usize, vector: InterruptVector,
+                new_cpu: usize) -> Option<(InterruptVector, MsiMessage)> {
+    let moved = allocate_msi(new_cpu);
+    if moved.is_some() {
+        vector::free(cpu, vector);
+    }
+    moved
+}
@@ -0,0 +1,218 @@
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
+use util::Mutex;
+use {preempt, softirq, logging};
+use arch::{cpu, inportb, outportb, io_wait, command_line_option};
+use arch::cpu::MAX_CPUS;
+use arch::init::set_interrupt_stack;
+use super::{LOCAL_APIC, IpiMode};
+use super::switch::{ExceptionStackFrame, INTERRUPTED_SAVED_WORDS};
+use super::{timer, stats};
+
+/// Interrupt stack table index of the stack NMIs are taken on.
+pub const NMI_STACK_INDEX: u16 = 2;
+/// Length of the NMI stack of each CPU.
+const NMI_STACK_LENGTH: usize = 8192;
+
+/// Time a CPU may go without a timer interrupt before the watchdog
+/// reports it as locked up, in nanoseconds.
+const WATCHDOG_THRESHOLD_NS: u64 = 2_000_000_000;
+
+/// System control port B, reporting the hardware error NMI sources.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+/// Port B: memory parity error or PCI SERR#.
+const PORT_B_SERR: u8 = 1 << 7;
+/// Port B: I/O channel check error.
+const PORT_B_IOCHK: u8 = 1 << 6;
+/// Port B: writing one clears and disables the SERR# NMI source.
+const PORT_B_SERR_DISABLE: u8 = 1 << 2;
+/// Port B: writing one clears and disables the IOCHK NMI source.
+const PORT_B_IOCHK_DISABLE: u8 = 1 << 3;
+/// Port B bits that are written back, the others being read-only.
+const PORT_B_WRITABLE: u8 = 0x0f;
+
+/// Model-specific register reporting performance counter overflows.
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+/// Model-specific register clearing performance counter overflows.
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+/// LVT delivery mode raising an NMI.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Stacks NMIs are taken on, one per CPU, so that one hitting the
+/// kernel does not clobber the stack it interrupted, and NMIs on
+/// different CPUs, as the watchdog sends, do not clobber each other.
+#[link_section = ".trampoline.data"]
+static mut NMI_STACKS: [[u64; NMI_STACK_LENGTH / 8]; MAX_CPUS] = [[0; NMI_STACK_LENGTH / 8]; MAX_CPUS];
+
+/// Whether `IA32_PERF_GLOBAL_STATUS` exists, to tell PMIs apart.
+static PERF_GLOBAL_STATUS: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether the soft lockup watchdog runs.
+static WATCHDOG_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+/// Bitmap of CPUs the watchdog sent an NMI to, indexed by local APIC
+/// id.
+static WATCHDOG_PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Timer interrupts taken by one CPU, as seen by the watchdog.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    count: usize,
+    /// `count` when the watchdog last saw it change.
+    seen: usize,
+    /// Time `count` last changed, in nanoseconds.
+    changed_ns: u64,
+    /// Whether the current lockup was already reported.
+    reported: bool,
+}
+
+/// Heartbeats of all CPUs, indexed by local APIC id.
+static HEARTBEATS: Mutex<[Heartbeat; MAX_CPUS]> = Mutex::new([Heartbeat {
+    count: 0,
+    seen: 0,
+    changed_ns: 0,
+    reported: false,
+}; MAX_CPUS]);
+
+/// Write a line to the NMI log ring of CPU `$cpu`. Like `log!` in NMI
+/// context, but also usable where the preemption count does not tell
+/// the handler apart, as in debug exceptions.
+macro_rules! nmi_log {
+    ($cpu:expr, $($arg:tt)*) => ({
+        use core::fmt::Write;
+        let mut writer = ::logging::RingWriter::nmi($cpu);
+        let _ = writeln!(&mut writer, $($arg)*);
+        writer.publish();
+    })
+}
+
+// Unlike other interrupts, an NMI can hit the kernel at any point, so
+// it returns to whatever it interrupted instead of to the scheduler.
+return_to_interrupted_fn!(nmi_entry, nmi_handler);
+
+/// Find out why the NMI was raised, and handle every source found.
+/// Runs with anything interrupted, so it must not take a lock that
+/// could be held.
+unsafe extern "C" fn nmi_handler(saved: *const u64) {
+    let frame = &*(saved.offset(INTERRUPTED_SAVED_WORDS) as *const ExceptionStackFrame);
+    let cpu = cpu::current_id_lockless();
+    let mut handled = false;
+    preempt::nmi_enter(cpu);
+    stats::record_nmi(cpu);
+
+    if WATCHDOG_PENDING.fetch_and(!(1 << cpu), Ordering::SeqCst) & (1 << cpu) != 0 {
+        nmi_log!(cpu, "soft lockup: no timer interrupt for {} ms, rip 0x{:x}, cs 0x{:x}, rflags 0x{:x}",
+                 WATCHDOG_THRESHOLD_NS / 1_000_000, frame.instruction_pointer,
+                 frame.code_segment, frame.cpu_flags);
+        handled = true;
+    }
+
+    if PERF_GLOBAL_STATUS.load(Ordering::Relaxed) {
+        let overflows = cpu::rdmsr(IA32_PERF_GLOBAL_STATUS);
+        if overflows != 0 {
+            cpu::wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, overflows);
+            // Delivering the PMI masked the LVT entry. If the local
+            // APIC is locked, the next watchdog tick unmasks it.
+            if let Some(mut local_apic) = LOCAL_APIC.try_lock() {
+                local_apic.set_perfmon(LVT_DELIVERY_NMI);
+            }
+            nmi_log!(cpu, "performance counter overflow 0x{:x}, rip 0x{:x}",
+                     overflows, frame.instruction_pointer);
+            handled = true;
+        }
+    }
+
+    let reason = inportb(SYSTEM_CONTROL_PORT_B);
+    if reason & PORT_B_SERR != 0 {
+        nmi_log!(cpu, "hardware error: memory parity or system error (SERR#), rip 0x{:x}",
+                 frame.instruction_pointer);
+        clear_port_b(reason, PORT_B_SERR_DISABLE);
+        handled = true;
+    }
+    if reason & PORT_B_IOCHK != 0 {
+        nmi_log!(cpu, "hardware error: I/O channel check (IOCHK), rip 0x{:x}",
+                 frame.instruction_pointer);
+        clear_port_b(reason, PORT_B_IOCHK_DISABLE);
+        handled = true;
+    }
+
+    if !handled {
+        nmi_log!(cpu, "NMI for unknown reason, rip 0x{:x}", frame.instruction_pointer);
+    }
+    preempt::nmi_exit(cpu);
+}
+
+/// Clear a hardware error NMI source of port B, by pulsing its
+/// disable bit.
+unsafe fn clear_port_b(reason: u8, disable: u8) {
+    let reason = reason & PORT_B_WRITABLE;
+    outportb(SYSTEM_CONTROL_PORT_B, reason | disable);
+    io_wait();
+    outportb(SYSTEM_CONTROL_PORT_B, reason & !disable);
+}
+
+/// Set up the NMI stack of the current CPU, deliver performance
+/// counter overflows as NMIs, and start the watchdog unless
+/// `nmi_watchdog=0` is given. Must be called on each CPU, after the
+/// APIC is initialized.
+pub fn init() {
+    unsafe {
+        let stack = &NMI_STACKS[cpu::current_id_lockless()];
+        set_interrupt_stack(NMI_STACK_INDEX, (stack as *const _ as u64) + NMI_STACK_LENGTH as u64);
+    }
+
+    // Architectural performance monitoring version 2 added the global
+    // overflow status.
+    let (eax, _, _, _) = cpu::cpuid(0xA, 0);
+    PERF_GLOBAL_STATUS.store((eax & 0xff) >= 2, Ordering::Relaxed);
+    LOCAL_APIC.lock().set_perfmon(LVT_DELIVERY_NMI);
+
+    match command_line_option("nmi_watchdog") {
+        Some("0") => log!("NMI watchdog disabled."),
+        None | Some("1") => WATCHDOG_ENABLED.store(true, Ordering::Relaxed),
+        Some(other) => {
+            log!("Unknown nmi_watchdog={}, keeping it enabled.", other);
+            WATCHDOG_ENABLED.store(true, Ordering::Relaxed);
+        },
+    }
+}
+
+/// Account a timer interrupt on the current CPU, and send an NMI to
+/// every other CPU that took none for `WATCHDOG_THRESHOLD_NS`, which
+/// reports where it is stuck. Also defers printing the log rings.
+pub fn watchdog_tick() {
+    if logging::pending() {
+        softirq::defer(|_| logging::flush(), 0);
+    }
+    if !WATCHDOG_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let current = cpu::current_id_lockless();
+    let now = timer::now_ns();
+
+    // A CPU stuck while holding either lock must not stop the others.
+    let mut heartbeats = match HEARTBEATS.try_lock() {
+        Some(heartbeats) => heartbeats,
+        None => return,
+    };
+    let mut local_apic = match LOCAL_APIC.try_lock() {
+        Some(local_apic) => local_apic,
+        None => return,
+    };
+    heartbeats[current].count += 1;
+    // Unmask performance counter overflows, in case the NMI handler
+    // could not.
+    local_apic.set_perfmon(LVT_DELIVERY_NMI);
+
+    for (id, heartbeat) in heartbeats.iter_mut().enumerate() {
+        if heartbeat.count != heartbeat.seen || id == current || !cpu::is_online(id) {
+            heartbeat.seen = heartbeat.count;
+            heartbeat.changed_ns = now;
+            heartbeat.reported = false;
+        } else if !heartbeat.reported &&
+            now.saturating_sub(heartbeat.changed_ns) >= WATCHDOG_THRESHOLD_NS
+        {
+            heartbeat.reported = true;
+            WATCHDOG_PENDING.fetch_or(1 << id, Ordering::SeqCst);
+            local_apic.send_ipi(id as u32, IpiMode::Nmi);
+        }
+    }
+}
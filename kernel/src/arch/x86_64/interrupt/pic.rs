@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
-use arch::{inportb, outportb};
+use arch::{inportb, outportb, io_wait};
+use super::InterruptVector;
 
 const PIC1_COMMAND: u16 = 0x20;
 const PIC1_DATA: u16 = 0x21;
@@ -18,37 +19,87 @@ const ICW4_AUTO: u8 = 0x02;
 const ICW4_BUF_SLAVE: u8 = 0x08;
 const ICW4_BUF_MASTER: u8 = 0x0C;
 const ICW4_SFNM: u8 = 0x10;
+/// OCW3 command making the next command port read return the
+/// in-service register.
+const OCW3_READ_ISR: u8 = 0x0B;
 
-/// Disable Programmable Interrupt Controller.
+/// Vector the master PIC IRQs are remapped to, clear of the CPU
+/// exception vectors.
+pub const PIC_MASTER_VECTOR_BASE: InterruptVector = 0xE0;
+/// Vector the slave PIC IRQs are remapped to.
+pub const PIC_SLAVE_VECTOR_BASE: InterruptVector = 0xE8;
+/// Vector of IRQ 7, raised spuriously by the master PIC.
+pub const PIC_SPURIOUS_MASTER_INTERRUPT_CODE: InterruptVector = PIC_MASTER_VECTOR_BASE + 7;
+/// Vector of IRQ 15, raised spuriously by the slave PIC.
+pub const PIC_SPURIOUS_SLAVE_INTERRUPT_CODE: InterruptVector = PIC_SLAVE_VECTOR_BASE + 7;
+
+/// Remap the legacy PICs clear of the CPU exception vectors, and mask
+/// all their IRQs, so that interrupts are only taken from the APICs.
+/// The PICs can still raise IRQ 7 and IRQ 15 spuriously; see
+/// `handle_spurious`.
 pub unsafe fn disable_pic() {
-    // Set ICW1
-    outportb(0x20, 0x11);
-    outportb(0xa0, 0x11);
+    // ICW1: start initialization, expecting ICW4.
+    outportb(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    outportb(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+
+    // ICW2: vector bases.
+    outportb(PIC1_DATA, PIC_MASTER_VECTOR_BASE as u8);
+    io_wait();
+    outportb(PIC2_DATA, PIC_SLAVE_VECTOR_BASE as u8);
+    io_wait();
 
-    // Set IWC2 (IRQ base offsets)
-    outportb(0x21, 0xe0);
-    outportb(0xa1, 0xe8);
+    // ICW3: the slave is cascaded on IRQ 2.
+    outportb(PIC1_DATA, 1 << 2);
+    io_wait();
+    outportb(PIC2_DATA, 2);
+    io_wait();
 
-    // Set ICW3
-    outportb(0x21, 4);
-    outportb(0xa1, 2);
+    // ICW4: 8086 mode.
+    outportb(PIC1_DATA, ICW4_8086);
+    io_wait();
+    outportb(PIC2_DATA, ICW4_8086);
+    io_wait();
+
+    // OCW1: mask everything.
+    outportb(PIC1_DATA, 0xff);
+    outportb(PIC2_DATA, 0xff);
+}
+
+/// Whether `irq` is in service, that is, the PIC really raised it.
+pub unsafe fn in_service(irq: u8) -> bool {
+    if irq >= 8 {
+        outportb(PIC2_COMMAND, OCW3_READ_ISR);
+        inportb(PIC2_COMMAND) & (1 << (irq - 8)) != 0
+    } else {
+        outportb(PIC1_COMMAND, OCW3_READ_ISR);
+        inportb(PIC1_COMMAND) & (1 << irq) != 0
+    }
+}
 
-    // Set ICW4
-    outportb(0x21, 1);
-    outportb(0xa1, 1);
+/// Handle IRQ 7 or IRQ 15. A spurious IRQ must not be acknowledged,
+/// except that the master PIC still expects an end of interrupt for
+/// the cascade when the slave raised it. Returns `true` if the IRQ was
+/// spurious.
+pub unsafe fn handle_spurious(irq: u8) -> bool {
+    if in_service(irq) {
+        send_pic_eoi(irq);
+        return false;
+    }
 
-    // Set OCW1 (interrupt masks)
-    outportb(0x21, 0xff);
-    outportb(0xa1, 0xff);
+    if irq >= 8 {
+        outportb(PIC1_COMMAND, PIC_EOI);
+    }
+    true
 }
 
 /// Send End of Interrupt for PIC.
 pub unsafe fn send_pic_eoi(irq: u8) {
     if irq >= 8 {
         outportb(PIC2_COMMAND, PIC_EOI);
-    } else {
-        outportb(PIC1_COMMAND, PIC_EOI);
     }
+    outportb(PIC1_COMMAND, PIC_EOI);
 }
 
 /// Enable Programmable Interrupt Controller.
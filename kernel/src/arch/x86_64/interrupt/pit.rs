@@ -0,0 +1,87 @@
+use arch::{inportb, outportb};
+use super::{LOCAL_APIC, IO_APIC, PIT_INTERRUPT_CODE};
+
+/// Input clock frequency of the PIT, in hertz.
+pub const PIT_FREQUENCY: u64 = 1193182;
+/// PIT channel 0 data port. Channel 0 drives ISA IRQ 0.
+const PIT_CHANNEL_0: u16 = 0x40;
+/// PIT channel 2 data port.
+pub const PIT_CHANNEL_2: u16 = 0x42;
+/// PIT mode/command port.
+pub const PIT_COMMAND: u16 = 0x43;
+/// Port controlling the PIT channel 2 gate, and reporting its output.
+pub const PIT_GATE: u16 = 0x61;
+
+/// ISA IRQ of PIT channel 0.
+const PIT_IRQ: u8 = 0;
+/// Command: channel 0, low byte then high byte, interrupt on terminal
+/// count.
+const CHANNEL_0_ONE_SHOT: u8 = 0b00110000;
+/// Command: channel 0, low byte then high byte, rate generator.
+const CHANNEL_0_PERIODIC: u8 = 0b00110100;
+/// Largest count, written as zero.
+const MAX_COUNT: u64 = 0x10000;
+
+/// PIT counts in `ns` nanoseconds, between 1 and `MAX_COUNT`.
+fn ns_to_count(ns: u64) -> u64 {
+    // Anything above 100 ms is above `MAX_COUNT` anyway, and the
+    // product does not overflow below it.
+    let ns = ::core::cmp::min(ns, 100_000_000);
+    ::core::cmp::max(1, ::core::cmp::min(ns * PIT_FREQUENCY / 1_000_000_000, MAX_COUNT))
+}
+
+/// Program channel 0 with `command`, and start it counting down from
+/// `count`.
+unsafe fn start_channel_0(command: u8, count: u64) {
+    outportb(PIT_COMMAND, command);
+    outportb(PIT_CHANNEL_0, count as u8);
+    outportb(PIT_CHANNEL_0, (count >> 8) as u8);
+}
+
+/// Busy-wait `ms` milliseconds, at most 54, on PIT channel 2.
+pub unsafe fn wait_ms(ms: u64) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count < MAX_COUNT);
+
+    // Enable the gate, with the speaker off.
+    let gate = inportb(PIT_GATE);
+    outportb(PIT_GATE, (gate & !0x02) | 0x01);
+
+    // Channel 2, low byte then high byte, interrupt on terminal count.
+    outportb(PIT_COMMAND, 0b10110000);
+    outportb(PIT_CHANNEL_2, count as u8);
+    outportb(PIT_CHANNEL_2, (count >> 8) as u8);
+
+    // Restart the count with a rising edge of the gate.
+    let gate = inportb(PIT_GATE) & !0x01;
+    outportb(PIT_GATE, gate);
+    outportb(PIT_GATE, gate | 0x01);
+
+    while inportb(PIT_GATE) & 0x20 == 0 { }
+}
+
+/// Stop channel 0, and route its interrupt to the current CPU. The PIT
+/// then serves as the timer of that CPU only.
+pub fn init() {
+    stop();
+    let apic_id = LOCAL_APIC.lock().id() as u8;
+    IO_APIC.lock().set_isa_irq(PIT_IRQ, apic_id, PIT_INTERRUPT_CODE);
+    log!("PIT: channel 0 on ISA IRQ {}", PIT_IRQ);
+}
+
+/// Fire the timer interrupt every `period_ns` nanoseconds, rounded
+/// down to at most 54 ms.
+pub fn set_periodic(period_ns: u64) {
+    unsafe { start_channel_0(CHANNEL_0_PERIODIC, ns_to_count(period_ns)); }
+}
+
+/// Fire the timer interrupt once, in `delta_ns` nanoseconds. Longer
+/// than 54 ms, it fires early, and the caller programs it again.
+pub fn set_one_shot(delta_ns: u64) {
+    unsafe { start_channel_0(CHANNEL_0_ONE_SHOT, ns_to_count(delta_ns)); }
+}
+
+/// Stop channel 0. It does not count until programmed again.
+pub fn stop() {
+    unsafe { outportb(PIT_COMMAND, CHANNEL_0_ONE_SHOT); }
+}
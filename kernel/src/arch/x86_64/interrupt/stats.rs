@@ -0,0 +1,93 @@
+use core::ptr;
+use abi::{IrqStats, IRQ_STATS_VECTORS};
+use arch::cpu::{self, MAX_CPUS};
+use super::InterruptVector;
+use super::timer;
+
+/// Number of interrupt vectors.
+const VECTOR_COUNT: usize = 256;
+
+/// Interrupt statistics of one CPU. Each CPU only updates its own;
+/// other CPUs read them with no synchronization, which is good enough
+/// for counters.
+#[derive(Copy)]
+struct CpuStats {
+    counts: [u64; VECTOR_COUNT],
+    max_latency_ns: [u64; VECTOR_COUNT],
+    spurious: u64,
+    nmis: u64,
+    /// Vector being handled, and when it was taken, until the CPU
+    /// returns to a task.
+    handling: Option<(InterruptVector, u64)>,
+}
+
+impl Clone for CpuStats {
+    fn clone(&self) -> CpuStats {
+        *self
+    }
+}
+
+/// Interrupt statistics, indexed by local APIC id.
+static mut STATS: [CpuStats; MAX_CPUS] = [CpuStats {
+    counts: [0; VECTOR_COUNT],
+    max_latency_ns: [0; VECTOR_COUNT],
+    spurious: 0,
+    nmis: 0,
+    handling: None,
+}; MAX_CPUS];
+
+/// Account an interrupt on `vector` taken by the current CPU.
+/// `spurious` is set if it turned out to be spurious.
+pub fn record(vector: InterruptVector, spurious: bool) {
+    let stats = unsafe { &mut STATS[cpu::current_id()] };
+    stats.counts[vector as usize] += 1;
+    if spurious {
+        stats.spurious += 1;
+    }
+    stats.handling = Some((vector, timer::now_ns()));
+}
+
+/// Account the end of the handling of the last interrupt taken by
+/// the current CPU, when it returns to a task.
+pub fn record_return() {
+    let stats = unsafe { &mut STATS[cpu::current_id()] };
+    if let Some((vector, taken_ns)) = stats.handling.take() {
+        let latency = timer::now_ns().saturating_sub(taken_ns);
+        if latency > stats.max_latency_ns[vector as usize] {
+            stats.max_latency_ns[vector as usize] = latency;
+        }
+    }
+}
+
+/// Account an NMI taken by CPU `cpu`. Called from the NMI handler,
+/// so it takes no lock.
+pub fn record_nmi(cpu: usize) {
+    unsafe {
+        let nmis = &mut STATS[cpu].nmis;
+        ptr::write_volatile(nmis, ptr::read_volatile(nmis) + 1);
+    }
+}
+
+/// Snapshot the statistics of the CPU with id `cpu`, for the
+/// `IRQ_STATS_VECTORS` vectors from `first_vector`. Returns `None` if
+/// the CPU is not online, or the vectors run past the last one.
+pub fn irq_stats(cpu: usize, first_vector: u8) -> Option<IrqStats> {
+    if !cpu::is_online(cpu) || first_vector as usize + IRQ_STATS_VECTORS > VECTOR_COUNT {
+        return None;
+    }
+
+    let stats = unsafe { &STATS[cpu] };
+    let mut snapshot = IrqStats {
+        first_vector: first_vector,
+        counts: [0; IRQ_STATS_VECTORS],
+        max_latency_ns: [0; IRQ_STATS_VECTORS],
+        spurious: unsafe { ptr::read_volatile(&stats.spurious) },
+        nmis: unsafe { ptr::read_volatile(&stats.nmis) },
+    };
+    for i in 0..IRQ_STATS_VECTORS {
+        let vector = first_vector as usize + i;
+        snapshot.counts[i] = unsafe { ptr::read_volatile(&stats.counts[vector]) };
+        snapshot.max_latency_ns[i] = unsafe { ptr::read_volatile(&stats.max_latency_ns[vector]) };
+    }
+    Some(snapshot)
+}
@@ -1,4 +1,4 @@
-use arch::init;
+use arch::{init, cpu, MAX_CPUS};
 
 /// Interrupt handler function type.
 pub type HandlerFunc = unsafe extern "C" fn();
@@ -55,16 +55,115 @@ impl Default for Registers {
 
 pub static mut RSP_AFTER_SAVING_REGISTERS: u64 = 0;
 
+/// Top of the kernel stack of the task each CPU is switching to, or
+/// zero to enter the kernel on the current stack.
+static mut NEXT_KERNEL_STACKS: [u64; MAX_CPUS] = [0; MAX_CPUS];
+
+/// Load the stack the task being switched to enters the kernel on in
+/// the TSS, for interrupts, exceptions and `syscall` alike.
 unsafe extern "C" fn set_kernel_stack(addr: u64) {
-    init::set_kernel_stack(addr);
+    let next = NEXT_KERNEL_STACKS[cpu::current_id_lockless()];
+    init::set_kernel_stack(if next != 0 { next } else { addr });
 }
 
-pub unsafe fn switch_to_raw(stack_vaddr: u64, code_start: u64, cpu_flags: u64, code_seg: u64, data_seg: u64) {
+pub unsafe fn switch_to_raw(stack_vaddr: u64, code_start: u64, cpu_flags: u64, code_seg: u64, data_seg: u64,
+                            kernel_stack: u64) {
+    NEXT_KERNEL_STACKS[cpu::current_id_lockless()] = kernel_stack;
     switch_to_raw_naked(stack_vaddr, code_start, cpu_flags, code_seg, data_seg);
 }
 
+/// Switch to the kernel page table on exception entry. Expands to
+/// nothing without KPTI.
+#[cfg(feature="kpti")]
+macro_rules! kpti_enter {
+    () => (
+        asm!("push rax
+              mov rax, [$0]
+              mov cr3, rax
+              pop rax"
+             :: "i"(&::arch::kpti::KERNEL_CR3)
+             :: "volatile", "intel");
+    )
+}
+
+#[cfg(not(feature="kpti"))]
+macro_rules! kpti_enter {
+    () => ()
+}
+
+/// Move to the entry stack before building the return frame, so that
+/// the frame stays mapped after switching to the user page table.
+#[cfg(feature="kpti")]
+macro_rules! kpti_entry_stack {
+    () => (
+        asm!("mov rsp, [$0]"
+             :: "i"(&::arch::kpti::ENTRY_STACK_TOP)
+             :: "volatile", "intel");
+    )
+}
+
+#[cfg(not(feature="kpti"))]
+macro_rules! kpti_entry_stack {
+    () => ()
+}
+
+/// Clear the direction flag, which kernel code assumes clear, and the
+/// alignment check flag, which with SMAP would leave user pages open to
+/// the kernel, whatever the interrupted code set them to. Both are
+/// restored from the interrupt stack frame on return. Only flags are
+/// changed, so this can run before the registers are saved, but after
+/// the kernel page table is loaded.
+macro_rules! clear_entry_flags {
+    () => (
+        asm!("cld
+              cmp byte ptr [$0], 0
+              je 1f
+              clac
+              1:"
+             :: "i"(&::arch::user::SMAP_ENABLED)
+             :: "volatile", "intel");
+    )
+}
+
+/// Swap GS bases if the interrupt stack frame `$cs_offset` bytes above
+/// the stack pointer is that of user mode. On entry, this swaps in the
+/// kernel per-CPU data; on exit to user mode, it swaps it out.
+macro_rules! swapgs_if_user {
+    ($cs_offset: expr) => (
+        asm!("test qword ptr [rsp + $0], 3
+              jz 1f
+              swapgs
+              1:"
+             :: "i"($cs_offset)
+             :: "volatile", "intel");
+    )
+}
+
+/// Return to the task with `iretq`, switching to the user page table
+/// first with KPTI.
+#[cfg(feature="kpti")]
+macro_rules! kpti_return {
+    () => (
+        asm!("push rax
+              mov rax, [$0]
+              mov cr3, rax
+              pop rax
+              iretq"
+             :: "i"(&::arch::kpti::USER_CR3)
+             :: "volatile", "intel");
+    )
+}
+
+#[cfg(not(feature="kpti"))]
+macro_rules! kpti_return {
+    () => (
+        asm!("iretq" :::: "volatile", "intel");
+    )
+}
+
 #[naked]
 #[inline(never)]
+#[link_section = ".trampoline.text"]
 pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64, cpu_flags: u64, code_seg: u64, data_seg: u64) {
     asm!("
        /* save registers */
@@ -83,8 +182,14 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
        push r13
        push r14
        push r15
-       mov [$1], rsp
+       mov [$0], rsp
+    "
+    :: "i"(&RSP_AFTER_SAVING_REGISTERS)
+    :: "volatile", "intel");
 
+    kpti_entry_stack!();
+
+    asm!("
        push r8 /* data seg */
        push rdi /* stack vaddr */
        push rdx /* cpu flags */
@@ -94,27 +199,24 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
        mov rdi, rsp
        call $0
 
-       mov rax, [$2]
-       mov rbx, [$3]
-       mov rcx, [$4]
-       mov rdx, [$5]
-       mov rsi, [$6]
-       mov rdi, [$7]
-       mov r8, [$8]
-       mov r9, [$9]
-       mov r10, [$10]
-       mov r11, [$11]
-       mov r12, [$12]
-       mov r13, [$13]
-       mov r14, [$14]
-       mov r15, [$15]
-       mov rbp, [$16]
-
-       iretq
+       mov rax, [$1]
+       mov rbx, [$2]
+       mov rcx, [$3]
+       mov rdx, [$4]
+       mov rsi, [$5]
+       mov rdi, [$6]
+       mov r8, [$7]
+       mov r9, [$8]
+       mov r10, [$9]
+       mov r11, [$10]
+       mov r12, [$11]
+       mov r13, [$12]
+       mov r14, [$13]
+       mov r15, [$14]
+       mov rbp, [$15]
     "
     ::
          "i"(set_kernel_stack as unsafe extern "C" fn(u64)),
-         "i"(&RSP_AFTER_SAVING_REGISTERS),
 
          "i"(&CUR_REGISTERS.rax),
          "i"(&CUR_REGISTERS.rbx),
@@ -139,6 +241,9 @@ pub unsafe extern "C" fn switch_to_raw_naked(stack_vaddr: u64, code_start: u64,
          "{rsi}"(code_start)
     ::
     "volatile", "intel");
+
+    swapgs_if_user!(8);
+    kpti_return!();
 }
 
 static mut CUR_EXCEPTION_STACK_FRAME: Option<ExceptionStackFrame> = None;
@@ -164,7 +269,6 @@ pub unsafe extern "C" fn store_exception_stack(exception_raw: *const ExceptionSt
     CUR_EXCEPTION_CODE = Some(exception_code);
 }
 
-#[allow(dead_code)]
 pub unsafe extern "C" fn store_error_exception_stack(exception_raw: *const ExceptionStackFrame, error_code: u64, exception_code: u64) {
     let exception = &*exception_raw;
     CUR_EXCEPTION_STACK_FRAME = Some(exception.clone());
@@ -174,11 +278,19 @@ pub unsafe extern "C" fn store_error_exception_stack(exception_raw: *const Excep
 
 macro_rules! return_to_raw_fn {
     ($name: ident, $exception_code: expr) => (
+        return_to_raw_fn!($name, $exception_code, cs_offset: 8);
+    );
+    ($name: ident, $exception_code: expr, cs_offset: $cs_offset: expr) => (
         #[naked]
         #[inline(never)]
+        #[link_section = ".trampoline.text"]
         pub unsafe extern "C" fn $name() {
             use ::arch::interrupt::switch::{RSP_AFTER_SAVING_REGISTERS, CUR_REGISTERS};
 
+            swapgs_if_user!($cs_offset);
+            kpti_enter!();
+            clear_entry_flags!();
+
             asm!("mov [$2], rax
                   mov [$3], rbx
                   mov [$4], rcx
@@ -243,14 +355,21 @@ macro_rules! return_to_raw_fn {
     )
 }
 
-#[allow(unused_macros)]
 macro_rules! return_error_to_raw_fn {
     ($name: ident, $exception_code: expr) => (
+        return_error_to_raw_fn!($name, $exception_code, cs_offset: 16);
+    );
+    ($name: ident, $exception_code: expr, cs_offset: $cs_offset: expr) => (
         #[naked]
         #[inline(never)]
+        #[link_section = ".trampoline.text"]
         pub unsafe extern "C" fn $name() {
             use ::arch::interrupt::switch::{RSP_AFTER_SAVING_REGISTERS, CUR_REGISTERS};
 
+            swapgs_if_user!($cs_offset);
+            kpti_enter!();
+            clear_entry_flags!();
+
             asm!("mov [$2], rax
                   mov [$3], rbx
                   mov [$4], rcx
@@ -316,6 +435,155 @@ macro_rules! return_error_to_raw_fn {
     )
 }
 
+/// Words `return_to_interrupted_fn!` entries push below the interrupt
+/// stack frame: the general purpose registers, and the interrupted CR3
+/// with KPTI.
+#[cfg(feature="kpti")]
+pub const INTERRUPTED_SAVED_WORDS: isize = 16;
+#[cfg(not(feature="kpti"))]
+pub const INTERRUPTED_SAVED_WORDS: isize = 15;
+
+/// Switch to the full page table on entry, saving the interrupted one
+/// on the stack. Expands to nothing without KPTI.
+#[cfg(feature="kpti")]
+macro_rules! interrupted_kpti_enter {
+    () => (
+        asm!("mov rax, cr3
+              push rax
+              mov rax, [$0]
+              mov cr3, rax"
+             :: "i"(&::arch::kpti::KERNEL_CR3)
+             :: "volatile", "intel");
+    )
+}
+
+#[cfg(not(feature="kpti"))]
+macro_rules! interrupted_kpti_enter {
+    () => ()
+}
+
+/// Restore the page table saved by `interrupted_kpti_enter`.
+#[cfg(feature="kpti")]
+macro_rules! interrupted_kpti_exit {
+    () => (
+        asm!("pop rax
+              mov cr3, rax"
+             :::: "volatile", "intel");
+    )
+}
+
+#[cfg(not(feature="kpti"))]
+macro_rules! interrupted_kpti_exit {
+    () => ()
+}
+
+/// Entry point of an exception that can hit the kernel at any point.
+/// It calls `$handler` with the saved registers, which lie below the
+/// interrupt stack frame, and returns to whatever it interrupted
+/// instead of to the scheduler. It runs the handler with the kernel GS
+/// base, and restores the interrupted one. With `user: $user`,
+/// exceptions raised in user mode go to the `$user` entry instead.
+macro_rules! return_to_interrupted_fn {
+    ($name: ident, $handler: expr, user: $user: expr) => (
+        return_to_interrupted_fn!($name, $handler, {
+            asm!("test qword ptr [rsp + 8], 3
+                  jnz $0"
+                 :: "i"($user as unsafe extern "C" fn())
+                 :: "volatile", "intel");
+        });
+    );
+    ($name: ident, $handler: expr) => (
+        return_to_interrupted_fn!($name, $handler, { });
+    );
+    ($name: ident, $handler: expr, $user_check: block) => (
+        #[naked]
+        #[inline(never)]
+        #[link_section = ".trampoline.text"]
+        pub unsafe extern "C" fn $name() {
+            $user_check
+
+            asm!("push rax
+                  push rbx
+                  push rcx
+                  push rdx
+                  push rbp
+                  push rsi
+                  push rdi
+                  push r8
+                  push r9
+                  push r10
+                  push r11
+                  push r12
+                  push r13
+                  push r14
+                  push r15"
+                 :::: "volatile", "intel");
+
+            interrupted_kpti_enter!();
+            clear_entry_flags!();
+
+            // The kernel GS base is the per-CPU data of this CPU, found
+            // by local APIC id. Any other one is the user one, even in
+            // kernel mode, as this may have hit an entry or exit path
+            // before its `swapgs`.
+            asm!("mov eax, 1
+                  cpuid
+                  shr ebx, 24
+                  shl rbx, $1
+                  lea rax, [$2]
+                  add rbx, rax
+                  mov ecx, $0
+                  rdmsr
+                  shl rdx, 32
+                  or rax, rdx
+                  xor r12d, r12d
+                  cmp rax, rbx
+                  je 1f
+                  swapgs
+                  mov r12d, 1
+                  1:"
+                 :: "i"(::arch::percpu::IA32_GS_BASE),
+                    "i"(::arch::percpu::PERCPU_SHIFT),
+                    "i"(&::arch::percpu::PER_CPU)
+                 :: "volatile", "intel");
+
+            asm!("mov rdi, rsp
+                  mov rbx, rsp
+                  and rsp, -16
+                  call $0
+                  mov rsp, rbx"
+                 :: "i"($handler as unsafe extern "C" fn(*const u64))
+                 :: "volatile", "intel");
+
+            asm!("test r12d, r12d
+                  jz 1f
+                  swapgs
+                  1:"
+                 :::: "volatile", "intel");
+
+            interrupted_kpti_exit!();
+
+            asm!("pop r15
+                  pop r14
+                  pop r13
+                  pop r12
+                  pop r11
+                  pop r10
+                  pop r9
+                  pop r8
+                  pop rdi
+                  pop rsi
+                  pop rbp
+                  pop rdx
+                  pop rcx
+                  pop rbx
+                  pop rax
+                  iretq"
+                 :::: "volatile", "intel");
+        }
+    )
+}
+
 pub fn last_exception_return_value() -> Option<ExceptionInfo> {
     unsafe {
         CUR_EXCEPTION_STACK_FRAME.clone().map(|exp| {
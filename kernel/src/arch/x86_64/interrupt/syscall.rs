@@ -0,0 +1,90 @@
+use arch::cpu::{self, IA32_EFER, EFER_SCE};
+use arch::percpu::{PERCPU_USER_STACK_OFFSET, PERCPU_TSS_OFFSET};
+use super::system_call_return_to_raw;
+#[cfg(not(feature="kpti"))]
+use abi::FASTPATH_CALL;
+#[cfg(not(feature="kpti"))]
+use super::fastpath::fastpath_entry;
+
+/// Selectors loaded on `syscall`: kernel code, and kernel data above
+/// it.
+const IA32_STAR: u32 = 0xC0000081;
+/// Entry point of `syscall` in 64-bit mode.
+const IA32_LSTAR: u32 = 0xC0000082;
+/// RFLAGS bits cleared on `syscall`.
+const IA32_FMASK: u32 = 0xC0000084;
+
+/// Kernel code selector, loaded on `syscall`. The kernel stack
+/// selector is the next one.
+const STAR_KERNEL_CS: u64 = 0x08;
+/// Base of the selectors `sysret` loads: 32-bit user code, user data,
+/// then 64-bit user code.
+const STAR_USER_BASE: u64 = 0x18;
+/// RFLAGS cleared on entry: TF, IF, DF, NT and AC, so that the stub
+/// runs with interrupts disabled, as through an interrupt gate.
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 14) | (1 << 18);
+
+/// Offset of RSP0 in the TSS, the stack interrupts from user mode
+/// enter the kernel on. `syscall` enters on it too.
+const TSS_RSP0_OFFSET: u64 = 4;
+
+/// Take a `FASTPATH_CALL` to `fastpath_entry`, once the interrupt
+/// stack frame is built. Expands to nothing with KPTI, whose entry
+/// stack leaves no room for the fastpath, so that the call takes the
+/// general path.
+#[cfg(not(feature="kpti"))]
+macro_rules! fastpath_check {
+    () => (
+        asm!("cmp rax, $0
+              je $1"
+             :: "i"(FASTPATH_CALL), "i"(fastpath_entry as unsafe extern "C" fn())
+             :: "volatile", "intel");
+    )
+}
+
+#[cfg(feature="kpti")]
+macro_rules! fastpath_check {
+    () => ()
+}
+
+/// Entry point of `syscall`. The TSS of the current CPU, found from its
+/// per-CPU data, gives the kernel stack. The stub builds the frame an
+/// `int 0x80` would have pushed, from the user stack pointer, and RIP
+/// and RFLAGS saved in RCX and R11, and then goes on as the interrupt
+/// does, swapping GS back first since the interrupt entry swaps it
+/// again for a frame from user mode. The task returns with `iretq`
+/// like after any interrupt. Fastpath calls leave for their own stub
+/// before that, still on the kernel GS base.
+#[naked]
+#[inline(never)]
+#[link_section = ".trampoline.text"]
+unsafe extern "C" fn syscall_entry() {
+    asm!("swapgs
+          mov gs:[$1], rsp
+          mov rsp, gs:[$2]
+          mov rsp, [rsp + $0]
+          push 0x33
+          push qword ptr gs:[$1]
+          push r11
+          push 0x2b
+          push rcx"
+         :: "i"(TSS_RSP0_OFFSET), "i"(PERCPU_USER_STACK_OFFSET), "i"(PERCPU_TSS_OFFSET)
+         :: "volatile", "intel");
+    fastpath_check!();
+    asm!("swapgs
+          jmp $0"
+         :: "i"(system_call_return_to_raw as unsafe extern "C" fn())
+         :: "volatile", "intel");
+}
+
+/// Enable `syscall` on the current CPU, entering at `syscall_entry`.
+/// Must be called on each CPU, after its per-CPU data is set up.
+pub fn init() {
+    unsafe {
+        cpu::wrmsr(IA32_STAR, (STAR_USER_BASE << 48) | (STAR_KERNEL_CS << 32));
+        cpu::wrmsr(IA32_LSTAR, syscall_entry as u64);
+        cpu::wrmsr(IA32_FMASK, FMASK);
+        cpu::wrmsr(IA32_EFER, cpu::rdmsr(IA32_EFER) | EFER_SCE);
+    }
+    log!("syscall entry at 0x{:x}", syscall_entry as u64);
+}
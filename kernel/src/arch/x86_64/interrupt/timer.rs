@@ -0,0 +1,216 @@
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
+use arch::{cpu, command_line_option};
+use super::{LOCAL_APIC, TIMER_INTERRUPT_CODE};
+use super::{hpet, pit, clocksource};
+
+/// Period of the scheduler tick, in nanoseconds.
+pub const TICK_PERIOD_NS: u64 = 10_000_000;
+
+/// LVT timer mode: count down once.
+const LVT_TIMER_ONE_SHOT: u32 = 0b00 << 17;
+/// LVT timer mode: count down, reloading the initial count.
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+/// LVT timer mode: fire when the TSC reaches `IA32_TSC_DEADLINE`.
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// LVT mask bit.
+const LVT_MASKED: u32 = 1 << 16;
+/// Divide configuration value dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+/// Model-specific register holding the TSC deadline.
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Length of the calibration interval, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// APIC timer counts, at divide by 16, per millisecond.
+static APIC_TICKS_PER_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Whether deadlines are programmed in TSC-deadline mode.
+static TSC_DEADLINE: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether the HPET is used instead of the local APIC timer and TSC.
+static USE_HPET: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether PIT channel 0 is used instead of the local APIC timer.
+static USE_PIT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Mode the local APIC timer is programmed in.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fire once, after a number of bus clock counts.
+    OneShot,
+    /// Fire at a fixed period.
+    Periodic,
+    /// Fire once, when the TSC reaches a deadline.
+    TscDeadline,
+}
+
+/// Measure the APIC timer and TSC frequencies against the PIT, pick
+/// the deadline mode, select the timer given by the `timer=` command
+/// line option, `apic` by default, and then the clocksource. The PIT
+/// is used if the local APIC timer does not count. Must be called
+/// once, on the bootstrap CPU, with interrupts disabled.
+pub fn init() {
+    let tsc_per_ms = calibrate();
+
+    match command_line_option("timer") {
+        None | Some("apic") => (),
+        Some("hpet") => {
+            if hpet::init() {
+                USE_HPET.store(true, Ordering::SeqCst);
+            } else {
+                log!("No usable HPET, using the local APIC timer.");
+            }
+        },
+        Some("pit") => USE_PIT.store(true, Ordering::SeqCst),
+        Some(other) => log!("Unknown timer {}, using the local APIC timer.", other),
+    }
+    if !USE_HPET.load(Ordering::SeqCst) && !USE_PIT.load(Ordering::SeqCst) &&
+        APIC_TICKS_PER_MS.load(Ordering::SeqCst) == 0
+    {
+        log!("Local APIC timer does not count, using the PIT.");
+        USE_PIT.store(true, Ordering::SeqCst);
+    }
+    if USE_PIT.load(Ordering::SeqCst) {
+        pit::init();
+    }
+
+    clocksource::init(tsc_per_ms, USE_HPET.load(Ordering::SeqCst));
+}
+
+/// Set up the local APIC timer of a secondary CPU as `init` left the
+/// bootstrap one, stopped. The frequencies measured there hold on all
+/// CPUs.
+pub fn init_secondary() {
+    let mut apic = LOCAL_APIC.lock();
+    apic.set_timer_divide(TIMER_DIVIDE_BY_16);
+    apic.set_timer(LVT_MASKED | LVT_TIMER_ONE_SHOT | TIMER_INTERRUPT_CODE as u32);
+}
+
+/// Measure the APIC timer and TSC frequencies against the PIT.
+/// Returns TSC ticks per millisecond.
+fn calibrate() -> u64 {
+    let mut apic = LOCAL_APIC.lock();
+    apic.set_timer_divide(TIMER_DIVIDE_BY_16);
+    apic.set_timer(LVT_MASKED | LVT_TIMER_ONE_SHOT | TIMER_INTERRUPT_CODE as u32);
+
+    apic.set_timer_initial_count(u32::max_value());
+    let tsc_start = cpu::rdtsc();
+    unsafe { pit::wait_ms(CALIBRATION_MS); }
+    let tsc_end = cpu::rdtsc();
+    let apic_elapsed = u32::max_value() - apic.timer_current_count();
+    apic.set_timer_initial_count(0);
+
+    APIC_TICKS_PER_MS.store(apic_elapsed as usize / CALIBRATION_MS as usize, Ordering::SeqCst);
+    let tsc_per_ms = (tsc_end - tsc_start) / CALIBRATION_MS;
+    TSC_DEADLINE.store(cpu::has_tsc_deadline(), Ordering::SeqCst);
+
+    log!("APIC timer: {} counts/ms, TSC: {} ticks/ms, TSC-deadline: {}",
+         APIC_TICKS_PER_MS.load(Ordering::SeqCst), tsc_per_ms,
+         TSC_DEADLINE.load(Ordering::SeqCst));
+    tsc_per_ms
+}
+
+/// Mode deadlines set with `set_next_deadline` are programmed in, or
+/// `None` if the HPET or the PIT is used.
+#[allow(dead_code)]
+pub fn deadline_mode() -> Option<TimerMode> {
+    if USE_HPET.load(Ordering::Relaxed) || USE_PIT.load(Ordering::Relaxed) {
+        None
+    } else if TSC_DEADLINE.load(Ordering::Relaxed) {
+        Some(TimerMode::TscDeadline)
+    } else {
+        Some(TimerMode::OneShot)
+    }
+}
+
+/// Convert `ns` nanoseconds to ticks of a clock running at
+/// `per_ms` ticks per millisecond, without overflowing.
+fn ns_to_ticks(ns: u64, per_ms: u64) -> u64 {
+    (ns / 1_000_000) * per_ms + (ns % 1_000_000) * per_ms / 1_000_000
+}
+
+/// Nanoseconds since the timer was initialized, from the clocksource.
+pub fn now_ns() -> u64 {
+    clocksource::now_ns()
+}
+
+/// Fire the timer interrupt every `period_ns` nanoseconds, on the
+/// current CPU.
+pub fn set_periodic(period_ns: u64) {
+    if USE_HPET.load(Ordering::Relaxed) && hpet::set_periodic(period_ns) {
+        return;
+    }
+    if USE_PIT.load(Ordering::Relaxed) {
+        pit::set_periodic(period_ns);
+        return;
+    }
+
+    let count = ns_to_ticks(period_ns, APIC_TICKS_PER_MS.load(Ordering::Relaxed) as u64);
+    let count = ::core::cmp::max(1, ::core::cmp::min(count, u32::max_value() as u64));
+
+    let mut apic = LOCAL_APIC.lock();
+    apic.set_timer_divide(TIMER_DIVIDE_BY_16);
+    apic.set_timer(LVT_TIMER_PERIODIC | TIMER_INTERRUPT_CODE as u32);
+    apic.set_timer_initial_count(count as u32);
+}
+
+/// Fire the timer interrupt once, on the current CPU, when `now_ns`
+/// reaches `deadline_ns`. A deadline in the past fires right away.
+/// This replaces any periodic tick or earlier deadline.
+pub fn set_next_deadline(deadline_ns: u64) {
+    if USE_HPET.load(Ordering::Relaxed) && hpet::set_next_deadline(deadline_ns) {
+        return;
+    }
+
+    let now = now_ns();
+    let delta = if deadline_ns > now { deadline_ns - now } else { 0 };
+    if USE_PIT.load(Ordering::Relaxed) {
+        pit::set_one_shot(delta);
+        return;
+    }
+
+    let mut apic = LOCAL_APIC.lock();
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        let ticks = ns_to_ticks(delta, clocksource::tsc_per_ms());
+        apic.set_timer(LVT_TIMER_TSC_DEADLINE | TIMER_INTERRUPT_CODE as u32);
+        unsafe {
+            // The LVT write must be ordered before the MSR write.
+            asm!("mfence" ::: "memory" : "volatile");
+            cpu::wrmsr(IA32_TSC_DEADLINE, cpu::rdtsc() + ::core::cmp::max(ticks, 1));
+        }
+    } else {
+        let count = ns_to_ticks(delta, APIC_TICKS_PER_MS.load(Ordering::Relaxed) as u64);
+        let count = ::core::cmp::max(1, ::core::cmp::min(count, u32::max_value() as u64));
+        apic.set_timer_divide(TIMER_DIVIDE_BY_16);
+        apic.set_timer(LVT_TIMER_ONE_SHOT | TIMER_INTERRUPT_CODE as u32);
+        apic.set_timer_initial_count(count as u32);
+    }
+}
+
+/// Stop the timer on the current CPU.
+pub fn stop() {
+    if USE_HPET.load(Ordering::Relaxed) {
+        hpet::stop();
+        return;
+    }
+    if USE_PIT.load(Ordering::Relaxed) {
+        pit::stop();
+        return;
+    }
+
+    let mut apic = LOCAL_APIC.lock();
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        unsafe { cpu::wrmsr(IA32_TSC_DEADLINE, 0); }
+    }
+    apic.set_timer_initial_count(0);
+    apic.set_timer(LVT_MASKED | TIMER_INTERRUPT_CODE as u32);
+}
+
+/// Stop the local APIC timer of the current CPU. The HPET and the PIT
+/// are shared by all CPUs, so they are left running.
+pub fn stop_local() {
+    if USE_HPET.load(Ordering::Relaxed) || USE_PIT.load(Ordering::Relaxed) {
+        return;
+    }
+    stop();
+}
@@ -0,0 +1,119 @@
+use util::Mutex;
+use arch::cpu::MAX_CPUS;
+use super::{InterruptVector, TIMER_INTERRUPT_CODE, HPET_INTERRUPT_CODE, PIT_INTERRUPT_CODE,
+            TLB_SHOOTDOWN_INTERRUPT_CODE, RESCHEDULE_INTERRUPT_CODE};
+
+/// Vectors sharing a local APIC priority level.
+const PRIORITY_LEVEL_LENGTH: InterruptVector = 16;
+/// First device vector.
+pub const DEVICE_VECTOR_BASE: InterruptVector = 0x50;
+/// Number of device vectors on each CPU.
+pub const DEVICE_VECTOR_COUNT: usize = 32;
+
+/// Priority class of an interrupt vector. The local APIC prioritizes
+/// vectors by their upper four bits, so each class takes whole
+/// priority levels, and higher classes win over lower ones when
+/// several interrupts are pending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorClass {
+    /// CPU exceptions. Architecturally fixed, never allocated.
+    Exception,
+    /// Device interrupts, from I/O APIC inputs or message-signaled.
+    /// Allocated per CPU as drivers come and go.
+    Device,
+    /// Local APIC timer, HPET and PIT interrupts.
+    Timer,
+    /// Inter-processor interrupts.
+    Ipi,
+}
+
+impl VectorClass {
+    /// First vector of the class, and the vector after its last.
+    pub fn range(self) -> (InterruptVector, InterruptVector) {
+        match self {
+            VectorClass::Exception => (0x00, 0x20),
+            VectorClass::Device => (DEVICE_VECTOR_BASE,
+                                    DEVICE_VECTOR_BASE + DEVICE_VECTOR_COUNT as InterruptVector),
+            VectorClass::Timer => (0xE0, 0xF0),
+            VectorClass::Ipi => (0xF0, 0xFF),
+        }
+    }
+
+    /// Class `vector` falls in, or `None` for vectors outside all
+    /// classes: legacy IRQs, system calls and the spurious vector.
+    pub fn of(vector: InterruptVector) -> Option<VectorClass> {
+        [VectorClass::Exception, VectorClass::Device, VectorClass::Timer, VectorClass::Ipi]
+            .iter().cloned()
+            .find(|class| {
+                let (start, end) = class.range();
+                vector >= start && vector < end
+            })
+    }
+}
+
+/// Bitmap of vectors in use, per CPU.
+static ALLOCATED: Mutex<[[u64; 4]; MAX_CPUS]> = Mutex::new([[0; 4]; MAX_CPUS]);
+
+fn is_set(bitmap: &[u64; 4], vector: InterruptVector) -> bool {
+    bitmap[(vector / 64) as usize] & (1 << (vector % 64)) != 0
+}
+
+fn set(bitmap: &mut [u64; 4], vector: InterruptVector, used: bool) {
+    if used {
+        bitmap[(vector / 64) as usize] |= 1 << (vector % 64);
+    } else {
+        bitmap[(vector / 64) as usize] &= !(1 << (vector % 64));
+    }
+}
+
+/// Reserve the exception vectors, and the timer and IPI vectors that
+/// have handlers of their own, on all CPUs. Must be called once, before
+/// any allocation.
+pub fn init() {
+    let (exceptions_start, exceptions_end) = VectorClass::Exception.range();
+    let fixed = [TIMER_INTERRUPT_CODE, HPET_INTERRUPT_CODE, PIT_INTERRUPT_CODE,
+                 TLB_SHOOTDOWN_INTERRUPT_CODE, RESCHEDULE_INTERRUPT_CODE];
+
+    let mut allocated = ALLOCATED.lock();
+    for bitmap in allocated.iter_mut() {
+        for vector in exceptions_start..exceptions_end {
+            set(bitmap, vector, true);
+        }
+        for vector in fixed.iter() {
+            set(bitmap, *vector, true);
+        }
+    }
+}
+
+/// Allocate a device vector on the CPU with id `cpu`. Allocations go
+/// round the priority levels of the class, so that devices are spread
+/// over them. Returns `None` if all vectors of the class are taken on
+/// that CPU.
+pub fn allocate(cpu: usize) -> Option<InterruptVector> {
+    assert!(cpu < MAX_CPUS);
+    let (start, end) = VectorClass::Device.range();
+
+    let mut allocated = ALLOCATED.lock();
+    let bitmap = &mut allocated[cpu];
+    for offset in 0..PRIORITY_LEVEL_LENGTH {
+        let mut vector = start + offset;
+        while vector < end {
+            if !is_set(bitmap, vector) {
+                set(bitmap, vector, true);
+                return Some(vector);
+            }
+            vector += PRIORITY_LEVEL_LENGTH;
+        }
+    }
+    None
+}
+
+/// Return a vector from `allocate` on the CPU with id `cpu`.
+pub fn free(cpu: usize, vector: InterruptVector) {
+    assert!(cpu < MAX_CPUS);
+    assert_eq!(VectorClass::of(vector), Some(VectorClass::Device));
+
+    let mut allocated = ALLOCATED.lock();
+    assert!(is_set(&allocated[cpu], vector));
+    set(&mut allocated[cpu], vector, false);
+}
@@ -0,0 +1,44 @@
+/// I/O port ranges the kernel drives itself, as first and last port.
+const KERNEL_PORTS: [(u16, u16); 10] = [
+    (0x20, 0x21),   // Master PIC.
+    (0x40, 0x43),   // PIT.
+    (0x60, 0x60),   // Keyboard data.
+    (0x61, 0x61),   // System control port B, PIT gate and NMI status.
+    (0x64, 0x64),   // Keyboard controller status.
+    (0x70, 0x71),   // CMOS and NMI mask.
+    (0x80, 0x80),   // POST port, used by `io_wait`.
+    (0xa0, 0xa1),   // Slave PIC.
+    (0xe9, 0xe9),   // Bochs debug console.
+    (0x3f8, 0x3ff), // Debug serial port.
+];
+
+/// Whether any of the `count` ports from `port` is driven by the
+/// kernel, so that user-space must never access it.
+pub fn is_kernel_port(port: u16, count: u16) -> bool {
+    let last = port as u32 + count as u32 - 1;
+    KERNEL_PORTS.iter().any(|&(first, end)| port <= end && last >= first as u32)
+}
+
+pub unsafe fn outportw(port: u16, val: u16)
+{
+    asm!("outw %ax, %dx" : : "{dx}"(port), "{ax}"(val));
+}
+
+pub unsafe fn inportw(port: u16) -> u16
+{
+    let ret: u16;
+    asm!("inw %dx, %ax" : "={ax}"(ret): "{dx}"(port));
+    ret
+}
+
+pub unsafe fn outportl(port: u16, val: u32)
+{
+    asm!("outl %eax, %dx" : : "{dx}"(port), "{eax}"(val));
+}
+
+pub unsafe fn inportl(port: u16) -> u32
+{
+    let ret: u32;
+    asm!("inl %dx, %eax" : "={eax}"(ret): "{dx}"(port));
+    ret
+}
@@ -0,0 +1,125 @@
+use common::{PAddr, VAddr, MemoryRegion};
+use arch::{KERNEL_BASE};
+use arch::init::{KERNEL_PML4, gdt_region, tss_region, ldt_region};
+use arch::interrupt::idt_region;
+use arch::paging::{PDPT, PD, PT, PML4Entry, PDPTEntry, PDEntry, PTEntry,
+                   PML4_P, PML4_RW, PDPT_P, PDPT_RW, PD_P, PD_RW, PT_P, PT_RW,
+                   pdpt_index, pd_index, pt_index, BASE_PAGE_LENGTH, MemoryObject};
+
+/// Length of the entry stack.
+const ENTRY_STACK_LENGTH: usize = 4096;
+
+extern {
+    /// Start of the trampoline section exposed by linker.
+    static trampoline_start: u8;
+    /// End of the trampoline section exposed by linker.
+    static trampoline_end: u8;
+}
+
+/// Top page table loaded on kernel entry. This is the full page
+/// table of the current address space.
+#[link_section = ".trampoline.data"]
+pub static mut KERNEL_CR3: u64 = 0;
+
+/// Top page table loaded on return to a task. For user tasks, this
+/// is the isolated page table of the current address space.
+#[link_section = ".trampoline.data"]
+pub static mut USER_CR3: u64 = 0;
+
+/// Top of `ENTRY_STACK`.
+#[link_section = ".trampoline.data"]
+pub static mut ENTRY_STACK_TOP: u64 = 0;
+
+/// Stack the processor pushes exception frames on while the isolated
+/// page table is loaded.
+#[link_section = ".trampoline.data"]
+static mut ENTRY_STACK: [u64; ENTRY_STACK_LENGTH / 8] = [0; ENTRY_STACK_LENGTH / 8];
+
+/// PDPT mapped in the kernel half of every isolated page table.
+static mut TRAMPOLINE_PDPT: Option<PAddr> = None;
+
+/// Allocate a zeroed page table page from `region`.
+unsafe fn alloc_table(region: &mut MemoryRegion) -> PAddr {
+    let paddr = region.start_paddr();
+    region.move_up(paddr + BASE_PAGE_LENGTH);
+
+    *MemoryObject::<PT>::new(paddr).as_mut() = [PTEntry::empty(); 512];
+    paddr
+}
+
+/// Map the kernel image pages covering `vaddr` to `vaddr + length`
+/// in the trampoline page directory `pd`, kernel-only.
+unsafe fn map_kernel_range(pd: PAddr, vaddr: VAddr, length: usize, region: &mut MemoryRegion) {
+    let start = vaddr.into(): usize;
+    let mut page = start - start % BASE_PAGE_LENGTH;
+
+    while page < start + length {
+        let page_vaddr = VAddr::from(page);
+        assert!(pdpt_index(page_vaddr) == pdpt_index(VAddr::from(KERNEL_BASE)));
+
+        let pd_entry = { MemoryObject::<PD>::new(pd).as_ref()[pd_index(page_vaddr)] };
+        let pt = if pd_entry.is_present() {
+            pd_entry.get_address()
+        } else {
+            let pt = alloc_table(region);
+            MemoryObject::<PD>::new(pd).as_mut()[pd_index(page_vaddr)] =
+                PDEntry::new(pt, PD_P | PD_RW);
+            pt
+        };
+
+        MemoryObject::<PT>::new(pt).as_mut()[pt_index(page_vaddr)] =
+            PTEntry::new(PAddr::from(page as u64 - KERNEL_BASE), PT_P | PT_RW);
+
+        page += BASE_PAGE_LENGTH;
+    }
+}
+
+/// Build the trampoline page tables. They map the trampoline section
+/// (entry and exit code, the CR3 values and the entry stack), and the
+/// descriptor tables the processor reads on an exception: the IDT,
+/// the GDT and the TSS. Nothing else of the kernel is visible while
+/// a user task runs.
+///
+/// Must be called after the IDT and TSS are set up.
+pub fn init(region: &mut MemoryRegion) {
+    unsafe {
+        ENTRY_STACK_TOP = (&ENTRY_STACK as *const _ as u64) + ENTRY_STACK_LENGTH as u64;
+        KERNEL_CR3 = KERNEL_PML4.paddr().into();
+        USER_CR3 = KERNEL_CR3;
+
+        let pdpt = alloc_table(region);
+        let pd = alloc_table(region);
+        MemoryObject::<PDPT>::new(pdpt).as_mut()[pdpt_index(VAddr::from(KERNEL_BASE))] =
+            PDPTEntry::new(pd, PDPT_P | PDPT_RW);
+
+        let start = &trampoline_start as *const _ as usize;
+        let end = &trampoline_end as *const _ as usize;
+        map_kernel_range(pd, VAddr::from(start), end - start, region);
+
+        for &(vaddr, length) in [gdt_region(), tss_region(), ldt_region(), idt_region()].iter() {
+            map_kernel_range(pd, vaddr, length, region);
+        }
+
+        TRAMPOLINE_PDPT = Some(pdpt);
+        log!("KPTI enabled, trampoline at 0x{:x}.", start);
+    }
+}
+
+/// PML4 entry to put in the kernel half of an isolated page table.
+pub fn trampoline_entry() -> PML4Entry {
+    let pdpt = unsafe { TRAMPOLINE_PDPT.expect("KPTI is not initialized") };
+    PML4Entry::new(pdpt, PML4_P | PML4_RW)
+}
+
+/// Set the page tables of the address space being switched to. The
+/// task runs on `user`, and the kernel switches to `kernel` on entry.
+pub unsafe fn set_top_page_tables(kernel: PAddr, user: PAddr) {
+    KERNEL_CR3 = kernel.into();
+    USER_CR3 = user.into();
+}
+
+/// Keep the full page table loaded when returning to a kernel-mode
+/// task, which runs on kernel code that is not in the trampoline.
+pub unsafe fn set_kernel_mode() {
+    USER_CR3 = KERNEL_CR3;
+}
@@ -0,0 +1,169 @@
+use common::*;
+use util::Mutex;
+use arch::init::{KERNEL_PD_VADDR, KERNEL_STACK_AREA_START_VADDR, KERNEL_STACK_AREA_MAX_PTS};
+use arch::paging::{PT, PD, PTEntry, PDEntry, PT_P, PT_RW, PD_P, PD_RW,
+                   pd_index, pt_index, flush, BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH};
+use frame;
+use meminfo::MemoryCategory;
+
+/// Block order of the frames backing one kernel stack.
+const KERNEL_STACK_ORDER: usize = 2;
+
+/// Pages one stack takes in the area: a guard page, then the stack.
+const SLOT_PAGES: usize = (1 << KERNEL_STACK_ORDER) + 1;
+
+/// Number of stacks mapped by one area PT.
+const SLOTS_PER_PT: usize = 512 / SLOT_PAGES;
+
+/// Header written at the bottom of every free stack, linking it into
+/// the free list.
+struct FreeStack {
+    next: Option<usize>,
+}
+
+/// Kernel stack area PTs, and the stacks mapped in it. Stacks are
+/// never unmapped; freed ones are kept on a free list, and reused
+/// before mapping new ones.
+struct KernelStackArea {
+    pts: [Option<PAddr>; KERNEL_STACK_AREA_MAX_PTS],
+    /// Number of slots mapped so far.
+    mapped: usize,
+    /// First free slot.
+    free: Option<usize>,
+}
+
+static AREA: Mutex<KernelStackArea> = Mutex::new(KernelStackArea {
+    pts: [None; KERNEL_STACK_AREA_MAX_PTS],
+    mapped: 0,
+    free: None,
+});
+
+/// Virtual address of the guard page of `slot`.
+fn slot_vaddr(slot: usize) -> VAddr {
+    KERNEL_STACK_AREA_START_VADDR + (slot / SLOTS_PER_PT) * LARGE_PAGE_LENGTH +
+        (slot % SLOTS_PER_PT) * SLOT_PAGES * BASE_PAGE_LENGTH
+}
+
+impl KernelStackArea {
+    /// PT of the area covering `slot`, allocating it from the frame
+    /// allocator if needed.
+    fn pt(&mut self, slot: usize) -> Option<PAddr> {
+        let index = slot / SLOTS_PER_PT;
+        if index >= KERNEL_STACK_AREA_MAX_PTS {
+            return None;
+        }
+        if let Some(pt) = self.pts[index] {
+            return Some(pt);
+        }
+
+        let pt = match frame::allocate(0, MemoryCategory::PageTable) {
+            Some(pt) => pt,
+            None => return None,
+        };
+        unsafe {
+            *(frame::to_vaddr(pt).into(): usize as *mut PT) = [PTEntry::empty(); 512];
+        }
+
+        // The kernel PD is shared by all address spaces, so the stacks
+        // are mapped everywhere.
+        unsafe {
+            let kernel_pd = &mut *(KERNEL_PD_VADDR.into(): usize as *mut PD);
+            kernel_pd[pd_index(slot_vaddr(slot))] = PDEntry::new(pt, PD_P | PD_RW);
+        }
+        self.pts[index] = Some(pt);
+        Some(pt)
+    }
+
+    /// Map a new stack in the next unused slot, leaving its guard
+    /// page unmapped. Returns `None` if the area is full, or frames
+    /// run out.
+    fn map(&mut self) -> Option<usize> {
+        let slot = self.mapped;
+        let pt = match self.pt(slot) {
+            Some(pt) => pt,
+            None => return None,
+        };
+        let frames = match frame::allocate(KERNEL_STACK_ORDER, MemoryCategory::KernelStack) {
+            Some(frames) => frames,
+            None => return None,
+        };
+
+        let entries = unsafe { &mut *(frame::to_vaddr(pt).into(): usize as *mut PT) };
+        for i in 0..(1 << KERNEL_STACK_ORDER) {
+            let vaddr = slot_vaddr(slot) + (i + 1) * BASE_PAGE_LENGTH;
+            entries[pt_index(vaddr)] = PTEntry::new(frames + i * BASE_PAGE_LENGTH, PT_P | PT_RW);
+            unsafe { flush(vaddr); }
+        }
+
+        self.mapped += 1;
+        Some(slot)
+    }
+}
+
+/// A kernel stack, owned by a task, that the processor switches to
+/// when the task enters the kernel. It is backed by frames from the
+/// frame allocator, and mapped right above a guard page, so that an
+/// overflow faults instead of corrupting a neighbouring stack.
+/// Dropping it puts it on a free list, still mapped.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Allocate a kernel stack, reusing a freed one if there is any.
+    /// Returns `None` if the stack area is full, or frames run out.
+    /// With KPTI, tasks enter the kernel on the per-CPU entry stack,
+    /// the only one mapped in user page tables, so this always
+    /// returns `None`.
+    pub fn allocate() -> Option<KernelStack> {
+        if cfg!(feature="kpti") {
+            return None;
+        }
+
+        let mut area = AREA.lock();
+        let slot = match area.free {
+            Some(slot) => {
+                area.free = unsafe { Self::header(slot).next };
+                slot
+            },
+            None => match area.map() {
+                Some(slot) => slot,
+                None => return None,
+            },
+        };
+
+        Some(KernelStack {
+            slot: slot,
+        })
+    }
+
+    /// Address right above the stack, to be loaded in the TSS.
+    pub fn top(&self) -> VAddr {
+        slot_vaddr(self.slot) + SLOT_PAGES * BASE_PAGE_LENGTH
+    }
+
+    unsafe fn header<'a>(slot: usize) -> &'a mut FreeStack {
+        &mut *((slot_vaddr(slot) + BASE_PAGE_LENGTH).into(): usize as *mut FreeStack)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut area = AREA.lock();
+        unsafe { Self::header(self.slot).next = area.free; }
+        area.free = Some(self.slot);
+    }
+}
+
+/// Whether `vaddr` is in the guard page of a kernel stack.
+pub fn is_guard_page(vaddr: VAddr) -> bool {
+    let start = KERNEL_STACK_AREA_START_VADDR.into(): usize;
+    let address = vaddr.into(): usize;
+    if address < start || address >= start + KERNEL_STACK_AREA_MAX_PTS * LARGE_PAGE_LENGTH {
+        return false;
+    }
+
+    let page = (address - start) % LARGE_PAGE_LENGTH / BASE_PAGE_LENGTH;
+    page < SLOTS_PER_PT * SLOT_PAGES && page % SLOT_PAGES == 0
+}
@@ -17,6 +17,49 @@ mod interrupt;
 /// Segment descriptor and task state segment representation.
 mod segmentation;
 
+/// CPU identification and online CPU bookkeeping.
+mod cpu;
+
+/// Per-CPU data reached through GS.
+mod percpu;
+
+/// Debug registers and hardware breakpoints.
+mod debugreg;
+
+/// x87, SSE and AVX state of tasks.
+mod fpu;
+
+/// Cache maintenance instructions.
+mod cache;
+
+/// SMEP/SMAP and guarded access to user memory.
+mod user;
+
+/// Kernel page-table isolation.
+#[cfg(feature="kpti")]
+mod kpti;
+
+/// Stack walking using frame pointers.
+mod backtrace;
+
+/// Kernel stacks of tasks, with guard pages.
+mod kstack;
+
+/// Idle loop, with `hlt` or `mwait`.
+mod idle;
+
+/// Bulk zeroing of memory.
+mod zero;
+
+/// ACPI table discovery.
+mod acpi;
+
+/// CMOS real-time clock driver.
+mod rtc;
+
+/// Word and double word port I/O, and ports kept by the kernel.
+mod ioport;
+
 /// Architecture-specific capabilities. Re-exported also in `kernel::cap`.
 #[macro_use]
 pub mod cap;
@@ -38,7 +81,6 @@ fn kernel_end_paddr() -> PAddr {
     unsafe { PAddr::from((&kernel_end as *const _) as u64 - KERNEL_BASE) }
 }
 
-#[allow(dead_code)]
 fn kernel_end_vaddr() -> VAddr {
     unsafe { kernel_paddr_to_vaddr(kernel_end_paddr()) }
 }
@@ -67,15 +109,63 @@ pub unsafe fn io_wait() {
     outportb(0x80, 0)
 }
 
+/// Calibrate the local APIC timer, and select the timer and the
+/// clocksource from the command line. The timer stays off until a
+/// deadline is set.
 pub fn enable_timer() {
-    interrupt::LOCAL_APIC.lock().enable_timer();
+    interrupt::timer::init();
+    idle::init();
 }
 
 // Public interfaces
-pub use self::paging::{MemoryObject};
-pub use self::interrupt::{enable_interrupt, disable_interrupt, set_interrupt_handler,
-                          Exception, TaskRuntime};
-pub use self::init::{InitInfo};
+pub use self::paging::{MemoryObject, ObjectPoolStats, object_pool_stats,
+                       map_device, is_kernel_device, VolatileMmio};
+pub use self::interrupt::{enable_interrupt, disable_interrupt, set_interrupt_handler, interrupt_pending,
+                          Exception, TaskRuntime, RuntimeEntry, InterruptVector,
+                          MsiMessage, allocate_msi, free_msi, move_msi, route_irq, set_irq_masked,
+                          set_irq_affinity, send_reschedule,
+                          irq_stats, Clocksource, current_clocksource, tsc_sync_source, tsc_sync_target,
+                          poll_machine_checks, set_fastpath_handler, FastpathHandler};
+pub use self::interrupt::timer::{set_next_deadline, set_periodic, stop as stop_timer, now_ns, TICK_PERIOD_NS};
+pub use self::init::{InitInfo, FRAME_WINDOW_LENGTH, map_frame_window,
+                     command_line, command_line_option, load_ldt, start_secondary_cpus};
+pub use self::cpu::{MAX_CPUS, current_id as current_cpu_id, current_id_lockless as current_cpu_id_lockless,
+                    is_online as is_cpu_online, is_bootstrap as is_bootstrap_cpu,
+                    park as park_cpu, unpark as unpark_cpu, is_unparking as is_cpu_unparking,
+                    finish_unpark as finish_unpark_cpu,
+                    stop_scheduling as stop_cpu_scheduling, rdtsc};
+pub use self::cache::{clean_range, store_fence};
+pub use self::user::USER_END;
+pub use self::backtrace::backtrace;
+pub use self::kstack::KernelStack;
+pub use self::idle::{idle, interrupt_window, park};
+pub use self::fpu::{FpuState, FPU_STATE_ALIGNMENT, state_length as fpu_state_length};
+pub use self::zero::{zero_range, zero_range_non_temporal};
+pub use self::rtc::unix_seconds as rtc_unix_seconds;
+pub use self::ioport::{is_kernel_port, inportw, outportw, inportl, outportl};
+
+/// Bitmap of architecture-specific kernel features, using the
+/// `abi::FEATURE_*` flags.
+pub fn features() -> u64 {
+    use abi::{FEATURE_SMP, FEATURE_VMX, FEATURE_PCID};
+
+    let mut features = 0;
+    if cpu::online_count() > 1 {
+        features |= FEATURE_SMP;
+    }
+    if cpu::has_vmx() {
+        features |= FEATURE_VMX;
+    }
+    if paging::pcid_enabled() {
+        features |= FEATURE_PCID;
+    }
+    features
+}
+
+/// Page sizes supported for mappings. See `abi::KernelInfo`.
+pub fn page_sizes() -> u64 {
+    cpu::page_sizes()
+}
 // pub use self::cap::{ArchCap, PageHalf, PageFull};
 pub use self::addr::{PAddr, VAddr};
 
@@ -0,0 +1,102 @@
+use core::mem::size_of;
+use core::intrinsics::{volatile_load, volatile_store};
+use common::{PAddr, VAddr};
+use util::{align_down, block_count};
+use arch::init::{MMIO_PT, MMIO_WINDOW_START_VADDR};
+use super::{PTEntry, PT_P, PT_RW, PT_PWT, PT_PCD, flush, BASE_PAGE_LENGTH};
+
+/// Handle to device memory mapped with `map_device`. All accesses are
+/// volatile and bounds-checked.
+#[derive(Debug)]
+pub struct VolatileMmio {
+    vaddr: VAddr,
+    length: usize,
+}
+
+impl VolatileMmio {
+    /// A handle mapping nothing, used before a device is mapped.
+    pub const fn empty() -> VolatileMmio {
+        VolatileMmio {
+            vaddr: VAddr::new(0),
+            length: 0,
+        }
+    }
+
+    /// Virtual address of the device memory.
+    pub fn vaddr(&self) -> VAddr {
+        self.vaddr
+    }
+
+    /// Length of the device memory.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Read a value at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// Reading a device register may have side effects.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.length);
+        volatile_load((self.vaddr.into(): usize + offset) as *const T)
+    }
+
+    /// Write `value` at `offset`. This takes `&self`, as device
+    /// registers are often written to select what a read returns.
+    ///
+    /// # Safety
+    ///
+    /// Writing a device register may have side effects.
+    pub unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.length);
+        volatile_store((self.vaddr.into(): usize + offset) as *mut T, value);
+    }
+}
+
+/// Whether the page at `paddr` is device memory the kernel mapped
+/// with `map_device` for its own use.
+pub fn is_kernel_device(paddr: PAddr) -> bool {
+    let aligned = align_down(paddr, BASE_PAGE_LENGTH);
+    let mmio_pt = MMIO_PT.lock();
+
+    (0..mmio_pt.len()).any(|i| mmio_pt[i].is_present() && mmio_pt[i].get_address() == aligned)
+}
+
+/// Map `size` bytes of device memory at `paddr` into the MMIO window,
+/// uncached. Device mappings are permanent. Panics if the window is
+/// full.
+///
+/// # Safety
+///
+/// `paddr` must be device memory, not memory used by anything else.
+pub unsafe fn map_device(paddr: PAddr, size: usize) -> VolatileMmio {
+    let aligned = align_down(paddr, BASE_PAGE_LENGTH);
+    let before_start = paddr.into(): usize - aligned.into(): usize;
+    let required_page_size = block_count(before_start + size, BASE_PAGE_LENGTH);
+
+    let mut mmio_pt = MMIO_PT.lock();
+
+    let mut index = 0;
+    while index + required_page_size <= mmio_pt.len() {
+        match (0..required_page_size).position(|j| mmio_pt[index + j].is_present()) {
+            Some(used) => index += used + 1,
+            None => break,
+        }
+    }
+    assert!(index + required_page_size <= mmio_pt.len(), "MMIO window is full");
+
+    let vaddr = MMIO_WINDOW_START_VADDR + index * BASE_PAGE_LENGTH;
+    for i in 0..required_page_size {
+        mmio_pt[index + i] = PTEntry::new(aligned + i * BASE_PAGE_LENGTH,
+                                          PT_P | PT_RW | PT_PWT | PT_PCD);
+        flush(vaddr + i * BASE_PAGE_LENGTH);
+    }
+
+    log!("Device memory 0x{:x} mapped at 0x{:x}.", paddr, vaddr + before_start);
+
+    VolatileMmio {
+        vaddr: vaddr + before_start,
+        length: size,
+    }
+}
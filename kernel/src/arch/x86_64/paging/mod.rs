@@ -9,6 +9,18 @@ mod table;
 /// Memory objects implementation.
 mod with;
 
+/// Cross-CPU TLB invalidation.
+mod shootdown;
+
+/// Process-context identifiers and ASID allocation.
+mod pcid;
+
+/// Software page table walks.
+mod walk;
+
+/// Device memory mapping.
+mod mmio;
+
 /// Basic page length in x86_64 (4 KiB).
 pub const BASE_PAGE_LENGTH: usize = 4096; // 4 KiB
 
@@ -16,11 +28,9 @@ pub const BASE_PAGE_LENGTH: usize = 4096; // 4 KiB
 pub const LARGE_PAGE_LENGTH: usize = 1024 * 1024 * 2; // 2 MiB
 
 /// Huge page length in x86_64 (1 GiB).
-#[allow(dead_code)]
 pub const HUGE_PAGE_LENGTH: usize = 1024 * 1024 * 1024; // 1 GiB
 
 /// Cache line length in x86_64 (64 Bytes).
-#[allow(dead_code)]
 pub const CACHE_LINE_LENGTH: usize = 64; // 64 Bytes
 
 /// MAXPHYADDR, which is at most 52; (use CPUID for finding system value).
@@ -30,7 +40,12 @@ pub const MAXPHYADDR: u64 = 52;
 const ADDRESS_MASK: u64 = ((1 << MAXPHYADDR) - 1) & !0xfff;
 
 pub use self::table::*;
-pub use self::with::{MemoryObject};
+pub use self::with::{MemoryObject, ObjectPoolStats, object_pool_stats};
+pub use self::shootdown::{flush_range, flush_range_all_cpus, handle_shootdown};
+pub use self::pcid::{Asid, ASID_POOL_SIZE, ASID_POOL_COUNT, switch_to_asid, pcid_enabled,
+                      allocate_pool, free_pool};
+pub use self::walk::{log_walk, lookup, lookup_in};
+pub use self::mmio::{map_device, is_kernel_device, VolatileMmio};
 
 /// Contains page-table root pointer.
 unsafe fn cr3() -> u64 {
@@ -44,6 +59,13 @@ unsafe fn cr3_write(val: u64) {
     asm!("mov $0, %cr3" :: "r" (val) : "memory");
 }
 
+/// Linear address that caused the last page fault.
+pub unsafe fn cr2() -> VAddr {
+    let ret: u64;
+    asm!("mov %cr2, $0" : "=r" (ret));
+    VAddr::from(ret)
+}
+
 /// Invalidate the given address in the TLB using the `invlpg` instruction.
 ///
 /// # Safety
@@ -54,17 +76,27 @@ pub unsafe fn flush(vaddr: VAddr) {
     asm!("invlpg ($0)" :: "r" (vaddr.into(): usize) : "memory");
 }
 
-/// Invalidate the TLB completely by reloading the CR3 register.
+/// Invalidate the TLB completely. If global pages are enabled, this
+/// toggles `CR4.PGE`, which also drops global entries and entries of
+/// all PCIDs. Otherwise the CR3 register is reloaded.
 ///
 /// # Safety
 ///
 /// This function is unsafe as it causes a general protection fault (GP) if the current privilege
 /// level is not 0.
 pub unsafe fn flush_all() {
-    cr3_write(cr3())
+    use arch::cpu::{cr4, cr4_write, CR4_PGE};
+
+    let value = cr4();
+    if value & CR4_PGE != 0 {
+        cr4_write(value & !CR4_PGE);
+        cr4_write(value);
+    } else {
+        cr3_write(cr3())
+    }
 }
 
-/// Switch to a PML4 page table.
+/// Switch to a PML4 page table, without a PCID.
 ///
 /// # Safety
 ///
@@ -73,3 +105,24 @@ pub unsafe fn flush_all() {
 pub unsafe fn switch_to(paddr: PAddr) {
     cr3_write(paddr.into());
 }
+
+/// Switch to the kernel page table if the PML4 at `paddr` is loaded,
+/// so that its memory can be reused. Only the current CPU is checked,
+/// as it is the only one running tasks.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn switch_away_from(paddr: PAddr) {
+    use arch::init::KERNEL_PML4;
+
+    if cr3() & ADDRESS_MASK == paddr.into(): u64 {
+        switch_to(KERNEL_PML4.paddr());
+    }
+}
+
+/// Enable PCIDs if supported. Called on each CPU, once on the kernel
+/// page table.
+pub unsafe fn init_pcid() {
+    pcid::init()
+}
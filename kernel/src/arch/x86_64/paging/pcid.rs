@@ -0,0 +1,215 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use common::PAddr;
+use util::Mutex;
+use arch::cpu::{self, MAX_CPUS, CR4_PGE, CR4_PCIDE, CPUID_01_ECX_PCID};
+use super::{cr3_write, flush_all};
+
+/// Number of process-context identifiers. PCID 0 is used by the
+/// kernel, and is never handed out to an address space.
+const PCID_COUNT: usize = 4096;
+
+/// First PCID handed out through ASID pools. PCIDs below it are
+/// recycled by the kernel for top page tables outside of VSpaces.
+const POOL_PCID_FIRST: usize = 2048;
+
+/// Number of ASIDs in an ASID pool.
+pub const ASID_POOL_SIZE: usize = 256;
+
+/// Number of ASID pools the PCIDs from `POOL_PCID_FIRST` divide into.
+pub const ASID_POOL_COUNT: usize = (PCID_COUNT - POOL_PCID_FIRST) / ASID_POOL_SIZE;
+
+/// CR3 bit that asks the processor to keep TLB entries of the loaded
+/// PCID.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/// Whether PCIDs are in use.
+static PCID_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Generation of ASIDs assigned from an ASID pool, which never become
+/// invalid.
+const PINNED_GENERATION: usize = 0;
+
+/// An address space identifier. It is valid as long as its generation
+/// is the allocator's current generation, or forever if it was
+/// assigned from an ASID pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Asid {
+    pcid: u16,
+    generation: usize,
+}
+
+impl Asid {
+    /// The ASID at `index` of ASID pool `pool`. Every CPU flushes its
+    /// PCID before first using it, as it may still hold entries from
+    /// an address space it was assigned to before.
+    pub fn pinned(pool: usize, index: usize) -> Asid {
+        assert!(pool < ASID_POOL_COUNT && index < ASID_POOL_SIZE);
+        let asid = Asid {
+            pcid: (POOL_PCID_FIRST + pool * ASID_POOL_SIZE + index) as u16,
+            generation: PINNED_GENERATION,
+        };
+        asid.invalidate();
+        asid
+    }
+
+    /// The PCID loaded into CR3 for this ASID.
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    /// Whether this ASID was assigned from an ASID pool.
+    pub fn is_pinned(&self) -> bool {
+        self.generation == PINNED_GENERATION
+    }
+
+    /// Make every CPU flush the PCID of this pinned ASID the next time
+    /// it switches to it.
+    pub fn invalidate(&self) {
+        assert!(self.is_pinned());
+        let index = self.pcid as usize - POOL_PCID_FIRST;
+        PINNED_STALE.lock()[index] = (1 << MAX_CPUS) - 1;
+    }
+
+    /// Whether the current CPU must flush the PCID of this pinned ASID
+    /// before using it. Clears the request for the current CPU.
+    fn take_stale(&self) -> bool {
+        let index = self.pcid as usize - POOL_PCID_FIRST;
+        let bit = 1 << cpu::current_id();
+        let mut stale = PINNED_STALE.lock();
+        let was_stale = stale[index] & bit != 0;
+        stale[index] &= !bit;
+        was_stale
+    }
+}
+
+/// ASID allocator. PCIDs below `POOL_PCID_FIRST` are handed out
+/// linearly. When they run out, the generation is bumped, which
+/// invalidates all ASIDs handed out so far.
+struct AsidAllocator {
+    next: usize,
+    generation: usize,
+}
+
+impl AsidAllocator {
+    fn allocate(&mut self) -> Asid {
+        if self.next == POOL_PCID_FIRST {
+            self.generation += 1;
+            self.next = 1;
+        }
+
+        let asid = Asid { pcid: self.next as u16, generation: self.generation };
+        self.next += 1;
+        asid
+    }
+}
+
+static ALLOCATOR: Mutex<AsidAllocator> =
+    Mutex::new(AsidAllocator { next: 1, generation: 1 });
+
+/// The last ASID generation each CPU has flushed its TLB for. A CPU
+/// must flush all PCIDs before using an ASID of a newer generation,
+/// because the PCID may still have entries from a recycled ASID.
+static FLUSHED_GENERATIONS: Mutex<[usize; MAX_CPUS]> = Mutex::new([1; MAX_CPUS]);
+
+/// For each pinned PCID, the CPUs that must flush it before using it,
+/// one bit each.
+static PINNED_STALE: Mutex<[u32; PCID_COUNT - POOL_PCID_FIRST]> =
+    Mutex::new([0; PCID_COUNT - POOL_PCID_FIRST]);
+
+/// Which ASID pools are handed out.
+static POOLS: Mutex<[bool; ASID_POOL_COUNT]> = Mutex::new([false; ASID_POOL_COUNT]);
+
+/// Hand out a free ASID pool. Returns its index, or `None` if all of
+/// them are handed out.
+pub fn allocate_pool() -> Option<usize> {
+    let mut pools = POOLS.lock();
+    let index = pools.iter().position(|used| !used);
+    if let Some(index) = index {
+        pools[index] = true;
+    }
+    index
+}
+
+/// Return the ASID pool at `index`, so that it can be handed out
+/// again.
+pub fn free_pool(index: usize) {
+    let mut pools = POOLS.lock();
+    assert!(pools[index]);
+    pools[index] = false;
+}
+
+/// Enable PCIDs if the processor supports them. Global pages are
+/// enabled together with PCIDs, so that `invlpg` on kernel mappings
+/// still reaches TLB entries tagged with other PCIDs.
+///
+/// # Safety
+///
+/// The low 12 bits of CR3 must be zero.
+pub unsafe fn init() {
+    if cfg!(feature="kpti") {
+        // Both page tables of an address space would need their own
+        // PCID. Global pages would also leak kernel mappings into
+        // user mode.
+        log!("PCID not used with KPTI.");
+        return;
+    }
+
+    let (_, _, ecx, _) = cpu::cpuid(0x1, 0);
+    if ecx & CPUID_01_ECX_PCID == 0 {
+        log!("PCID not supported.");
+        return;
+    }
+
+    cpu::cr4_write(cpu::cr4() | CR4_PGE | CR4_PCIDE);
+    PCID_ENABLED.store(true, Ordering::SeqCst);
+    log!("PCID enabled.");
+}
+
+/// Whether PCIDs are in use.
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Switch to a PML4 page table tagged with `asid`. A new ASID is
+/// allocated if `asid` is `None` or belongs to an old generation. The
+/// ASID now in use is returned, or `asid` itself if PCIDs are
+/// disabled.
+///
+/// # Safety
+///
+/// The PML4 page table must have kernel mapped in
+/// `KERNEL_BASE`. `paddr` must point to a valid PML4 page table.
+pub unsafe fn switch_to_asid(paddr: PAddr, asid: Option<Asid>) -> Option<Asid> {
+    if !pcid_enabled() {
+        super::switch_to(paddr);
+        return asid;
+    }
+
+    let (asid, generation) = {
+        let mut allocator = ALLOCATOR.lock();
+        let asid = match asid {
+            Some(asid) if asid.is_pinned() || asid.generation == allocator.generation => asid,
+            _ => allocator.allocate(),
+        };
+        (asid, allocator.generation)
+    };
+
+    let stale = {
+        let id = cpu::current_id();
+        let mut flushed = FLUSHED_GENERATIONS.lock();
+        let stale = flushed[id] != generation;
+        flushed[id] = generation;
+        stale
+    };
+    let stale = (asid.is_pinned() && asid.take_stale()) || stale;
+
+    let value = paddr.into(): u64 | asid.pcid() as u64;
+    if stale {
+        cr3_write(value);
+        flush_all();
+    } else {
+        cr3_write(value | CR3_NOFLUSH);
+    }
+
+    Some(asid)
+}
@@ -0,0 +1,146 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use common::VAddr;
+use util::Mutex;
+use arch::cpu::{self, MAX_CPUS};
+use arch::interrupt::{LOCAL_APIC, IpiMode, TLB_SHOOTDOWN_INTERRUPT_CODE};
+use super::{flush, flush_all, BASE_PAGE_LENGTH};
+
+/// Number of pending requests a CPU queue can hold. When a queue
+/// overflows, the target CPU falls back to a full TLB flush.
+const QUEUE_LENGTH: usize = 16;
+
+/// Ranges larger than this number of pages are flushed by reloading
+/// CR3 instead of page by page.
+const FULL_FLUSH_THRESHOLD: usize = 32;
+
+/// A single range invalidation request.
+#[derive(Debug, Clone, Copy)]
+struct ShootdownRequest {
+    start: VAddr,
+    length: usize,
+}
+
+/// Shootdown requests pending for one CPU.
+#[derive(Debug, Clone, Copy)]
+struct ShootdownQueue {
+    requests: [Option<ShootdownRequest>; QUEUE_LENGTH],
+    overflowed: bool,
+    /// Number of requests pushed, including those lost to overflow.
+    pushed: usize,
+}
+
+impl ShootdownQueue {
+    const fn empty() -> ShootdownQueue {
+        ShootdownQueue {
+            requests: [None; QUEUE_LENGTH],
+            overflowed: false,
+            pushed: 0,
+        }
+    }
+
+    fn push(&mut self, request: ShootdownRequest) {
+        self.pushed += 1;
+        match self.requests.iter().position(|r| r.is_none()) {
+            Some(i) => self.requests[i] = Some(request),
+            None => self.overflowed = true,
+        }
+    }
+}
+
+/// Per-CPU shootdown request queues, indexed by CPU id.
+static QUEUES: Mutex<[ShootdownQueue; MAX_CPUS]> =
+    Mutex::new([ShootdownQueue::empty(); MAX_CPUS]);
+
+/// Number of requests queued to other CPUs but not yet acknowledged.
+static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Invalidate a virtual address range on the current CPU only.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn flush_range(start: VAddr, length: usize) {
+    let pages = length / BASE_PAGE_LENGTH +
+        if length % BASE_PAGE_LENGTH == 0 { 0 } else { 1 };
+
+    if pages > FULL_FLUSH_THRESHOLD {
+        flush_all();
+    } else {
+        for i in 0..pages {
+            flush(start + i * BASE_PAGE_LENGTH);
+        }
+    }
+}
+
+/// Invalidate a virtual address range on all CPUs that may be running
+/// tasks, including those taken offline that have yet to hand their
+/// tasks over. The
+/// current CPU is flushed directly, other CPUs are sent a shootdown
+/// IPI, and this function returns only after all of them have
+/// acknowledged.
+///
+/// Every path that removes a mapping or reduces its permissions must
+/// call this instead of `flush`.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn flush_range_all_cpus(start: VAddr, length: usize) {
+    flush_range(start, length);
+
+    if cpu::scheduling_count() <= 1 {
+        return;
+    }
+
+    let current = cpu::current_id();
+    let request = ShootdownRequest { start: start, length: length };
+    let mut targets = 0;
+
+    {
+        let mut queues = QUEUES.lock();
+        for id in 0..MAX_CPUS {
+            if id != current && cpu::is_scheduling(id) {
+                queues[id].push(request);
+                targets += 1;
+            }
+        }
+        PENDING.fetch_add(targets, Ordering::SeqCst);
+    }
+
+    LOCAL_APIC.lock().broadcast_ipi(IpiMode::Fixed(TLB_SHOOTDOWN_INTERRUPT_CODE));
+
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        // Serve requests targeting us while we wait, so that two CPUs
+        // shooting down at the same time cannot deadlock.
+        handle_shootdown();
+    }
+}
+
+/// Drain the current CPU's shootdown queue. Called when the shootdown
+/// IPI is received.
+pub fn handle_shootdown() {
+    let id = cpu::current_id();
+
+    let queue = {
+        let mut queues = QUEUES.lock();
+        let queue = queues[id];
+        queues[id] = ShootdownQueue::empty();
+        queue
+    };
+
+    unsafe {
+        if queue.overflowed {
+            flush_all();
+        } else {
+            for request in queue.requests.iter() {
+                if let &Some(request) = request {
+                    flush_range(request.start, request.length);
+                }
+            }
+        }
+    }
+
+    if queue.pushed != 0 {
+        PENDING.fetch_sub(queue.pushed, Ordering::SeqCst);
+    }
+}
@@ -1,5 +1,6 @@
 use common::{PAddr, VAddr};
 use super::{BASE_PAGE_LENGTH, ADDRESS_MASK};
+use core::sync::atomic::AtomicUsize;
 
 /// A PML4 table.
 /// In practice this has only 4 entries but it still needs to be the size of a 4K page.
@@ -260,6 +261,18 @@ bitflags! {
         const PT_D       = bit!(6),
         /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
         const PT_G       = bit!(8),
+        /// Available to software; marks a read-only copy-on-write mapping.
+        const PT_COW     = bit!(9),
+        /// Available to software; with PT_P clear, marks a page reserved to be filled with zeros on first touch.
+        const PT_ZERO    = bit!(10),
+        /// Available to software; with PT_P clear, marks a guard page below a stack.
+        const PT_GUARD   = bit!(11),
+        /// Available to software; marks a frame owned by the address space mapping it.
+        const PT_OWNED   = bit!(52),
+        /// Available to software; marks a writable mapping write-protected for soft-dirty tracking.
+        const PT_TRACKED = bit!(53),
+        /// Available to software; marks a frame of a shared frame set, only unmapped through the set.
+        const PT_SHARED  = bit!(54),
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const PT_XD      = bit!(63),
@@ -284,6 +297,12 @@ impl PTEntry {
         PAddr::from(self.bits & ADDRESS_MASK)
     }
 
+    /// View this entry as an atomic, to update it while the processor
+    /// may set its accessed and dirty bits.
+    pub fn as_atomic(&mut self) -> &AtomicUsize {
+        unsafe { &*(self as *mut PTEntry as *const AtomicUsize) }
+    }
+
     check_flag!(doc = "Present; must be 1 to map a 4-KByte page or reference a page table.",
                 is_present, PT_P);
     check_flag!(doc = "Read/write; if 0, writes may not be allowed to the 4-KByte region controlled by this entry",
@@ -300,6 +319,18 @@ impl PTEntry {
                 is_dirty, PT_D);
     check_flag!(doc = "Global; if PT_PS && CR4.PGE = 1, determines whether the translation is global; ignored otherwise if not PT_PS this is ignored.",
                 is_global, PT_G);
+    check_flag!(doc = "Copy-on-write; the 4-KByte page is shared read-only and copied on the first write.",
+                is_cow, PT_COW);
+    check_flag!(doc = "Demand-zero; the 4-KByte page is not present yet and is filled with a zeroed frame on first touch.",
+                is_demand_zero, PT_ZERO);
+    check_flag!(doc = "Guard; the 4-KByte page is never mapped, and accessing it means a stack overflow.",
+                is_guard, PT_GUARD);
+    check_flag!(doc = "Owned; the 4-KByte page was allocated by the address space, and is freed when unmapped.",
+                is_owned, PT_OWNED);
+    check_flag!(doc = "Tracked; the 4-KByte page is writable, but write-protected until its next write is logged.",
+                is_tracked, PT_TRACKED);
+    check_flag!(doc = "Shared; the 4-KByte page belongs to a shared frame set, which keeps track of where it is mapped.",
+                is_shared, PT_SHARED);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 4-KByte region.",
                 is_instruction_fetching_disabled, PT_XD);
 }
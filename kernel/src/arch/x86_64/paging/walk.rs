@@ -0,0 +1,130 @@
+use common::{PAddr, VAddr};
+use abi::MapAttributes;
+use super::{PML4, PDPT, PD, PT, PDPT_PS, PDPT_G, PDPT_D, MemoryObject,
+            pml4_index, pdpt_index, pd_index, pt_index, cr3, ADDRESS_MASK,
+            BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH, HUGE_PAGE_LENGTH};
+
+/// Log every paging structure entry used to translate `vaddr` in the
+/// current address space, stopping at the first non-present entry or
+/// at the entry mapping the page.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn log_walk(vaddr: VAddr) {
+    let pml4_paddr = PAddr::from(cr3() & ADDRESS_MASK);
+    log!("Page walk of 0x{:x}, PML4 at 0x{:x}:", vaddr, pml4_paddr);
+
+    let pml4_entry = MemoryObject::<PML4>::new(pml4_paddr).as_ref()[pml4_index(vaddr)];
+    log!("  PML4[{}] = {:?} 0x{:x}", pml4_index(vaddr), pml4_entry, pml4_entry.get_address());
+    if !pml4_entry.is_present() {
+        return;
+    }
+
+    let pdpt_entry = MemoryObject::<PDPT>::new(pml4_entry.get_address()).as_ref()[pdpt_index(vaddr)];
+    log!("  PDPT[{}] = {:?} 0x{:x}", pdpt_index(vaddr), pdpt_entry, pdpt_entry.get_address());
+    if !pdpt_entry.is_present() || pdpt_entry.contains(PDPT_PS) {
+        return;
+    }
+
+    let pd_entry = MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref()[pd_index(vaddr)];
+    log!("  PD[{}] = {:?} 0x{:x}", pd_index(vaddr), pd_entry, pd_entry.get_address());
+    if !pd_entry.is_present() || pd_entry.is_page() {
+        return;
+    }
+
+    let pt_entry = MemoryObject::<PT>::new(pd_entry.get_address()).as_ref()[pt_index(vaddr)];
+    log!("  PT[{}] = {:?} 0x{:x}", pt_index(vaddr), pt_entry, pt_entry.get_address());
+}
+
+/// Translate `vaddr` in the current address space. See `lookup_in`.
+///
+/// # Safety
+///
+/// Must be called in ring 0.
+pub unsafe fn lookup(vaddr: VAddr) -> Option<(PAddr, MapAttributes)> {
+    lookup_in(PAddr::from(cr3() & ADDRESS_MASK), vaddr)
+}
+
+/// Walk the page tables rooted at `pml4` in software, and translate
+/// `vaddr` to the physical address it maps to, together with the
+/// attributes of the mapping. Returns `None` if nothing is mapped at
+/// `vaddr`.
+///
+/// # Safety
+///
+/// `pml4` must point to a valid PML4 page table.
+pub unsafe fn lookup_in(pml4: PAddr, vaddr: VAddr) -> Option<(PAddr, MapAttributes)> {
+    let pml4_entry = MemoryObject::<PML4>::new(pml4).as_ref()[pml4_index(vaddr)];
+    if !pml4_entry.is_present() {
+        return None;
+    }
+
+    let mut attributes = MapAttributes {
+        page_length: BASE_PAGE_LENGTH,
+        writable: pml4_entry.is_writeable(),
+        user: pml4_entry.is_user_mode_allowed(),
+        executable: !pml4_entry.is_instruction_fetching_disabled(),
+        global: false,
+        write_through: false,
+        cache_disabled: false,
+        accessed: false,
+        dirty: false,
+    };
+
+    let pdpt_entry = MemoryObject::<PDPT>::new(pml4_entry.get_address()).as_ref()[pdpt_index(vaddr)];
+    if !pdpt_entry.is_present() {
+        return None;
+    }
+    attributes.writable &= pdpt_entry.is_writeable();
+    attributes.user &= pdpt_entry.is_user_mode_allowed();
+    attributes.executable &= !pdpt_entry.is_instruction_fetching_disabled();
+
+    if pdpt_entry.contains(PDPT_PS) {
+        attributes.page_length = HUGE_PAGE_LENGTH;
+        attributes.global = pdpt_entry.contains(PDPT_G);
+        attributes.write_through = pdpt_entry.is_page_write_through();
+        attributes.cache_disabled = pdpt_entry.is_page_level_cache_disabled();
+        attributes.accessed = pdpt_entry.is_accessed();
+        attributes.dirty = pdpt_entry.contains(PDPT_D);
+
+        let offset = vaddr.into(): usize % HUGE_PAGE_LENGTH;
+        return Some((pdpt_entry.get_address() + offset, attributes));
+    }
+
+    let pd_entry = MemoryObject::<PD>::new(pdpt_entry.get_address()).as_ref()[pd_index(vaddr)];
+    if !pd_entry.is_present() {
+        return None;
+    }
+    attributes.writable &= pd_entry.is_writeable();
+    attributes.user &= pd_entry.is_user_mode_allowed();
+    attributes.executable &= !pd_entry.is_instruction_fetching_disabled();
+
+    if pd_entry.is_page() {
+        attributes.page_length = LARGE_PAGE_LENGTH;
+        attributes.global = pd_entry.is_global();
+        attributes.write_through = pd_entry.is_page_write_through();
+        attributes.cache_disabled = pd_entry.is_page_level_cache_disabled();
+        attributes.accessed = pd_entry.is_accessed();
+        attributes.dirty = pd_entry.is_dirty();
+
+        let offset = vaddr.into(): usize % LARGE_PAGE_LENGTH;
+        return Some((pd_entry.get_address() + offset, attributes));
+    }
+
+    let pt_entry = MemoryObject::<PT>::new(pd_entry.get_address()).as_ref()[pt_index(vaddr)];
+    if !pt_entry.is_present() {
+        return None;
+    }
+    attributes.writable &= pt_entry.is_writeable();
+    attributes.user &= pt_entry.is_user_mode_allowed();
+    attributes.executable &= !pt_entry.is_instruction_fetching_disabled();
+    attributes.global = pt_entry.is_global();
+    attributes.write_through = pt_entry.is_page_write_through();
+    attributes.cache_disabled = pt_entry.is_page_level_cache_disabled();
+    attributes.accessed = pt_entry.is_accessed();
+    attributes.dirty = pt_entry.is_dirty();
+
+    let offset = vaddr.into(): usize % BASE_PAGE_LENGTH;
+    Some((pt_entry.get_address() + offset, attributes))
+}
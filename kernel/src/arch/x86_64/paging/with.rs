@@ -1,15 +1,149 @@
 // TODO Disable interrupt before entering those.
 use core::mem::{size_of};
-use util::{align_down, block_count};
-use super::{PTEntry, PT_P, PT_RW, flush, BASE_PAGE_LENGTH};
-use arch::init::{OBJECT_POOL_PT, OBJECT_POOL_START_VADDR};
-use common::PAddr;
+use util::{align_down, block_count, Mutex};
+use super::{PT, PD, PTEntry, PDEntry, PT_P, PT_RW, PT_G, PD_P, PD_RW,
+            flush, flush_range_all_cpus, pd_index, BASE_PAGE_LENGTH, LARGE_PAGE_LENGTH};
+use arch::init::{OBJECT_POOL_PT, OBJECT_POOL_START_VADDR, OBJECT_POOL_SIZE,
+                 OBJECT_POOL_EXTENSION_START_VADDR, OBJECT_POOL_MAX_EXTENSIONS,
+                 KERNEL_PD_VADDR};
+use common::{PAddr, VAddr};
+use frame;
+use meminfo::MemoryCategory;
 
 use core::ptr::NonNull;
 use core::marker::{PhantomData, Unsize};
 use core::ops::CoerceUnsized;
 use core::fmt;
 
+/// Usage statistics of the object pool, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectPoolStats {
+    /// Pages currently mapped.
+    pub in_use: usize,
+    /// Largest number of pages mapped at the same time.
+    pub high_water: usize,
+    /// Pages the pool can map without growing.
+    pub capacity: usize,
+}
+
+/// Object pool extension PTs and statistics. Always locked after
+/// `OBJECT_POOL_PT`.
+struct ObjectPoolState {
+    extensions: [Option<PAddr>; OBJECT_POOL_MAX_EXTENSIONS],
+    in_use: usize,
+    high_water: usize,
+}
+
+static OBJECT_POOL_STATE: Mutex<ObjectPoolState> = Mutex::new(ObjectPoolState {
+    extensions: [None; OBJECT_POOL_MAX_EXTENSIONS],
+    in_use: 0,
+    high_water: 0,
+});
+
+impl ObjectPoolState {
+    /// Entries of the object pool segment `segment`, and the virtual
+    /// address the segment starts at. Segment 0 is `OBJECT_POOL_PT`,
+    /// and the others are extensions, if they have been chained in.
+    fn segment<'a>(&self, object_pool: &'a mut [PTEntry], segment: usize)
+                   -> Option<(&'a mut [PTEntry], VAddr)> {
+        if segment == 0 {
+            return Some((object_pool, OBJECT_POOL_START_VADDR));
+        }
+
+        self.extensions[segment - 1].map(|pt| {
+            let entries = unsafe { &mut *(frame::to_vaddr(pt).into(): usize as *mut PT) };
+            (&mut entries[..],
+             OBJECT_POOL_EXTENSION_START_VADDR + (segment - 1) * LARGE_PAGE_LENGTH)
+        })
+    }
+
+    /// Map `count` pages starting at `paddr` in the first segment
+    /// with enough consecutive free entries. Returns the segment,
+    /// entry index and virtual address of the mapping.
+    fn map(&mut self, object_pool: &mut [PTEntry], paddr: PAddr, count: usize)
+           -> Option<(usize, usize, VAddr)> {
+        for segment in 0..(OBJECT_POOL_MAX_EXTENSIONS + 1) {
+            let (entries, start_vaddr) = match self.segment(object_pool, segment) {
+                Some(segment) => segment,
+                None => break,
+            };
+
+            let mut index = 0;
+            while index + count <= entries.len() {
+                match (0..count).position(|j| entries[index + j].is_present()) {
+                    Some(used) => index += used + 1,
+                    None => break,
+                }
+            }
+            if index + count > entries.len() {
+                continue;
+            }
+
+            // Object pool entries are global, so that flushing them also
+            // reaches TLB entries tagged with other PCIDs.
+            let vaddr = start_vaddr + index * BASE_PAGE_LENGTH;
+            for i in 0..count {
+                entries[index + i] = PTEntry::new(paddr + (i * BASE_PAGE_LENGTH), PT_P | PT_RW | PT_G);
+                unsafe { flush(vaddr + i * BASE_PAGE_LENGTH); }
+            }
+
+            self.in_use += count;
+            if self.in_use > self.high_water {
+                self.high_water = self.in_use;
+            }
+            return Some((segment, index, vaddr));
+        }
+
+        None
+    }
+
+    /// Clear a mapping made by `map`. Returns its virtual address.
+    fn unmap(&mut self, object_pool: &mut [PTEntry], segment: usize, index: usize,
+             count: usize) -> VAddr {
+        let (entries, start_vaddr) = self.segment(object_pool, segment).unwrap();
+        for i in 0..count {
+            entries[index + i] = PTEntry::empty();
+        }
+
+        self.in_use -= count;
+        start_vaddr + index * BASE_PAGE_LENGTH
+    }
+}
+
+/// Chain a new extension PT into the object pool, allocated from the
+/// frame allocator. Panics if the pool can grow no further.
+fn grow() {
+    let pt = frame::allocate(0, MemoryCategory::PageTable).expect("object pool exhausted, out of frames");
+    unsafe {
+        *(frame::to_vaddr(pt).into(): usize as *mut PT) = [PTEntry::empty(); 512];
+    }
+
+    let _object_pool = OBJECT_POOL_PT.lock();
+    let mut state = OBJECT_POOL_STATE.lock();
+    let extension = state.extensions.iter().position(|e| e.is_none())
+        .expect("object pool exhausted, too many extensions");
+    let vaddr = OBJECT_POOL_EXTENSION_START_VADDR + extension * LARGE_PAGE_LENGTH;
+
+    // The kernel PD is shared by all address spaces, so the extension
+    // is visible everywhere.
+    unsafe {
+        let kernel_pd = &mut *(KERNEL_PD_VADDR.into(): usize as *mut PD);
+        kernel_pd[pd_index(vaddr)] = PDEntry::new(pt, PD_P | PD_RW);
+    }
+    state.extensions[extension] = Some(pt);
+    log!("Object pool extended at 0x{:x}.", vaddr);
+}
+
+/// Current usage statistics of the object pool.
+pub fn object_pool_stats() -> ObjectPoolStats {
+    let state = OBJECT_POOL_STATE.lock();
+    ObjectPoolStats {
+        in_use: state.in_use,
+        high_water: state.high_water,
+        capacity: OBJECT_POOL_SIZE + state.extensions.iter().filter(|e| e.is_some()).count() * 512,
+    }
+}
+
 /// Represent a memory object, that converts a physical address to an
 /// accessible object.
 ///
@@ -27,6 +161,7 @@ use core::fmt;
 /// `ObjectGuard` requires T must be Sized.
 pub struct MemoryObject<T: ?Sized> {
     paddr: PAddr,
+    mapping_segment: usize,
     mapping_start_index: usize,
     mapping_size: usize,
     pointer: NonNull<T>,
@@ -74,38 +209,22 @@ impl<T: ?Sized> MemoryObject<T> {
         let required_page_size = block_count((paddr + size).into(): usize - aligned.into(): usize,
                                              BASE_PAGE_LENGTH);
 
-        let mut object_pool = OBJECT_POOL_PT.lock();
-        let mapping_start_index: usize = {
-            let mut mapping_start_index: Option<usize> = None;
-
-            for i in 0..object_pool.len() {
-                let mut available = true;
-                for j in 0..required_page_size {
-                    if object_pool[i + j].is_present() {
-                        available = false;
-                        break;
-                    }
-                }
-
-                if available {
-                    mapping_start_index = Some(i);
-                    break;
+        let (mapping_segment, mapping_start_index, vaddr) = loop {
+            {
+                let mut object_pool = OBJECT_POOL_PT.lock();
+                let mut state = OBJECT_POOL_STATE.lock();
+                if let Some(mapping) = state.map(&mut *object_pool, aligned, required_page_size) {
+                    break mapping;
                 }
             }
 
-            mapping_start_index
-        }.unwrap();
-
-
-        for i in 0..required_page_size {
-            object_pool[mapping_start_index + i] = PTEntry::new(aligned + (i * BASE_PAGE_LENGTH), PT_P | PT_RW);
-            flush(OBJECT_POOL_START_VADDR + (mapping_start_index * BASE_PAGE_LENGTH) + i * BASE_PAGE_LENGTH);
-        }
-
-        let vaddr = OBJECT_POOL_START_VADDR + ((mapping_start_index * BASE_PAGE_LENGTH) + before_start);
+            grow();
+        };
+        let vaddr = vaddr + before_start;
 
         MemoryObject::<T> {
             paddr: paddr,
+            mapping_segment: mapping_segment,
             mapping_start_index: mapping_start_index,
             mapping_size: required_page_size,
             pointer: NonNull::new_unchecked(vaddr.into(): usize as *mut T),
@@ -119,11 +238,11 @@ impl<T: ?Sized, U: ?Sized> CoerceUnsized<MemoryObject<U>> for MemoryObject<T> wh
 impl<T: ?Sized> Drop for MemoryObject<T> {
     fn drop(&mut self) {
         let mut object_pool = OBJECT_POOL_PT.lock();
+        let mut state = OBJECT_POOL_STATE.lock();
 
-        for i in 0..self.mapping_size {
-            object_pool[self.mapping_start_index + i] = PTEntry::empty();
-            unsafe { flush(OBJECT_POOL_START_VADDR + (self.mapping_start_index * BASE_PAGE_LENGTH) + i * BASE_PAGE_LENGTH); }
-        }
+        let vaddr = state.unmap(&mut *object_pool, self.mapping_segment,
+                                self.mapping_start_index, self.mapping_size);
+        unsafe { flush_range_all_cpus(vaddr, self.mapping_size * BASE_PAGE_LENGTH); }
     }
 }
 
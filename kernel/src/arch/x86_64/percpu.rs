@@ -0,0 +1,87 @@
+use arch::cpu::{self, MAX_CPUS};
+
+/// Base of the GS segment: in the kernel, the per-CPU data of the
+/// current CPU.
+pub const IA32_GS_BASE: u32 = 0xC0000101;
+/// GS base `swapgs` exchanges with the current one: in the kernel, the
+/// GS base of user mode.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+/// Offset of `PerCpu::user_stack`, for entry stubs.
+pub const PERCPU_USER_STACK_OFFSET: u64 = 16;
+/// Offset of `PerCpu::tss`, for entry stubs.
+pub const PERCPU_TSS_OFFSET: u64 = 24;
+
+/// Log2 of the size of `PerCpu`, for entry stubs to index `PER_CPU`.
+pub const PERCPU_SHIFT: u64 = 5;
+
+/// Data of one CPU, which the kernel reaches through GS.
+///
+/// Kernel code always runs with GS based on the data of its CPU. Entry
+/// stubs swap it in with `swapgs` when coming from user mode, and exit
+/// paths swap it out when returning there. Entries that can hit the
+/// kernel at any point, such as NMIs, check the GS base itself, since
+/// they may hit an entry or exit path between its check of the mode
+/// and its `swapgs`. They compare it with the address of the data of
+/// their CPU, as user mode can set any GS base with `wrgsbase`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this structure, to get a plain pointer from GS.
+    this: u64,
+    /// Local APIC id of the CPU.
+    id: u64,
+    /// User stack pointer, kept by the `syscall` entry stub while it
+    /// switches stacks.
+    user_stack: u64,
+    /// Address of the TSS of the CPU.
+    tss: u64,
+}
+
+/// Per-CPU data, indexed by local APIC id. In the trampoline, as entry
+/// stubs read it before switching page tables with KPTI.
+#[link_section = ".trampoline.data"]
+pub static mut PER_CPU: [PerCpu; MAX_CPUS] = [PerCpu {
+    this: 0,
+    id: 0,
+    user_stack: 0,
+    tss: 0,
+}; MAX_CPUS];
+
+impl PerCpu {
+    /// Local APIC id of the CPU.
+    pub fn id(&self) -> usize {
+        self.id as usize
+    }
+
+    /// Address of the TSS of the CPU.
+    pub fn tss(&self) -> u64 {
+        self.tss
+    }
+}
+
+/// Set up the data of the current CPU, whose TSS is at `tss`, and base
+/// GS on it. Must be called on each CPU, before anything uses GS.
+pub fn init(tss: u64) {
+    assert_eq!(::core::mem::size_of::<PerCpu>(), 1 << PERCPU_SHIFT);
+
+    let id = cpu::current_id_lockless();
+    unsafe {
+        let data = &mut PER_CPU[id];
+        data.this = data as *const _ as u64;
+        data.id = id as u64;
+        data.tss = tss;
+
+        cpu::wrmsr(IA32_GS_BASE, data.this);
+        cpu::wrmsr(IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// Data of the current CPU.
+pub fn current() -> &'static PerCpu {
+    let this: u64;
+    unsafe {
+        asm!("mov $0, gs:[0]" : "=r" (this) ::: "intel");
+        &*(this as *const PerCpu)
+    }
+}
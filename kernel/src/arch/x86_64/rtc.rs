@@ -0,0 +1,156 @@
+use arch::{inportb, outportb};
+use arch::acpi::{self, SdtHeader};
+
+/// CMOS register index port. Bit 7 of the index disables NMIs, so it
+/// is left clear.
+const CMOS_INDEX: u16 = 0x70;
+/// CMOS register data port.
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+/// Status A: the clock is being updated, and reads may be torn.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: hours are in 24-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B: values are binary rather than BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Hours register bit set for PM in 12-hour format.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Offset of the century register index in the FADT.
+const FADT_CENTURY_OFFSET: usize = 108;
+/// Century assumed without a century register.
+const DEFAULT_CENTURY: u64 = 20;
+/// Number of reads tried before giving up on a consistent one.
+const READ_ATTEMPTS: usize = 16;
+
+/// Date and time as the RTC holds it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RtcTime {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn read_register(index: u8) -> u8 {
+    outportb(CMOS_INDEX, index);
+    inportb(CMOS_DATA)
+}
+
+/// Read all clock registers, after any update in progress ends.
+unsafe fn read_time(century_register: Option<u8>) -> RtcTime {
+    while read_register(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 { }
+
+    RtcTime {
+        seconds: read_register(RTC_SECONDS),
+        minutes: read_register(RTC_MINUTES),
+        hours: read_register(RTC_HOURS),
+        day: read_register(RTC_DAY),
+        month: read_register(RTC_MONTH),
+        year: read_register(RTC_YEAR),
+        century: century_register.map(|index| read_register(index)).unwrap_or(0),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// CMOS index of the century register, from the FADT, if there is one.
+fn century_register() -> Option<u8> {
+    let fadt = match acpi::find_table(b"FACP") {
+        Some(fadt) => fadt,
+        None => return None,
+    };
+    let length = unsafe { acpi::read::<SdtHeader>(fadt) }.length as usize;
+    if length <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+
+    match unsafe { acpi::read::<u8>(fadt + FADT_CENTURY_OFFSET) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so that the leap day ends a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Read the RTC, as seconds since the Unix epoch. The RTC is assumed
+/// to hold UTC. Returns `None` if no consistent read could be made,
+/// or the clock holds an invalid date.
+pub fn unix_seconds() -> Option<u64> {
+    let century_register = century_register();
+
+    // Two equal reads in a row cannot have been torn by an update.
+    let mut last = unsafe { read_time(century_register) };
+    let mut time = None;
+    for _ in 0..READ_ATTEMPTS {
+        let current = unsafe { read_time(century_register) };
+        if current == last {
+            time = Some(current);
+            break;
+        }
+        last = current;
+    }
+    let mut time = match time {
+        Some(time) => time,
+        None => return None,
+    };
+
+    let status_b = unsafe { read_register(RTC_STATUS_B) };
+    let pm = time.hours & HOURS_PM != 0;
+    time.hours &= !HOURS_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        time.seconds = bcd_to_binary(time.seconds);
+        time.minutes = bcd_to_binary(time.minutes);
+        time.hours = bcd_to_binary(time.hours);
+        time.day = bcd_to_binary(time.day);
+        time.month = bcd_to_binary(time.month);
+        time.year = bcd_to_binary(time.year);
+        time.century = bcd_to_binary(time.century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, and 12 PM noon.
+        time.hours = time.hours % 12 + if pm { 12 } else { 0 };
+    }
+
+    if time.seconds > 59 || time.minutes > 59 || time.hours > 23 ||
+        time.day < 1 || time.day > 31 || time.month < 1 || time.month > 12
+    {
+        log!("Invalid RTC time: {:?}", time);
+        return None;
+    }
+
+    let century = if time.century != 0 { time.century as u64 } else { DEFAULT_CENTURY };
+    let year = century * 100 + time.year as u64;
+    if year < 1970 {
+        log!("Invalid RTC time: {:?}", time);
+        return None;
+    }
+
+    let days = days_from_civil(year, time.month as u64, time.day as u64);
+    Some(((days * 24 + time.hours as u64) * 60 + time.minutes as u64) * 60 + time.seconds as u64)
+}
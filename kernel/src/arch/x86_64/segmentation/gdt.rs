@@ -0,0 +1,155 @@
+use core::mem::size_of;
+use super::{SegmentDescriptor, SystemDescriptor, SegmentSelector, TaskStateSegment, Ldt};
+use super::{DESC_S, DESC_P, DESC_L, DESC_DB, DESC_DPL0, DESC_DPL3, TYPE_C_ER, TYPE_D_RW};
+
+/// Number of entries of a GDT: the null descriptor, six code and data
+/// segments, and the TSS and LDT descriptors, which take two each.
+pub const GDT_LENGTH: usize = 11;
+
+/// Selector of the LDT descriptor in the GDT built by `kernel_gdt`.
+pub const LDT_SELECTOR: SegmentSelector = SegmentSelector::new(9);
+
+/// Global descriptor table of one CPU.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Gdt {
+    entries: [SegmentDescriptor; GDT_LENGTH],
+}
+
+impl Gdt {
+    /// GDT with only null descriptors.
+    pub const fn empty() -> Gdt {
+        Gdt {
+            entries: [SegmentDescriptor { bits: 0 }; GDT_LENGTH],
+        }
+    }
+
+    /// Load this GDT. It must not move or change while loaded, except
+    /// for the busy bit the processor sets in the TSS descriptor.
+    pub unsafe fn load(&self) {
+        use arch::interrupt::dtables::{DescriptorTablePointer, lgdt};
+
+        let ptr = DescriptorTablePointer {
+            base: self as *const _ as u64,
+            limit: (size_of::<Self>() - 1) as u16,
+        };
+
+        lgdt(&ptr);
+    }
+}
+
+/// Builder composing a GDT entry by entry. Each entry is placed after
+/// the previous one, so the order of calls fixes the selectors.
+pub struct GdtBuilder {
+    gdt: Gdt,
+    next: usize,
+}
+
+impl GdtBuilder {
+    /// Builder of a GDT starting with the null descriptor.
+    pub fn new() -> GdtBuilder {
+        GdtBuilder {
+            gdt: Gdt::empty(),
+            next: 1,
+        }
+    }
+
+    /// Append `descriptor`, and return its selector.
+    pub fn push(&mut self, descriptor: SegmentDescriptor) -> SegmentSelector {
+        assert!(self.next < GDT_LENGTH, "GDT is full");
+        self.gdt.entries[self.next] = descriptor;
+        self.next += 1;
+        SegmentSelector::new((self.next - 1) as u16)
+    }
+
+    /// Append a 64-bit code segment with privilege level `dpl`, one of
+    /// `DESC_DPL0` to `DESC_DPL3`.
+    pub fn code64(&mut self, dpl: SegmentDescriptor) -> SegmentSelector {
+        self.push(DESC_P | DESC_S | DESC_L | TYPE_C_ER | dpl)
+    }
+
+    /// Append a 32-bit code segment with privilege level `dpl`, for
+    /// compatibility mode.
+    pub fn code32(&mut self, dpl: SegmentDescriptor) -> SegmentSelector {
+        self.push(DESC_P | DESC_S | DESC_DB | TYPE_C_ER | dpl)
+    }
+
+    /// Append a writable data segment with privilege level `dpl`.
+    pub fn data(&mut self, dpl: SegmentDescriptor) -> SegmentSelector {
+        self.push(DESC_P | DESC_S | TYPE_D_RW | dpl)
+    }
+
+    /// Append a writable data segment with privilege level `dpl`, with
+    /// a 32-bit stack in compatibility mode.
+    pub fn data32(&mut self, dpl: SegmentDescriptor) -> SegmentSelector {
+        self.push(DESC_P | DESC_S | DESC_DB | TYPE_D_RW | dpl)
+    }
+
+    /// Append the two entries of `descriptor`, and return the
+    /// selector of the first.
+    pub fn push_system(&mut self, descriptor: SystemDescriptor) -> SegmentSelector {
+        assert!(self.next + 1 < GDT_LENGTH, "GDT is full");
+        let selector = self.push(descriptor.low);
+        self.push(descriptor.high);
+        selector
+    }
+
+    /// Append the descriptor of `tss`.
+    pub fn tss(&mut self, tss: &TaskStateSegment) -> SegmentSelector {
+        self.push_system(SystemDescriptor::tss(tss))
+    }
+
+    /// Append the descriptor of `ldt`.
+    pub fn ldt(&mut self, ldt: &Ldt) -> SegmentSelector {
+        self.push_system(SystemDescriptor::ldt(ldt))
+    }
+
+    /// The GDT built.
+    pub fn build(self) -> Gdt {
+        self.gdt
+    }
+}
+
+/// Build the GDT of a CPU using `tss` and `ldt`. The selectors are
+/// fixed, as the boot GDT and the interrupt return path rely on them:
+/// kernel code 0x08 and data 0x10, 32-bit user code 0x18 and data
+/// 0x20, 64-bit user code 0x28 and data 0x30, the TSS at 0x38 and the
+/// LDT at 0x48.
+pub fn kernel_gdt(tss: &TaskStateSegment, ldt: &Ldt) -> Gdt {
+    let mut builder = GdtBuilder::new();
+    assert_eq!(builder.code64(DESC_DPL0).bits(), 0x08);
+    assert_eq!(builder.data(DESC_DPL0).bits(), 0x10);
+    assert_eq!(builder.code32(DESC_DPL3).bits(), 0x18);
+    assert_eq!(builder.data32(DESC_DPL3).bits(), 0x20);
+    assert_eq!(builder.code64(DESC_DPL3).bits(), 0x28);
+    assert_eq!(builder.data(DESC_DPL3).bits(), 0x30);
+    assert_eq!(builder.tss(tss).bits(), 0x38);
+    assert_eq!(builder.ldt(ldt), LDT_SELECTOR);
+    builder.build()
+}
+
+/// Log each entry of the GDT the current CPU has loaded, read back with
+/// `sgdt`, to find malformed descriptors.
+pub fn log_loaded_gdt() {
+    use arch::interrupt::dtables::sgdt;
+
+    let pointer = sgdt();
+    let base = pointer.base;
+    let length = (pointer.limit as usize + 1) / size_of::<SegmentDescriptor>();
+    log!("GDT at 0x{:x}, {} entries", base, length);
+
+    let entries = base as *const SegmentDescriptor;
+    let mut index = 0;
+    while index < length {
+        let entry = unsafe { *entries.offset(index as isize) };
+        if entry.is_system() && index + 1 < length {
+            let high = unsafe { *entries.offset(index as isize + 1) };
+            let descriptor = SystemDescriptor { low: entry, high: high };
+            log!("0x{:02x}: {}, base 0x{:016x}", index * 8, entry, descriptor.base());
+            index += 2;
+        } else {
+            log!("0x{:02x}: {}", index * 8, entry);
+            index += 1;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use super::{SegmentDescriptor, DESC_S, DESC_P, DESC_L, DESC_DB};
+
+/// Number of entries of an LDT.
+pub const LDT_LENGTH: usize = 64;
+
+/// Local descriptor table, holding user code and data segments that
+/// selectors with `TI_LDT` refer to.
+#[derive(Copy)]
+#[repr(C)]
+pub struct Ldt {
+    entries: [SegmentDescriptor; LDT_LENGTH],
+}
+
+impl Clone for Ldt {
+    fn clone(&self) -> Ldt {
+        *self
+    }
+}
+
+impl ::core::fmt::Debug for Ldt {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        let used = self.entries.iter().filter(|entry| entry.bits() != 0).count();
+        write!(f, "Ldt {{ used: {} }}", used)
+    }
+}
+
+impl Ldt {
+    /// LDT with only null descriptors.
+    pub const fn empty() -> Ldt {
+        Ldt {
+            entries: [SegmentDescriptor { bits: 0 }; LDT_LENGTH],
+        }
+    }
+
+    /// Set entry `index` to `descriptor`, or clear it if `descriptor`
+    /// is null. Returns `false` if the entry does not exist, or if the
+    /// descriptor is not a present code or data segment of privilege
+    /// level 3. System descriptors, such as call gates, are refused.
+    pub fn set(&mut self, index: usize, descriptor: SegmentDescriptor) -> bool {
+        if index >= LDT_LENGTH {
+            return false;
+        }
+        if descriptor.bits() != 0 {
+            if !descriptor.contains(DESC_S | DESC_P) || descriptor.dpl() != 3 {
+                return false;
+            }
+            // L and D together are reserved.
+            if descriptor.contains(DESC_L | DESC_DB) {
+                return false;
+            }
+        }
+        self.entries[index] = descriptor;
+        true
+    }
+
+    /// Entry `index`.
+    pub fn get(&self, index: usize) -> Option<SegmentDescriptor> {
+        self.entries.get(index).cloned()
+    }
+
+    /// Address and length of the entries, for its system descriptor.
+    pub fn region(&self) -> (u64, usize) {
+        (self.entries.as_ptr() as u64, ::core::mem::size_of::<[SegmentDescriptor; LDT_LENGTH]>())
+    }
+}
@@ -2,8 +2,16 @@
 
 /// Task State Segment Representation.
 mod tss;
+/// Global Descriptor Table builder.
+mod gdt;
+/// Local Descriptor Table.
+mod ldt;
 
-pub use self::tss::{TaskStateSegment};
+pub use self::tss::{TaskStateSegment, IST_LENGTH};
+pub use self::gdt::{Gdt, GdtBuilder, GDT_LENGTH, LDT_SELECTOR, kernel_gdt, log_loaded_gdt};
+pub use self::ldt::{Ldt, LDT_LENGTH};
+
+use core::fmt;
 
 bitflags! {
     /// Specifies which element to load into a segment from
@@ -18,9 +26,9 @@ bitflags! {
         const RPL_3 = 0b11,
 
         /// Table Indicator (TI) 0 means GDT is used.
-        const TI_GDT = 0 << 3,
+        const TI_GDT = 0 << 2,
         /// Table Indicator (TI) 1 means LDT is used.
-        const TI_LDT = 1 << 3,
+        const TI_LDT = 1 << 2,
     }
 }
 
@@ -34,6 +42,12 @@ impl SegmentSelector {
         SegmentSelector { bits: index << 3 }
     }
 
+    /// Selector of entry `index` of the LDT, with `TI_LDT` and
+    /// `RPL_3`, for user mode.
+    pub const fn new_ldt(index: u16) -> SegmentSelector {
+        SegmentSelector { bits: index << 3 | 0b100 | 0b11 }
+    }
+
     /// Create the selector from raw.
     pub const fn from_raw(bits: u16) -> SegmentSelector {
         SegmentSelector { bits: bits }
@@ -136,6 +150,111 @@ impl SegmentDescriptor {
             bits: raw,
         }
     }
+
+    /// Base address of the segment, without the upper half of a
+    /// system descriptor.
+    pub fn base(&self) -> u32 {
+        (((self.bits >> 16) & 0xFFFFFF) | (((self.bits >> (32 + 24)) & 0xFF) << 24)) as u32
+    }
+
+    /// Limit of the segment, in units of its granularity.
+    pub fn limit(&self) -> u32 {
+        ((self.bits & 0xFFFF) | ((self.bits >> 32) & (0b1111 << 16))) as u32
+    }
+
+    /// Descriptor privilege level.
+    pub fn dpl(&self) -> u8 {
+        ((self.bits >> (32 + 13)) & 0b11) as u8
+    }
+
+    /// Whether this is a TSS or an LDT descriptor, which takes two
+    /// entries.
+    pub fn is_system(&self) -> bool {
+        !self.contains(DESC_S) && self.bits != 0
+    }
+
+    /// Name of the type, as in the `TYPE_` flags.
+    fn type_name(&self) -> &'static str {
+        const CODE_DATA: [&'static str; 16] = [
+            "TYPE_D_RO", "TYPE_D_ROA", "TYPE_D_RW", "TYPE_D_RWA",
+            "TYPE_D_ROEXD", "TYPE_D_ROEXDA", "TYPE_D_RWEXD", "TYPE_D_RWEXDA",
+            "TYPE_C_EO", "TYPE_C_EOA", "TYPE_C_ER", "TYPE_C_ERA",
+            "TYPE_C_EOC", "TYPE_C_EOCA", "TYPE_C_ERC", "TYPE_C_ERCA",
+        ];
+        let kind = ((self.bits >> (32 + 8)) & 0b1111) as usize;
+        if self.contains(DESC_S) {
+            return CODE_DATA[kind];
+        }
+        match (kind as u64) << (32 + 8) {
+            bits if bits == TYPE_SYS_LDT.bits() => "TYPE_SYS_LDT",
+            bits if bits == TYPE_SYS_TSS_AVAILABLE.bits() => "TYPE_SYS_TSS_AVAILABLE",
+            bits if bits == TYPE_SYS_TSS_BUSY.bits() => "TYPE_SYS_TSS_BUSY",
+            bits if bits == TYPE_SYS_CALL_GATE.bits() => "TYPE_SYS_CALL_GATE",
+            bits if bits == TYPE_SYS_INTERRUPT_GATE.bits() => "TYPE_SYS_INTERRUPT_GATE",
+            bits if bits == TYPE_SYS_TRAP_GATE.bits() => "TYPE_SYS_TRAP_GATE",
+            _ => "reserved system type",
+        }
+    }
+}
+
+impl fmt::Display for SegmentDescriptor {
+    /// Decode the descriptor with the names of its flags. The base of a
+    /// system descriptor lacks its upper half.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bits == 0 {
+            return write!(f, "null");
+        }
+        try!(write!(f, "0x{:016x} base 0x{:08x} limit 0x{:05x} DESC_DPL{} {}",
+                    self.bits, self.base(), self.limit(), self.dpl(), self.type_name()));
+        for &(flag, name) in [(DESC_P, "DESC_P"), (DESC_S, "DESC_S"), (DESC_AVL, "DESC_AVL"),
+                              (DESC_L, "DESC_L"), (DESC_DB, "DESC_DB"), (DESC_G, "DESC_G")].iter() {
+            if self.contains(flag) {
+                try!(write!(f, " | {}", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Long mode system segment descriptor, for a TSS or an LDT. It takes
+/// two GDT entries, the second holding the upper half of the base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SystemDescriptor {
+    pub low: SegmentDescriptor,
+    pub high: SegmentDescriptor,
+}
+
+impl SystemDescriptor {
+    /// Descriptor of the system segment at `base`, with `limit` and
+    /// the type and privilege level in `flags`. It is present.
+    pub fn new(base: u64, limit: u32, flags: SegmentDescriptor) -> SystemDescriptor {
+        let mut low = SegmentDescriptor::new((base & 0xFFFFFFFF) as u32, limit);
+        low.insert(DESC_P | flags);
+
+        SystemDescriptor {
+            low: low,
+            high: SegmentDescriptor::from_raw(base >> 32),
+        }
+    }
+
+    /// Descriptor of an available `tss`.
+    pub fn tss(tss: &TaskStateSegment) -> SystemDescriptor {
+        SystemDescriptor::new(tss as *const _ as u64,
+                              (::core::mem::size_of::<TaskStateSegment>() - 1) as u32,
+                              TYPE_SYS_TSS_AVAILABLE | DESC_DPL0)
+    }
+
+    /// Descriptor of `ldt`.
+    pub fn ldt(ldt: &Ldt) -> SystemDescriptor {
+        let (base, length) = ldt.region();
+        SystemDescriptor::new(base, (length - 1) as u32, TYPE_SYS_LDT | DESC_DPL0)
+    }
+
+    /// Base address of the segment.
+    pub fn base(&self) -> u64 {
+        self.low.base() as u64 | (self.high.bits() << 32)
+    }
 }
 
 /// Reload stack segment register.
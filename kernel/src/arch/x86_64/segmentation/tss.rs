@@ -1,25 +1,35 @@
 /// Represents a Task State Segment. It holds the kernel stack
 /// information used by interrupts.
 #[repr(packed)]
+#[derive(Copy)]
 #[allow(dead_code)]
 pub struct TaskStateSegment {
     _reserved1: u32,
-    pub sp0: u64,
-    pub sp1: u64,
-    pub sp2: u64,
+    sp0: u64,
+    sp1: u64,
+    sp2: u64,
     _reserved2: u32,
     _reserved3: u32,
-    pub ist1: u64,
-    pub ist2: u64,
-    pub ist3: u64,
-    pub ist4: u64,
-    pub ist5: u64,
-    pub ist6: u64,
-    pub ist7: u64,
+    ist1: u64,
+    ist2: u64,
+    ist3: u64,
+    ist4: u64,
+    ist5: u64,
+    ist6: u64,
+    ist7: u64,
     _reserved4: u32,
     _reserved5: u32,
     _reserved6: u16,
-    pub iomap_base: u16,
+    iomap_base: u16,
+}
+
+/// Number of interrupt stack table entries, numbered from 1.
+pub const IST_LENGTH: usize = 7;
+
+impl Clone for TaskStateSegment {
+    fn clone(&self) -> TaskStateSegment {
+        *self
+    }
 }
 
 impl TaskStateSegment {
@@ -45,4 +55,57 @@ impl TaskStateSegment {
             iomap_base: 0,
         }
     }
+
+    /// Stack pointer loaded on a switch to privilege level `level`,
+    /// from 0 to 2.
+    pub fn rsp(&self, level: usize) -> u64 {
+        match level {
+            0 => self.sp0,
+            1 => self.sp1,
+            2 => self.sp2,
+            _ => panic!("no stack for privilege level {}", level),
+        }
+    }
+
+    /// Set the stack pointer loaded on a switch to privilege level
+    /// `level`, from 0 to 2.
+    pub fn set_rsp(&mut self, level: usize, stack_top: u64) {
+        match level {
+            0 => self.sp0 = stack_top,
+            1 => self.sp1 = stack_top,
+            2 => self.sp2 = stack_top,
+            _ => panic!("no stack for privilege level {}", level),
+        }
+    }
+
+    /// Stack pointer of interrupt stack table entry `index`, from 1 to
+    /// `IST_LENGTH`.
+    pub fn ist(&self, index: usize) -> u64 {
+        match index {
+            1 => self.ist1,
+            2 => self.ist2,
+            3 => self.ist3,
+            4 => self.ist4,
+            5 => self.ist5,
+            6 => self.ist6,
+            7 => self.ist7,
+            _ => panic!("no interrupt stack table entry {}", index),
+        }
+    }
+
+    /// Set interrupt stack table entry `index`, from 1 to
+    /// `IST_LENGTH`, to `stack_top`. Interrupt gates with that stack
+    /// index switch to it.
+    pub fn set_ist(&mut self, index: usize, stack_top: u64) {
+        match index {
+            1 => self.ist1 = stack_top,
+            2 => self.ist2 = stack_top,
+            3 => self.ist3 = stack_top,
+            4 => self.ist4 = stack_top,
+            5 => self.ist5 = stack_top,
+            6 => self.ist6 = stack_top,
+            7 => self.ist7 = stack_top,
+            _ => panic!("no interrupt stack table entry {}", index),
+        }
+    }
 }
@@ -0,0 +1,30 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use arch::cpu::{self, CR4_SMEP, CR4_SMAP, CPUID_07_EBX_SMEP, CPUID_07_EBX_SMAP};
+
+/// End of the user half of the address space, exclusive.
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Whether SMAP is enabled, and `clac` is available. Read by the
+/// exception entry stubs.
+pub static SMAP_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Enable SMEP and SMAP if the processor supports them. After this,
+/// the kernel can neither execute nor access user pages. It never
+/// needs to: system call arguments are passed in task buffers, which
+/// the kernel reads through its own mapping of their frames.
+pub fn init() {
+    let (_, ebx, _, _) = cpu::cpuid(0x7, 0);
+
+    unsafe {
+        if ebx & CPUID_07_EBX_SMEP != 0 {
+            cpu::cr4_write(cpu::cr4() | CR4_SMEP);
+            log!("SMEP enabled.");
+        }
+
+        if ebx & CPUID_07_EBX_SMAP != 0 {
+            cpu::cr4_write(cpu::cr4() | CR4_SMAP);
+            SMAP_ENABLED.store(true, Ordering::SeqCst);
+            log!("SMAP enabled.");
+        }
+    }
+}
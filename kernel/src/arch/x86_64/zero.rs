@@ -0,0 +1,61 @@
+use arch::paging::CACHE_LINE_LENGTH;
+
+/// Ranges of at least this many bytes are zeroed with non-temporal
+/// stores, which bypass the cache instead of evicting its contents.
+pub const NON_TEMPORAL_THRESHOLD: usize = 64 * 1024;
+
+/// Zero the given virtual range with forward `rep stosq`, or with
+/// non-temporal stores if it is large and cache-line aligned.
+///
+/// # Safety
+///
+/// The range must be mapped writable, and hold nothing in use.
+pub unsafe fn zero_range(start: usize, length: usize) {
+    if length >= NON_TEMPORAL_THRESHOLD && start % CACHE_LINE_LENGTH == 0 &&
+        length % CACHE_LINE_LENGTH == 0
+    {
+        zero_range_non_temporal(start, length);
+        return;
+    }
+
+    // Entry stubs clear the direction flag, but a string store going
+    // backwards would zero memory below the range, so do not rely on
+    // it.
+    let _rdi: usize;
+    let _rcx: usize;
+    asm!("cld
+          rep stosq
+          mov $4, $1
+          rep stosb"
+         : "={rdi}"(_rdi), "={rcx}"(_rcx)
+         : "0"(start), "1"(length / 8), "r"(length % 8), "{rax}"(0: u64)
+         : "memory" : "volatile");
+}
+
+/// Zero the given virtual range with non-temporal stores, for memory
+/// not expected to be used soon. Both `start` and `length` must be
+/// multiples of the cache line length.
+///
+/// # Safety
+///
+/// The range must be mapped writable, and hold nothing in use.
+pub unsafe fn zero_range_non_temporal(start: usize, length: usize) {
+    assert!(start % CACHE_LINE_LENGTH == 0 && length % CACHE_LINE_LENGTH == 0);
+
+    let mut line = start;
+    while line < start + length {
+        asm!("movnti $1, 0($0)
+              movnti $1, 8($0)
+              movnti $1, 16($0)
+              movnti $1, 24($0)
+              movnti $1, 32($0)
+              movnti $1, 40($0)
+              movnti $1, 48($0)
+              movnti $1, 56($0)"
+             :: "r"(line), "r"(0: u64) : "memory" : "volatile");
+        line += CACHE_LINE_LENGTH;
+    }
+
+    // Non-temporal stores are weakly ordered.
+    asm!("sfence" ::: "memory" : "volatile");
+}
@@ -0,0 +1,90 @@
+use common::*;
+use core::slice;
+use elf::{ElfBinary, PT_LOAD};
+use util::{RwLock, MemoryObject};
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, VSpaceCap, Derived};
+
+/// Boot image descriptor.
+#[derive(Debug)]
+pub struct BootImageDescriptor {
+    start_paddr: PAddr,
+    length: usize,
+    next: Option<ManagedArcAny>,
+}
+
+/// Boot image capability. Reference-counted smart pointer to boot
+/// image descriptor.
+///
+/// There is only one, given to the initial task. It refers to the
+/// ELF image the initial task was loaded from, which stays in memory,
+/// so that programs of the image can be loaded again into new address
+/// spaces, for example to restart a server from scratch.
+pub type BootImageCap = ManagedArc<RwLock<BootImageDescriptor>>;
+
+impl BootImageCap {
+    /// Create the boot image capability.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot, for the rinit region returned from
+    /// `InitInfo`.
+    pub unsafe fn bootstrap(region: MemoryRegion, untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(BootImageDescriptor {
+                    start_paddr: region.start_paddr(),
+                    length: region.length(),
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl BootImageDescriptor {
+    /// Load every loadable segment of the image into `vspace`, in
+    /// zeroed frames owned by the address space, and return the entry
+    /// point. Returns `None` if the image is malformed, a segment
+    /// overlaps other mappings, or the untyped capability of `vspace`
+    /// is short. Segments loaded before a failure stay mapped, and
+    /// are freed with the address space.
+    pub fn load(&self, vspace: &VSpaceCap) -> Option<VAddr> {
+        let object = unsafe { MemoryObject::<u8>::slice(self.start_paddr, self.length) };
+        let image = unsafe { slice::from_raw_parts(object.as_ptr(), self.length) };
+        let bin = ElfBinary::new("rinit", image)?;
+
+        for p in bin.program_headers() {
+            if p.progtype != PT_LOAD {
+                continue;
+            }
+
+            let offset = p.offset as usize;
+            let filesz = p.filesz as usize;
+            if filesz > p.memsz as usize ||
+                offset.checked_add(filesz).map(|end| end > image.len()).unwrap_or(true)
+            {
+                return None;
+            }
+
+            if !vspace.write().fill(VAddr::from(p.vaddr), p.memsz as usize,
+                                    &image[offset..(offset + filesz)]) {
+                return None;
+            }
+        }
+
+        Some(VAddr::from(bin.file_header().entry))
+    }
+}
+
+impl Derived for BootImageDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
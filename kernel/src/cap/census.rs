@@ -0,0 +1,76 @@
+use core::any::TypeId;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use abi::{CapInfo, CapDump, ObjectCounts, CAP_TYPE_COUNT, CAP_DUMP_ENTRIES};
+use super::{CPoolCap, cap_type};
+
+/// Kernel objects alive, indexed by `CapType`.
+static LIVE: [AtomicUsize; CAP_TYPE_COUNT] = [ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT];
+
+/// Record a new object of the capability type `type_id`.
+pub fn object_created(type_id: TypeId) {
+    if let Some(cap_type) = cap_type(type_id) {
+        LIVE[cap_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Record an object of the capability type `type_id` as destroyed.
+pub fn object_destroyed(type_id: TypeId) {
+    if let Some(cap_type) = cap_type(type_id) {
+        LIVE[cap_type as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Objects currently alive, reported to user-space by the
+/// `ObjectCounts` system call.
+pub fn object_counts() -> ObjectCounts {
+    let mut live = [0; CAP_TYPE_COUNT];
+    for (count, counter) in live.iter_mut().zip(LIVE.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+
+    ObjectCounts {
+        live: live,
+    }
+}
+
+/// List up to `CAP_DUMP_ENTRIES` capabilities reachable from `root`,
+/// skipping the first `start` in walk order.
+pub fn cap_dump(root: &CPoolCap, start: usize) -> CapDump {
+    let mut dump = CapDump {
+        entries: [None; CAP_DUMP_ENTRIES],
+        next: None,
+    };
+    let mut position: usize = 0;
+
+    root.walk(&mut |caddr, any, rights| {
+        if let Some(offset) = position.checked_sub(start) {
+            if offset < CAP_DUMP_ENTRIES {
+                dump.entries[offset] = cap_type(any.type_id()).map(|cap_type| {
+                    CapInfo {
+                        caddr: caddr,
+                        cap_type: cap_type,
+                        object: any.ptr().into(): u64,
+                        rights: rights,
+                        badge: 0,
+                    }
+                });
+            } else if offset == CAP_DUMP_ENTRIES {
+                dump.next = Some(position);
+            }
+        }
+        position += 1;
+    });
+
+    dump
+}
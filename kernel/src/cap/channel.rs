@@ -2,14 +2,26 @@ use common::*;
 use core::convert::From;
 use util::RwLock;
 use util::managed_arc::{ManagedArc, ManagedArcAny};
-use abi::ChannelMessage;
-use super::{UntypedDescriptor, TaskCap, TaskBufferPageCap};
+use abi::{ChannelMessage, CapTransfer, FrameLoan, PageFaultInfo, FaultInfo, DebugEvent, MESSAGE_CAPS, MESSAGE_LENGTH_MAX};
+use super::{UntypedDescriptor, TaskCap, TaskBufferPageCap, SchedContextCap, SharedFrameSetCap, Derived, wake_waiters};
+
+/// Capabilities of a grant, with the rights they were sent with.
+pub type GrantedCaps = [Option<(ManagedArcAny, CapRights)>; MESSAGE_CAPS];
 
 #[derive(Debug)]
 pub enum ChannelValue {
     Raw(u64),
-    Cap(ManagedArcAny),
+    /// A capability, with the rights it was sent with.
+    Cap(ManagedArcAny, CapRights),
+    /// A value with capabilities, and whether the sender kept them.
+    Grant(u64, GrantedCaps, bool),
+    /// A value with a shared frame set lent, and whether the receiver
+    /// may write to it.
+    Lend(u64, SharedFrameSetCap, bool),
     Payload(TaskBufferPageCap),
+    PageFault(PageFaultInfo),
+    Fault(FaultInfo),
+    Debug(DebugEvent),
 }
 
 impl ChannelValue {
@@ -18,40 +30,175 @@ impl ChannelValue {
             ChannelMessage::Raw(value) => Some(ChannelValue::Raw(value)),
             ChannelMessage::Cap(Some(caddr)) => {
                 let source_root = source_root.read().upgrade_cpool().unwrap();
-                let obj = source_root.lookup_upgrade_any(caddr);
-                if obj.is_some() {
-                    Some(ChannelValue::Cap(obj.unwrap()))
-                } else {
-                    None
+                let rights = source_root.lookup_rights(caddr);
+                let obj = source_root.lookup_upgrade_any(caddr, RIGHTS_NONE);
+                match (obj, rights) {
+                    (Some(obj), Some(rights)) => {
+                        // Send-once capabilities are moved, so that
+                        // only one copy is ever used.
+                        if rights.contains(RIGHT_SEND_ONCE) && !source_root.lookup_remove(caddr) {
+                            super::drop_any(obj);
+                            None
+                        } else {
+                            Some(ChannelValue::Cap(obj, rights))
+                        }
+                    },
+                    (Some(obj), None) => {
+                        super::drop_any(obj);
+                        None
+                    },
+                    _ => None,
                 }
             },
             ChannelMessage::Cap(None) => None,
+            ChannelMessage::Grant(value, transfer) => {
+                let source_root = match source_root.read().upgrade_cpool() {
+                    Some(source_root) => source_root,
+                    None => return None,
+                };
+                let mut caps: GrantedCaps = [None, None, None, None];
+                for i in 0..MESSAGE_CAPS {
+                    if let Some(caddr) = transfer.caps[i] {
+                        let rights = source_root.lookup_rights(caddr);
+                        let obj = source_root.lookup_upgrade_any(caddr, RIGHTS_NONE);
+                        // Moved capabilities must be removable from
+                        // their pool.
+                        let movable = transfer.copy || source_root.lookup_is_removable(caddr);
+                        match (obj, rights) {
+                            // Send-once capabilities are only moved.
+                            (Some(obj), Some(rights)) if movable && !(transfer.copy && rights.contains(RIGHT_SEND_ONCE)) => {
+                                caps[i] = Some((obj, rights))
+                            },
+                            (obj, _) => {
+                                // Nothing is sent unless all the
+                                // capabilities are.
+                                if let Some(obj) = obj {
+                                    super::drop_any(obj);
+                                }
+                                ChannelValue::Grant(value, caps, transfer.copy).release();
+                                return None;
+                            },
+                        }
+                    }
+                }
+                if !transfer.copy {
+                    for caddr in transfer.caps.iter().filter_map(|caddr| *caddr) {
+                        source_root.lookup_remove(caddr);
+                    }
+                }
+                Some(ChannelValue::Grant(value, caps, transfer.copy))
+            },
+            ChannelMessage::Lend(value, FrameLoan { frames: Some(caddr), writable, .. }) => {
+                let source_root = match source_root.read().upgrade_cpool() {
+                    Some(source_root) => source_root,
+                    None => return None,
+                };
+                let rights = if writable {
+                    RIGHT_MAP | RIGHT_READ | RIGHT_WRITE
+                } else {
+                    RIGHT_MAP | RIGHT_READ
+                };
+                let frames: Option<SharedFrameSetCap> = source_root.lookup_upgrade(caddr, rights);
+                frames.map(|frames| ChannelValue::Lend(value, frames, writable))
+            },
+            ChannelMessage::Lend(_, FrameLoan { frames: None, .. }) => None,
             ChannelMessage::Payload => {
-                let source_root = source_root.read().upgrade_buffer().unwrap();
-                Some(ChannelValue::Payload(source_root))
-            }
+                let source_buffer = match source_root.read().upgrade_buffer() {
+                    Some(source_buffer) => source_buffer,
+                    None => return None,
+                };
+                let fits = source_buffer.read().read().payload_length <= MESSAGE_LENGTH_MAX;
+                if fits { Some(ChannelValue::Payload(source_buffer)) } else { None }
+            },
+            // Only the kernel reports faults, debug events and notifications.
+            ChannelMessage::PageFault(_) | ChannelMessage::Fault(_) |
+            ChannelMessage::Debug(_) | ChannelMessage::Notification(_) => None,
         }
     }
 
     pub fn to_message(value: ChannelValue, target_root: TaskCap) -> ChannelMessage {
         match value {
             ChannelValue::Raw(value) => ChannelMessage::Raw(value),
-            ChannelValue::Cap(arc) => {
-                let target_root = target_root.read().upgrade_cpool().unwrap();
-                let target_desc = target_root.read();
-                let index = target_desc.downgrade_any_free(arc);
-                ChannelMessage::Cap(index.map(|i| { CAddr::from(i as u8) }))
+            ChannelValue::Cap(arc, rights) => {
+                let target_root = target_root.read().upgrade_cpool();
+                match target_root {
+                    Some(target_root) => {
+                        let target_desc = target_root.read();
+                        let index = target_desc.downgrade_any_free(arc);
+                        if let Some(index) = index {
+                            target_desc.set_rights(index, rights);
+                        }
+                        ChannelMessage::Cap(index.map(|i| { CAddr::from(i as u8) }))
+                    },
+                    // The root pool was revoked.
+                    None => {
+                        super::drop_any(arc);
+                        ChannelMessage::Cap(None)
+                    },
+                }
+            },
+            ChannelValue::Grant(value, mut caps, copy) => {
+                let window = target_root.read().receive_window();
+                let target_root = target_root.read().upgrade_cpool();
+                let mut received = [None; MESSAGE_CAPS];
+                for i in 0..MESSAGE_CAPS {
+                    if let Some((arc, rights)) = caps[i].take() {
+                        let slot = window.and_then(|window| window.offset(i as u8));
+                        match (target_root.as_ref(), slot) {
+                            (Some(target_root), Some(slot)) if target_root.lookup_is_free(slot) => {
+                                target_root.lookup_downgrade_any_at(arc, slot, rights);
+                                received[i] = Some(slot);
+                            },
+                            // No window, or the entry is in use.
+                            _ => super::drop_any(arc),
+                        }
+                    }
+                }
+                ChannelMessage::Grant(value, CapTransfer { caps: received, copy: copy })
+            },
+            // Frames are only lent along a call, whose reply object
+            // maps them. See `ReplyCap::lend`.
+            ChannelValue::Lend(value, _, writable) => {
+                ChannelMessage::Lend(value, FrameLoan { frames: None, writable: writable, address: None })
             },
             ChannelValue::Payload(buffer_cap) => {
                 let source_buffer = buffer_cap.read().read();
-                let mut target_buffer_cap = target_root.read().upgrade_buffer().unwrap();
-                let mut target_buffer = target_buffer_cap.write().write();
-                target_buffer.payload_length = source_buffer.payload_length;
-                for i in 0..source_buffer.payload_length {
-                    target_buffer.payload_data[i] = source_buffer.payload_data[i];
+                // The length is checked again, as the sender may have
+                // changed it since the message was sent.
+                let length = source_buffer.payload_length;
+                let target_buffer_cap = target_root.read().upgrade_buffer();
+                if let Some(mut target_buffer_cap) = target_buffer_cap {
+                    if !target_buffer_cap.ptr_eq(&buffer_cap) {
+                        let mut target_buffer = target_buffer_cap.write().write();
+                        if length <= MESSAGE_LENGTH_MAX {
+                            target_buffer.payload_length = length;
+                            target_buffer.payload_data[..length]
+                                .copy_from_slice(&source_buffer.payload_data[..length]);
+                        } else {
+                            target_buffer.payload_length = 0;
+                        }
+                    }
                 }
                 ChannelMessage::Payload
-            }
+            },
+            ChannelValue::PageFault(info) => ChannelMessage::PageFault(info),
+            ChannelValue::Fault(info) => ChannelMessage::Fault(info),
+            ChannelValue::Debug(event) => ChannelMessage::Debug(event),
+        }
+    }
+
+    /// Drop the capabilities held by a value that is never received.
+    pub fn release(self) {
+        match self {
+            ChannelValue::Cap(any, _) => super::drop_any(any),
+            ChannelValue::Grant(_, mut caps, _) => {
+                for i in 0..MESSAGE_CAPS {
+                    if let Some((any, _)) = caps[i].take() {
+                        super::drop_any(any);
+                    }
+                }
+            },
+            _ => (),
         }
     }
 }
@@ -60,6 +207,13 @@ impl ChannelValue {
 #[derive(Debug)]
 pub struct ChannelDescriptor {
     value: Option<ChannelValue>,
+    /// Scheduling context donated along with the value.
+    donation: Option<SchedContextCap>,
+    /// Whether the channel was torn down, so that no value will ever
+    /// be put to it again.
+    closed: bool,
+    /// Address of the channel, that tasks waiting on it are woken by.
+    paddr: PAddr,
     next: Option<ManagedArcAny>,
 }
 /// Channel capability. Reference-counted smart pointer to channel
@@ -78,6 +232,9 @@ impl ChannelCap {
             arc = Some(
                 Self::new(paddr, RwLock::new(ChannelDescriptor {
                     value: None,
+                    donation: None,
+                    closed: false,
+                    paddr: paddr,
                     next: next_child,
                 }))
             );
@@ -92,7 +249,33 @@ impl ChannelCap {
 impl ChannelDescriptor {
     /// Put a value to the channel.
     pub fn put(&mut self, value: ChannelValue) {
+        if let Some(old) = self.value.take() {
+            old.release();
+        }
+        if let Some(old) = self.donation.take() {
+            old.give_back();
+        }
         self.value = Some(value);
+        wake_waiters(self.paddr);
+    }
+
+    /// Put a value to the channel, along with a scheduling context
+    /// donated by the sender. The task taking the value runs on it.
+    pub fn put_donation(&mut self, value: ChannelValue, sched_context: SchedContextCap) {
+        self.put(value);
+        self.donation = Some(sched_context);
+    }
+
+    /// Whether the value in the channel comes with a donated
+    /// scheduling context.
+    pub fn has_donation(&self) -> bool {
+        self.donation.is_some()
+    }
+
+    /// Take the scheduling context donated along with the value last
+    /// taken.
+    pub fn take_donation(&mut self) -> Option<SchedContextCap> {
+        self.donation.take()
     }
 
     /// Take a value from the channel. If there's no value in the
@@ -100,4 +283,31 @@ impl ChannelDescriptor {
     pub fn take(&mut self) -> Option<ChannelValue> {
         self.value.take()
     }
+
+    /// Whether the channel was torn down. Tasks waiting on it can
+    /// never take a value.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Derived for ChannelDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &ChannelCap) {
+        let (value, donation) = {
+            let mut desc = arc.write();
+            desc.closed = true;
+            (desc.take(), desc.take_donation())
+        };
+        if let Some(value) = value {
+            value.release();
+        }
+        if let Some(donation) = donation {
+            donation.give_back();
+        }
+        wake_waiters(arc.ptr());
+    }
 }
@@ -1,23 +1,46 @@
 use common::*;
 use core::any::Any;
-use core::ops::Deref;
-use util::RwLock;
+use core::fmt;
+use util::{Mutex, RwLock};
 use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool256Arc};
+use abi::CapError;
 
-use super::{UntypedDescriptor};
+use super::{UntypedDescriptor, Derived, record_call_error};
+
+/// Largest number of entries of a capability pool.
+pub const CPOOL_MAX_SIZE: usize = 256;
+
+/// Rights of each entry of a capability pool.
+struct EntryRights([CapRights; CPOOL_MAX_SIZE]);
+
+impl fmt::Debug for EntryRights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EntryRights")
+    }
+}
 
 /// Capability pool descriptor.
 #[derive(Debug)]
 pub struct CPoolDescriptor {
     weak_pool: ManagedWeakPool256Arc,
+    /// Rights of the capability in each entry.
+    rights: Mutex<EntryRights>,
+    /// Number of usable entries, from index 0.
+    size: usize,
+    /// Path a capability address must go through before indexing this
+    /// pool.
+    guard: CAddr,
     next: Option<ManagedArcAny>,
 }
 /// Capability pool capability. Reference-counted smart pointer to
 /// capability pool descriptor. Capability pool itself is a
-/// `ManagedWeakPool` with 256 entries.
+/// `ManagedWeakPool` with up to 256 entries.
 ///
 /// Capability pool capability is used to hold multiple capabilities
-/// together so as to be addressable in user-space programs.
+/// together so as to be addressable in user-space programs. Pools hold
+/// other pools, and capability addresses are paths through them: each
+/// pool first strips its guard from the path, and then takes the next
+/// byte as the index of an entry.
 pub type CPoolCap = ManagedArc<RwLock<CPoolDescriptor>>;
 
 fn downgrade_at_owning<T: Any>(arc: ManagedArc<T>, index: usize, desc: &CPoolDescriptor)
@@ -45,9 +68,10 @@ impl CPoolDescriptor {
     }
 
     /// Downgrade a capability into the capability pool (weak pool) at
-    /// a specified index.
+    /// a specified index, with all rights.
     pub fn downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, index: usize)
         where ManagedArc<T>: Any {
+        self.rights.lock().0[index] = RIGHTS_ALL;
         self.weak_pool.read().downgrade_at(arc, index)
     }
 
@@ -55,7 +79,11 @@ impl CPoolDescriptor {
     /// a free index.
     pub fn downgrade_free<T: Any>(&self, arc: &ManagedArc<T>) -> Option<usize>
         where ManagedArc<T>: Any {
-        self.weak_pool.read().downgrade_free(arc)
+        let index = (0..self.size).find(|index| self.is_free(*index));
+        if let Some(index) = index {
+            self.downgrade_at(arc, index);
+        }
+        index
     }
 
     /// Downgrade a `ManagedArcAny` into the capability pool (weak
@@ -70,16 +98,61 @@ impl CPoolDescriptor {
         doto_any!(arc, downgrade_free_owning, self)
     }
 
+    /// Rights of the capability at `index`.
+    pub fn rights(&self, index: usize) -> CapRights {
+        self.rights.lock().0[index]
+    }
+
+    /// Set the rights of the capability at `index`. Callers only ever
+    /// pass rights within those of the capability it was copied from.
+    pub fn set_rights(&self, index: usize, rights: CapRights) {
+        self.rights.lock().0[index] = rights;
+    }
+
+    /// Remove the capability at `index`. Returns `false` if the entry
+    /// is empty.
+    pub fn remove(&self, index: usize) -> bool {
+        self.weak_pool.read().remove(index)
+    }
+
+    /// Whether the entry at `index` is empty.
+    pub fn is_free(&self, index: usize) -> bool {
+        !self.weak_pool.read().is_occupied(index)
+    }
+
     /// Size of the capability pool.
     pub fn size(&self) -> usize {
-        256
+        self.size
+    }
+
+    /// Guard of the capability pool.
+    pub fn guard(&self) -> CAddr {
+        self.guard
+    }
+}
+
+impl Derived for CPoolDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &CPoolCap) {
+        arc.read().weak_pool.read().clear();
     }
 }
 
 impl CPoolCap {
     /// Create a capability pool capability from an untyped
-    /// capability.
+    /// capability, with all entries usable and no guard.
     pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        Self::retype_guarded_from(untyped, CPOOL_MAX_SIZE, CAddr::empty())
+    }
+
+    /// Create a capability pool capability from an untyped capability,
+    /// with `size` usable entries, at most `CPOOL_MAX_SIZE`, and
+    /// `guard`.
+    pub fn retype_guarded_from(untyped: &mut UntypedDescriptor, size: usize, guard: CAddr) -> Self {
+        assert!(size <= CPOOL_MAX_SIZE);
         let mut arc: Option<Self> = None;
 
         let weak_pool = unsafe { ManagedWeakPool256Arc::create(
@@ -90,6 +163,9 @@ impl CPoolCap {
             arc = Some(
                 Self::new(paddr, RwLock::new(CPoolDescriptor {
                     weak_pool: weak_pool,
+                    rights: Mutex::new(EntryRights([RIGHTS_ALL; CPOOL_MAX_SIZE])),
+                    size: size,
+                    guard: guard,
                     next: next_child,
                 }))
             );
@@ -100,58 +176,248 @@ impl CPoolCap {
         arc.unwrap()
     }
 
-    fn lookup<R, F: FnOnce(Option<(&CPoolDescriptor, usize)>) -> R>(&self, caddr: CAddr, f: F) -> R {
+    /// The pool holding the entry at a capability address, and the
+    /// index of the entry in it. Holding the pool keeps the entry
+    /// addressable even if the path to it changes. `None` unless every
+    /// pool on the path can be written, so that the entry can be
+    /// changed. Failures are recorded for the running system call.
+    pub fn lookup_entry(&self, caddr: CAddr) -> Option<(CPoolCap, usize)> {
+        recorded(self.resolve(caddr).and_then(|(cpool, index, path)| {
+            if path.contains(RIGHT_WRITE) { Ok((cpool, index)) } else { Err(CapError::InsufficientRights) }
+        }))
+    }
+
+    /// Like `lookup_entry`, but returns why the address names no
+    /// entry, without recording it for the running system call, and
+    /// the rights left along the path to the entry: those of every
+    /// pool capability it goes through.
+    pub fn resolve(&self, caddr: CAddr) -> Result<(CPoolCap, usize, CapRights), CapError> {
+        self.resolve_from(caddr, RIGHTS_ALL)
+    }
+
+    fn resolve_from(&self, caddr: CAddr, path: CapRights) -> Result<(CPoolCap, usize, CapRights), CapError> {
+        let (size, guard) = {
+            let desc = self.read();
+            (desc.size(), desc.guard())
+        };
+        if caddr.1 < guard.1 {
+            return Err(CapError::Truncated);
+        }
+        let caddr = match caddr.strip_prefix(guard) {
+            Some(caddr) => caddr,
+            None => return Err(CapError::InvalidSlot),
+        };
+
         if caddr.1 == 0 {
-            f(None)
+            Err(CapError::Truncated)
+        } else if caddr.0[0] as usize >= size {
+            Err(CapError::InvalidSlot)
         } else if caddr.1 == 1 {
-            let cur_lookup_index = caddr.0[0];
-            f(Some((self.read().deref(), cur_lookup_index as usize)))
+            Ok((self.clone(), caddr.0[0] as usize, path))
         } else {
-            let cur_lookup_index = caddr.0[0];
-            let next_lookup_cpool: Option<CPoolCap> = self.read().upgrade(cur_lookup_index as usize);
-            let next_caddr = caddr << 1;
-
-            if next_lookup_cpool.is_some() {
-                let next_lookup_cpool = next_lookup_cpool.unwrap();
-                next_lookup_cpool.lookup::<R, F>(next_caddr, f)
-            } else {
-                f(None)
+            let index = caddr.0[0] as usize;
+            let next_lookup_cpool: Option<CPoolCap> = self.read().upgrade(index);
+            match next_lookup_cpool {
+                Some(next_lookup_cpool) => {
+                    let path = path.intersect(self.read().rights(index));
+                    next_lookup_cpool.resolve_from(caddr << 1, path)
+                },
+                None if self.read().is_free(index) => Err(CapError::DeletedObject),
+                None => Err(CapError::TypeMismatch),
             }
         }
     }
 
-    /// Lookup upgrading a capability from a capability address to a `ManagedArcAny`.
-    pub fn lookup_upgrade_any(&self, caddr: CAddr) -> Option<ManagedArcAny> {
-        self.lookup(caddr, |data| {
-            data.map_or(None, |(cpool, index)| {
-                cpool.upgrade_any(index)
-            })
-        })
+    /// The entry at a capability address, if every pool on the path
+    /// can be written.
+    fn lookup_writable(&self, caddr: CAddr) -> Option<(CPoolCap, usize)> {
+        match self.resolve(caddr) {
+            Ok((cpool, index, path)) if path.contains(RIGHT_WRITE) => Some((cpool, index)),
+            _ => None,
+        }
+    }
+
+    /// Whether a capability address names an empty entry that can be
+    /// filled: every pool on the path can be written.
+    pub fn lookup_is_free(&self, caddr: CAddr) -> bool {
+        self.lookup_writable(caddr).map_or(false, |(cpool, index)| cpool.read().is_free(index))
+    }
+
+    /// Whether the capability at a capability address can be removed:
+    /// the entry is not empty, and every pool on the path can be
+    /// written.
+    pub fn lookup_is_removable(&self, caddr: CAddr) -> bool {
+        self.lookup_writable(caddr).map_or(false, |(cpool, index)| !cpool.read().is_free(index))
+    }
+
+    /// Remove the capability at a capability address. Returns `false`
+    /// if the entry is empty, or a pool on the path cannot be written.
+    pub fn lookup_remove(&self, caddr: CAddr) -> bool {
+        self.lookup_writable(caddr).map_or(false, |(cpool, index)| cpool.read().remove(index))
+    }
+
+    /// Remove the capability at a capability address if it is
+    /// send-once, after a send through it succeeded.
+    pub fn lookup_consume_once(&self, caddr: CAddr) {
+        if self.lookup_rights(caddr).map_or(false, |rights| rights.contains(RIGHT_SEND_ONCE)) {
+            self.lookup_remove(caddr);
+        }
+    }
+
+    /// Rights of the capability at a capability address, or `None`
+    /// if the entry is empty. Those are the rights of the entry, less
+    /// any the pools on the path lack.
+    pub fn lookup_rights(&self, caddr: CAddr) -> Option<CapRights> {
+        match self.resolve(caddr) {
+            Ok((cpool, index, path)) => {
+                let desc = cpool.read();
+                if desc.is_free(index) { None } else { Some(within_path(desc.rights(index), path)) }
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Lookup upgrading a capability from a capability address to a
+    /// `ManagedArcAny`. `None` if the capability lacks any of
+    /// `rights`. Failures are recorded for the running system call.
+    pub fn lookup_upgrade_any(&self, caddr: CAddr, rights: CapRights) -> Option<ManagedArcAny> {
+        recorded(self.lookup_checked_any(caddr, rights))
+    }
+
+    /// Lookup upgrading a capability from a capability address. `None`
+    /// if the capability lacks any of `rights`. Failures are recorded
+    /// for the running system call.
+    pub fn lookup_upgrade<T: Any>(&self, caddr: CAddr, rights: CapRights) -> Option<ManagedArc<T>> {
+        recorded(self.lookup_checked(caddr, rights))
     }
 
-    /// Lookup upgrading a capability from a capability address.
-    pub fn lookup_upgrade<T: Any>(&self, caddr: CAddr) -> Option<ManagedArc<T>> {
-        self.lookup(caddr, |data| {
-            data.map_or(None, |(cpool, index)| {
-                cpool.upgrade(index)
-            })
-        })
+    /// Like `lookup_upgrade_any`, but returns why the capability
+    /// cannot be used, without recording it.
+    pub fn lookup_checked_any(&self, caddr: CAddr, rights: CapRights) -> Result<ManagedArcAny, CapError> {
+        let (cpool, index, path) = match self.resolve(caddr) {
+            Ok(entry) => entry,
+            Err(error) => return Err(error),
+        };
+
+        let desc = cpool.read();
+        let result = if desc.is_free(index) {
+            Err(CapError::DeletedObject)
+        } else if !within_path(desc.rights(index), path).contains(rights) {
+            Err(CapError::InsufficientRights)
+        } else {
+            desc.upgrade_any(index).ok_or(CapError::DeletedObject)
+        };
+        result
+    }
+
+    /// Like `lookup_upgrade`, but returns why the capability cannot
+    /// be used, without recording it. Used where a capability of
+    /// either of two types is accepted.
+    pub fn lookup_checked<T: Any>(&self, caddr: CAddr, rights: CapRights) -> Result<ManagedArc<T>, CapError> {
+        let (cpool, index, path) = match self.resolve(caddr) {
+            Ok(entry) => entry,
+            Err(error) => return Err(error),
+        };
+
+        let desc = cpool.read();
+        let result = if desc.is_free(index) {
+            Err(CapError::DeletedObject)
+        } else if !within_path(desc.rights(index), path).contains(rights) {
+            Err(CapError::InsufficientRights)
+        } else {
+            desc.upgrade(index).ok_or(CapError::TypeMismatch)
+        };
+        result
     }
 
-    /// Downgrade a capability into the capability pool at a specified capability address.
-    pub fn lookup_downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, caddr: CAddr)
+    /// Downgrade a capability into the empty entry at a specified
+    /// capability address. Returns `false`, leaving the entry as it
+    /// is, if it is not empty or cannot be filled.
+    pub fn lookup_downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, caddr: CAddr) -> bool
         where ManagedArc<T>: Any {
-        self.lookup(caddr, |data| {
-            let (cpool, index) = data.unwrap();
-            cpool.downgrade_at(arc, index);
-        });
-    }
-
-    /// Downgrade a `ManagedArcAny` into the capability pool at a specified capability address.
-    pub fn lookup_downgrade_any_at<T: Any>(&self, arc: ManagedArcAny, caddr: CAddr) {
-        self.lookup(caddr, |data| {
-            let (cpool, index) = data.unwrap();
-            cpool.downgrade_any_at(arc, index);
-        });
+        match self.lookup_writable(caddr) {
+            Some((cpool, index)) if cpool.read().is_free(index) => {
+                cpool.read().downgrade_at(arc, index);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Downgrade a `ManagedArcAny` into the capability pool at a
+    /// specified capability address, with `rights`. The entry must be
+    /// one `lookup_is_free` accepts.
+    pub fn lookup_downgrade_any_at(&self, arc: ManagedArcAny, caddr: CAddr, rights: CapRights) {
+        let (cpool, index) = self.lookup_writable(caddr).unwrap();
+        let desc = cpool.read();
+        desc.downgrade_any_at(arc, index);
+        desc.set_rights(index, rights);
+    }
+
+    /// Call `f` with the address, the capability and the rights of
+    /// every capability reachable from this pool, depth first. Pools
+    /// already on the path, like a pool holding itself, are not
+    /// walked again, nor are pools too deep to be addressed.
+    pub fn walk<F: FnMut(CAddr, &ManagedArcAny, CapRights)>(&self, f: &mut F) {
+        let mut path = [PAddr::from(0: usize); 8];
+        path[0] = self.ptr();
+        self.walk_from(CAddr::empty(), RIGHTS_ALL, &mut path, 0, f);
+    }
+
+    fn walk_from<F: FnMut(CAddr, &ManagedArcAny, CapRights)>(&self, prefix: CAddr, path_rights: CapRights,
+                                                             path: &mut [PAddr; 8], depth: usize, f: &mut F) {
+        let (size, guard) = {
+            let desc = self.read();
+            (desc.size(), desc.guard())
+        };
+        let base = match prefix.join(guard) {
+            Some(base) => base,
+            None => return,
+        };
+
+        for index in 0..size {
+            let caddr = match base.join(CAddr::from(index as u8)) {
+                Some(caddr) => caddr,
+                None => return,
+            };
+            let any = self.read().upgrade_any(index);
+            let rights = within_path(self.read().rights(index), path_rights);
+            let any = match any {
+                Some(any) => any,
+                None => continue,
+            };
+
+            f(caddr, &any, rights);
+
+            if any.is::<CPoolCap>() {
+                let child: CPoolCap = any.into();
+                let ptr = child.ptr();
+                if depth + 1 < path.len() && !path[..(depth + 1)].contains(&ptr) {
+                    path[depth + 1] = ptr;
+                    child.walk_from(caddr, path_rights.intersect(rights), path, depth + 1, f);
+                }
+            } else {
+                super::drop_any(any);
+            }
+        }
+    }
+}
+
+/// Rights of an entry, less those the pools on the path to it lack.
+/// Send-once is an attribute of the entry rather than a right, and is
+/// kept.
+pub fn within_path(rights: CapRights, path: CapRights) -> CapRights {
+    rights.intersect(path | RIGHT_SEND_ONCE)
+}
+
+/// The value of `result`, recording its error for the running system
+/// call.
+fn recorded<T>(result: Result<T, CapError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(error) => {
+            record_call_error(error);
+            None
+        },
     }
 }
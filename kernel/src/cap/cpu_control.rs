@@ -0,0 +1,87 @@
+use arch;
+use timer_wheel;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, Derived};
+use super::irq::migrate_irq_handlers;
+
+/// CPU control descriptor.
+#[derive(Debug)]
+pub struct CpuControlDescriptor {
+    next: Option<ManagedArcAny>,
+}
+
+/// CPU control capability. Reference-counted smart pointer to CPU
+/// control descriptor.
+///
+/// There is only one, given to the initial task. Its holder can park
+/// secondary CPUs, which then run no task and wait in their deepest
+/// C-state, and bring them back online later, for power experiments
+/// or to leave a core to a dedicated task.
+pub type CpuControlCap = ManagedArc<RwLock<CpuControlDescriptor>>;
+
+impl CpuControlCap {
+    /// Create the CPU control capability.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot.
+    pub unsafe fn bootstrap(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(CpuControlDescriptor {
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl CpuControlDescriptor {
+    /// Take the CPU with id `cpu` offline. Its IRQ handlers are routed
+    /// to the current CPU, which also takes over the timer deadline,
+    /// and the CPU is asked to pick a task again, on which it hands
+    /// its tasks over to the online CPUs and parks. Returns `false` if
+    /// the CPU is not online, or is the bootstrap or the current CPU.
+    pub fn offline(&self, cpu: usize) -> bool {
+        let current = arch::current_cpu_id();
+        if cpu == current || arch::is_bootstrap_cpu(cpu) || !arch::is_cpu_online(cpu) {
+            return false;
+        }
+        if !arch::park_cpu(cpu) {
+            return false;
+        }
+
+        migrate_irq_handlers(cpu, current);
+        timer_wheel::reprogram();
+        arch::send_reschedule(cpu);
+        true
+    }
+
+    /// Bring the CPU with id `cpu`, parked by `offline`, back online.
+    /// Waits for it to wake up and check its TSC against the current
+    /// CPU's, as the TSC may have drifted in deep C-states. It takes
+    /// tasks over from the busiest CPUs as soon as it finds itself
+    /// idle. Returns `false` if it is not parked.
+    pub fn online(&self, cpu: usize) -> bool {
+        if cpu >= arch::MAX_CPUS || !arch::unpark_cpu(cpu) {
+            return false;
+        }
+
+        arch::send_reschedule(cpu);
+        arch::tsc_sync_source(cpu);
+        true
+    }
+}
+
+impl Derived for CpuControlDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
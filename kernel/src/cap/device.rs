@@ -0,0 +1,87 @@
+use common::*;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use arch;
+use super::{UntypedDescriptor, RawPageCap, Derived, PAGE_LENGTH};
+
+/// Device untyped descriptor.
+#[derive(Debug)]
+pub struct DeviceUntypedDescriptor {
+    start_paddr: PAddr,
+    length: usize,
+    next: Option<ManagedArcAny>,
+}
+/// Device untyped capability. Reference-counted smart pointer to
+/// device untyped descriptor.
+///
+/// Device untyped memory is a reserved or memory-mapped I/O region
+/// found at boot. Unlike untyped memory, it can only be retyped into
+/// uncached device frames, and never into kernel objects. Pages the
+/// kernel drives itself, like the APICs, are never handed out.
+pub type DeviceUntypedCap = ManagedArc<RwLock<DeviceUntypedDescriptor>>;
+
+impl DeviceUntypedCap {
+    /// Bootstrap a device untyped capability from a region reported
+    /// by the boot loader.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used for device memory regions returned from
+    /// `InitInfo`.
+    pub unsafe fn bootstrap(region: MemoryRegion, untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(DeviceUntypedDescriptor {
+                    start_paddr: region.start_paddr(),
+                    length: region.length(),
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl DeviceUntypedDescriptor {
+    /// Start physical address of the device memory region.
+    pub fn start_paddr(&self) -> PAddr {
+        self.start_paddr
+    }
+
+    /// Length of the device memory region.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Number of whole pages in the region.
+    pub fn page_count(&self) -> usize {
+        self.length / PAGE_LENGTH
+    }
+
+    /// Retype the page at `index` of the region into an uncached
+    /// device frame, keeping its contents. Returns `None` if `index`
+    /// is out of range, or the kernel uses the page itself.
+    pub fn retype_frame(&self, index: usize, untyped: &mut UntypedDescriptor) -> Option<RawPageCap> {
+        if index >= self.page_count() {
+            return None;
+        }
+
+        let paddr = self.start_paddr + index * PAGE_LENGTH;
+        if arch::is_kernel_device(paddr) {
+            return None;
+        }
+
+        Some(unsafe { RawPageCap::bootstrap_uncached(paddr, untyped) })
+    }
+}
+
+impl Derived for DeviceUntypedDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
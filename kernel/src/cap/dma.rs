@@ -0,0 +1,153 @@
+use common::*;
+use core::cmp;
+use util::{RwLock, block_count};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc, ManagedWeakPool256Arc};
+use dma::DmaRegion;
+use arch::cap::PageDescriptor;
+use super::{UntypedDescriptor, QuotaCap, RawPage, RawPageCap, Derived, PAGE_LENGTH};
+
+/// Largest number of pages in a DMA buffer.
+pub const MAX_DMA_PAGES: usize = 256;
+
+/// DMA buffer descriptor.
+#[derive(Debug)]
+pub struct DmaDescriptor {
+    start_paddr: PAddr,
+    length: usize,
+    /// The buffer, until the capability is revoked.
+    region: Option<DmaRegion>,
+    /// Page capabilities handed out, by page index.
+    page_weak_pool: ManagedWeakPool256Arc,
+    /// Quota the buffer is charged to, if any.
+    quota_weak_pool: ManagedWeakPool1Arc,
+    next: Option<ManagedArcAny>,
+}
+/// DMA buffer capability. Reference-counted smart pointer to DMA
+/// buffer descriptor.
+///
+/// A DMA buffer is physically contiguous memory from the kernel's
+/// frame allocator, below an address mask, for user-space drivers.
+/// When it is revoked, its pages are unmapped everywhere, and their
+/// capabilities revoked, before it goes back to the frame allocator.
+pub type DmaCap = ManagedArc<RwLock<DmaDescriptor>>;
+
+impl DmaCap {
+    /// Allocate a zeroed DMA buffer of at least `length` bytes that
+    /// lies entirely at or below the physical address `mask`, and
+    /// create a capability for it from `untyped`. The buffer is charged
+    /// to the quota of `untyped` like memory retyped from it. Returns
+    /// `None` if `length` is larger than `MAX_DMA_PAGES` pages, the
+    /// quota has not that much left, or no suitable memory is free.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, length: usize, mask: u64) -> Option<Self> {
+        if length > MAX_DMA_PAGES * PAGE_LENGTH {
+            return None;
+        }
+
+        // The buffer comes from the frame allocator rather than from
+        // the untyped memory, so only the quota is checked, before
+        // any frame is taken.
+        let quota = untyped.quota();
+        if quota.as_ref().map_or(false, |quota| quota.remaining() < cmp::max(length, 1)) {
+            return None;
+        }
+
+        let region = match DmaRegion::allocate(length, PAGE_LENGTH, mask) {
+            Some(region) => region,
+            None => return None,
+        };
+
+        let page_weak_pool = unsafe { ManagedWeakPool256Arc::create(
+            untyped.allocate(ManagedWeakPool256Arc::inner_length(),
+                             ManagedWeakPool256Arc::inner_alignment())) };
+        let quota_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        if let Some(quota) = quota {
+            quota.charge(region.length());
+            quota_weak_pool.read().downgrade_at(&quota, 0);
+        }
+
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(DmaDescriptor {
+                    start_paddr: region.paddr(),
+                    length: region.length(),
+                    region: Some(region),
+                    page_weak_pool: page_weak_pool,
+                    quota_weak_pool: quota_weak_pool,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc
+    }
+}
+
+impl DmaDescriptor {
+    /// Start physical address of the buffer.
+    pub fn start_paddr(&self) -> PAddr {
+        self.start_paddr
+    }
+
+    /// Length of the buffer.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Number of pages in the buffer.
+    pub fn page_count(&self) -> usize {
+        block_count(self.length, PAGE_LENGTH)
+    }
+
+    /// Raw page capability for the page at `index` of the buffer,
+    /// keeping its contents. The page capability is created from
+    /// `untyped` the first time. Returns `None` if `index` is out of
+    /// range, or the buffer was revoked.
+    pub fn page(&self, index: usize, untyped: &mut UntypedDescriptor) -> Option<RawPageCap> {
+        if self.region.is_none() || index >= self.page_count() {
+            return None;
+        }
+
+        let page_weak_pool = self.page_weak_pool.read();
+        if let Some(page) = page_weak_pool.upgrade(index) {
+            return Some(page);
+        }
+
+        let page = unsafe { RawPageCap::bootstrap_device(self.start_paddr + index * PAGE_LENGTH, untyped) };
+        page_weak_pool.downgrade_at(&page, index);
+        Some(page)
+    }
+}
+
+impl Derived for DmaDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &DmaCap) {
+        let mut desc = arc.write();
+        // Devices and tasks must be done with the pages before the
+        // frames are reused: unmap them, and take every capability to
+        // them away, so that they cannot be mapped again.
+        for index in 0..desc.page_count() {
+            let page: Option<RawPageCap> = desc.page_weak_pool.read().upgrade(index);
+            if let Some(page) = page {
+                page.remove_weak_all();
+                PageDescriptor::<RawPage>::revoke(&page);
+            }
+        }
+        desc.page_weak_pool.read().clear();
+        if let Some(region) = desc.region.take() {
+            let quota: Option<QuotaCap> = desc.quota_weak_pool.read().upgrade(0);
+            if let Some(quota) = quota {
+                quota.uncharge(region.length());
+            }
+            desc.quota_weak_pool.read().clear();
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc, ManagedWeakPool256Arc};
+use abi::{SystemCall, ChannelMessage, IpcKind};
+use common::*;
+use super::{UntypedDescriptor, TaskCap, TaskStatus, ChannelValue, ReplyCap, TaskBufferPageCap, Derived, wake_waiters};
+
+/// Number of tasks that can be blocked sending to an endpoint at once.
+const SENDER_QUEUE_LENGTH: usize = 256;
+
+/// Endpoint descriptor.
+#[derive(Debug)]
+pub struct EndpointDescriptor {
+    /// Tasks blocked sending to the endpoint, or calling it.
+    sender_weak_pool: ManagedWeakPool256Arc,
+    /// Ticket of the last task that blocked sending. Senders are
+    /// received in the order of their tickets.
+    last_ticket: u64,
+    /// Last ticket when the queue was found empty. It stays empty
+    /// until the next sender blocks.
+    empty_ticket: u64,
+    /// Task that last blocked receiving from the endpoint.
+    receiver_weak_pool: ManagedWeakPool1Arc,
+    /// Whether a receiver taking a call inherits the priority of the
+    /// caller, if higher, until it replies.
+    inherit: bool,
+    /// Whether the endpoint was torn down, so that no task will ever
+    /// send to it again.
+    closed: bool,
+    next: Option<ManagedArcAny>,
+}
+
+/// Endpoint capability. Reference-counted smart pointer to endpoint
+/// descriptor.
+///
+/// Unlike a channel, an endpoint holds no message: a sender blocks
+/// until a receiver takes its message from its task buffer, and
+/// senders are received by priority, and in the order they blocked
+/// among those of the same priority. A sender that
+/// calls the endpoint then waits for a reply, sent through the reply
+/// object the receiver passed.
+pub type EndpointCap = ManagedArc<RwLock<EndpointDescriptor>>;
+
+impl EndpointCap {
+    /// Create an endpoint capability from an untyped capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let sender_weak_pool = unsafe { ManagedWeakPool256Arc::create(
+            untyped.allocate(ManagedWeakPool256Arc::inner_length(),
+                             ManagedWeakPool256Arc::inner_alignment())) };
+        let receiver_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(EndpointDescriptor {
+                    sender_weak_pool: sender_weak_pool,
+                    last_ticket: 0,
+                    empty_ticket: 0,
+                    receiver_weak_pool: receiver_weak_pool,
+                    inherit: false,
+                    closed: false,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+
+    /// Block `sender` sending the message of its task buffer to the
+    /// endpoint, behind the senders already blocked. Returns `false`
+    /// if the endpoint is closed, or its queue full.
+    pub fn send(&self, sender: &TaskCap) -> bool {
+        let mut desc = self.write();
+        if desc.closed || desc.sender_weak_pool.read().downgrade_free(sender).is_none() {
+            return false;
+        }
+        desc.last_ticket += 1;
+        sender.write().set_status(TaskStatus::EndpointSend(self.clone(), desc.last_ticket));
+        wake_waiters(self.ptr());
+        true
+    }
+
+    /// Block `receiver` receiving from the endpoint.
+    pub fn wait(&self, receiver: &TaskCap) {
+        let desc = self.read();
+        let pool = desc.receiver_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(receiver, 0);
+        receiver.write().set_status(TaskStatus::EndpointReceive(self.clone()));
+    }
+
+    /// The task blocked receiving from the endpoint, if no sender is
+    /// queued ahead of a new one, so that a call can go to it directly.
+    pub fn waiting_receiver(&self) -> Option<TaskCap> {
+        let desc = self.read();
+        if desc.closed || desc.empty_ticket != desc.last_ticket {
+            return None;
+        }
+        let receiver: Option<TaskCap> = desc.receiver_weak_pool.read().upgrade(0);
+        receiver.and_then(|receiver| {
+            let waiting = match receiver.read().status() {
+                TaskStatus::EndpointReceive(ref endpoint) => endpoint.ptr_eq(self),
+                _ => false,
+            };
+            if waiting { Some(receiver) } else { None }
+        })
+    }
+
+    /// Remove the sender with the highest priority from the queue,
+    /// the one with the lowest ticket among those of that priority.
+    fn dequeue(&self) -> Option<TaskCap> {
+        let desc = self.write();
+        let mut first: Option<(usize, u8, u64, TaskCap)> = None;
+        for index in 0..SENDER_QUEUE_LENGTH {
+            let sender: Option<TaskCap> = desc.sender_weak_pool.read().upgrade(index);
+            let sender = match sender {
+                Some(sender) => sender,
+                None => continue,
+            };
+            let ticket = match sender.read().status() {
+                TaskStatus::EndpointSend(ref endpoint, ticket) if endpoint.ptr_eq(self) => ticket,
+                // The sender was stopped, or set active, since.
+                _ => {
+                    desc.sender_weak_pool.read().remove(index);
+                    continue;
+                },
+            };
+            let priority = sender.read().priority();
+            let earlier = match first {
+                Some((_, first_priority, first_ticket, _)) => {
+                    priority > first_priority || (priority == first_priority && ticket < first_ticket)
+                },
+                None => true,
+            };
+            if earlier {
+                first = Some((index, priority, ticket, sender));
+            }
+        }
+
+        first.map(|(index, _, _, sender)| {
+            desc.sender_weak_pool.read().remove(index);
+            sender
+        })
+    }
+
+    /// Take the message of the first blocked sender, for `receiver`.
+    /// A sender that called the endpoint is bound to `reply`, and
+    /// waits for the reply; without a reply object to bind, its call
+    /// fails. With inheritance, `receiver` runs at the caller's
+    /// priority until the reply. Returns `None` if no sender has a
+    /// message to take.
+    pub fn receive(&self, receiver: &TaskCap, reply: Option<&ReplyCap>) -> Option<ChannelMessage> {
+        while let Some(sender) = self.dequeue() {
+            let buffer_cap = sender.read().upgrade_buffer();
+            let buffer_cap = match buffer_cap {
+                Some(buffer_cap) => buffer_cap,
+                None => {
+                    sender.write().set_status(TaskStatus::Inactive);
+                    continue;
+                },
+            };
+            let call = {
+                let buffer_desc = buffer_cap.read();
+                let buffer = buffer_desc.read();
+                buffer.call.clone()
+            };
+
+            match call {
+                Some(SystemCall::EndpointSend { request }) => {
+                    let length = if ::ipc_trace::is_enabled() {
+                        ::ipc_trace::message_length(&request.1, &sender)
+                    } else {
+                        0
+                    };
+                    let value = ChannelValue::from_message(request.1, sender.clone());
+                    sender.write().set_status(TaskStatus::Active);
+                    if let Some(value) = value {
+                        self.consume_once(&sender, request.0);
+                        if ::ipc_trace::is_enabled() {
+                            ::ipc_trace::record(IpcKind::Send, Some(&sender), Some(receiver),
+                                                self.ptr().into(): u64, 0, length, false);
+                        }
+                        return Some(ChannelValue::to_message(value, receiver.clone()));
+                    }
+                },
+                Some(SystemCall::EndpointCall { request, .. }) => {
+                    let value = ChannelValue::from_message(request.1.clone(), sender.clone());
+                    match (value, reply) {
+                        (Some(value), Some(reply)) if reply.bind(&sender, receiver) => {
+                            self.consume_once(&sender, request.0);
+                            sender.write().set_status(TaskStatus::ReplyWait(reply.clone()));
+                            if ::ipc_trace::is_enabled() {
+                                let length = ::ipc_trace::message_length(&request.1, &sender);
+                                ::ipc_trace::record(IpcKind::Call, Some(&sender), Some(receiver),
+                                                    self.ptr().into(): u64, 0, length, false);
+                            }
+                            if self.read().inherit {
+                                let priority = sender.read().priority();
+                                reply.inherit(receiver, priority);
+                            }
+                            return Some(match value {
+                                ChannelValue::Lend(value, frames, writable) => reply.lend(value, frames, writable),
+                                value => ChannelValue::to_message(value, receiver.clone()),
+                            });
+                        },
+                        (value, _) => {
+                            if let Some(value) = value {
+                                value.release();
+                            }
+                            {
+                                let mut buffer_desc = buffer_cap.write();
+                                let mut buffer = buffer_desc.write();
+                                buffer.call = Some(SystemCall::EndpointCall {
+                                    request: request,
+                                    response: None,
+                                });
+                            }
+                            sender.write().set_status(TaskStatus::Active);
+                        },
+                    }
+                },
+                Some(SystemCall::EndpointForward { request, .. }) => {
+                    let message = self.forward(&sender, &buffer_cap, request, receiver, reply);
+                    if message.is_some() {
+                        return message;
+                    }
+                },
+                _ => sender.write().set_status(TaskStatus::Inactive),
+            }
+        }
+
+        let mut desc = self.write();
+        desc.empty_ticket = desc.last_ticket;
+        None
+    }
+
+    /// Remove the capability at `caddr` `sender` sent through, if it
+    /// is send-once and still refers to the endpoint, now that the
+    /// message is delivered. Until then it stays, so that a send that
+    /// never reaches a receiver does not use it up.
+    fn consume_once(&self, sender: &TaskCap, caddr: CAddr) {
+        if let Some(cpool) = sender.read().upgrade_cpool() {
+            let endpoint: Option<EndpointCap> = cpool.lookup_checked(caddr, RIGHTS_NONE).ok();
+            if endpoint.map_or(false, |endpoint| endpoint.ptr_eq(self)) {
+                cpool.lookup_consume_once(caddr);
+            }
+        }
+    }
+
+    /// Take the message `sender` forwards along the call bound to one
+    /// of its reply objects. The caller is moved to `reply`, so that
+    /// `receiver` replies to it directly, and `sender` runs again.
+    /// Returns `None` if the call could not be forwarded, which the
+    /// sender learns.
+    fn forward(&self, sender: &TaskCap, buffer_cap: &TaskBufferPageCap, request: (CAddr, CAddr, ChannelMessage),
+               receiver: &TaskCap, reply: Option<&ReplyCap>) -> Option<ChannelMessage> {
+        let endpoint = request.1;
+        let forwarded: Option<ReplyCap> = sender.read().upgrade_cpool()
+            .and_then(|cpool| cpool.lookup_upgrade(request.0, RIGHT_WRITE));
+        let length = if ::ipc_trace::is_enabled() {
+            ::ipc_trace::message_length(&request.2, sender)
+        } else {
+            0
+        };
+        let value = ChannelValue::from_message(request.2.clone(), sender.clone());
+        let moved = match (value.is_some(), forwarded, reply) {
+            (true, Some(forwarded), Some(reply)) => forwarded.forward(reply, receiver),
+            _ => false,
+        };
+
+        {
+            let mut buffer_desc = buffer_cap.write();
+            let mut buffer = buffer_desc.write();
+            buffer.call = Some(SystemCall::EndpointForward {
+                request: request,
+                response: Some(moved),
+            });
+        }
+        sender.write().set_status(TaskStatus::Active);
+
+        let (value, reply) = match (value, reply) {
+            (Some(value), Some(reply)) if moved => (value, reply),
+            (value, _) => {
+                if let Some(value) = value {
+                    value.release();
+                }
+                return None;
+            },
+        };
+        self.consume_once(sender, endpoint);
+        let caller = reply.caller();
+        if ::ipc_trace::is_enabled() {
+            ::ipc_trace::record(IpcKind::Forward, Some(sender), Some(receiver),
+                                self.ptr().into(): u64, 0, length, false);
+        }
+        if let (true, Some(caller)) = (self.read().inherit, caller) {
+            let priority = caller.read().priority();
+            reply.inherit(receiver, priority);
+        }
+        // Frames forwarded are passed on unmapped, as by a send.
+        Some(ChannelValue::to_message(value, receiver.clone()))
+    }
+}
+
+impl EndpointDescriptor {
+    /// Set whether receivers inherit the priority of their callers.
+    pub fn set_inheritance(&mut self, inherit: bool) {
+        self.inherit = inherit;
+    }
+
+    /// Whether the endpoint was torn down. Tasks waiting on it can
+    /// never receive a message.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Derived for EndpointDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    /// Senders still blocked stop, as tasks waiting on a closed
+    /// channel do.
+    fn revoke(arc: &EndpointCap) {
+        arc.write().closed = true;
+        while let Some(sender) = arc.dequeue() {
+            sender.write().set_status(TaskStatus::Inactive);
+        }
+        arc.read().sender_weak_pool.read().clear();
+        arc.read().receiver_weak_pool.read().clear();
+        wake_waiters(arc.ptr());
+    }
+}
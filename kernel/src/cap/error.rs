@@ -0,0 +1,31 @@
+use core::ptr;
+use arch::{self, MAX_CPUS};
+use abi::CapError;
+
+/// First capability error of the system call running on each CPU,
+/// indexed by CPU id. System calls run to completion with interrupts
+/// disabled, so only a CPU touches its own entry.
+static mut CALL_ERRORS: [Option<CapError>; MAX_CPUS] = [None; MAX_CPUS];
+
+/// Forget the capability error of the previous system call on the
+/// current CPU.
+pub fn clear_call_error() {
+    unsafe { ptr::write_volatile(&mut CALL_ERRORS[arch::current_cpu_id()], None); }
+}
+
+/// Record `error` for the system call running on the current CPU,
+/// unless an earlier one is recorded already.
+pub fn record_call_error(error: CapError) {
+    unsafe {
+        let slot = &mut CALL_ERRORS[arch::current_cpu_id()];
+        if ptr::read_volatile(slot).is_none() {
+            ptr::write_volatile(slot, Some(error));
+        }
+    }
+}
+
+/// First capability error of the system call running on the current
+/// CPU.
+pub fn call_error() -> Option<CapError> {
+    unsafe { ptr::read_volatile(&CALL_ERRORS[arch::current_cpu_id()]) }
+}
@@ -0,0 +1,133 @@
+use core::ptr;
+use util::{RwLock, MemoryObject};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use abi::IpcRingHeader;
+use super::{UntypedDescriptor, SharedFrameSetCap, NotificationCap, Derived};
+
+/// IPC ring descriptor.
+#[derive(Debug)]
+pub struct IpcRingDescriptor {
+    /// Frames of the ring: the header page, then the data pages.
+    frames_weak_pool: ManagedWeakPool1Arc,
+    /// Notification signalled to wake the consumer.
+    notification_weak_pool: ManagedWeakPool1Arc,
+    /// Badge the notification is signalled with.
+    badge: u64,
+    next: Option<ManagedArcAny>,
+}
+
+/// IPC ring capability. Reference-counted smart pointer to IPC ring
+/// descriptor.
+///
+/// An IPC ring lays an `IpcRingHeader` over the first page of a
+/// shared frame set, and takes the other pages as data. Producer and
+/// consumer map the set, and pass buffers through the ring without
+/// the kernel. The kernel only wakes the consumer: `notify` signals
+/// the bound notification if the consumer marked itself waiting.
+pub type IpcRingCap = ManagedArc<RwLock<IpcRingDescriptor>>;
+
+impl IpcRingCap {
+    /// Create an IPC ring over `frames` from an untyped capability.
+    /// Returns `None` if the set has less than two pages, or was
+    /// released.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, frames: &SharedFrameSetCap) -> Option<Self> {
+        {
+            let frames_desc = frames.read();
+            if frames_desc.page_count() < 2 || frames_desc.is_released() {
+                return None;
+            }
+        }
+
+        let mut arc: Option<Self> = None;
+
+        let frames_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        frames_weak_pool.read().downgrade_at(frames, 0);
+
+        let notification_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(IpcRingDescriptor {
+                    frames_weak_pool: frames_weak_pool,
+                    notification_weak_pool: notification_weak_pool,
+                    badge: 0,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc
+    }
+}
+
+impl IpcRingDescriptor {
+    /// Signal `notification` with `badge` to wake the consumer, or
+    /// nothing if `None`.
+    pub fn bind(&mut self, notification: Option<&NotificationCap>, badge: u64) {
+        match notification {
+            Some(notification) => {
+                let pool = self.notification_weak_pool.read();
+                pool.remove(0);
+                pool.downgrade_at(notification, 0);
+            },
+            None => self.notification_weak_pool.read().clear(),
+        }
+        self.badge = badge;
+    }
+
+    /// Wake the consumer if it marked itself waiting in the header,
+    /// clearing the mark. Returns `false` if it was not waiting, or
+    /// there is nothing to wake it with.
+    pub fn notify(&self) -> bool {
+        let frames: SharedFrameSetCap = match self.frames_weak_pool.read().upgrade(0) {
+            Some(frames) => frames,
+            None => return false,
+        };
+        let notification: NotificationCap = match self.notification_weak_pool.read().upgrade(0) {
+            Some(notification) => notification,
+            None => return false,
+        };
+
+        // The set is kept from being released while its header is
+        // read.
+        let frames_desc = frames.read();
+        if frames_desc.is_released() {
+            return false;
+        }
+        let waiting = unsafe {
+            let mut header = MemoryObject::<IpcRingHeader>::new(frames_desc.start_paddr());
+            let header = header.as_mut();
+            // The consumer sets the mark concurrently. Losing a race
+            // only costs it a spurious wakeup: the signal is kept in
+            // the notification word.
+            let waiting = ptr::read_volatile(&header.consumer_waiting) != 0;
+            if waiting {
+                ptr::write_volatile(&mut header.consumer_waiting, 0);
+            }
+            waiting
+        };
+
+        if waiting {
+            notification.write().signal(self.badge);
+        }
+        waiting
+    }
+}
+
+impl Derived for IpcRingDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &IpcRingCap) {
+        let desc = arc.read();
+        desc.frames_weak_pool.read().clear();
+        desc.notification_weak_pool.read().clear();
+    }
+}
@@ -0,0 +1,271 @@
+use core::iter::Iterator;
+use arch::{self, InterruptVector};
+use util::{RwLock, Mutex};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use super::{UntypedDescriptor, ChannelCap, ChannelValue, NotificationCap, Derived};
+
+/// IRQ handler descriptor.
+#[derive(Debug)]
+pub struct IrqHandlerDescriptor {
+    gsi: u32,
+    vector: InterruptVector,
+    cpu: usize,
+    /// Channel, or notification, interrupts are delivered to.
+    target_weak_pool: ManagedWeakPool1Arc,
+    /// Badge a bound notification is signalled with.
+    badge: u64,
+    /// Whether the line is masked until the holder acknowledges the
+    /// last interrupt.
+    pending: bool,
+    next: Option<ManagedArcAny>,
+    next_handler: Option<IrqHandlerCap>,
+}
+/// IRQ handler capability. Reference-counted smart pointer to IRQ
+/// handler descriptor.
+///
+/// The capability owns one I/O APIC input. Once bound to a channel,
+/// each interrupt on the input masks it and puts the input number to
+/// the channel. Bound to a notification instead, it signals the badge
+/// it was bound with. The input stays masked until the holder
+/// acknowledges the interrupt.
+pub type IrqHandlerCap = ManagedArc<RwLock<IrqHandlerDescriptor>>;
+
+impl IrqHandlerCap {
+    /// Route I/O APIC input `gsi` to the CPU with id `cpu`, and create
+    /// a capability for it from `untyped`. Returns `None` if the input
+    /// already has a handler, or cannot be routed.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, gsi: u32, cpu: usize) -> Option<Self> {
+        if irq_handler_iter().any(|handler| handler.read().gsi == gsi) {
+            return None;
+        }
+
+        let vector = match arch::route_irq(gsi, cpu) {
+            Some(vector) => vector,
+            None => return None,
+        };
+
+        let mut arc: Option<Self> = None;
+
+        let target_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(IrqHandlerDescriptor {
+                    gsi: gsi,
+                    vector: vector,
+                    cpu: cpu,
+                    target_weak_pool: target_weak_pool,
+                    badge: 0,
+                    pending: false,
+                    next: next_child,
+                    next_handler: None,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        register_irq_handler(arc.clone().unwrap());
+        arc
+    }
+}
+
+impl IrqHandlerDescriptor {
+    /// I/O APIC input the handler owns.
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    /// Vector the input is routed to.
+    pub fn vector(&self) -> InterruptVector {
+        self.vector
+    }
+
+    /// Id of the CPU the input is routed to.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Route the input to the CPU with id `cpu` instead. Returns
+    /// `false`, leaving it where it is, if the CPU is not online or has
+    /// no free vector.
+    pub fn set_affinity(&mut self, cpu: usize) -> bool {
+        match arch::set_irq_affinity(self.gsi, self.cpu, self.vector, cpu) {
+            Some(vector) => {
+                self.vector = vector;
+                self.cpu = cpu;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Deliver interrupts to `channel`, and unmask the input.
+    pub fn bind(&mut self, channel: &ChannelCap) {
+        {
+            let pool = self.target_weak_pool.read();
+            pool.remove(0);
+            pool.downgrade_at(channel, 0);
+        }
+        self.pending = false;
+        arch::set_irq_masked(self.gsi, false);
+    }
+
+    /// Signal interrupts to `notification` with `badge`, instead of
+    /// any channel, and unmask the input.
+    pub fn bind_notification(&mut self, notification: &NotificationCap, badge: u64) {
+        {
+            let pool = self.target_weak_pool.read();
+            pool.remove(0);
+            pool.downgrade_at(notification, 0);
+        }
+        self.badge = badge;
+        self.pending = false;
+        arch::set_irq_masked(self.gsi, false);
+    }
+
+    /// Read the channel interrupts are delivered to.
+    pub fn upgrade_channel(&self) -> Option<ChannelCap> {
+        self.target_weak_pool.read().upgrade(0)
+    }
+
+    /// Read the notification interrupts are signalled to.
+    pub fn upgrade_notification(&self) -> Option<NotificationCap> {
+        self.target_weak_pool.read().upgrade(0)
+    }
+
+    /// Acknowledge the last interrupt, unmasking the input. Returns
+    /// `false` if no interrupt is waiting to be acknowledged.
+    pub fn acknowledge(&mut self) -> bool {
+        if !self.pending {
+            return false;
+        }
+
+        self.pending = false;
+        arch::set_irq_masked(self.gsi, false);
+        true
+    }
+
+    /// Handle an interrupt on the input: mask it until it is
+    /// acknowledged, and signal the bound channel or notification.
+    fn signal(&mut self) {
+        arch::set_irq_masked(self.gsi, true);
+        self.pending = true;
+        if let Some(channel) = self.upgrade_channel() {
+            channel.write().put(ChannelValue::Raw(self.gsi as u64));
+        } else if let Some(notification) = self.upgrade_notification() {
+            notification.write().signal(self.badge);
+        }
+    }
+}
+
+impl Derived for IrqHandlerDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &IrqHandlerCap) {
+        {
+            let desc = arc.read();
+            arch::set_irq_masked(desc.gsi, true);
+            desc.target_weak_pool.read().clear();
+        }
+        unregister_irq_handler(arc);
+    }
+}
+
+/// The first IRQ handler created.
+static FIRST_IRQ_HANDLER: Mutex<Option<IrqHandlerCap>> = Mutex::new(None);
+
+/// Register a new IRQ handler. Using `FIRST_IRQ_HANDLER` static, this
+/// forms a linked-list of all created handlers.
+fn register_irq_handler(cap: IrqHandlerCap) {
+    let mut first_handler = FIRST_IRQ_HANDLER.lock();
+    if first_handler.is_none() {
+        *first_handler = Some(cap);
+    } else {
+        let mut first = first_handler.as_mut().unwrap().write();
+        let mut second = cap.write();
+        let third_handler = first.next_handler.take();
+
+        second.next_handler = third_handler;
+        first.next_handler = Some(cap.clone());
+    }
+}
+
+/// Remove an IRQ handler from the list of all handlers, if it is
+/// there. Its input can then get a new handler.
+fn unregister_irq_handler(cap: &IrqHandlerCap) {
+    let mut first_handler = FIRST_IRQ_HANDLER.lock();
+    if first_handler.as_ref().map(|first| first.ptr_eq(cap)).unwrap_or(false) {
+        *first_handler = cap.write().next_handler.take();
+        return;
+    }
+
+    let mut current = first_handler.clone();
+    while let Some(handler) = current {
+        let mut desc = handler.write();
+        if desc.next_handler.as_ref().map(|next| next.ptr_eq(cap)).unwrap_or(false) {
+            desc.next_handler = cap.write().next_handler.take();
+            return;
+        }
+        current = desc.next_handler.clone();
+    }
+}
+
+/// An IRQ handler iterator.
+struct IrqHandlerIterator {
+    next: Option<IrqHandlerCap>,
+}
+
+impl Iterator for IrqHandlerIterator {
+    type Item = IrqHandlerCap;
+
+    fn next(&mut self) -> Option<IrqHandlerCap> {
+        if let Some(current) = self.next.clone() {
+            {
+                let current_handler = current.read();
+                self.next = current_handler.next_handler.clone();
+            }
+            return Some(current);
+        } else {
+            None
+        }
+    }
+}
+
+/// Return an IRQ handler iterator using `FIRST_IRQ_HANDLER`.
+fn irq_handler_iter() -> IrqHandlerIterator {
+    IrqHandlerIterator {
+        next: FIRST_IRQ_HANDLER.lock().clone(),
+    }
+}
+
+/// Signal the IRQ handler whose input is routed to `vector` on the
+/// current CPU. Returns `false` if there is none, as for
+/// message-signaled interrupts.
+pub fn irq_notify(vector: InterruptVector) -> bool {
+    let cpu = arch::current_cpu_id();
+    for handler in irq_handler_iter() {
+        let mut handler = handler.write();
+        if handler.vector == vector && handler.cpu == cpu {
+            handler.signal();
+            return true;
+        }
+    }
+    false
+}
+
+/// Route the inputs of the IRQ handlers on the CPU with id `cpu`,
+/// taken offline, to the CPU with id `new_cpu`. Inputs that find no
+/// free vector there stay, and still wake the CPU up.
+pub fn migrate_irq_handlers(cpu: usize, new_cpu: usize) {
+    for handler in irq_handler_iter() {
+        let mut handler = handler.write();
+        if handler.cpu == cpu && !handler.set_affinity(new_cpu) {
+            log!("IRQ {} stays on offline CPU {}.", handler.gsi, cpu);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, IrqHandlerCap, MsiCap, Derived};
+
+/// IRQ control descriptor.
+#[derive(Debug)]
+pub struct IrqControlDescriptor {
+    next: Option<ManagedArcAny>,
+}
+
+/// IRQ control capability. Reference-counted smart pointer to IRQ
+/// control descriptor.
+///
+/// There is only one, given to the initial task. IRQ handler and MSI
+/// capabilities are only ever created through it, so a task handles
+/// exactly the interrupt lines and vectors it was given.
+pub type IrqControlCap = ManagedArc<RwLock<IrqControlDescriptor>>;
+
+impl IrqControlCap {
+    /// Create the IRQ control capability.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot.
+    pub unsafe fn bootstrap(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(IrqControlDescriptor {
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl IrqControlDescriptor {
+    /// Create a handler for I/O APIC input `gsi`, routed to the CPU
+    /// with id `cpu`, from `untyped`. Returns `None` if the input
+    /// already has a handler, or cannot be routed.
+    pub fn get_handler(&self, gsi: u32, cpu: usize, untyped: &mut UntypedDescriptor) -> Option<IrqHandlerCap> {
+        IrqHandlerCap::retype_from(untyped, gsi, cpu)
+    }
+
+    /// Allocate a message-signaled interrupt vector delivered to the
+    /// CPU with id `cpu`, with its capability from `untyped`. Returns
+    /// `None` if the CPU is not online, or no vector is free.
+    pub fn get_msi(&self, cpu: usize, untyped: &mut UntypedDescriptor) -> Option<MsiCap> {
+        MsiCap::retype_from(untyped, cpu)
+    }
+}
+
+impl Derived for IrqControlDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
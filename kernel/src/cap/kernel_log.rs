@@ -0,0 +1,76 @@
+use core::str;
+use logging;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, RawPageCap, Derived};
+
+/// Kernel log descriptor.
+#[derive(Debug)]
+pub struct KernelLogDescriptor {
+    next: Option<ManagedArcAny>,
+}
+
+/// Kernel log capability. Reference-counted smart pointer to kernel
+/// log descriptor.
+///
+/// Holders with the read right can read the history of the kernel
+/// log, and holders with the write right can add lines to it, tagged
+/// as coming from user-space.
+pub type KernelLogCap = ManagedArc<RwLock<KernelLogDescriptor>>;
+
+impl KernelLogCap {
+    /// Create the kernel log capability.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used at boot.
+    pub unsafe fn bootstrap(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(KernelLogDescriptor {
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl KernelLogDescriptor {
+    /// Copy the log from `position` on into `page`, as much as fits.
+    /// Returns the position of the first byte copied, later than
+    /// `position` if older bytes were overwritten, and the number of
+    /// bytes copied. `None` if `page` is device memory.
+    pub fn read(&self, position: u64, page: &RawPageCap) -> Option<(u64, usize)> {
+        let mut page_desc = page.write();
+        if page_desc.is_uncached() {
+            return None;
+        }
+
+        let mut page_data = page_desc.write();
+        Some(logging::read_history(position, &mut page_data.0))
+    }
+
+    /// Log `bytes` as a line written by user-space. Returns `false`
+    /// if they are not UTF-8.
+    pub fn write(&self, bytes: &[u8]) -> bool {
+        match str::from_utf8(bytes) {
+            Ok(text) => {
+                logging::write_user(text);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+impl Derived for KernelLogDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
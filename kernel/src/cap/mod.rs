@@ -12,6 +12,42 @@ macro_rules! doto_any {
             $f ($any.into(): ::cap::TaskBufferPageCap, $($param),*)
         } else if $any.is::<::cap::ChannelCap>() {
             $f ($any.into(): ::cap::ChannelCap, $($param),*)
+        } else if $any.is::<::cap::PmemCap>() {
+            $f ($any.into(): ::cap::PmemCap, $($param),*)
+        } else if $any.is::<::cap::DeviceUntypedCap>() {
+            $f ($any.into(): ::cap::DeviceUntypedCap, $($param),*)
+        } else if $any.is::<::cap::DmaCap>() {
+            $f ($any.into(): ::cap::DmaCap, $($param),*)
+        } else if $any.is::<::cap::SharedFrameSetCap>() {
+            $f ($any.into(): ::cap::SharedFrameSetCap, $($param),*)
+        } else if $any.is::<::cap::MsiCap>() {
+            $f ($any.into(): ::cap::MsiCap, $($param),*)
+        } else if $any.is::<::cap::IrqHandlerCap>() {
+            $f ($any.into(): ::cap::IrqHandlerCap, $($param),*)
+        } else if $any.is::<::cap::TimerCap>() {
+            $f ($any.into(): ::cap::TimerCap, $($param),*)
+        } else if $any.is::<::cap::QuotaCap>() {
+            $f ($any.into(): ::cap::QuotaCap, $($param),*)
+        } else if $any.is::<::cap::KernelLogCap>() {
+            $f ($any.into(): ::cap::KernelLogCap, $($param),*)
+        } else if $any.is::<::cap::IrqControlCap>() {
+            $f ($any.into(): ::cap::IrqControlCap, $($param),*)
+        } else if $any.is::<::cap::CpuControlCap>() {
+            $f ($any.into(): ::cap::CpuControlCap, $($param),*)
+        } else if $any.is::<::cap::BootImageCap>() {
+            $f ($any.into(): ::cap::BootImageCap, $($param),*)
+        } else if $any.is::<::cap::SchedContextCap>() {
+            $f ($any.into(): ::cap::SchedContextCap, $($param),*)
+        } else if $any.is::<::cap::EndpointCap>() {
+            $f ($any.into(): ::cap::EndpointCap, $($param),*)
+        } else if $any.is::<::cap::ReplyCap>() {
+            $f ($any.into(): ::cap::ReplyCap, $($param),*)
+        } else if $any.is::<::cap::NotificationCap>() {
+            $f ($any.into(): ::cap::NotificationCap, $($param),*)
+        } else if $any.is::<::cap::NotificationGroupCap>() {
+            $f ($any.into(): ::cap::NotificationGroupCap, $($param),*)
+        } else if $any.is::<::cap::IpcRingCap>() {
+            $f ($any.into(): ::cap::IpcRingCap, $($param),*)
         } else {
             doto_arch_any!($any, $f $(,$param)*)
         }
@@ -26,19 +62,86 @@ mod cpool;
 mod task;
 /// Channel capability implementation.
 mod channel;
+/// Persistent memory capability implementation.
+mod pmem;
+/// Device untyped capability implementation.
+mod device;
+/// DMA buffer capability implementation.
+mod dma;
+/// Shared frame set capability implementation.
+mod shared;
+/// Message-signaled interrupt capability implementation.
+mod msi;
+/// IRQ handler capability implementation.
+mod irq;
+/// IRQ control capability implementation.
+mod irq_control;
+/// CPU control capability implementation.
+mod cpu_control;
+/// Boot image capability implementation.
+mod boot_image;
+/// Timer capability implementation.
+mod timer;
+/// Quota capability implementation.
+mod quota;
+/// Kernel log capability implementation.
+mod kernel_log;
+/// Scheduling context capability implementation.
+mod sched_context;
+/// Endpoint capability implementation.
+mod endpoint;
+/// Reply capability implementation.
+mod reply;
+/// Notification capability implementation.
+mod notification;
+/// Notification group capability implementation.
+mod notification_group;
+/// IPC ring capability implementation.
+mod ipc_ring;
+/// Live object counters and capability listings.
+mod census;
+/// Capability errors of the running system call.
+mod error;
 
-pub use self::untyped::{UntypedDescriptor, UntypedCap};
-pub use self::cpool::{CPoolDescriptor, CPoolCap};
-pub use self::task::{TaskDescriptor, TaskCap, TaskStatus, idle, task_iter};
+pub use self::untyped::{UntypedDescriptor, UntypedCap, scrub_free_pages};
+pub use self::cpool::{CPoolDescriptor, CPoolCap, CPOOL_MAX_SIZE};
+pub use self::task::{TaskDescriptor, TaskCap, TaskStatus, TaskClaim, requeue_task, rotate_task, set_task_affinity,
+                     pick_task, set_current_task, block_task, wake_task, wake_waiters, wake_expired,
+                     steal_task, migrate_tasks, running_task};
 pub use self::channel::{ChannelDescriptor, ChannelCap, ChannelValue};
+pub use self::pmem::{PmemDescriptor, PmemCap};
+pub use self::device::{DeviceUntypedDescriptor, DeviceUntypedCap};
+pub use self::dma::{DmaDescriptor, DmaCap};
+pub use self::shared::{SharedFrameSetDescriptor, SharedFrameSetCap};
+pub use self::msi::{MsiDescriptor, MsiCap};
+pub use self::irq::{IrqHandlerDescriptor, IrqHandlerCap, irq_notify};
+pub use self::irq_control::{IrqControlDescriptor, IrqControlCap};
+pub use self::cpu_control::{CpuControlDescriptor, CpuControlCap};
+pub use self::boot_image::{BootImageDescriptor, BootImageCap};
+pub use self::timer::{TimerDescriptor, TimerCap};
+pub use self::quota::{QuotaDescriptor, QuotaCap};
+pub use self::kernel_log::{KernelLogDescriptor, KernelLogCap};
+pub use self::sched_context::{SchedContextDescriptor, SchedContextCap};
+pub use self::endpoint::{EndpointDescriptor, EndpointCap};
+pub use self::reply::{ReplyDescriptor, ReplyCap};
+pub use self::notification::{NotificationDescriptor, NotificationCap};
+pub use self::notification_group::{NotificationGroupDescriptor, NotificationGroupCap};
+pub use self::ipc_ring::{IpcRingDescriptor, IpcRingCap};
+pub use self::census::{object_created, object_destroyed, object_counts, cap_dump};
+pub use self::error::{clear_call_error, record_call_error, call_error};
 
-pub use arch::cap::{TopPageTableCap, PageCap, PAGE_LENGTH};
+pub use arch::cap::{TopPageTableCap, PageCap, LargePageCap, VSpaceCap, LdtCap, IoPortCap, AsidControlCap, AsidPoolCap,
+                    PageFaultResult,
+                    PAGE_LENGTH, LARGE_PAGE_SPLIT_COUNT, unmap_region};
 
 use arch;
 use common::*;
-use core::any::{TypeId};
-use core::mem::drop;
+use core::any::{Any, TypeId};
+use core::mem::{self, drop};
+use core::ops::DerefMut;
+use util::RwLock;
 use util::managed_arc::{ManagedArcAny, ManagedArc};
+use abi::{ObjectType, CapType};
 
 pub use abi::{SetDefault, TaskBuffer};
 /// Raw page struct representing a whole page.
@@ -50,9 +153,7 @@ pub type TaskBufferPageCap = PageCap<TaskBuffer>;
 
 impl SetDefault for RawPage {
     fn set_default(&mut self) {
-        for raw in self.0.iter_mut() {
-            *raw = 0x0;
-        }
+        unsafe { arch::zero_range(self.0.as_mut_ptr() as usize, PAGE_LENGTH); }
     }
 }
 
@@ -79,11 +180,320 @@ pub unsafe fn upgrade_any(ptr: PAddr, type_id: TypeId) -> Option<ManagedArcAny>
         Some({ ManagedArc::from_ptr(ptr): TaskBufferPageCap }.into())
     } else if type_id == TypeId::of::<ChannelCap>() {
         Some({ ManagedArc::from_ptr(ptr): ChannelCap }.into())
+    } else if type_id == TypeId::of::<PmemCap>() {
+        Some({ ManagedArc::from_ptr(ptr): PmemCap }.into())
+    } else if type_id == TypeId::of::<DeviceUntypedCap>() {
+        Some({ ManagedArc::from_ptr(ptr): DeviceUntypedCap }.into())
+    } else if type_id == TypeId::of::<DmaCap>() {
+        Some({ ManagedArc::from_ptr(ptr): DmaCap }.into())
+    } else if type_id == TypeId::of::<SharedFrameSetCap>() {
+        Some({ ManagedArc::from_ptr(ptr): SharedFrameSetCap }.into())
+    } else if type_id == TypeId::of::<MsiCap>() {
+        Some({ ManagedArc::from_ptr(ptr): MsiCap }.into())
+    } else if type_id == TypeId::of::<IrqHandlerCap>() {
+        Some({ ManagedArc::from_ptr(ptr): IrqHandlerCap }.into())
+    } else if type_id == TypeId::of::<TimerCap>() {
+        Some({ ManagedArc::from_ptr(ptr): TimerCap }.into())
+    } else if type_id == TypeId::of::<QuotaCap>() {
+        Some({ ManagedArc::from_ptr(ptr): QuotaCap }.into())
+    } else if type_id == TypeId::of::<KernelLogCap>() {
+        Some({ ManagedArc::from_ptr(ptr): KernelLogCap }.into())
+    } else if type_id == TypeId::of::<IrqControlCap>() {
+        Some({ ManagedArc::from_ptr(ptr): IrqControlCap }.into())
+    } else if type_id == TypeId::of::<CpuControlCap>() {
+        Some({ ManagedArc::from_ptr(ptr): CpuControlCap }.into())
+    } else if type_id == TypeId::of::<BootImageCap>() {
+        Some({ ManagedArc::from_ptr(ptr): BootImageCap }.into())
+    } else if type_id == TypeId::of::<SchedContextCap>() {
+        Some({ ManagedArc::from_ptr(ptr): SchedContextCap }.into())
+    } else if type_id == TypeId::of::<EndpointCap>() {
+        Some({ ManagedArc::from_ptr(ptr): EndpointCap }.into())
+    } else if type_id == TypeId::of::<ReplyCap>() {
+        Some({ ManagedArc::from_ptr(ptr): ReplyCap }.into())
+    } else if type_id == TypeId::of::<NotificationCap>() {
+        Some({ ManagedArc::from_ptr(ptr): NotificationCap }.into())
+    } else if type_id == TypeId::of::<NotificationGroupCap>() {
+        Some({ ManagedArc::from_ptr(ptr): NotificationGroupCap }.into())
+    } else if type_id == TypeId::of::<IpcRingCap>() {
+        Some({ ManagedArc::from_ptr(ptr): IpcRingCap }.into())
     } else {
         arch::cap::upgrade_arch_any(ptr, type_id)
     }
 }
 
+/// Type of the capability with the given `type_id`, as reported to
+/// user-space. If the `type_id` is not recognized, `None` is returned.
+pub fn cap_type(type_id: TypeId) -> Option<CapType> {
+    if type_id == TypeId::of::<CPoolCap>() {
+        Some(CapType::CPool)
+    } else if type_id == TypeId::of::<UntypedCap>() {
+        Some(CapType::Untyped)
+    } else if type_id == TypeId::of::<TaskCap>() {
+        Some(CapType::Task)
+    } else if type_id == TypeId::of::<RawPageCap>() {
+        Some(CapType::RawPage)
+    } else if type_id == TypeId::of::<TaskBufferPageCap>() {
+        Some(CapType::TaskBufferPage)
+    } else if type_id == TypeId::of::<ChannelCap>() {
+        Some(CapType::Channel)
+    } else if type_id == TypeId::of::<PmemCap>() {
+        Some(CapType::Pmem)
+    } else if type_id == TypeId::of::<DeviceUntypedCap>() {
+        Some(CapType::DeviceUntyped)
+    } else if type_id == TypeId::of::<DmaCap>() {
+        Some(CapType::Dma)
+    } else if type_id == TypeId::of::<SharedFrameSetCap>() {
+        Some(CapType::SharedFrameSet)
+    } else if type_id == TypeId::of::<MsiCap>() {
+        Some(CapType::Msi)
+    } else if type_id == TypeId::of::<IrqHandlerCap>() {
+        Some(CapType::IrqHandler)
+    } else if type_id == TypeId::of::<TimerCap>() {
+        Some(CapType::Timer)
+    } else if type_id == TypeId::of::<QuotaCap>() {
+        Some(CapType::Quota)
+    } else if type_id == TypeId::of::<KernelLogCap>() {
+        Some(CapType::KernelLog)
+    } else if type_id == TypeId::of::<IrqControlCap>() {
+        Some(CapType::IrqControl)
+    } else if type_id == TypeId::of::<CpuControlCap>() {
+        Some(CapType::CpuControl)
+    } else if type_id == TypeId::of::<BootImageCap>() {
+        Some(CapType::BootImage)
+    } else if type_id == TypeId::of::<SchedContextCap>() {
+        Some(CapType::SchedContext)
+    } else if type_id == TypeId::of::<EndpointCap>() {
+        Some(CapType::Endpoint)
+    } else if type_id == TypeId::of::<ReplyCap>() {
+        Some(CapType::Reply)
+    } else if type_id == TypeId::of::<NotificationCap>() {
+        Some(CapType::Notification)
+    } else if type_id == TypeId::of::<NotificationGroupCap>() {
+        Some(CapType::NotificationGroup)
+    } else if type_id == TypeId::of::<IpcRingCap>() {
+        Some(CapType::IpcRing)
+    } else {
+        arch::cap::arch_cap_type(type_id)
+    }
+}
+
+/// Create an object of type `object` from an untyped capability. A
+/// capability pool gets `1 << size_bits` entries, at most
+/// `CPOOL_MAX_SIZE`. Other objects take `size_bits` of 0, or their
+/// natural size. Returns `None` if the size is invalid, or does not
+/// fit the untyped memory.
+pub fn retype_any(untyped: &UntypedCap, object: ObjectType, size_bits: usize) -> Option<ManagedArcAny> {
+    // `size_bits` comes from userspace, and is checked before anything
+    // is shifted by it.
+    if size_bits >= mem::size_of::<usize>() * 8 {
+        return None;
+    }
+    let frame_length = match object {
+        ObjectType::LargePage => LARGE_PAGE_SPLIT_COUNT * PAGE_LENGTH,
+        ObjectType::RawPage => PAGE_LENGTH,
+        _ => 0,
+    };
+    if !untyped.read().can_retype(::core::cmp::max(frame_length, 1 << size_bits)) {
+        return None;
+    }
+
+    match object {
+        ObjectType::CPool => {
+            if size_bits > CPOOL_MAX_SIZE.trailing_zeros() as usize {
+                return None;
+            }
+            let cpool = CPoolCap::retype_guarded_from(untyped.write().deref_mut(),
+                                                      1 << size_bits, CAddr::empty());
+            Some(cpool.into())
+        },
+        ObjectType::Task if size_bits == 0 => {
+            Some(TaskCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Channel if size_bits == 0 => {
+            Some(ChannelCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Timer if size_bits == 0 => {
+            Some(TimerCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::SchedContext if size_bits == 0 => {
+            Some(SchedContextCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Endpoint if size_bits == 0 => {
+            Some(EndpointCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Reply if size_bits == 0 => {
+            Some(ReplyCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Notification if size_bits == 0 => {
+            Some(NotificationCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::NotificationGroup if size_bits == 0 => {
+            Some(NotificationGroupCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::RawPage if size_bits == 0 || 1 << size_bits == PAGE_LENGTH => {
+            Some(RawPageCap::retype_from(untyped.write().deref_mut()).into())
+        },
+        ObjectType::Task | ObjectType::Channel | ObjectType::Timer | ObjectType::SchedContext |
+        ObjectType::Endpoint | ObjectType::Reply | ObjectType::Notification | ObjectType::NotificationGroup |
+        ObjectType::RawPage => None,
+        _ => arch::cap::retype_arch_any(untyped, object, size_bits),
+    }
+}
+
+/// Kernel object in a derivation list, the list of objects retyped
+/// from an untyped capability, or split from a large page. Together,
+/// the lists form the capability derivation tree. All capabilities to
+/// an object, wherever they were copied or sent, are its weak
+/// pointers, so they are found from the object itself.
+pub trait Derived: Sized {
+    /// Next object in the derivation list.
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny>;
+
+    /// Tear the object down when it is revoked, so that it refers to
+    /// nothing: stop it, unmap it, and empty its weak pools. Weak
+    /// pointers to it are already removed. This may be called again
+    /// on an object kept by an earlier revoke.
+    fn revoke(_arc: &ManagedArc<RwLock<Self>>) { }
+
+    /// Whether the memory of the object, once nothing refers to it,
+    /// can be reused.
+    fn is_destroyable(&self) -> bool {
+        true
+    }
+}
+
+/// Revoke every object of a derivation list, and destroy those that
+/// nothing refers to anymore. Returns the list of the objects kept,
+/// still held by the kernel, for example by a running task.
+///
+/// Teardown happens in this order:
+///
+/// 1. Each object in turn loses all capabilities to it, wherever
+///    they were copied or sent, and all weak pointers other objects
+///    hold to it. A capability pool holding the last capability to
+///    itself loses it here.
+/// 2. Right after, `Derived::revoke` tears it down: tasks stop and
+///    leave the scheduler, giving donated time back, channels close
+///    and release the values and donated time queued in them,
+///    endpoints close and stop the tasks blocked on them, address
+///    spaces unmap and free their paging structures and frames,
+///    timers and interrupt sources are disarmed.
+/// 3. Once the whole list is torn down, objects the list holds the
+///    only reference to are destroyed, dropping their descriptors.
+///    As this drops references they held, for example a channel a
+///    task waited on, or a capability queued in a channel, it is
+///    repeated until no more objects can be destroyed.
+///
+/// Objects still kept after that are only referred to by the kernel
+/// itself, and are destroyed by a later revoke.
+pub fn revoke_list(first: Option<ManagedArcAny>) -> Option<ManagedArcAny> {
+    let mut kept = None;
+    let mut next = first;
+    while let Some(any) = next {
+        let (following, list) = doto_any!(any, revoke_owning, kept);
+        next = following;
+        kept = list;
+    }
+
+    // Destroying an object drops what it holds, which may make
+    // another one destroyable.
+    loop {
+        let mut destroyed = false;
+        let mut next = kept.take();
+        while let Some(any) = next {
+            let (following, list, done) = doto_any!(any, destroy_owning, kept);
+            next = following;
+            kept = list;
+            destroyed |= done;
+        }
+
+        if !destroyed || kept.is_none() {
+            return kept;
+        }
+    }
+}
+
+/// Append the derivation list `tail` to `head`.
+pub fn append_list(head: Option<ManagedArcAny>, tail: Option<ManagedArcAny>) -> Option<ManagedArcAny> {
+    let mut kept = tail;
+    let mut reversed = None;
+    let mut next = head;
+    while let Some(any) = next {
+        let (following, list) = doto_any!(any, relink_owning, reversed);
+        next = following;
+        reversed = list;
+    }
+    while let Some(any) = reversed {
+        let (following, list) = doto_any!(any, relink_owning, kept);
+        reversed = following;
+        kept = list;
+    }
+    kept
+}
+
+/// Link `arc` in front of `list`. Returns the next object of its old
+/// list, and the new list.
+fn relink_owning<T: Derived + Any>(arc: ManagedArc<RwLock<T>>, list: Option<ManagedArcAny>)
+                                   -> (Option<ManagedArcAny>, Option<ManagedArcAny>) {
+    let next = mem::replace(arc.write().next_mut(), list);
+    (next, Some(arc.into()))
+}
+
+/// Remove all capabilities to `arc`, tear it down, and link it in
+/// front of `kept`.
+fn revoke_owning<T: Derived + Any>(arc: ManagedArc<RwLock<T>>, kept: Option<ManagedArcAny>)
+                                   -> (Option<ManagedArcAny>, Option<ManagedArcAny>) {
+    arc.remove_weak_all();
+    T::revoke(&arc);
+    relink_owning(arc, kept)
+}
+
+/// Destroy `arc` if the derivation list holds the only reference to
+/// it, or link it in front of `kept`. Returns also whether it was
+/// destroyed.
+fn destroy_owning<T: Derived + Any>(arc: ManagedArc<RwLock<T>>, kept: Option<ManagedArcAny>)
+                                    -> (Option<ManagedArcAny>, Option<ManagedArcAny>, bool) {
+    if arc.lead_count() == 1 && !arc.is_weakly_referenced() && arc.read().is_destroyable() {
+        let next = arc.write().next_mut().take();
+        unsafe { arc.destroy(); }
+        object_destroyed(TypeId::of::<ManagedArc<RwLock<T>>>());
+        (next, kept, true)
+    } else {
+        let (next, list) = relink_owning(arc, kept);
+        (next, list, false)
+    }
+}
+
+/// Remove every capability to the object of `any`, wherever it was
+/// copied or sent, and tear it down, like a revoke of the untyped
+/// capability it came from. Its memory is reused only once that
+/// untyped capability is revoked.
+pub fn revoke_any(any: ManagedArcAny) {
+    doto_any!(any, revoke_one)
+}
+
+/// Remove every weak pointer to `arc`, and tear it down.
+fn revoke_one<T: Derived + Any>(arc: ManagedArc<RwLock<T>>) {
+    arc.remove_weak_all();
+    T::revoke(&arc);
+}
+
+/// Tear down the object of `any` once nothing refers to it weakly
+/// anymore, as after its last capability is deleted. Like a revoked
+/// object, its memory is reused only when the untyped capability it
+/// came from is revoked, and the kernel, for example a task waiting on
+/// it, has let go of it.
+pub fn delete_any(any: ManagedArcAny) {
+    doto_any!(any, delete_owning)
+}
+
+/// Tear `arc` down if it is not weakly referenced.
+fn delete_owning<T: Derived + Any>(arc: ManagedArc<RwLock<T>>) {
+    if !arc.is_weakly_referenced() {
+        T::revoke(&arc);
+    }
+}
+
 /// Drop an architecture-specific `any` capability. `ManagedArcAny` is
 /// not itself droppable. It must be converted to its real type before
 /// dropping.
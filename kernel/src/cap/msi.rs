@@ -0,0 +1,101 @@
+use arch::{self, MsiMessage, InterruptVector};
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, Derived};
+
+/// Message-signaled interrupt descriptor.
+#[derive(Debug)]
+pub struct MsiDescriptor {
+    vector: InterruptVector,
+    cpu: usize,
+    message: MsiMessage,
+    /// Whether the vector was returned on revoke.
+    revoked: bool,
+    next: Option<ManagedArcAny>,
+}
+/// Message-signaled interrupt capability. Reference-counted smart
+/// pointer to MSI descriptor.
+///
+/// The capability owns one kernel interrupt vector on one CPU. Its
+/// holder programs the message address and data into a device's MSI
+/// capability or MSI-X table, so that the device raises that vector.
+pub type MsiCap = ManagedArc<RwLock<MsiDescriptor>>;
+
+impl MsiCap {
+    /// Allocate a vector delivered to the CPU with id `cpu`, and create
+    /// a capability for it from `untyped`. Returns `None` if the CPU is
+    /// not online, or no vector is free. Only the IRQ control
+    /// capability creates MSI capabilities.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, cpu: usize) -> Option<Self> {
+        let (vector, message) = match arch::allocate_msi(cpu) {
+            Some(msi) => msi,
+            None => return None,
+        };
+
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(MsiDescriptor {
+                    vector: vector,
+                    cpu: cpu,
+                    message: message,
+                    revoked: false,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc
+    }
+}
+
+impl MsiDescriptor {
+    /// Vector the interrupt is raised on.
+    pub fn vector(&self) -> InterruptVector {
+        self.vector
+    }
+
+    /// Id of the CPU the interrupt is delivered to.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Address and data the device must write.
+    pub fn message(&self) -> MsiMessage {
+        self.message
+    }
+
+    /// Deliver the interrupt to the CPU with id `cpu` instead. Returns
+    /// the new message, which the holder must program into the device,
+    /// or `None` if the CPU is not online or has no free vector, or
+    /// the capability was revoked and its vector returned.
+    pub fn set_affinity(&mut self, cpu: usize) -> Option<MsiMessage> {
+        if self.revoked {
+            return None;
+        }
+
+        arch::move_msi(self.cpu, self.vector, cpu).map(|(vector, message)| {
+            self.vector = vector;
+            self.cpu = cpu;
+            self.message = message;
+            message
+        })
+    }
+}
+
+impl Derived for MsiDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &MsiCap) {
+        let mut desc = arc.write();
+        if !desc.revoked {
+            arch::free_msi(desc.cpu, desc.vector);
+            desc.revoked = true;
+        }
+    }
+}
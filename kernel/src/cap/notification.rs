@@ -0,0 +1,92 @@
+use common::*;
+use core::mem;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use super::{UntypedDescriptor, Derived, wake_waiters};
+
+/// Notification descriptor.
+#[derive(Debug)]
+pub struct NotificationDescriptor {
+    /// Badges signalled since the word was last taken, or'ed together.
+    word: u64,
+    /// Whether the notification was torn down, so that it will never
+    /// be signalled again.
+    closed: bool,
+    /// Address of the notification, that tasks waiting on it are woken
+    /// by.
+    paddr: PAddr,
+    next: Option<ManagedArcAny>,
+}
+
+/// Notification capability. Reference-counted smart pointer to
+/// notification descriptor.
+///
+/// Signalling a notification never blocks: it ors a badge into the
+/// notification word. A task waiting on it takes the word, and clears
+/// it, once it is not zero. Interrupts and timers can be bound to a
+/// notification instead of a channel, each with its own badge, so
+/// that one task waits on several of them at once.
+pub type NotificationCap = ManagedArc<RwLock<NotificationDescriptor>>;
+
+impl NotificationCap {
+    /// Create a notification capability, with a clear word, from an
+    /// untyped capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(NotificationDescriptor {
+                    word: 0,
+                    closed: false,
+                    paddr: paddr,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+}
+
+impl NotificationDescriptor {
+    /// Or `badge` into the notification word.
+    pub fn signal(&mut self, badge: u64) {
+        if !self.closed {
+            self.word |= badge;
+            wake_waiters(self.paddr);
+        }
+    }
+
+    /// Take the notification word, and clear it. Zero if nothing was
+    /// signalled since it was last taken.
+    pub fn take(&mut self) -> u64 {
+        mem::replace(&mut self.word, 0)
+    }
+
+    /// Whether a badge was signalled since the word was last taken.
+    pub fn is_signalled(&self) -> bool {
+        self.word != 0
+    }
+
+    /// Whether the notification was torn down. Tasks waiting on it
+    /// can never take a signal.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Derived for NotificationDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &NotificationCap) {
+        let mut desc = arc.write();
+        desc.closed = true;
+        desc.word = 0;
+        wake_waiters(arc.ptr());
+    }
+}
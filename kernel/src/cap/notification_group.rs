@@ -0,0 +1,99 @@
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool256Arc};
+use super::{UntypedDescriptor, NotificationCap, Derived};
+
+/// Number of notifications a group holds at once.
+const GROUP_LENGTH: usize = 256;
+
+/// Notification group descriptor.
+#[derive(Debug)]
+pub struct NotificationGroupDescriptor {
+    /// Notifications signalled together.
+    member_weak_pool: ManagedWeakPool256Arc,
+    next: Option<ManagedArcAny>,
+}
+
+/// Notification group capability. Reference-counted smart pointer to
+/// notification group descriptor.
+///
+/// Signalling a group signals every notification in it with the same
+/// badge, so that all the tasks waiting on them wake at once, as for a
+/// shutdown broadcast or a barrier among worker tasks. A notification
+/// leaves the group when it is removed, or destroyed.
+pub type NotificationGroupCap = ManagedArc<RwLock<NotificationGroupDescriptor>>;
+
+impl NotificationGroupCap {
+    /// Create an empty notification group capability from an untyped
+    /// capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let member_weak_pool = unsafe { ManagedWeakPool256Arc::create(
+            untyped.allocate(ManagedWeakPool256Arc::inner_length(),
+                             ManagedWeakPool256Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(NotificationGroupDescriptor {
+                    member_weak_pool: member_weak_pool,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+}
+
+impl NotificationGroupDescriptor {
+    /// Slot of `notification` in the group, if it is in it.
+    fn find(&self, notification: &NotificationCap) -> Option<usize> {
+        (0..GROUP_LENGTH).find(|&index| {
+            let member: Option<NotificationCap> = self.member_weak_pool.read().upgrade(index);
+            member.map(|member| member.ptr_eq(notification)).unwrap_or(false)
+        })
+    }
+
+    /// Add `notification` to the group. Returns `false` if the group
+    /// is full. Adding a notification already in it does nothing.
+    pub fn add(&self, notification: &NotificationCap) -> bool {
+        if self.find(notification).is_some() {
+            return true;
+        }
+        self.member_weak_pool.read().downgrade_free(notification).is_some()
+    }
+
+    /// Remove `notification` from the group. Returns `false` if it was
+    /// not in it.
+    pub fn remove(&self, notification: &NotificationCap) -> bool {
+        match self.find(notification) {
+            Some(index) => {
+                self.member_weak_pool.read().remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Or `badge` into the word of every notification in the group.
+    pub fn signal(&self, badge: u64) {
+        for index in 0..GROUP_LENGTH {
+            let member: Option<NotificationCap> = self.member_weak_pool.read().upgrade(index);
+            if let Some(member) = member {
+                member.write().signal(badge);
+            }
+        }
+    }
+}
+
+impl Derived for NotificationGroupDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &NotificationGroupCap) {
+        arc.read().member_weak_pool.read().clear();
+    }
+}
@@ -0,0 +1,109 @@
+use common::*;
+use util::{RwLock, MemoryObject};
+use util::managed_arc::{ManagedArc, ManagedArcAny};
+use arch;
+use super::{UntypedDescriptor, RawPage, RawPageCap, Derived, PAGE_LENGTH};
+
+/// Persistent memory descriptor.
+#[derive(Debug)]
+pub struct PmemDescriptor {
+    start_paddr: PAddr,
+    length: usize,
+    next: Option<ManagedArcAny>,
+}
+/// Persistent memory capability. Reference-counted smart pointer to
+/// persistent memory descriptor.
+///
+/// Persistent memory is device memory whose contents survive
+/// reboots. It is never used by the volatile frame allocator, and its
+/// contents are never cleared by the kernel.
+pub type PmemCap = ManagedArc<RwLock<PmemDescriptor>>;
+
+impl PmemCap {
+    /// Bootstrap a persistent memory capability from a region
+    /// reported by the boot loader.
+    ///
+    /// # Safety
+    ///
+    /// Can only be used for persistent memory regions returned from
+    /// `InitInfo`.
+    pub unsafe fn bootstrap(region: MemoryRegion, untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(PmemDescriptor {
+                    start_paddr: region.start_paddr(),
+                    length: region.length(),
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        });
+
+        arc.unwrap()
+    }
+}
+
+impl PmemDescriptor {
+    /// Start physical address of the persistent memory region.
+    pub fn start_paddr(&self) -> PAddr {
+        self.start_paddr
+    }
+
+    /// Length of the persistent memory region.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Number of whole pages in the region.
+    pub fn page_count(&self) -> usize {
+        self.length / PAGE_LENGTH
+    }
+
+    /// Create a raw page capability for the page at `index` of the
+    /// region, keeping its contents. Returns `None` if `index` is out
+    /// of range.
+    pub fn page(&self, index: usize, untyped: &mut UntypedDescriptor) -> Option<RawPageCap> {
+        if index >= self.page_count() {
+            return None;
+        }
+
+        Some(unsafe { RawPageCap::bootstrap_device(self.start_paddr + index * PAGE_LENGTH, untyped) })
+    }
+
+    /// Write back the cache lines covering `length` bytes at
+    /// `offset`. Returns `false` if the range is outside the region.
+    pub fn flush(&self, offset: usize, length: usize) -> bool {
+        let end = match offset.checked_add(length) {
+            Some(end) if end <= self.length => end,
+            _ => return false,
+        };
+
+        let mut current = offset;
+        while current < end {
+            let page_offset = current % PAGE_LENGTH;
+            let page_start = current - page_offset;
+            let chunk_end = if page_start + PAGE_LENGTH < end { page_start + PAGE_LENGTH } else { end };
+
+            let object = unsafe { MemoryObject::<RawPage>::new(self.start_paddr + page_start) };
+            unsafe { arch::clean_range(object.as_ptr() as usize + page_offset, chunk_end - current); }
+
+            current = chunk_end;
+        }
+
+        true
+    }
+
+    /// Order earlier flushes before later stores.
+    pub fn fence(&self) {
+        arch::store_fence();
+    }
+}
+
+impl Derived for PmemDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+}
@@ -0,0 +1,126 @@
+use core::cmp;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use super::{UntypedDescriptor, Derived};
+
+/// Quota descriptor.
+#[derive(Debug)]
+pub struct QuotaDescriptor {
+    /// Bytes that may be charged.
+    limit: usize,
+    /// Bytes charged, by this quota's untyped regions and those of
+    /// its children.
+    used: usize,
+    parent_weak_pool: ManagedWeakPool1Arc,
+    next: Option<ManagedArcAny>,
+}
+/// Quota capability. Reference-counted smart pointer to quota
+/// descriptor.
+///
+/// A quota attached to untyped capabilities limits the bytes retyped
+/// from them, together. Quotas nest: a child quota is charged to its
+/// parent as well, so a subsystem handed untyped memory under a quota
+/// can only divide it further.
+pub type QuotaCap = ManagedArc<RwLock<QuotaDescriptor>>;
+
+impl QuotaCap {
+    /// Create a quota of `limit` bytes from an untyped capability,
+    /// below `parent` if any.
+    pub fn retype_from(untyped: &mut UntypedDescriptor, limit: usize, parent: Option<&QuotaCap>) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let parent_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        if let Some(parent) = parent {
+            parent_weak_pool.read().downgrade_at(parent, 0);
+        }
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(QuotaDescriptor {
+                    limit: limit,
+                    used: 0,
+                    parent_weak_pool: parent_weak_pool,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+
+    /// Bytes that can still be charged, to this quota and all its
+    /// parents.
+    pub fn remaining(&self) -> usize {
+        let (remaining, parent) = {
+            let desc = self.read();
+            (desc.limit.saturating_sub(desc.used), desc.upgrade_parent())
+        };
+        match parent {
+            Some(parent) => cmp::min(remaining, parent.remaining()),
+            None => remaining,
+        }
+    }
+
+    /// Charge `length` bytes to this quota and all its parents.
+    pub fn charge(&self, length: usize) {
+        let parent = {
+            let mut desc = self.write();
+            desc.used += length;
+            desc.upgrade_parent()
+        };
+        if let Some(parent) = parent {
+            parent.charge(length);
+        }
+    }
+
+    /// Return `length` bytes to this quota and all its parents.
+    pub fn uncharge(&self, length: usize) {
+        let parent = {
+            let mut desc = self.write();
+            desc.used = desc.used.saturating_sub(length);
+            desc.upgrade_parent()
+        };
+        if let Some(parent) = parent {
+            parent.uncharge(length);
+        }
+    }
+
+    /// Whether `other` is this quota, or one of its parents.
+    pub fn is_within(&self, other: &QuotaCap) -> bool {
+        if self.ptr_eq(other) {
+            return true;
+        }
+        let parent = self.read().upgrade_parent();
+        parent.map_or(false, |parent| parent.is_within(other))
+    }
+}
+
+impl QuotaDescriptor {
+    /// Bytes that may be charged.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes charged.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn upgrade_parent(&self) -> Option<QuotaCap> {
+        self.parent_weak_pool.read().upgrade(0)
+    }
+}
+
+impl Derived for QuotaDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &QuotaCap) {
+        arc.read().parent_weak_pool.read().clear();
+    }
+}
@@ -0,0 +1,331 @@
+use common::*;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use abi::{SystemCall, ChannelMessage, FrameLoan, IpcKind};
+use super::{UntypedDescriptor, TaskCap, TaskStatus, ChannelValue, SharedFrameSetCap, VSpaceCap, Derived};
+
+/// Reply descriptor.
+#[derive(Debug)]
+pub struct ReplyDescriptor {
+    /// Task waiting for the reply, between a receive and the reply.
+    caller_weak_pool: ManagedWeakPool1Arc,
+    /// Address space of the lend window.
+    vspace_weak_pool: ManagedWeakPool1Arc,
+    /// Address and number of pages of the lend window, where frames
+    /// lent by the caller are mapped.
+    lend_window: Option<(VAddr, usize)>,
+    /// Frames lent by the caller, mapped in the lend window until the
+    /// reply.
+    loan: Option<SharedFrameSetCap>,
+    /// Receiver that took the call of the caller, until the reply.
+    server_weak_pool: ManagedWeakPool1Arc,
+    /// Priority of the caller the receiver inherited, to give back
+    /// on the reply.
+    server_priority: Option<u8>,
+    next: Option<ManagedArcAny>,
+}
+
+/// Reply capability. Reference-counted smart pointer to reply
+/// descriptor.
+///
+/// A server passes a reply object when it receives from an endpoint.
+/// If the message came from a call, the caller is bound to the reply
+/// object, which then replies to that caller exactly once. A server
+/// with several calls outstanding receives each through its own reply
+/// object.
+///
+/// A caller can also lend a shared frame set along its call. It is
+/// mapped in the lend window of the reply object the call is received
+/// with, and unmapped again once the call is replied to.
+pub type ReplyCap = ManagedArc<RwLock<ReplyDescriptor>>;
+
+impl ReplyCap {
+    /// Create an unbound reply capability from an untyped capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let caller_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        let vspace_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+        let server_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(ReplyDescriptor {
+                    caller_weak_pool: caller_weak_pool,
+                    vspace_weak_pool: vspace_weak_pool,
+                    lend_window: None,
+                    loan: None,
+                    server_weak_pool: server_weak_pool,
+                    server_priority: None,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+
+    /// Bind `caller` to the reply object, for `server`, which took its
+    /// call, to reply to. Returns `false` if another caller still waits
+    /// for a reply through it.
+    pub fn bind(&self, caller: &TaskCap, server: &TaskCap) -> bool {
+        let desc = self.read();
+        if desc.caller_weak_pool.read().is_occupied(0) {
+            return false;
+        }
+        desc.caller_weak_pool.read().downgrade_at(caller, 0);
+        desc.server_weak_pool.read().remove(0);
+        desc.server_weak_pool.read().downgrade_at(server, 0);
+        true
+    }
+
+    /// Set the window, of `page_count` pages at `address` in `vspace`,
+    /// frames lent to the receiver are mapped in, or disable lending.
+    /// Returns `false` while frames are lent.
+    pub fn set_lend_window(&self, window: Option<(&VSpaceCap, VAddr, usize)>) -> bool {
+        let mut desc = self.write();
+        if desc.loan.is_some() {
+            return false;
+        }
+        match window {
+            Some((vspace, address, page_count)) => {
+                desc.vspace_weak_pool.read().remove(0);
+                desc.vspace_weak_pool.read().downgrade_at(vspace, 0);
+                desc.lend_window = Some((address, page_count));
+            },
+            None => {
+                desc.vspace_weak_pool.read().remove(0);
+                desc.lend_window = None;
+            },
+        }
+        true
+    }
+
+    /// Map `frames`, lent along the call of `value` just bound, in the
+    /// lend window. The message received says where, unless the
+    /// window is missing or too small.
+    pub fn lend(&self, value: u64, frames: SharedFrameSetCap, writable: bool) -> ChannelMessage {
+        let mut desc = self.write();
+        let vspace: Option<VSpaceCap> = desc.vspace_weak_pool.read().upgrade(0);
+        let address = match (desc.lend_window, vspace) {
+            (Some((address, page_count)), Some(vspace)) if desc.loan.is_none() => {
+                let fits = frames.read().page_count() <= page_count;
+                if fits && frames.write().map(&vspace, address, writable) {
+                    Some(address)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        };
+        if address.is_some() {
+            desc.loan = Some(frames);
+        }
+
+        ChannelMessage::Lend(value, FrameLoan {
+            frames: None,
+            writable: writable,
+            address: address.map(|address| address.into(): usize as u64),
+        })
+    }
+
+    /// Run `server` at `priority`, that of the caller just bound, if
+    /// it is higher, until the reply. A server with several callers
+    /// waiting on its reply objects runs at the highest of their
+    /// priorities.
+    pub fn inherit(&self, server: &TaskCap, priority: u8) {
+        let before = server.read().priority();
+        {
+            let mut desc = self.write();
+            if desc.server_priority.is_some() {
+                return;
+            }
+            server.write().inherit_priority(priority);
+            desc.server_priority = Some(priority);
+        }
+        if server.read().priority() != before {
+            super::requeue_task(server);
+        }
+    }
+
+    /// Stop passing the priority of the caller on to the receiver,
+    /// which keeps that of the other callers it serves.
+    fn restore_priority(&self) {
+        let mut desc = self.write();
+        let server: Option<TaskCap> = desc.server_weak_pool.read().upgrade(0);
+        let priority = desc.server_priority.take();
+        if let (Some(server), Some(priority)) = (server, priority) {
+            let before = server.read().priority();
+            server.write().restore_priority(priority);
+            if server.read().priority() != before {
+                super::requeue_task(&server);
+            }
+        }
+    }
+
+    /// Unmap the frames lent by the caller, if any.
+    fn return_loan(&self) {
+        let mut desc = self.write();
+        if let Some(frames) = desc.loan.take() {
+            let vspace: Option<VSpaceCap> = desc.vspace_weak_pool.read().upgrade(0);
+            if let (Some(vspace), Some((address, _))) = (vspace, desc.lend_window) {
+                frames.write().unmap(&vspace, address);
+            }
+        }
+    }
+
+    /// Unbind the caller, which can only be replied to once. Frames it
+    /// lent and the priority the receiver inherited go back first.
+    fn take_caller(&self) -> Option<TaskCap> {
+        self.return_loan();
+        self.restore_priority();
+        let desc = self.read();
+        let caller = desc.caller_weak_pool.read().upgrade(0);
+        desc.caller_weak_pool.read().remove(0);
+        desc.server_weak_pool.read().remove(0);
+        caller
+    }
+
+    /// The caller bound to the reply object, if any.
+    pub fn caller(&self) -> Option<TaskCap> {
+        self.read().caller_weak_pool.read().upgrade(0)
+    }
+
+    /// The receiver that took the call of the bound caller, if any.
+    pub fn server(&self) -> Option<TaskCap> {
+        self.read().server_weak_pool.read().upgrade(0)
+    }
+
+    /// Move the caller bound to the reply object to `target`, which
+    /// `server` then replies to it through instead. Frames it lent are
+    /// unmapped first. Returns `false` if no caller waits for a reply,
+    /// or `target` is bound already, in which case the caller stays
+    /// bound to this reply object.
+    pub fn forward(&self, target: &ReplyCap, server: &TaskCap) -> bool {
+        if target.ptr_eq(self) {
+            return false;
+        }
+        let caller = match self.caller() {
+            Some(caller) => caller,
+            None => return false,
+        };
+        match caller.read().status() {
+            TaskStatus::ReplyWait(..) => (),
+            _ => return false,
+        }
+        // Only unbound once `target` has it, so that it is never left
+        // without a reply object to be replied to through.
+        if !target.bind(&caller, server) {
+            return false;
+        }
+        self.take_caller();
+        caller.write().set_status(TaskStatus::ReplyWait(target.clone()));
+        true
+    }
+
+    /// Fail the call of the bound caller if the task that took it
+    /// exited, or was revoked, without replying, so that the caller
+    /// does not wait for the reply forever. Returns whether it did.
+    pub fn fail_if_abandoned(&self) -> bool {
+        let server: Option<TaskCap> = self.read().server_weak_pool.read().upgrade(0);
+        if server.map_or(false, |server| server.read().exit_code().is_none()) {
+            return false;
+        }
+        let caller = match self.take_caller() {
+            Some(caller) => caller,
+            None => return false,
+        };
+        match caller.read().status() {
+            TaskStatus::ReplyWait(..) => (),
+            _ => return false,
+        }
+        if let Some(buffer_cap) = caller.read().upgrade_buffer() {
+            let mut buffer_desc = buffer_cap.write();
+            let mut buffer = buffer_desc.write();
+            buffer.call = match buffer.call.clone() {
+                Some(SystemCall::EndpointCall { request, .. }) => Some(SystemCall::EndpointCall {
+                    request: request,
+                    response: None,
+                }),
+                call => call,
+            };
+        }
+        caller.write().set_status(TaskStatus::Active);
+        true
+    }
+
+    /// Reply `message` from `source` to the bound caller, which runs
+    /// again. Returns `false` if no caller waits for a reply.
+    pub fn reply(&self, message: ChannelMessage, source: &TaskCap) -> bool {
+        let caller = match self.take_caller() {
+            Some(caller) => caller,
+            None => return false,
+        };
+        match caller.read().status() {
+            TaskStatus::ReplyWait(..) => (),
+            // The caller was stopped, or set active, since.
+            _ => return false,
+        }
+        let buffer_cap = match caller.read().upgrade_buffer() {
+            Some(buffer_cap) => buffer_cap,
+            None => {
+                caller.write().set_status(TaskStatus::Inactive);
+                return false;
+            },
+        };
+        let request = {
+            let buffer_desc = buffer_cap.read();
+            let buffer = buffer_desc.read();
+            match buffer.call.clone() {
+                Some(SystemCall::EndpointCall { request, .. }) => request,
+                _ => panic!(),
+            }
+        };
+
+        if ::ipc_trace::is_enabled() {
+            let length = ::ipc_trace::message_length(&message, source);
+            ::ipc_trace::record(IpcKind::Reply, Some(source), Some(&caller), 0, 0, length, false);
+        }
+        let response = ChannelValue::from_message(message, source.clone())
+            .map(|value| ChannelValue::to_message(value, caller.clone()));
+        {
+            let mut buffer_desc = buffer_cap.write();
+            let mut buffer = buffer_desc.write();
+            buffer.call = Some(SystemCall::EndpointCall {
+                request: request,
+                response: response,
+            });
+        }
+        caller.write().set_status(TaskStatus::Active);
+        true
+    }
+}
+
+impl Derived for ReplyDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    /// A caller still waiting can never get its reply, so it stops,
+    /// and frames it lent are unmapped.
+    fn revoke(arc: &ReplyCap) {
+        if let Some(caller) = arc.take_caller() {
+            let waiting = match caller.read().status() {
+                TaskStatus::ReplyWait(..) => true,
+                _ => false,
+            };
+            if waiting {
+                caller.write().set_status(TaskStatus::Inactive);
+            }
+        }
+        arc.read().vspace_weak_pool.read().clear();
+    }
+}
@@ -0,0 +1,214 @@
+use util::RwLock;
+use timer_wheel;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use super::{UntypedDescriptor, TaskCap, Derived, wake_task};
+
+/// Period, and budget, of a new scheduling context: all the time of
+/// the CPU, until it is configured otherwise.
+const DEFAULT_PERIOD_NS: u64 = 10_000_000;
+
+/// Scheduling context descriptor.
+#[derive(Debug)]
+pub struct SchedContextDescriptor {
+    budget_ns: u64,
+    period_ns: u64,
+    /// Budget left in the current period.
+    remaining_ns: u64,
+    /// Monotonic time the current period started at.
+    period_start_ns: u64,
+    /// Start of the period whose end a wakeup is armed for.
+    wakeup_period_ns: u64,
+    /// Task the scheduling context is bound to.
+    task_weak_pool: ManagedWeakPool1Arc,
+    /// Task the scheduling context was donated by, while it is.
+    donor_weak_pool: ManagedWeakPool1Arc,
+    next: Option<ManagedArcAny>,
+}
+
+/// Scheduling context capability. Reference-counted smart pointer to
+/// scheduling context descriptor.
+///
+/// A task only runs while it is bound to a scheduling context with
+/// budget left. The time it runs is charged to the budget, which is
+/// replenished at the start of every period. A task can donate its
+/// scheduling context along with a channel message, so that the task
+/// taking the message runs on the sender's time.
+pub type SchedContextCap = ManagedArc<RwLock<SchedContextDescriptor>>;
+
+impl SchedContextCap {
+    /// Create a scheduling context capability from an untyped
+    /// capability. It is unbound, and its budget is its whole period.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let task_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let donor_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(SchedContextDescriptor {
+                    budget_ns: DEFAULT_PERIOD_NS,
+                    period_ns: DEFAULT_PERIOD_NS,
+                    remaining_ns: DEFAULT_PERIOD_NS,
+                    period_start_ns: 0,
+                    wakeup_period_ns: u64::max_value(),
+                    task_weak_pool: task_weak_pool,
+                    donor_weak_pool: donor_weak_pool,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc.unwrap()
+    }
+
+    /// Bind the scheduling context to `task`, so that the task can
+    /// run. Returns `false` if either of them is already bound.
+    pub fn bind(&self, task: &TaskCap) -> bool {
+        // Never hold both locks: a running task holds its own while
+        // it charges its scheduling context.
+        if task.read().upgrade_sched_context().is_some() {
+            return false;
+        }
+        {
+            let desc = self.read();
+            if desc.task_weak_pool.read().is_occupied(0) {
+                return false;
+            }
+            desc.task_weak_pool.read().downgrade_at(task, 0);
+        }
+        task.read().downgrade_sched_context(self);
+        wake_task(task);
+        true
+    }
+
+    /// The task the scheduling context is bound to, if any.
+    pub fn task(&self) -> Option<TaskCap> {
+        self.read().task_weak_pool.read().upgrade(0)
+    }
+
+    /// Unbind the scheduling context from its task, which stops
+    /// running. A donated scheduling context is no longer given back.
+    pub fn unbind(&self) {
+        let task: Option<TaskCap> = {
+            let desc = self.read();
+            desc.donor_weak_pool.read().remove(0);
+            let task = desc.task_weak_pool.read().upgrade(0);
+            desc.task_weak_pool.read().remove(0);
+            task
+        };
+        if let Some(task) = task {
+            task.read().remove_sched_context();
+        }
+    }
+
+    /// Take the scheduling context from its task, to be sent along a
+    /// message. The task is recorded as the donor, which gets it back
+    /// once the receiver waits again.
+    pub fn donate(&self) {
+        let task: Option<TaskCap> = { self.read().task_weak_pool.read().upgrade(0) };
+        self.unbind();
+        if let Some(task) = task {
+            self.read().donor_weak_pool.read().downgrade_at(&task, 0);
+        }
+    }
+
+    /// Whether the scheduling context runs a task other than its own,
+    /// on donation.
+    pub fn is_donated(&self) -> bool {
+        self.read().donor_weak_pool.read().is_occupied(0)
+    }
+
+    /// Hand a donated scheduling context to `task`, which took the
+    /// message it was sent with. If the task has a scheduling context
+    /// already, this one goes back to its donor.
+    pub fn receive(&self, task: &TaskCap) {
+        if !self.bind(task) {
+            self.give_back();
+        }
+    }
+
+    /// Give a donated scheduling context back to its donor. If the
+    /// donor was revoked, or has another scheduling context by now,
+    /// the scheduling context stays unbound.
+    pub fn give_back(&self) {
+        let donor: Option<TaskCap> = { self.read().donor_weak_pool.read().upgrade(0) };
+        self.unbind();
+        if let Some(donor) = donor {
+            self.bind(&donor);
+        }
+    }
+}
+
+impl SchedContextDescriptor {
+    /// Set the budget, and the period it is replenished at. Starts a
+    /// new period with the full budget. Returns `false` if the period
+    /// is zero or shorter than the budget.
+    pub fn configure(&mut self, budget_ns: u64, period_ns: u64) -> bool {
+        if period_ns == 0 || budget_ns > period_ns {
+            return false;
+        }
+        self.budget_ns = budget_ns;
+        self.period_ns = period_ns;
+        self.remaining_ns = budget_ns;
+        self.period_start_ns = ::time::monotonic_ns();
+        true
+    }
+
+    /// Replenish the budget if its period is over at monotonic time
+    /// `now_ns`. Returns whether any budget is left.
+    pub fn replenish(&mut self, now_ns: u64) -> bool {
+        if now_ns >= self.period_start_ns + self.period_ns {
+            self.period_start_ns = now_ns;
+            self.remaining_ns = self.budget_ns;
+        }
+        self.remaining_ns > 0
+    }
+
+    /// Charge `ns` nanoseconds the task ran to the budget.
+    pub fn charge(&mut self, ns: u64) {
+        self.remaining_ns = self.remaining_ns.saturating_sub(ns);
+    }
+
+    /// Budget left in the current period.
+    pub fn remaining_ns(&self) -> u64 {
+        self.remaining_ns
+    }
+
+    /// Monotonic time the current period ends at, and the budget is
+    /// replenished.
+    pub fn period_end_ns(&self) -> u64 {
+        self.period_start_ns + self.period_ns
+    }
+
+    /// Wake the CPU at the end of the current period, so that a task
+    /// descheduled for using up its budget runs again as soon as it is
+    /// replenished, rather than at the next interrupt. Armed once per
+    /// period, unless the timer wheel was full, in which case it is
+    /// armed again the next time the CPU wakes.
+    pub fn arm_wakeup(&mut self) {
+        if self.wakeup_period_ns == self.period_start_ns {
+            return;
+        }
+        if timer_wheel::add_wakeup(self.period_end_ns()) {
+            self.wakeup_period_ns = self.period_start_ns;
+        }
+    }
+}
+
+impl Derived for SchedContextDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &SchedContextCap) {
+        arc.unbind();
+    }
+}
@@ -0,0 +1,205 @@
+use common::*;
+use util::RwLock;
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc, ManagedWeakPool256Arc};
+use meminfo::MemoryCategory;
+use super::{UntypedCap, VSpaceCap, Derived, PAGE_LENGTH};
+
+/// Largest number of pages in a shared frame set.
+pub const MAX_SHARED_PAGES: usize = 512;
+
+/// Largest number of mappings of a shared frame set at once.
+pub const MAX_SHARED_MAPPINGS: usize = 32;
+
+/// Shared frame set descriptor.
+#[derive(Debug)]
+pub struct SharedFrameSetDescriptor {
+    start_paddr: PAddr,
+    page_count: usize,
+    /// VSpaces the set is mapped in, one entry per mapping.
+    mapped_weak_pool: ManagedWeakPool256Arc,
+    /// Address of each mapping in `mapped_weak_pool`.
+    mapped_vaddrs: [VAddr; MAX_SHARED_MAPPINGS],
+    /// The frames were returned to their untyped capability.
+    released: bool,
+    /// Untyped capability the frames are allocated from.
+    untyped_weak_pool: ManagedWeakPool1Arc,
+    next: Option<ManagedArcAny>,
+}
+/// Shared frame set capability. Reference-counted smart pointer to
+/// shared frame set descriptor.
+///
+/// A shared frame set is a physically contiguous run of zeroed
+/// frames, that can be mapped into any number of VSpaces, each with
+/// its own permissions. The frames can only be returned to their
+/// untyped capability once they are mapped nowhere.
+pub type SharedFrameSetCap = ManagedArc<RwLock<SharedFrameSetDescriptor>>;
+
+impl SharedFrameSetCap {
+    /// Create a set of `page_count` zeroed frames from `untyped`.
+    /// Returns `None` if `page_count` is zero or larger than
+    /// `MAX_SHARED_PAGES`.
+    pub fn retype_from(untyped: &UntypedCap, page_count: usize) -> Option<Self> {
+        if page_count == 0 || page_count > MAX_SHARED_PAGES {
+            return None;
+        }
+
+        let mut arc: Option<Self> = None;
+        let mut untyped_desc = untyped.write();
+
+        // Untyped memory is zeroed on allocation.
+        let start_paddr = unsafe {
+            untyped_desc.allocate_as(page_count * PAGE_LENGTH, PAGE_LENGTH, MemoryCategory::UserFrame)
+        };
+
+        let untyped_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped_desc.allocate(ManagedWeakPool1Arc::inner_length(),
+                                  ManagedWeakPool1Arc::inner_alignment())) };
+        untyped_weak_pool.read().downgrade_at(untyped, 0);
+
+        let mapped_weak_pool = unsafe { ManagedWeakPool256Arc::create(
+            untyped_desc.allocate(ManagedWeakPool256Arc::inner_length(),
+                                  ManagedWeakPool256Arc::inner_alignment())) };
+
+        unsafe { untyped_desc.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(SharedFrameSetDescriptor {
+                    start_paddr: start_paddr,
+                    page_count: page_count,
+                    mapped_weak_pool: mapped_weak_pool,
+                    mapped_vaddrs: [VAddr::from(0: usize); MAX_SHARED_MAPPINGS],
+                    released: false,
+                    untyped_weak_pool: untyped_weak_pool,
+                    next: next_child,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        arc
+    }
+}
+
+impl SharedFrameSetDescriptor {
+    /// Number of pages in the set.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    /// Physical address of the first page of the set.
+    pub fn start_paddr(&self) -> PAddr {
+        self.start_paddr
+    }
+
+    /// Whether the frames went back to their untyped capability.
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Number of mappings of the set. Mappings gone with the address
+    /// space they were in, whether it was revoked or torn down, are
+    /// forgotten here.
+    pub fn map_count(&mut self) -> usize {
+        let mapped_weak_pool = self.mapped_weak_pool.read();
+        let mut count = 0;
+        for index in 0..MAX_SHARED_MAPPINGS {
+            let vspace: Option<VSpaceCap> = mapped_weak_pool.upgrade(index);
+            if let Some(vspace) = vspace {
+                if vspace.read().maps_frames(self.mapped_vaddrs[index], self.start_paddr, self.page_count) {
+                    count += 1;
+                } else {
+                    mapped_weak_pool.remove(index);
+                }
+            }
+        }
+        count
+    }
+
+    /// Map the whole set at `vaddr` in `vspace`, read-only unless
+    /// `writable`. Returns `false` if the set was released, is already
+    /// mapped `MAX_SHARED_MAPPINGS` times, or the range cannot be
+    /// mapped.
+    pub fn map(&mut self, vspace: &VSpaceCap, vaddr: VAddr, writable: bool) -> bool {
+        if self.released {
+            return false;
+        }
+
+        self.map_count();
+        let index = match (0..MAX_SHARED_MAPPINGS).find(|&index| !self.mapped_weak_pool.read().is_occupied(index)) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let mapped = vspace.write().map_frames(vaddr, self.start_paddr, self.page_count, writable);
+        if mapped {
+            self.mapped_weak_pool.read().downgrade_at(vspace, index);
+            self.mapped_vaddrs[index] = vaddr;
+        }
+        mapped
+    }
+
+    /// Remove a mapping of the set at `vaddr` in `vspace`. Returns
+    /// `false` if the set is not mapped there.
+    pub fn unmap(&mut self, vspace: &VSpaceCap, vaddr: VAddr) -> bool {
+        if self.released {
+            return false;
+        }
+
+        let index = match (0..MAX_SHARED_MAPPINGS).find(|&index| {
+            self.mapped_vaddrs[index] == vaddr &&
+                self.mapped_weak_pool.read().upgrade(index)
+                    .map(|mapped: VSpaceCap| mapped.ptr_eq(vspace)).unwrap_or(false)
+        }) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let unmapped = vspace.write().unmap_frames(vaddr, self.start_paddr, self.page_count);
+        self.mapped_weak_pool.read().remove(index);
+        unmapped
+    }
+
+    /// Return the frames to the untyped capability they came from.
+    /// Returns `false` if the set is still mapped somewhere, or was
+    /// already released.
+    pub fn release(&mut self) -> bool {
+        if self.released || self.map_count() != 0 {
+            return false;
+        }
+
+        let untyped: UntypedCap = match self.untyped_weak_pool.read().upgrade(0) {
+            Some(untyped) => untyped,
+            None => return false,
+        };
+        let mut untyped_desc = untyped.write();
+        for i in 0..self.page_count {
+            unsafe {
+                untyped_desc.free_page(self.start_paddr + i * PAGE_LENGTH, MemoryCategory::UserFrame);
+            }
+        }
+
+        self.released = true;
+        true
+    }
+}
+
+impl Derived for SharedFrameSetDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &SharedFrameSetCap) {
+        // The frames go back with the rest of the untyped region, so
+        // no VSpace may keep them.
+        let mut desc = arc.write();
+        for index in 0..MAX_SHARED_MAPPINGS {
+            let vspace: Option<VSpaceCap> = desc.mapped_weak_pool.read().upgrade(index);
+            if let Some(vspace) = vspace {
+                vspace.write().unmap_frames(desc.mapped_vaddrs[index], desc.start_paddr, desc.page_count);
+            }
+        }
+        desc.mapped_weak_pool.read().clear();
+        desc.released = true;
+        desc.untyped_weak_pool.read().clear();
+    }
+}
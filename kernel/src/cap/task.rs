@@ -1,44 +1,87 @@
 use common::*;
-use core::iter::Iterator;
-use util::{RwLock, Mutex};
-use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool3Arc};
-use arch::{TaskRuntime, Exception};
+use core::{fmt, mem, ptr};
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use util::{RwLock, Mutex, MemoryObject};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc, ManagedWeakPool3Arc};
+use abi::{TaskRegisters, BreakpointKind, PRIORITY_DEFAULT};
+use timer_wheel::{self, TimerId};
+use arch::{self, TaskRuntime, RuntimeEntry, Exception, KernelStack, FpuState, FPU_STATE_ALIGNMENT, MAX_CPUS};
 
-use super::{UntypedDescriptor, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap};
-
-/// Switch to an idle task that runs in kernel-mode. This is used when
-/// no other tasks is runnable. Like normal context switching, this
-/// returns only when exceptions (interrupts) happen.
-pub fn idle() -> Exception {
-    #[naked]
-    unsafe fn idle_task() -> ! {
-        asm!("hlt");
-        ::core::intrinsics::unreachable();
-    }
-
-    let mut task_runtime = TaskRuntime::default();
-    task_runtime.set_instruction_pointer(VAddr::from(idle_task as *const () as u64));
-
-    unsafe {
-        task_runtime.switch_to(false)
-    }
-}
+use super::{UntypedDescriptor, TopPageTableCap, CPoolCap, TaskBufferPageCap, ChannelCap, EndpointCap, ReplyCap, NotificationCap, LdtCap, SchedContextCap,
+            Derived};
 
 /// Represent a task status.
 #[derive(Debug, Clone)]
 pub enum TaskStatus {
     Active,
     ChannelWait(ChannelCap),
+    /// Blocked sending to an endpoint, with the ticket that orders it
+    /// among the other senders.
+    EndpointSend(EndpointCap, u64),
+    /// Blocked receiving from an endpoint.
+    EndpointReceive(EndpointCap),
+    /// Called an endpoint, and waits for the reply.
+    ReplyWait(ReplyCap),
+    /// Blocked until a notification is signalled, or the deadline, if
+    /// any, passes.
+    NotificationWait(NotificationCap, Option<u64>),
+    /// Asleep until the deadline, in monotonic nanoseconds.
+    Sleep(u64),
     Inactive,
+    /// Ended with the exit code. The task never runs again.
+    Exited(u64),
 }
 
 /// Task descriptor.
 #[derive(Debug)]
 pub struct TaskDescriptor {
     weak_pool: ManagedWeakPool3Arc,
+    fault_weak_pool: ManagedWeakPool1Arc,
+    debugger_weak_pool: ManagedWeakPool1Arc,
+    ldt_weak_pool: ManagedWeakPool1Arc,
+    sched_context_weak_pool: ManagedWeakPool1Arc,
+    /// Notification whose signals the task also receives while it
+    /// receives from an endpoint.
+    notification_weak_pool: ManagedWeakPool1Arc,
+    /// Notification signalled when the task exits.
+    exit_notification_weak_pool: ManagedWeakPool1Arc,
+    /// Badge the exit notification is signalled with.
+    exit_badge: u64,
     runtime: TaskRuntime,
+    /// Stack the task enters the kernel on. `None` with KPTI, or if
+    /// none could be allocated, in which case it enters on the
+    /// scheduler's stack, or the entry stack with KPTI.
+    kernel_stack: Option<KernelStack>,
+    /// First entry of the receive window, where capabilities granted
+    /// to the task are placed.
+    receive_window: Option<CAddr>,
+    priority: u8,
+    /// Highest priority the task may give itself or other tasks.
+    max_priority: u8,
+    /// Priorities inherited from the callers the task serves.
+    inherited: InheritedPriorities,
+    /// Whether tasks of the same priority wait for their turn, so that
+    /// the task is preempted at the end of its time slice.
+    slice_shared: bool,
+    /// Time left of the task's time slice.
+    slice_left_ns: u64,
     next: Option<ManagedArcAny>,
-    next_task: Option<TaskCap>,
+    /// Place of the task in the run queues.
+    run_link: PAddr,
+    /// CPU the task runs on, if it does. The descriptor is not locked
+    /// while the task runs.
+    running: Option<usize>,
+    /// CPUs the task may run on, a bit for each.
+    affinity: u64,
+    /// Kept from running, whatever its status, until resumed.
+    suspended: bool,
+    /// When the call the task waits on the reply of entered the
+    /// kernel, in TSC cycles.
+    #[cfg(feature="kernel_debug")]
+    call_tsc: Option<u64>,
+    /// Timer waking the CPU at the deadline of a sleep or timed wait
+    /// the task is blocked in. Cancelled once its status changes.
+    wake_timer: Option<TimerId>,
     status: TaskStatus
 }
 /// Task capability. Reference-counted smart pointer to task
@@ -56,13 +99,76 @@ impl TaskCap {
             untyped.allocate(ManagedWeakPool3Arc::inner_length(),
                              ManagedWeakPool3Arc::inner_alignment())) };
 
+        let fault_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let debugger_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let ldt_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let sched_context_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let notification_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let exit_notification_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        let mut runtime = TaskRuntime::default();
+        runtime.set_fpu_state(unsafe { FpuState::new(
+            untyped.allocate(::arch::fpu_state_length(), FPU_STATE_ALIGNMENT)) });
+
+        let run_link = unsafe { untyped.allocate(mem::size_of::<RunLink>(), mem::align_of::<RunLink>()) };
+        unsafe {
+            ptr::write(MemoryObject::<RunLink>::new(run_link).as_ptr(), RunLink {
+                cpu: AtomicUsize::new(NOT_QUEUED),
+                level: 0,
+                affinity: !0,
+                task: None,
+                next: None,
+                blocked: false,
+                waits_on: [None, None],
+                wake_ns: None,
+                woken: AtomicBool::new(false),
+            });
+        }
+
         unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
             arc = Some(
                 Self::new(paddr, RwLock::new(TaskDescriptor {
                     weak_pool: weak_pool,
-                    runtime: TaskRuntime::default(),
+                    fault_weak_pool: fault_weak_pool,
+                    debugger_weak_pool: debugger_weak_pool,
+                    ldt_weak_pool: ldt_weak_pool,
+                    sched_context_weak_pool: sched_context_weak_pool,
+                    notification_weak_pool: notification_weak_pool,
+                    exit_notification_weak_pool: exit_notification_weak_pool,
+                    exit_badge: 0,
+                    runtime: runtime,
+                    kernel_stack: KernelStack::allocate(),
+                    receive_window: None,
+                    priority: PRIORITY_DEFAULT,
+                    max_priority: PRIORITY_DEFAULT,
+                    inherited: InheritedPriorities { counts: [0; PRIORITY_LEVELS] },
+                    slice_shared: false,
+                    slice_left_ns: TIME_SLICE_NS,
                     next: next_child,
-                    next_task: None,
+                    run_link: run_link,
+                    running: None,
+                    affinity: !0,
+                    suspended: false,
+                    #[cfg(feature="kernel_debug")]
+                    call_tsc: None,
+                    wake_timer: None,
                     status: TaskStatus::Inactive,
                 }))
             );
@@ -74,6 +180,222 @@ impl TaskCap {
 
         arc.unwrap()
     }
+
+    /// End the task with exit `code`. It leaves the run queues, its
+    /// kernel stack is freed, its scheduling context is unbound, or
+    /// given back if donated, and its exit notification is signalled.
+    /// The task object itself stays, for the exit code to be read,
+    /// until its capabilities are revoked.
+    pub fn exit(&self, code: u64) {
+        let (sched_context, notification, badge) = {
+            let mut desc = self.write();
+            desc.status = TaskStatus::Exited(code);
+            // A task running on another CPU still enters the kernel on
+            // its stack, which is freed once it did.
+            let running = desc.running;
+            match running {
+                Some(cpu) => arch::send_reschedule(cpu),
+                None => desc.kernel_stack = None,
+            }
+            (desc.upgrade_sched_context(),
+             desc.exit_notification_weak_pool.read().upgrade(0): Option<NotificationCap>,
+             desc.exit_badge)
+        };
+        unregister_task(self);
+        // Callers it served fail their call.
+        wake_waiters(self.ptr());
+
+        if let Some(sched_context) = sched_context {
+            if sched_context.is_donated() {
+                sched_context.give_back();
+            } else {
+                sched_context.unbind();
+            }
+        }
+        if let Some(notification) = notification {
+            notification.write().signal(badge);
+        }
+    }
+
+    /// Switch to the task. The function is returned when exception
+    /// happens, at the latest once the budget of its scheduling
+    /// context runs out, and the time the task ran is charged to it.
+    /// Returns the task the exception is of, which is the receiver if
+    /// a fastpath call switched to it, with the exception. Returns
+    /// `None` if the task cannot be claimed.
+    ///
+    /// The task descriptor is only locked to load the task into the
+    /// CPU and to save it back, not while the task runs, so that other
+    /// CPUs need not wait for it.
+    pub fn switch_to(&self) -> Option<(TaskCap, Exception)> {
+        debug_assert!(::preempt::preemptible(), "task switch while handling an interrupt");
+        let claim = match self.claim() {
+            Some(claim) => claim,
+            None => return None,
+        };
+        let mut entry = self.load(claim);
+
+        loop {
+            unsafe { entry.run(); }
+            // Back on the scheduler's stack, the kernel stacks of the
+            // callers a fastpath call switched away from are no longer
+            // in use.
+            let (task, left) = {
+                let mut run = CPU_RUNS[arch::current_cpu_id()].lock();
+                let task = run.task.as_ref().map(|&(ref task, _, _)| task.clone());
+                (task.expect("no task ran"), run.left.take())
+            };
+            if let Some(left) = left {
+                left.release();
+            }
+
+            let exception = unsafe { task.write().runtime.exit() };
+            match exception {
+                Some(exception) => {
+                    task.stop();
+                    task.release();
+                    return Some((task, exception));
+                },
+                None => entry = task.enter(),
+            }
+        }
+    }
+
+    /// Check that the task can run on the current CPU, and mark it as
+    /// running there, so that no other CPU runs it too. Returns `None`
+    /// if the task has no scheduling context with budget left, or runs
+    /// on another CPU. Returns `None`, and sets the task inactive, if
+    /// its top page table was revoked.
+    pub fn claim(&self) -> Option<TaskClaim> {
+        let mut desc = self.write();
+        if desc.running.is_some() {
+            return None;
+        }
+        let sched_context = match desc.upgrade_sched_context() {
+            Some(sched_context) => sched_context,
+            None => return None,
+        };
+        if !sched_context.write().replenish(::time::monotonic_ns()) {
+            sched_context.write().arm_wakeup();
+            return None;
+        }
+        let top_page_table = match desc.upgrade_top_page_table() {
+            Some(top_page_table) => top_page_table,
+            None => {
+                desc.status = TaskStatus::Inactive;
+                return None;
+            },
+        };
+        let ldt = desc.upgrade_ldt();
+
+        desc.running = Some(arch::current_cpu_id());
+        Some(TaskClaim {
+            sched_context: sched_context,
+            top_page_table: top_page_table,
+            ldt: ldt,
+        })
+    }
+
+    /// Mark the task, claimed with `claim`, as no longer running on
+    /// the current CPU. Its kernel stack is freed if it exited from
+    /// another CPU meanwhile.
+    pub fn release(&self) {
+        let mut desc = self.write();
+        desc.running = None;
+        if desc.exit_code().is_some() {
+            desc.kernel_stack = None;
+        }
+    }
+
+    /// Load the task, claimed with `claim`, into the current CPU, and
+    /// record it as the task the CPU runs. Returns the entry to run it
+    /// with.
+    fn load(&self, claim: TaskClaim) -> RuntimeEntry {
+        claim.top_page_table.write().switch_to();
+        match claim.ldt {
+            Some(ldt) => ldt.read().load(),
+            None => unsafe { ::arch::load_ldt(None) },
+        }
+        let start_ns = ::time::monotonic_ns();
+        {
+            let mut desc = self.write();
+            // Preempt the task when its budget runs out, or its time
+            // slice if it is not alone at its priority. The deadline is
+            // the CPU's own, and never needs an entry of the timer
+            // wheel.
+            let mut run_ns = claim.sched_context.read().remaining_ns();
+            if desc.slice_shared {
+                run_ns = ::core::cmp::min(run_ns, desc.slice_left_ns);
+            }
+            ::timer_wheel::set_preempt_deadline(start_ns.saturating_add(run_ns));
+            #[cfg(feature="kernel_debug")]
+            {
+                if let Some(call_tsc) = desc.call_tsc.take() {
+                    ::switch_stats::record_ipc_round_trip(call_tsc);
+                }
+                ::switch_stats::record_switch();
+            }
+        }
+
+        CPU_RUNS[arch::current_cpu_id()].lock().task =
+            Some((self.clone(), claim.sched_context, start_ns));
+        self.enter()
+    }
+
+    /// Enter the runtime of the task, loaded into the current CPU.
+    fn enter(&self) -> RuntimeEntry {
+        let mut guard = self.write();
+        let desc = &mut *guard;
+        let entry = unsafe { desc.runtime.enter(true, desc.kernel_stack.as_ref()) };
+        entry
+    }
+
+    /// Charge the time the task ran since it was loaded to its
+    /// scheduling context and time slice, and record that the current
+    /// CPU no longer runs it. The task stays claimed.
+    fn stop(&self) {
+        let run = CPU_RUNS[arch::current_cpu_id()].lock().task.take();
+        let (sched_context, start_ns) = match run {
+            Some((_, sched_context, start_ns)) => (sched_context, start_ns),
+            None => return,
+        };
+        #[cfg(feature="kernel_debug")]
+        ::switch_stats::record_entry();
+        ::timer_wheel::clear_preempt_deadline();
+        let ran_ns = ::time::monotonic_ns() - start_ns;
+        sched_context.write().charge(ran_ns);
+
+        let mut desc = self.write();
+        desc.slice_left_ns = desc.slice_left_ns.saturating_sub(ran_ns);
+    }
+
+    /// Hand the current CPU over from the task, which entered the
+    /// kernel with a fastpath call, to `receiver`, claimed with
+    /// `claim`, without going back to the scheduler. The task is saved
+    /// and charged, but stays claimed until the scheduler is back on
+    /// its own stack, as the fastpath runs on the task's kernel stack.
+    /// Returns the entry to run the receiver with.
+    pub fn hand_over(&self, receiver: &TaskCap, claim: TaskClaim) -> RuntimeEntry {
+        unsafe { self.write().runtime.exit() };
+        self.stop();
+        // A caller left before is no longer on the stack.
+        let left = mem::replace(&mut CPU_RUNS[arch::current_cpu_id()].lock().left,
+                                Some(self.clone()));
+        if let Some(left) = left {
+            left.release();
+        }
+
+        set_current_task(receiver);
+        receiver.load(claim)
+    }
+}
+
+/// What a task needs to run, held from when it is claimed until it is
+/// loaded, so that revoking any of it in between does not matter.
+pub struct TaskClaim {
+    sched_context: SchedContextCap,
+    top_page_table: TopPageTableCap,
+    ldt: Option<LdtCap>,
 }
 
 impl TaskDescriptor {
@@ -87,9 +409,27 @@ impl TaskDescriptor {
         self.runtime.set_stack_pointer(stack_pointer)
     }
 
-    /// Set the task's root capability pool.
+    /// Set the task's FS segment base. Returns `false` if it is not in
+    /// user space.
+    pub fn set_fs_base(&mut self, fs_base: VAddr) -> bool {
+        self.runtime.set_fs_base(fs_base)
+    }
+
+    /// The task's registers, as saved when it last entered the kernel.
+    pub fn registers(&self) -> TaskRegisters {
+        self.runtime.registers()
+    }
+
+    /// Set the task's registers.
+    pub fn set_registers(&mut self, registers: &TaskRegisters) {
+        self.runtime.set_registers(registers)
+    }
+
+    /// Set the task's root capability pool, replacing any set before.
     pub fn downgrade_cpool(&self, cpool: &CPoolCap) {
-        self.weak_pool.read().downgrade_at(cpool, 0)
+        let pool = self.weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(cpool, 0)
     }
 
     /// Read from the task's root capability pool.
@@ -97,9 +437,11 @@ impl TaskDescriptor {
         self.weak_pool.read().upgrade(0)
     }
 
-    /// Set the task's top page table.
+    /// Set the task's top page table, replacing any set before.
     pub fn downgrade_top_page_table(&self, pml4: &TopPageTableCap) {
-        self.weak_pool.read().downgrade_at(pml4, 1)
+        let pool = self.weak_pool.read();
+        pool.remove(1);
+        pool.downgrade_at(pml4, 1)
     }
 
     /// Read from the task's top page table.
@@ -107,9 +449,11 @@ impl TaskDescriptor {
         self.weak_pool.read().upgrade(1)
     }
 
-    /// Set the task's buffer.
+    /// Set the task's buffer, replacing any set before.
     pub fn downgrade_buffer(&self, buffer: &TaskBufferPageCap) {
-        self.weak_pool.read().downgrade_at(buffer, 2)
+        let pool = self.weak_pool.read();
+        pool.remove(2);
+        pool.downgrade_at(buffer, 2)
     }
 
     /// Read from the task's buffer.
@@ -117,70 +461,1084 @@ impl TaskDescriptor {
         self.weak_pool.read().upgrade(2)
     }
 
+    /// Set the channel that unhandled page faults, and the CPU faults
+    /// with a `FaultKind`, of the task are reported to, replacing any
+    /// set before.
+    pub fn downgrade_fault_handler(&self, channel: &ChannelCap) {
+        let pool = self.fault_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(channel, 0)
+    }
+
+    /// Read the task's fault handler channel.
+    pub fn upgrade_fault_handler(&self) -> Option<ChannelCap> {
+        self.fault_weak_pool.read().upgrade(0)
+    }
+
+    /// Set the channel that breakpoints and single steps of the task
+    /// are reported to, replacing any attached before.
+    pub fn downgrade_debugger(&self, channel: &ChannelCap) {
+        let pool = self.debugger_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(channel, 0)
+    }
+
+    /// Read the task's debugger channel.
+    pub fn upgrade_debugger(&self) -> Option<ChannelCap> {
+        self.debugger_weak_pool.read().upgrade(0)
+    }
+
+    /// Install `ldt` in the task, replacing any installed before.
+    pub fn downgrade_ldt(&self, ldt: &LdtCap) {
+        let pool = self.ldt_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(ldt, 0)
+    }
+
+    /// Read the task's LDT.
+    pub fn upgrade_ldt(&self) -> Option<LdtCap> {
+        self.ldt_weak_pool.read().upgrade(0)
+    }
+
+    /// Record the scheduling context the task is bound to. Use
+    /// `SchedContextCap::bind` to bind one.
+    pub fn downgrade_sched_context(&self, sched_context: &SchedContextCap) {
+        self.sched_context_weak_pool.read().downgrade_at(sched_context, 0)
+    }
+
+    /// Read the scheduling context the task is bound to.
+    pub fn upgrade_sched_context(&self) -> Option<SchedContextCap> {
+        self.sched_context_weak_pool.read().upgrade(0)
+    }
+
+    /// Forget the scheduling context the task was bound to.
+    pub fn remove_sched_context(&self) {
+        self.sched_context_weak_pool.read().remove(0);
+    }
+
+    /// Bind a notification to the task, replacing any bound before.
+    pub fn downgrade_notification(&self, notification: &NotificationCap) {
+        let pool = self.notification_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(notification, 0)
+    }
+
+    /// Read the notification bound to the task.
+    pub fn upgrade_notification(&self) -> Option<NotificationCap> {
+        self.notification_weak_pool.read().upgrade(0)
+    }
+
+    /// Unbind the notification bound to the task.
+    pub fn remove_notification(&self) {
+        self.notification_weak_pool.read().remove(0);
+    }
+
+    /// Whether the task is bound to a scheduling context with budget
+    /// left, so that it can run now.
+    pub fn has_budget(&self) -> bool {
+        match self.upgrade_sched_context() {
+            Some(sched_context) => sched_context.write().replenish(::time::monotonic_ns()),
+            None => false,
+        }
+    }
+
+    /// Set the first entry of the task's receive window, an address in
+    /// its root capability pool, or disable receiving capabilities.
+    pub fn set_receive_window(&mut self, window: Option<CAddr>) {
+        self.receive_window = window;
+    }
+
+    /// First entry of the task's receive window.
+    pub fn receive_window(&self) -> Option<CAddr> {
+        self.receive_window
+    }
+
+    /// Priority the task runs at, and is queued at on endpoints: its
+    /// own, or the highest it inherited if that is higher.
+    pub fn priority(&self) -> u8 {
+        match self.inherited.highest() {
+            Some(inherited) if inherited > self.priority => inherited,
+            _ => self.priority,
+        }
+    }
+
+    /// Set the task's own priority. Use `requeue_task` after, so that
+    /// it is scheduled at the new priority.
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    /// Highest priority the task may set, for itself or for other
+    /// tasks, and highest maximum priority it may hand out.
+    pub fn max_priority(&self) -> u8 {
+        self.max_priority
+    }
+
+    /// Set the highest priority the task may set.
+    pub fn set_max_priority(&mut self, max_priority: u8) {
+        self.max_priority = max_priority;
+    }
+
+    /// Inherit `priority` from a caller, until `restore_priority` is
+    /// called with it once the call is replied to.
+    pub fn inherit_priority(&mut self, priority: u8) {
+        self.inherited.counts[priority as usize] += 1;
+    }
+
+    /// Stop inheriting `priority` from a caller replied to. The task
+    /// keeps the highest priority of the callers it still serves.
+    pub fn restore_priority(&mut self, priority: u8) {
+        assert!(self.inherited.counts[priority as usize] > 0);
+        self.inherited.counts[priority as usize] -= 1;
+    }
+
+    /// Set a hardware breakpoint of the task. Returns `false` if it is
+    /// invalid.
+    pub fn set_breakpoint(&mut self, slot: usize, address: u64, kind: BreakpointKind, length: u8) -> bool {
+        self.runtime.set_breakpoint(slot, address, kind, length)
+    }
+
+    /// Clear a hardware breakpoint of the task. Returns `false` if it
+    /// was not set.
+    pub fn clear_breakpoint(&mut self, slot: usize) -> bool {
+        self.runtime.clear_breakpoint(slot)
+    }
+
+    /// Whether the task used up its time slice, and is to make way for
+    /// the other tasks of its priority.
+    pub fn slice_expired(&self) -> bool {
+        self.slice_left_ns == 0
+    }
+
     /// Current task status.
     pub fn status(&self) -> TaskStatus {
         self.status.clone()
     }
 
-    /// Set the current task status.
+    /// Set the current task status. An exited task keeps its status.
+    /// The timer of a sleep or timed wait the task was blocked in is
+    /// cancelled, as it has nothing left to wake, and the task is
+    /// woken, to be tried in its new status.
     pub fn set_status(&mut self, status: TaskStatus) {
+        if let TaskStatus::Exited(_) = self.status {
+            return;
+        }
+        self.cancel_wake_timer();
         self.status = status;
+        wake_link(self.run_link);
     }
 
-    /// Switch to the task. The function is returned when exception
-    /// happens.
-    pub fn switch_to(&mut self) -> Exception {
-        if let Some(pml4) = self.upgrade_top_page_table() {
-            pml4.write().switch_to();
+    /// Objects whose events may let the task run, by address: those
+    /// it waits on, and for a call, the server, which fails it by
+    /// exiting.
+    fn waits_on(&self) -> [Option<PAddr>; 2] {
+        match self.status {
+            TaskStatus::EndpointReceive(ref endpoint) =>
+                [Some(endpoint.ptr()), self.upgrade_notification().map(|notification| notification.ptr())],
+            TaskStatus::NotificationWait(ref notification, _) => [Some(notification.ptr()), None],
+            TaskStatus::ChannelWait(ref chan) => [Some(chan.ptr()), None],
+            TaskStatus::ReplyWait(ref reply) => [reply.server().map(|server| server.ptr()), None],
+            _ => [None, None],
+        }
+    }
+
+    /// Time the task may run at without any event: the deadline of
+    /// its sleep or timed wait, or the end of the period of its
+    /// scheduling context, if it has no budget left, which the CPU is
+    /// woken at.
+    fn arm_wake_deadline(&self) -> Option<u64> {
+        let deadline = match self.status {
+            TaskStatus::Sleep(deadline) => Some(deadline),
+            TaskStatus::NotificationWait(_, deadline) => deadline,
+            _ => None,
+        };
+        let replenished = self.upgrade_sched_context().and_then(|sched_context| {
+            let mut desc = sched_context.write();
+            let end_ns = if desc.replenish(::time::monotonic_ns()) {
+                None
+            } else {
+                desc.arm_wakeup();
+                Some(desc.period_end_ns())
+            };
+            end_ns
+        });
+        match (deadline, replenished) {
+            (Some(deadline), Some(replenished)) => Some(::core::cmp::min(deadline, replenished)),
+            (deadline, replenished) => deadline.or(replenished),
+        }
+    }
+
+    /// Block the task in `status`, a sleep or timed wait, woken by
+    /// the timer `wake_timer` at its deadline.
+    pub fn set_timed_status(&mut self, status: TaskStatus, wake_timer: TimerId) {
+        self.set_status(status);
+        self.wake_timer = Some(wake_timer);
+    }
+
+    fn cancel_wake_timer(&mut self) {
+        if let Some(wake_timer) = self.wake_timer.take() {
+            timer_wheel::cancel(wake_timer);
+        }
+    }
+
+    /// Whether the task has a kernel stack, to enter the kernel on.
+    pub fn has_kernel_stack(&self) -> bool {
+        self.kernel_stack.is_some()
+    }
+
+    /// Exit code of the task, or `None` if it did not exit.
+    pub fn exit_code(&self) -> Option<u64> {
+        match self.status {
+            TaskStatus::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Signal `notification` with `badge` when the task exits, or
+    /// nothing if `None`.
+    pub fn bind_exit_notification(&mut self, notification: Option<&NotificationCap>, badge: u64) {
+        match notification {
+            Some(notification) => {
+                let pool = self.exit_notification_weak_pool.read();
+                pool.remove(0);
+                pool.downgrade_at(notification, 0);
+            },
+            None => self.exit_notification_weak_pool.read().clear(),
+        }
+        self.exit_badge = badge;
+    }
+
+    /// Whether the task is suspended. A suspended task keeps its
+    /// status, so that it goes on waiting where it was once resumed,
+    /// but does not run.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Suspend or resume the task.
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+        if !suspended {
+            wake_link(self.run_link);
+        }
+    }
+
+    /// Time the call the task just made, until it resumes with the
+    /// reply, for the IPC round trip statistics of debug builds.
+    pub fn start_call_timing(&mut self) {
+        #[cfg(feature="kernel_debug")]
+        {
+            self.call_tsc = ::switch_stats::entry_tsc();
         }
-        unsafe { self.runtime.switch_to(true) }
     }
 }
 
-/// The first task initialized by the kernel.
-static FIRST_TASK: Mutex<Option<TaskCap>> = Mutex::new(None);
+impl Derived for TaskDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
 
-/// Register a new task. Using `FIRST_TASK` static, this forms a
-/// linked-list that allows an iterator to iterate over all created
-/// tasks.
-fn register_task(cap: TaskCap) {
-    let mut first_task = FIRST_TASK.lock();
-    if first_task.is_none() {
-        *first_task = Some(cap);
+    fn revoke(arc: &TaskCap) {
+        let sched_context = {
+            let mut desc = arc.write();
+            let sched_context = desc.upgrade_sched_context();
+            desc.cancel_wake_timer();
+            desc.status = TaskStatus::Inactive;
+            desc.weak_pool.read().clear();
+            desc.fault_weak_pool.read().clear();
+            desc.debugger_weak_pool.read().clear();
+            desc.ldt_weak_pool.read().clear();
+            desc.sched_context_weak_pool.read().clear();
+            desc.notification_weak_pool.read().clear();
+            desc.exit_notification_weak_pool.read().clear();
+            sched_context
+        };
+        // Time the task ran on by donation goes back to its donor.
+        if let Some(sched_context) = sched_context {
+            if sched_context.is_donated() {
+                sched_context.give_back();
+            }
+        }
+        unregister_task(arc);
+        wake_waiters(arc.ptr());
+    }
+}
+
+/// Number of run queue levels, one for each task priority.
+const PRIORITY_LEVELS: usize = 256;
+
+/// Number of callers a task serves at each priority, those of the
+/// reply objects it inherited their priority through that are not
+/// replied to yet.
+struct InheritedPriorities {
+    counts: [u16; PRIORITY_LEVELS],
+}
+
+impl InheritedPriorities {
+    /// Highest priority of a caller served, if any.
+    fn highest(&self) -> Option<u8> {
+        (0..PRIORITY_LEVELS).rev().find(|&level| self.counts[level] != 0).map(|level| level as u8)
+    }
+}
+
+impl fmt::Debug for InheritedPriorities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InheritedPriorities {{ highest: {:?} }}", self.highest())
+    }
+}
+
+/// Time a task runs before the other tasks of its priority get their
+/// turn. Only enforced while there are any.
+const TIME_SLICE_NS: u64 = 10_000_000;
+
+/// Write `$e` sixteen times in an array, for types that are not
+/// `Copy`.
+macro_rules! array_16 {
+    ($e:expr) => { [$e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e] }
+}
+
+/// No CPU, in the run link of a task out of the run queues.
+const NOT_QUEUED: usize = !0;
+
+/// Place of a task in the run queues. It is kept apart from the task
+/// descriptor, so that the run queues never need the lock of a task.
+/// Apart from `cpu` and `woken`, a run link is only used with the
+/// queues of that CPU locked.
+struct RunLink {
+    /// CPU whose queues the task is in, `NOT_QUEUED` once it exited or
+    /// was revoked. Read without the queues locked to know which ones
+    /// to lock, and checked again once they are.
+    cpu: AtomicUsize,
+    /// Level the task is in, or goes back to once woken.
+    level: u8,
+    /// CPUs the task may run on, as last set on the task.
+    affinity: u64,
+    /// The task, while it is queued.
+    task: Option<TaskCap>,
+    /// Run link of the next task in the same level, or in the blocked
+    /// list.
+    next: Option<PAddr>,
+    /// Whether the task is in the blocked list rather than its level.
+    blocked: bool,
+    /// Objects whose events may let the blocked task run, by address.
+    waits_on: [Option<PAddr>; 2],
+    /// Monotonic time the blocked task may run at, if any.
+    wake_ns: Option<u64>,
+    /// Whether the task may have been let run since the CPU last
+    /// picked it, so that it is not blocked for what was found then.
+    woken: AtomicBool,
+}
+
+/// Use the run link at `paddr`.
+fn with_link<R, F: FnOnce(&mut RunLink) -> R>(paddr: PAddr, f: F) -> R {
+    let mut link = unsafe { MemoryObject::<RunLink>::new(paddr) };
+    f(unsafe { link.as_mut() })
+}
+
+/// CPU whose queues the task of the run link at `paddr` is in, or
+/// `NOT_QUEUED`.
+fn queued_cpu(paddr: PAddr) -> usize {
+    let link = unsafe { MemoryObject::<RunLink>::new(paddr) };
+    unsafe { link.as_ref() }.cpu.load(Ordering::SeqCst)
+}
+
+/// Tasks of one priority, by their run links.
+struct RunQueueLevel {
+    head: Option<PAddr>,
+    tail: Option<PAddr>,
+}
+
+/// Tasks of a CPU. Those that may run are in the level of their
+/// priority, and the CPU runs the head of the highest level with tasks
+/// in it, found from the bitmap. A task stays at the head of its level
+/// until its time slice runs out, or it yields. Tasks found unable to
+/// run are kept in the blocked list instead, until woken.
+struct RunQueues {
+    /// Level of priority `p` at `levels[p >> 4][p & 0xf]`.
+    levels: [[RunQueueLevel; 16]; 16],
+    /// A bit for each level with tasks in it.
+    bitmap: [u64; PRIORITY_LEVELS / 64],
+    /// Number of tasks in the levels.
+    count: usize,
+    /// Run link of the task blocked last, followed by the others.
+    blocked: Option<PAddr>,
+    /// Number of blocked tasks with a deadline.
+    timed: usize,
+    /// Run link of the task the CPU last picked, which other CPUs do
+    /// not steal.
+    current: Option<PAddr>,
+}
+
+/// Run queues of each CPU, indexed by local APIC id. Every task is in
+/// the queues of exactly one CPU.
+static RUN_QUEUES: [Mutex<RunQueues>; MAX_CPUS] = array_16!(Mutex::new(RunQueues {
+    levels: array_16!(array_16!(RunQueueLevel { head: None, tail: None })),
+    bitmap: [0; PRIORITY_LEVELS / 64],
+    count: 0,
+    blocked: None,
+    timed: 0,
+    current: None,
+}));
+
+/// The task running on a CPU.
+struct CpuRun {
+    /// The task, with the scheduling context it is charged to, and
+    /// when it was loaded, in monotonic nanoseconds.
+    task: Option<(TaskCap, SchedContextCap, u64)>,
+    /// The last caller a fastpath call switched away from, still
+    /// claimed, as the receiver may run on its kernel stack.
+    left: Option<TaskCap>,
+}
+
+/// The task running on each CPU, indexed by local APIC id.
+static CPU_RUNS: [Mutex<CpuRun>; MAX_CPUS] = array_16!(Mutex::new(CpuRun {
+    task: None,
+    left: None,
+}));
+
+/// The task running on the current CPU, if any.
+pub fn running_task() -> Option<TaskCap> {
+    let run = CPU_RUNS[arch::current_cpu_id()].lock();
+    let task = run.task.as_ref().map(|&(ref task, _, _)| task.clone());
+    task
+}
+
+impl RunQueues {
+    fn level(&self, level: usize) -> &RunQueueLevel {
+        &self.levels[level >> 4][level & 0xf]
+    }
+
+    fn level_mut(&mut self, level: usize) -> &mut RunQueueLevel {
+        &mut self.levels[level >> 4][level & 0xf]
+    }
+
+    /// Highest level below `limit` with tasks in it.
+    fn highest_below(&self, limit: usize) -> Option<usize> {
+        let mut end = limit;
+        while end > 0 {
+            let word = (end - 1) / 64;
+            let bits = (end - 1) % 64 + 1;
+            let mask = if bits == 64 { !0 } else { (1u64 << bits) - 1 };
+            let levels = self.bitmap[word] & mask;
+            if levels != 0 {
+                return Some(word * 64 + 63 - levels.leading_zeros() as usize);
+            }
+            end = word * 64;
+        }
+        None
+    }
+
+    /// Add `task`, of the run link `link`, at the tail of `level`, on
+    /// the CPU `cpu` the queues belong to.
+    fn push_back(&mut self, link: PAddr, task: TaskCap, cpu: usize, level: u8) {
+        with_link(link, |link| {
+            link.cpu.store(cpu, Ordering::SeqCst);
+            link.level = level;
+            link.task = Some(task);
+            link.next = None;
+            link.blocked = false;
+        });
+        let level = level as usize;
+        match self.level_mut(level).tail.take() {
+            Some(tail) => with_link(tail, |tail| tail.next = Some(link)),
+            None => self.level_mut(level).head = Some(link),
+        }
+        self.level_mut(level).tail = Some(link);
+        self.bitmap[level / 64] |= 1 << (level % 64);
+        self.count += 1;
+    }
+
+    /// Add `task`, of the run link `link`, to the blocked list, on the
+    /// CPU `cpu` the queues belong to, to go back to `level` once
+    /// woken. What it waits for is kept in the run link.
+    fn push_blocked(&mut self, link: PAddr, task: TaskCap, cpu: usize, level: u8) {
+        let next = self.blocked.take();
+        let timed = with_link(link, |link| {
+            link.cpu.store(cpu, Ordering::SeqCst);
+            link.level = level;
+            link.task = Some(task);
+            link.next = next;
+            link.blocked = true;
+            link.wake_ns.is_some()
+        });
+        self.blocked = Some(link);
+        if timed {
+            self.timed += 1;
+        }
+    }
+
+    /// Add `task` back after `remove`, to the blocked list if it was
+    /// blocked, or at the tail of `level` otherwise.
+    fn insert(&mut self, link: PAddr, task: TaskCap, cpu: usize, level: u8, blocked: bool) {
+        if blocked {
+            self.push_blocked(link, task, cpu, level);
+        } else {
+            self.push_back(link, task, cpu, level);
+        }
+    }
+
+    /// Take the task of the run link `link` out of its level, or of
+    /// the blocked list, if it is in these queues, those of the CPU
+    /// `cpu`, and return it with its level, and whether it was
+    /// blocked. Returns `None`, changing nothing, otherwise.
+    fn remove(&mut self, link: PAddr, cpu: usize) -> Option<(TaskCap, u8, bool)> {
+        let (level, blocked, timed, next) = match with_link(link, |link| {
+            if link.cpu.load(Ordering::SeqCst) == cpu {
+                Some((link.level, link.blocked, link.wake_ns.is_some(), link.next.take()))
+            } else {
+                None
+            }
+        }) {
+            Some(queued) => queued,
+            None => return None,
+        };
+
+        if blocked {
+            let mut previous: Option<PAddr> = None;
+            let mut current = self.blocked;
+            while let Some(candidate) = current {
+                if candidate == link {
+                    break;
+                }
+                current = with_link(candidate, |candidate| candidate.next);
+                previous = Some(candidate);
+            }
+            match previous {
+                Some(previous) => with_link(previous, |previous| previous.next = next),
+                None => self.blocked = next,
+            }
+            if timed {
+                self.timed -= 1;
+            }
+        } else {
+            let index = level as usize;
+            let mut previous: Option<PAddr> = None;
+            let mut current = self.level(index).head;
+            while let Some(candidate) = current {
+                if candidate == link {
+                    break;
+                }
+                current = with_link(candidate, |candidate| candidate.next);
+                previous = Some(candidate);
+            }
+            let is_tail = self.level(index).tail == Some(link);
+            match previous {
+                Some(previous) => with_link(previous, |previous| previous.next = next),
+                None => self.level_mut(index).head = next,
+            }
+            if is_tail {
+                self.level_mut(index).tail = previous;
+            }
+            if self.level(index).head.is_none() {
+                self.bitmap[index / 64] &= !(1 << (index % 64));
+            }
+            self.count -= 1;
+        }
+
+        let task = with_link(link, |link| {
+            link.cpu.store(NOT_QUEUED, Ordering::SeqCst);
+            link.task.take()
+        });
+        task.map(|task| (task, level, blocked))
+    }
+
+    /// Move the task of the run link `link`, if it is blocked in these
+    /// queues, those of the CPU `cpu`, back to the tail of its level.
+    /// Returns whether it was.
+    fn unblock(&mut self, link: PAddr, cpu: usize) -> bool {
+        match self.remove(link, cpu) {
+            Some((task, level, true)) => {
+                with_link(link, |link| link.wake_ns = None);
+                self.push_back(link, task, cpu, level);
+                true
+            },
+            Some((task, level, false)) => {
+                self.push_back(link, task, cpu, level);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Move the blocked tasks whose run link `f` returns `true` for
+    /// back to the tail of their level, on the CPU `cpu` the queues
+    /// belong to. Returns whether there were any.
+    fn unblock_where<F: Fn(&RunLink) -> bool>(&mut self, cpu: usize, f: F) -> bool {
+        let mut unblocked = false;
+        let mut previous: Option<PAddr> = None;
+        let mut current = self.blocked;
+        while let Some(link) = current {
+            let (matches, next) = with_link(link, |link| (f(&*link), link.next));
+            current = next;
+            if !matches {
+                previous = Some(link);
+                continue;
+            }
+
+            match previous {
+                Some(previous) => with_link(previous, |previous| previous.next = next),
+                None => self.blocked = next,
+            }
+            let (task, level, timed) = with_link(link, |link| {
+                (link.task.take(), link.level, link.wake_ns.take().is_some())
+            });
+            if timed {
+                self.timed -= 1;
+            }
+            self.push_back(link, task.unwrap(), cpu, level);
+            unblocked = true;
+        }
+        unblocked
+    }
+
+    /// Mark the task the CPU `cpu` the queues belong to picked last as
+    /// woken, if it is in these queues, so that it is not blocked for
+    /// what was found before an event.
+    fn wake_current(&self, cpu: usize) {
+        if let Some(current) = self.current {
+            with_link(current, |link| {
+                if link.cpu.load(Ordering::SeqCst) == cpu {
+                    link.woken.store(true, Ordering::SeqCst);
+                }
+            });
+        }
+    }
+
+    /// Run link of a task other CPUs can take over: one in its level,
+    /// not the one last picked, and allowed on `cpu`. The most urgent
+    /// one is found first.
+    fn stealable(&self, cpu: usize) -> Option<PAddr> {
+        let mut limit = PRIORITY_LEVELS;
+        while let Some(level) = self.highest_below(limit) {
+            let mut current = self.level(level).head;
+            while let Some(candidate) = current {
+                let (allowed, next) = with_link(candidate, |link| {
+                    (link.affinity & (1 << cpu) != 0, link.next)
+                });
+                if allowed && self.current != Some(candidate) {
+                    return Some(candidate);
+                }
+                current = next;
+            }
+            limit = level;
+        }
+        None
+    }
+}
+
+/// Lock the queues of `cpu` and of `other`, another CPU, lower CPU
+/// first, so that a task moved between them is in one of them all
+/// along, and run `f` on them, in that order.
+fn with_queue_pair<R, F: FnOnce(&mut RunQueues, &mut RunQueues) -> R>(cpu: usize, other: usize, f: F) -> R {
+    let (first, second) = if cpu < other { (cpu, other) } else { (other, cpu) };
+    let mut first_queues = RUN_QUEUES[first].lock();
+    let mut second_queues = RUN_QUEUES[second].lock();
+    let result = if cpu < other {
+        f(&mut *first_queues, &mut *second_queues)
     } else {
-        let mut first = first_task.as_mut().unwrap().write();
-        let mut second = cap.write();
-        let third_task = first.next_task.take();
+        f(&mut *second_queues, &mut *first_queues)
+    };
+    result
+}
+
+/// Online CPU, in `affinity`, with the fewest tasks able to run.
+/// `None` if no CPU in `affinity` is online.
+fn least_loaded_cpu(affinity: u64) -> Option<usize> {
+    (0..MAX_CPUS)
+        .filter(|&cpu| affinity & (1 << cpu) != 0 && arch::is_cpu_online(cpu))
+        .min_by_key(|&cpu| RUN_QUEUES[cpu].lock().count)
+}
 
-        second.next_task = third_task;
-        first.next_task = Some(cap.clone());
+/// Register a new task, in the run queues of the least loaded CPU, at
+/// the level of its priority. If that CPU went offline in the
+/// meantime, the task goes to another online CPU instead.
+fn register_task(cap: TaskCap) {
+    let (link, priority) = {
+        let desc = cap.read();
+        (desc.run_link, desc.priority())
+    };
+    let mut cpu = least_loaded_cpu(!0).unwrap_or(arch::current_cpu_id());
+    loop {
+        // Checked with the queues locked, as a CPU taken offline hands
+        // its tasks over with them locked, and is given none after.
+        {
+            let mut queues = RUN_QUEUES[cpu].lock();
+            if arch::is_cpu_online(cpu) {
+                queues.push_back(link, cap, cpu, priority);
+                break;
+            }
+        }
+        match least_loaded_cpu(!0) {
+            Some(target) => cpu = target,
+            // No CPU is online yet, while booting.
+            None => {
+                RUN_QUEUES[cpu].lock().push_back(link, cap, cpu, priority);
+                break;
+            },
+        }
+    }
+    if cpu != arch::current_cpu_id() {
+        arch::send_reschedule(cpu);
     }
 }
 
-/// A task iterator.
-pub struct TaskIterator {
-    next: Option<TaskCap>,
+/// Remove a task from the run queues, if it is there, so that it is
+/// never scheduled again.
+fn unregister_task(cap: &TaskCap) {
+    let link = cap.read().run_link;
+    loop {
+        let cpu = queued_cpu(link);
+        if cpu == NOT_QUEUED {
+            break;
+        }
+        // Moved to another CPU since it was read, if not removed.
+        if RUN_QUEUES[cpu].lock().remove(link, cpu).is_some() {
+            break;
+        }
+    }
+    // Nor is its run link marked woken anymore.
+    for queues in RUN_QUEUES.iter() {
+        let mut queues = queues.lock();
+        if queues.current == Some(link) {
+            queues.current = None;
+        }
+    }
 }
 
-impl Iterator for TaskIterator {
-    type Item = TaskCap;
+/// Move `task` to the level of its priority, after the priority
+/// changed, and to a CPU in its affinity, after that changed. Does
+/// nothing if it is there already, or was revoked. A blocked task
+/// stays blocked, and goes to its new level once woken.
+pub fn requeue_task(task: &TaskCap) {
+    let (link, priority, affinity) = {
+        let desc = task.read();
+        (desc.run_link, desc.priority(), desc.affinity)
+    };
+    loop {
+        let cpu = queued_cpu(link);
+        if cpu == NOT_QUEUED {
+            return;
+        }
+        let target = if affinity & (1 << cpu) != 0 && arch::is_cpu_online(cpu) {
+            cpu
+        } else {
+            least_loaded_cpu(affinity).unwrap_or(cpu)
+        };
 
-    fn next(&mut self) -> Option<TaskCap> {
-        if let Some(current) = self.next.clone() {
-            {
-                let current_task = current.read();
-                self.next = current_task.next_task.clone();
+        // Checked again with the queues locked, as the task may have
+        // been moved to another CPU since, and the target gone
+        // offline.
+        let moved = if target == cpu {
+            let mut queues = RUN_QUEUES[cpu].lock();
+            let queued = with_link(link, |link| {
+                if link.cpu.load(Ordering::SeqCst) == cpu {
+                    link.affinity = affinity;
+                    Some((link.level, link.blocked))
+                } else {
+                    None
+                }
+            });
+            match queued {
+                Some((level, _)) if level == priority => return,
+                Some((_, true)) => {
+                    with_link(link, |link| link.level = priority);
+                    return;
+                },
+                Some((_, false)) => {
+                    let (task, _, _) = queues.remove(link, cpu).unwrap();
+                    queues.push_back(link, task, cpu, priority);
+                    true
+                },
+                None => false,
             }
-            return Some(current);
         } else {
-            None
+            with_queue_pair(cpu, target, |own, other| {
+                if !arch::is_cpu_online(target) {
+                    return false;
+                }
+                match own.remove(link, cpu) {
+                    Some((task, _, blocked)) => {
+                        with_link(link, |link| link.affinity = affinity);
+                        other.insert(link, task, target, priority, blocked);
+                        true
+                    },
+                    None => false,
+                }
+            })
+        };
+        if moved {
+            if target != arch::current_cpu_id() {
+                arch::send_reschedule(target);
+            }
+            return;
         }
     }
 }
 
-/// Return a task iterator using `FIRST_TASK`.
-pub fn task_iter() -> TaskIterator {
-    TaskIterator {
-        next: FIRST_TASK.lock().clone(),
+/// Give the turn to the next task of the priority of `task`, which
+/// used up its time slice or yielded: move it to the tail of its
+/// level, with a new time slice.
+pub fn rotate_task(task: &TaskCap) {
+    let link = {
+        let mut desc = task.write();
+        desc.slice_left_ns = TIME_SLICE_NS;
+        desc.run_link
+    };
+    loop {
+        let cpu = queued_cpu(link);
+        if cpu == NOT_QUEUED {
+            return;
+        }
+        let mut queues = RUN_QUEUES[cpu].lock();
+        // Checked again with the queues locked, as the task may have
+        // been moved to another CPU since.
+        match queues.remove(link, cpu) {
+            Some((task, level, blocked)) => {
+                queues.insert(link, task, cpu, level, blocked);
+                return;
+            },
+            None => continue,
+        }
+    }
+}
+
+/// Restrict `task` to the CPUs in `affinity`, a bit for each. Returns
+/// `false`, changing nothing, if none of them is online.
+pub fn set_task_affinity(task: &TaskCap, affinity: u64) -> bool {
+    if least_loaded_cpu(affinity).is_none() {
+        return false;
+    }
+    task.write().affinity = affinity;
+    requeue_task(task);
+    true
+}
+
+/// Task the current CPU runs next: the head of the highest level with
+/// tasks in it, found from the bitmap of the levels without going
+/// through any task. `None` if no task may run.
+pub fn pick_task() -> Option<TaskCap> {
+    let queues = RUN_QUEUES[arch::current_cpu_id()].lock();
+    let task = queues.highest_below(PRIORITY_LEVELS)
+        .and_then(|level| queues.level(level).head)
+        .and_then(|link| with_link(link, |link| link.task.clone()));
+    task
+}
+
+/// Record that the current CPU picked `task` to try next, so that
+/// other CPUs leave it alone, and whether the task shares its level,
+/// so that it only needs a time slice then.
+pub fn set_current_task(task: &TaskCap) {
+    let cpu = arch::current_cpu_id();
+    let link = task.read().run_link;
+    let shared = {
+        let mut queues = RUN_QUEUES[cpu].lock();
+        queues.current = Some(link);
+
+        let (queued, level) = with_link(link, |link| {
+            let queued = link.cpu.load(Ordering::SeqCst);
+            if queued == cpu {
+                link.woken.store(false, Ordering::SeqCst);
+            }
+            (queued, link.level)
+        });
+        if queued == cpu {
+            let level = queues.level(level as usize);
+            level.head.is_some() && level.head != level.tail
+        } else {
+            // Run from another CPU's queues, as a fastpath receiver.
+            queued != NOT_QUEUED
+        }
+    };
+    task.write().slice_shared = shared;
+}
+
+/// Take `task`, picked by the current CPU but found unable to run,
+/// out of its level, until it is woken: by `wake_waiters` for an
+/// object it waits on, by `wake_expired` once its deadline passed, or
+/// by `wake_task` once it changed. A task woken since it was picked
+/// stays, to be tried again, and one running on another CPU only makes
+/// way for the other tasks of its level.
+pub fn block_task(task: &TaskCap) {
+    let (link, running, waits_on, wake_ns) = {
+        let desc = task.read();
+        (desc.run_link, desc.running.is_some(), desc.waits_on(), desc.arm_wake_deadline())
+    };
+    if running {
+        rotate_task(task);
+        return;
+    }
+
+    let cpu = arch::current_cpu_id();
+    let mut queues = RUN_QUEUES[cpu].lock();
+    let woken = with_link(link, |link| {
+        link.cpu.load(Ordering::SeqCst) != cpu || link.woken.load(Ordering::SeqCst)
+    });
+    if woken || wake_ns.map_or(false, |wake_ns| wake_ns <= ::time::monotonic_ns()) {
+        return;
+    }
+    if let Some((task, level, _)) = queues.remove(link, cpu) {
+        with_link(link, |link| {
+            link.waits_on = waits_on;
+            link.wake_ns = wake_ns;
+        });
+        queues.push_blocked(link, task, cpu, level);
+    }
+}
+
+/// Let the task of the run link `link` run again if it is blocked, or
+/// mark it woken otherwise, after it changed in a way that may let it
+/// run.
+fn wake_link(link: PAddr) {
+    loop {
+        let cpu = queued_cpu(link);
+        if cpu == NOT_QUEUED {
+            return;
+        }
+        let unblocked = {
+            let mut queues = RUN_QUEUES[cpu].lock();
+            let queued = with_link(link, |link| {
+                if link.cpu.load(Ordering::SeqCst) == cpu {
+                    link.woken.store(true, Ordering::SeqCst);
+                    Some(link.blocked)
+                } else {
+                    None
+                }
+            });
+            match queued {
+                Some(true) => queues.unblock(link, cpu),
+                Some(false) => false,
+                // Moved to another CPU since it was read.
+                None => continue,
+            }
+        };
+        if unblocked && cpu != arch::current_cpu_id() {
+            arch::send_reschedule(cpu);
+        }
+        return;
+    }
+}
+
+/// Let `task` run again if it is blocked, after it was given a
+/// scheduling context, or its scheduling context was given budget.
+pub fn wake_task(task: &TaskCap) {
+    let link = task.read().run_link;
+    wake_link(link);
+}
+
+/// Move the blocked tasks of every CPU whose run link `f` returns
+/// `true` for back to their level, and mark the task each CPU picked
+/// last as woken, as it may be about to block for the same reason.
+fn wake_where<F: Fn(&RunLink) -> bool>(f: F) {
+    let own = arch::current_cpu_id();
+    for cpu in 0..MAX_CPUS {
+        let unblocked = {
+            let mut queues = RUN_QUEUES[cpu].lock();
+            queues.wake_current(cpu);
+            queues.unblock_where(cpu, &f)
+        };
+        if unblocked && cpu != own {
+            arch::send_reschedule(cpu);
+        }
+    }
+}
+
+/// Let the tasks blocked on `object`, the address of an endpoint,
+/// notification, channel, or of a task serving a call, run again,
+/// after an event on it.
+pub fn wake_waiters(object: PAddr) {
+    wake_where(|link| link.waits_on.iter().any(|&waits_on| waits_on == Some(object)));
+}
+
+/// Let the blocked tasks whose deadline passed run again. Run on each
+/// timer interrupt, after the timers expired.
+pub fn wake_expired() {
+    let now = ::time::monotonic_ns();
+    let own = arch::current_cpu_id();
+    for cpu in 0..MAX_CPUS {
+        let unblocked = {
+            let mut queues = RUN_QUEUES[cpu].lock();
+            queues.timed != 0 &&
+                queues.unblock_where(cpu, |link| link.wake_ns.map_or(false, |wake_ns| wake_ns <= now))
+        };
+        if unblocked && cpu != own {
+            arch::send_reschedule(cpu);
+        }
+    }
+}
+
+/// Move a task able to run to the current CPU, which is idle, from the
+/// busiest other CPU. Returns `false` if no task could be taken.
+pub fn steal_task() -> bool {
+    let cpu = arch::current_cpu_id();
+    let victim = (0..MAX_CPUS)
+        .filter(|&other| other != cpu && arch::is_cpu_online(other))
+        .max_by_key(|&other| RUN_QUEUES[other].lock().count);
+    let victim = match victim {
+        Some(victim) => victim,
+        None => return false,
+    };
+
+    with_queue_pair(cpu, victim, |own, other| {
+        // Taken offline since, in which case its tasks are handed over.
+        if !arch::is_cpu_online(cpu) || other.count <= 1 {
+            return false;
+        }
+        let link = match other.stealable(cpu) {
+            Some(link) => link,
+            None => return false,
+        };
+        match other.remove(link, victim) {
+            Some((task, level, _)) => {
+                own.push_back(link, task, cpu, level);
+                true
+            },
+            None => false,
+        }
+    })
+}
+
+/// Hand the tasks of the current CPU, taken offline, blocked or not,
+/// over to the least loaded online CPUs in their affinity. Tasks only
+/// allowed on offline CPUs go to the least loaded online CPU, keeping
+/// their affinity. The CPU no longer accepts tasks, so once its queues
+/// are found empty with them locked, they stay so.
+pub fn migrate_tasks() {
+    let cpu = arch::current_cpu_id();
+    RUN_QUEUES[cpu].lock().current = None;
+
+    loop {
+        let (link, affinity) = {
+            let queues = RUN_QUEUES[cpu].lock();
+            let link = match queues.highest_below(PRIORITY_LEVELS) {
+                Some(level) => queues.level(level).head,
+                None => queues.blocked,
+            };
+            match link {
+                Some(link) => (link, with_link(link, |link| link.affinity)),
+                None => return,
+            }
+        };
+        let target = match least_loaded_cpu(affinity).or_else(|| least_loaded_cpu(!0)) {
+            Some(target) => target,
+            None => return,
+        };
+
+        // The task may have exited, or been moved, and the target gone
+        // offline, in the meantime.
+        let moved = with_queue_pair(cpu, target, |own, other| {
+            if !arch::is_cpu_online(target) {
+                return false;
+            }
+            match own.remove(link, cpu) {
+                Some((task, level, blocked)) => {
+                    other.insert(link, task, target, level, blocked);
+                    true
+                },
+                None => false,
+            }
+        });
+        if moved {
+            arch::send_reschedule(target);
+        }
     }
 }
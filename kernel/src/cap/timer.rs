@@ -0,0 +1,225 @@
+use core::iter::Iterator;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use util::{RwLock, Mutex};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use timer_wheel::{self, TimerId};
+use super::{UntypedDescriptor, ChannelCap, ChannelValue, NotificationCap, Derived};
+
+/// Serial number of the next timer created.
+static NEXT_TIMER_SERIAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Timer descriptor.
+#[derive(Debug)]
+pub struct TimerDescriptor {
+    /// Number passed to the wheel callback, to find the timer.
+    serial: usize,
+    /// Channel, or notification, expirations are delivered to.
+    target_weak_pool: ManagedWeakPool1Arc,
+    /// Badge a bound notification is signalled with.
+    badge: u64,
+    /// Wheel timer, while armed.
+    armed: Option<TimerId>,
+    period_ns: u64,
+    /// Expirations since the timer was last armed.
+    expirations: u64,
+    next: Option<ManagedArcAny>,
+    next_timer: Option<TimerCap>,
+}
+/// Timer capability. Reference-counted smart pointer to timer
+/// descriptor.
+///
+/// Once armed, the timer puts its expiration count to the bound
+/// channel when the monotonic clock reaches its deadline, and then
+/// at each period, if it has one. Bound to a notification instead,
+/// it signals the badge it was bound with.
+pub type TimerCap = ManagedArc<RwLock<TimerDescriptor>>;
+
+impl TimerCap {
+    /// Create a timer capability from an untyped capability.
+    pub fn retype_from(untyped: &mut UntypedDescriptor) -> Self {
+        let mut arc: Option<Self> = None;
+
+        let target_weak_pool = unsafe { ManagedWeakPool1Arc::create(
+            untyped.allocate(ManagedWeakPool1Arc::inner_length(),
+                             ManagedWeakPool1Arc::inner_alignment())) };
+
+        unsafe { untyped.derive(Self::inner_length(), Self::inner_alignment(), |paddr, next_child| {
+            arc = Some(
+                Self::new(paddr, RwLock::new(TimerDescriptor {
+                    serial: NEXT_TIMER_SERIAL.fetch_add(1, Ordering::Relaxed),
+                    target_weak_pool: target_weak_pool,
+                    badge: 0,
+                    armed: None,
+                    period_ns: 0,
+                    expirations: 0,
+                    next: next_child,
+                    next_timer: None,
+                }))
+            );
+
+            arc.clone().unwrap().into()
+        }) };
+
+        register_timer(arc.clone().unwrap());
+        arc.unwrap()
+    }
+}
+
+impl TimerDescriptor {
+    /// Deliver expirations to `channel`.
+    pub fn bind(&mut self, channel: &ChannelCap) {
+        {
+            let pool = self.target_weak_pool.read();
+            pool.remove(0);
+            pool.downgrade_at(channel, 0);
+        }
+    }
+
+    /// Signal expirations to `notification` with `badge`, instead of
+    /// any channel.
+    pub fn bind_notification(&mut self, notification: &NotificationCap, badge: u64) {
+        {
+            let pool = self.target_weak_pool.read();
+            pool.remove(0);
+            pool.downgrade_at(notification, 0);
+        }
+        self.badge = badge;
+    }
+
+    /// Read the channel expirations are delivered to.
+    pub fn upgrade_channel(&self) -> Option<ChannelCap> {
+        self.target_weak_pool.read().upgrade(0)
+    }
+
+    /// Read the notification expirations are signalled to.
+    pub fn upgrade_notification(&self) -> Option<NotificationCap> {
+        self.target_weak_pool.read().upgrade(0)
+    }
+
+    /// Expire when the monotonic clock reaches `deadline_ns`, and then
+    /// every `period_ns` nanoseconds if it is not zero. This replaces
+    /// any earlier deadline. Returns `false` if userspace already has
+    /// as many timers armed as the timer wheel leaves it.
+    pub fn arm(&mut self, deadline_ns: u64, period_ns: u64) -> bool {
+        self.cancel();
+
+        self.armed = timer_wheel::add_user(deadline_ns, period_ns, timer_expired, self.serial);
+        self.period_ns = period_ns;
+        self.expirations = 0;
+        self.armed.is_some()
+    }
+
+    /// Stop the timer. Returns `false` if it was not armed.
+    pub fn cancel(&mut self) -> bool {
+        match self.armed.take() {
+            Some(id) => timer_wheel::cancel(id),
+            None => false,
+        }
+    }
+
+    /// Handle an expiration: signal the bound channel or notification.
+    fn expire(&mut self) {
+        self.expirations += 1;
+        if self.period_ns == 0 {
+            self.armed = None;
+        }
+        if let Some(channel) = self.upgrade_channel() {
+            channel.write().put(ChannelValue::Raw(self.expirations));
+        } else if let Some(notification) = self.upgrade_notification() {
+            notification.write().signal(self.badge);
+        }
+    }
+}
+
+impl Derived for TimerDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        &mut self.next
+    }
+
+    fn revoke(arc: &TimerCap) {
+        {
+            let mut desc = arc.write();
+            desc.cancel();
+            desc.target_weak_pool.read().clear();
+        }
+        unregister_timer(arc);
+    }
+}
+
+/// The first timer created.
+static FIRST_TIMER: Mutex<Option<TimerCap>> = Mutex::new(None);
+
+/// Register a new timer. Using `FIRST_TIMER` static, this forms a
+/// linked-list of all created timers.
+fn register_timer(cap: TimerCap) {
+    let mut first_timer = FIRST_TIMER.lock();
+    if first_timer.is_none() {
+        *first_timer = Some(cap);
+    } else {
+        let mut first = first_timer.as_mut().unwrap().write();
+        let mut second = cap.write();
+        let third_timer = first.next_timer.take();
+
+        second.next_timer = third_timer;
+        first.next_timer = Some(cap.clone());
+    }
+}
+
+/// Remove a timer from the list of all timers, if it is there.
+fn unregister_timer(cap: &TimerCap) {
+    let mut first_timer = FIRST_TIMER.lock();
+    if first_timer.as_ref().map(|first| first.ptr_eq(cap)).unwrap_or(false) {
+        *first_timer = cap.write().next_timer.take();
+        return;
+    }
+
+    let mut current = first_timer.clone();
+    while let Some(timer) = current {
+        let mut desc = timer.write();
+        if desc.next_timer.as_ref().map(|next| next.ptr_eq(cap)).unwrap_or(false) {
+            desc.next_timer = cap.write().next_timer.take();
+            return;
+        }
+        current = desc.next_timer.clone();
+    }
+}
+
+/// A timer iterator.
+struct TimerIterator {
+    next: Option<TimerCap>,
+}
+
+impl Iterator for TimerIterator {
+    type Item = TimerCap;
+
+    fn next(&mut self) -> Option<TimerCap> {
+        if let Some(current) = self.next.clone() {
+            {
+                let current_timer = current.read();
+                self.next = current_timer.next_timer.clone();
+            }
+            return Some(current);
+        } else {
+            None
+        }
+    }
+}
+
+/// Return a timer iterator using `FIRST_TIMER`.
+fn timer_iter() -> TimerIterator {
+    TimerIterator {
+        next: FIRST_TIMER.lock().clone(),
+    }
+}
+
+/// Timer wheel callback of user timers. `serial` is the serial number
+/// of the timer that expired.
+fn timer_expired(serial: usize) {
+    for timer in timer_iter() {
+        let mut timer = timer.write();
+        if timer.serial == serial {
+            timer.expire();
+            return;
+        }
+    }
+}
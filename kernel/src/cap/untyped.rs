@@ -1,6 +1,19 @@
 use common::*;
-use util::{RwLock, align_up};
-use util::managed_arc::{ManagedArc, ManagedArcAny};
+use core::{cmp, mem};
+use util::{RwLock, Mutex, MemoryObject, align_up};
+use util::managed_arc::{ManagedArc, ManagedArcAny, ManagedWeakPool1Arc};
+use meminfo::{self, MemoryCategory, CATEGORY_COUNT, CATEGORIES};
+use arch;
+use super::{QuotaCap, Derived, PAGE_LENGTH};
+
+/// Largest range zeroed through one memory object mapping.
+const ZERO_CHUNK_LENGTH: usize = 64 * PAGE_LENGTH;
+
+/// Upper bound of the memory a retype of a fixed-size object takes,
+/// descriptors, weak pools and alignment included. Retypes only start
+/// with this much room left, besides the size of any frames, so that
+/// they never run out midway.
+pub const RETYPE_MAX_LENGTH: usize = 16 * PAGE_LENGTH;
 
 /// Untyped descriptor.
 #[derive(Debug)]
@@ -8,7 +21,27 @@ pub struct UntypedDescriptor {
     start_paddr: PAddr,
     length: usize,
     watermark: PAddr,
-    first_child: Option<ManagedArcAny>
+    /// Watermark that `revoke` resets to. Memory below it is never
+    /// reclaimed.
+    floor: PAddr,
+    /// Bytes allocated above `floor` and not freed, indexed by
+    /// `MemoryCategory`.
+    charged: [usize; CATEGORY_COUNT],
+    /// Zeroed pages, except for their `FreePage` headers, ready to be
+    /// reused.
+    free_pages: Option<PAddr>,
+    /// Pages returned with `free_page` that are not zeroed yet.
+    dirty_pages: Option<PAddr>,
+    /// Quota charged for allocations, if any.
+    quota_weak_pool: ManagedWeakPool1Arc,
+    first_child: Option<ManagedArcAny>,
+    next_untyped: Option<UntypedCap>,
+}
+
+/// Header written at the start of every page in the free list of an
+/// untyped descriptor.
+struct FreePage {
+    next: Option<PAddr>,
 }
 /// Untyped capability. Reference-counted smart pointer to untyped
 /// descriptor.
@@ -30,12 +63,64 @@ impl UntypedCap {
 
         log!("des_paddr: {:?}", des_paddr);
 
-        Self::new(des_paddr, RwLock::new(UntypedDescriptor {
+        let quota_paddr = align_up(des_paddr + UntypedCap::inner_length(),
+                                   ManagedWeakPool1Arc::inner_alignment());
+        let watermark = quota_paddr + ManagedWeakPool1Arc::inner_length();
+        assert!(watermark <= start_paddr + length);
+        meminfo::add_total(length);
+        meminfo::charge(MemoryCategory::KernelObject,
+                        watermark.into(): usize - start_paddr.into(): usize);
+
+        let cap = Self::new(des_paddr, RwLock::new(UntypedDescriptor {
             start_paddr: start_paddr,
             length: length,
-            watermark: des_paddr + UntypedCap::inner_length(),
+            watermark: watermark,
+            floor: watermark,
+            charged: [0; CATEGORY_COUNT],
+            free_pages: None,
+            dirty_pages: None,
+            quota_weak_pool: ManagedWeakPool1Arc::create(quota_paddr),
             first_child: None,
-        }))
+            next_untyped: None,
+        }));
+        register_untyped(cap.clone());
+        cap
+    }
+
+    /// Revoke every capability derived from this one, recursively:
+    /// remove all their copies from capability pools, and tear down
+    /// the objects. Once none of them is in use by the kernel, the
+    /// memory above the floor is free again. Returns `false` if some
+    /// objects are still in use, in which case they are kept, and
+    /// freed by a later revoke.
+    pub fn revoke(&self) -> bool {
+        let first_child = self.write().first_child.take();
+        let kept = super::revoke_list(first_child);
+
+        let mut desc = self.write();
+        if kept.is_some() {
+            let newer = desc.first_child.take();
+            desc.first_child = super::append_list(newer, kept);
+            return false;
+        }
+        if desc.first_child.is_none() {
+            // Address spaces may still map frames of the region that
+            // no capability tracks.
+            super::unmap_region(desc.floor, desc.watermark);
+            desc.reset();
+        }
+        true
+    }
+}
+
+/// Zero `length` bytes of physical memory from `paddr`.
+unsafe fn zero(paddr: PAddr, length: usize) {
+    let mut offset = 0;
+    while offset < length {
+        let chunk = cmp::min(length - offset, ZERO_CHUNK_LENGTH);
+        let object = MemoryObject::<u8>::slice(paddr + offset, chunk);
+        arch::zero_range(object.as_ptr() as usize, chunk);
+        offset += chunk;
     }
 }
 
@@ -50,10 +135,133 @@ impl UntypedDescriptor {
         self.start_paddr
     }
 
-    /// Allocate a memory region using the given length and
-    /// alignment. Shift the watermark of the current descriptor
-    /// passing over the allocated region.
+    /// Physical address the next allocation from the region starts
+    /// at, before alignment.
+    pub fn watermark(&self) -> PAddr {
+        self.watermark
+    }
+
+    /// Whether any object was derived from the untyped region, and is
+    /// not revoked yet.
+    pub fn has_children(&self) -> bool {
+        self.first_child.is_some()
+    }
+
+    /// Keep everything allocated so far from being reclaimed by
+    /// `revoke`. Used for memory handed out at boot outside the
+    /// derivation list.
+    pub fn pin(&mut self) {
+        self.floor = self.watermark;
+        self.charged = [0; CATEGORY_COUNT];
+    }
+
+    /// Make the memory above the floor free again, dropping freed
+    /// pages above it. Only once nothing derived from this descriptor
+    /// is left.
+    fn reset(&mut self) {
+        let quota = self.quota();
+        for category in CATEGORIES.iter() {
+            meminfo::uncharge(*category, self.charged[*category as usize]);
+            if let Some(ref quota) = quota {
+                quota.uncharge(self.charged[*category as usize]);
+            }
+        }
+        self.charged = [0; CATEGORY_COUNT];
+        self.free_pages = retain_below(self.free_pages, self.floor);
+        self.dirty_pages = retain_below(self.dirty_pages, self.floor);
+        self.watermark = self.floor;
+    }
+
+    fn charge(&mut self, category: MemoryCategory, length: usize) {
+        self.charged[category as usize] += length;
+        meminfo::charge(category, length);
+        if let Some(quota) = self.quota() {
+            quota.charge(length);
+        }
+    }
+
+    /// Quota charged for allocations, if any.
+    pub fn quota(&self) -> Option<QuotaCap> {
+        self.quota_weak_pool.read().upgrade(0)
+    }
+
+    /// Charge later allocations to `quota`. An attached quota can
+    /// only be replaced by itself or one of its children, so the
+    /// limit never loosens. Returns `false` otherwise.
+    pub fn set_quota(&mut self, quota: &QuotaCap) -> bool {
+        if let Some(current) = self.quota() {
+            if !quota.is_within(&current) {
+                return false;
+            }
+        }
+
+        let pool = self.quota_weak_pool.read();
+        pool.remove(0);
+        pool.downgrade_at(quota, 0);
+        true
+    }
+
+    /// Whether a retype needing `length` bytes besides its
+    /// descriptors fits both in the region and in the quota.
+    pub fn can_retype(&self, length: usize) -> bool {
+        let needed = match length.checked_add(RETYPE_MAX_LENGTH) {
+            Some(needed) => needed,
+            None => return false,
+        };
+        let region_left = (self.start_paddr + self.length).into(): usize - self.watermark.into(): usize;
+        let quota_left = self.quota().map_or(region_left, |quota| quota.remaining());
+        region_left >= needed && quota_left >= needed
+    }
+
+    /// Allocate a memory region for kernel objects using the given
+    /// length and alignment. Shift the watermark of the current
+    /// descriptor passing over the allocated region.
     pub unsafe fn allocate(&mut self, length: usize, alignment: usize) -> PAddr {
+        self.allocate_as(length, alignment, MemoryCategory::KernelObject)
+    }
+
+    /// Allocate a memory region like `allocate`, accounting it to
+    /// `category`. The region is always zeroed. Single pages are
+    /// taken from the pages returned with `free_page` first,
+    /// preferring those already zeroed by `scrub`.
+    pub unsafe fn allocate_as(&mut self, length: usize, alignment: usize,
+                              category: MemoryCategory) -> PAddr {
+        if length == PAGE_LENGTH && PAGE_LENGTH % alignment == 0 {
+            if let Some(paddr) = self.free_pages {
+                self.free_pages = MemoryObject::<FreePage>::new(paddr).as_ref().next;
+                zero(paddr, mem::size_of::<FreePage>());
+                self.charge(category, PAGE_LENGTH);
+                return paddr;
+            }
+
+            if let Some(paddr) = self.dirty_pages {
+                self.dirty_pages = MemoryObject::<FreePage>::new(paddr).as_ref().next;
+                zero(paddr, PAGE_LENGTH);
+                self.charge(category, PAGE_LENGTH);
+                return paddr;
+            }
+        }
+
+        let start = self.watermark;
+        let paddr = self.bump(length, alignment);
+        zero(paddr, length);
+        self.charge(category, paddr.into(): usize + length - start.into(): usize);
+        paddr
+    }
+
+    /// Allocate a memory region like `allocate`, without accounting
+    /// or zeroing it. Only for memory handed at boot to an allocator
+    /// that accounts for its own uses. The region, and everything
+    /// allocated before it, is pinned.
+    pub unsafe fn reserve(&mut self, length: usize, alignment: usize) -> PAddr {
+        let paddr = self.bump(length, alignment);
+        self.floor = self.watermark;
+        paddr
+    }
+
+    /// Move the watermark past a region of `length` bytes aligned to
+    /// `alignment`, and return its start.
+    fn bump(&mut self, length: usize, alignment: usize) -> PAddr {
         let paddr = align_up(self.watermark, alignment);
         assert!(paddr + length <= self.start_paddr + self.length);
 
@@ -61,10 +269,120 @@ impl UntypedDescriptor {
         paddr
     }
 
+    /// Return a page allocated for `category` to this descriptor, to
+    /// be reused by later page-sized allocations. It is zeroed by
+    /// `scrub`, or when reused, whichever comes first.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` must be a page allocated from this descriptor that is
+    /// no longer referred to by anything, including paging-structure
+    /// caches of any CPU.
+    pub unsafe fn free_page(&mut self, paddr: PAddr, category: MemoryCategory) {
+        assert!(paddr.into(): usize % PAGE_LENGTH == 0);
+        assert!(paddr >= self.start_paddr && paddr + PAGE_LENGTH <= self.watermark);
+
+        MemoryObject::<FreePage>::new(paddr).as_mut().next = self.dirty_pages;
+        self.dirty_pages = Some(paddr);
+        let charged = &mut self.charged[category as usize];
+        *charged = charged.saturating_sub(PAGE_LENGTH);
+        meminfo::uncharge(category, PAGE_LENGTH);
+        if let Some(quota) = self.quota() {
+            quota.uncharge(PAGE_LENGTH);
+        }
+    }
+
+    /// Zero at most `budget` freed pages, so that reusing them later
+    /// takes no more than clearing a header. Returns the number of
+    /// pages zeroed.
+    pub fn scrub(&mut self, budget: usize) -> usize {
+        let mut scrubbed = 0;
+        while scrubbed < budget {
+            let paddr = match self.dirty_pages {
+                Some(paddr) => paddr,
+                None => break,
+            };
+
+            unsafe {
+                let mut page = MemoryObject::<FreePage>::new(paddr);
+                self.dirty_pages = page.as_ref().next;
+                // Scrubbed pages may stay free for long, so keep them
+                // out of the cache.
+                arch::zero_range_non_temporal(page.as_ptr() as usize, PAGE_LENGTH);
+                page.as_mut().next = self.free_pages;
+            }
+            self.free_pages = Some(paddr);
+            scrubbed += 1;
+        }
+        scrubbed
+    }
+
     /// Derive and allocate a memory region to a capability that
     /// requires memory region.
     pub unsafe fn derive<F>(&mut self, length: usize, alignment: usize, f: F) where F: FnOnce(PAddr, Option<ManagedArcAny>) -> ManagedArcAny {
         let paddr = self.allocate(length, alignment);
-        self.first_child = Some(f(paddr, self.first_child.take()));
+        let child = f(paddr, self.first_child.take());
+        super::object_created(child.type_id());
+        self.first_child = Some(child);
+    }
+}
+
+impl Derived for UntypedDescriptor {
+    fn next_mut(&mut self) -> &mut Option<ManagedArcAny> {
+        // Untyped capabilities are bootstrapped, never retyped.
+        unreachable!()
+    }
+}
+
+/// Keep the pages of a free list that lie below `floor`.
+fn retain_below(first: Option<PAddr>, floor: PAddr) -> Option<PAddr> {
+    let mut kept: Option<PAddr> = None;
+    let mut next = first;
+    while let Some(paddr) = next {
+        unsafe {
+            let mut page = MemoryObject::<FreePage>::new(paddr);
+            next = page.as_ref().next;
+            if paddr < floor {
+                page.as_mut().next = kept;
+                kept = Some(paddr);
+            }
+        }
+    }
+    kept
+}
+
+/// The first untyped capability bootstrapped by the kernel.
+static FIRST_UNTYPED: Mutex<Option<UntypedCap>> = Mutex::new(None);
+
+/// Register a new untyped capability. Like tasks, all untyped
+/// capabilities form a linked list from `FIRST_UNTYPED`.
+fn register_untyped(cap: UntypedCap) {
+    let mut first_untyped = FIRST_UNTYPED.lock();
+    if first_untyped.is_none() {
+        *first_untyped = Some(cap);
+    } else {
+        let mut first = first_untyped.as_mut().unwrap().write();
+        let mut second = cap.write();
+        let third_untyped = first.next_untyped.take();
+
+        second.next_untyped = third_untyped;
+        first.next_untyped = Some(cap.clone());
+    }
+}
+
+/// Zero at most `budget` freed pages across all untyped
+/// capabilities, in the background of retypes. Returns the number of
+/// pages zeroed.
+pub fn scrub_free_pages(budget: usize) -> usize {
+    let mut scrubbed = 0;
+    let mut next = FIRST_UNTYPED.lock().clone();
+    while let Some(current) = next {
+        let mut current_desc = current.write();
+        scrubbed += current_desc.scrub(budget - scrubbed);
+        if scrubbed == budget {
+            break;
+        }
+        next = current_desc.next_untyped.clone();
     }
+    scrubbed
 }
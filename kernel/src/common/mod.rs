@@ -1,5 +1,5 @@
 pub use arch::{VAddr, PAddr};
-pub use abi::{CAddr};
+pub use abi::{CAddr, CapRights, RIGHT_READ, RIGHT_WRITE, RIGHT_GRANT, RIGHT_MAP, RIGHT_SEND_ONCE, RIGHTS_NONE, RIGHTS_ALL};
 
 /// Represents a memory region with a start physical address and a
 /// length.
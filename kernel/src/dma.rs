@@ -0,0 +1,63 @@
+use core::cmp;
+use common::*;
+use arch;
+use frame;
+use meminfo::MemoryCategory;
+
+/// A physically contiguous, zeroed buffer for device DMA, allocated
+/// from the frame allocator. It is freed when dropped.
+#[derive(Debug)]
+pub struct DmaRegion {
+    paddr: PAddr,
+    length: usize,
+    order: usize,
+}
+
+impl DmaRegion {
+    /// Allocate a buffer of at least `length` bytes, aligned to
+    /// `alignment`, that lies entirely at or below the physical
+    /// address `mask`. Returns `None` if the request is too large or
+    /// no suitable memory is free.
+    pub fn allocate(length: usize, alignment: usize, mask: u64) -> Option<DmaRegion> {
+        if length > frame::MAX_BLOCK_LENGTH || alignment > frame::MAX_BLOCK_LENGTH {
+            return None;
+        }
+
+        // Blocks are aligned to their own length.
+        let order = frame::order_for(cmp::max(length, alignment));
+        if order > frame::MAX_ORDER {
+            return None;
+        }
+
+        frame::allocate_masked(order, mask, MemoryCategory::UserFrame).map(|paddr| {
+            let region = DmaRegion {
+                paddr: paddr,
+                length: cmp::max(length, 1),
+                order: order,
+            };
+            unsafe { arch::zero_range(region.vaddr().into(): usize, region.length); }
+            region
+        })
+    }
+
+    /// Physical address of the buffer, to be programmed into devices.
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+
+    /// Kernel virtual address of the buffer.
+    pub fn vaddr(&self) -> VAddr {
+        frame::to_vaddr(self.paddr)
+    }
+
+    /// Length of the buffer.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        unsafe { frame::free(self.paddr, self.order, MemoryCategory::UserFrame); }
+    }
+}
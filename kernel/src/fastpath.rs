@@ -0,0 +1,146 @@
+use common::*;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use abi::{SystemCall, ChannelMessage, IpcStats, IpcKind};
+use arch::RuntimeEntry;
+use cap::{self, TaskCap, TaskClaim, TaskStatus, EndpointCap, ReplyCap};
+
+/// Fastpath calls switched directly to the receiver.
+static HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Fastpath calls that fell back to the general path.
+static MISSES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Fastpath hit and miss counts, reported to user-space by the
+/// `IpcStats` system call.
+pub fn ipc_stats() -> IpcStats {
+    IpcStats {
+        fastpath_hits: HITS.load(Ordering::Relaxed) as u64,
+        fastpath_misses: MISSES.load(Ordering::Relaxed) as u64,
+    }
+}
+
+/// Handler of the fastpath stub of the architecture, for a
+/// `FASTPATH_CALL` to the endpoint at `endpoint` bytes and `depth`,
+/// sending `word`, by the task running on the current CPU. Returns the
+/// entry of the receiver, which the stub returns to directly, without
+/// the scheduler. `None` if the call is to be handled from the task
+/// buffer, on the general path, with nothing changed.
+///
+/// The stub runs on the kernel stack of the caller, with interrupts
+/// disabled, and skips the decoding of the task buffer and the
+/// scheduler. The lookups and checks are the same as on the general
+/// path.
+pub fn call(endpoint: u64, depth: u64, word: u64) -> Option<RuntimeEntry> {
+    let caller = match cap::running_task() {
+        Some(caller) => caller,
+        None => return None,
+    };
+
+    cap::clear_call_error();
+    let receiver = direct_call(&caller, endpoint, depth, word);
+    match receiver {
+        Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+        None => MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+    receiver.map(|(receiver, claim)| caller.hand_over(&receiver, claim))
+}
+
+/// The fastpath applies to a call of a word, to an endpoint with a
+/// receiver waiting on it, which is not suspended, has a reply object,
+/// time to run and the priority of the caller, so that switching to it
+/// never runs it ahead of a more urgent task, nor needs it to inherit
+/// a priority. Both tasks need a kernel stack, as the receiver is
+/// returned to directly, and enters the kernel on its own stack.
+/// The message is not decoded, and no capability is transferred.
+/// Returns the receiver, claimed for the current CPU.
+fn direct_call(caller: &TaskCap, endpoint: u64, depth: u64, word: u64) -> Option<(TaskCap, TaskClaim)> {
+    let depth = depth as usize;
+    if depth == 0 || depth > 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (endpoint >> (i * 8)) as u8;
+    }
+    if caller.read().exit_code().is_some() || !caller.read().has_kernel_stack() {
+        return None;
+    }
+
+    let cpool = match caller.read().upgrade_cpool() {
+        Some(cpool) => cpool,
+        None => return None,
+    };
+    let caddr = CAddr(bytes, depth);
+    let endpoint: EndpointCap = match cpool.lookup_upgrade(caddr, RIGHT_WRITE) {
+        Some(endpoint) => endpoint,
+        None => return None,
+    };
+    let receiver = match endpoint.waiting_receiver() {
+        Some(receiver) => receiver,
+        None => return None,
+    };
+    // Signals of the bound notification go to the receiver first.
+    let signalled = receiver.read().upgrade_notification()
+        .map(|notification| notification.read().is_signalled()).unwrap_or(false);
+    let same_priority = receiver.read().priority() == caller.read().priority();
+    if signalled || !same_priority || !receiver.read().has_kernel_stack() || receiver.read().is_suspended() {
+        return None;
+    }
+
+    let caller_buffer_cap = match caller.read().upgrade_buffer() {
+        Some(caller_buffer_cap) => caller_buffer_cap,
+        None => return None,
+    };
+    let buffer_cap = receiver.read().upgrade_buffer();
+    let receiver_cpool = receiver.read().upgrade_cpool();
+    let (buffer_cap, receiver_cpool) = match (buffer_cap, receiver_cpool) {
+        (Some(buffer_cap), Some(receiver_cpool)) => (buffer_cap, receiver_cpool),
+        _ => return None,
+    };
+    let request = {
+        let buffer_desc = buffer_cap.read();
+        let buffer = buffer_desc.read();
+        match buffer.call.clone() {
+            Some(SystemCall::EndpointReceive { request, .. }) => request,
+            _ => return None,
+        }
+    };
+    let reply: ReplyCap = match request.1.and_then(|caddr| receiver_cpool.lookup_upgrade(caddr, RIGHT_WRITE)) {
+        Some(reply) => reply,
+        None => return None,
+    };
+    // Checks the budget of the receiver, and that no other CPU runs
+    // it.
+    let claim = match receiver.claim() {
+        Some(claim) => claim,
+        None => return None,
+    };
+    if !reply.bind(caller, &receiver) {
+        receiver.release();
+        return None;
+    }
+
+    {
+        let mut buffer_desc = buffer_cap.write();
+        let mut buffer = buffer_desc.write();
+        buffer.call = Some(SystemCall::EndpointReceive {
+            request: request,
+            response: Some(ChannelMessage::Raw(word)),
+        });
+    }
+    {
+        // As on the general path, the caller sees the capability
+        // errors of this call, and not those of the one before.
+        let mut buffer_desc = caller_buffer_cap.write();
+        let mut buffer = buffer_desc.write();
+        buffer.cap_error = cap::call_error();
+    }
+    cpool.lookup_consume_once(caddr);
+    caller.write().set_status(TaskStatus::ReplyWait(reply.clone()));
+    caller.write().start_call_timing();
+    receiver.write().set_status(TaskStatus::Active);
+    if ::ipc_trace::is_enabled() {
+        ::ipc_trace::record(IpcKind::Call, Some(caller), Some(&receiver), endpoint.ptr().into(): u64, 0, 0, true);
+    }
+    Some((receiver, claim))
+}
@@ -0,0 +1,502 @@
+use common::*;
+use spin::Once;
+use util::{Mutex, block_count};
+use cap::PAGE_LENGTH;
+use arch;
+use meminfo::{self, MemoryCategory};
+use core::ptr;
+#[cfg(feature="debug_alloc")]
+use core::mem;
+#[cfg(feature="debug_alloc")]
+use alloc_debug;
+
+/// Largest block order. A block of order `n` is `2^n` frames long,
+/// and aligned to its own length.
+pub const MAX_ORDER: usize = 10;
+
+/// Length of a block of `MAX_ORDER`.
+pub const MAX_BLOCK_LENGTH: usize = PAGE_LENGTH << MAX_ORDER;
+
+/// Largest total length of the regions the frame allocator can
+/// manage, bounded by the kernel window they are mapped at.
+pub const MAX_POOL_LENGTH: usize = arch::FRAME_WINDOW_LENGTH;
+
+/// Largest number of regions the frame allocator can manage.
+pub const MAX_ZONES: usize = 8;
+
+/// Bit set in the tag of the first frame of an allocated block. The
+/// other bits hold the backtrace ID of the block's last allocation.
+#[cfg(feature="debug_alloc")]
+const TAG_ALLOCATED: u32 = 1 << 31;
+
+/// Header written at the start of every free block, linking it into
+/// the free list of its order.
+struct FreeBlock {
+    prev: Option<PAddr>,
+    next: Option<PAddr>,
+    order: usize,
+}
+
+/// One physical memory region of a buddy allocator. Blocks never
+/// merge across zones.
+#[derive(Debug, Clone, Copy)]
+struct Zone {
+    base: PAddr,
+    length: usize,
+    /// One bit per frame, set on the first frame of each free block,
+    /// kept in the first frames of the zone.
+    free_map: PAddr,
+    /// One tag per frame, see `TAG_ALLOCATED`.
+    #[cfg(feature="debug_alloc")]
+    tags: Option<PAddr>,
+}
+
+impl Zone {
+    /// Word of the free map holding the bit of `block`, and the bit.
+    unsafe fn free_bit<'a>(&self, block: PAddr) -> (&'a mut u64, u64) {
+        let index = (block.into(): usize - self.base.into(): usize) / PAGE_LENGTH;
+        let words = to_vaddr(self.free_map).into(): usize as *mut u64;
+        (&mut *words.offset((index / 64) as isize), 1 << (index % 64))
+    }
+}
+
+/// A buddy allocator over up to `MAX_ZONES` physical memory
+/// regions. Free lists are kept inside the free blocks themselves,
+/// accessed through the frame window, so the allocator never needs the
+/// object pool. They are doubly linked, and the free map of each zone
+/// tells whether a buddy is free, so that freeing takes constant time
+/// at each order.
+pub struct BuddyAllocator {
+    zones: [Option<Zone>; MAX_ZONES],
+    free_lists: [Option<PAddr>; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyAllocator {
+    /// Create an allocator with no memory.
+    pub const fn empty() -> BuddyAllocator {
+        BuddyAllocator {
+            zones: [None; MAX_ZONES],
+            free_lists: [None; MAX_ORDER + 1],
+            free_frames: 0,
+        }
+    }
+
+    /// Hand `region` to the allocator as a new zone. The region must
+    /// be aligned to `MAX_BLOCK_LENGTH`, and its length a multiple of
+    /// it. The free map of the zone takes its first frames.
+    ///
+    /// # Safety
+    ///
+    /// The region must be free memory not used by anything else.
+    pub unsafe fn add_zone(&mut self, region: MemoryRegion) {
+        assert!(region.start_paddr().into(): usize % MAX_BLOCK_LENGTH == 0);
+        assert!(region.length() % MAX_BLOCK_LENGTH == 0);
+
+        let index = self.zones.iter().position(|zone| zone.is_none()).expect("too many zones");
+        let frames = region.length() / PAGE_LENGTH;
+        let map_length = block_count(block_count(frames, 64) * 8, PAGE_LENGTH) * PAGE_LENGTH;
+        ptr::write_bytes(to_vaddr(region.start_paddr()).into(): usize as *mut u8, 0, map_length);
+        meminfo::charge(MemoryCategory::KernelObject, map_length);
+        self.zones[index] = Some(Zone {
+            base: region.start_paddr(),
+            length: region.length(),
+            free_map: region.start_paddr(),
+            #[cfg(feature="debug_alloc")]
+            tags: None,
+        });
+
+        let start = region.start_paddr() + map_length;
+        let end = region.start_paddr() + region.length();
+        #[cfg(feature="debug_alloc")]
+        alloc_debug::poison(to_vaddr(start), end.into(): usize - start.into(): usize);
+
+        self.free_range(start, end);
+
+        #[cfg(feature="debug_alloc")]
+        {
+            let length = frames * 4;
+            let tags = self.allocate(order_for(length)).expect("no memory for frame tags");
+            meminfo::charge(MemoryCategory::KernelObject, PAGE_LENGTH << order_for(length));
+            ptr::write_bytes(to_vaddr(tags).into(): usize as *mut u8, 0, length);
+            self.zones[index].as_mut().unwrap().tags = Some(tags);
+        }
+    }
+
+    /// Free `[start, end)` in blocks as large as their alignment and
+    /// the end of the range allow.
+    unsafe fn free_range(&mut self, start: PAddr, end: PAddr) {
+        let mut block = start;
+        while block < end {
+            let mut order = 0;
+            while order < MAX_ORDER &&
+                (block.into(): usize) % (PAGE_LENGTH << (order + 1)) == 0 &&
+                block + (PAGE_LENGTH << (order + 1)) <= end
+            {
+                order += 1;
+            }
+
+            self.push(block, order);
+            self.free_frames += 1 << order;
+            block = block + (PAGE_LENGTH << order);
+        }
+    }
+
+    /// Zone holding `block`.
+    fn zone(&self, block: PAddr) -> Zone {
+        self.zones.iter()
+            .filter_map(|zone| *zone)
+            .find(|zone| block >= zone.base && block < zone.base + zone.length)
+            .expect("frame outside of the frame allocator")
+    }
+
+    /// Number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocate a block of `2^order` frames, aligned to its
+    /// length. Returns `None` if no block is large enough.
+    pub fn allocate(&mut self, order: usize) -> Option<PAddr> {
+        assert!(order <= MAX_ORDER);
+
+        let mut current = order;
+        while current <= MAX_ORDER && self.free_lists[current].is_none() {
+            current += 1;
+        }
+        if current > MAX_ORDER {
+            return None;
+        }
+
+        let block = unsafe { self.pop(current) };
+        Some(self.take(block, current, order))
+    }
+
+    /// Allocate a block of `2^order` frames whose last byte is at or
+    /// below the physical address `mask`, for devices that can only
+    /// address part of physical memory. Returns `None` if no such
+    /// block is free.
+    pub fn allocate_masked(&mut self, order: usize, mask: u64) -> Option<PAddr> {
+        assert!(order <= MAX_ORDER);
+
+        for current in order..(MAX_ORDER + 1) {
+            let mut candidate = self.free_lists[current];
+            while let Some(block) = candidate {
+                let last = (block.into(): u64).checked_add(((PAGE_LENGTH << order) - 1) as u64);
+                if last.map(|last| last <= mask).unwrap_or(false) {
+                    unsafe { self.remove(block, current); }
+                    return Some(self.take(block, current, order));
+                }
+                candidate = unsafe { Self::header(block).next };
+            }
+        }
+
+        None
+    }
+
+    /// Hand out the lowest `2^order` frames of `block`, a block of
+    /// `current` order just taken off its free list.
+    fn take(&mut self, block: PAddr, current: usize, order: usize) -> PAddr {
+        // Split down to the requested order, freeing the upper halves.
+        let mut current = current;
+        while current > order {
+            current -= 1;
+            unsafe { self.push(block + (PAGE_LENGTH << current), current); }
+        }
+
+        self.free_frames -= 1 << order;
+
+        #[cfg(feature="debug_alloc")]
+        unsafe { self.check_allocate(block, order); }
+
+        block
+    }
+
+    /// Free a block allocated with `allocate`, merging it with its
+    /// buddy as long as the buddy is free.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` and `order` must be those of a block returned by
+    /// `allocate`, and the block must not be used afterwards.
+    pub unsafe fn free(&mut self, paddr: PAddr, order: usize) {
+        assert!(order <= MAX_ORDER);
+        let zone = self.zone(paddr);
+        assert!(paddr + (PAGE_LENGTH << order) <= zone.base + zone.length);
+
+        #[cfg(feature="debug_alloc")]
+        self.check_free(paddr, order);
+
+        self.free_frames += 1 << order;
+
+        let mut block = paddr;
+        let mut current = order;
+        while current < MAX_ORDER {
+            let offset = block.into(): usize - zone.base.into(): usize;
+            let buddy = zone.base + (offset ^ (PAGE_LENGTH << current));
+            let (word, bit) = zone.free_bit(buddy);
+            if *word & bit == 0 || Self::header(buddy).order != current {
+                break;
+            }
+            self.remove(buddy, current);
+
+            if buddy < block {
+                block = buddy;
+            }
+            current += 1;
+        }
+
+        self.push(block, current);
+    }
+
+    unsafe fn header<'a>(block: PAddr) -> &'a mut FreeBlock {
+        &mut *(to_vaddr(block).into(): usize as *mut FreeBlock)
+    }
+
+    unsafe fn push(&mut self, block: PAddr, order: usize) {
+        let next = self.free_lists[order];
+        if let Some(next) = next {
+            Self::header(next).prev = Some(block);
+        }
+        *Self::header(block) = FreeBlock {
+            prev: None,
+            next: next,
+            order: order,
+        };
+        self.free_lists[order] = Some(block);
+
+        let (word, bit) = self.zone(block).free_bit(block);
+        *word |= bit;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> PAddr {
+        let block = self.free_lists[order].unwrap();
+        self.remove(block, order);
+        block
+    }
+
+    /// Called on a block taken off a free list. With `debug_alloc`,
+    /// its header is poisoned, so that all free memory is poisoned
+    /// except the headers of free blocks.
+    #[allow(unused_variables)]
+    unsafe fn unlinked(block: PAddr) {
+        #[cfg(feature="debug_alloc")]
+        alloc_debug::poison(to_vaddr(block), mem::size_of::<FreeBlock>());
+    }
+
+    /// Unlink `block`, a free block, from the free list of `order`.
+    unsafe fn remove(&mut self, block: PAddr, order: usize) {
+        let (prev, next) = {
+            let header = Self::header(block);
+            debug_assert!(header.order == order);
+            (header.prev, header.next)
+        };
+        match prev {
+            Some(prev) => Self::header(prev).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            Self::header(next).prev = prev;
+        }
+
+        let (word, bit) = self.zone(block).free_bit(block);
+        *word &= !bit;
+        Self::unlinked(block);
+    }
+}
+
+#[cfg(feature="debug_alloc")]
+impl BuddyAllocator {
+    unsafe fn tag<'a>(&self, block: PAddr) -> Option<&'a mut u32> {
+        let zone = self.zone(block);
+        zone.tags.map(|tags| {
+            let index = (block.into(): usize - zone.base.into(): usize) / PAGE_LENGTH;
+            &mut *((to_vaddr(tags).into(): usize as *mut u32).offset(index as isize))
+        })
+    }
+
+    /// Check that a block being allocated has not been written to
+    /// since it was freed, and tag it with the current backtrace.
+    unsafe fn check_allocate(&mut self, block: PAddr, order: usize) {
+        let length = PAGE_LENGTH << order;
+        if let Some(offset) = alloc_debug::find_unpoisoned(to_vaddr(block), length) {
+            let last = self.tag(block).map(|tag| *tag).unwrap_or(0);
+            log!("Frame block 0x{:x} of order {} was written at offset 0x{:x} after being freed.",
+                 block, order, offset);
+            log!("It was last allocated by:");
+            alloc_debug::log_backtrace(last & !TAG_ALLOCATED);
+            panic!("use after free of frame block 0x{:x}", block);
+        }
+
+        if let Some(tag) = self.tag(block) {
+            *tag = TAG_ALLOCATED | alloc_debug::backtrace_id();
+        }
+    }
+
+    /// Check that a block being freed is allocated, untag and poison
+    /// it.
+    unsafe fn check_free(&mut self, block: PAddr, order: usize) {
+        if let Some(tag) = self.tag(block) {
+            if *tag & TAG_ALLOCATED == 0 {
+                log!("Frame block 0x{:x} of order {} freed twice.", block, order);
+                log!("It was last allocated by:");
+                alloc_debug::log_backtrace(*tag);
+                log!("And freed again by:");
+                alloc_debug::log_backtrace(alloc_debug::backtrace_id());
+                panic!("double free of frame block 0x{:x}", block);
+            }
+            *tag &= !TAG_ALLOCATED;
+        }
+
+        alloc_debug::poison(to_vaddr(block), PAGE_LENGTH << order);
+    }
+}
+
+/// The kernel's frame allocator, for memory the kernel allocates and
+/// frees on its own, outside of untyped capabilities.
+static FRAME_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::empty());
+
+/// Where one region of the frame allocator is permanently mapped in
+/// the kernel half of the address space.
+#[derive(Clone, Copy)]
+struct Window {
+    paddr: PAddr,
+    vaddr: VAddr,
+    length: usize,
+}
+
+static WINDOWS: Once<[Option<Window>; MAX_ZONES]> = Once::new();
+
+/// Window of the frame allocator region for which `f` holds.
+fn find_window<F: Fn(&Window) -> bool>(f: F) -> Window {
+    WINDOWS.try().expect("frame allocator is not initialized")
+        .iter()
+        .filter_map(|window| *window)
+        .find(f)
+        .expect("address outside of the frame window")
+}
+
+/// Regions gathered at boot for the frame allocator, at most
+/// `MAX_ZONES` of them, and `MAX_POOL_LENGTH` long in all.
+pub struct FramePool {
+    regions: [MemoryRegion; MAX_ZONES],
+    count: usize,
+    length: usize,
+}
+
+impl FramePool {
+    /// Create a pool with no regions.
+    pub fn new() -> FramePool {
+        FramePool {
+            regions: [MemoryRegion::new(PAddr::from(0: usize), 0); MAX_ZONES],
+            count: 0,
+            length: 0,
+        }
+    }
+
+    /// Length the pool can still take.
+    pub fn room(&self) -> usize {
+        if self.count == MAX_ZONES {
+            0
+        } else {
+            MAX_POOL_LENGTH - self.length
+        }
+    }
+
+    /// Add `region` to the pool. It must be aligned to
+    /// `MAX_BLOCK_LENGTH`, and fit in `room`.
+    pub fn push(&mut self, region: MemoryRegion) {
+        assert!(region.length() <= self.room());
+        self.regions[self.count] = region;
+        self.count += 1;
+        self.length += region.length();
+    }
+
+    /// The regions of the pool.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.count]
+    }
+}
+
+/// Smallest order whose blocks hold `length` bytes.
+pub fn order_for(length: usize) -> usize {
+    let mut order = 0;
+    while (PAGE_LENGTH << order) < length {
+        order += 1;
+    }
+    order
+}
+
+/// Map the regions of `pool` into the kernel window, one after the
+/// other, using the free page `window_pd` as its page directory, and
+/// initialize the frame allocator with them, one zone each. See
+/// `BuddyAllocator::add_zone`.
+pub unsafe fn init(pool: &FramePool, window_pd: PAddr) {
+    let vaddr = arch::map_frame_window(pool.regions(), window_pd);
+    let mut windows = [None; MAX_ZONES];
+    let mut offset = 0;
+    for (window, region) in windows.iter_mut().zip(pool.regions()) {
+        *window = Some(Window {
+            paddr: region.start_paddr(),
+            vaddr: vaddr + offset,
+            length: region.length(),
+        });
+        offset += region.length();
+    }
+    WINDOWS.call_once(|| windows);
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for region in pool.regions() {
+        allocator.add_zone(*region);
+    }
+}
+
+/// Kernel virtual address of a frame allocated from the frame
+/// allocator. Unlike a `MemoryObject`, the mapping is permanent.
+pub fn to_vaddr(paddr: PAddr) -> VAddr {
+    let window = find_window(|window| {
+        paddr >= window.paddr && (paddr.into(): usize - window.paddr.into(): usize) < window.length
+    });
+    window.vaddr + (paddr.into(): usize - window.paddr.into(): usize)
+}
+
+/// Physical address of a kernel virtual address returned by
+/// `to_vaddr`.
+pub fn to_paddr(vaddr: VAddr) -> PAddr {
+    let window = find_window(|window| {
+        vaddr >= window.vaddr && (vaddr.into(): usize - window.vaddr.into(): usize) < window.length
+    });
+    window.paddr + (vaddr.into(): usize - window.vaddr.into(): usize)
+}
+
+/// Allocate a block of `2^order` frames, accounted to `category`.
+pub fn allocate(order: usize, category: MemoryCategory) -> Option<PAddr> {
+    let block = FRAME_ALLOCATOR.lock().allocate(order);
+    if block.is_some() {
+        meminfo::charge(category, PAGE_LENGTH << order);
+    }
+    block
+}
+
+/// Allocate a block of `2^order` frames below `mask`, accounted to
+/// `category`. See `BuddyAllocator::allocate_masked`.
+pub fn allocate_masked(order: usize, mask: u64, category: MemoryCategory) -> Option<PAddr> {
+    let block = FRAME_ALLOCATOR.lock().allocate_masked(order, mask);
+    if block.is_some() {
+        meminfo::charge(category, PAGE_LENGTH << order);
+    }
+    block
+}
+
+/// Free a block of `2^order` frames, allocated for `category`. See
+/// `BuddyAllocator::free`.
+pub unsafe fn free(paddr: PAddr, order: usize, category: MemoryCategory) {
+    FRAME_ALLOCATOR.lock().free(paddr, order);
+    meminfo::uncharge(category, PAGE_LENGTH << order);
+}
+
+/// Number of free frames.
+#[allow(dead_code)]
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
@@ -0,0 +1,288 @@
+use core::{cmp, ptr};
+use core::alloc::{GlobalAlloc, Opaque};
+use alloc::allocator::Layout;
+use common::*;
+use util::Mutex;
+use cap::PAGE_LENGTH;
+use arch::{self, MAX_CPUS};
+use frame;
+use meminfo::MemoryCategory;
+#[cfg(feature="debug_alloc")]
+use core::mem;
+#[cfg(feature="debug_alloc")]
+use alloc_debug;
+
+/// Object sizes of the slab caches. Allocations larger than the last
+/// class, or aligned more strictly than their class, are served
+/// directly by the frame allocator.
+const SIZE_CLASSES: [usize; CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of slab caches.
+const CLASS_COUNT: usize = 8;
+
+/// Number of objects a magazine holds.
+const MAGAZINE_SIZE: usize = 32;
+
+/// Bytes reserved at the end of every object for its `Trailer`.
+#[cfg(feature="debug_alloc")]
+const TRAILER_LENGTH: usize = 8;
+#[cfg(not(feature="debug_alloc"))]
+const TRAILER_LENGTH: usize = 0;
+
+/// `Trailer` state of an allocated object.
+#[cfg(feature="debug_alloc")]
+const OBJECT_ALLOCATED: u32 = 0xa110_ca7e;
+/// `Trailer` state of a free object.
+#[cfg(feature="debug_alloc")]
+const OBJECT_FREE: u32 = 0xf4ee_f4ee;
+
+/// Written at the end of every object with `debug_alloc`.
+#[cfg(feature="debug_alloc")]
+struct Trailer {
+    state: u32,
+    /// Backtrace ID of the last allocation of the object.
+    id: u32,
+}
+
+/// Header written in every free object, linking it into the free
+/// list of its cache.
+struct FreeObject {
+    next: Option<VAddr>,
+}
+
+/// A cache of objects of one size. Slabs are single frames carved
+/// into objects, and every free object is kept in one free list.
+/// Slabs are never returned to the frame allocator.
+struct SlabCache {
+    object_size: usize,
+    free_list: Option<VAddr>,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            object_size: object_size,
+            free_list: None,
+        }
+    }
+
+    /// Carve a new frame into objects. Returns `false` if the frame
+    /// allocator is out of memory.
+    fn grow(&mut self) -> bool {
+        let slab = match frame::allocate(0, MemoryCategory::KernelObject) {
+            Some(paddr) => frame::to_vaddr(paddr),
+            None => return false,
+        };
+
+        for i in (0..(PAGE_LENGTH / self.object_size)).rev() {
+            let object = slab + i * self.object_size;
+            #[cfg(feature="debug_alloc")]
+            unsafe {
+                *trailer(object, self.object_size) = Trailer { state: OBJECT_FREE, id: 0 };
+            }
+            unsafe { self.push(object); }
+        }
+        true
+    }
+
+    unsafe fn push(&mut self, object: VAddr) {
+        ptr::write(object.into(): usize as *mut FreeObject, FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = Some(object);
+    }
+
+    fn pop(&mut self) -> Option<VAddr> {
+        if self.free_list.is_none() && !self.grow() {
+            return None;
+        }
+
+        let object = self.free_list.unwrap();
+        self.free_list = unsafe { (*(object.into(): usize as *const FreeObject)).next };
+        Some(object)
+    }
+}
+
+/// Per-CPU stack of free objects of one size class, refilled from and
+/// flushed to the shared cache in batches.
+#[derive(Clone, Copy)]
+struct Magazine {
+    count: usize,
+    objects: [Option<VAddr>; MAGAZINE_SIZE],
+}
+
+impl Magazine {
+    const fn empty() -> Magazine {
+        Magazine {
+            count: 0,
+            objects: [None; MAGAZINE_SIZE],
+        }
+    }
+}
+
+/// Shared slab caches, one per size class.
+static CACHES: [Mutex<SlabCache>; CLASS_COUNT] = [
+    Mutex::new(SlabCache::new(16)), Mutex::new(SlabCache::new(32)),
+    Mutex::new(SlabCache::new(64)), Mutex::new(SlabCache::new(128)),
+    Mutex::new(SlabCache::new(256)), Mutex::new(SlabCache::new(512)),
+    Mutex::new(SlabCache::new(1024)), Mutex::new(SlabCache::new(2048)),
+];
+
+/// Magazines of every CPU, indexed by CPU id and then size class.
+/// Kernel code runs with interrupts disabled, so a CPU's magazines
+/// are only ever accessed by that CPU, one allocation at a time.
+static mut MAGAZINES: [[Magazine; CLASS_COUNT]; MAX_CPUS] =
+    [[Magazine::empty(); CLASS_COUNT]; MAX_CPUS];
+
+/// Size class serving `layout`, if any.
+fn class_for(layout: &Layout) -> Option<usize> {
+    SIZE_CLASSES.iter().position(|&size| {
+        size >= layout.size() + TRAILER_LENGTH && size % layout.align() == 0
+    })
+}
+
+#[cfg(feature="debug_alloc")]
+unsafe fn trailer<'a>(object: VAddr, object_size: usize) -> &'a mut Trailer {
+    &mut *((object + (object_size - TRAILER_LENGTH)).into(): usize as *mut Trailer)
+}
+
+/// Check that an object taken from a cache is free and has not been
+/// written to since it was freed, and mark it allocated.
+#[cfg(feature="debug_alloc")]
+unsafe fn check_allocate(object: VAddr, class: usize) {
+    let object_size = SIZE_CLASSES[class];
+    let trailer = trailer(object, object_size);
+    if trailer.state != OBJECT_FREE {
+        panic!("heap corruption: object 0x{:x} in the free list has state 0x{:x}",
+               object, trailer.state);
+    }
+
+    // The first bytes hold the free list link.
+    let header = mem::size_of::<FreeObject>();
+    if let Some(offset) = alloc_debug::find_unpoisoned(object + header,
+                                                       object_size - header - TRAILER_LENGTH) {
+        log!("Heap object 0x{:x} of size {} was written at offset {} after being freed.",
+             object, object_size, offset + header);
+        log!("It was last allocated by:");
+        alloc_debug::log_backtrace(trailer.id);
+        panic!("use after free of heap object 0x{:x}", object);
+    }
+
+    trailer.state = OBJECT_ALLOCATED;
+    trailer.id = alloc_debug::backtrace_id();
+}
+
+/// Check that an object being freed is allocated, and poison it.
+#[cfg(feature="debug_alloc")]
+unsafe fn check_free(object: VAddr, class: usize) {
+    let object_size = SIZE_CLASSES[class];
+    let trailer = trailer(object, object_size);
+    match trailer.state {
+        OBJECT_ALLOCATED => (),
+        OBJECT_FREE => {
+            log!("Heap object 0x{:x} of size {} freed twice.", object, object_size);
+            log!("It was last allocated by:");
+            alloc_debug::log_backtrace(trailer.id);
+            log!("And freed again by:");
+            alloc_debug::log_backtrace(alloc_debug::backtrace_id());
+            panic!("double free of heap object 0x{:x}", object);
+        },
+        state => panic!("freeing invalid heap object 0x{:x} with state 0x{:x}", object, state),
+    }
+
+    alloc_debug::poison(object, object_size - TRAILER_LENGTH);
+    trailer.state = OBJECT_FREE;
+}
+
+/// Frame order of a block serving `layout`. Blocks are aligned to
+/// their own length.
+fn order_for(layout: &Layout) -> usize {
+    frame::order_for(cmp::max(layout.size(), layout.align()))
+}
+
+/// Allocate an object of the given size class.
+unsafe fn allocate_object(class: usize) -> Option<VAddr> {
+    let magazine = &mut MAGAZINES[arch::current_cpu_id()][class];
+
+    if magazine.count == 0 {
+        let mut cache = CACHES[class].lock();
+        while magazine.count < MAGAZINE_SIZE / 2 {
+            match cache.pop() {
+                Some(object) => {
+                    magazine.objects[magazine.count] = Some(object);
+                    magazine.count += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    if magazine.count == 0 {
+        return None;
+    }
+
+    magazine.count -= 1;
+    let object = magazine.objects[magazine.count].take();
+
+    #[cfg(feature="debug_alloc")]
+    check_allocate(object.unwrap(), class);
+
+    object
+}
+
+/// Free an object of the given size class.
+unsafe fn free_object(object: VAddr, class: usize) {
+    #[cfg(feature="debug_alloc")]
+    check_free(object, class);
+
+    let magazine = &mut MAGAZINES[arch::current_cpu_id()][class];
+
+    if magazine.count == MAGAZINE_SIZE {
+        let mut cache = CACHES[class].lock();
+        while magazine.count > MAGAZINE_SIZE / 2 {
+            magazine.count -= 1;
+            cache.push(magazine.objects[magazine.count].take().unwrap());
+        }
+    }
+
+    magazine.objects[magazine.count] = Some(object);
+    magazine.count += 1;
+}
+
+/// The kernel heap. Small objects come from the slab caches, and
+/// everything else from the frame allocator as whole blocks.
+struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut Opaque {
+        let object = match class_for(&layout) {
+            Some(class) => allocate_object(class),
+            None => frame::allocate(order_for(&layout), MemoryCategory::KernelObject)
+                .map(frame::to_vaddr),
+        };
+
+        match object {
+            Some(vaddr) => vaddr.into(): usize as *mut Opaque,
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut Opaque, layout: Layout) {
+        let object = VAddr::from(pointer as usize);
+
+        match class_for(&layout) {
+            Some(class) => free_object(object, class),
+            None => frame::free(frame::to_paddr(object), order_for(&layout),
+                                MemoryCategory::KernelObject),
+        }
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+#[lang="oom"]
+#[no_mangle]
+pub fn rust_oom() -> ! {
+    panic!("Out of kernel heap memory");
+}
@@ -0,0 +1,28 @@
+use abi::{KernelInfo, ABI_VERSION, FEATURE_KPTI, FEATURE_TRACING};
+use arch;
+
+/// Parse a version component from the crate metadata.
+fn version_component(s: &str) -> u16 {
+    s.parse().unwrap_or(0)
+}
+
+/// Configuration of the running kernel, reported to user-space by the
+/// `KernelInfo` system call.
+pub fn kernel_info() -> KernelInfo {
+    let mut features = arch::features();
+    if cfg!(feature = "kpti") {
+        features |= FEATURE_KPTI;
+    }
+    if cfg!(feature = "kernel_debug") {
+        features |= FEATURE_TRACING;
+    }
+
+    KernelInfo {
+        version: (version_component(env!("CARGO_PKG_VERSION_MAJOR")),
+                  version_component(env!("CARGO_PKG_VERSION_MINOR")),
+                  version_component(env!("CARGO_PKG_VERSION_PATCH"))),
+        abi_version: ABI_VERSION,
+        features: features,
+        page_sizes: arch::page_sizes(),
+    }
+}
@@ -0,0 +1,113 @@
+use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use abi::{IpcKind, IpcTraceEntry, IpcTrace, ChannelMessage, IPC_TRACE_ENTRIES};
+use util::Mutex;
+use cap::{TaskCap, ChannelCap, ChannelValue};
+use time;
+
+/// Number of messages the trace keeps.
+const TRACE_LENGTH: usize = 256;
+
+/// The last messages recorded.
+struct Ring {
+    entries: [Option<IpcTraceEntry>; TRACE_LENGTH],
+    /// Number of messages ever recorded. The message at position `p`
+    /// is at index `p % TRACE_LENGTH`, until overwritten.
+    written: u64,
+}
+
+/// Whether messages are recorded.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+static TRACE: Mutex<Ring> = Mutex::new(Ring {
+    entries: [None; TRACE_LENGTH],
+    written: 0,
+});
+
+/// Start or stop recording messages. Returns `false` if tracing is
+/// not compiled in, as reported by `FEATURE_TRACING`.
+pub fn set_enabled(enabled: bool) -> bool {
+    if !cfg!(feature = "kernel_debug") {
+        return false;
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+    true
+}
+
+/// Whether messages are recorded. Callers check it before gathering
+/// what `record` takes.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Payload bytes `message`, sent by `sender`, carries.
+pub fn message_length(message: &ChannelMessage, sender: &TaskCap) -> usize {
+    match *message {
+        ChannelMessage::Payload => {
+            sender.read().upgrade_buffer()
+                .map(|buffer_cap| buffer_cap.read().read().payload_length).unwrap_or(0)
+        },
+        _ => 0,
+    }
+}
+
+/// Payload bytes a value put in a channel carries.
+pub fn value_length(value: &ChannelValue) -> usize {
+    match *value {
+        ChannelValue::Payload(ref buffer_cap) => buffer_cap.read().read().payload_length,
+        _ => 0,
+    }
+}
+
+/// Record a message from `sender` to `receiver`. Values put in and
+/// taken from channels have only one of them.
+pub fn record(kind: IpcKind, sender: Option<&TaskCap>, receiver: Option<&TaskCap>,
+              endpoint: u64, badge: u64, length: usize, fastpath: bool) {
+    let entry = IpcTraceEntry {
+        timestamp_ns: time::monotonic_ns(),
+        kind: kind,
+        sender: sender.map(|sender| sender.ptr().into(): u64).unwrap_or(0),
+        receiver: receiver.map(|receiver| receiver.ptr().into(): u64).unwrap_or(0),
+        endpoint: endpoint,
+        badge: badge,
+        length: length,
+        fastpath: fastpath,
+    };
+
+    let mut trace = TRACE.lock();
+    let index = (trace.written % TRACE_LENGTH as u64) as usize;
+    trace.entries[index] = Some(entry);
+    trace.written += 1;
+}
+
+/// Record `value` put in or taken from `chan` by `task`, as `kind`
+/// says, if tracing is on.
+pub fn record_channel(kind: IpcKind, task: &TaskCap, chan: &ChannelCap, value: &ChannelValue) {
+    if !is_enabled() {
+        return;
+    }
+    let length = value_length(value);
+    let endpoint = chan.ptr().into(): u64;
+    match kind {
+        IpcKind::ChannelTake => record(kind, None, Some(task), endpoint, 0, length, false),
+        _ => record(kind, Some(task), None, endpoint, 0, length, false),
+    }
+}
+
+/// Up to `IPC_TRACE_ENTRIES` messages from `position` on. Messages
+/// already overwritten are skipped.
+pub fn read(position: u64) -> IpcTrace {
+    let trace = TRACE.lock();
+    let oldest = trace.written.saturating_sub(TRACE_LENGTH as u64);
+    let start = cmp::min(cmp::max(position, oldest), trace.written);
+    let length = cmp::min((trace.written - start) as usize, IPC_TRACE_ENTRIES);
+
+    let mut result = IpcTrace {
+        start: start,
+        entries: [None; IPC_TRACE_ENTRIES],
+    };
+    for i in 0..length {
+        result.entries[i] = trace.entries[((start + i as u64) % TRACE_LENGTH as u64) as usize];
+    }
+    result
+}
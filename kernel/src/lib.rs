@@ -15,11 +15,15 @@
 #![feature(reflect_marker)]
 #![feature(core_slice_ext)]
 #![feature(ptr_internals)]
+#![feature(alloc)]
+#![feature(allocator_api)]
+#![feature(global_allocator)]
 #![no_std]
 
 extern crate spin;
 extern crate rlibc;
 extern crate abi;
+extern crate alloc;
 
 #[macro_use]
 extern crate lazy_static;
@@ -63,19 +67,79 @@ mod cap;
 /// System call handler.
 mod system_calls;
 
-use core::slice;
+/// Endpoint calls switched directly to a waiting receiver.
+mod fastpath;
+
+/// Ring of the last messages between tasks, for debugging servers.
+mod ipc_trace;
+
+/// Cycle counts of context switches and IPC round trips.
+#[cfg(feature="kernel_debug")]
+mod switch_stats;
+
+/// Kernel time keeping based on timer interrupts.
+mod time;
+
+/// Preemption and interrupt nesting count.
+mod preempt;
+
+/// Work deferred by interrupt handlers.
+mod softirq;
+
+/// Hierarchical timer wheel, driven by the timer deadline.
+mod timer_wheel;
+
+/// Kernel configuration and feature discovery.
+mod info;
+
+/// Buddy allocator for physical frames used by the kernel itself.
+mod frame;
+
+/// Kernel heap, with slab caches backed by the frame allocator.
+mod heap;
+
+/// Physically contiguous buffers for device DMA.
+mod dma;
+
+/// Accounting of physical memory usage by category.
+mod meminfo;
+
+/// Poisoning and allocation tracking for the kernel allocators.
+#[cfg(feature="debug_alloc")]
+mod alloc_debug;
+
+/// Boot-time check that tearing down objects frees all their memory.
+#[cfg(feature="teardown_test")]
+mod teardown_test;
+
+use core::{cmp, slice};
 use common::*;
 use arch::{InitInfo, Exception};
-use cap::{UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue, PAGE_LENGTH};
+use cap::{UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue, PmemCap, DeviceUntypedCap, IoPortCap, KernelLogCap, IrqControlCap, AsidControlCap, CpuControlCap, BootImageCap, SchedContextCap, PageFaultResult, PAGE_LENGTH};
 use core::ops::DerefMut;
-use abi::SystemCall;
+use abi::{SystemCall, IpcKind, FaultInfo, DebugEvent, BootInfo, SlotRegion, RegionInfo,
+          BOOT_CPOOL, BOOT_TASK, BOOT_TOP_PAGE_TABLE, BOOT_INFO_FRAME, BOOT_SCHED_CONTEXT, BOOT_INFO_VADDR, UNTYPED_FIRST, UNTYPED_COUNT,
+          DEVICE_UNTYPED_FIRST, DEVICE_UNTYPED_COUNT, IO_PORT_ALL, KERNEL_LOG, IRQ_CONTROL, ASID_CONTROL, CPU_CONTROL, BOOT_IMAGE, PRIORITY_MAX};
 use util::MemoryObject;
 use core::any::TypeId;
+use meminfo::MemoryCategory;
+
+/// Fraction of each untyped region given to the frame allocator at
+/// boot.
+const FRAME_POOL_FRACTION: usize = 8;
+
+/// Number of freed pages zeroed each time the kernel would otherwise
+/// idle. Small enough not to delay the next interrupt much.
+const SCRUB_BATCH: usize = 16;
 
 /// Map a stack for the rinit program using the given physical address
-/// and stack size.
+/// and stack size. The page below the stack is left as a guard page.
 fn map_rinit_stack(rinit_stack_vaddr: VAddr, rinit_stack_size: usize,
                    cpool: &mut CPoolCap, untyped: &mut UntypedCap, rinit_pml4: &mut TopPageTableCap) {
+    rinit_pml4.guard(rinit_stack_vaddr - PAGE_LENGTH,
+                     untyped.write().deref_mut(),
+                     cpool.write().deref_mut());
+
     for i in 0..rinit_stack_size {
         let mut rinit_stack_page = RawPageCap::retype_from(untyped.write().deref_mut());
         cpool.read().downgrade_free(&rinit_stack_page);
@@ -98,8 +162,9 @@ fn map_rinit_buffer(rinit_buffer_vaddr: VAddr,
 }
 
 /// Bootstrap paging for the rinit program. This creates stacks and
-/// task buffers for both a "parent" and a "child".
-fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &mut UntypedCap) -> (TopPageTableCap, TaskBufferPageCap, VAddr, VAddr) {
+/// task buffers for both a "parent" and a "child", and maps the page
+/// the boot information goes into.
+fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &mut UntypedCap) -> (TopPageTableCap, TaskBufferPageCap, RawPageCap, VAddr, VAddr) {
     use elf::{ElfBinary};
 
     let rinit_stack_vaddr = VAddr::from(0x80000000: usize);
@@ -110,7 +175,13 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
     let rinit_child_buffer_vaddr = VAddr::from(0x90003000: usize);
 
     let mut rinit_pml4 = TopPageTableCap::retype_from(untyped.write().deref_mut());
-    cpool.read().downgrade_free(&rinit_pml4);
+    cpool.read().downgrade_at(&rinit_pml4, BOOT_TOP_PAGE_TABLE as usize);
+
+    let boot_info_page = RawPageCap::retype_from(untyped.write().deref_mut());
+    cpool.read().downgrade_at(&boot_info_page, BOOT_INFO_FRAME as usize);
+    rinit_pml4.map(VAddr::from(BOOT_INFO_VADDR), &boot_info_page,
+                   untyped.write().deref_mut(),
+                   cpool.write().deref_mut());
 
     let slice_object = unsafe { MemoryObject::<u8>::slice(archinfo.rinit_region().start_paddr(),
                                                           archinfo.rinit_region().length()) };
@@ -179,17 +250,68 @@ fn bootstrap_rinit_paging(archinfo: &InitInfo, cpool: &mut CPoolCap, untyped: &m
                    untyped.write().deref_mut(),
                    cpool.write().deref_mut());
 
-    (rinit_pml4, rinit_buffer_page, VAddr::from(rinit_entry), rinit_stack_vaddr + (PAGE_LENGTH * rinit_stack_size - 4))
+    (rinit_pml4, rinit_buffer_page, boot_info_page, VAddr::from(rinit_entry), rinit_stack_vaddr + (PAGE_LENGTH * rinit_stack_size - 4))
+}
+
+/// Put `untyped` in the next entry of the untyped capabilities of the
+/// boot layout, and describe it in `boot_info`. Returns `false` if the
+/// entries are all taken.
+fn add_boot_untyped(untyped: &UntypedCap, cpool: &CPoolCap, boot_info: &mut BootInfo) -> bool {
+    let index = boot_info.untyped_slots.end - boot_info.untyped_slots.start;
+    if index == UNTYPED_COUNT {
+        return false;
+    }
+
+    cpool.read().downgrade_at(untyped, UNTYPED_FIRST as usize + index);
+    boot_info.untyped[index] = RegionInfo {
+        start_paddr: untyped.read().start_paddr().into(): u64,
+        length: untyped.read().length(),
+    };
+    boot_info.untyped_slots.end += 1;
+    true
+}
+
+/// Write `boot_info` into the boot information page of rinit, once
+/// every capability it describes is in place. The entries left empty
+/// are those after the ones taken at boot, up to the untyped
+/// capabilities.
+/// Hand a `FRAME_POOL_FRACTION` of `untyped`, in whole blocks of the
+/// largest order, to the kernel's frame allocator, as far as `pool`
+/// has room. The region stays pinned in the untyped capability, while
+/// frames from the pool can be freed.
+fn reserve_frame_pool(untyped: &UntypedCap, pool: &mut frame::FramePool) {
+    let length = cmp::min(untyped.read().length() / FRAME_POOL_FRACTION, pool.room()) /
+        frame::MAX_BLOCK_LENGTH * frame::MAX_BLOCK_LENGTH;
+    if length > 0 {
+        let start_paddr = unsafe { untyped.write().reserve(length, frame::MAX_BLOCK_LENGTH) };
+        untyped.write().pin();
+        pool.push(MemoryRegion::new(start_paddr, length));
+    }
 }
 
-/// The kernel main function. It initialize the rinit program, and
-/// then run a loop to switch to all available tasks.
+fn write_boot_info(mut boot_info: BootInfo, boot_info_page: &RawPageCap, cpool: &CPoolCap) {
+    let first_empty = (0..UNTYPED_FIRST as usize).find(|i| cpool.read().is_free(*i))
+        .unwrap_or(UNTYPED_FIRST as usize);
+    boot_info.empty_slots = SlotRegion { start: first_empty, end: UNTYPED_FIRST as usize };
+
+    let mut page = boot_info_page.write();
+    let mut page_raw = page.write();
+    unsafe { *(page_raw.0.as_mut_ptr() as *mut BootInfo) = boot_info; }
+}
+
+/// The kernel main function. It initialize the rinit program, starts
+/// the secondary CPUs, and then run a loop to switch to all available
+/// tasks.
 #[no_mangle]
 pub fn kmain(archinfo: InitInfo)
 {
     log!("archinfo: {:?}", &archinfo);
     let mut region_iter = archinfo.free_regions();
 
+    let mut boot_info = BootInfo::empty();
+    boot_info.untyped_slots = SlotRegion { start: UNTYPED_FIRST as usize, end: UNTYPED_FIRST as usize };
+
+    let mut frame_pool = frame::FramePool::new();
     let (mut cpool_cap, mut untyped_cap) = {
         let cpool_target_region = region_iter.next().unwrap();
 
@@ -197,15 +319,20 @@ pub fn kmain(archinfo: InitInfo)
                                                      cpool_target_region.length()) };
         let cpool = CPoolCap::retype_from(untyped.write().deref_mut());
 
-        cpool.read().downgrade_at(&cpool, 0);
-        cpool.read().downgrade_free(&untyped);
+        cpool.read().downgrade_at(&cpool, BOOT_CPOOL as usize);
+        add_boot_untyped(&untyped, &cpool, &mut boot_info);
+        reserve_frame_pool(&untyped, &mut frame_pool);
 
         let mut untyped_target = untyped;
 
         for region in region_iter {
             let untyped = unsafe { UntypedCap::bootstrap(region.start_paddr(),
                                                          region.length()) };
-            cpool.read().downgrade_free(&untyped);
+            if !add_boot_untyped(&untyped, &cpool, &mut boot_info) {
+                log!("Untyped region left out: {:?}", untyped);
+                continue;
+            }
+            reserve_frame_pool(&untyped, &mut frame_pool);
 
             if untyped.read().length() > untyped_target.read().length() {
                 untyped_target = untyped;
@@ -215,29 +342,109 @@ pub fn kmain(archinfo: InitInfo)
         (cpool, untyped_target)
     };
 
-    log!("CPool: {:?}", cpool_cap);
-    log!("Untyped: {:?}", untyped_cap);
+    if !frame_pool.regions().is_empty() {
+        // Untyped memory stays with its capabilities, and is retyped
+        // from their watermark. The frame allocator only serves the
+        // memory the kernel allocates and frees on its own.
+        let window_pd = unsafe {
+            untyped_cap.write().allocate_as(PAGE_LENGTH, PAGE_LENGTH, MemoryCategory::PageTable)
+        };
+        unsafe { frame::init(&frame_pool, window_pd); }
+        untyped_cap.write().pin();
+        for region in frame_pool.regions() {
+            log!("Frame allocator: 0x{:x}, {} frames", region.start_paddr(), region.length() / PAGE_LENGTH);
+        }
+    }
 
-    log!("type_id: {:?}", TypeId::of::<CPoolCap>());
+    #[cfg(feature="teardown_test")]
     {
-        use util::{RwLock};
-        use util::managed_arc::{ManagedArc};
-        use cap::{CPoolDescriptor};
-        log!("type_id: {:?}", TypeId::of::<ManagedArc<RwLock<CPoolDescriptor>>>());
+        // Nothing is derived from the largest untyped region yet,
+        // unless it also holds the boot capability pool.
+        if untyped_cap.read().has_children() {
+            log!("Teardown test skipped: the boot capability pool shares its region");
+        } else {
+            teardown_test::run(&untyped_cap);
+        }
     }
 
-    {
-        let (rinit_pml4, rinit_buffer_page, rinit_entry, rinit_stack) =
-            bootstrap_rinit_paging(&archinfo, &mut cpool_cap, &mut untyped_cap);
+    // The rinit task and its paging structures take the fixed entries
+    // of the boot layout before anything else takes free entries.
+    let boot_info_page = {
         let rinit_task_cap = TaskCap::retype_from(untyped_cap.write().deref_mut());
+        cpool_cap.read().downgrade_at(&rinit_task_cap, BOOT_TASK as usize);
+        let rinit_sched_context_cap = SchedContextCap::retype_from(untyped_cap.write().deref_mut());
+        cpool_cap.read().downgrade_at(&rinit_sched_context_cap, BOOT_SCHED_CONTEXT as usize);
+        assert!(rinit_sched_context_cap.bind(&rinit_task_cap));
+        let (rinit_pml4, rinit_buffer_page, boot_info_page, rinit_entry, rinit_stack) =
+            bootstrap_rinit_paging(&archinfo, &mut cpool_cap, &mut untyped_cap);
         let mut rinit_task = rinit_task_cap.write();
         rinit_task.set_instruction_pointer(rinit_entry);
         rinit_task.set_stack_pointer(rinit_stack);
         rinit_task.set_status(TaskStatus::Active);
+        rinit_task.set_max_priority(PRIORITY_MAX);
         rinit_task.downgrade_cpool(&cpool_cap);
         rinit_task.downgrade_top_page_table(&rinit_pml4);
         rinit_task.downgrade_buffer(&rinit_buffer_page);
+        boot_info_page
+    };
+
+    for region in archinfo.pmem_regions() {
+        let pmem = unsafe { PmemCap::bootstrap(region, untyped_cap.write().deref_mut()) };
+        cpool_cap.read().downgrade_free(&pmem);
+        log!("Persistent memory: {:?}", pmem);
+    }
+
+    // Device untyped capabilities go into a well-known range, before
+    // anything else takes free entries.
+    let mut device_count = 0;
+    for (i, region) in archinfo.device_regions().take(DEVICE_UNTYPED_COUNT).enumerate() {
+        let device = unsafe { DeviceUntypedCap::bootstrap(region, untyped_cap.write().deref_mut()) };
+        cpool_cap.read().downgrade_at(&device, DEVICE_UNTYPED_FIRST as usize + i);
+        boot_info.device_untyped[i] = RegionInfo {
+            start_paddr: device.read().start_paddr().into(): u64,
+            length: device.read().length(),
+        };
+        device_count = i + 1;
+        log!("Device memory: {:?}", device);
     }
+    boot_info.device_untyped_slots = SlotRegion {
+        start: DEVICE_UNTYPED_FIRST as usize,
+        end: DEVICE_UNTYPED_FIRST as usize + device_count,
+    };
+
+    let ioport = unsafe { IoPortCap::bootstrap(0, 0xffff, untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&ioport, IO_PORT_ALL as usize);
+
+    let kernel_log = unsafe { KernelLogCap::bootstrap(untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&kernel_log, KERNEL_LOG as usize);
+
+    let irq_control = unsafe { IrqControlCap::bootstrap(untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&irq_control, IRQ_CONTROL as usize);
+
+    let asid_control = unsafe { AsidControlCap::bootstrap(untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&asid_control, ASID_CONTROL as usize);
+
+    let cpu_control = unsafe { CpuControlCap::bootstrap(untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&cpu_control, CPU_CONTROL as usize);
+
+    let boot_image = unsafe { BootImageCap::bootstrap(archinfo.rinit_region(), untyped_cap.write().deref_mut()) };
+    cpool_cap.read().downgrade_at(&boot_image, BOOT_IMAGE as usize);
+
+    log!("CPool: {:?}", cpool_cap);
+    log!("Untyped: {:?}", untyped_cap);
+
+    log!("type_id: {:?}", TypeId::of::<CPoolCap>());
+    {
+        use util::{RwLock};
+        use util::managed_arc::{ManagedArc};
+        use cap::{CPoolDescriptor};
+        log!("type_id: {:?}", TypeId::of::<ManagedArc<RwLock<CPoolDescriptor>>>());
+    }
+
+    write_boot_info(boot_info, &boot_info_page, &cpool_cap);
+
+    log!("Object pool: {:?}", arch::object_pool_stats());
+    log!("Memory: {:?}", meminfo::mem_info());
 
     let keyboard_cap = ChannelCap::retype_from(untyped_cap.write().deref_mut());
     cpool_cap.read().downgrade_at(&keyboard_cap, 254);
@@ -247,87 +454,322 @@ pub fn kmain(archinfo: InitInfo)
 
     log!("hello, world!");
     arch::enable_timer();
+    time::init();
+    // Fastpath calls that can skip the general path switch straight to
+    // the receiver from the system call entry.
+    arch::set_fastpath_handler(fastpath::call);
+    // Corrected machine check errors raise nothing, so poll for them.
+    timer_wheel::add(time::monotonic_ns() + 1_000_000_000, 1_000_000_000,
+                     |_| arch::poll_machine_checks(), 0);
+    // Only the bootstrap CPU takes the keyboard interrupt.
+    let handle_bootstrap_interrupt = |exception: &Exception| match *exception {
+        Exception::Keyboard => {
+            keyboard_cap.write().put(ChannelValue::Raw(unsafe { arch::inportb(0x60) } as u64));
+        },
+        _ => handle_interrupt(exception),
+    };
+    // Secondary CPUs share everything set up so far, and start taking
+    // tasks as soon as they are online.
+    arch::start_secondary_cpus();
+    schedule(&handle_bootstrap_interrupt)
+}
+
+/// The kernel main function of secondary CPUs, once online. They run
+/// the same loop as the bootstrap CPU.
+#[no_mangle]
+pub fn kmain_secondary() -> ! {
+    schedule(&handle_interrupt)
+}
+
+/// Handle interrupts that need no more than handling, whatever the CPU
+/// was doing when they came.
+fn handle_interrupt(exception: &Exception) {
+    match *exception {
+        Exception::Timer => {
+            timer_wheel::run_expired();
+            cap::wake_expired();
+        },
+        Exception::Device { vector } => {
+            cap::irq_notify(vector);
+        },
+        _ => (),
+    }
+}
+
+/// Switch to all available tasks of the current CPU, forever, handing
+/// interrupts to `handle_interrupt`.
+fn schedule<F: Fn(&Exception)>(handle_interrupt: &F) -> ! {
     loop {
-        let mut idle = true;
-
-        for task_cap in cap::task_iter() {
-            let status = task_cap.read().status();
-            let exception = match status {
-                TaskStatus::Inactive => None,
-                TaskStatus::Active => {
-                    idle = false;
-                    Some(task_cap.write().switch_to())
-                },
-                TaskStatus::ChannelWait(ref chan) => {
-                    let value = chan.write().take();
-                    if let Some(value) = value {
-                        let system_call: SystemCall = {
-                            let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
-                            let buffer_desc = buffer_cap.read();
-                            let buffer = buffer_desc.read();
-                            buffer.call.clone().unwrap()
-                        };
-                        let ret_system_call = match system_call {
-                            SystemCall::ChannelTake {
-                                request, ..
-                            } => {
-                                Some(SystemCall::ChannelTake {
-                                    request: request,
-                                    response: Some(ChannelValue::to_message(
-                                        value,
-                                        task_cap.clone()))
-                                })
-                            }
-                            _ => panic!(),
-                        };
-                        if ret_system_call.is_some() {
-                            let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
-                            let mut buffer_desc = buffer_cap.write();
-                            let mut buffer = buffer_desc.write();
-                            buffer.call = ret_system_call;
+        // A CPU taken offline hands its tasks over, and then only wakes
+        // up for the interrupts still routed to it, until it is brought
+        // back online.
+        if !arch::is_cpu_online(arch::current_cpu_id()) {
+            cap::migrate_tasks();
+            // Shootdowns reached the CPU until now, as it may have run
+            // a task since it was taken offline.
+            arch::stop_cpu_scheduling(arch::current_cpu_id());
+            #[cfg(feature="kernel_debug")]
+            switch_stats::discard_entry();
+            let exception = arch::park();
+            let cpu = arch::current_cpu_id();
+            if arch::is_cpu_unparking(cpu) {
+                arch::tsc_sync_target();
+                arch::finish_unpark_cpu(cpu);
+            }
+            let _irq = preempt::IrqGuard::new();
+            if let Exception::Device { vector } = exception {
+                cap::irq_notify(vector);
+            }
+            continue;
+        }
+
+        softirq::run_pending(&handle_interrupt);
+        let picked = match cap::pick_task() {
+            Some(picked) => picked,
+            None => {
+                // Another CPU may have more tasks than it can run.
+                if cap::steal_task() {
+                    continue;
+                }
+                cap::scrub_free_pages(SCRUB_BATCH);
+                #[cfg(feature="kernel_debug")]
+                switch_stats::discard_entry();
+                let sleep_ns = timer_wheel::next_deadline()
+                    .map(|deadline| deadline.saturating_sub(time::monotonic_ns()));
+                let exception = arch::idle(sleep_ns);
+                let _irq = preempt::IrqGuard::new();
+                handle_interrupt(&exception);
+                continue;
+            },
+        };
+
+        let task_cap = picked;
+        cap::set_current_task(&task_cap);
+        // Suspended tasks are skipped like inactive ones, their
+        // status kept for when they are resumed.
+        let status = if task_cap.read().is_suspended() {
+            TaskStatus::Inactive
+        } else {
+            task_cap.read().status()
+        };
+        let exception = match status {
+            TaskStatus::Inactive | TaskStatus::Exited(_) => None,
+            // Tasks without budget left wait for their next
+            // period like idle ones.
+            TaskStatus::Active => task_cap.switch_to(),
+            TaskStatus::EndpointReceive(ref endpoint) => {
+                if system_calls::endpoint_receive(&task_cap, endpoint) {
+                    task_cap.switch_to()
+                } else {
+                    None
+                }
+            },
+            TaskStatus::NotificationWait(ref notification, deadline) => {
+                if system_calls::notification_wait(&task_cap, notification, deadline) {
+                    task_cap.switch_to()
+                } else {
+                    None
+                }
+            },
+            TaskStatus::Sleep(deadline) => {
+                if system_calls::sleep(&task_cap, deadline) {
+                    task_cap.switch_to()
+                } else {
+                    None
+                }
+            },
+            // Woken by the receiver.
+            TaskStatus::EndpointSend(..) => None,
+            // Woken by the reply, or failed if the server exited
+            // without replying.
+            TaskStatus::ReplyWait(ref reply) => {
+                if reply.fail_if_abandoned() {
+                    task_cap.switch_to()
+                } else {
+                    None
+                }
+            },
+            TaskStatus::ChannelWait(ref chan) => {
+                let buffer_cap = task_cap.read().upgrade_buffer();
+                // A task without time of its own can only take a
+                // value that comes with donated time.
+                let runnable = task_cap.read().has_budget() || chan.read().has_donation();
+                let value = match buffer_cap {
+                    Some(_) if !runnable => None,
+                    Some(_) => {
+                        let mut chan_desc = chan.write();
+                        let value = chan_desc.take();
+                        if let Some(donation) = chan_desc.take_donation() {
+                            donation.receive(&task_cap);
                         }
-                        task_cap.write().set_status(TaskStatus::Active);
-                        Some(task_cap.write().switch_to())
-                    } else {
+                        value
+                    },
+                    // The buffer was revoked, so the task can never
+                    // take the value.
+                    None => {
+                        task_cap.write().set_status(TaskStatus::Inactive);
                         None
-                    }
+                    },
+                };
+                // Likewise if the channel was torn down while the
+                // task waited on it.
+                if value.is_none() && chan.read().is_closed() {
+                    task_cap.write().set_status(TaskStatus::Inactive);
                 }
-            };
-            match exception {
-                Some(Exception::SystemCall) => {
-                    let cpool_cap = task_cap.read().upgrade_cpool().unwrap();
+                if let (Some(value), Some(buffer_cap)) = (value, buffer_cap) {
+                    ipc_trace::record_channel(IpcKind::ChannelTake, &task_cap, chan, &value);
                     let system_call: SystemCall = {
-                        let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
                         let buffer_desc = buffer_cap.read();
                         let buffer = buffer_desc.read();
                         buffer.call.clone().unwrap()
                     };
-                    let ret_system_call = system_calls::handle(
-                        system_call,
-                        task_cap.clone(),
-                        cpool_cap.clone());
+                    let ret_system_call = match system_call {
+                        SystemCall::ChannelTake {
+                            request, ..
+                        } => {
+                            Some(SystemCall::ChannelTake {
+                                request: request,
+                                response: Some(ChannelValue::to_message(
+                                    value,
+                                    task_cap.clone()))
+                            })
+                        },
+                        SystemCall::ChannelDonate {
+                            request, ..
+                        } => {
+                            Some(SystemCall::ChannelDonate {
+                                request: request,
+                                response: Some(ChannelValue::to_message(
+                                    value,
+                                    task_cap.clone()))
+                            })
+                        },
+                        _ => panic!(),
+                    };
                     if ret_system_call.is_some() {
-                        let buffer_cap = task_cap.read().upgrade_buffer().unwrap();
                         let mut buffer_desc = buffer_cap.write();
                         let mut buffer = buffer_desc.write();
                         buffer.call = ret_system_call;
                     }
-                },
-                Some(Exception::Keyboard) => {
-                    keyboard_cap.write().put(ChannelValue::Raw(unsafe { arch::inportb(0x60) } as u64));
-                },
-                _ => (),
+                    task_cap.write().set_status(TaskStatus::Active);
+                    task_cap.switch_to()
+                } else {
+                    None
+                }
             }
-        }
-
-        if idle {
-            let exception = cap::idle();
-            match exception {
-                Exception::Keyboard => {
-                    keyboard_cap.write().put(ChannelValue::Raw(unsafe { arch::inportb(0x60) } as u64));
-                },
-                _ => (),
+        };
+        // A fastpath call may have switched to the receiver, which the
+        // exception is then of.
+        let (task_cap, exception) = match exception {
+            Some((task_cap, exception)) => (task_cap, Some(exception)),
+            None => (task_cap, None),
+        };
+        if exception.is_some() {
+            if task_cap.read().slice_expired() {
+                cap::rotate_task(&task_cap);
             }
+        } else {
+            // Nothing lets the task run for now, so it leaves its level
+            // until something does.
+            cap::block_task(&task_cap);
+        }
+        let _irq = match exception {
+            Some(ref exception) if exception.is_interrupt() => Some(preempt::IrqGuard::new()),
+            _ => None,
+        };
+        match exception {
+            Some(Exception::SystemCall) => {
+                let cpool_cap = task_cap.read().upgrade_cpool();
+                let buffer_cap = task_cap.read().upgrade_buffer();
+                let (cpool_cap, buffer_cap) = match (cpool_cap, buffer_cap) {
+                    (Some(cpool_cap), Some(buffer_cap)) => (cpool_cap, buffer_cap),
+                    // Without its root pool or buffer, the task
+                    // cannot make system calls anymore.
+                    _ => {
+                        task_cap.write().set_status(TaskStatus::Inactive);
+                        continue;
+                    },
+                };
+                let system_call: SystemCall = {
+                    let buffer_desc = buffer_cap.read();
+                    let buffer = buffer_desc.read();
+                    buffer.call.clone().unwrap()
+                };
+                cap::clear_call_error();
+                let ret_system_call = system_calls::handle(
+                    system_call,
+                    task_cap.clone(),
+                    cpool_cap.clone());
+                {
+                    let mut buffer_desc = buffer_cap.write();
+                    let mut buffer = buffer_desc.write();
+                    buffer.cap_error = cap::call_error();
+                    if ret_system_call.is_some() {
+                        buffer.call = ret_system_call;
+                    }
+                }
+            },
+            Some(ref exception) if exception.is_interrupt() => {
+                handle_interrupt(exception);
+            },
+            Some(Exception::PageFault { address, instruction_pointer, error }) => {
+                let result = task_cap.read().upgrade_top_page_table()
+                    .map(|pml4| pml4.handle_page_fault(address, error))
+                    .unwrap_or(PageFaultResult::Unhandled);
+                let stack_overflow = match result {
+                    PageFaultResult::Resolved => continue,
+                    PageFaultResult::StackOverflow => {
+                        log!("Stack overflow at 0x{:x}.", address);
+                        true
+                    },
+                    PageFaultResult::Unhandled => {
+                        log!("Unhandled page fault at 0x{:x}: {}, rip 0x{:x}.",
+                             address, error, instruction_pointer);
+                        false
+                    },
+                };
+
+                // The task stays stopped until its fault handler, if
+                // any, sets it active again.
+                task_cap.write().set_status(TaskStatus::Inactive);
+                if let Some(handler) = task_cap.read().upgrade_fault_handler() {
+                    handler.write().put(ChannelValue::PageFault(
+                        error.info(address, instruction_pointer, stack_overflow)));
+                }
+            },
+            Some(Exception::Fault { exception, instruction_pointer, error_code }) => {
+                // Restarting the task would only raise the same
+                // exception again, so it stays stopped until its
+                // fault handler, if any, deals with the fault.
+                task_cap.write().set_status(TaskStatus::Inactive);
+                if let Some((kind, status)) = exception.debug_event() {
+                    // Breakpoints and steps go to the debugger
+                    // instead, which resumes the task.
+                    match task_cap.read().upgrade_debugger() {
+                        Some(debugger) => {
+                            debugger.write().put(ChannelValue::Debug(DebugEvent {
+                                kind: kind,
+                                status: status,
+                                registers: task_cap.read().registers(),
+                            }));
+                        },
+                        None => log!("Unhandled {}, rip 0x{:x}.", exception, instruction_pointer),
+                    }
+                } else {
+                    let handler = task_cap.read().upgrade_fault_handler();
+                    match (exception.fault_kind(), handler) {
+                        (Some(kind), Some(handler)) => {
+                            handler.write().put(ChannelValue::Fault(FaultInfo {
+                                kind: kind,
+                                error_code: error_code,
+                                registers: task_cap.read().registers(),
+                            }));
+                        },
+                        _ => log!("Unhandled {}, rip 0x{:x}.", exception, instruction_pointer),
+                    }
+                }
+            },
+            _ => (),
         }
     }
 }
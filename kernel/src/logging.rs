@@ -1,28 +1,108 @@
-use core::sync::atomic;
-use core::fmt;
+use core::{cmp, fmt, ptr};
+use core::sync::atomic::{self, Ordering};
+use util::Mutex;
+use arch::{self, MAX_CPUS};
+use preempt;
+
+/// Length of each log ring.
+const RING_LENGTH: usize = 1024;
+
+/// Length of the log history kept for readers of the kernel log.
+const HISTORY_LENGTH: usize = 16 * 1024;
 
 /// A formatter object
-pub struct Writer(bool);
+pub struct Writer(Output);
+
+/// Where a `Writer` puts its bytes.
+enum Output {
+	/// Straight to the console, with `LOGGING_LOCK` held.
+	Console,
+	/// To a log ring, printed later by `flush`.
+	Ring(RingWriter),
+}
 
 /// A primitive lock for the logging output
 ///
-/// This is not really a lock. Since there is no threading at the moment, all
-/// it does is prevent writing when a collision would occur.
+/// Writers that find it taken, or run in interrupt context, never wait
+/// for it: they log to their CPU's ring instead.
 static LOGGING_LOCK: atomic::AtomicBool = atomic::ATOMIC_BOOL_INIT;
 
+/// Lines logged where the console could not be written. It is a
+/// single-producer single-consumer ring: only its CPU writes it, so
+/// writers never take a lock, and `flush` drains it.
+#[derive(Copy)]
+struct Ring {
+	buffer: [u8; RING_LENGTH],
+	/// Index the producer writes next.
+	head: usize,
+	/// Index the consumer reads next.
+	tail: usize,
+}
+
+impl Clone for Ring {
+	fn clone(&self) -> Ring {
+		*self
+	}
+}
+
+/// Rings written in NMI context, indexed by local APIC id.
+static mut NMI_RINGS: [Ring; MAX_CPUS] = [Ring {
+	buffer: [0; RING_LENGTH],
+	head: 0,
+	tail: 0,
+}; MAX_CPUS];
+
+/// Rings written in any other context, indexed by local APIC id. The
+/// kernel runs with interrupts disabled, so only NMIs can interrupt a
+/// writer, and they have rings of their own.
+static mut DEFERRED_RINGS: [Ring; MAX_CPUS] = [Ring {
+	buffer: [0; RING_LENGTH],
+	head: 0,
+	tail: 0,
+}; MAX_CPUS];
+
+/// Held while draining the rings.
+static RING_CONSUMER: Mutex<()> = Mutex::new(());
+
+/// The last bytes printed to the console, for readers of the kernel
+/// log.
+struct History {
+	buffer: [u8; HISTORY_LENGTH],
+	/// Number of bytes ever recorded. The byte at position `p` is at
+	/// index `p % HISTORY_LENGTH`, until overwritten.
+	written: u64,
+}
+
+/// History of the console. Only accessed with `LOGGING_LOCK` held.
+static mut HISTORY: History = History {
+	buffer: [0; HISTORY_LENGTH],
+	written: 0,
+};
+
 impl Writer
 {
 	/// Obtain a logger for the specified module
 	pub fn get(module: &str) -> Writer {
-		// This "acquires" the lock (actually just disables output if paralel writes are attempted
-		let mut ret = Writer( ! LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) );
-		
+		// Neither the preemption count nor the CPU id may take a
+		// lock here, as the caller may hold any.
+		let cpu = arch::current_cpu_id_lockless();
+		let output = if preempt::in_nmi(cpu) {
+			Output::Ring(RingWriter::nmi(cpu))
+		} else if preempt::in_irq_on(cpu) || LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) {
+			Output::Ring(RingWriter::deferred(cpu))
+		} else {
+			// Print what was deferred first, to keep lines in order.
+			unsafe { drain(); }
+			Output::Console
+		};
+		let mut ret = Writer(output);
+
 		// Print the module name before returning (prefixes all messages)
 		{
 			use core::fmt::Write;
 			let _ = write!(&mut ret, "[{}] ", module);
 		}
-		
+
 		ret
 	}
 }
@@ -36,9 +116,10 @@ impl ::core::ops::Drop for Writer
 			use core::fmt::Write;
 			let _ = write!(self, "\n");
 		}
-		// On drop, "release" the lock
-		if self.0 {
-			LOGGING_LOCK.store(false, atomic::Ordering::Release);
+		match self.0 {
+			// On drop, "release" the lock
+			Output::Console => LOGGING_LOCK.store(false, atomic::Ordering::Release),
+			Output::Ring(ref mut ring) => ring.publish(),
 		}
 	}
 }
@@ -47,13 +128,204 @@ impl fmt::Write for Writer
 {
 	fn write_str(&mut self, s: &str) -> fmt::Result
 	{
-		// If the lock is owned by this instance, then we can safely write to the output
-		if self.0
-		{
-			unsafe {
-				::arch::debug::puts( s );
-			}
+		use core::fmt::Write;
+
+		match self.0 {
+			Output::Console => unsafe { console_puts(s); },
+			Output::Ring(ref mut ring) => { let _ = ring.write_str(s); },
 		}
 		Ok( () )
 	}
 }
+
+/// Writer appending to a log ring of one CPU. Bytes that do not fit
+/// are dropped. They become visible to `flush` on `publish`.
+pub struct RingWriter {
+	ring: *mut Ring,
+	head: usize,
+	/// Whether bytes were dropped.
+	truncated: bool,
+}
+
+impl RingWriter {
+	/// Writer to the NMI ring of CPU `cpu`, the current one. Safe to
+	/// use in NMI context.
+	pub fn nmi(cpu: usize) -> RingWriter {
+		unsafe { RingWriter::new(&mut NMI_RINGS[cpu]) }
+	}
+
+	/// Writer to the ring of CPU `cpu`, the current one, outside NMI
+	/// context.
+	fn deferred(cpu: usize) -> RingWriter {
+		unsafe { RingWriter::new(&mut DEFERRED_RINGS[cpu]) }
+	}
+
+	unsafe fn new(ring: *mut Ring) -> RingWriter {
+		RingWriter {
+			ring: ring,
+			head: ptr::read_volatile(&(*ring).head),
+			truncated: false,
+		}
+	}
+
+	/// Make the bytes written visible to `flush`. If some were dropped,
+	/// the last one kept becomes a newline, so that the next line does
+	/// not run on.
+	pub fn publish(&mut self) {
+		let ring = unsafe { &mut *self.ring };
+		if self.truncated && self.head != unsafe { ptr::read_volatile(&ring.head) } {
+			ring.buffer[(self.head + RING_LENGTH - 1) % RING_LENGTH] = b'\n';
+		}
+		atomic::fence(Ordering::Release);
+		unsafe { ptr::write_volatile(&mut ring.head, self.head); }
+	}
+}
+
+impl fmt::Write for RingWriter {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let ring = unsafe { &mut *self.ring };
+		let tail = unsafe { ptr::read_volatile(&ring.tail) };
+		for &byte in s.as_bytes() {
+			let next = (self.head + 1) % RING_LENGTH;
+			if next == tail {
+				self.truncated = true;
+				break;
+			}
+			ring.buffer[self.head] = byte;
+			self.head = next;
+		}
+		Ok(())
+	}
+}
+
+/// Writer printing straight to the console, for `drain`.
+struct Console;
+
+impl fmt::Write for Console {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		unsafe { console_puts(s); }
+		Ok(())
+	}
+}
+
+/// Print `s` to the console, and record it in the history. The
+/// console must be held.
+unsafe fn console_puts(s: &str) {
+	::arch::debug::puts(s);
+	for &byte in s.as_bytes() {
+		record(byte);
+	}
+}
+
+/// Print `byte` to the console, and record it in the history. The
+/// console must be held.
+unsafe fn console_putb(byte: u8) {
+	::arch::debug::putb(byte);
+	record(byte);
+}
+
+unsafe fn record(byte: u8) {
+	HISTORY.buffer[(HISTORY.written % HISTORY_LENGTH as u64) as usize] = byte;
+	HISTORY.written += 1;
+}
+
+/// Copy the history from `position` on into `buffer`, after printing
+/// what was deferred. Bytes already overwritten are skipped. Returns
+/// the position of the first byte copied, and the number of bytes
+/// copied. Waits for the console, which is only ever held briefly.
+pub fn read_history(position: u64, buffer: &mut [u8]) -> (u64, usize) {
+	while LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) { }
+	unsafe { drain(); }
+
+	let (start, length) = unsafe {
+		let oldest = HISTORY.written.saturating_sub(HISTORY_LENGTH as u64);
+		let start = cmp::min(cmp::max(position, oldest), HISTORY.written);
+		let length = cmp::min((HISTORY.written - start) as usize, buffer.len());
+		for (i, byte) in buffer[..length].iter_mut().enumerate() {
+			*byte = HISTORY.buffer[((start + i as u64) % HISTORY_LENGTH as u64) as usize];
+		}
+		(start, length)
+	};
+
+	LOGGING_LOCK.store(false, atomic::Ordering::Release);
+	(start, length)
+}
+
+/// Log `text` as a line written by user-space. Anything but printable
+/// ASCII, which could move the cursor, clear the console or fake a
+/// line of its own, is replaced by `?`.
+pub fn write_user(text: &str) {
+	use core::fmt::Write;
+	let mut writer = Writer::get("user");
+	for c in text.chars() {
+		let _ = writer.write_char(match c {
+			' ' ... '~' => c,
+			_ => '?',
+		});
+	}
+}
+
+/// Whether a ring has lines `flush` has not printed yet.
+pub fn pending() -> bool {
+	(0..MAX_CPUS).any(|cpu| unsafe {
+		ptr::read_volatile(&NMI_RINGS[cpu].head) != ptr::read_volatile(&NMI_RINGS[cpu].tail) ||
+			ptr::read_volatile(&DEFERRED_RINGS[cpu].head) != ptr::read_volatile(&DEFERRED_RINGS[cpu].tail)
+	})
+}
+
+/// Print the lines logged to the rings since the last call. Does
+/// nothing if another CPU holds the console. Called from task context,
+/// and on each `log!` that gets the console.
+pub fn flush() {
+	if LOGGING_LOCK.swap(true, atomic::Ordering::Acquire) {
+		return;
+	}
+	unsafe { drain(); }
+	LOGGING_LOCK.store(false, atomic::Ordering::Release);
+}
+
+/// Print the lines logged to the rings, even if the console is held,
+/// as the panicking CPU may be the one holding it.
+pub fn flush_on_panic() {
+	let acquired = !LOGGING_LOCK.swap(true, atomic::Ordering::Acquire);
+	unsafe { drain(); }
+	if acquired {
+		LOGGING_LOCK.store(false, atomic::Ordering::Release);
+	}
+}
+
+/// Print the lines of all rings, prefixed by their CPU id. Does
+/// nothing if another CPU is already doing it. The console must be
+/// held.
+unsafe fn drain() {
+	let _consumer = match RING_CONSUMER.try_lock() {
+		Some(consumer) => consumer,
+		None => return,
+	};
+
+	for cpu in 0..MAX_CPUS {
+		drain_ring(cpu, &mut NMI_RINGS[cpu]);
+		drain_ring(cpu, &mut DEFERRED_RINGS[cpu]);
+	}
+}
+
+unsafe fn drain_ring(cpu: usize, ring: &mut Ring) {
+	use core::fmt::Write;
+
+	let head = ptr::read_volatile(&ring.head);
+	atomic::fence(Ordering::Acquire);
+
+	let mut line_start = true;
+	while ring.tail != head {
+		let byte = ring.buffer[ring.tail];
+		ring.tail = (ring.tail + 1) % RING_LENGTH;
+		if line_start {
+			let _ = write!(Console, "[CPU {}] ", cpu);
+		}
+		console_putb(byte);
+		line_start = byte == b'\n';
+	}
+
+	atomic::fence(Ordering::Release);
+	ptr::write_volatile(&mut ring.tail, ring.tail);
+}
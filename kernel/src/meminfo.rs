@@ -0,0 +1,66 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use abi::MemInfo;
+
+/// What a piece of physical memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryCategory {
+    /// Paging structures, of both user and kernel address spaces.
+    PageTable,
+    /// Capability descriptors, weak pools and kernel heap slabs.
+    KernelObject,
+    /// Stacks tasks enter the kernel on.
+    KernelStack,
+    /// Frames mapped, or mappable, into user address spaces.
+    UserFrame,
+}
+
+/// Number of `MemoryCategory` variants.
+pub const CATEGORY_COUNT: usize = 4;
+
+/// All memory categories, in index order.
+pub const CATEGORIES: [MemoryCategory; CATEGORY_COUNT] =
+    [MemoryCategory::PageTable, MemoryCategory::KernelObject,
+     MemoryCategory::KernelStack, MemoryCategory::UserFrame];
+
+/// Bytes in use, indexed by `MemoryCategory`.
+static USED: [AtomicUsize; CATEGORY_COUNT] = [ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                              ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT];
+
+/// Bytes of memory handed to the kernel as untyped regions.
+static TOTAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Record `length` bytes of new memory, initially free.
+pub fn add_total(length: usize) {
+    TOTAL.fetch_add(length, Ordering::Relaxed);
+}
+
+/// Record `length` bytes taken for `category`.
+pub fn charge(category: MemoryCategory, length: usize) {
+    USED[category as usize].fetch_add(length, Ordering::Relaxed);
+}
+
+/// Record `length` bytes of `category` returned as free.
+pub fn uncharge(category: MemoryCategory, length: usize) {
+    USED[category as usize].fetch_sub(length, Ordering::Relaxed);
+}
+
+/// Current memory usage, reported to user-space by the `MemInfo`
+/// system call. Memory not charged to any category is free, whether
+/// it is in untyped regions or the frame allocator.
+pub fn mem_info() -> MemInfo {
+    let used = |category: MemoryCategory| USED[category as usize].load(Ordering::Relaxed);
+    let total = TOTAL.load(Ordering::Relaxed);
+    let page_tables = used(MemoryCategory::PageTable);
+    let kernel_objects = used(MemoryCategory::KernelObject);
+    let kernel_stacks = used(MemoryCategory::KernelStack);
+    let user_frames = used(MemoryCategory::UserFrame);
+
+    MemInfo {
+        total: total,
+        page_tables: page_tables,
+        kernel_objects: kernel_objects,
+        kernel_stacks: kernel_stacks,
+        user_frames: user_frames,
+        free: total - page_tables - kernel_objects - kernel_stacks - user_frames,
+    }
+}
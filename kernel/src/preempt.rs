@@ -0,0 +1,138 @@
+use core::ptr;
+use arch::{self, MAX_CPUS};
+
+/// Shift of the softirq nesting depth.
+const SOFTIRQ_SHIFT: usize = 8;
+/// Bits of the count holding the softirq nesting depth.
+const SOFTIRQ_MASK: usize = 0xff << SOFTIRQ_SHIFT;
+/// Shift of the hard interrupt nesting depth.
+const HARDIRQ_SHIFT: usize = 16;
+/// Bits of the count holding the hard interrupt nesting depth.
+const HARDIRQ_MASK: usize = 0xf << HARDIRQ_SHIFT;
+/// Shift of the NMI nesting depth.
+const NMI_SHIFT: usize = 20;
+/// Bits of the count holding the NMI nesting depth.
+const NMI_MASK: usize = 0xf << NMI_SHIFT;
+
+/// Interrupt nesting count of each CPU, indexed by CPU id. The low
+/// byte is left for a preemption disable depth, which nothing needs
+/// while the kernel itself is never preempted. Only a CPU changes its own count, and an NMI always restores
+/// the count it interrupted, so plain updates are enough.
+static mut COUNTS: [usize; MAX_CPUS] = [0; MAX_CPUS];
+
+fn add(cpu: usize, value: usize) {
+    unsafe {
+        let count = &mut COUNTS[cpu];
+        ptr::write_volatile(count, ptr::read_volatile(count) + value);
+    }
+}
+
+fn sub(cpu: usize, value: usize) {
+    unsafe {
+        let count = &mut COUNTS[cpu];
+        let old = ptr::read_volatile(count);
+        assert!(old >= value, "unbalanced preemption count");
+        ptr::write_volatile(count, old - value);
+    }
+}
+
+/// Interrupt nesting count of the current CPU. Not for use in the NMI
+/// handler, which must use `count_on`.
+pub fn count() -> usize {
+    count_on(arch::current_cpu_id())
+}
+
+/// Interrupt nesting count of CPU `cpu`.
+pub fn count_on(cpu: usize) -> usize {
+    unsafe { ptr::read_volatile(&COUNTS[cpu]) }
+}
+
+/// Whether the current CPU may switch tasks: it is neither handling
+/// an interrupt nor running deferred work, either of which would be
+/// left half done until the task enters the kernel again.
+pub fn preemptible() -> bool {
+    count() == 0
+}
+
+/// Enter hard interrupt context on the current CPU.
+pub fn irq_enter() {
+    add(arch::current_cpu_id(), 1 << HARDIRQ_SHIFT);
+}
+
+/// Leave hard interrupt context on the current CPU.
+pub fn irq_exit() {
+    sub(arch::current_cpu_id(), 1 << HARDIRQ_SHIFT);
+}
+
+/// Enter softirq context on the current CPU, to run deferred work.
+pub fn softirq_enter() {
+    add(arch::current_cpu_id(), 1 << SOFTIRQ_SHIFT);
+}
+
+/// Leave softirq context on the current CPU.
+pub fn softirq_exit() {
+    sub(arch::current_cpu_id(), 1 << SOFTIRQ_SHIFT);
+}
+
+/// Enter NMI context on CPU `cpu`, which must be the current one. NMI
+/// context counts as hard interrupt context too.
+pub fn nmi_enter(cpu: usize) {
+    add(cpu, (1 << NMI_SHIFT) + (1 << HARDIRQ_SHIFT));
+}
+
+/// Leave NMI context on CPU `cpu`.
+pub fn nmi_exit(cpu: usize) {
+    sub(cpu, (1 << NMI_SHIFT) + (1 << HARDIRQ_SHIFT));
+}
+
+/// Whether CPU `cpu`, the current one, is handling a hardware
+/// interrupt or NMI. Safe to use in the NMI handler.
+pub fn in_irq_on(cpu: usize) -> bool {
+    count_on(cpu) & HARDIRQ_MASK != 0
+}
+
+/// Whether CPU `cpu`, the current one, is handling an NMI.
+pub fn in_nmi(cpu: usize) -> bool {
+    count_on(cpu) & NMI_MASK != 0
+}
+
+/// Whether a long-running handler should stop at this point and let
+/// the CPU take its pending interrupts. Interrupts taken in kernel mode
+/// return to the scheduler rather than to the interrupted code, so the
+/// kernel runs with them disabled; a handler gives way by returning, or
+/// by taking them through `arch::interrupt_window`.
+pub fn should_yield() -> bool {
+    !in_nmi(arch::current_cpu_id()) && arch::interrupt_pending()
+}
+
+/// Hard interrupt context for as long as the guard lives.
+pub struct IrqGuard(());
+
+impl IrqGuard {
+    pub fn new() -> IrqGuard {
+        irq_enter();
+        IrqGuard(())
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        irq_exit();
+    }
+}
+
+/// Softirq context for as long as the guard lives.
+pub struct SoftirqGuard(());
+
+impl SoftirqGuard {
+    pub fn new() -> SoftirqGuard {
+        softirq_enter();
+        SoftirqGuard(())
+    }
+}
+
+impl Drop for SoftirqGuard {
+    fn drop(&mut self) {
+        softirq_exit();
+    }
+}
@@ -0,0 +1,119 @@
+use util::Mutex;
+use arch::{self, Exception, MAX_CPUS};
+use preempt;
+
+/// Number of work items a CPU queue can hold.
+const QUEUE_LENGTH: usize = 32;
+
+/// Deferred work: a function and its argument.
+#[derive(Clone, Copy)]
+struct Work {
+    function: fn(usize),
+    argument: usize,
+}
+
+/// Work deferred on one CPU, run in order.
+#[derive(Clone, Copy)]
+struct WorkQueue {
+    items: [Option<Work>; QUEUE_LENGTH],
+    /// Index of the oldest item.
+    head: usize,
+    /// Number of items queued.
+    length: usize,
+    /// Number of items refused since last reported, as the queue was
+    /// full.
+    dropped: usize,
+}
+
+impl WorkQueue {
+    const fn empty() -> WorkQueue {
+        WorkQueue {
+            items: [None; QUEUE_LENGTH],
+            head: 0,
+            length: 0,
+            dropped: 0,
+        }
+    }
+
+    fn contains(&self, work: Work) -> bool {
+        (0..self.length).any(|i| match self.items[(self.head + i) % QUEUE_LENGTH] {
+            Some(item) => item.function as usize == work.function as usize &&
+                item.argument == work.argument,
+            None => false,
+        })
+    }
+
+    fn push(&mut self, work: Work) -> bool {
+        // The same work queued twice would only run twice.
+        if self.contains(work) {
+            return true;
+        }
+        if self.length == QUEUE_LENGTH {
+            self.dropped += 1;
+            return false;
+        }
+        self.items[(self.head + self.length) % QUEUE_LENGTH] = Some(work);
+        self.length += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.length == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_LENGTH;
+        self.length -= 1;
+        work
+    }
+}
+
+/// Per-CPU deferred work queues, indexed by CPU id.
+static QUEUES: Mutex<[WorkQueue; MAX_CPUS]> = Mutex::new([WorkQueue::empty(); MAX_CPUS]);
+
+/// Defer `function(argument)` until the current CPU is done handling
+/// interrupts. Work stays on the CPU that deferred it, and work
+/// already queued there is not queued again. Returns `false` if the
+/// queue of the CPU is full; the work is dropped, and the drops are
+/// logged once the queue runs. Not for use in the NMI handler.
+pub fn defer(function: fn(usize), argument: usize) -> bool {
+    QUEUES.lock()[arch::current_cpu_id()].push(Work {
+        function: function,
+        argument: argument,
+    })
+}
+
+/// Run the work deferred on the current CPU, in softirq context.
+/// Called by `kmain` before switching to a task. The kernel runs with
+/// interrupts disabled, so between items the CPU takes any interrupt
+/// that is waiting, hands it to `handle_interrupt` in hard interrupt
+/// context, and goes on with the work.
+pub fn run_pending<F: FnMut(&Exception)>(mut handle_interrupt: F) {
+    let cpu = arch::current_cpu_id();
+    let _softirq = preempt::SoftirqGuard::new();
+
+    let dropped = {
+        let mut queues = QUEUES.lock();
+        let dropped = queues[cpu].dropped;
+        queues[cpu].dropped = 0;
+        dropped
+    };
+    if dropped != 0 {
+        log!("Softirq: CPU {} dropped {} deferred work items, its queue full.", cpu, dropped);
+    }
+
+    loop {
+        // Work may defer more work, so the queue is not kept locked.
+        let work = QUEUES.lock()[cpu].pop();
+        match work {
+            Some(work) => (work.function)(work.argument),
+            None => break,
+        }
+
+        if preempt::should_yield() {
+            let exception = arch::interrupt_window();
+            let _irq = preempt::IrqGuard::new();
+            handle_interrupt(&exception);
+        }
+    }
+}
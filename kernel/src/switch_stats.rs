@@ -0,0 +1,125 @@
+use core::cmp;
+use abi::{CycleStats, SwitchStats};
+use arch::{self, MAX_CPUS};
+
+/// Samples kept of each path, on each CPU.
+const SAMPLES: usize = 512;
+
+/// The last cycle counts of a path.
+#[derive(Copy)]
+struct Samples {
+    cycles: [u32; SAMPLES],
+    /// Number of samples ever recorded. Sample `n` is at index
+    /// `n % SAMPLES`, until overwritten.
+    recorded: u64,
+}
+
+impl Clone for Samples {
+    fn clone(&self) -> Samples {
+        *self
+    }
+}
+
+impl Samples {
+    fn record(&mut self, cycles: u64) {
+        self.cycles[(self.recorded % SAMPLES as u64) as usize] = cmp::min(cycles, u32::max_value() as u64) as u32;
+        self.recorded += 1;
+    }
+
+    fn summary(&self) -> CycleStats {
+        let length = cmp::min(self.recorded, SAMPLES as u64) as usize;
+        if length == 0 {
+            return CycleStats::default();
+        }
+        let mut sorted = self.cycles;
+        let sorted = &mut sorted[..length];
+        sorted.sort_unstable();
+        CycleStats {
+            samples: self.recorded,
+            median: sorted[length / 2] as u64,
+            p99: sorted[(length * 99) / 100] as u64,
+            max: sorted[length - 1] as u64,
+        }
+    }
+}
+
+/// Switch statistics of one CPU.
+#[derive(Copy)]
+struct CpuStats {
+    switch: Samples,
+    ipc_round_trip: Samples,
+    /// When the task that ran last entered the kernel, until the next
+    /// switch accounts it.
+    entry_tsc: Option<u64>,
+}
+
+impl Clone for CpuStats {
+    fn clone(&self) -> CpuStats {
+        *self
+    }
+}
+
+const EMPTY_SAMPLES: Samples = Samples {
+    cycles: [0; SAMPLES],
+    recorded: 0,
+};
+
+/// Switch statistics, indexed by local APIC id. Each CPU only updates
+/// its own, as for the interrupt statistics.
+static mut STATS: [CpuStats; MAX_CPUS] = [CpuStats {
+    switch: EMPTY_SAMPLES,
+    ipc_round_trip: EMPTY_SAMPLES,
+    entry_tsc: None,
+}; MAX_CPUS];
+
+/// Statistics of the current CPU. The CPU id is read without locking
+/// the local APIC, which the switch path may hold.
+fn current() -> &'static mut CpuStats {
+    unsafe { &mut STATS[arch::current_cpu_id_lockless()] }
+}
+
+/// Account a task entering the kernel, right after its switch
+/// returned.
+pub fn record_entry() {
+    current().entry_tsc = Some(arch::rdtsc());
+}
+
+/// Account a switch to a task, right before it: the cycles since the
+/// last task entered the kernel.
+pub fn record_switch() {
+    let stats = current();
+    if let Some(entry_tsc) = stats.entry_tsc.take() {
+        stats.switch.record(arch::rdtsc().saturating_sub(entry_tsc));
+    }
+}
+
+/// Forget the last kernel entry, when the CPU goes idle, so that the
+/// idle time is not taken for switch time.
+pub fn discard_entry() {
+    current().entry_tsc = None;
+}
+
+/// When the task that ran last entered the kernel, to time a call
+/// from.
+pub fn entry_tsc() -> Option<u64> {
+    current().entry_tsc
+}
+
+/// Account the end of a call round trip started at `call_tsc`, when
+/// the caller resumes with the reply.
+pub fn record_ipc_round_trip(call_tsc: u64) {
+    current().ipc_round_trip.record(arch::rdtsc().saturating_sub(call_tsc));
+}
+
+/// Statistics of the CPU with id `cpu`, or `None` if it is not online.
+pub fn switch_stats(cpu: usize) -> Option<SwitchStats> {
+    if !arch::is_cpu_online(cpu) {
+        return None;
+    }
+
+    let stats = unsafe { &STATS[cpu] };
+    Some(SwitchStats {
+        switch: stats.switch.summary(),
+        ipc_round_trip: stats.ipc_round_trip.summary(),
+    })
+}
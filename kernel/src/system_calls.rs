@@ -1,7 +1,176 @@
 use common::*;
 use core::ops::DerefMut;
-use cap::{self, UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue};
-use abi::SystemCall;
+use cap::{self, CPOOL_MAX_SIZE, UntypedCap, CPoolCap, RawPageCap, TaskBufferPageCap, TopPageTableCap, TaskCap, TaskStatus, ChannelCap, ChannelValue,
+          LargePageCap, PmemCap, DeviceUntypedCap, DmaCap, VSpaceCap, SharedFrameSetCap, MsiCap, IrqHandlerCap, TimerCap, LdtCap, IoPortCap, QuotaCap,
+          KernelLogCap, IrqControlCap, CpuControlCap, BootImageCap, AsidControlCap, AsidPoolCap, SchedContextCap, EndpointCap, ReplyCap, NotificationCap, NotificationGroupCap, IpcRingCap,
+          LARGE_PAGE_SPLIT_COUNT, PAGE_LENGTH};
+use abi::{SystemCall, ChannelMessage, CapError, IpcKind, MAP_COW, KERNEL_LOG_WRITE_LENGTH};
+
+/// Upgrade the untyped capability at `caddr` for a retype needing
+/// `length` bytes besides its descriptors. `None` if the capability
+/// lacks the write right, or the retype fits in neither its region nor
+/// its quota.
+fn lookup_retype_untyped(cpool: &CPoolCap, caddr: CAddr, length: usize) -> Option<UntypedCap> {
+    let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(caddr, RIGHT_WRITE);
+    match untyped_cap {
+        Some(untyped_cap) => {
+            let fits = untyped_cap.read().can_retype(length);
+            if fits { Some(untyped_cap) } else { None }
+        },
+        None => None,
+    }
+}
+
+/// Give the scheduling context `task` runs on back to its donor, if
+/// it was donated. A task waiting again is done with the message it
+/// was donated for.
+fn give_back_donation(task: &TaskCap) {
+    let sched_context = task.read().upgrade_sched_context();
+    if let Some(sched_context) = sched_context {
+        if sched_context.is_donated() {
+            sched_context.give_back();
+        }
+    }
+}
+
+/// Complete the receive `task` is blocked in on `endpoint`, once a
+/// sender is. Returns whether the task runs again with a message.
+pub fn endpoint_receive(task: &TaskCap, endpoint: &EndpointCap) -> bool {
+    let buffer_cap = task.read().upgrade_buffer();
+    let cpool = task.read().upgrade_cpool();
+    let (buffer_cap, cpool) = match (buffer_cap, cpool) {
+        (Some(buffer_cap), Some(cpool)) => (buffer_cap, cpool),
+        // As for a channel, the task could never take the message.
+        _ => {
+            task.write().set_status(TaskStatus::Inactive);
+            return false;
+        },
+    };
+    if endpoint.read().is_closed() {
+        task.write().set_status(TaskStatus::Inactive);
+        return false;
+    }
+    if !task.read().has_budget() {
+        return false;
+    }
+
+    let request = {
+        let buffer_desc = buffer_cap.read();
+        let buffer = buffer_desc.read();
+        match buffer.call.clone() {
+            Some(SystemCall::EndpointReceive { request, .. }) => request,
+            _ => panic!(),
+        }
+    };
+    // Signals of the bound notification come before any sender.
+    let signalled = task.read().upgrade_notification()
+        .map(|notification| notification.write().take()).unwrap_or(0);
+    let message = if signalled != 0 {
+        if ::ipc_trace::is_enabled() {
+            ::ipc_trace::record(IpcKind::Notification, None, Some(task), endpoint.ptr().into(): u64, signalled, 0, false);
+        }
+        Some(ChannelMessage::Notification(signalled))
+    } else {
+        let reply: Option<ReplyCap> = request.1.and_then(|caddr| cpool.lookup_upgrade(caddr, RIGHT_WRITE));
+        endpoint.receive(task, reply.as_ref())
+    };
+    match message {
+        Some(message) => {
+            {
+                let mut buffer_desc = buffer_cap.write();
+                let mut buffer = buffer_desc.write();
+                buffer.call = Some(SystemCall::EndpointReceive {
+                    request: request,
+                    response: Some(message),
+                });
+            }
+            task.write().set_status(TaskStatus::Active);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Block `task` until monotonic time `deadline`, with a timer to wake
+/// the CPU then, cancelled if the task wakes earlier. The timer counts
+/// against those userspace may arm. Returns `false`, leaving the task
+/// running, if none is left.
+fn block_until(task: &TaskCap, status: TaskStatus, deadline: u64) -> bool {
+    let wake_timer = match ::timer_wheel::add_user(deadline, 0, |_| (), 0) {
+        Some(wake_timer) => wake_timer,
+        None => return false,
+    };
+    give_back_donation(task);
+    task.write().set_timed_status(status, wake_timer);
+    true
+}
+
+/// Wake `task` once its sleep is over. Returns whether it runs again.
+pub fn sleep(task: &TaskCap, deadline: u64) -> bool {
+    if ::time::monotonic_ns() < deadline {
+        return false;
+    }
+    if let Some(buffer_cap) = task.read().upgrade_buffer() {
+        let mut buffer_desc = buffer_cap.write();
+        let mut buffer = buffer_desc.write();
+        buffer.call = match buffer.call.clone() {
+            Some(SystemCall::SleepUntil { request, .. }) => Some(SystemCall::SleepUntil {
+                request: request,
+                response: Some(true),
+            }),
+            Some(SystemCall::SleepFor { request, .. }) => Some(SystemCall::SleepFor {
+                request: request,
+                response: Some(true),
+            }),
+            call => call,
+        };
+    }
+    task.write().set_status(TaskStatus::Active);
+    true
+}
+
+/// Complete the wait of `task` on `notification`, once it is
+/// signalled, or `deadline` passed. Returns whether the task runs
+/// again with the word, zero if the deadline passed first.
+pub fn notification_wait(task: &TaskCap, notification: &NotificationCap, deadline: Option<u64>) -> bool {
+    let buffer_cap = match task.read().upgrade_buffer() {
+        Some(buffer_cap) => buffer_cap,
+        None => {
+            task.write().set_status(TaskStatus::Inactive);
+            return false;
+        },
+    };
+    if notification.read().is_closed() {
+        task.write().set_status(TaskStatus::Inactive);
+        return false;
+    }
+    if !task.read().has_budget() {
+        return false;
+    }
+
+    let word = notification.write().take();
+    let expired = deadline.map(|deadline| ::time::monotonic_ns() >= deadline).unwrap_or(false);
+    if word == 0 && !expired {
+        return false;
+    }
+    {
+        let mut buffer_desc = buffer_cap.write();
+        let mut buffer = buffer_desc.write();
+        buffer.call = match buffer.call.clone() {
+            Some(SystemCall::NotificationWait { request, .. }) => Some(SystemCall::NotificationWait {
+                request: request,
+                response: Some(word),
+            }),
+            Some(SystemCall::NotificationWaitUntil { request, .. }) => Some(SystemCall::NotificationWaitUntil {
+                request: request,
+                response: Some(word),
+            }),
+            _ => panic!(),
+        };
+    }
+    task.write().set_status(TaskStatus::Active);
+    true
+}
 
 /// System call handling function. Dispatch based on the type of the
 /// system call.
@@ -10,7 +179,7 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
         #[cfg(feature="kernel_debug")]
         SystemCall::DebugCPoolList => {
             for i in 0..(256 as usize) {
-                let arc = cpool.lookup_upgrade_any(CAddr::from(i as u8));
+                let arc = cpool.lookup_checked_any(CAddr::from(i as u8), RIGHTS_NONE).ok();
                 if arc.is_some() {
                     let arc = arc.unwrap();
                     if arc.is::<CPoolCap>() {
@@ -27,6 +196,50 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
                         log!("CPool index {} => {:?}", i, arc.into(): TopPageTableCap);
                     } else if arc.is::<ChannelCap>() {
                         log!("CPool index {} => {:?}", i, arc.into(): ChannelCap);
+                    } else if arc.is::<PmemCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): PmemCap);
+                    } else if arc.is::<DeviceUntypedCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): DeviceUntypedCap);
+                    } else if arc.is::<DmaCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): DmaCap);
+                    } else if arc.is::<LargePageCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): LargePageCap);
+                    } else if arc.is::<VSpaceCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): VSpaceCap);
+                    } else if arc.is::<SharedFrameSetCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): SharedFrameSetCap);
+                    } else if arc.is::<MsiCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): MsiCap);
+                    } else if arc.is::<IrqHandlerCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): IrqHandlerCap);
+                    } else if arc.is::<TimerCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): TimerCap);
+                    } else if arc.is::<QuotaCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): QuotaCap);
+                    } else if arc.is::<KernelLogCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): KernelLogCap);
+                    } else if arc.is::<IrqControlCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): IrqControlCap);
+                    } else if arc.is::<CpuControlCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): CpuControlCap);
+                    } else if arc.is::<BootImageCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): BootImageCap);
+                    } else if arc.is::<SchedContextCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): SchedContextCap);
+                    } else if arc.is::<EndpointCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): EndpointCap);
+                    } else if arc.is::<ReplyCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): ReplyCap);
+                    } else if arc.is::<NotificationCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): NotificationCap);
+                    } else if arc.is::<NotificationGroupCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): NotificationGroupCap);
+                    } else if arc.is::<IpcRingCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): IpcRingCap);
+                    } else if arc.is::<AsidControlCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): AsidControlCap);
+                    } else if arc.is::<AsidPoolCap>() {
+                        log!("CPool index {} => {:?}", i, arc.into(): AsidPoolCap);
                     } else {
                         log!("CPool index {} (arch specific) => {:?}", i, arc);
                         cap::drop_any(arc);
@@ -46,6 +259,15 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             unsafe { ::arch::outportb(0x501, 0x30); }
             loop {}
         }
+        #[cfg(feature="kernel_debug")]
+        SystemCall::DebugSwitchStats {
+            request, ..
+        } => {
+            Some(SystemCall::DebugSwitchStats {
+                request: request,
+                response: ::switch_stats::switch_stats(request),
+            })
+        }
 
         SystemCall::Print {
             request
@@ -61,7 +283,7 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
         SystemCall::RetypeRawPageFree {
             request, ..
         } => {
-            let source: Option<UntypedCap> = cpool.lookup_upgrade(request);
+            let source = lookup_retype_untyped(&cpool, request, PAGE_LENGTH);
             if source.is_some() {
                 let source = source.unwrap();
                 let target = RawPageCap::retype_from(source.write().deref_mut());
@@ -76,134 +298,2193 @@ pub fn handle(call: SystemCall, task_cap: TaskCap, cpool: CPoolCap) -> Option<Sy
             }
         },
         SystemCall::MapRawPageFree {
-            untyped, toplevel_table, request,
+            untyped, toplevel_table, request, flags,
         } => {
             let vaddr: VAddr = VAddr::from(request.0);
-            let page_cap: Option<RawPageCap> = cpool.lookup_upgrade(request.1);
-            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(untyped);
-            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table);
+            // A copy-on-write mapping is read-only until copied.
+            let map_rights = if flags & MAP_COW != 0 {
+                RIGHT_MAP | RIGHT_READ
+            } else {
+                RIGHT_MAP | RIGHT_READ | RIGHT_WRITE
+            };
+            let page_cap: Option<RawPageCap> = cpool.lookup_upgrade(request.1, map_rights);
+            let untyped_cap = lookup_retype_untyped(&cpool, untyped, 0);
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table, RIGHT_WRITE);
             if page_cap.is_some() && untyped_cap.is_some() && pml4_cap.is_some() {
                 let untyped_cap = untyped_cap.unwrap();
-                pml4_cap.unwrap().map(vaddr, &page_cap.unwrap(),
-                                      untyped_cap.write().deref_mut(),
-                                      cpool.write().deref_mut());
+                let mut pml4_cap = pml4_cap.unwrap();
+                if flags & MAP_COW != 0 {
+                    pml4_cap.read().downgrade_fault_pool(&untyped_cap, &cpool);
+                    pml4_cap.map_cow(vaddr, &page_cap.unwrap(),
+                                     untyped_cap.write().deref_mut(),
+                                     cpool.write().deref_mut());
+                } else {
+                    pml4_cap.map(vaddr, &page_cap.unwrap(),
+                                 untyped_cap.write().deref_mut(),
+                                 cpool.write().deref_mut());
+                }
                 log!("Map raw page okay.");
             } else {
                 log!("Map raw page failed.");
             }
             None
         }
-        SystemCall::RetypeCPool {
+        SystemCall::RetypeLargePage {
             request,
         } => {
-            let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
+            let source = lookup_retype_untyped(&cpool, request.0, LARGE_PAGE_SPLIT_COUNT * PAGE_LENGTH);
             if source.is_some() {
                 let source = source.unwrap();
-                let target = CPoolCap::retype_from(source.write().deref_mut());
+                let target = LargePageCap::retype_from(source.write().deref_mut());
                 let _ = cpool.lookup_downgrade_at(&target, request.1);
             }
 
             None
         },
-        SystemCall::RetypeTask {
-            request,
+        SystemCall::MapLargePage {
+            untyped, toplevel_table, request,
         } => {
-            let source: Option<UntypedCap> = cpool.lookup_upgrade(request.0);
-            if source.is_some() {
-                let source = source.unwrap();
-                let target = TaskCap::retype_from(source.write().deref_mut());
-                let _ = cpool.lookup_downgrade_at(&target, request.1);
+            let vaddr: VAddr = VAddr::from(request.0);
+            let page_cap: Option<LargePageCap> = cpool.lookup_upgrade(request.1, RIGHT_MAP | RIGHT_READ | RIGHT_WRITE);
+            let untyped_cap = lookup_retype_untyped(&cpool, untyped, 0);
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table, RIGHT_WRITE);
+            if page_cap.is_some() && untyped_cap.is_some() && pml4_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                pml4_cap.unwrap().map_large(vaddr, &page_cap.unwrap(),
+                                            untyped_cap.write().deref_mut(),
+                                            cpool.write().deref_mut());
+                log!("Map large page okay.");
+            } else {
+                log!("Map large page failed.");
             }
-
             None
         },
-        SystemCall::TaskSetInstructionPointer {
-            request,
+        SystemCall::LargePageSplit {
+            request, ..
         } => {
-            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0);
-            if target.is_some() {
-                let target = target.unwrap();
-                target.write().set_instruction_pointer(VAddr::from(request.1));
-            }
+            // Capability pools hold at most 256 entries, so the split
+            // pages go into two pools, each large enough for its
+            // half: the lower and the upper half.
+            let half = LARGE_PAGE_SPLIT_COUNT / 2;
+            let page_cap: Option<LargePageCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let page_rights = cpool.lookup_rights(request.0).unwrap_or(RIGHTS_NONE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.1, LargePageCap::split_length());
+            let low_cap: Option<CPoolCap> = cpool.lookup_upgrade(request.2, RIGHT_WRITE);
+            let high_cap: Option<CPoolCap> = cpool.lookup_upgrade(request.3, RIGHT_WRITE);
+
+            let result = if page_cap.is_some() && untyped_cap.is_some() &&
+                low_cap.is_some() && high_cap.is_some()
+            {
+                let page_cap = page_cap.unwrap();
+                let untyped_cap = untyped_cap.unwrap();
+                let low_cap = low_cap.unwrap();
+                let high_cap = high_cap.unwrap();
+                let low = low_cap.read();
+                let high = high_cap.read();
 
+                // Both halves would land in the same slots of a single
+                // pool.
+                if !low_cap.ptr_eq(&high_cap) && low.size() >= half && high.size() >= half &&
+                    (0..half).all(|i| low.is_free(i) && high.is_free(i))
+                {
+                    page_cap.split(&untyped_cap, |i, page| {
+                        if i < half {
+                            low.downgrade_at(page, i);
+                            low.set_rights(i, page_rights);
+                        } else {
+                            high.downgrade_at(page, i - half);
+                            high.set_rights(i - half, page_rights);
+                        }
+                    })
+                } else {
+                    false
+                }
+            } else {
+                false
+            };
+
+            Some(SystemCall::LargePageSplit {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::LargePageMerge {
+            request, ..
+        } => {
+            let page_cap: Option<LargePageCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = page_cap.map(|page_cap| page_cap.merge()).unwrap_or(false);
+
+            Some(SystemCall::LargePageMerge {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::MapDemandZero {
+            untyped, toplevel_table, request,
+        } => {
+            let vaddr: VAddr = VAddr::from(request.0);
+            let untyped_cap = lookup_retype_untyped(&cpool, untyped, 0);
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(toplevel_table, RIGHT_WRITE);
+            if untyped_cap.is_some() && pml4_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                let mut pml4_cap = pml4_cap.unwrap();
+                pml4_cap.read().downgrade_fault_pool(&untyped_cap, &cpool);
+                for i in 0..request.1 {
+                    // Each page may need new page tables.
+                    if !untyped_cap.read().can_retype(0) {
+                        break;
+                    }
+                    pml4_cap.reserve_zero(vaddr + i * PAGE_LENGTH,
+                                          untyped_cap.write().deref_mut(),
+                                          cpool.write().deref_mut());
+                }
+                log!("Map demand-zero range okay.");
+            } else {
+                log!("Map demand-zero range failed.");
+            }
             None
         },
-        SystemCall::TaskSetStackPointer {
+        SystemCall::PmemPage {
+            request, ..
+        } => {
+            let pmem_cap: Option<PmemCap> = cpool.lookup_upgrade(request.0, RIGHT_MAP);
+            // Pages keep the rights of the region they are taken from.
+            let rights = cpool.lookup_rights(request.0).unwrap_or(RIGHTS_NONE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.2, 0);
+            let result = if pmem_cap.is_some() && untyped_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                let page = pmem_cap.unwrap().read().page(request.1, untyped_cap.write().deref_mut());
+                page.and_then(|page| cpool.read().downgrade_free(&page)).map(|index| {
+                    cpool.read().set_rights(index, rights);
+                    index
+                })
+            } else {
+                None
+            };
+
+            Some(SystemCall::PmemPage {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
+        },
+        SystemCall::DeviceUntypedRetype {
+            request, ..
+        } => {
+            let device_cap: Option<DeviceUntypedCap> = cpool.lookup_upgrade(request.0, RIGHT_MAP);
+            // Frames keep the rights of the region they are retyped from.
+            let rights = cpool.lookup_rights(request.0).unwrap_or(RIGHTS_NONE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.2, 0);
+            let result = if device_cap.is_some() && untyped_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                let page = device_cap.unwrap().read().retype_frame(request.1, untyped_cap.write().deref_mut());
+                page.and_then(|page| cpool.read().downgrade_free(&page)).map(|index| {
+                    cpool.read().set_rights(index, rights);
+                    index
+                })
+            } else {
+                None
+            };
+
+            Some(SystemCall::DeviceUntypedRetype {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
+        },
+        SystemCall::DeviceUntypedInfo {
+            request, ..
+        } => {
+            let device_cap: Option<DeviceUntypedCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = device_cap.map(|device_cap| {
+                let device = device_cap.read();
+                (device.start_paddr().into(): u64, device.length())
+            });
+
+            Some(SystemCall::DeviceUntypedInfo {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::PmemFlush {
+            request, ..
+        } => {
+            let pmem_cap: Option<PmemCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = pmem_cap.map(|pmem_cap| {
+                pmem_cap.read().flush(request.1, request.2)
+            }).unwrap_or(false);
+
+            Some(SystemCall::PmemFlush {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::PmemFence {
             request,
         } => {
-            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0);
-            if target.is_some() {
-                let target = target.unwrap();
-                target.write().set_stack_pointer(VAddr::from(request.1));
+            let pmem_cap: Option<PmemCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            if let Some(pmem_cap) = pmem_cap {
+                pmem_cap.read().fence();
             }
 
             None
         },
-        SystemCall::TaskSetCPool {
-            request,
+        SystemCall::RetypeDma {
+            request, ..
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_cpool: CPoolCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_cpool(&target_cpool);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.0, 0);
+            let dma_cap = if let Some(untyped_cap) = untyped_cap {
+                let mut untyped = untyped_cap.write();
+                let dma_cap = DmaCap::retype_from(untyped.deref_mut(), request.1, request.2);
+                dma_cap
+            } else {
+                None
+            };
+            let result = dma_cap.and_then(|dma_cap| {
+                let paddr = dma_cap.read().start_paddr();
+                cpool.read().downgrade_free(&dma_cap)
+                    .map(|x| (CAddr::from(x as u8), paddr.into(): u64))
+            });
 
-            None
+            Some(SystemCall::RetypeDma {
+                request: request,
+                response: result,
+            })
         },
-        SystemCall::TaskSetTopPageTable {
-            request,
+        SystemCall::DmaPage {
+            request, ..
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_table: TopPageTableCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_top_page_table(&target_table);
+            let dma_cap: Option<DmaCap> = cpool.lookup_upgrade(request.0, RIGHT_MAP);
+            // Pages keep the rights of the region they are taken from.
+            let rights = cpool.lookup_rights(request.0).unwrap_or(RIGHTS_NONE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.2, 0);
+            let result = if dma_cap.is_some() && untyped_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                let page = dma_cap.unwrap().read().page(request.1, untyped_cap.write().deref_mut());
+                page.and_then(|page| cpool.read().downgrade_free(&page)).map(|index| {
+                    cpool.read().set_rights(index, rights);
+                    index
+                })
+            } else {
+                None
+            };
 
-            None
+            Some(SystemCall::DmaPage {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
         },
-        SystemCall::TaskSetBuffer {
-            request,
+        SystemCall::AsidControlMakePool {
+            request, ..
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request.0).unwrap();
-            let target_buffer: TaskBufferPageCap = cpool.lookup_upgrade(request.1).unwrap();
-            target_task.read().downgrade_buffer(&target_buffer);
+            let control_cap: Option<AsidControlCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.1, 0);
+            let result = match (control_cap, untyped_cap) {
+                (Some(control_cap), Some(untyped_cap)) => {
+                    control_cap.read().make_pool(untyped_cap.write().deref_mut()).and_then(|pool_cap| {
+                        cpool.read().downgrade_free(&pool_cap)
+                    })
+                },
+                _ => None,
+            };
 
-            None
+            Some(SystemCall::AsidControlMakePool {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
         },
-        SystemCall::TaskSetActive {
-            request,
+        SystemCall::RetypeVSpace {
+            request, ..
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request).unwrap();
-            target_task.write().set_status(TaskStatus::Active);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.0, 0);
+            let pool_cap: Option<AsidPoolCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (untyped_cap, pool_cap) {
+                (Some(untyped_cap), Some(pool_cap)) => {
+                    // The pool is locked and checked first, so that no
+                    // address space is created that could not be
+                    // assigned an ASID.
+                    let pool = pool_cap.write();
+                    if pool.has_free() {
+                        let vspace_cap = VSpaceCap::retype_from(&untyped_cap);
+                        assert!(pool.assign(&vspace_cap));
+                        cpool.read().downgrade_free(&vspace_cap)
+                    } else {
+                        None
+                    }
+                },
+                _ => None,
+            };
 
-            None
+            Some(SystemCall::RetypeVSpace {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
         },
-        SystemCall::TaskSetInactive {
-            request,
+        SystemCall::VSpaceMap {
+            request, ..
         } => {
-            let target_task: TaskCap = cpool.lookup_upgrade(request).unwrap();
-            target_task.write().set_status(TaskStatus::Inactive);
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let rights = RIGHT_MAP | RIGHT_READ | RIGHT_WRITE;
+            // Either page type is accepted, so only a task buffer
+            // lookup reports a type mismatch.
+            let page_cap: Result<RawPageCap, CapError> = cpool.lookup_checked(request.2, rights);
+            let buffer_cap: Option<TaskBufferPageCap> = match page_cap {
+                Err(CapError::TypeMismatch) => cpool.lookup_upgrade(request.2, rights),
+                Err(error) => {
+                    cap::record_call_error(error);
+                    None
+                },
+                Ok(_) => None,
+            };
+            let vaddr = VAddr::from(request.1);
+            let result = vspace_cap.and_then(|vspace_cap| {
+                let mut vspace = vspace_cap.write();
+                let mapped = match (page_cap.ok(), buffer_cap) {
+                    (Some(page_cap), _) => Some(vspace.map(vaddr, &page_cap)),
+                    (None, Some(buffer_cap)) => Some(vspace.map(vaddr, &buffer_cap)),
+                    (None, None) => None,
+                };
+                mapped
+            });
 
-            None
+            Some(SystemCall::VSpaceMap {
+                request: request,
+                response: result,
+            })
         },
-        SystemCall::ChannelTake {
+        SystemCall::VSpaceUnmap {
             request, ..
         } => {
-            let mut chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request);
-            if let Some(chan) = chan_option {
-                task_cap.write().set_status(TaskStatus::ChannelWait(chan))
-            }
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = vspace_cap.map(|vspace_cap| {
+                let unmapped = vspace_cap.write().unmap(VAddr::from(request.1));
+                unmapped
+            });
 
-            None
+            Some(SystemCall::VSpaceUnmap {
+                request: request,
+                response: result,
+            })
         },
-        SystemCall::ChannelPut {
-            request,
+        SystemCall::VSpaceDestroy {
+            request, ..
         } => {
-            let chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request.0);
-            if let Some(chan) = chan_option {
-                let value = ChannelValue::from_message(request.1.clone(), task_cap.clone());
-                if value.is_some() {
-                    chan.write().put(value.unwrap());
-                }
-            }
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = vspace_cap.map(|vspace_cap| {
+                let freed = vspace_cap.write().destroy();
+                freed
+            });
 
-            None
+            Some(SystemCall::VSpaceDestroy {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::BootImageLoad {
+            request, ..
+        } => {
+            let image_cap: Option<BootImageCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (image_cap, vspace_cap) {
+                (Some(image_cap), Some(vspace_cap)) => {
+                    let entry = image_cap.read().load(&vspace_cap);
+                    entry.map(|entry| entry.into(): u64)
+                },
+                _ => None,
+            };
+
+            Some(SystemCall::BootImageLoad {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::VSpaceRemap {
+            request, ..
+        } => {
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = vspace_cap.map(|vspace_cap| {
+                let remapped = vspace_cap.write().remap(VAddr::from(request.1), request.2,
+                                                        VAddr::from(request.3), request.4);
+                remapped
+            });
+
+            Some(SystemCall::VSpaceRemap {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::VSpaceHarvest {
+            request, ..
+        } => {
+            let harvest_rights = if request.3 { RIGHT_READ | RIGHT_WRITE } else { RIGHT_READ };
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.0, harvest_rights);
+            let result = vspace_cap.and_then(|vspace_cap| {
+                let bits = vspace_cap.write().harvest(VAddr::from(request.1), request.2, request.3);
+                bits
+            });
+
+            Some(SystemCall::VSpaceHarvest {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::VSpaceTrackWrites {
+            request, ..
+        } => {
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let ring_cap: Option<RawPageCap> = cpool.lookup_upgrade(request.1, RIGHT_READ | RIGHT_WRITE);
+            let result = if vspace_cap.is_some() && ring_cap.is_some() {
+                let count = vspace_cap.unwrap().write().track_writes(&ring_cap.unwrap());
+                Some(count)
+            } else {
+                None
+            };
+
+            Some(SystemCall::VSpaceTrackWrites {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::RetypeSharedFrameSet {
+            request, ..
+        } => {
+            let frames_length = request.1.checked_mul(PAGE_LENGTH).unwrap_or(::core::usize::MAX);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.0, frames_length);
+            let result = untyped_cap.and_then(|untyped_cap| {
+                SharedFrameSetCap::retype_from(&untyped_cap, request.1)
+            }).and_then(|set_cap| cpool.read().downgrade_free(&set_cap));
+
+            Some(SystemCall::RetypeSharedFrameSet {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
+        },
+        SystemCall::SharedFrameSetMap {
+            request, ..
+        } => {
+            let map_rights = if request.3 {
+                RIGHT_MAP | RIGHT_READ | RIGHT_WRITE
+            } else {
+                RIGHT_MAP | RIGHT_READ
+            };
+            let set_cap: Option<SharedFrameSetCap> = cpool.lookup_upgrade(request.0, map_rights);
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = if set_cap.is_some() && vspace_cap.is_some() {
+                let mapped = set_cap.unwrap().write().map(&vspace_cap.unwrap(),
+                                                          VAddr::from(request.2), request.3);
+                Some(mapped)
+            } else {
+                None
+            };
+
+            Some(SystemCall::SharedFrameSetMap {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::SharedFrameSetUnmap {
+            request, ..
+        } => {
+            let set_cap: Option<SharedFrameSetCap> = cpool.lookup_upgrade(request.0, RIGHT_MAP);
+            let vspace_cap: Option<VSpaceCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = if set_cap.is_some() && vspace_cap.is_some() {
+                let unmapped = set_cap.unwrap().write().unmap(&vspace_cap.unwrap(),
+                                                              VAddr::from(request.2));
+                Some(unmapped)
+            } else {
+                None
+            };
+
+            Some(SystemCall::SharedFrameSetUnmap {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::SharedFrameSetRelease {
+            request, ..
+        } => {
+            let set_cap: Option<SharedFrameSetCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = set_cap.map(|set_cap| {
+                let released = set_cap.write().release();
+                released
+            });
+
+            Some(SystemCall::SharedFrameSetRelease {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::RetypeIpcRing {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request.0, 0);
+            let set_cap: Option<SharedFrameSetCap> = cpool.lookup_upgrade(request.1, RIGHT_READ | RIGHT_WRITE);
+            let result = match (untyped_cap, set_cap) {
+                (Some(untyped_cap), Some(set_cap)) => {
+                    let mut untyped = untyped_cap.write();
+                    IpcRingCap::retype_from(untyped.deref_mut(), &set_cap)
+                },
+                _ => None,
+            }.and_then(|ring_cap| {
+                cpool.read().downgrade_free(&ring_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeIpcRing {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IpcRingBind {
+            request, ..
+        } => {
+            let ring_cap: Option<IpcRingCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = match (ring_cap, request.1) {
+                (Some(ring_cap), Some(notification)) => {
+                    let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(notification, RIGHT_WRITE);
+                    notification_cap.map(|notification_cap| {
+                        ring_cap.write().bind(Some(&notification_cap), request.2);
+                        true
+                    })
+                },
+                (Some(ring_cap), None) => {
+                    ring_cap.write().bind(None, request.2);
+                    Some(true)
+                },
+                (None, _) => None,
+            };
+
+            Some(SystemCall::IpcRingBind {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IpcRingNotify {
+            request, ..
+        } => {
+            let ring_cap: Option<IpcRingCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = ring_cap.map(|ring_cap| ring_cap.read().notify());
+
+            Some(SystemCall::IpcRingNotify {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IrqControlGetMsi {
+            request, ..
+        } => {
+            let control_cap: Option<IrqControlCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.1, 0);
+            let msi_cap = match (control_cap, untyped_cap) {
+                (Some(control_cap), Some(untyped_cap)) => {
+                    let msi_cap = control_cap.read().get_msi(request.2, untyped_cap.write().deref_mut());
+                    msi_cap
+                },
+                _ => None,
+            };
+            let result = msi_cap.and_then(|msi_cap| {
+                let message = msi_cap.read().message();
+                cpool.read().downgrade_free(&msi_cap)
+                    .map(|x| (CAddr::from(x as u8), message.address, message.data))
+            });
+
+            Some(SystemCall::IrqControlGetMsi {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::MsiMessage {
+            request, ..
+        } => {
+            let msi_cap: Option<MsiCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = msi_cap.map(|msi_cap| {
+                let message = msi_cap.read().message();
+                (message.address, message.data)
+            });
+
+            Some(SystemCall::MsiMessage {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::MsiSetAffinity {
+            request, ..
+        } => {
+            let msi_cap: Option<MsiCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = msi_cap.and_then(|msi_cap| {
+                msi_cap.write().set_affinity(request.1)
+                    .map(|message| (message.address, message.data))
+            });
+
+            Some(SystemCall::MsiSetAffinity {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IrqControlGetHandler {
+            request, ..
+        } => {
+            let (control, untyped, gsi, cpu, target) = request;
+            let control_cap: Option<IrqControlCap> = cpool.lookup_upgrade(control, RIGHT_WRITE);
+            let untyped_cap = lookup_retype_untyped(&cpool, untyped, 0);
+            // The entry is checked first, so that the input is never
+            // routed for a handler with nowhere to go.
+            let result = match (control_cap, untyped_cap) {
+                (Some(control_cap), Some(untyped_cap)) if cpool.lookup_is_free(target) => {
+                    let irq_cap = control_cap.read().get_handler(gsi, cpu, untyped_cap.write().deref_mut());
+                    match irq_cap {
+                        Some(irq_cap) => {
+                            cpool.lookup_downgrade_at(&irq_cap, target);
+                            true
+                        },
+                        None => false,
+                    }
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::IrqControlGetHandler {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CpuControlOffline {
+            request, ..
+        } => {
+            let control_cap: Option<CpuControlCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = control_cap.map(|control_cap| control_cap.read().offline(request.1)).unwrap_or(false);
+
+            Some(SystemCall::CpuControlOffline {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CpuControlOnline {
+            request, ..
+        } => {
+            let control_cap: Option<CpuControlCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = control_cap.map(|control_cap| control_cap.read().online(request.1)).unwrap_or(false);
+
+            Some(SystemCall::CpuControlOnline {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IrqHandlerBind {
+            request, ..
+        } => {
+            let irq_cap: Option<IrqHandlerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let chan_cap: Option<ChannelCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (irq_cap, chan_cap) {
+                (Some(irq_cap), Some(chan_cap)) => {
+                    irq_cap.write().bind(&chan_cap);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::IrqHandlerBind {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IrqHandlerBindNotification {
+            request, ..
+        } => {
+            let irq_cap: Option<IrqHandlerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (irq_cap, notification_cap) {
+                (Some(irq_cap), Some(notification_cap)) => {
+                    irq_cap.write().bind_notification(&notification_cap, request.2);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::IrqHandlerBindNotification {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IrqHandlerAck {
+            request, ..
+        } => {
+            let irq_cap: Option<IrqHandlerCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = irq_cap.map(|irq_cap| irq_cap.write().acknowledge()).unwrap_or(false);
+
+            Some(SystemCall::IrqHandlerAck {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IrqHandlerSetAffinity {
+            request, ..
+        } => {
+            let irq_cap: Option<IrqHandlerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = irq_cap.map(|irq_cap| irq_cap.write().set_affinity(request.1)).unwrap_or(false);
+
+            Some(SystemCall::IrqHandlerSetAffinity {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::RetypeTimer {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let timer_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                TimerCap::retype_from(untyped.deref_mut())
+            });
+            let result = timer_cap.and_then(|timer_cap| {
+                cpool.read().downgrade_free(&timer_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeTimer {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TimerBind {
+            request, ..
+        } => {
+            let timer_cap: Option<TimerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let chan_cap: Option<ChannelCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (timer_cap, chan_cap) {
+                (Some(timer_cap), Some(chan_cap)) => {
+                    timer_cap.write().bind(&chan_cap);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::TimerBind {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TimerBindNotification {
+            request, ..
+        } => {
+            let timer_cap: Option<TimerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (timer_cap, notification_cap) {
+                (Some(timer_cap), Some(notification_cap)) => {
+                    timer_cap.write().bind_notification(&notification_cap, request.2);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::TimerBindNotification {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TimerArm {
+            request, ..
+        } => {
+            let timer_cap: Option<TimerCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = timer_cap.map(|timer_cap| {
+                timer_cap.write().arm(request.1, request.2)
+            }).unwrap_or(false);
+
+            Some(SystemCall::TimerArm {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TimerCancel {
+            request, ..
+        } => {
+            let timer_cap: Option<TimerCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = timer_cap.map(|timer_cap| timer_cap.write().cancel()).unwrap_or(false);
+
+            Some(SystemCall::TimerCancel {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::RetypeSchedContext {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let sched_context_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                SchedContextCap::retype_from(untyped.deref_mut())
+            });
+            let result = sched_context_cap.and_then(|sched_context_cap| {
+                cpool.read().downgrade_free(&sched_context_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeSchedContext {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::SchedContextConfigure {
+            request, ..
+        } => {
+            let sched_context_cap: Option<SchedContextCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = sched_context_cap.map(|sched_context_cap| {
+                let configured = sched_context_cap.write().configure(request.1, request.2);
+                // With a full budget, its task may run again.
+                if let Some(task) = sched_context_cap.task() {
+                    cap::wake_task(&task);
+                }
+                configured
+            }).unwrap_or(false);
+
+            Some(SystemCall::SchedContextConfigure {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::SchedContextBind {
+            request, ..
+        } => {
+            let sched_context_cap: Option<SchedContextCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (sched_context_cap, target_task) {
+                (Some(sched_context_cap), Some(target_task)) => sched_context_cap.bind(&target_task),
+                _ => false,
+            };
+
+            Some(SystemCall::SchedContextBind {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::SchedContextUnbind {
+            request, ..
+        } => {
+            let sched_context_cap: Option<SchedContextCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = sched_context_cap.map(|sched_context_cap| {
+                sched_context_cap.unbind();
+                true
+            }).unwrap_or(false);
+
+            Some(SystemCall::SchedContextUnbind {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::RetypeLdt {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let ldt_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                LdtCap::retype_from(untyped.deref_mut())
+            });
+            let result = ldt_cap.and_then(|ldt_cap| {
+                cpool.read().downgrade_free(&ldt_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeLdt {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::LdtSetEntry {
+            request, ..
+        } => {
+            let ldt_cap: Option<LdtCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = ldt_cap.map(|ldt_cap| ldt_cap.write().set_entry(request.1, request.2)).unwrap_or(false);
+
+            Some(SystemCall::LdtSetEntry {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IoPortIssue {
+            request, ..
+        } => {
+            let ioport_cap: Option<IoPortCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            // Narrower ranges keep the rights of the range they are
+            // issued from.
+            let rights = cpool.lookup_rights(request.0).unwrap_or(RIGHTS_NONE);
+            let untyped_cap = lookup_retype_untyped(&cpool, request.1, 0);
+            let result = if ioport_cap.is_some() && untyped_cap.is_some() {
+                let untyped_cap = untyped_cap.unwrap();
+                let ioport = ioport_cap.unwrap().read().issue(request.2, request.3,
+                                                              untyped_cap.write().deref_mut());
+                ioport.and_then(|ioport| cpool.read().downgrade_free(&ioport)).map(|index| {
+                    cpool.read().set_rights(index, rights);
+                    index
+                })
+            } else {
+                None
+            };
+
+            Some(SystemCall::IoPortIssue {
+                request: request,
+                response: result.map(|x| CAddr::from(x as u8)),
+            })
+        },
+        SystemCall::IoPortIn {
+            request, ..
+        } => {
+            let ioport_cap: Option<IoPortCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            let result = ioport_cap.and_then(|ioport_cap| ioport_cap.read().read(request.1, request.2));
+
+            Some(SystemCall::IoPortIn {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IoPortOut {
+            request, ..
+        } => {
+            let ioport_cap: Option<IoPortCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = ioport_cap.map(|ioport_cap| {
+                ioport_cap.read().write(request.1, request.2, request.3)
+            }).unwrap_or(false);
+
+            Some(SystemCall::IoPortOut {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::KernelLogRead {
+            request, ..
+        } => {
+            let log_cap: Option<KernelLogCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            let page_cap: Option<RawPageCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (log_cap, page_cap) {
+                (Some(log_cap), Some(page_cap)) => {
+                    let result = log_cap.read().read(request.2, &page_cap);
+                    result
+                },
+                _ => None,
+            };
+
+            Some(SystemCall::KernelLogRead {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::KernelLogWrite {
+            request, ..
+        } => {
+            let log_cap: Option<KernelLogCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = log_cap.map(|log_cap| {
+                request.2 <= KERNEL_LOG_WRITE_LENGTH && log_cap.read().write(&request.1[..request.2])
+            }).unwrap_or(false);
+
+            Some(SystemCall::KernelLogWrite {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::IpcTraceSet {
+            request, ..
+        } => {
+            // Tracing shows messages between any tasks, so only
+            // holders of the kernel log may turn it on.
+            let log_cap: Option<KernelLogCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = log_cap.map(|_| ::ipc_trace::set_enabled(request.1));
+
+            Some(SystemCall::IpcTraceSet {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::IpcTraceRead {
+            request, ..
+        } => {
+            let log_cap: Option<KernelLogCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+
+            Some(SystemCall::IpcTraceRead {
+                request: request,
+                response: log_cap.map(|_| ::ipc_trace::read(request.1)),
+            })
+        },
+        SystemCall::CacheMaintenance {
+            request, ..
+        } => {
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = pml4_cap.map(|pml4_cap| {
+                pml4_cap.read().cache_maintenance(VAddr::from(request.1), request.2, request.3)
+            }).unwrap_or(false);
+
+            Some(SystemCall::CacheMaintenance {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::PageCacheMaintenance {
+            request, ..
+        } => {
+            let (page, offset, length, operation) = request;
+            // Either page type is accepted, so only a large page lookup
+            // reports a type mismatch.
+            let raw_cap: Result<RawPageCap, CapError> = cpool.lookup_checked(page, RIGHT_WRITE);
+            let large_cap: Option<LargePageCap> = match raw_cap {
+                Err(CapError::TypeMismatch) => cpool.lookup_upgrade(page, RIGHT_WRITE),
+                Err(error) => {
+                    cap::record_call_error(error);
+                    None
+                },
+                Ok(_) => None,
+            };
+            let raw_cap = raw_cap.ok();
+            let result = raw_cap.map(|raw_cap| {
+                raw_cap.read().cache_maintenance(offset, length, operation)
+            }).or_else(|| large_cap.map(|large_cap| {
+                large_cap.read().cache_maintenance(offset, length, operation)
+            })).unwrap_or(false);
+
+            Some(SystemCall::PageCacheMaintenance {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::PageSetCacheMode {
+            request, ..
+        } => {
+            let page_cap: Option<RawPageCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = page_cap.map(|page_cap| page_cap.set_cache_mode(request.1)).unwrap_or(false);
+
+            Some(SystemCall::PageSetCacheMode {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::VirtToPhys {
+            request, ..
+        } => {
+            let pml4_cap: Option<TopPageTableCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            let result = pml4_cap.and_then(|pml4_cap| {
+                pml4_cap.read().lookup(VAddr::from(request.1))
+            }).map(|(paddr, attributes)| (paddr.into(): usize, attributes));
+
+            Some(SystemCall::VirtToPhys {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::RetypeCPool {
+            request,
+        } => {
+            let source = lookup_retype_untyped(&cpool, request.0, 0);
+            if source.is_some() {
+                let source = source.unwrap();
+                let target = CPoolCap::retype_from(source.write().deref_mut());
+                let _ = cpool.lookup_downgrade_at(&target, request.1);
+            }
+
+            None
+        },
+        SystemCall::CPoolMint {
+            request, ..
+        } => {
+            let (source, target, size, guard) = request;
+            let untyped_cap = lookup_retype_untyped(&cpool, source, 0);
+            // A guard fills at most all but the last byte of an address.
+            let valid = size > 0 && size <= CPOOL_MAX_SIZE && guard.1 < 8 && cpool.lookup_is_free(target);
+            let result = match untyped_cap {
+                Some(untyped_cap) if valid => {
+                    let child = CPoolCap::retype_guarded_from(untyped_cap.write().deref_mut(), size, guard);
+                    cpool.lookup_downgrade_at(&child, target);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::CPoolMint {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CPoolCopyWithRights {
+            request, ..
+        } => {
+            let (source, target, rights) = request;
+            let result = match cpool.lookup_upgrade_any(source, RIGHT_GRANT) {
+                Some(source_cap) => {
+                    let source_once = cpool.lookup_rights(source).map_or(false, |rights| rights.contains(RIGHT_SEND_ONCE));
+                    if cpool.lookup_is_free(target) && !source_once {
+                        // Copies only ever keep rights the source already has.
+                        let source_rights = cpool.lookup_rights(source).unwrap_or(RIGHTS_NONE);
+                        cpool.lookup_downgrade_any_at(source_cap, target, source_rights.intersect(rights));
+                        true
+                    } else {
+                        cap::drop_any(source_cap);
+                        false
+                    }
+                },
+                None => false,
+            };
+
+            Some(SystemCall::CPoolCopyWithRights {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CPoolMove {
+            request, ..
+        } => {
+            // Entries are resolved first, so that moving a pool into
+            // itself never loses the path to the target. The capability
+            // keeps the rights it has through its path.
+            let (source, target) = request;
+            let rights = cpool.lookup_rights(source).unwrap_or(RIGHTS_NONE);
+            let result = match (cpool.lookup_entry(source), cpool.lookup_entry(target)) {
+                (Some((source_pool, source_index)), Some((target_pool, target_index))) => {
+                    let source_desc = source_pool.read();
+                    let target_desc = target_pool.read();
+                    match source_desc.upgrade_any(source_index) {
+                        Some(source_cap) => {
+                            if target_desc.is_free(target_index) {
+                                // The capability is held while it is
+                                // moved, so its object is never found
+                                // unreferenced.
+                                source_desc.remove(source_index);
+                                target_desc.downgrade_any_at(source_cap, target_index);
+                                target_desc.set_rights(target_index, rights);
+                                true
+                            } else {
+                                cap::drop_any(source_cap);
+                                false
+                            }
+                        },
+                        None => false,
+                    }
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::CPoolMove {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CPoolSwap {
+            request, ..
+        } => {
+            let (first, second) = request;
+            let first_rights = cpool.lookup_rights(first).unwrap_or(RIGHTS_NONE);
+            let second_rights = cpool.lookup_rights(second).unwrap_or(RIGHTS_NONE);
+            let result = match (cpool.lookup_entry(first), cpool.lookup_entry(second)) {
+                (Some((first_pool, first_index)), Some((second_pool, second_index))) => {
+                    let same = first_pool.ptr_eq(&second_pool) && first_index == second_index;
+                    let first_desc = first_pool.read();
+                    let second_desc = second_pool.read();
+                    match (first_desc.upgrade_any(first_index), second_desc.upgrade_any(second_index)) {
+                        (Some(first_cap), Some(second_cap)) => {
+                            if same {
+                                cap::drop_any(first_cap);
+                                cap::drop_any(second_cap);
+                            } else {
+                                first_desc.remove(first_index);
+                                second_desc.remove(second_index);
+                                first_desc.downgrade_any_at(second_cap, first_index);
+                                first_desc.set_rights(first_index, second_rights);
+                                second_desc.downgrade_any_at(first_cap, second_index);
+                                second_desc.set_rights(second_index, first_rights);
+                            }
+                            true
+                        },
+                        (Some(cap), None) | (None, Some(cap)) => {
+                            cap::drop_any(cap);
+                            false
+                        },
+                        (None, None) => false,
+                    }
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::CPoolSwap {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CPoolDelete {
+            request, ..
+        } => {
+            let result = match cpool.lookup_entry(request) {
+                Some((target_pool, index)) => {
+                    let target_cap = target_pool.read().upgrade_any(index);
+                    match target_cap {
+                        Some(target_cap) => {
+                            target_pool.read().remove(index);
+                            cap::delete_any(target_cap);
+                            true
+                        },
+                        None => false,
+                    }
+                },
+                None => false,
+            };
+
+            Some(SystemCall::CPoolDelete {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::CPoolRevoke {
+            request, ..
+        } => {
+            // Untyped memory is revoked with everything retyped from
+            // it, through `UntypedRevoke`.
+            let target_cap = cpool.lookup_upgrade_any(request, RIGHT_WRITE);
+            let result = match target_cap {
+                Some(target_cap) => {
+                    if target_cap.is::<UntypedCap>() {
+                        cap::drop_any(target_cap);
+                        false
+                    } else {
+                        cap::revoke_any(target_cap);
+                        true
+                    }
+                },
+                None => false,
+            };
+
+            Some(SystemCall::CPoolRevoke {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::UntypedRetype {
+            request, ..
+        } => {
+            let (source, object, size_bits, target, index, count) = request;
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(source, RIGHT_WRITE);
+            let target_cap: Option<CPoolCap> = cpool.lookup_upgrade(target, RIGHT_WRITE);
+            let result = match (untyped_cap, target_cap) {
+                (Some(untyped_cap), Some(target_cap)) => {
+                    let target = target_cap.read();
+                    let valid = index.checked_add(count).map(|end| end <= target.size()).unwrap_or(false) &&
+                        (index..(index + count)).all(|i| target.is_free(i));
+                    let mut created = 0;
+                    while valid && created < count {
+                        match cap::retype_any(&untyped_cap, object, size_bits) {
+                            Some(arc) => target.downgrade_any_at(arc, index + created),
+                            None => break,
+                        }
+                        created += 1;
+                    }
+                    created
+                },
+                _ => 0,
+            };
+
+            Some(SystemCall::UntypedRetype {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::UntypedRevoke {
+            request, ..
+        } => {
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            let result = untyped_cap.map(|untyped_cap| untyped_cap.revoke()).unwrap_or(false);
+
+            Some(SystemCall::UntypedRevoke {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::RetypeQuota {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request.0, 0);
+            let parent_cap: Option<Option<QuotaCap>> = match request.1 {
+                Some(parent) => cpool.lookup_upgrade(parent, RIGHT_WRITE).map(|parent| Some(parent)),
+                None => Some(None),
+            };
+            let quota_cap = match (untyped_cap, parent_cap) {
+                (Some(untyped_cap), Some(parent_cap)) => {
+                    let mut untyped = untyped_cap.write();
+                    Some(QuotaCap::retype_from(untyped.deref_mut(), request.2, parent_cap.as_ref()))
+                },
+                _ => None,
+            };
+            let result = quota_cap.and_then(|quota_cap| {
+                cpool.read().downgrade_free(&quota_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeQuota {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::UntypedSetQuota {
+            request, ..
+        } => {
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let quota_cap: Option<QuotaCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (untyped_cap, quota_cap) {
+                (Some(untyped_cap), Some(quota_cap)) => {
+                    let result = untyped_cap.write().set_quota(&quota_cap);
+                    result
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::UntypedSetQuota {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::QuotaInfo {
+            request, ..
+        } => {
+            let quota_cap: Option<QuotaCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = quota_cap.map(|quota_cap| {
+                let quota = quota_cap.read();
+                (quota.limit(), quota.used())
+            });
+
+            Some(SystemCall::QuotaInfo {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::RetypeChannel {
+            request,
+        } => {
+            let source = lookup_retype_untyped(&cpool, request.0, 0);
+            if source.is_some() {
+                let source = source.unwrap();
+                let target = ChannelCap::retype_from(source.write().deref_mut());
+                let _ = cpool.lookup_downgrade_at(&target, request.1);
+            }
+
+            None
+        },
+        SystemCall::RetypeTask {
+            request,
+        } => {
+            let source = lookup_retype_untyped(&cpool, request.0, 0);
+            if source.is_some() {
+                let source = source.unwrap();
+                let target = TaskCap::retype_from(source.write().deref_mut());
+                let _ = cpool.lookup_downgrade_at(&target, request.1);
+            }
+
+            None
+        },
+        SystemCall::TaskSetInstructionPointer {
+            request,
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if target.is_some() {
+                let target = target.unwrap();
+                target.write().set_instruction_pointer(VAddr::from(request.1));
+            }
+
+            None
+        },
+        SystemCall::TaskSetStackPointer {
+            request,
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if target.is_some() {
+                let target = target.unwrap();
+                target.write().set_stack_pointer(VAddr::from(request.1));
+            }
+
+            None
+        },
+        SystemCall::TaskSetRegisters {
+            request,
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if target.is_some() {
+                let target = target.unwrap();
+                target.write().set_registers(&request.1);
+            }
+
+            None
+        },
+        SystemCall::TaskGetRegisters {
+            request, ..
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = target.map(|target| target.read().registers());
+
+            Some(SystemCall::TaskGetRegisters {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskSetFsBase {
+            request, ..
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = target.map(|target| target.write().set_fs_base(VAddr::from(request.1))).unwrap_or(false);
+
+            Some(SystemCall::TaskSetFsBase {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TaskSetLdt {
+            request, ..
+        } => {
+            let target: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let ldt_cap: Option<LdtCap> = cpool.lookup_upgrade(request.1, RIGHT_READ);
+            let result = match (target, ldt_cap) {
+                (Some(target), Some(ldt_cap)) => {
+                    target.read().downgrade_ldt(&ldt_cap);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::TaskSetLdt {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TaskSetCPool {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_cpool: Option<CPoolCap> = cpool.lookup_upgrade(request.1, RIGHT_READ | RIGHT_WRITE);
+            if let (Some(target_task), Some(target_cpool)) = (target_task, target_cpool) {
+                target_task.read().downgrade_cpool(&target_cpool);
+            }
+
+            None
+        },
+        SystemCall::TaskSetTopPageTable {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_table: Option<TopPageTableCap> = cpool.lookup_upgrade(request.1, RIGHT_READ);
+            if let (Some(target_task), Some(target_table)) = (target_task, target_table) {
+                target_task.read().downgrade_top_page_table(&target_table);
+            }
+
+            None
+        },
+        SystemCall::TaskSetVSpace {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_vspace: Option<VSpaceCap> = cpool.lookup_upgrade(request.1, RIGHT_READ);
+            if let (Some(target_task), Some(target_vspace)) = (target_task, target_vspace) {
+                let pml4 = target_vspace.read().pml4();
+                target_task.read().downgrade_top_page_table(&pml4);
+            }
+
+            None
+        },
+        SystemCall::TaskSetBuffer {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_buffer: Option<TaskBufferPageCap> = cpool.lookup_upgrade(request.1, RIGHT_READ | RIGHT_WRITE);
+            if let (Some(target_task), Some(target_buffer)) = (target_task, target_buffer) {
+                target_task.read().downgrade_buffer(&target_buffer);
+            }
+
+            None
+        },
+        SystemCall::TaskSetFaultHandler {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_channel: Option<ChannelCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            if let (Some(target_task), Some(target_channel)) = (target_task, target_channel) {
+                target_task.read().downgrade_fault_handler(&target_channel);
+            }
+
+            None
+        },
+        SystemCall::TaskSetDebugger {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let target_channel: Option<ChannelCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            if let (Some(target_task), Some(target_channel)) = (target_task, target_channel) {
+                target_task.read().downgrade_debugger(&target_channel);
+            }
+
+            None
+        },
+        SystemCall::TaskSetReceiveWindow {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if let Some(target_task) = target_task {
+                target_task.write().set_receive_window(request.1);
+            }
+
+            None
+        },
+        SystemCall::TaskSetPriority {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let allowed = request.1 <= task_cap.read().max_priority();
+            let result = target_task.map(|target_task| {
+                if allowed {
+                    target_task.write().set_priority(request.1);
+                    cap::requeue_task(&target_task);
+                }
+                allowed
+            });
+
+            Some(SystemCall::TaskSetPriority {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskSetMaxPriority {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let allowed = request.1 <= task_cap.read().max_priority();
+            let result = target_task.map(|target_task| {
+                if allowed {
+                    target_task.write().set_max_priority(request.1);
+                }
+                allowed
+            });
+
+            Some(SystemCall::TaskSetMaxPriority {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskYield => {
+            cap::rotate_task(&task_cap);
+            None
+        },
+        SystemCall::SleepUntil {
+            request, ..
+        } => {
+            if block_until(&task_cap, TaskStatus::Sleep(request), request) {
+                return None;
+            }
+
+            Some(SystemCall::SleepUntil {
+                request: request,
+                response: Some(false),
+            })
+        },
+        SystemCall::SleepFor {
+            request, ..
+        } => {
+            let deadline = ::time::monotonic_ns().saturating_add(request);
+            if block_until(&task_cap, TaskStatus::Sleep(deadline), deadline) {
+                return None;
+            }
+
+            Some(SystemCall::SleepFor {
+                request: request,
+                response: Some(false),
+            })
+        },
+        SystemCall::TaskSetAffinity {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = target_task.map(|target_task| cap::set_task_affinity(&target_task, request.1));
+
+            Some(SystemCall::TaskSetAffinity {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskSetBreakpoint {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = target_task.map(|target_task| {
+                target_task.write().set_breakpoint(request.1 as usize, request.2, request.3, request.4)
+            }).unwrap_or(false);
+
+            Some(SystemCall::TaskSetBreakpoint {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TaskClearBreakpoint {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = target_task.map(|target_task| {
+                target_task.write().clear_breakpoint(request.1 as usize)
+            }).unwrap_or(false);
+
+            Some(SystemCall::TaskClearBreakpoint {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TaskSetActive {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            if let Some(target_task) = target_task {
+                target_task.write().set_status(TaskStatus::Active);
+            }
+
+            None
+        },
+        SystemCall::TaskSetInactive {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            if let Some(target_task) = target_task {
+                target_task.write().set_status(TaskStatus::Inactive);
+            }
+
+            None
+        },
+        SystemCall::TaskExit {
+            request,
+        } => {
+            task_cap.exit(request);
+            None
+        },
+        SystemCall::TaskBindExitNotification {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = target_task.and_then(|target_task| {
+                match request.1 {
+                    Some(caddr) => {
+                        let notification: Option<NotificationCap> = cpool.lookup_upgrade(caddr, RIGHT_WRITE);
+                        notification.map(|notification| {
+                            target_task.write().bind_exit_notification(Some(&notification), request.2);
+                            true
+                        })
+                    },
+                    None => {
+                        target_task.write().bind_exit_notification(None, request.2);
+                        Some(true)
+                    },
+                }
+            });
+
+            Some(SystemCall::TaskBindExitNotification {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskExitStatus {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let result = target_task.and_then(|target_task| target_task.read().exit_code());
+
+            Some(SystemCall::TaskExitStatus {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::TaskSuspend {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            if let Some(target_task) = target_task {
+                target_task.write().set_suspended(true);
+            }
+
+            None
+        },
+        SystemCall::TaskResume {
+            request,
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request, RIGHT_WRITE);
+            if let Some(target_task) = target_task {
+                target_task.write().set_suspended(false);
+            }
+
+            None
+        },
+        SystemCall::ChannelTake {
+            request, ..
+        } => {
+            let mut chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            if let Some(chan) = chan_option {
+                give_back_donation(&task_cap);
+                task_cap.write().set_status(TaskStatus::ChannelWait(chan))
+            }
+
+            None
+        },
+        SystemCall::ChannelPut {
+            request,
+        } => {
+            let put_rights = match request.1 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            let chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request.0, put_rights);
+            if let Some(chan) = chan_option {
+                let value = ChannelValue::from_message(request.1.clone(), task_cap.clone());
+                if value.is_some() {
+                    ::ipc_trace::record_channel(IpcKind::ChannelPut, &task_cap, &chan, value.as_ref().unwrap());
+                    chan.write().put(value.unwrap());
+                }
+            }
+
+            None
+        },
+        SystemCall::ChannelDonate {
+            request, ..
+        } => {
+            let put_rights = match request.1 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            let chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request.0, put_rights);
+            let reply_option: Option<ChannelCap> = cpool.lookup_upgrade(request.2, RIGHT_READ);
+            if let (Some(chan), Some(reply)) = (chan_option, reply_option) {
+                let value = ChannelValue::from_message(request.1.clone(), task_cap.clone());
+                let sched_context = task_cap.read().upgrade_sched_context();
+                match (value, sched_context) {
+                    (Some(value), Some(sched_context)) => {
+                        ::ipc_trace::record_channel(IpcKind::ChannelPut, &task_cap, &chan, &value);
+                        // The sender waits for the reply without time
+                        // of its own, until the receiver gives it back.
+                        sched_context.donate();
+                        chan.write().put_donation(value, sched_context);
+                        task_cap.write().set_status(TaskStatus::ChannelWait(reply));
+                        return None;
+                    },
+                    (Some(value), None) => value.release(),
+                    _ => (),
+                }
+            }
+
+            Some(SystemCall::ChannelDonate {
+                request: request,
+                response: None,
+            })
+        },
+        SystemCall::ChannelPoll {
+            request, ..
+        } => {
+            let chan_option: Option<ChannelCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            let value = chan_option.and_then(|chan| {
+                let value = {
+                    let mut chan_desc = chan.write();
+                    let value = chan_desc.take();
+                    if let Some(donation) = chan_desc.take_donation() {
+                        donation.receive(&task_cap);
+                    }
+                    value
+                };
+                if let Some(ref value) = value {
+                    ::ipc_trace::record_channel(IpcKind::ChannelTake, &task_cap, &chan, value);
+                }
+                value
+            });
+
+            Some(SystemCall::ChannelPoll {
+                request: request,
+                response: value.map(|value| ChannelValue::to_message(value, task_cap.clone())),
+            })
+        },
+        SystemCall::RetypeEndpoint {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let endpoint_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                EndpointCap::retype_from(untyped.deref_mut())
+            });
+            let result = endpoint_cap.and_then(|endpoint_cap| {
+                cpool.read().downgrade_free(&endpoint_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeEndpoint {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::EndpointSetInheritance {
+            request,
+        } => {
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if let Some(endpoint) = endpoint_option {
+                endpoint.write().set_inheritance(request.1);
+            }
+
+            None
+        },
+        SystemCall::EndpointMintSendOnce {
+            request, ..
+        } => {
+            let (source, target) = request;
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(source, RIGHT_WRITE);
+            let result = match endpoint_option {
+                Some(endpoint) if cpool.lookup_is_free(target) => {
+                    // The capability only sends, with the grant right
+                    // if the source has it.
+                    let source_rights = cpool.lookup_rights(source).unwrap_or(RIGHTS_NONE);
+                    let rights = source_rights.intersect(RIGHT_WRITE | RIGHT_GRANT) | RIGHT_SEND_ONCE;
+                    cpool.lookup_downgrade_any_at(endpoint.into(), target, rights);
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::EndpointMintSendOnce {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::RetypeReply {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let reply_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                ReplyCap::retype_from(untyped.deref_mut())
+            });
+            let result = reply_cap.and_then(|reply_cap| {
+                cpool.read().downgrade_free(&reply_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeReply {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::EndpointSend {
+            request,
+        } => {
+            let send_rights = match request.1 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            // The message stays in the task buffer until a receiver
+            // takes it, and a send-once capability until then too.
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(request.0, send_rights);
+            if let Some(endpoint) = endpoint_option {
+                endpoint.send(&task_cap);
+            }
+
+            None
+        },
+        SystemCall::EndpointCall {
+            request, ..
+        } => {
+            let send_rights = match request.1 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(request.0, send_rights);
+            if let Some(endpoint) = endpoint_option {
+                if endpoint.send(&task_cap) {
+                    task_cap.write().start_call_timing();
+                    return None;
+                }
+            }
+
+            Some(SystemCall::EndpointCall {
+                request: request,
+                response: None,
+            })
+        },
+        SystemCall::EndpointForward {
+            request, ..
+        } => {
+            let send_rights = match request.2 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            let reply_option: Option<ReplyCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(request.1, send_rights);
+            if let (Some(reply), Some(endpoint)) = (reply_option, endpoint_option) {
+                if reply.caller().is_some() && endpoint.send(&task_cap) {
+                    return None;
+                }
+            }
+
+            Some(SystemCall::EndpointForward {
+                request: request,
+                response: Some(false),
+            })
+        },
+        SystemCall::EndpointReceive {
+            request, ..
+        } => {
+            let endpoint_option: Option<EndpointCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            match endpoint_option {
+                Some(endpoint) => {
+                    give_back_donation(&task_cap);
+                    endpoint.wait(&task_cap);
+                    None
+                },
+                None => Some(SystemCall::EndpointReceive {
+                    request: request,
+                    response: None,
+                }),
+            }
+        },
+        SystemCall::EndpointReply {
+            request, ..
+        } => {
+            let put_rights = match request.1 {
+                ChannelMessage::Cap(_) | ChannelMessage::Grant(..) | ChannelMessage::Lend(..) => RIGHT_WRITE | RIGHT_GRANT,
+                _ => RIGHT_WRITE,
+            };
+            let reply_option: Option<ReplyCap> = cpool.lookup_upgrade(request.0, put_rights);
+            let result = reply_option.map(|reply| {
+                reply.reply(request.1.clone(), &task_cap)
+            }).unwrap_or(false);
+
+            Some(SystemCall::EndpointReply {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::ReplySetLendWindow {
+            request, ..
+        } => {
+            let reply_option: Option<ReplyCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = match (reply_option, request.1) {
+                (Some(reply), Some((vspace, address, page_count))) => {
+                    let vspace_option: Option<VSpaceCap> = cpool.lookup_upgrade(vspace, RIGHT_WRITE);
+                    vspace_option.map(|vspace| {
+                        reply.set_lend_window(Some((&vspace, VAddr::from(address), page_count)))
+                    })
+                },
+                (Some(reply), None) => Some(reply.set_lend_window(None)),
+                (None, _) => None,
+            };
+
+            Some(SystemCall::ReplySetLendWindow {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::RetypeNotification {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let notification_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                NotificationCap::retype_from(untyped.deref_mut())
+            });
+            let result = notification_cap.and_then(|notification_cap| {
+                cpool.read().downgrade_free(&notification_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeNotification {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::NotificationSignal {
+            request,
+        } => {
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if let Some(notification_cap) = notification_cap {
+                notification_cap.write().signal(request.1);
+            }
+
+            None
+        },
+        SystemCall::NotificationWait {
+            request, ..
+        } => {
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+            match notification_cap {
+                Some(notification_cap) => {
+                    give_back_donation(&task_cap);
+                    task_cap.write().set_status(TaskStatus::NotificationWait(notification_cap, None));
+                    None
+                },
+                None => Some(SystemCall::NotificationWait {
+                    request: request,
+                    response: None,
+                }),
+            }
+        },
+        SystemCall::NotificationWaitUntil {
+            request, ..
+        } => {
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+            if let Some(notification_cap) = notification_cap {
+                let status = TaskStatus::NotificationWait(notification_cap, Some(request.1));
+                if block_until(&task_cap, status, request.1) {
+                    return None;
+                }
+            }
+
+            Some(SystemCall::NotificationWaitUntil {
+                request: request,
+                response: None,
+            })
+        },
+        SystemCall::RetypeNotificationGroup {
+            request, ..
+        } => {
+            let untyped_cap = lookup_retype_untyped(&cpool, request, 0);
+            let group_cap = untyped_cap.map(|untyped_cap| {
+                let mut untyped = untyped_cap.write();
+                NotificationGroupCap::retype_from(untyped.deref_mut())
+            });
+            let result = group_cap.and_then(|group_cap| {
+                cpool.read().downgrade_free(&group_cap).map(|x| CAddr::from(x as u8))
+            });
+
+            Some(SystemCall::RetypeNotificationGroup {
+                request: request,
+                response: result,
+            })
+        },
+        SystemCall::NotificationGroupAdd {
+            request, ..
+        } => {
+            let group_cap: Option<NotificationGroupCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (group_cap, notification_cap) {
+                (Some(group_cap), Some(notification_cap)) => group_cap.read().add(&notification_cap),
+                _ => false,
+            };
+
+            Some(SystemCall::NotificationGroupAdd {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::NotificationGroupRemove {
+            request, ..
+        } => {
+            let group_cap: Option<NotificationGroupCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(request.1, RIGHT_WRITE);
+            let result = match (group_cap, notification_cap) {
+                (Some(group_cap), Some(notification_cap)) => group_cap.read().remove(&notification_cap),
+                _ => false,
+            };
+
+            Some(SystemCall::NotificationGroupRemove {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::NotificationGroupSignal {
+            request,
+        } => {
+            let group_cap: Option<NotificationGroupCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            if let Some(group_cap) = group_cap {
+                group_cap.read().signal(request.1);
+            }
+
+            None
+        },
+        SystemCall::TaskBindNotification {
+            request, ..
+        } => {
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(request.0, RIGHT_WRITE);
+            let result = match (target_task, request.1) {
+                (Some(target_task), Some(caddr)) => {
+                    let notification_cap: Option<NotificationCap> = cpool.lookup_upgrade(caddr, RIGHT_READ);
+                    match notification_cap {
+                        Some(notification_cap) => {
+                            target_task.read().downgrade_notification(&notification_cap);
+                            true
+                        },
+                        None => false,
+                    }
+                },
+                (Some(target_task), None) => {
+                    target_task.read().remove_notification();
+                    true
+                },
+                _ => false,
+            };
+
+            Some(SystemCall::TaskBindNotification {
+                request: request,
+                response: Some(result),
+            })
+        },
+        SystemCall::TimerTicks { .. } => {
+            Some(SystemCall::TimerTicks {
+                response: Some(::time::ticks()),
+            })
+        },
+        SystemCall::ClockGetTime {
+            request, ..
+        } => {
+            Some(SystemCall::ClockGetTime {
+                request: request,
+                response: ::time::clock_gettime(request),
+            })
+        },
+        SystemCall::KernelInfo { .. } => {
+            Some(SystemCall::KernelInfo {
+                response: Some(::info::kernel_info()),
+            })
+        },
+        SystemCall::MemInfo {
+            request, ..
+        } => {
+            // Usage is global, so only tasks holding untyped memory
+            // may see it.
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+
+            Some(SystemCall::MemInfo {
+                request: request,
+                response: untyped_cap.map(|_| ::meminfo::mem_info()),
+            })
+        },
+        SystemCall::IpcStats {
+            request, ..
+        } => {
+            // As for `MemInfo`, only tasks holding untyped memory may
+            // see global statistics.
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+
+            Some(SystemCall::IpcStats {
+                request: request,
+                response: untyped_cap.map(|_| ::fastpath::ipc_stats()),
+            })
+        },
+        SystemCall::IrqStats {
+            request, ..
+        } => {
+            // As for `MemInfo`, only tasks holding untyped memory may
+            // see global statistics.
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request.0, RIGHT_READ);
+
+            Some(SystemCall::IrqStats {
+                request: request,
+                response: untyped_cap.and_then(|_| ::arch::irq_stats(request.1, request.2)),
+            })
+        },
+        SystemCall::CapDump {
+            request, ..
+        } => {
+            // As for `MemInfo`, only tasks holding untyped memory may
+            // list another task's capabilities.
+            let (untyped, target, start) = request;
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(untyped, RIGHT_READ);
+            let target_task: Option<TaskCap> = cpool.lookup_upgrade(target, RIGHT_READ);
+            let response = match (untyped_cap, target_task) {
+                (Some(_), Some(target_task)) => {
+                    let root = target_task.read().upgrade_cpool();
+                    root.map(|root| cap::cap_dump(&root, start))
+                },
+                _ => None,
+            };
+
+            Some(SystemCall::CapDump {
+                request: request,
+                response: response,
+            })
+        },
+        SystemCall::ObjectCounts {
+            request, ..
+        } => {
+            let untyped_cap: Option<UntypedCap> = cpool.lookup_upgrade(request, RIGHT_READ);
+
+            Some(SystemCall::ObjectCounts {
+                request: request,
+                response: untyped_cap.map(|_| cap::object_counts()),
+            })
         }
     }
 }
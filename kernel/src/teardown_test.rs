@@ -0,0 +1,139 @@
+use common::*;
+use core::ops::DerefMut;
+use abi::{ObjectCounts, MemInfo, RIGHTS_ALL};
+use cap::{UntypedCap, CPoolCap, TaskCap, TaskStatus, ChannelCap, ChannelValue, SchedContextCap,
+          TimerCap, EndpointCap, NotificationCap, NotificationGroupCap, VSpaceCap, RawPageCap, AsidControlCap,
+          SharedFrameSetCap, object_counts, PAGE_LENGTH};
+use meminfo;
+use frame;
+use time;
+
+/// Rounds of objects created and destroyed, after the first one.
+const ROUNDS: usize = 16;
+
+/// Objects of each kind created in every round. Stays below the
+/// number of timers the timer wheel holds.
+const OBJECTS_PER_ROUND: usize = 64;
+
+/// How far in the future the timers of a round expire. They are
+/// destroyed long before.
+const TIMER_DEADLINE_NS: u64 = 3_600_000_000_000;
+
+/// Where shared frame sets are mapped, above the pages of a round.
+const SHARED_VADDR: usize = 0x200000;
+
+/// Kernel memory state that must not change across a round.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    free_frames: usize,
+    watermark: PAddr,
+    counts: ObjectCounts,
+    mem: MemInfo,
+}
+
+impl Snapshot {
+    fn take(untyped: &UntypedCap) -> Snapshot {
+        Snapshot {
+            free_frames: frame::free_frames(),
+            watermark: untyped.read().watermark(),
+            counts: object_counts(),
+            mem: meminfo::mem_info(),
+        }
+    }
+}
+
+/// Create and destroy objects of every kind from `untyped`, in the
+/// states hardest to tear down, and panic unless the frame allocator,
+/// the object counts, the memory accounting and the watermark of
+/// `untyped` are back to where they started. `untyped` must not have any objects derived from it.
+pub fn run(untyped: &UntypedCap) {
+    assert!(!untyped.read().has_children());
+
+    // Kernel stacks and object pool entries are cached on their first
+    // use, and kept.
+    round(untyped);
+    let before = Snapshot::take(untyped);
+
+    for _ in 0..ROUNDS {
+        round(untyped);
+    }
+
+    let after = Snapshot::take(untyped);
+    assert_eq!(before, after);
+    log!("Teardown test: {} rounds of {} objects, {} frames free",
+         ROUNDS, OBJECTS_PER_ROUND, after.free_frames);
+}
+
+fn round(untyped: &UntypedCap) {
+    let start = untyped.read().watermark();
+    let asid_control = unsafe { AsidControlCap::bootstrap(untyped.write().deref_mut()) };
+    let asid_pool = asid_control.read().make_pool(untyped.write().deref_mut()).unwrap();
+
+    for i in 0..OBJECTS_PER_ROUND {
+        // A pool holding the last capability to itself.
+        let cpool = CPoolCap::retype_from(untyped.write().deref_mut());
+        cpool.read().downgrade_at(&cpool, 0);
+
+        // A task waiting on a channel, whose scheduling context is
+        // donated along the value in it, and a timer bound to it.
+        let task = TaskCap::retype_from(untyped.write().deref_mut());
+        let chan = ChannelCap::retype_from(untyped.write().deref_mut());
+        let sched_context = SchedContextCap::retype_from(untyped.write().deref_mut());
+        task.write().downgrade_cpool(&cpool);
+        assert!(sched_context.bind(&task));
+        sched_context.donate();
+        chan.write().put_donation(ChannelValue::Cap(cpool.clone().into(), RIGHTS_ALL), sched_context);
+        task.write().set_status(TaskStatus::ChannelWait(chan.clone()));
+
+        // An endpoint with a task blocked sending to it, which has a
+        // notification bound, in a group, in place of another one,
+        // and a task receiving from it a second time.
+        let endpoint = EndpointCap::retype_from(untyped.write().deref_mut());
+        let receiver = TaskCap::retype_from(untyped.write().deref_mut());
+        endpoint.wait(&receiver);
+        receiver.write().set_status(TaskStatus::Inactive);
+        endpoint.wait(&receiver);
+        let sender = TaskCap::retype_from(untyped.write().deref_mut());
+        let notification = NotificationCap::retype_from(untyped.write().deref_mut());
+        let replaced = NotificationCap::retype_from(untyped.write().deref_mut());
+        sender.read().downgrade_notification(&replaced);
+        sender.read().downgrade_notification(&notification);
+        assert!(sender.read().upgrade_notification().map_or(false, |bound| bound.ptr_eq(&notification)));
+        assert!(endpoint.send(&sender));
+        let group = NotificationGroupCap::retype_from(untyped.write().deref_mut());
+        assert!(group.read().add(&notification));
+
+        // A timer bound to the notification, then to the channel
+        // instead.
+        let timer = TimerCap::retype_from(untyped.write().deref_mut());
+        timer.write().bind_notification(&notification, 0);
+        timer.write().bind(&chan);
+        assert!(timer.read().upgrade_notification().is_none());
+        assert!(timer.write().arm(time::monotonic_ns() + TIMER_DEADLINE_NS, 0));
+
+        // An address space with a live mapping, tagged with an ASID
+        // of the pool.
+        let vspace = VSpaceCap::retype_from(untyped);
+        let page = RawPageCap::retype_from(untyped.write().deref_mut());
+        assert!(vspace.write().map(VAddr::from((i + 1) * PAGE_LENGTH), &page));
+        assert!(asid_pool.read().assign(&vspace));
+
+        // A shared frame set mapped twice, once unmapped through the
+        // set, and once gone with its address space, so that it is
+        // mapped nowhere anymore, then mapped again.
+        let frames = SharedFrameSetCap::retype_from(untyped, 2).unwrap();
+        let other = VSpaceCap::retype_from(untyped);
+        let shared_vaddr = VAddr::from(SHARED_VADDR);
+        assert!(frames.write().map(&vspace, shared_vaddr, true));
+        assert!(frames.write().map(&other, shared_vaddr, false));
+        assert_eq!(frames.write().map_count(), 2);
+        assert!(!vspace.write().unmap(shared_vaddr));
+        assert!(frames.write().unmap(&vspace, shared_vaddr));
+        other.write().destroy();
+        assert_eq!(frames.write().map_count(), 0);
+        assert!(frames.write().map(&vspace, shared_vaddr, true));
+    }
+
+    assert!(untyped.revoke());
+    assert_eq!(untyped.read().watermark(), start);
+}
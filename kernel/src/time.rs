@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use abi::{Clock, TimeSpec};
+use arch::Clocksource;
+use {arch, timer_wheel};
+
+/// Read the clocksource. Run by the timer wheel every
+/// `arch::TICK_PERIOD_NS` while the PIT is the clocksource, so that
+/// it is read often enough not to miss a wrap. The kernel is tickless
+/// otherwise: time slices, budgets and timeouts arm one-shot
+/// deadlines of their own.
+fn tick(_: usize) {
+    monotonic_ns();
+}
+
+/// Timer ticks elapsed since the timer was enabled, of
+/// `arch::TICK_PERIOD_NS` each.
+pub fn ticks() -> u64 {
+    monotonic_ns() / arch::TICK_PERIOD_NS
+}
+
+/// Largest value `monotonic_ns` returned.
+static LAST_MONOTONIC_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Wall-clock time, in nanoseconds since the Unix epoch, at which
+/// `monotonic_ns` read zero. Zero until `init` reads the RTC.
+static REALTIME_OFFSET_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Read the RTC, anchor real time to the monotonic clock, and start
+/// the tick if the clocksource needs one. Called by `kmain` once the
+/// timer is enabled.
+pub fn init() {
+    if arch::current_clocksource() == Clocksource::Pit {
+        let tick = timer_wheel::add(monotonic_ns() + arch::TICK_PERIOD_NS, arch::TICK_PERIOD_NS, tick, 0);
+        assert!(tick.is_some());
+    }
+
+    match arch::rtc_unix_seconds() {
+        Some(seconds) => {
+            let offset = seconds * 1_000_000_000 - monotonic_ns();
+            REALTIME_OFFSET_NS.store(offset as usize, Ordering::Relaxed);
+            log!("RTC: {} seconds since the epoch", seconds);
+        },
+        None => log!("RTC: unreadable, real time starts at the epoch"),
+    }
+}
+
+/// Nanoseconds since boot. Never goes backwards, even across CPUs
+/// whose clocks disagree slightly.
+pub fn monotonic_ns() -> u64 {
+    let now = arch::now_ns();
+    let mut last = LAST_MONOTONIC_NS.load(Ordering::Relaxed);
+    loop {
+        if (now as usize) <= last {
+            return last as u64;
+        }
+        let previous = LAST_MONOTONIC_NS.compare_and_swap(last, now as usize, Ordering::Relaxed);
+        if previous == last {
+            return now;
+        }
+        last = previous;
+    }
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    REALTIME_OFFSET_NS.load(Ordering::Relaxed) as u64 + monotonic_ns()
+}
+
+/// Current time of `clock`, for the `ClockGetTime` system call.
+pub fn clock_gettime(clock: Clock) -> Option<TimeSpec> {
+    let ns = match clock {
+        Clock::Monotonic => monotonic_ns(),
+        Clock::Realtime => realtime_ns(),
+    };
+
+    Some(TimeSpec {
+        seconds: ns / 1_000_000_000,
+        nanoseconds: (ns % 1_000_000_000) as u32,
+    })
+}
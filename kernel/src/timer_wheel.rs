@@ -0,0 +1,453 @@
+use util::Mutex;
+use arch::{self, MAX_CPUS};
+use time;
+
+/// Length of a wheel tick, in nanoseconds. Timers expire at the end
+/// of the tick their deadline falls in.
+pub const TIMER_GRANULARITY_NS: u64 = 1_000_000;
+
+/// Number of timers the wheel holds.
+const MAX_TIMERS: usize = 256;
+/// Number of timers armed on behalf of userspace the wheel holds. The
+/// rest are left to kernel deadlines, which userspace cannot crowd
+/// out.
+const MAX_USER_TIMERS: usize = 192;
+/// Number of wheel levels.
+const LEVELS: usize = 4;
+/// Slots per level, as a shift.
+const SLOT_BITS: usize = 6;
+/// Slots per level.
+const SLOTS: usize = 1 << SLOT_BITS;
+/// Mask of a slot index.
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// List of timers whose callback is due.
+const EXPIRED_LIST: usize = LEVELS * SLOTS;
+/// Number of lists: one per slot, and the expired list.
+const LISTS: usize = EXPIRED_LIST + 1;
+/// End of a list.
+const NIL: u16 = 0xffff;
+
+/// Handle to a timer added to the wheel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u16,
+}
+
+/// Timer in the wheel. Free if it has no function.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Deadline, in nanoseconds of `time::monotonic_ns`.
+    expires_ns: u64,
+    /// Period the timer is rearmed with, or zero for a one-shot timer.
+    period_ns: u64,
+    function: Option<fn(usize)>,
+    argument: usize,
+    /// Whether the timer counts against `MAX_USER_TIMERS`.
+    user: bool,
+    /// Incremented each time the entry is freed, so that stale
+    /// `TimerId`s do not match.
+    generation: u16,
+    /// List the entry is on.
+    list: usize,
+    prev: u16,
+    next: u16,
+}
+
+const FREE_ENTRY: Entry = Entry {
+    expires_ns: 0,
+    period_ns: 0,
+    function: None,
+    argument: 0,
+    user: false,
+    generation: 0,
+    list: 0,
+    prev: NIL,
+    next: NIL,
+};
+
+/// Hierarchical timer wheel. Level `n` slots span `SLOTS^n` ticks, and
+/// timers move down a level each time their slot comes up, until they
+/// reach level 0 and then the expired list.
+struct Wheel {
+    entries: [Entry; MAX_TIMERS],
+    heads: [u16; LISTS],
+    /// Bitmap of non-empty slots, per level.
+    occupied: [u64; LEVELS],
+    /// Next tick to process.
+    current: u64,
+    /// Deadline programmed in the timer of each CPU, indexed by CPU
+    /// id, or `u64::max_value()`.
+    programmed_ns: [u64; MAX_CPUS],
+    /// Deadline each CPU preempts the task it runs at, indexed by CPU
+    /// id, or `u64::max_value()`. Kept out of the entries, so that a
+    /// task always runs with one, however full the wheel is.
+    preempt_ns: [u64; MAX_CPUS],
+    /// Number of timers armed on behalf of userspace.
+    user_count: usize,
+    /// Earliest wakeup added while the wheel was full, or
+    /// `u64::max_value()`.
+    wakeup_ns: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    entries: [FREE_ENTRY; MAX_TIMERS],
+    heads: [NIL; LISTS],
+    occupied: [0; LEVELS],
+    current: 0,
+    programmed_ns: [u64::max_value(); MAX_CPUS],
+    preempt_ns: [u64::max_value(); MAX_CPUS],
+    user_count: 0,
+    wakeup_ns: u64::max_value(),
+});
+
+/// Tick a deadline falls in.
+fn tick_of(ns: u64) -> u64 {
+    (ns + TIMER_GRANULARITY_NS - 1) / TIMER_GRANULARITY_NS
+}
+
+impl Wheel {
+    fn push(&mut self, list: usize, index: u16) {
+        let head = self.heads[list];
+        {
+            let entry = &mut self.entries[index as usize];
+            entry.list = list;
+            entry.prev = NIL;
+            entry.next = head;
+        }
+        if head != NIL {
+            self.entries[head as usize].prev = index;
+        }
+        self.heads[list] = index;
+        if list < EXPIRED_LIST {
+            self.occupied[list / SLOTS] |= 1 << (list % SLOTS);
+        }
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let (list, prev, next) = {
+            let entry = &self.entries[index as usize];
+            (entry.list, entry.prev, entry.next)
+        };
+        if prev != NIL {
+            self.entries[prev as usize].next = next;
+        } else {
+            self.heads[list] = next;
+        }
+        if next != NIL {
+            self.entries[next as usize].prev = prev;
+        }
+        if list < EXPIRED_LIST && self.heads[list] == NIL {
+            self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+        }
+    }
+
+    /// Put a timer on the slot its deadline falls in, relative to the
+    /// current tick.
+    fn enqueue(&mut self, index: u16) {
+        let expires = tick_of(self.entries[index as usize].expires_ns);
+        if expires < self.current {
+            self.push(EXPIRED_LIST, index);
+            return;
+        }
+
+        // Deadlines beyond the last level wait in its farthest slot,
+        // and are placed again when it comes up.
+        let max_delta = (1 << (SLOT_BITS * LEVELS)) - 1;
+        let expires = ::core::cmp::min(expires, self.current + max_delta);
+        let delta = expires - self.current;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level + 1)) {
+            level += 1;
+        }
+
+        let slot = ((expires >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+        self.push(level * SLOTS + slot, index);
+    }
+
+    /// Place again all timers of a slot.
+    fn cascade(&mut self, list: usize) {
+        let mut index = self.heads[list];
+        while index != NIL {
+            let next = self.entries[index as usize].next;
+            self.unlink(index);
+            self.enqueue(index);
+            index = next;
+        }
+    }
+
+    /// Process ticks up to and including `tick`, moving timers that
+    /// expire in them to the expired list.
+    fn advance(&mut self, tick: u64) {
+        while self.current <= tick {
+            if self.occupied.iter().all(|bits| *bits == 0) {
+                self.current = tick + 1;
+                return;
+            }
+
+            // Nothing at level 0 can expire before the next level 1
+            // slot comes up.
+            if self.occupied[0] == 0 && self.current & SLOT_MASK != 0 {
+                self.current = ::core::cmp::min((self.current | SLOT_MASK) + 1, tick + 1);
+                continue;
+            }
+
+            let current = self.current;
+            for level in (1..LEVELS).rev() {
+                if current & ((1 << (SLOT_BITS * level)) - 1) == 0 {
+                    let slot = ((current >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+                    self.cascade(level * SLOTS + slot);
+                }
+            }
+
+            let list = (current & SLOT_MASK) as usize;
+            let mut index = self.heads[list];
+            while index != NIL {
+                let next = self.entries[index as usize].next;
+                self.unlink(index);
+                self.push(EXPIRED_LIST, index);
+                index = next;
+            }
+
+            self.current += 1;
+        }
+    }
+
+    /// Earliest tick the wheel needs processing at: when a timer
+    /// expires, or a higher level slot comes up.
+    fn next_tick(&self) -> Option<u64> {
+        if self.heads[EXPIRED_LIST] != NIL {
+            return Some(self.current);
+        }
+
+        let mut next: Option<u64> = None;
+        for level in 0..LEVELS {
+            if self.occupied[level] == 0 {
+                continue;
+            }
+
+            let shift = SLOT_BITS * level;
+            let position = (self.current >> shift) & SLOT_MASK;
+            let distance = self.occupied[level].rotate_right(position as u32).trailing_zeros() as u64;
+            let tick = if level == 0 {
+                self.current + distance
+            } else {
+                // A slot matching the current position comes up after
+                // a full turn.
+                let distance = if distance == 0 { SLOTS as u64 } else { distance };
+                ((self.current >> shift) + distance) << shift
+            };
+            next = Some(next.map_or(tick, |next| ::core::cmp::min(next, tick)));
+        }
+        if self.wakeup_ns != u64::max_value() {
+            let tick = tick_of(self.wakeup_ns);
+            next = Some(next.map_or(tick, |next| ::core::cmp::min(next, tick)));
+        }
+        next
+    }
+
+    /// Program the timer of the current CPU for the next tick that
+    /// needs processing, or its preemption deadline if earlier, if
+    /// that is earlier than the one programmed, or `force`. With
+    /// `force` and no deadline left, the timer is stopped.
+    fn program(&mut self, force: bool) {
+        let cpu = arch::current_cpu_id();
+        let deadline = match self.next_tick() {
+            Some(tick) => tick * TIMER_GRANULARITY_NS,
+            None => u64::max_value(),
+        };
+        let deadline = ::core::cmp::min(deadline, self.preempt_ns[cpu]);
+        let programmed_ns = self.programmed_ns[cpu];
+
+        if deadline != u64::max_value() && (force || deadline < programmed_ns) {
+            arch::set_next_deadline(deadline);
+        } else if deadline == u64::max_value() && force && programmed_ns != u64::max_value() {
+            arch::stop_timer();
+        }
+        if force || deadline < programmed_ns {
+            self.programmed_ns[cpu] = deadline;
+        }
+    }
+
+    /// Take the first expired timer, rearming or freeing it. Returns
+    /// its callback.
+    fn pop_expired(&mut self, now: u64) -> Option<(fn(usize), usize)> {
+        let index = self.heads[EXPIRED_LIST];
+        if index == NIL {
+            return None;
+        }
+        self.unlink(index);
+
+        let (function, argument, period_ns, expires_ns) = {
+            let entry = &self.entries[index as usize];
+            (entry.function.unwrap(), entry.argument, entry.period_ns, entry.expires_ns)
+        };
+
+        if period_ns != 0 {
+            // Skip the periods that were missed.
+            let missed = if now > expires_ns { (now - expires_ns) / period_ns } else { 0 };
+            self.entries[index as usize].expires_ns = expires_ns + (missed + 1) * period_ns;
+            self.enqueue(index);
+        } else {
+            self.free(index);
+        }
+
+        Some((function, argument))
+    }
+
+    /// Take a free entry for a timer, or `None` if the wheel, or the
+    /// share of it userspace may use, is full.
+    fn allocate(&mut self, expires_ns: u64, period_ns: u64, function: fn(usize), argument: usize,
+                user: bool) -> Option<TimerId> {
+        if user && self.user_count == MAX_USER_TIMERS {
+            return None;
+        }
+        let index = match self.entries.iter().position(|entry| entry.function.is_none()) {
+            Some(index) => index,
+            None => return None,
+        };
+        if user {
+            self.user_count += 1;
+        }
+
+        let generation = {
+            let entry = &mut self.entries[index];
+            entry.expires_ns = expires_ns;
+            entry.period_ns = period_ns;
+            entry.function = Some(function);
+            entry.argument = argument;
+            entry.user = user;
+            entry.generation
+        };
+        self.enqueue(index as u16);
+        self.program(false);
+
+        Some(TimerId {
+            index: index as u16,
+            generation: generation,
+        })
+    }
+
+    /// Free an entry taken off its list.
+    fn free(&mut self, index: u16) {
+        let user = {
+            let entry = &mut self.entries[index as usize];
+            entry.function = None;
+            entry.generation = entry.generation.wrapping_add(1);
+            entry.user
+        };
+        if user {
+            self.user_count -= 1;
+        }
+    }
+}
+
+/// Call `function` with `argument` once `time::monotonic_ns` reaches
+/// `expires_ns`, and then every `period_ns` nanoseconds if it is not
+/// zero. Callbacks run in interrupt context, with the wheel unlocked.
+/// For kernel deadlines, which have the entries userspace may not
+/// take. Returns `None` if the wheel is full.
+pub fn add(expires_ns: u64, period_ns: u64, function: fn(usize), argument: usize) -> Option<TimerId> {
+    WHEEL.lock().allocate(expires_ns, period_ns, function, argument, false)
+}
+
+/// Like `add`, for a timer armed on behalf of userspace. Returns
+/// `None` if userspace already has `MAX_USER_TIMERS` timers armed, or
+/// the wheel is full.
+pub fn add_user(expires_ns: u64, period_ns: u64, function: fn(usize), argument: usize) -> Option<TimerId> {
+    WHEEL.lock().allocate(expires_ns, period_ns, function, argument, true)
+}
+
+/// Wake the CPU once `time::monotonic_ns` reaches `expires_ns`, so
+/// that the scheduler looks at its tasks again. For kernel deadlines
+/// without a callback, which must not be lost: with the wheel full,
+/// the timer is still programmed for the earliest of them, and
+/// `false` returned, for the caller to add it again once woken.
+pub fn add_wakeup(expires_ns: u64) -> bool {
+    let mut wheel = WHEEL.lock();
+    if wheel.allocate(expires_ns, 0, |_| (), 0, false).is_some() {
+        return true;
+    }
+    wheel.wakeup_ns = ::core::cmp::min(wheel.wakeup_ns, expires_ns);
+    wheel.program(false);
+    false
+}
+
+/// Remove a timer from the wheel. Returns `false` if it already
+/// expired, and was not periodic.
+pub fn cancel(id: TimerId) -> bool {
+    let mut wheel = WHEEL.lock();
+    {
+        let entry = &wheel.entries[id.index as usize];
+        if entry.function.is_none() || entry.generation != id.generation {
+            return false;
+        }
+    }
+
+    wheel.unlink(id.index);
+    wheel.free(id.index);
+    // The timer does not fire for nothing, which would wake an idle
+    // CPU.
+    wheel.program(true);
+    true
+}
+
+/// Set the deadline the current CPU preempts the task it is about to
+/// run at. The timer is only programmed again if the deadline is
+/// earlier than the one programmed, so that most switches leave it
+/// alone.
+pub fn set_preempt_deadline(deadline_ns: u64) {
+    let mut wheel = WHEEL.lock();
+    wheel.preempt_ns[arch::current_cpu_id()] = deadline_ns;
+    wheel.program(false);
+}
+
+/// Forget the preemption deadline of the current CPU, once the task
+/// stopped running. The timer is left as it is: firing early only
+/// makes `run_expired` program it again.
+pub fn clear_preempt_deadline() {
+    WHEEL.lock().preempt_ns[arch::current_cpu_id()] = u64::max_value();
+}
+
+/// Deadline the timer of the current CPU is programmed for, or `None`
+/// if no timer is pending. Idle CPUs sleep deeper the further away it
+/// is.
+pub fn next_deadline() -> Option<u64> {
+    let programmed_ns = WHEEL.lock().programmed_ns[arch::current_cpu_id()];
+    if programmed_ns == u64::max_value() {
+        None
+    } else {
+        Some(programmed_ns)
+    }
+}
+
+/// Run the callbacks of expired timers, and program the timer for the
+/// next one. Called by `kmain` whenever a task switch returns with
+/// `Exception::Timer`.
+pub fn run_expired() {
+    let now = time::monotonic_ns();
+    loop {
+        let callback = {
+            let mut wheel = WHEEL.lock();
+            wheel.advance(now / TIMER_GRANULARITY_NS);
+            wheel.pop_expired(now)
+        };
+
+        match callback {
+            Some((function, argument)) => function(argument),
+            None => break,
+        }
+    }
+
+    let mut wheel = WHEEL.lock();
+    if wheel.wakeup_ns <= now {
+        wheel.wakeup_ns = u64::max_value();
+    }
+    wheel.program(true);
+}
+
+/// Program the timer of the current CPU for the next deadline, which
+/// another CPU, taken offline, may have held.
+pub fn reprogram() {
+    WHEEL.lock().program(true);
+}
@@ -4,6 +4,9 @@ pub extern "C" fn rust_begin_unwind(args: ::core::fmt::Arguments, file: &str, li
 {
 	// 'args' will print to the formatted string passed to panic!
 	log!("file='{}', line={} :: {}", file, line, args);
+	// In interrupt context, or with the console held, the message only
+	// went to a log ring.
+	::logging::flush_on_panic();
 	loop {}
 }
 
@@ -34,7 +34,9 @@ struct ManagedWeakAddr {
 }
 
 /// Inner of an Arc, containing strong pointers and weak pointers
-/// information. Wrap the actual data.
+/// information. Wrap the actual data. The layout is fixed, so that
+/// weak pools can reach `first_weak` without knowing `T`.
+#[repr(C)]
 struct ManagedArcInner<T> {
     lead: Mutex<usize>,
     // TODO: Implement weak pool lock.
@@ -100,6 +102,23 @@ impl ManagedArcAny {
         where ManagedArc<T>: Any {
         self.type_id == TypeId::of::<T>()
     }
+
+    /// Physical address of the object, which identifies it.
+    pub fn ptr(&self) -> PAddr {
+        self.ptr
+    }
+
+    /// `TypeId` of the typed Arc this one was made from.
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    /// Create a new strong pointer of the given type, without
+    /// consuming this one.
+    pub fn clone_typed<T: Any>(&self) -> ManagedArc<T> {
+        assert!(self.type_id == TypeId::of::<ManagedArc<T>>());
+        unsafe { ManagedArc::from_ptr(self.ptr) }
+    }
 }
 
 impl<T: Any> From<ManagedArcAny> for ManagedArc<T> {
@@ -179,4 +198,37 @@ impl<T> ManagedArc<T> {
         let lead = unsafe { inner.as_ref().lead.lock() };
         *lead
     }
+
+    /// Whether any weak pointer (for example, a capability pool
+    /// entry) still refers to this Arc.
+    pub fn is_weakly_referenced(&self) -> bool {
+        let inner = self.inner_object();
+        let first_weak = unsafe { inner.as_ref().first_weak.lock() };
+        first_weak.is_some()
+    }
+
+    /// Physical address of the object, which identifies it.
+    pub fn ptr(&self) -> PAddr {
+        self.ptr
+    }
+
+    /// Whether both Arcs point to the same object.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// Drop the data in place, so that its memory can be reused.
+    ///
+    /// # Safety
+    ///
+    /// `self` must be the only pointer, strong or weak, to the data.
+    pub unsafe fn destroy(self) {
+        {
+            let mut inner_obj = self.inner_object();
+            let inner = inner_obj.as_mut();
+            assert!(*inner.lead.lock() == 1 && inner.first_weak.lock().is_none());
+            ptr::drop_in_place(&mut inner.data);
+        }
+        mem::forget(self);
+    }
 }
@@ -85,6 +85,11 @@ macro_rules! weak_pool {
                 })
             }
 
+            /// Whether the entry at `index` holds a weak pointer.
+            pub fn is_occupied(&self, index: usize) -> bool {
+                self.0[index].lock().is_some()
+            }
+
             /// Downgrade a strong pointer to a weak pointer and store
             /// it at `index` in this weak pool.
             pub fn downgrade_at<T: Any>(&self, arc: &ManagedArc<T>, index: usize)
@@ -135,6 +140,47 @@ macro_rules! weak_pool {
                 }
             }
 
+            /// Remove the weak pointer at `index`, unlinking it from
+            /// the Arc it points to. Returns `false` if the entry is
+            /// empty.
+            pub fn remove(&self, index: usize) -> bool {
+                let mut weak_node_option = self.0[index].lock();
+                let weak_node = match weak_node_option.take() {
+                    Some(weak_node) => weak_node,
+                    None => return false,
+                };
+
+                let arc_inner_obj = unsafe { arc_inner_header(weak_node.ptr) };
+                let arc_inner = unsafe { arc_inner_obj.as_ref() };
+                let mut arc_first_weak = arc_inner.first_weak.lock();
+
+                match weak_node.prev {
+                    Some(prev_addr) => set_weak_node(prev_addr, |prev_weak_node| {
+                        prev_weak_node.map(|mut prev_weak_node| {
+                            prev_weak_node.next = weak_node.next;
+                            prev_weak_node
+                        })
+                    }),
+                    None => *arc_first_weak = weak_node.next,
+                }
+                if let Some(next_addr) = weak_node.next {
+                    set_weak_node(next_addr, |next_weak_node| {
+                        next_weak_node.map(|mut next_weak_node| {
+                            next_weak_node.prev = weak_node.prev;
+                            next_weak_node
+                        })
+                    });
+                }
+                true
+            }
+
+            /// Remove all weak pointers in this weak pool.
+            pub fn clear(&self) {
+                for index in 0..self.0.len() {
+                    self.remove(index);
+                }
+            }
+
             /// Downgrade a strong pointer to a weak pointer, and then
             /// store it in a free slot in this weak pool.
             pub fn downgrade_free<T: Any>(&self, arc: &ManagedArc<T>) -> Option<usize>
@@ -157,6 +203,30 @@ weak_pool!(ManagedWeakPool1);
 weak_pool!(ManagedWeakPool3);
 weak_pool!(ManagedWeakPool256);
 
+impl<T> ManagedArc<T> {
+    /// Remove every weak pointer to this Arc, emptying the capability
+    /// pool entries, and other weak pool entries, that refer to it.
+    pub fn remove_weak_all(&self) {
+        let arc_inner_obj = self.inner_object();
+        let arc_inner = unsafe { arc_inner_obj.as_ref() };
+        let mut arc_first_weak = arc_inner.first_weak.lock();
+
+        let mut next = arc_first_weak.take();
+        while let Some(weak_addr) = next {
+            set_weak_node(weak_addr, |weak_node| {
+                next = weak_node.and_then(|weak_node| weak_node.next);
+                None
+            });
+        }
+    }
+}
+
+/// The `ManagedArcInner` at `ptr`, of any type. Only the fields before
+/// `data` can be used.
+unsafe fn arc_inner_header(ptr: PAddr) -> MemoryObject<ManagedArcInner<()>> {
+    MemoryObject::new(ptr)
+}
+
 fn set_weak_node<F>(addr: ManagedWeakAddr, f: F) where F: FnOnce(Option<ManagedWeakNode>) -> Option<ManagedWeakNode> {
     if addr.inner_type_id == TypeId::of::<ManagedArcInner<ManagedWeakPool1>>() {
         let inner_obj: MemoryObject<ManagedArcInner<ManagedWeakPool1>> =
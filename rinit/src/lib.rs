@@ -16,7 +16,26 @@ extern crate alloc;
 #[macro_use]
 mod vga_buffer;
 
+/// Soft watchdog restarting unresponsive servers.
+mod supervisor;
+
 use system::CAddr;
+use spin::Mutex;
+use supervisor::{Supervisor, ServerSpec, RestartPolicy};
+
+/// Supervisor watching servers started by the parent rinit.
+static SUPERVISOR: Mutex<Supervisor> = Mutex::new(Supervisor::new());
+
+/// Scheduling context of the child rinit.
+const CHILD_SCHED_CONTEXT: u8 = 246;
+/// Health ping channel of the child rinit.
+const CHILD_PING: u8 = 247;
+/// Health pong channel of the child rinit.
+const CHILD_PONG: u8 = 248;
+/// Number of timer ticks the child has to answer a health ping.
+const CHILD_TIMEOUT: u64 = 100;
+/// First argument the child rinit is started with by the supervisor.
+const CHILD_ARGUMENT: isize = 1;
 
 /// Decode a code in the PS/2 scan code set 1 (legacy set).
 ///
@@ -109,8 +128,11 @@ static mut IS_PARENT: bool = true;
 #[lang="start"]
 #[no_mangle]
 #[allow(private_no_mangle_fns)]
-fn start(_argc: isize, _argv: *const *const u8) {
-    if unsafe { IS_PARENT } {
+fn start(argc: isize, _argv: *const *const u8) {
+    // A child loaded from the boot image into its own address space
+    // gets its argument, and one started by hand in the parent's
+    // address space finds the parent already running.
+    if argc != CHILD_ARGUMENT && unsafe { IS_PARENT } {
         unsafe { IS_PARENT = false; }
         parent_main();
     } else {
@@ -123,14 +145,32 @@ fn parent_main() {
     unsafe { system::set_task_buffer_addr(0x90001000); }
     system_print!("parent rinit started.");
 
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    let top_page_table = CAddr::from(system::BOOT_TOP_PAGE_TABLE);
+
     // Test allocator
-    unsafe { selfalloc::setup_allocator(CAddr::from(2), CAddr::from(3), 0x1000000000); }
+    unsafe { selfalloc::setup_allocator(untyped, top_page_table, 0x1000000000); }
     {
         use alloc::boxed::Box;
         let heap_test = Box::new(42);
         system_print!("testing heap: {:?}", heap_test);
     }
 
+    // Test demand-zero mapping
+    system::map_demand_zero(0x2000000000, 16, untyped, top_page_table);
+    {
+        let lazy = 0x2000000000 as *mut u64;
+        unsafe { *lazy.offset(1024) = 42; }
+        system_print!("testing demand-zero: {} {}", unsafe { *lazy }, unsafe { *lazy.offset(1024) });
+    }
+
+    // Test virtual to physical translation
+    match system::virt_to_phys(top_page_table, 0x2000000000) {
+        Some((paddr, attributes)) =>
+            system_print!("0x2000000000 => 0x{:x}, writable: {}", paddr, attributes.writable),
+        None => system_print!("0x2000000000 is not mapped"),
+    }
+
     system_print!("parent stack addr: 0x{:x}.",
                   system::task_buffer_addr() as usize);
     print!("Child entry should be at: 0x{:x} ({})\nChild stack pointer should be at: 0x{:x} ({})\n",
@@ -141,7 +181,13 @@ fn parent_main() {
     let mut command = [0u8; 32];
     let mut command_size = 0;
     loop {
-        let key = from_scancode(system::channel_take_raw(CAddr::from(254)) as usize);
+        let key = match system::channel_poll_raw(CAddr::from(254)) {
+            Some(code) => from_scancode(code as usize),
+            None => {
+                SUPERVISOR.lock().poll();
+                continue;
+            },
+        };
         if key == lastkey {
             continue;
         } else {
@@ -167,13 +213,32 @@ fn parent_main() {
 }
 
 fn start_child() {
-    system::retype_task(CAddr::from(2), CAddr::from(249));
-    system::task_set_stack_pointer(CAddr::from(249), 0x70000000 + (0x1000 * 4 - 4));
-    system::task_set_instruction_pointer(CAddr::from(249), start as *const () as u64);
-    system::task_set_cpool(CAddr::from(249), CAddr::from(0));
-    system::task_set_top_page_table(CAddr::from(249), CAddr::from(3));
-    system::task_set_buffer(CAddr::from(249), CAddr::from(250));
-    system::task_set_active(CAddr::from(249));
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    let asid_pool = system::asid_control_make_pool(CAddr::from(system::ASID_CONTROL), untyped).unwrap();
+    system::untyped_retype(untyped, system::ObjectType::SchedContext, 0,
+                           CAddr::from(system::BOOT_CPOOL), CHILD_SCHED_CONTEXT as usize, 1);
+    system::retype_channel(untyped, CAddr::from(CHILD_PING));
+    system::retype_channel(untyped, CAddr::from(CHILD_PONG));
+
+    // The child runs in an address space of its own, loaded from the
+    // boot image, so that a restart starts it from scratch.
+    SUPERVISOR.lock().register(ServerSpec {
+        task: CAddr::from(249),
+        image: CAddr::from(system::BOOT_IMAGE),
+        argument: CHILD_ARGUMENT as u64,
+        untyped: untyped,
+        asid_pool: asid_pool,
+        stack: 0x70000000,
+        stack_pages: 4,
+        cpool: CAddr::from(system::BOOT_CPOOL),
+        buffer: CAddr::from(250),
+        buffer_vaddr: 0x90003000,
+        sched_context: CAddr::from(CHILD_SCHED_CONTEXT),
+        ping: CAddr::from(CHILD_PING),
+        pong: CAddr::from(CHILD_PONG),
+        timeout: CHILD_TIMEOUT,
+        policy: RestartPolicy::Always,
+    }).unwrap();
 }
 
 fn child_main() {
@@ -183,8 +248,10 @@ fn child_main() {
     system_print!("parent stack addr: 0x{:x}.",
                   system::task_buffer_addr() as usize);
     loop {
-        let value: u64 = system::channel_take(CAddr::from(255));
-        system_print!("Received from master: {:?}", value);
+        supervisor::answer_ping(CAddr::from(CHILD_PING), CAddr::from(CHILD_PONG));
+        if let Some(value) = system::channel_poll::<u64>(CAddr::from(255)) {
+            system_print!("Received from master: {:?}", value);
+        }
     }
 }
 
@@ -223,6 +290,13 @@ fn execute_command(s: &str) {
     } else if let Some((source, target)) = parse_usize(s, "retype task") {
         system::retype_task(CAddr::from(source as u8), CAddr::from(target as u8));
         print!("Operation finished.\n");
+    } else if let Some((source, target)) = parse_usize(s, "retype sched") {
+        system::untyped_retype(CAddr::from(source as u8), system::ObjectType::SchedContext, 0,
+                               CAddr::from(system::BOOT_CPOOL), target, 1);
+        print!("Operation finished.\n");
+    } else if let Some((target, sched_context)) = parse_usize(s, "set sched") {
+        system::sched_context_bind(CAddr::from(sched_context as u8), CAddr::from(target as u8));
+        print!("Operation finished.\n");
     } else if let Some((target, ptr)) = parse_usize(s, "set stack") {
         system::task_set_stack_pointer(CAddr::from(target as u8), ptr as u64);
         print!("Operation finished.\n");
@@ -0,0 +1,237 @@
+use system::{self, CAddr};
+
+/// Maximum number of servers a supervisor can watch.
+const MAX_SERVERS: usize = 8;
+
+/// What the supervisor does after a server misses its health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Leave the server stopped.
+    Never,
+    /// Restart the server every time it stops responding.
+    Always,
+    /// Restart the server at most the given number of times.
+    Limited(usize),
+}
+
+/// Static description of a supervised server. Everything needed to
+/// respawn the server from the boot image is recorded here, so that
+/// a restarted server gets exactly the capabilities it was declared
+/// with.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerSpec {
+    /// Entry the server's task is created in.
+    pub task: CAddr,
+    /// Boot image the server's program is loaded from.
+    pub image: CAddr,
+    /// Value the server gets as its first argument, telling it apart
+    /// from the other programs of the image.
+    pub argument: u64,
+    /// Untyped memory the server's task and address space are created
+    /// from.
+    pub untyped: CAddr,
+    /// ASID pool the server's address spaces get an ASID from.
+    pub asid_pool: CAddr,
+    /// Lowest address of the server's stack.
+    pub stack: u64,
+    /// Number of pages of the server's stack.
+    pub stack_pages: usize,
+    /// Root capability pool given to the server.
+    pub cpool: CAddr,
+    /// Task buffer given to the server.
+    pub buffer: CAddr,
+    /// Address the task buffer is mapped at in the server.
+    pub buffer_vaddr: u64,
+    /// Scheduling context the server runs on.
+    pub sched_context: CAddr,
+    /// Channel the supervisor sends health pings on.
+    pub ping: CAddr,
+    /// Channel the server answers health pings on.
+    pub pong: CAddr,
+    /// Number of timer ticks a server has to answer a ping.
+    pub timeout: u64,
+    /// Restart policy applied on timeout.
+    pub policy: RestartPolicy,
+}
+
+/// Runtime state of a supervised server.
+#[derive(Debug, Clone, Copy)]
+struct ServerState {
+    spec: ServerSpec,
+    /// Address space of the running instance.
+    vspace: Option<CAddr>,
+    restarts: usize,
+    stopped: bool,
+    /// Token of the outstanding ping, and the tick it was sent at.
+    outstanding: Option<(u64, u64)>,
+}
+
+/// A soft watchdog for user servers. It periodically pings every
+/// registered server on its health channel, and restarts servers that
+/// fail to answer within their timeout according to their restart
+/// policy.
+pub struct Supervisor {
+    servers: [Option<ServerState>; MAX_SERVERS],
+    next_token: u64,
+}
+
+impl Supervisor {
+    /// Create an empty supervisor.
+    pub const fn new() -> Supervisor {
+        Supervisor {
+            servers: [None; MAX_SERVERS],
+            next_token: 1,
+        }
+    }
+
+    /// Spawn the server described by `spec` and start watching
+    /// it. Returns the server index, or `None` if the supervisor is
+    /// full, or the server cannot be spawned.
+    pub fn register(&mut self, spec: ServerSpec) -> Option<usize> {
+        let index = self.servers.iter().position(|s| s.is_none())?;
+
+        let vspace = spawn(&spec)?;
+        self.servers[index] = Some(ServerState {
+            spec: spec,
+            vspace: Some(vspace),
+            restarts: 0,
+            stopped: false,
+            outstanding: None,
+        });
+
+        Some(index)
+    }
+
+    /// Number of times the server at `index` has been restarted.
+    pub fn restarts(&self, index: usize) -> Option<usize> {
+        self.servers[index].map(|s| s.restarts)
+    }
+
+    /// Run one round of health checks. This never blocks, and should
+    /// be called regularly from the supervising task's main loop.
+    pub fn poll(&mut self) {
+        let now = system::timer_ticks();
+
+        for i in 0..MAX_SERVERS {
+            let mut state = match self.servers[i] {
+                Some(state) if !state.stopped => state,
+                _ => continue,
+            };
+
+            match state.outstanding {
+                None => {
+                    let token = self.next_token;
+                    self.next_token += 1;
+
+                    system::channel_put_raw(state.spec.ping, token);
+                    state.outstanding = Some((token, now));
+                },
+                Some((token, sent)) => {
+                    if system::channel_poll_raw(state.spec.pong) == Some(token) {
+                        state.outstanding = None;
+                    } else if now - sent >= state.spec.timeout {
+                        system_print!("server {} missed its health check.", i);
+                        restart(&mut state);
+                    }
+                },
+            }
+
+            self.servers[i] = Some(state);
+        }
+    }
+}
+
+/// Answer a pending health ping, if any. Supervised servers call this
+/// from their main loop.
+pub fn answer_ping(ping: CAddr, pong: CAddr) {
+    if let Some(token) = system::channel_poll_raw(ping) {
+        system::channel_put_raw(pong, token);
+    }
+}
+
+/// Create a fresh task and address space for the server, load its
+/// program from the boot image, map its stack and task buffer, and
+/// start it from its entry point with its declared capabilities.
+/// Returns the address space, or `None` if anything is short, in
+/// which case nothing is left behind.
+fn spawn(spec: &ServerSpec) -> Option<CAddr> {
+    system::retype_task(spec.untyped, spec.task);
+    let vspace = match system::retype_vspace(spec.untyped, spec.asid_pool) {
+        Some(vspace) => vspace,
+        None => {
+            system::cpool_revoke(spec.task);
+            return None;
+        },
+    };
+
+    let stack = spec.stack as usize;
+    let loaded = system::boot_image_load(spec.image, vspace).and_then(|entry| {
+        if system::vspace_remap(vspace, stack, 0, stack, spec.stack_pages) &&
+            system::vspace_map(vspace, spec.buffer_vaddr as usize, spec.buffer)
+        {
+            system::task_get_registers(spec.task).map(|registers| (entry, registers))
+        } else {
+            None
+        }
+    });
+    let (entry, mut registers) = match loaded {
+        Some(loaded) => loaded,
+        None => {
+            revoke(spec.task, vspace);
+            return None;
+        },
+    };
+
+    registers.rip = entry;
+    registers.rsp = spec.stack + (0x1000 * spec.stack_pages as u64 - 4);
+    registers.rdi = spec.argument;
+    system::task_set_registers(spec.task, registers);
+    system::task_set_cpool(spec.task, spec.cpool);
+    system::task_set_vspace(spec.task, vspace);
+    system::task_set_buffer(spec.task, spec.buffer);
+    system::sched_context_bind(spec.sched_context, spec.task);
+    system::task_set_active(spec.task);
+    Some(vspace)
+}
+
+/// Revoke the task and address space of a server instance. The task
+/// stops and lets go of its scheduling context, and the address
+/// space frees the frames and paging structures it owns. Their
+/// descriptors are reused once the untyped memory is revoked.
+fn revoke(task: CAddr, vspace: CAddr) {
+    system::cpool_revoke(task);
+    system::cpool_revoke(vspace);
+}
+
+/// Stop a server that missed its health check, and respawn it from
+/// the boot image if its restart policy allows.
+fn restart(state: &mut ServerState) {
+    if let Some(vspace) = state.vspace.take() {
+        revoke(state.spec.task, vspace);
+    }
+    state.outstanding = None;
+
+    // Drain stale health messages so the new instance starts clean.
+    let _ = system::channel_poll_raw(state.spec.ping);
+    let _ = system::channel_poll_raw(state.spec.pong);
+
+    let allowed = match state.spec.policy {
+        RestartPolicy::Never => false,
+        RestartPolicy::Always => true,
+        RestartPolicy::Limited(max) => state.restarts < max,
+    };
+
+    if allowed {
+        state.restarts += 1;
+        state.vspace = spawn(&state.spec);
+        if state.vspace.is_some() {
+            system_print!("server restarted ({} restarts).", state.restarts);
+        } else {
+            state.stopped = true;
+            system_print!("server could not be respawned.");
+        }
+    } else {
+        state.stopped = true;
+        system_print!("server stopped by restart policy.");
+    }
+}
@@ -1,4 +1,9 @@
-use abi::{SystemCall, TaskBuffer, CAddr, ChannelMessage};
+use abi::{SystemCall, TaskBuffer, CAddr, ChannelMessage, FrameLoan, CacheOperation, CacheMode, KernelInfo, MemInfo,
+          IpcStats, IrqStats, Clock, TimeSpec, PageFaultInfo, FaultInfo, TaskRegisters, MapAttributes, MAP_COW,
+          BreakpointKind, DebugEvent, ObjectType, CapRights, CapTransfer, MESSAGE_CAPS,
+          CapDump, ObjectCounts, IpcTrace, CapError, KERNEL_LOG_WRITE_LENGTH, FASTPATH_CALL, MESSAGE_LENGTH_MAX};
+#[cfg(feature="kernel_debug")]
+use abi::SwitchStats;
 use core::any::Any;
 use super::task_buffer_addr;
 
@@ -8,86 +13,1855 @@ pub fn retype_raw_page_free(source: CAddr) -> CAddr {
         response: None
     });
     match result {
-        SystemCall::RetypeRawPageFree {
+        SystemCall::RetypeRawPageFree {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+pub fn map_raw_page_free(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, page: CAddr) {
+    system_call(SystemCall::MapRawPageFree {
+        untyped: untyped,
+        toplevel_table: toplevel_table,
+        request: (vaddr, page),
+        flags: 0,
+    });
+}
+
+/// Map a page copy-on-write. Later write faults on the mapping are
+/// resolved with frames from `untyped`.
+pub fn map_raw_page_cow(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, page: CAddr) {
+    system_call(SystemCall::MapRawPageFree {
+        untyped: untyped,
+        toplevel_table: toplevel_table,
+        request: (vaddr, page),
+        flags: MAP_COW,
+    });
+}
+
+pub fn retype_large_page(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypeLargePage {
+        request: (source, target),
+    });
+}
+
+pub fn map_large_page(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, page: CAddr) {
+    system_call(SystemCall::MapLargePage {
+        untyped: untyped,
+        toplevel_table: toplevel_table,
+        request: (vaddr, page),
+    });
+}
+
+pub fn large_page_split(page: CAddr, untyped: CAddr, low_cpool: CAddr, high_cpool: CAddr) -> bool {
+    let result = system_call(SystemCall::LargePageSplit {
+        request: (page, untyped, low_cpool, high_cpool),
+        response: None
+    });
+    match result {
+        SystemCall::LargePageSplit {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+pub fn large_page_merge(page: CAddr) -> bool {
+    let result = system_call(SystemCall::LargePageMerge {
+        request: page,
+        response: None
+    });
+    match result {
+        SystemCall::LargePageMerge {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+/// Reserve `count` pages at `vaddr` with no backing frames. Each page
+/// is filled with a zeroed frame from `untyped` on first touch.
+pub fn map_demand_zero(vaddr: usize, count: usize, untyped: CAddr, toplevel_table: CAddr) {
+    system_call(SystemCall::MapDemandZero {
+        untyped: untyped,
+        toplevel_table: toplevel_table,
+        request: (vaddr, count),
+    });
+}
+
+/// Create a raw page capability for page `index` of a persistent
+/// memory region. The page keeps its contents. Returns `None` if
+/// `index` is out of range.
+pub fn pmem_page(pmem: CAddr, index: usize, untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::PmemPage {
+        request: (pmem, index, untyped),
+        response: None
+    });
+    match result {
+        SystemCall::PmemPage {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Retype page `index` of a device untyped region into an uncached
+/// device frame, using `untyped` for the capability itself. Returns
+/// `None` if `index` is out of range or the kernel uses the page.
+pub fn device_untyped_retype(device: CAddr, index: usize, untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::DeviceUntypedRetype {
+        request: (device, index, untyped),
+        response: None
+    });
+    match result {
+        SystemCall::DeviceUntypedRetype {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Start physical address and length of a device untyped region.
+pub fn device_untyped_info(device: CAddr) -> Option<(u64, usize)> {
+    let result = system_call(SystemCall::DeviceUntypedInfo {
+        request: device,
+        response: None
+    });
+    match result {
+        SystemCall::DeviceUntypedInfo {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Write back the cache lines covering `length` bytes at `offset` of
+/// a persistent memory region.
+pub fn pmem_flush(pmem: CAddr, offset: usize, length: usize) -> bool {
+    let result = system_call(SystemCall::PmemFlush {
+        request: (pmem, offset, length),
+        response: None
+    });
+    match result {
+        SystemCall::PmemFlush {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+/// Order earlier persistent memory flushes before later stores.
+pub fn pmem_fence(pmem: CAddr) {
+    system_call(SystemCall::PmemFence {
+        request: pmem,
+    });
+}
+
+/// Allocate a zeroed, physically contiguous DMA buffer of at least
+/// `length` bytes whose last byte is at or below the physical address
+/// `mask`, charged to the quota of `untyped`. Returns the buffer
+/// capability and its physical address, or `None` if `length` is over
+/// 256 pages, the quota has not that much left, or no suitable memory
+/// is free. Revoking the buffer unmaps its pages everywhere before the
+/// memory is reused.
+pub fn retype_dma(untyped: CAddr, length: usize, mask: u64) -> Option<(CAddr, u64)> {
+    let result = system_call(SystemCall::RetypeDma {
+        request: (untyped, length, mask),
+        response: None
+    });
+    match result {
+        SystemCall::RetypeDma {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create a raw page capability for page `index` of a DMA buffer, to
+/// be mapped by the driver. Every call for the same page returns a
+/// capability to the same page object. Returns `None` if `index` is
+/// out of range.
+pub fn dma_page(dma: CAddr, index: usize, untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::DmaPage {
+        request: (dma, index, untyped),
+        response: None
+    });
+    match result {
+        SystemCall::DmaPage {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create an ASID pool from `untyped` through the ASID control
+/// capability `control`. Returns `None` if all ASID pools are handed
+/// out.
+pub fn asid_control_make_pool(control: CAddr, untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::AsidControlMakePool {
+        request: (control, untyped),
+        response: None
+    });
+    match result {
+        SystemCall::AsidControlMakePool {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create an empty address space from `untyped`, and assign it an
+/// ASID from `asid_pool`. Its paging structures are allocated from,
+/// and returned to, `untyped`. Returns `None` if the pool has no free
+/// ASID.
+pub fn retype_vspace(untyped: CAddr, asid_pool: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeVSpace {
+        request: (untyped, asid_pool),
+        response: None
+    });
+    match result {
+        SystemCall::RetypeVSpace {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Map the raw page or task buffer page `page` writable at `vaddr` in
+/// `vspace`. Returns `false` if `vaddr` is not a free, page-aligned
+/// user address.
+pub fn vspace_map(vspace: CAddr, vaddr: usize, page: CAddr) -> bool {
+    let result = system_call(SystemCall::VSpaceMap {
+        request: (vspace, vaddr, page),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceMap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Unmap the page at `vaddr` in `vspace`. Returns `false` if nothing
+/// is mapped there.
+pub fn vspace_unmap(vspace: CAddr, vaddr: usize) -> bool {
+    let result = system_call(SystemCall::VSpaceUnmap {
+        request: (vspace, vaddr),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceUnmap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Tear down every mapping of `vspace`, and return its paging
+/// structures to the untyped capability it was created from. Returns
+/// the number of paging structures freed.
+pub fn vspace_destroy(vspace: CAddr) -> usize {
+    let result = system_call(SystemCall::VSpaceDestroy {
+        request: vspace,
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceDestroy {
+            response, ..
+        } => { return response.unwrap_or(0); },
+        _ => panic!(),
+    };
+}
+
+/// Load the program of the boot image `image` into `vspace`, in zeroed
+/// pages owned by the address space, and return its entry point.
+/// Returns `None` if a segment overlaps other mappings of `vspace`, or
+/// its untyped memory is short.
+pub fn boot_image_load(image: CAddr, vspace: CAddr) -> Option<u64> {
+    let result = system_call(SystemCall::BootImageLoad {
+        request: (image, vspace),
+        response: None
+    });
+    match result {
+        SystemCall::BootImageLoad {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Move the `old_count` pages mapped at `old_vaddr` in `vspace` to
+/// `new_vaddr`, resized to `new_count` pages, without copying
+/// them. Growing maps zeroed pages, shrinking unmaps the tail. Pass
+/// the same address to resize in place. Returns `false` if nothing
+/// was changed: a page of the old range is unmapped, the new range
+/// overlaps other mappings or large pages, or `vspace`'s untyped
+/// memory is short.
+pub fn vspace_remap(vspace: CAddr, old_vaddr: usize, old_count: usize,
+                    new_vaddr: usize, new_count: usize) -> bool {
+    let result = system_call(SystemCall::VSpaceRemap {
+        request: (vspace, old_vaddr, old_count, new_vaddr, new_count),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceRemap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Read the accessed and dirty bits of `count` pages from `vaddr` in
+/// `vspace`, at most 64, as bitmaps with bit `i` for page `i`. With
+/// `clear`, the bits are cleared as they are read. Returns `None` if
+/// the range is invalid.
+pub fn vspace_harvest(vspace: CAddr, vaddr: usize, count: usize, clear: bool) -> Option<(u64, u64)> {
+    let result = system_call(SystemCall::VSpaceHarvest {
+        request: (vspace, vaddr, count, clear),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceHarvest {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Write-protect every writable page of `vspace`, and log the pages
+/// written from then on into the `SoftDirtyRing` held by the raw page
+/// `ring`. Returns the number of pages protected, or `None` if either
+/// capability is invalid.
+pub fn vspace_track_writes(vspace: CAddr, ring: CAddr) -> Option<usize> {
+    let result = system_call(SystemCall::VSpaceTrackWrites {
+        request: (vspace, ring),
+        response: None
+    });
+    match result {
+        SystemCall::VSpaceTrackWrites {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create a shared frame set of `page_count` zeroed frames from
+/// `untyped`.
+pub fn retype_shared_frame_set(untyped: CAddr, page_count: usize) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeSharedFrameSet {
+        request: (untyped, page_count),
+        response: None
+    });
+    match result {
+        SystemCall::RetypeSharedFrameSet {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Map the whole shared frame set `set` at `vaddr` in `vspace`,
+/// read-only unless `writable`. The pages can only be unmapped with
+/// `shared_frame_set_unmap`, or by tearing `vspace` down. Returns
+/// `false` if the set is already mapped 32 times, or any page of the
+/// range cannot be mapped.
+pub fn shared_frame_set_map(set: CAddr, vspace: CAddr, vaddr: usize, writable: bool) -> bool {
+    let result = system_call(SystemCall::SharedFrameSetMap {
+        request: (set, vspace, vaddr, writable),
+        response: None
+    });
+    match result {
+        SystemCall::SharedFrameSetMap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Remove the mapping of `set` at `vaddr` in `vspace`. Returns
+/// `false` if the set is not mapped there.
+pub fn shared_frame_set_unmap(set: CAddr, vspace: CAddr, vaddr: usize) -> bool {
+    let result = system_call(SystemCall::SharedFrameSetUnmap {
+        request: (set, vspace, vaddr),
+        response: None
+    });
+    match result {
+        SystemCall::SharedFrameSetUnmap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Return the frames of `set` to their untyped capability. Returns
+/// `false` if the set is still mapped anywhere. Mappings in address
+/// spaces since torn down or revoked do not count.
+pub fn shared_frame_set_release(set: CAddr) -> bool {
+    let result = system_call(SystemCall::SharedFrameSetRelease {
+        request: set,
+        response: None
+    });
+    match result {
+        SystemCall::SharedFrameSetRelease {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create an IPC ring from `untyped` over the shared frame set
+/// `frames`, of at least two pages: an `IpcRingHeader`, then data.
+pub fn retype_ipc_ring(untyped: CAddr, frames: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeIpcRing {
+        request: (untyped, frames),
+        response: None
+    });
+    match result {
+        SystemCall::RetypeIpcRing {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Wake the consumer of `ring` by signalling `notification` with
+/// `badge`, or never if `None`, replacing any notification bound
+/// before. Needs the write right on `ring`.
+pub fn ipc_ring_bind(ring: CAddr, notification: Option<CAddr>, badge: u64) -> bool {
+    let result = system_call(SystemCall::IpcRingBind {
+        request: (ring, notification, badge),
+        response: None
+    });
+    match result {
+        SystemCall::IpcRingBind {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Wake the consumer of `ring`, if it marked itself waiting. Only
+/// needed once `consumer_waiting` is found set. Returns `false` if
+/// the consumer was not waiting. Needs the read right on `ring`, so
+/// that a producer can be handed a capability that only notifies.
+pub fn ipc_ring_notify(ring: CAddr) -> bool {
+    let result = system_call(SystemCall::IpcRingNotify {
+        request: ring,
+        response: None
+    });
+    match result {
+        SystemCall::IpcRingNotify {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Allocate an interrupt vector delivered to the CPU with id `cpu`,
+/// for a device's MSI or MSI-X, through the IRQ control capability
+/// `control`. The capability's memory comes from `untyped`. Returns
+/// the capability, and the message address and data to program into
+/// the device, or `None` if the CPU is not online or no vector is
+/// free. Revoking the capability returns the vector.
+pub fn irq_control_get_msi(control: CAddr, untyped: CAddr, cpu: usize) -> Option<(CAddr, u64, u32)> {
+    let result = system_call(SystemCall::IrqControlGetMsi {
+        request: (control, untyped, cpu),
+        response: None
+    });
+    match result {
+        SystemCall::IrqControlGetMsi {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Message address and data of an MSI capability.
+pub fn msi_message(msi: CAddr) -> Option<(u64, u32)> {
+    let result = system_call(SystemCall::MsiMessage {
+        request: msi,
+        response: None
+    });
+    match result {
+        SystemCall::MsiMessage {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Deliver the interrupt of an MSI capability to the CPU with id
+/// `cpu`. Returns the new message address and data, which must be
+/// programmed into the device, or `None` if the CPU is not online or
+/// has no free vector.
+pub fn msi_set_affinity(msi: CAddr, cpu: usize) -> Option<(u64, u32)> {
+    let result = system_call(SystemCall::MsiSetAffinity {
+        request: (msi, cpu),
+        response: None
+    });
+    match result {
+        SystemCall::MsiSetAffinity {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create an IRQ handler capability at the empty entry `target`
+/// through the IRQ control capability `control`, for I/O APIC input
+/// `gsi` delivered to the CPU with id `cpu`. The handler's memory
+/// comes from `untyped`. Returns `false` if `target` is not empty, or
+/// the input already has a handler or cannot be routed.
+pub fn irq_control_get_handler(control: CAddr, untyped: CAddr, gsi: u32, cpu: usize, target: CAddr) -> bool {
+    let result = system_call(SystemCall::IrqControlGetHandler {
+        request: (control, untyped, gsi, cpu, target),
+        response: None
+    });
+    match result {
+        SystemCall::IrqControlGetHandler {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Take the CPU with id `cpu` offline through the CPU control
+/// capability `control`: its tasks and IRQ handlers move to online
+/// CPUs, and it parks in its deepest C-state. Returns `false` if the
+/// CPU is not online, or is the bootstrap CPU or the one the caller
+/// runs on.
+pub fn cpu_control_offline(control: CAddr, cpu: usize) -> bool {
+    let result = system_call(SystemCall::CpuControlOffline {
+        request: (control, cpu),
+        response: None
+    });
+    match result {
+        SystemCall::CpuControlOffline {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Bring the CPU with id `cpu`, taken offline by `cpu_control_offline`,
+/// back online. Returns `false` if it is not offline.
+pub fn cpu_control_online(control: CAddr, cpu: usize) -> bool {
+    let result = system_call(SystemCall::CpuControlOnline {
+        request: (control, cpu),
+        response: None
+    });
+    match result {
+        SystemCall::CpuControlOnline {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Deliver interrupts of an IRQ handler to `channel`, and unmask its
+/// input. Each interrupt masks the input again until acknowledged.
+pub fn irq_handler_bind(irq_handler: CAddr, channel: CAddr) -> bool {
+    let result = system_call(SystemCall::IrqHandlerBind {
+        request: (irq_handler, channel),
+        response: None
+    });
+    match result {
+        SystemCall::IrqHandlerBind {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Signal interrupts of an IRQ handler to `notification` with
+/// `badge`, instead of any channel, and unmask its input. Each
+/// interrupt masks the input again until acknowledged.
+pub fn irq_handler_bind_notification(irq_handler: CAddr, notification: CAddr, badge: u64) -> bool {
+    let result = system_call(SystemCall::IrqHandlerBindNotification {
+        request: (irq_handler, notification, badge),
+        response: None
+    });
+    match result {
+        SystemCall::IrqHandlerBindNotification {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Acknowledge the last interrupt of an IRQ handler, unmasking its
+/// input. Returns `false` if no interrupt was waiting.
+pub fn irq_handler_ack(irq_handler: CAddr) -> bool {
+    let result = system_call(SystemCall::IrqHandlerAck {
+        request: irq_handler,
+        response: None
+    });
+    match result {
+        SystemCall::IrqHandlerAck {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Route the input of an IRQ handler to the CPU with id `cpu`.
+/// Returns `false` if the CPU is not online or has no free vector.
+pub fn irq_handler_set_affinity(irq_handler: CAddr, cpu: usize) -> bool {
+    let result = system_call(SystemCall::IrqHandlerSetAffinity {
+        request: (irq_handler, cpu),
+        response: None
+    });
+    match result {
+        SystemCall::IrqHandlerSetAffinity {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create a timer from `untyped`. Returns its capability address, or
+/// `None` if the capability pool is full.
+pub fn retype_timer(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeTimer {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::RetypeTimer {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Deliver expirations of a timer to `channel`, as the number of
+/// expirations since the timer was armed.
+pub fn timer_bind(timer: CAddr, channel: CAddr) -> bool {
+    let result = system_call(SystemCall::TimerBind {
+        request: (timer, channel),
+        response: None
+    });
+    match result {
+        SystemCall::TimerBind {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Signal expirations of a timer to `notification` with `badge`,
+/// instead of any channel.
+pub fn timer_bind_notification(timer: CAddr, notification: CAddr, badge: u64) -> bool {
+    let result = system_call(SystemCall::TimerBindNotification {
+        request: (timer, notification, badge),
+        response: None
+    });
+    match result {
+        SystemCall::TimerBindNotification {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Arm a timer to expire when the monotonic clock reaches
+/// `deadline_ns`, and then every `period_ns` nanoseconds if it is not
+/// zero. Returns `false` if the kernel has no room for another timer:
+/// userspace as a whole can have 192 timers armed.
+pub fn timer_arm(timer: CAddr, deadline_ns: u64, period_ns: u64) -> bool {
+    let result = system_call(SystemCall::TimerArm {
+        request: (timer, deadline_ns, period_ns),
+        response: None
+    });
+    match result {
+        SystemCall::TimerArm {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Stop a timer. Returns `false` if it was not armed.
+pub fn timer_cancel(timer: CAddr) -> bool {
+    let result = system_call(SystemCall::TimerCancel {
+        request: timer,
+        response: None
+    });
+    match result {
+        SystemCall::TimerCancel {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create an unbound scheduling context from `untyped`, whose budget
+/// is its whole period. Returns its capability address, or `None` if
+/// the capability pool is full.
+pub fn retype_sched_context(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeSchedContext {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::RetypeSchedContext {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Let the task bound to a scheduling context run `budget_ns`
+/// nanoseconds in every period of `period_ns` nanoseconds. Returns
+/// `false` if the period is zero or shorter than the budget.
+pub fn sched_context_configure(sched_context: CAddr, budget_ns: u64, period_ns: u64) -> bool {
+    let result = system_call(SystemCall::SchedContextConfigure {
+        request: (sched_context, budget_ns, period_ns),
+        response: None
+    });
+    match result {
+        SystemCall::SchedContextConfigure {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Bind a scheduling context to `task`, which can only run once it
+/// is. Returns `false` if either of them is already bound.
+pub fn sched_context_bind(sched_context: CAddr, task: CAddr) -> bool {
+    let result = system_call(SystemCall::SchedContextBind {
+        request: (sched_context, task),
+        response: None
+    });
+    match result {
+        SystemCall::SchedContextBind {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Unbind a scheduling context from its task, which stops running.
+pub fn sched_context_unbind(sched_context: CAddr) -> bool {
+    let result = system_call(SystemCall::SchedContextUnbind {
+        request: sched_context,
+        response: None
+    });
+    match result {
+        SystemCall::SchedContextUnbind {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create an LDT, with null entries, from `untyped`. Returns its
+/// capability address, or `None` if the capability pool is full.
+pub fn retype_ldt(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeLdt {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::RetypeLdt {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create an I/O port capability for the ports from `first` to `last`
+/// of `ioport`, using `untyped` for the capability itself. Needs
+/// `RIGHT_WRITE` on `ioport`. Returns `None` if the range is not
+/// within that of `ioport`.
+pub fn io_port_issue(ioport: CAddr, untyped: CAddr, first: u16, last: u16) -> Option<CAddr> {
+    let result = system_call(SystemCall::IoPortIssue {
+        request: (ioport, untyped, first, last),
+        response: None
+    });
+    match result {
+        SystemCall::IoPortIssue {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+fn io_port_in(ioport: CAddr, port: u16, width: u8) -> Option<u32> {
+    let result = system_call(SystemCall::IoPortIn {
+        request: (ioport, port, width),
+        response: None
+    });
+    match result {
+        SystemCall::IoPortIn {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+fn io_port_out(ioport: CAddr, port: u16, width: u8, value: u32) -> bool {
+    let result = system_call(SystemCall::IoPortOut {
+        request: (ioport, port, width, value),
+        response: None
+    });
+    match result {
+        SystemCall::IoPortOut {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Read a byte from `port` through `ioport`. Returns `None` if the
+/// port is out of its range, or used by the kernel.
+pub fn io_port_in8(ioport: CAddr, port: u16) -> Option<u8> {
+    io_port_in(ioport, port, 1).map(|value| value as u8)
+}
+
+/// Read a word from `port` through `ioport`. See `io_port_in8`.
+pub fn io_port_in16(ioport: CAddr, port: u16) -> Option<u16> {
+    io_port_in(ioport, port, 2).map(|value| value as u16)
+}
+
+/// Read a double word from `port` through `ioport`. See
+/// `io_port_in8`.
+pub fn io_port_in32(ioport: CAddr, port: u16) -> Option<u32> {
+    io_port_in(ioport, port, 4)
+}
+
+/// Write a byte to `port` through `ioport`. Returns `false` if the
+/// port is out of its range, or used by the kernel.
+pub fn io_port_out8(ioport: CAddr, port: u16, value: u8) -> bool {
+    io_port_out(ioport, port, 1, value as u32)
+}
+
+/// Write a word to `port` through `ioport`. See `io_port_out8`.
+pub fn io_port_out16(ioport: CAddr, port: u16, value: u16) -> bool {
+    io_port_out(ioport, port, 2, value as u32)
+}
+
+/// Write a double word to `port` through `ioport`. See
+/// `io_port_out8`.
+pub fn io_port_out32(ioport: CAddr, port: u16, value: u32) -> bool {
+    io_port_out(ioport, port, 4, value)
+}
+
+/// Copy the kernel log from `position` on into the raw page `page`,
+/// through the kernel log capability `log`. Returns the position of
+/// the first byte copied, later than `position` if older bytes were
+/// overwritten, and the number of bytes copied. The next read starts
+/// at their sum. Returns `None` if `page` is device memory.
+pub fn kernel_log_read(log: CAddr, page: CAddr, position: u64) -> Option<(u64, usize)> {
+    let result = system_call(SystemCall::KernelLogRead {
+        request: (log, page, position),
+        response: None
+    });
+    match result {
+        SystemCall::KernelLogRead {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Start or stop recording every message between tasks in the IPC
+/// trace, through the kernel log capability `log`. Returns `false` if
+/// tracing is not compiled in, or `None` without the capability.
+pub fn ipc_trace_set(log: CAddr, enabled: bool) -> Option<bool> {
+    let result = system_call(SystemCall::IpcTraceSet {
+        request: (log, enabled),
+        response: None
+    });
+    match result {
+        SystemCall::IpcTraceSet {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Read up to `IPC_TRACE_ENTRIES` messages of the IPC trace from
+/// `position` on, through the kernel log capability `log`. The next
+/// read starts at the position of the trace returned plus the number
+/// of entries in it.
+pub fn ipc_trace_read(log: CAddr, position: u64) -> Option<IpcTrace> {
+    let result = system_call(SystemCall::IpcTraceRead {
+        request: (log, position),
+        response: None
+    });
+    match result {
+        SystemCall::IpcTraceRead {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Add `text` to the kernel log as a line from user-space, through the
+/// kernel log capability `log`. Characters other than printable ASCII
+/// are logged as `?`. Returns `false` if `text` is longer than
+/// `KERNEL_LOG_WRITE_LENGTH` bytes.
+pub fn kernel_log_write(log: CAddr, text: &str) -> bool {
+    let bytes = text.as_bytes();
+    if bytes.len() > KERNEL_LOG_WRITE_LENGTH {
+        return false;
+    }
+
+    let mut buffer = [0u8; KERNEL_LOG_WRITE_LENGTH];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+    let result = system_call(SystemCall::KernelLogWrite {
+        request: (log, buffer, bytes.len()),
+        response: None
+    });
+    match result {
+        SystemCall::KernelLogWrite {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Set entry `index` of an LDT to the raw segment descriptor
+/// `descriptor`, or clear it if zero. Only present code and data
+/// segments of privilege level 3 are accepted. Returns `false` if the
+/// entry or the descriptor is invalid.
+pub fn ldt_set_entry(ldt: CAddr, index: usize, descriptor: u64) -> bool {
+    let result = system_call(SystemCall::LdtSetEntry {
+        request: (ldt, index, descriptor),
+        response: None
+    });
+    match result {
+        SystemCall::LdtSetEntry {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Run a cache maintenance operation on `length` bytes at `vaddr` in
+/// the address space `toplevel_table`. Returns `false` if any page in
+/// the range is not mapped for user access.
+pub fn cache_maintenance(toplevel_table: CAddr, vaddr: usize, length: usize,
+                         operation: CacheOperation) -> bool {
+    let result = system_call(SystemCall::CacheMaintenance {
+        request: (toplevel_table, vaddr, length, operation),
+        response: None
+    });
+    match result {
+        SystemCall::CacheMaintenance {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+/// Run a cache maintenance operation on `length` bytes at `offset` in
+/// the raw or large page `page`, wherever it is mapped. Returns
+/// `false` if the range is not within the page.
+pub fn page_cache_maintenance(page: CAddr, offset: usize, length: usize,
+                              operation: CacheOperation) -> bool {
+    let result = system_call(SystemCall::PageCacheMaintenance {
+        request: (page, offset, length, operation),
+        response: None
+    });
+    match result {
+        SystemCall::PageCacheMaintenance {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Change the memory type later mappings of the raw page `page` get.
+/// Returns `false` while the page is mapped anywhere; unmap it first.
+/// Pages retyped from device memory stay uncached, and return `false`
+/// for any other mode.
+pub fn page_set_cache_mode(page: CAddr, mode: CacheMode) -> bool {
+    let result = system_call(SystemCall::PageSetCacheMode {
+        request: (page, mode),
+        response: None
+    });
+    match result {
+        SystemCall::PageSetCacheMode {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+pub fn retype_cpool(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypeCPool {
+        request: (source, target),
+    });
+}
+
+/// Create a capability pool from `source` at `target`, with `size`
+/// entries, at most 256, and `guard`. Addresses reach its entries
+/// through its capability address, then `guard`, then an index below
+/// `size`. Returns `false` if `target` is not empty or the size or the
+/// guard is invalid.
+pub fn cpool_mint(source: CAddr, target: CAddr, size: usize, guard: CAddr) -> bool {
+    let result = system_call(SystemCall::CPoolMint {
+        request: (source, target, size, guard),
+        response: None
+    });
+    match result {
+        SystemCall::CPoolMint {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Copy the capability at `source` into the empty entry `target`,
+/// keeping only the rights of `source` that are also in `rights`.
+/// Returns `false` if `source` is empty or lacks `RIGHT_GRANT`, or
+/// `target` is not empty.
+pub fn cpool_copy_with_rights(source: CAddr, target: CAddr, rights: CapRights) -> bool {
+    let result = system_call(SystemCall::CPoolCopyWithRights {
+        request: (source, target, rights),
+        response: None
+    });
+    match result {
+        SystemCall::CPoolCopyWithRights {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Move the capability at `source`, with its rights, into the empty
+/// entry `target`, leaving `source` empty. Returns `false` if `source`
+/// is empty or `target` is not.
+pub fn cpool_move(source: CAddr, target: CAddr) -> bool {
+    let result = system_call(SystemCall::CPoolMove {
+        request: (source, target),
+        response: None
+    });
+    match result {
+        SystemCall::CPoolMove {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Exchange the capabilities, with their rights, at `first` and
+/// `second`. Returns `false` if either entry is empty.
+pub fn cpool_swap(first: CAddr, second: CAddr) -> bool {
+    let result = system_call(SystemCall::CPoolSwap {
+        request: (first, second),
+        response: None
+    });
+    match result {
+        SystemCall::CPoolSwap {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Delete the capability at `target`. If it was the last reference to
+/// its object, the object is torn down, like on revoke. Returns
+/// `false` if the entry is empty.
+pub fn cpool_delete(target: CAddr) -> bool {
+    let result = system_call(SystemCall::CPoolDelete {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::CPoolDelete {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Remove every capability to the object at `target`, wherever it was
+/// copied or sent, and tear the object down: a task stops, an address
+/// space frees its mappings. Its memory is reused once the untyped
+/// capability it came from is revoked. Returns `false` if the entry
+/// is empty or holds untyped memory, which is revoked with
+/// `untyped_revoke`.
+pub fn cpool_revoke(target: CAddr) -> bool {
+    let result = system_call(SystemCall::CPoolRevoke {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::CPoolRevoke {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create `count` objects of type `object` from the untyped capability
+/// `source`, into the entries of the capability pool `cpool` from
+/// `index`. `size_bits` is the number of entries of capability pools
+/// as a power of two, and must be 0 or the natural size of the other
+/// types. Returns the number of objects created, which is 0 if any
+/// entry is in use or out of the pool.
+pub fn untyped_retype(source: CAddr, object: ObjectType, size_bits: usize,
+                      cpool: CAddr, index: usize, count: usize) -> usize {
+    let result = system_call(SystemCall::UntypedRetype {
+        request: (source, object, size_bits, cpool, index, count),
+        response: None
+    });
+    match result {
+        SystemCall::UntypedRetype {
+            response, ..
+        } => { return response.unwrap_or(0); },
+        _ => panic!(),
+    };
+}
+
+/// Revoke every capability derived from the untyped capability
+/// `untyped`, and destroy the objects, unmapping them from all address
+/// spaces. Returns `false` if some objects are still in use by the
+/// kernel, for example the calling task or its buffer. They are then
+/// destroyed by a later revoke, and only then is the memory reused.
+pub fn untyped_revoke(untyped: CAddr) -> bool {
+    let result = system_call(SystemCall::UntypedRevoke {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::UntypedRevoke {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Create a quota of `limit` bytes from `untyped`, charged to
+/// `parent` as well if given. Returns its capability address, or
+/// `None` if the capability pool is full.
+pub fn retype_quota(untyped: CAddr, parent: Option<CAddr>, limit: usize) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeQuota {
+        request: (untyped, parent, limit),
+        response: None
+    });
+    match result {
+        SystemCall::RetypeQuota {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Limit the memory retyped from `untyped`, through any copy of it, to
+/// `quota`. An untyped capability that already has a quota only
+/// accepts that quota or one below it. Returns `false` otherwise.
+pub fn untyped_set_quota(untyped: CAddr, quota: CAddr) -> bool {
+    let result = system_call(SystemCall::UntypedSetQuota {
+        request: (untyped, quota),
+        response: None
+    });
+    match result {
+        SystemCall::UntypedSetQuota {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Limit and bytes used of `quota`.
+pub fn quota_info(quota: CAddr) -> Option<(usize, usize)> {
+    let result = system_call(SystemCall::QuotaInfo {
+        request: quota,
+        response: None
+    });
+    match result {
+        SystemCall::QuotaInfo {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+pub fn retype_channel(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypeChannel {
+        request: (source, target),
+    });
+}
+
+pub fn retype_task(source: CAddr, target: CAddr) {
+    system_call(SystemCall::RetypeTask {
+        request: (source, target),
+    });
+}
+
+pub fn task_set_instruction_pointer(target: CAddr, ptr: u64) {
+    system_call(SystemCall::TaskSetInstructionPointer {
+        request: (target, ptr),
+    });
+}
+
+pub fn task_set_stack_pointer(target: CAddr, ptr: u64) {
+    system_call(SystemCall::TaskSetStackPointer {
+        request: (target, ptr),
+    });
+}
+
+/// Set all user-mode registers of a stopped task, as a fault handler
+/// does after emulating the faulting instruction. Privileged flags are
+/// kept.
+pub fn task_set_registers(target: CAddr, registers: TaskRegisters) {
+    system_call(SystemCall::TaskSetRegisters {
+        request: (target, registers),
+    });
+}
+
+/// All user-mode registers of a task, including `rip`, `rsp` and
+/// `rflags`, as saved when it last entered the kernel. Returns `None`
+/// if `target` is not a task readable by the caller.
+pub fn task_get_registers(target: CAddr) -> Option<TaskRegisters> {
+    let result = system_call(SystemCall::TaskGetRegisters {
+        request: target,
+        response: None,
+    });
+    match result {
+        SystemCall::TaskGetRegisters { response, .. } => {
+            return response;
+        },
+        _ => panic!(),
+    };
+}
+
+/// Set the FS segment base of a task, for thread-local storage. It is
+/// loaded whenever the task runs. Returns `false` if it is not in user
+/// space.
+pub fn task_set_fs_base(target: CAddr, fs_base: u64) -> bool {
+    let result = system_call(SystemCall::TaskSetFsBase {
+        request: (target, fs_base),
+        response: None
+    });
+    match result {
+        SystemCall::TaskSetFsBase {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Install an LDT in a task, whose entries it can then load with LDT
+/// selectors. Returns `false` if either capability is invalid.
+pub fn task_set_ldt(target: CAddr, ldt: CAddr) -> bool {
+    let result = system_call(SystemCall::TaskSetLdt {
+        request: (target, ldt),
+        response: None
+    });
+    match result {
+        SystemCall::TaskSetLdt {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+pub fn task_set_cpool(target: CAddr, cpool: CAddr) {
+    system_call(SystemCall::TaskSetCPool {
+        request: (target, cpool),
+    });
+}
+
+pub fn task_set_top_page_table(target: CAddr, table: CAddr) {
+    system_call(SystemCall::TaskSetTopPageTable {
+        request: (target, table),
+    });
+}
+
+pub fn task_set_vspace(target: CAddr, vspace: CAddr) {
+    system_call(SystemCall::TaskSetVSpace {
+        request: (target, vspace),
+    });
+}
+
+pub fn task_set_buffer(target: CAddr, buffer: CAddr) {
+    system_call(SystemCall::TaskSetBuffer {
+        request: (target, buffer),
+    });
+}
+
+pub fn task_set_fault_handler(target: CAddr, channel: CAddr) {
+    system_call(SystemCall::TaskSetFaultHandler {
+        request: (target, channel),
+    });
+}
+
+pub fn task_set_debugger(target: CAddr, channel: CAddr) {
+    system_call(SystemCall::TaskSetDebugger {
+        request: (target, channel),
+    });
+}
+
+/// Set the first entry of the receive window of `target`, where
+/// capabilities granted to it are placed, or disable receiving them.
+/// The window spans `MESSAGE_CAPS` entries of one capability pool.
+pub fn task_set_receive_window(target: CAddr, window: Option<CAddr>) {
+    system_call(SystemCall::TaskSetReceiveWindow {
+        request: (target, window),
+    });
+}
+
+/// Set the priority of `target`. Tasks run by priority, and senders
+/// to an endpoint are received by priority. Returns `false` if
+/// `priority` is above the maximum priority of the caller.
+pub fn task_set_priority(target: CAddr, priority: u8) -> bool {
+    let result = system_call(SystemCall::TaskSetPriority {
+        request: (target, priority),
+        response: None,
+    });
+    match result {
+        SystemCall::TaskSetPriority { response, .. } => {
+            return response.unwrap_or(false);
+        },
+        _ => panic!(),
+    };
+}
+
+/// Set the maximum priority of `target`, the highest it may set for
+/// itself or for other tasks. Returns `false` if `max_priority` is
+/// above the maximum priority of the caller, so that no task gives out
+/// more than it has.
+pub fn task_set_max_priority(target: CAddr, max_priority: u8) -> bool {
+    let result = system_call(SystemCall::TaskSetMaxPriority {
+        request: (target, max_priority),
+        response: None,
+    });
+    match result {
+        SystemCall::TaskSetMaxPriority { response, .. } => {
+            return response.unwrap_or(false);
+        },
+        _ => panic!(),
+    };
+}
+
+/// Give the rest of the time slice to the other tasks of the same
+/// priority. Returns right away if there are none.
+pub fn task_yield() {
+    system_call(SystemCall::TaskYield);
+}
+
+/// Sleep until monotonic time `deadline_ns`, as `clock_gettime` with
+/// `Clock::Monotonic` reports it. The wakeup takes one of the timers
+/// userspace may arm; returns `false`, right away, if none is left.
+pub fn sleep_until(deadline_ns: u64) -> bool {
+    let result = system_call(SystemCall::SleepUntil {
+        request: deadline_ns,
+        response: None
+    });
+    match result {
+        SystemCall::SleepUntil {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Sleep for `duration_ns` nanoseconds. Returns `false` like
+/// `sleep_until`.
+pub fn sleep_for(duration_ns: u64) -> bool {
+    let result = system_call(SystemCall::SleepFor {
+        request: duration_ns,
+        response: None
+    });
+    match result {
+        SystemCall::SleepFor {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Restrict `target` to the CPUs in `affinity`, a bit for each local
+/// APIC id. Returns `false` if none of them is online.
+pub fn task_set_affinity(target: CAddr, affinity: u64) -> bool {
+    let result = system_call(SystemCall::TaskSetAffinity {
+        request: (target, affinity),
+        response: None,
+    });
+    match result {
+        SystemCall::TaskSetAffinity { response, .. } => {
+            return response.unwrap_or(false);
+        },
+        _ => panic!(),
+    };
+}
+
+pub fn task_set_breakpoint(target: CAddr, slot: u8, address: u64, kind: BreakpointKind, length: u8) -> bool {
+    let result = system_call(SystemCall::TaskSetBreakpoint {
+        request: (target, slot, address, kind, length),
+        response: None
+    });
+    match result {
+        SystemCall::TaskSetBreakpoint {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+pub fn task_clear_breakpoint(target: CAddr, slot: u8) -> bool {
+    let result = system_call(SystemCall::TaskClearBreakpoint {
+        request: (target, slot),
+        response: None
+    });
+    match result {
+        SystemCall::TaskClearBreakpoint {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+pub fn task_set_active(target: CAddr) {
+    system_call(SystemCall::TaskSetActive {
+        request: target
+    });
+}
+
+pub fn task_set_inactive(target: CAddr) {
+    system_call(SystemCall::TaskSetInactive {
+        request: target
+    });
+}
+
+/// End the calling task with exit `code`. Calls it took and did not
+/// reply to fail. Never returns.
+pub fn task_exit(code: u64) -> ! {
+    system_call(SystemCall::TaskExit {
+        request: code,
+    });
+    unreachable!()
+}
+
+/// Signal `notification` with `badge` when `target` exits, or nothing
+/// if `None`. Returns `false` if `notification` cannot be signalled by
+/// the caller.
+pub fn task_bind_exit_notification(target: CAddr, notification: Option<CAddr>, badge: u64) -> bool {
+    let result = system_call(SystemCall::TaskBindExitNotification {
+        request: (target, notification, badge),
+        response: None,
+    });
+    match result {
+        SystemCall::TaskBindExitNotification { response, .. } => {
+            return response.unwrap_or(false);
+        },
+        _ => panic!(),
+    };
+}
+
+/// Exit code of `target`, or `None` if it did not exit.
+pub fn task_exit_status(target: CAddr) -> Option<u64> {
+    let result = system_call(SystemCall::TaskExitStatus {
+        request: target,
+        response: None,
+    });
+    match result {
+        SystemCall::TaskExitStatus { response, .. } => {
+            return response;
+        },
+        _ => panic!(),
+    };
+}
+
+/// Keep `target` from running until `task_resume`. Unlike
+/// `task_set_inactive`, the task keeps what it was waiting for, so
+/// its registers can be read and written, and it goes on from where
+/// it was once resumed.
+pub fn task_suspend(target: CAddr) {
+    system_call(SystemCall::TaskSuspend {
+        request: target,
+    });
+}
+
+/// Let a task suspended by `task_suspend` run again.
+pub fn task_resume(target: CAddr) {
+    system_call(SystemCall::TaskResume {
+        request: target,
+    });
+}
+
+fn channel_take_nonpayload(target: CAddr) -> ChannelMessage {
+    let result = system_call(SystemCall::ChannelTake {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::ChannelTake {
+            response, ..
+        } => {
+            return response.unwrap()
+        },
+        _ => panic!(),
+    };
+}
+
+/// Put `value` to `target` along with the caller's scheduling
+/// context, and wait for the reply on `reply`. The task taking the
+/// value runs on the caller's time, until it waits on a channel again,
+/// which gives the time back. Returns `None` if nothing was sent.
+pub fn channel_donate_raw(target: CAddr, value: u64, reply: CAddr) -> Option<ChannelMessage> {
+    let result = system_call(SystemCall::ChannelDonate {
+        request: (target, ChannelMessage::Raw(value), reply),
+        response: None
+    });
+    match result {
+        SystemCall::ChannelDonate {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Create an endpoint from `untyped`. Returns its capability address,
+/// or `None` if the capability pool is full.
+pub fn retype_endpoint(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeEndpoint {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::RetypeEndpoint {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Put a send-once capability to `endpoint` at `target`, which must be
+/// empty. It is deleted once a receiver takes a message sent through
+/// it, and can be moved to another task, but not copied: sent as the
+/// capability of a message, it leaves the pool of the sender. Returns `false` if
+/// `target` is not empty.
+pub fn endpoint_mint_send_once(endpoint: CAddr, target: CAddr) -> bool {
+    let result = system_call(SystemCall::EndpointMintSendOnce {
+        request: (endpoint, target),
+        response: None
+    });
+    match result {
+        SystemCall::EndpointMintSendOnce {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Set whether tasks receiving a call from `endpoint` run at the
+/// priority of the caller, if higher, until they reply to it.
+pub fn endpoint_set_inheritance(endpoint: CAddr, inherit: bool) {
+    system_call(SystemCall::EndpointSetInheritance {
+        request: (endpoint, inherit),
+    });
+}
+
+/// Create a reply object from `untyped`, to receive calls through.
+/// Returns its capability address, or `None` if the capability pool
+/// is full.
+pub fn retype_reply(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeReply {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::RetypeReply {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Send `message` to `endpoint`, blocking until a receiver takes it.
+/// Senders are received in the order they blocked.
+pub fn endpoint_send(endpoint: CAddr, message: ChannelMessage) {
+    system_call(SystemCall::EndpointSend {
+        request: (endpoint, message)
+    });
+}
+
+/// Send `message` to `endpoint`, and wait for the reply of the
+/// receiver. Returns `None` if the call failed, the receiver had no
+/// reply object to receive it with, or it exited without replying.
+pub fn endpoint_call(endpoint: CAddr, message: ChannelMessage) -> Option<ChannelMessage> {
+    let result = system_call(SystemCall::EndpointCall {
+        request: (endpoint, message),
+        response: None
+    });
+    match result {
+        SystemCall::EndpointCall {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Send the word `word` to `endpoint`, as `endpoint_call` does with
+/// `ChannelMessage::Raw`. When a receiver is waiting, the kernel takes
+/// the call from registers and switches to the receiver directly.
+pub fn endpoint_call_fast(endpoint: CAddr, word: u64) -> Option<ChannelMessage> {
+    let result = system_call_fastpath(endpoint, word);
+    match result {
+        SystemCall::EndpointCall {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Send `value` to `endpoint` as a payload, copied by the kernel from
+/// the caller's task buffer into the receiver's, and wait for the
+/// reply. Returns `None` if the call failed, or the reply is not a
+/// payload of type `R`.
+pub fn endpoint_call_payload<T: Any, R: Any + Clone>(endpoint: CAddr, value: T) -> Option<R> {
+    let result = system_call_put_payload(SystemCall::EndpointCall {
+        request: (endpoint, ChannelMessage::Payload),
+        response: None
+    }, value);
+    match result {
+        SystemCall::EndpointCall {
+            response: Some(ChannelMessage::Payload), ..
+        } => received_payload(),
+        SystemCall::EndpointCall { .. } => None,
+        _ => panic!(),
+    }
+}
+
+/// Send `value` to `endpoint`, lending it the shared frame set
+/// `frames`, and wait for the reply. The frames are mapped in the lend
+/// window of the receiver's reply object, writable if `writable`,
+/// until it replies. Returns `None` if the call failed.
+pub fn endpoint_call_lend(endpoint: CAddr, value: u64, frames: CAddr, writable: bool) -> Option<ChannelMessage> {
+    endpoint_call(endpoint, ChannelMessage::Lend(value, FrameLoan {
+        frames: Some(frames),
+        writable: writable,
+        address: None,
+    }))
+}
+
+/// Receive a message from `endpoint`, blocking until a task sends one.
+/// If the message came from a call, the caller is bound to `reply`,
+/// which replies to it once. Returns `None` if the endpoint cannot be
+/// received from.
+pub fn endpoint_receive(endpoint: CAddr, reply: Option<CAddr>) -> Option<ChannelMessage> {
+    let result = system_call(SystemCall::EndpointReceive {
+        request: (endpoint, reply),
+        response: None
+    });
+    match result {
+        SystemCall::EndpointReceive {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Reply `message` to the caller bound to `reply`, which runs again.
+/// Returns `false` if no caller waits for a reply through it.
+pub fn endpoint_reply(reply: CAddr, message: ChannelMessage) -> bool {
+    let result = system_call(SystemCall::EndpointReply {
+        request: (reply, message),
+        response: None
+    });
+    match result {
+        SystemCall::EndpointReply {
             response, ..
-        } => { return response.unwrap(); },
+        } => { return response.unwrap_or(false); },
         _ => panic!(),
     };
 }
 
-pub fn map_raw_page_free(vaddr: usize, untyped: CAddr, toplevel_table: CAddr, page: CAddr) {
-    system_call(SystemCall::MapRawPageFree {
-        untyped: untyped,
-        toplevel_table: toplevel_table,
-        request: (vaddr, page),
+/// Reply `value` as a payload to the caller bound to `reply`. Returns
+/// `false` if no caller waits for a reply through it.
+pub fn endpoint_reply_payload<T: Any>(reply: CAddr, value: T) -> bool {
+    let result = system_call_put_payload(SystemCall::EndpointReply {
+        request: (reply, ChannelMessage::Payload),
+        response: None
+    }, value);
+    match result {
+        SystemCall::EndpointReply {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
+}
+
+/// Forward the call bound to `reply` to `endpoint` with `message`,
+/// blocking until a receiver takes it. The receiver then replies to
+/// the original caller directly, and `reply` is free again. Returns
+/// `false` if no caller waits for a reply through `reply`, or the
+/// receiver had no reply object to take the call with.
+pub fn endpoint_forward(reply: CAddr, endpoint: CAddr, message: ChannelMessage) -> bool {
+    let result = system_call(SystemCall::EndpointForward {
+        request: (reply, endpoint, message),
+        response: None
     });
+    match result {
+        SystemCall::EndpointForward {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
 }
 
-pub fn retype_cpool(source: CAddr, target: CAddr) {
-    system_call(SystemCall::RetypeCPool {
-        request: (source, target),
+/// Map frames lent by callers received with `reply` in `window`, of
+/// a VSpace, an address and a number of pages, or stop accepting
+/// them. Returns `false` while frames are lent through the reply
+/// object, or if a capability is missing.
+pub fn reply_set_lend_window(reply: CAddr, window: Option<(CAddr, u64, usize)>) -> bool {
+    let result = system_call(SystemCall::ReplySetLendWindow {
+        request: (reply, window),
+        response: None
     });
+    match result {
+        SystemCall::ReplySetLendWindow {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
 }
 
-pub fn retype_task(source: CAddr, target: CAddr) {
-    system_call(SystemCall::RetypeTask {
-        request: (source, target),
+/// Payload of the last `ChannelMessage::Payload` received into the
+/// task buffer, if it is of type `T`.
+pub fn received_payload<T: Any + Clone>() -> Option<T> {
+    use core::mem::{size_of};
+    let addr = task_buffer_addr();
+
+    unsafe {
+        let buffer = &*(addr as *const TaskBuffer);
+        if buffer.payload_length != size_of::<T>() {
+            return None;
+        }
+        let payload_addr = &buffer.payload_data as *const _ as *const T;
+        Some((*payload_addr).clone())
+    }
+}
+
+/// Create a notification, with a clear word, from `untyped`. Returns
+/// its capability address, or `None` if the capability pool is full.
+pub fn retype_notification(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeNotification {
+        request: untyped,
+        response: None
     });
+    match result {
+        SystemCall::RetypeNotification {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
 }
 
-pub fn task_set_instruction_pointer(target: CAddr, ptr: u64) {
-    system_call(SystemCall::TaskSetInstructionPointer {
-        request: (target, ptr),
+/// Create an empty notification group from `untyped`. Returns its
+/// capability address, or `None` if the capability pool is full.
+pub fn retype_notification_group(untyped: CAddr) -> Option<CAddr> {
+    let result = system_call(SystemCall::RetypeNotificationGroup {
+        request: untyped,
+        response: None
     });
+    match result {
+        SystemCall::RetypeNotificationGroup {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
 }
 
-pub fn task_set_stack_pointer(target: CAddr, ptr: u64) {
-    system_call(SystemCall::TaskSetStackPointer {
-        request: (target, ptr),
+/// Add `notification` to `group`. Returns `false` if the group is
+/// full, or a capability is missing.
+pub fn notification_group_add(group: CAddr, notification: CAddr) -> bool {
+    let result = system_call(SystemCall::NotificationGroupAdd {
+        request: (group, notification),
+        response: None
     });
+    match result {
+        SystemCall::NotificationGroupAdd {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
 }
 
-pub fn task_set_cpool(target: CAddr, cpool: CAddr) {
-    system_call(SystemCall::TaskSetCPool {
-        request: (target, cpool),
+/// Remove `notification` from `group`. Both need `RIGHT_WRITE`.
+/// Returns `false` if it was not in it.
+pub fn notification_group_remove(group: CAddr, notification: CAddr) -> bool {
+    let result = system_call(SystemCall::NotificationGroupRemove {
+        request: (group, notification),
+        response: None
     });
+    match result {
+        SystemCall::NotificationGroupRemove {
+            response, ..
+        } => { return response.unwrap_or(false); },
+        _ => panic!(),
+    };
 }
 
-pub fn task_set_top_page_table(target: CAddr, table: CAddr) {
-    system_call(SystemCall::TaskSetTopPageTable {
-        request: (target, table),
+/// Or `badge` into the word of every notification in `group`, waking
+/// all the tasks waiting on them. Never blocks.
+pub fn notification_group_signal(group: CAddr, badge: u64) {
+    system_call(SystemCall::NotificationGroupSignal {
+        request: (group, badge)
     });
 }
 
-pub fn task_set_buffer(target: CAddr, buffer: CAddr) {
-    system_call(SystemCall::TaskSetBuffer {
-        request: (target, buffer),
+/// Or `badge` into the word of `notification`. Never blocks.
+pub fn notification_signal(notification: CAddr, badge: u64) {
+    system_call(SystemCall::NotificationSignal {
+        request: (notification, badge)
     });
 }
 
-pub fn task_set_active(target: CAddr) {
-    system_call(SystemCall::TaskSetActive {
-        request: target
+/// Wait until the word of `notification` is not zero, and take it,
+/// clearing it. Returns `None` if the notification cannot be waited
+/// on.
+pub fn notification_wait(notification: CAddr) -> Option<u64> {
+    let result = system_call(SystemCall::NotificationWait {
+        request: notification,
+        response: None
     });
+    match result {
+        SystemCall::NotificationWait {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
 }
 
-pub fn task_set_inactive(target: CAddr) {
-    system_call(SystemCall::TaskSetInactive {
-        request: target
+/// Wait like `notification_wait`, but only until monotonic time
+/// `deadline_ns`. Returns `Some(0)` if the deadline passed first, and
+/// `None`, right away, if no timer userspace may arm is left to wake
+/// the task at the deadline.
+pub fn notification_wait_until(notification: CAddr, deadline_ns: u64) -> Option<u64> {
+    let result = system_call(SystemCall::NotificationWaitUntil {
+        request: (notification, deadline_ns),
+        response: None
     });
+    match result {
+        SystemCall::NotificationWaitUntil {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
 }
 
-fn channel_take_nonpayload(target: CAddr) -> ChannelMessage {
-    let result = system_call(SystemCall::ChannelTake {
-        request: target,
+/// Bind `notification` to `target`, which then also takes its word
+/// while receiving from an endpoint, as a `ChannelMessage::Notification`.
+/// `None` unbinds the notification bound before.
+pub fn task_bind_notification(target: CAddr, notification: Option<CAddr>) -> bool {
+    let result = system_call(SystemCall::TaskBindNotification {
+        request: (target, notification),
         response: None
     });
     match result {
-        SystemCall::ChannelTake {
+        SystemCall::TaskBindNotification {
             response, ..
-        } => {
-            return response.unwrap()
-        },
+        } => { return response.unwrap_or(false); },
         _ => panic!(),
     };
 }
@@ -108,6 +1882,41 @@ pub fn channel_take_cap(target: CAddr) -> CAddr {
     };
 }
 
+/// Take a value with capabilities. The capabilities are in the
+/// receive window entries listed in the transfer.
+pub fn channel_take_grant(target: CAddr) -> (u64, CapTransfer) {
+    let result = channel_take_nonpayload(target);
+    match result {
+        ChannelMessage::Grant(v, transfer) => return (v, transfer),
+        _ => panic!(),
+    };
+}
+
+pub fn channel_take_page_fault(target: CAddr) -> PageFaultInfo {
+    let result = channel_take_nonpayload(target);
+    match result {
+        ChannelMessage::PageFault(v) => return v,
+        _ => panic!(),
+    };
+}
+
+/// Take a CPU fault of a task from its fault handler channel.
+pub fn channel_take_fault(target: CAddr) -> FaultInfo {
+    let result = channel_take_nonpayload(target);
+    match result {
+        ChannelMessage::Fault(v) => return v,
+        _ => panic!(),
+    };
+}
+
+pub fn channel_take_debug(target: CAddr) -> DebugEvent {
+    let result = channel_take_nonpayload(target);
+    match result {
+        ChannelMessage::Debug(v) => return v,
+        _ => panic!(),
+    };
+}
+
 pub fn channel_take<T: Any + Clone>(target: CAddr) -> T {
     let (result, payload) = system_call_take_payload(SystemCall::ChannelTake {
         request: target,
@@ -124,6 +1933,40 @@ pub fn channel_take<T: Any + Clone>(target: CAddr) -> T {
     };
 }
 
+pub fn channel_poll_raw(target: CAddr) -> Option<u64> {
+    let result = system_call(SystemCall::ChannelPoll {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::ChannelPoll {
+            response: Some(ChannelMessage::Raw(v)), ..
+        } => Some(v),
+        SystemCall::ChannelPoll {
+            response: None, ..
+        } => None,
+        _ => panic!(),
+    }
+}
+
+pub fn channel_poll<T: Any + Clone>(target: CAddr) -> Option<T> {
+    let (result, payload) = system_call_poll_payload(SystemCall::ChannelPoll {
+        request: target,
+        response: None
+    });
+    match result {
+        SystemCall::ChannelPoll {
+            request: _,
+            response: Some(ChannelMessage::Payload),
+        } => payload,
+        SystemCall::ChannelPoll {
+            request: _,
+            response: None,
+        } => None,
+        _ => panic!(),
+    }
+}
+
 pub fn channel_put_raw(target: CAddr, value: u64) {
     system_call(SystemCall::ChannelPut {
         request: (target, ChannelMessage::Raw(value))
@@ -136,12 +1979,159 @@ pub fn channel_put_cap(target: CAddr, value: CAddr) {
     });
 }
 
+/// Put `value` with up to `MESSAGE_CAPS` capabilities. Unless `copy`,
+/// the capabilities are moved out of the caller's capability pools.
+/// Nothing is sent if any of them is missing, or if the channel lacks
+/// `RIGHT_GRANT`.
+pub fn channel_put_grant(target: CAddr, value: u64, caps: &[CAddr], copy: bool) {
+    assert!(caps.len() <= MESSAGE_CAPS);
+    let mut transfer = CapTransfer { caps: [None; MESSAGE_CAPS], copy: copy };
+    for (i, cap) in caps.iter().enumerate() {
+        transfer.caps[i] = Some(*cap);
+    }
+    system_call(SystemCall::ChannelPut {
+        request: (target, ChannelMessage::Grant(value, transfer))
+    });
+}
+
 pub fn channel_put<T: Any + Clone>(target: CAddr, value: T) {
     system_call_put_payload(SystemCall::ChannelPut {
         request: (target, ChannelMessage::Payload)
     }, value);
 }
 
+pub fn timer_ticks() -> u64 {
+    let result = system_call(SystemCall::TimerTicks {
+        response: None
+    });
+    match result {
+        SystemCall::TimerTicks {
+            response
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+/// Current time of `clock`.
+pub fn clock_gettime(clock: Clock) -> TimeSpec {
+    let result = system_call(SystemCall::ClockGetTime {
+        request: clock,
+        response: None
+    });
+    match result {
+        SystemCall::ClockGetTime {
+            response, ..
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+pub fn virt_to_phys(toplevel_table: CAddr, vaddr: usize) -> Option<(usize, MapAttributes)> {
+    let result = system_call(SystemCall::VirtToPhys {
+        request: (toplevel_table, vaddr),
+        response: None
+    });
+    match result {
+        SystemCall::VirtToPhys {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+pub fn kernel_info() -> KernelInfo {
+    let result = system_call(SystemCall::KernelInfo {
+        response: None
+    });
+    match result {
+        SystemCall::KernelInfo {
+            response
+        } => { return response.unwrap(); },
+        _ => panic!(),
+    };
+}
+
+/// Physical memory usage by category. `untyped` must be an untyped
+/// capability, as only tasks managing memory may query it. Returns
+/// `None` otherwise.
+pub fn mem_info(untyped: CAddr) -> Option<MemInfo> {
+    let result = system_call(SystemCall::MemInfo {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::MemInfo {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Endpoint calls taken on the fastpath, and those that fell back to
+/// the general path. `untyped` must be an untyped capability, as for
+/// `mem_info`. Returns `None` otherwise.
+pub fn ipc_stats(untyped: CAddr) -> Option<IpcStats> {
+    let result = system_call(SystemCall::IpcStats {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::IpcStats {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Interrupt statistics of the CPU with id `cpu`, for the
+/// `IRQ_STATS_VECTORS` vectors from `first_vector`. `untyped` must be
+/// an untyped capability, as for `mem_info`. Returns `None` otherwise,
+/// or if the CPU is not online.
+pub fn irq_stats(untyped: CAddr, cpu: usize, first_vector: u8) -> Option<IrqStats> {
+    let result = system_call(SystemCall::IrqStats {
+        request: (untyped, cpu, first_vector),
+        response: None
+    });
+    match result {
+        SystemCall::IrqStats {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// List up to `CAP_DUMP_ENTRIES` capabilities reachable from the pools
+/// of `task`, from position `start` of the listing. `untyped` must be
+/// an untyped capability, as for `mem_info`. Returns `None` otherwise,
+/// or if the task has no capability pool.
+pub fn debug_cap_dump(untyped: CAddr, task: CAddr, start: usize) -> Option<CapDump> {
+    let result = system_call(SystemCall::CapDump {
+        request: (untyped, task, start),
+        response: None
+    });
+    match result {
+        SystemCall::CapDump {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
+/// Kernel objects currently alive, by type. `untyped` must be an
+/// untyped capability, as for `mem_info`. Returns `None` otherwise.
+pub fn object_counts(untyped: CAddr) -> Option<ObjectCounts> {
+    let result = system_call(SystemCall::ObjectCounts {
+        request: untyped,
+        response: None
+    });
+    match result {
+        SystemCall::ObjectCounts {
+            response, ..
+        } => { return response; },
+        _ => panic!(),
+    };
+}
+
 pub fn print(buffer: [u8; 32], size: usize) {
     let _ = system_call(SystemCall::Print {
         request: (buffer, size)
@@ -165,6 +2155,29 @@ pub fn debug_test_fail() {
     loop {}
 }
 
+/// Context switch and IPC round trip cycle counts of the CPU with id
+/// `cpu`, or `None` if it is not online.
+#[cfg(feature="kernel_debug")]
+pub fn debug_switch_stats(cpu: usize) -> Option<SwitchStats> {
+    let result = system_call(SystemCall::DebugSwitchStats {
+        request: cpu,
+        response: None,
+    });
+    match result {
+        SystemCall::DebugSwitchStats { response, .. } => {
+            return response;
+        },
+        _ => panic!(),
+    };
+}
+
+/// First capability error of the last system call: why one of its
+/// capability addresses could not be used. `None` if all could.
+pub fn last_cap_error() -> Option<CapError> {
+    let addr = task_buffer_addr();
+    unsafe { (*(addr as *const TaskBuffer)).cap_error }
+}
+
 fn system_call(message: SystemCall) -> SystemCall {
     let addr = task_buffer_addr();
     unsafe {
@@ -175,10 +2188,31 @@ fn system_call(message: SystemCall) -> SystemCall {
     }
 }
 
+/// Make an endpoint call of one word, which the kernel takes from
+/// registers on the fastpath. The call is in the task buffer too, for
+/// when the fastpath does not apply.
+fn system_call_fastpath(endpoint: CAddr, word: u64) -> SystemCall {
+    let addr = task_buffer_addr();
+    let mut bytes = 0;
+    for (i, byte) in endpoint.0.iter().enumerate() {
+        bytes |= (*byte as u64) << (i * 8);
+    }
+    unsafe {
+        let buffer = &mut *(addr as *mut TaskBuffer);
+        buffer.call = Some(SystemCall::EndpointCall {
+            request: (endpoint, ChannelMessage::Raw(word)),
+            response: None
+        });
+        system_call_fastpath_raw(bytes, endpoint.1 as u64, word);
+        buffer.call.take().unwrap()
+    }
+}
+
 fn system_call_put_payload<T: Any>(message: SystemCall, payload: T) -> SystemCall {
     use core::mem::{size_of};
     let addr = task_buffer_addr();
 
+    assert!(size_of::<T>() <= MESSAGE_LENGTH_MAX);
     unsafe {
         let buffer = &mut *(addr as *mut TaskBuffer);
         buffer.call = Some(message);
@@ -211,11 +2245,52 @@ fn system_call_take_payload<T: Any + Clone>(message: SystemCall) -> (SystemCall,
     }
 }
 
+fn system_call_poll_payload<T: Any + Clone>(message: SystemCall) -> (SystemCall, Option<T>) {
+    use core::mem::{size_of};
+    let addr = task_buffer_addr();
+
+    unsafe {
+        let buffer = &mut *(addr as *mut TaskBuffer);
+        buffer.call = Some(message);
+        buffer.payload_length = 0;
+
+        system_call_raw();
+
+        if buffer.payload_length == 0 {
+            return (buffer.call.take().unwrap(), None);
+        }
+
+        let payload_addr = &mut buffer.payload_data as *mut _ as *mut T;
+        let payload_data = &*payload_addr;
+        assert!(buffer.payload_length == size_of::<T>());
+
+        (buffer.call.take().unwrap(), Some(payload_data.clone()))
+    }
+}
+
+/// Enter the kernel with `syscall`, which clobbers RCX and R11. The
+/// call itself is in the task buffer, which RAX being zero tells the
+/// kernel.
 #[inline(never)]
 unsafe fn system_call_raw() {
-    asm!("int 80h"
+    asm!("xor eax, eax
+          syscall"
          ::
          : "rax", "rbx", "rcx", "rdx",
          "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
          : "volatile", "intel");
 }
+
+/// Enter the kernel with `syscall` for a `FASTPATH_CALL`. The kernel
+/// returns with the registers as they were, but for RCX and R11.
+#[inline(never)]
+unsafe fn system_call_fastpath_raw(endpoint: u64, depth: u64, word: u64) {
+    asm!("mov rax, $0
+          mov rdi, $1
+          mov rsi, $2
+          mov rdx, $3
+          syscall"
+         :: "i"(FASTPATH_CALL), "r"(endpoint), "r"(depth), "r"(word)
+         : "rax", "rcx", "rdx", "rsi", "rdi", "r11", "memory"
+         : "volatile", "intel");
+}
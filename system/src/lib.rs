@@ -19,17 +19,64 @@ pub mod unwind;
 mod call;
 
 #[cfg(feature="kernel_debug")]
-pub use self::call::{debug_cpool_list, debug_test_succeed, debug_test_fail};
-
-pub use self::call::{retype_cpool, retype_task,
-                     channel_put, channel_take,
-                     channel_put_raw, channel_take_raw,
-                     channel_put_cap, channel_take_cap,
-                     retype_raw_page_free, map_raw_page_free,
-                     task_set_stack_pointer, task_set_instruction_pointer,
-                     task_set_cpool, task_set_top_page_table, task_set_buffer,
-                     task_set_active, task_set_inactive};
-pub use abi::{CAddr, ChannelMessage};
+pub use self::call::{debug_cpool_list, debug_test_succeed, debug_test_fail, debug_switch_stats};
+
+pub use self::call::{retype_cpool, cpool_mint, cpool_copy_with_rights,
+                     cpool_move, cpool_swap, cpool_delete, cpool_revoke, untyped_retype, untyped_revoke,
+                     retype_quota, untyped_set_quota, quota_info,
+                     retype_task, retype_channel,
+                     channel_put, channel_take, channel_poll,
+                     channel_put_raw, channel_take_raw, channel_poll_raw,
+                     channel_put_cap, channel_take_cap, channel_put_grant, channel_take_grant, channel_take_page_fault, channel_take_fault,
+                     channel_take_debug, channel_donate_raw,
+                     retype_endpoint, retype_reply, endpoint_set_inheritance, endpoint_mint_send_once, endpoint_send, endpoint_call, endpoint_call_fast, endpoint_call_payload, endpoint_call_lend,
+                     endpoint_receive, endpoint_reply, endpoint_reply_payload, endpoint_forward, reply_set_lend_window, received_payload,
+                     retype_notification, notification_signal, notification_wait, notification_wait_until, task_bind_notification,
+                     retype_notification_group, notification_group_add, notification_group_remove,
+                     notification_group_signal,
+                     retype_raw_page_free, map_raw_page_free, map_raw_page_cow,
+                     map_demand_zero,
+                     retype_large_page, map_large_page,
+                     large_page_split, large_page_merge,
+                     cache_maintenance, page_cache_maintenance, page_set_cache_mode, virt_to_phys,
+                     pmem_page, pmem_flush, pmem_fence,
+                     device_untyped_retype, device_untyped_info,
+                     retype_dma, dma_page,
+                     asid_control_make_pool, retype_vspace, vspace_map, vspace_unmap, vspace_destroy, vspace_remap, boot_image_load,
+                     vspace_harvest, vspace_track_writes,
+                     retype_shared_frame_set, shared_frame_set_map, shared_frame_set_unmap,
+                     shared_frame_set_release, retype_ipc_ring, ipc_ring_bind, ipc_ring_notify,
+                     irq_control_get_msi, msi_message, msi_set_affinity,
+                     irq_control_get_handler, irq_handler_bind, irq_handler_bind_notification, irq_handler_ack, irq_handler_set_affinity,
+                     cpu_control_offline, cpu_control_online,
+                     retype_timer, timer_bind, timer_bind_notification, timer_arm, timer_cancel,
+                     retype_sched_context, sched_context_configure, sched_context_bind, sched_context_unbind,
+                     retype_ldt, ldt_set_entry,
+                     io_port_issue, io_port_in8, io_port_in16, io_port_in32,
+                     io_port_out8, io_port_out16, io_port_out32,
+                     kernel_log_read, kernel_log_write, ipc_trace_set, ipc_trace_read,
+                     task_set_stack_pointer, task_set_instruction_pointer, task_set_registers, task_get_registers,
+                     task_set_fs_base, task_set_ldt,
+                     task_set_cpool, task_set_top_page_table, task_set_vspace, task_set_buffer,
+                     task_set_fault_handler, task_set_debugger, task_set_receive_window,
+                     task_set_priority, task_set_max_priority, task_yield, sleep_until, sleep_for, task_set_affinity,
+                     task_set_breakpoint, task_clear_breakpoint,
+                     task_set_active, task_set_inactive, task_suspend, task_resume,
+                     task_exit, task_bind_exit_notification, task_exit_status,
+                     timer_ticks, clock_gettime, kernel_info, mem_info, ipc_stats, irq_stats,
+                     debug_cap_dump, object_counts, last_cap_error};
+pub use abi::{CAddr, ChannelMessage, CapTransfer, FrameLoan, MESSAGE_CAPS, MESSAGE_LENGTH_MAX, PRIORITY_DEFAULT, PRIORITY_MAX, CacheOperation, CacheMode, ObjectType, KernelInfo, MemInfo, IpcStats, PageFaultInfo,
+              FaultInfo, FaultKind, TaskRegisters,
+              BreakpointKind, DebugEvent, DebugEventKind,
+              IrqStats, IRQ_STATS_VECTORS, CycleStats, SwitchStats, Clock, TimeSpec,
+              MapAttributes, SoftDirtyRing, SOFT_DIRTY_RING_ENTRIES, IpcRingHeader, IpcRingSlot, IPC_RING_SLOTS,
+              BootInfo, SlotRegion, RegionInfo, BOOT_INFO_VERSION, BOOT_INFO_VADDR,
+              BOOT_CPOOL, BOOT_TASK, BOOT_TOP_PAGE_TABLE, BOOT_INFO_FRAME, BOOT_SCHED_CONTEXT, UNTYPED_FIRST, UNTYPED_COUNT,
+              DEVICE_UNTYPED_FIRST, DEVICE_UNTYPED_COUNT, IO_PORT_ALL, KERNEL_LOG, IRQ_CONTROL, ASID_CONTROL, CPU_CONTROL, BOOT_IMAGE, KERNEL_LOG_WRITE_LENGTH,
+              CapType, CapInfo, CapDump, ObjectCounts, CAP_TYPE_COUNT, CAP_DUMP_ENTRIES,
+              IpcKind, IpcTraceEntry, IpcTrace, IPC_TRACE_ENTRIES,
+              CapRights, CapError, RIGHT_READ, RIGHT_WRITE, RIGHT_GRANT, RIGHT_MAP, RIGHT_SEND_ONCE, RIGHTS_NONE, RIGHTS_ALL,
+              FEATURE_SMP, FEATURE_KPTI, FEATURE_TRACING, FEATURE_VMX, FEATURE_PCID};
 
 use core::fmt;
 
@@ -59,6 +106,14 @@ pub unsafe fn set_task_buffer_addr(addr: usize) {
     write(loc, addr);
 }
 
+/// Boot information of the initial task. It is only mapped in the
+/// address space the kernel created for the initial task.
+pub fn boot_info() -> &'static BootInfo {
+    let info = unsafe { &*(BOOT_INFO_VADDR as *const BootInfo) };
+    assert!(info.version == BOOT_INFO_VERSION);
+    info
+}
+
 pub struct PrintWriter {
     buffer: [u8; 32],
     size: usize
@@ -17,7 +17,8 @@ use system::{CAddr};
 #[allow(private_no_mangle_fns)]
 fn start(_argc: isize, _argv: *const *const u8) {
     unsafe { system::set_task_buffer_addr(0x90001000); }
-    unsafe { selfalloc::setup_allocator(CAddr::from(2), CAddr::from(3), 0x1000000000); }
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    unsafe { selfalloc::setup_allocator(untyped, CAddr::from(system::BOOT_TOP_PAGE_TABLE), 0x1000000000); }
 
     // Test allocator
     {
@@ -0,0 +1,134 @@
+#![feature(lang_items)]
+#![feature(asm)]
+#![feature(const_fn)]
+#![feature(unique)]
+#![no_std]
+
+#[macro_use]
+extern crate system;
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use system::CAddr;
+
+/// Number of CPUs the kernel supports.
+const MAX_CPUS: usize = 16;
+
+/// Yields the client waits for the worker to make progress before it
+/// gives up.
+const PATIENCE: usize = 100_000;
+
+const WORKER_TASK: u8 = 249;
+const WORKER_SCHED_CONTEXT: u8 = 246;
+
+/// Rounds of the worker so far.
+static PROGRESS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static mut IS_CLIENT: bool = true;
+
+#[lang="start"]
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+fn start(_argc: isize, _argv: *const *const u8) {
+    if unsafe { IS_CLIENT } {
+        unsafe { IS_CLIENT = false; }
+        client_main();
+    } else {
+        worker_main();
+    }
+}
+
+/// Wait until the worker went through another round, wherever it
+/// runs.
+fn wait_for_progress() {
+    let start = PROGRESS.load(Ordering::SeqCst);
+    for _ in 0..PATIENCE {
+        if PROGRESS.load(Ordering::SeqCst) != start {
+            return;
+        }
+        system::task_yield();
+    }
+    system_print!("worker stuck after {} rounds", start);
+    system::debug_test_fail();
+}
+
+fn client_main() {
+    unsafe { system::set_task_buffer_addr(0x90001000); }
+    let control = CAddr::from(system::CPU_CONTROL);
+
+    // The bootstrap CPU stays online, and only a parked CPU can be
+    // brought back.
+    if system::cpu_control_offline(control, 0) || system::cpu_control_online(control, 0) {
+        system::debug_test_fail();
+    }
+    // The test runs on two CPUs at least.
+    if system::kernel_info().features & system::FEATURE_SMP == 0 {
+        system_print!("only the bootstrap CPU is online");
+        system::debug_test_fail();
+        return;
+    }
+
+    // The client stays on the bootstrap CPU, as a CPU cannot take
+    // itself offline.
+    if !system::task_set_affinity(CAddr::from(system::BOOT_TASK), 1) {
+        system::debug_test_fail();
+    }
+    system::task_yield();
+
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    let worker = CAddr::from(WORKER_TASK);
+    system::retype_task(untyped, worker);
+    system::untyped_retype(untyped, system::ObjectType::SchedContext, 0,
+                           CAddr::from(system::BOOT_CPOOL), WORKER_SCHED_CONTEXT as usize, 1);
+    system::task_set_stack_pointer(worker, 0x70000000 + (0x1000 * 4 - 4));
+    system::task_set_instruction_pointer(worker, start as *const () as u64);
+    system::task_set_cpool(worker, CAddr::from(system::BOOT_CPOOL));
+    system::task_set_top_page_table(worker, CAddr::from(system::BOOT_TOP_PAGE_TABLE));
+    system::task_set_buffer(worker, CAddr::from(250));
+    system::sched_context_bind(CAddr::from(WORKER_SCHED_CONTEXT), worker);
+
+    // The worker is pinned to the first online secondary CPU, which
+    // then hands it over when taken offline.
+    let cpu = match (1..MAX_CPUS).find(|&cpu| system::task_set_affinity(worker, 1 << cpu)) {
+        Some(cpu) => cpu,
+        None => {
+            system::debug_test_fail();
+            return;
+        },
+    };
+    system::task_set_active(worker);
+    wait_for_progress();
+
+    if !system::cpu_control_offline(control, cpu) || system::cpu_control_offline(control, cpu) {
+        system::debug_test_fail();
+    }
+    wait_for_progress();
+    wait_for_progress();
+
+    if !system::cpu_control_online(control, cpu) || system::cpu_control_online(control, cpu) {
+        system::debug_test_fail();
+    }
+    // The CPU is online once it checked its TSC, which it may not
+    // have recorded yet.
+    let mut waited = 0;
+    while !system::task_set_affinity(worker, 1 << cpu) {
+        waited += 1;
+        if waited == PATIENCE {
+            system::debug_test_fail();
+        }
+        system::task_yield();
+    }
+    wait_for_progress();
+    wait_for_progress();
+
+    system_print!("CPU {} went offline and back with the worker running", cpu);
+    system::debug_test_succeed();
+}
+
+fn worker_main() {
+    unsafe { system::set_task_buffer_addr(0x90003000); }
+
+    loop {
+        PROGRESS.fetch_add(1, Ordering::SeqCst);
+        system::task_yield();
+    }
+}
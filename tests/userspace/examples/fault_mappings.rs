@@ -0,0 +1,75 @@
+#![feature(lang_items)]
+#![feature(asm)]
+#![feature(const_fn)]
+#![feature(unique)]
+#![no_std]
+
+extern crate system;
+
+use core::ptr;
+use system::CAddr;
+
+const PAGE_LENGTH: usize = 0x1000;
+
+/// Page written before it is mapped copy-on-write.
+const SOURCE_VADDR: usize = 0x50000000;
+/// Copy-on-write mappings of the source page, in the same address
+/// space.
+const COW_VADDRS: [usize; 2] = [0x50001000, 0x50002000];
+/// Demand-zero ranges, reserved one after the other in the same
+/// address space.
+const DEMAND_ZERO_VADDRS: [usize; 2] = [0x50010000, 0x50020000];
+
+fn read(vaddr: usize) -> u64 {
+    unsafe { ptr::read_volatile(vaddr as *const u64) }
+}
+
+fn write(vaddr: usize, value: u64) {
+    unsafe { ptr::write_volatile(vaddr as *mut u64, value) }
+}
+
+fn check(vaddr: usize, value: u64) {
+    if read(vaddr) != value {
+        system::debug_test_fail();
+    }
+}
+
+#[lang="start"]
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+fn start(_argc: isize, _argv: *const *const u8) {
+    unsafe { system::set_task_buffer_addr(0x90001000); }
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    let top = CAddr::from(system::BOOT_TOP_PAGE_TABLE);
+
+    let source = system::retype_raw_page_free(untyped);
+    system::map_raw_page_free(SOURCE_VADDR, untyped, top, source);
+    write(SOURCE_VADDR, 0xc0ffee);
+
+    // Each mapping sets the frames faults are resolved with again.
+    for &vaddr in COW_VADDRS.iter() {
+        system::map_raw_page_cow(vaddr, untyped, top, source);
+    }
+    for &vaddr in COW_VADDRS.iter() {
+        check(vaddr, 0xc0ffee);
+    }
+    for (i, &vaddr) in COW_VADDRS.iter().enumerate() {
+        write(vaddr, i as u64);
+    }
+    for (i, &vaddr) in COW_VADDRS.iter().enumerate() {
+        check(vaddr, i as u64);
+    }
+    check(SOURCE_VADDR, 0xc0ffee);
+
+    for &vaddr in DEMAND_ZERO_VADDRS.iter() {
+        system::map_demand_zero(vaddr, 2, untyped, top);
+    }
+    for &vaddr in DEMAND_ZERO_VADDRS.iter() {
+        check(vaddr, 0);
+        check(vaddr + PAGE_LENGTH, 0);
+        write(vaddr + PAGE_LENGTH, vaddr as u64);
+        check(vaddr + PAGE_LENGTH, vaddr as u64);
+    }
+
+    system::debug_test_succeed();
+}
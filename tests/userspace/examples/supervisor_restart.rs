@@ -0,0 +1,165 @@
+#![feature(lang_items)]
+#![feature(asm)]
+#![feature(const_fn)]
+#![feature(unique)]
+#![no_std]
+
+#[macro_use]
+extern crate system;
+
+/// The supervisor of rinit, restarting servers that miss a ping.
+#[path = "../../../rinit/src/supervisor.rs"]
+#[allow(dead_code)]
+mod supervisor;
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use system::CAddr;
+use supervisor::{Supervisor, ServerSpec, RestartPolicy};
+
+/// First argument the server is started with.
+const SERVER_ARGUMENT: isize = 1;
+/// Number of timer ticks the server has to answer a ping.
+const SERVER_TIMEOUT: u64 = 20;
+
+const SERVER_TASK: u8 = 249;
+const SERVER_SCHED_CONTEXT: u8 = 246;
+const SERVER_PING: u8 = 247;
+const SERVER_PONG: u8 = 248;
+const SERVER_BUFFER: u8 = 250;
+/// Channel telling the next server instance to stop answering pings.
+const SERVER_STALL: u8 = 251;
+/// Channel each server instance reports its start count on.
+const SERVER_STARTED: u8 = 252;
+
+/// Number of times this copy of the program started. A server loaded
+/// afresh from the boot image always starts from zero.
+static STARTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[lang="start"]
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+fn start(argc: isize, _argv: *const *const u8) {
+    if argc == SERVER_ARGUMENT {
+        server_main();
+    } else {
+        client_main();
+    }
+}
+
+/// Run the supervisor until a server instance reports its start, and
+/// return its start count.
+fn wait_for_start(supervisor: &mut Supervisor) -> u64 {
+    let deadline = system::timer_ticks() + 10 * SERVER_TIMEOUT;
+    while system::timer_ticks() < deadline {
+        if let Some(starts) = system::channel_poll_raw(CAddr::from(SERVER_STARTED)) {
+            return starts;
+        }
+        supervisor.poll();
+        system::task_yield();
+    }
+    system_print!("the server never started");
+    system::debug_test_fail();
+    0
+}
+
+fn client_main() {
+    unsafe { system::set_task_buffer_addr(0x90001000); }
+
+    let untyped = system::boot_info().largest_untyped().unwrap();
+    let asid_pool = match system::asid_control_make_pool(CAddr::from(system::ASID_CONTROL), untyped) {
+        Some(asid_pool) => asid_pool,
+        None => {
+            system::debug_test_fail();
+            return;
+        },
+    };
+    system::untyped_retype(untyped, system::ObjectType::SchedContext, 0,
+                           CAddr::from(system::BOOT_CPOOL), SERVER_SCHED_CONTEXT as usize, 1);
+    for &channel in [SERVER_PING, SERVER_PONG, SERVER_STALL, SERVER_STARTED].iter() {
+        system::retype_channel(untyped, CAddr::from(channel));
+    }
+
+    // Only the first instance finds the request to stall.
+    system::channel_put_raw(CAddr::from(SERVER_STALL), 1);
+
+    let mut supervisor = Supervisor::new();
+    let index = match supervisor.register(ServerSpec {
+        task: CAddr::from(SERVER_TASK),
+        image: CAddr::from(system::BOOT_IMAGE),
+        argument: SERVER_ARGUMENT as u64,
+        untyped: untyped,
+        asid_pool: asid_pool,
+        stack: 0x70000000,
+        stack_pages: 4,
+        cpool: CAddr::from(system::BOOT_CPOOL),
+        buffer: CAddr::from(SERVER_BUFFER),
+        buffer_vaddr: 0x90003000,
+        sched_context: CAddr::from(SERVER_SCHED_CONTEXT),
+        ping: CAddr::from(SERVER_PING),
+        pong: CAddr::from(SERVER_PONG),
+        timeout: SERVER_TIMEOUT,
+        policy: RestartPolicy::Always,
+    }) {
+        Some(index) => index,
+        None => {
+            system_print!("the server could not be spawned");
+            system::debug_test_fail();
+            return;
+        },
+    };
+
+    if wait_for_start(&mut supervisor) != 1 {
+        system::debug_test_fail();
+    }
+
+    // The first instance misses its ping, and is restarted.
+    let deadline = system::timer_ticks() + 10 * SERVER_TIMEOUT;
+    while supervisor.restarts(index) != Some(1) {
+        if system::timer_ticks() >= deadline {
+            system_print!("the stalled server was never restarted");
+            system::debug_test_fail();
+        }
+        supervisor.poll();
+        system::task_yield();
+    }
+
+    // The new instance was loaded from the boot image, not resumed
+    // from the old one's memory.
+    let starts = wait_for_start(&mut supervisor);
+    if starts != 1 {
+        system_print!("the restarted server found {} starts", starts);
+        system::debug_test_fail();
+    }
+
+    // It answers its pings, so it is left running.
+    let deadline = system::timer_ticks() + 3 * SERVER_TIMEOUT;
+    while system::timer_ticks() < deadline {
+        supervisor.poll();
+        system::task_yield();
+    }
+    if supervisor.restarts(index) != Some(1) {
+        system_print!("the restarted server missed a ping");
+        system::debug_test_fail();
+    }
+
+    system_print!("the server missed a ping and was restarted from the boot image");
+    system::debug_test_succeed();
+}
+
+fn server_main() {
+    unsafe { system::set_task_buffer_addr(0x90003000); }
+
+    let starts = STARTS.fetch_add(1, Ordering::SeqCst) + 1;
+    system::channel_put_raw(CAddr::from(SERVER_STARTED), starts as u64);
+
+    if system::channel_poll_raw(CAddr::from(SERVER_STALL)).is_some() {
+        loop {
+            system::task_yield();
+        }
+    }
+
+    loop {
+        supervisor::answer_ping(CAddr::from(SERVER_PING), CAddr::from(SERVER_PONG));
+        system::task_yield();
+    }
+}
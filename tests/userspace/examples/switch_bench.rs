@@ -0,0 +1,92 @@
+#![feature(lang_items)]
+#![feature(asm)]
+#![feature(const_fn)]
+#![feature(unique)]
+#![no_std]
+
+#[macro_use]
+extern crate system;
+
+use system::{CAddr, ChannelMessage, CycleStats};
+
+/// Yields, then calls, the benchmark makes.
+const ROUNDS: u64 = 1000;
+
+const SERVER_TASK: u8 = 249;
+const SERVER_SCHED_CONTEXT: u8 = 246;
+
+/// Endpoint and reply of the server, which shares the address space
+/// of the client.
+static mut SERVER: Option<(CAddr, CAddr)> = None;
+
+static mut IS_CLIENT: bool = true;
+
+#[lang="start"]
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+fn start(_argc: isize, _argv: *const *const u8) {
+    if unsafe { IS_CLIENT } {
+        unsafe { IS_CLIENT = false; }
+        client_main();
+    } else {
+        server_main();
+    }
+}
+
+fn print_stats(name: &str, stats: &CycleStats) {
+    system_print!("{}: median {} cycles, p99 {} cycles, max {} cycles ({} samples)",
+                  name, stats.median, stats.p99, stats.max, stats.samples);
+}
+
+fn client_main() {
+    unsafe { system::set_task_buffer_addr(0x90001000); }
+    let untyped = system::boot_info().largest_untyped().unwrap();
+
+    let endpoint = system::retype_endpoint(untyped).unwrap();
+    let reply = system::retype_reply(untyped).unwrap();
+    unsafe { SERVER = Some((endpoint, reply)); }
+
+    let server = CAddr::from(SERVER_TASK);
+    system::retype_task(untyped, server);
+    system::untyped_retype(untyped, system::ObjectType::SchedContext, 0,
+                           CAddr::from(system::BOOT_CPOOL), SERVER_SCHED_CONTEXT as usize, 1);
+    system::task_set_stack_pointer(server, 0x70000000 + (0x1000 * 4 - 4));
+    system::task_set_instruction_pointer(server, start as *const () as u64);
+    system::task_set_cpool(server, CAddr::from(system::BOOT_CPOOL));
+    system::task_set_top_page_table(server, CAddr::from(system::BOOT_TOP_PAGE_TABLE));
+    system::task_set_buffer(server, CAddr::from(250));
+    system::sched_context_bind(CAddr::from(SERVER_SCHED_CONTEXT), server);
+    system::task_set_active(server);
+
+    for _ in 0..ROUNDS {
+        system::task_yield();
+    }
+    for i in 0..ROUNDS {
+        match system::endpoint_call(endpoint, ChannelMessage::Raw(i)) {
+            Some(ChannelMessage::Raw(value)) if value == i => (),
+            _ => system::debug_test_fail(),
+        }
+    }
+
+    // Only the bootstrap CPU is brought up.
+    match system::debug_switch_stats(0) {
+        Some(stats) => {
+            print_stats("context switch", &stats.switch);
+            print_stats("IPC round trip", &stats.ipc_round_trip);
+        },
+        None => system::debug_test_fail(),
+    }
+
+    system::debug_test_succeed();
+}
+
+fn server_main() {
+    unsafe { system::set_task_buffer_addr(0x90003000); }
+    let (endpoint, reply) = unsafe { SERVER.unwrap() };
+
+    loop {
+        if let Some(message) = system::endpoint_receive(endpoint, Some(reply)) {
+            system::endpoint_reply(reply, message);
+        }
+    }
+}